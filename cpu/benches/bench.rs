@@ -3,6 +3,7 @@ extern crate crustationcpu;
 extern crate test;
 
 use crustationcpu::gte::Gte;
+use crustationcpu::{Cpu, PsxBus};
 
 use std::mem::replace;
 use test::Bencher;
@@ -61,3 +62,80 @@ fn ncdt(b: &mut Bencher) {
         });
     })
 }
+
+/// Flat 2MB RAM bus, enough to run a small loop out of KUSEG
+struct RamBus {
+    ram: std::cell::RefCell<Vec<u8>>,
+}
+
+impl PsxBus for RamBus {
+    fn read<const S: u32>(&self, address: u32) -> u32 {
+        let ram = self.ram.borrow();
+        let address = (address & 0x1f_ffff) as usize;
+
+        let mut value = 0;
+        for i in 0..S as usize {
+            value |= (ram[address + i] as u32) << (i * 8);
+        }
+
+        value
+    }
+
+    fn write<const S: u32>(&self, address: u32, value: u32) {
+        let mut ram = self.ram.borrow_mut();
+        let address = (address & 0x1f_ffff) as usize;
+
+        for i in 0..S as usize {
+            ram[address + i] = (value >> (i * 8)) as u8;
+        }
+    }
+
+    fn update_cycles(&self, _: u64) {}
+
+    fn cycles(&self) -> u64 {
+        0
+    }
+}
+
+#[bench]
+fn interpreter_loop(b: &mut Bencher) {
+    // A mix of ALU, load/store and branch instructions:
+    //   loop: addiu t0, t0, 1
+    //         sw    t0, 0x100(zero)
+    //         lw    t1, 0x100(zero)
+    //         xor   t2, t0, t1
+    //         sll   t3, t0, 2
+    //         bne   t0, t4, loop
+    //         addu  t5, t3, t2
+    const PROGRAM: [u32; 7] = [
+        0x2508_0001,
+        0xac08_0100,
+        0x8c09_0100,
+        0x0109_5026,
+        0x0008_5880,
+        0x150c_fffa,
+        0x016a_6821,
+    ];
+
+    let bus = RamBus {
+        ram: std::cell::RefCell::new(vec![0; 2 * 1024 * 1024]),
+    };
+
+    for (i, ins) in PROGRAM.iter().enumerate() {
+        bus.write::<4>((i * 4) as u32, *ins);
+    }
+
+    let mut cpu: Cpu<RamBus> = Cpu::new();
+    cpu.link(&bus);
+    cpu.pc = 0;
+    cpu.regs[12] = 0xffff_ffff;
+
+    b.iter(|| {
+        let n = test::black_box(1000);
+
+        (0..n).fold(0, |_, _| {
+            cpu.step();
+            1
+        });
+    })
+}
@@ -1,5 +1,8 @@
 use crate::{Cpu, Exception, PsxBus};
 
+/// Cycles a DIV or DIVU takes, whatever the operands
+const DIV_CYCLES: u32 = 36;
+
 impl<T: PsxBus> Cpu<T> {
     #[inline(always)]
     pub fn ins_sll(&mut self) {
@@ -46,8 +49,39 @@ impl<T: PsxBus> Cpu<T> {
         );
     }
 
+    /// Cycles a multiplication takes: the multiplier is done early when
+    /// `rs` has few significant bits
+    fn mult_cycles(rs: u32, signed: bool) -> u32 {
+        let bits = match signed && (rs as i32) < 0 {
+            true => 32 - rs.leading_ones(),
+            false => 32 - rs.leading_zeros(),
+        };
+        match bits {
+            0..=11 => 6,
+            12..=20 => 9,
+            _ => 13,
+        }
+    }
+
+    /// Stalls until HI and LO have the result of the last MULT or DIV
+    fn wait_for_muldiv(&mut self) {
+        let cycles = self.muldiv_done.saturating_sub(self.bus_cycles());
+        if cycles > 0 {
+            unsafe {
+                (*self.bus).update_cycles(cycles);
+            }
+        }
+    }
+
+    /// Starts a MULT or DIV that takes `cycles`
+    fn start_muldiv(&mut self, cycles: u32) {
+        self.muldiv_done = self.bus_cycles() + cycles as u64;
+    }
+
     #[inline(always)]
     pub fn ins_mult(&mut self) {
+        self.wait_for_muldiv();
+        self.start_muldiv(Self::mult_cycles(self.r_rs(), true));
         let res = ((self.r_rs() as i32 as i64) * (self.r_rt() as i32 as i64)) as u64;
         self.hi = (res >> 32) as u32;
         self.lo = (res & 0xffff_ffff) as u32;
@@ -55,6 +89,8 @@ impl<T: PsxBus> Cpu<T> {
 
     #[inline(always)]
     pub fn ins_multu(&mut self) {
+        self.wait_for_muldiv();
+        self.start_muldiv(Self::mult_cycles(self.r_rs(), false));
         let res = (self.r_rs() as u64) * (self.r_rt() as u64);
         self.hi = (res >> 32) as u32;
         self.lo = (res & 0xffff_ffff) as u32;
@@ -62,6 +98,8 @@ impl<T: PsxBus> Cpu<T> {
 
     #[inline(always)]
     pub fn ins_div(&mut self) {
+        self.wait_for_muldiv();
+        self.start_muldiv(DIV_CYCLES);
         let op1 = self.r_rs() as i32;
         let op2 = self.r_rt() as i32;
 
@@ -83,6 +121,8 @@ impl<T: PsxBus> Cpu<T> {
 
     #[inline(always)]
     pub fn ins_divu(&mut self) {
+        self.wait_for_muldiv();
+        self.start_muldiv(DIV_CYCLES);
         let op1 = self.r_rs();
         let op2 = self.r_rt();
 
@@ -237,6 +277,7 @@ impl<T: PsxBus> Cpu<T> {
 
     #[inline(always)]
     pub fn ins_mfhi(&mut self) {
+        self.wait_for_muldiv();
         self.write_reg(self.current_instruction.rd(), self.hi);
     }
 
@@ -247,6 +288,7 @@ impl<T: PsxBus> Cpu<T> {
 
     #[inline(always)]
     pub fn ins_mflo(&mut self) {
+        self.wait_for_muldiv();
         self.write_reg(self.current_instruction.rd(), self.lo);
     }
 
@@ -259,16 +301,7 @@ impl<T: PsxBus> Cpu<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    struct NullBus {}
-
-    impl PsxBus for NullBus {
-        fn read<const S: u32>(&self, _: u32) -> u32 {
-            0
-        }
-        fn write<const S: u32>(&self, _: u32, _: u32) {}
-        fn update_cycles(&self, _: u64) {}
-    }
+    use crate::test_bus::{CycleBus, NullBus};
 
     impl<T: PsxBus> Cpu<T> {
         pub fn trash_registers(&mut self) {
@@ -476,4 +509,48 @@ mod tests {
         assert_eq!(cpu.regs[1], 0x004c_df03);
         assert_eq!(cpu.regs[2], 0x1337_c0d3);
     }
+
+    #[test]
+    fn test_mult_cycles_follow_rs() {
+        assert_eq!(Cpu::<NullBus>::mult_cycles(0x7ff, true), 6);
+        assert_eq!(Cpu::<NullBus>::mult_cycles(0xffff_f800, true), 6);
+        assert_eq!(Cpu::<NullBus>::mult_cycles(0xffff_f800, false), 13);
+        assert_eq!(Cpu::<NullBus>::mult_cycles(0x000f_ffff, false), 9);
+        assert_eq!(Cpu::<NullBus>::mult_cycles(0xfff0_0000, true), 9);
+        assert_eq!(Cpu::<NullBus>::mult_cycles(0x8000_0000, true), 13);
+    }
+
+    #[test]
+    fn test_reading_hi_lo_waits_for_the_divider() {
+        let bus = CycleBus::default();
+        let mut cpu = Cpu::new();
+        cpu.link(&bus);
+        cpu.regs[1] = 100;
+        cpu.regs[2] = 7;
+
+        // DIV r1, r2, then 6 cycles of other instructions
+        cpu.current_instruction.0 = 0x0022_001a;
+        cpu.ins_div();
+        bus.update_cycles(6);
+
+        // MFLO r8
+        cpu.current_instruction.0 = 0x0000_4012;
+        cpu.ins_mflo();
+        assert_eq!(cpu.regs[8], 14);
+        assert_eq!(bus.cycles.get(), 36);
+
+        // MFHI r8, the result is there already
+        cpu.current_instruction.0 = 0x0000_4010;
+        cpu.ins_mfhi();
+        assert_eq!(cpu.regs[8], 2);
+        assert_eq!(bus.cycles.get(), 36);
+
+        // DIV r1, r2, then a load with 40 cycles of wait states
+        cpu.current_instruction.0 = 0x0022_001a;
+        cpu.ins_div();
+        bus.update_cycles(40);
+        cpu.current_instruction.0 = 0x0000_4012;
+        cpu.ins_mflo();
+        assert_eq!(bus.cycles.get(), 76);
+    }
 }
@@ -0,0 +1,244 @@
+//! GTE fuzz vector generator.
+//!
+//! Writes randomized vectors for one operation in the format of
+//! `tests/gte/fuzz/data`, with the outputs recorded from this GTE. The same
+//! seed always gives the same inputs, so a set can be regenerated after a
+//! fix and the changed outputs reviewed in the diff.
+//!
+//!     gte_vectors <operation> [--seed=N] [--count=N] [--out=FILE]
+//!
+//! The operation is named as the data files (rtps, nclip, ...). The vectors
+//! go to stdout without `--out`.
+
+use std::fmt::Write;
+use std::process::exit;
+
+use crustationcpu::gte::Gte;
+
+const OPERATIONS: [(&str, u32); 22] = [
+    ("rtps", 0x01),
+    ("nclip", 0x06),
+    ("op", 0x0c),
+    ("dpcs", 0x10),
+    ("intpl", 0x11),
+    ("mvmva", 0x12),
+    ("ncds", 0x13),
+    ("cdp", 0x14),
+    ("ncdt", 0x16),
+    ("nccs", 0x1b),
+    ("cc", 0x1c),
+    ("ncs", 0x1e),
+    ("nct", 0x20),
+    ("sqr", 0x28),
+    ("dcpl", 0x29),
+    ("dpct", 0x2a),
+    ("avsz3", 0x2d),
+    ("avsz4", 0x2e),
+    ("rtpt", 0x30),
+    ("gpf", 0x3d),
+    ("gpl", 0x3e),
+    ("ncct", 0x3f),
+];
+
+/// Values at the edges of the fixed point ranges, where saturation and
+/// overflow flags are set. The shipped vectors use the same ones.
+const EDGE_VALUES: [u32; 10] = [
+    0x0000_0000,
+    0x0000_1000,
+    0x5555_5555,
+    0x7fff_7fff,
+    0x7fff_ffff,
+    0x8000_0000,
+    0x8000_8000,
+    0xaaaa_aaaa,
+    0xffff_ffff,
+    0xffff_0000,
+];
+
+/// A counter through the MurmurHash3 final mix, like the random RAM fill
+struct Rng {
+    seed: u32,
+    counter: u32,
+}
+
+impl Rng {
+    fn new(seed: u32) -> Rng {
+        Rng { seed, counter: 0 }
+    }
+
+    fn next(&mut self) -> u32 {
+        self.counter = self.counter.wrapping_add(1);
+        let mut x = self.seed ^ self.counter.wrapping_mul(0x9e37_79b9);
+        x ^= x >> 16;
+        x = x.wrapping_mul(0x85eb_ca6b);
+        x ^= x >> 13;
+        x = x.wrapping_mul(0xc2b2_ae35);
+        x ^ (x >> 16)
+    }
+
+    /// Half random words, the rest split between edge values and words
+    /// with a zero half, as the GTE mostly reads 16 bits
+    fn register(&mut self) -> u32 {
+        let value = self.next();
+        match self.next() % 8 {
+            0 | 1 => EDGE_VALUES[value as usize % EDGE_VALUES.len()],
+            2 => value & 0xffff,
+            3 => value & 0xffff_0000,
+            _ => value,
+        }
+    }
+}
+
+struct Vector {
+    name: String,
+    input: [u32; 64],
+    opcode: u32,
+    output: [u32; 64],
+}
+
+fn generate(funct: u32, seed: u32, count: usize) -> Vec<Vector> {
+    let mut rng = Rng::new(seed);
+    (0..count)
+        .map(|n| {
+            let mut input = [0; 64];
+            for value in input.iter_mut() {
+                *value = rng.register();
+            }
+
+            // sf, mx, vx, tx and lm, at random
+            let opcode = funct | (rng.next() & 0x000f_e400);
+            let mut gte = Gte::new();
+            for (r, &value) in input.iter().enumerate() {
+                gte.write_reg(r as u32, value);
+            }
+            gte.execute(opcode);
+
+            let mut output = [0; 64];
+            for (r, value) in output.iter_mut().enumerate() {
+                *value = gte.read_reg(r as u32);
+            }
+
+            let name = format!(
+                "seed {} test {} - GTE 0x{:02x} (sf={}, lm={}, tx={}, vx={}, mx={})",
+                seed,
+                n + 1,
+                funct,
+                (opcode >> 19) & 1,
+                (opcode >> 10) & 1,
+                (opcode >> 13) & 3,
+                (opcode >> 15) & 3,
+                (opcode >> 17) & 3
+            );
+            Vector {
+                name,
+                input,
+                opcode,
+                output,
+            }
+        })
+        .collect()
+}
+
+/// Four registers per line, like the shipped files
+fn write_registers(out: &mut String, values: &[u32; 64]) {
+    for (line, values) in values.chunks(4).enumerate() {
+        let values: Vec<String> = values.iter().map(|v| format!("\"0x{:08x}\"", v)).collect();
+        let separator = if line == 15 { "" } else { "," };
+        writeln!(out, "      {}{}", values.join(", "), separator).unwrap();
+    }
+}
+
+fn to_json(vectors: &[Vector]) -> String {
+    let mut out = String::from("[\n");
+    for (n, vector) in vectors.iter().enumerate() {
+        out += "  {\n";
+        writeln!(out, "    \"name\": \"{}\",", vector.name).unwrap();
+        out += "    \"input\": [\n";
+        write_registers(&mut out, &vector.input);
+        out += "    ],\n";
+        writeln!(out, "    \"opcode\": \"0x{:08x}\",", vector.opcode).unwrap();
+        out += "    \"output\": [\n";
+        write_registers(&mut out, &vector.output);
+        out += "    ]\n";
+        out += if n + 1 == vectors.len() { "  }\n" } else { "  },\n" };
+    }
+    out += "]";
+    out
+}
+
+fn parse_number(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: gte_vectors <operation> [--seed=N] [--count=N] [--out=FILE]");
+    let names: Vec<&str> = OPERATIONS.iter().map(|(name, _)| *name).collect();
+    eprintln!("Operations: {}", names.join(", "));
+    exit(2);
+}
+
+fn main() {
+    let mut operation = None;
+    let mut seed = 1;
+    let mut count = 50;
+    let mut out = None;
+
+    for arg in std::env::args().skip(1) {
+        if let Some(value) = arg.strip_prefix("--seed=") {
+            seed = parse_number(value).unwrap_or_else(|| usage());
+        } else if let Some(value) = arg.strip_prefix("--count=") {
+            count = parse_number(value).unwrap_or_else(|| usage()) as usize;
+        } else if let Some(value) = arg.strip_prefix("--out=") {
+            out = Some(value.to_string());
+        } else if operation.is_none() && !arg.starts_with("--") {
+            operation = Some(arg);
+        } else {
+            usage();
+        }
+    }
+
+    let operation = operation.unwrap_or_else(|| usage());
+    let funct = match OPERATIONS.iter().find(|(name, _)| *name == operation) {
+        Some(&(_, funct)) => funct,
+        None => usage(),
+    };
+
+    let json = to_json(&generate(funct, seed, count));
+    match out {
+        Some(path) => {
+            if let Err(err) = std::fs::write(&path, json + "\n") {
+                eprintln!("Could not write {}: {}", path, err);
+                exit(1);
+            }
+        }
+        None => println!("{}", json),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_seed_decides_the_vectors() {
+        let a = generate(0x01, 7, 3);
+        let b = generate(0x01, 7, 3);
+        let c = generate(0x01, 8, 3);
+        assert_eq!(to_json(&a), to_json(&b));
+        assert_ne!(to_json(&a), to_json(&c));
+        assert!(a.iter().all(|v| v.opcode & 0x3f == 0x01));
+    }
+
+    #[test]
+    fn writes_the_fuzz_data_format() {
+        let json = to_json(&generate(0x06, 1, 2));
+        assert!(json.starts_with("[\n  {\n    \"name\": \"seed 1 test 1 - GTE 0x06 (sf="));
+        assert!(json.ends_with("    ]\n  }\n]"));
+        // 64 registers in, 64 out, for each vector
+        assert_eq!(json.matches("\"0x").count(), 2 * (64 + 64 + 1));
+        assert_eq!(json.matches("\"input\"").count(), 2);
+    }
+}
@@ -0,0 +1,335 @@
+//! Breakpoints and watchpoints for the debugger.
+//!
+//! Addresses are compared without the segment bits, so a breakpoint at
+//! 0x8001_0000 also stops at 0xa001_0000. Execution breakpoints stop before
+//! the instruction runs, watchpoints after the access that hit them. Only
+//! CPU loads and stores are watched, DMA is not.
+
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    const ALL: [(Comparison, &'static str); 6] = [
+        (Comparison::Eq, "=="),
+        (Comparison::Ne, "!="),
+        (Comparison::Lt, "<"),
+        (Comparison::Le, "<="),
+        (Comparison::Gt, ">"),
+        (Comparison::Ge, ">="),
+    ];
+
+    pub fn from_symbol(symbol: &str) -> Option<Comparison> {
+        Comparison::ALL
+            .iter()
+            .find(|(_, s)| *s == symbol)
+            .map(|(comparison, _)| *comparison)
+    }
+
+    pub fn symbol(self) -> &'static str {
+        Comparison::ALL.iter().find(|(c, _)| *c == self).unwrap().1
+    }
+}
+
+/// Unsigned comparison of a general purpose register with a value
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub reg: u32,
+    pub comparison: Comparison,
+    pub value: u32,
+}
+
+impl Condition {
+    pub fn holds(&self, regs: &[u32]) -> bool {
+        let reg = regs[self.reg as usize];
+        match self.comparison {
+            Comparison::Eq => reg == self.value,
+            Comparison::Ne => reg != self.value,
+            Comparison::Lt => reg < self.value,
+            Comparison::Le => reg <= self.value,
+            Comparison::Gt => reg > self.value,
+            Comparison::Ge => reg >= self.value,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Kind {
+    Execute(u32),
+    /// `len` bytes from `address`
+    Watch {
+        address: u32,
+        len: u32,
+        read: bool,
+        write: bool,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub id: u32,
+    pub kind: Kind,
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+    pub hits: u64,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            Kind::Execute(address) => write!(f, "{}: break at {:08x}", self.id, address)?,
+            Kind::Watch {
+                address,
+                len,
+                read,
+                write,
+            } => {
+                let access = match (read, write) {
+                    (true, true) => "read/write",
+                    (true, false) => "read",
+                    _ => "write",
+                };
+                write!(f, "{}: watch {} {:08x}+{}", self.id, access, address, len)?
+            }
+        }
+        if let Some(condition) = self.condition {
+            write!(
+                f,
+                " if r{} {} {:08x}",
+                condition.reg,
+                condition.comparison.symbol(),
+                condition.value
+            )?;
+        }
+        if !self.enabled {
+            write!(f, " (disabled)")?;
+        }
+        write!(f, ", hit {} times", self.hits)
+    }
+}
+
+/// Why the debugger was entered
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DebugStop {
+    /// Asked for, with Ctrl-C or by a device
+    Requested,
+    Breakpoint(u32),
+    /// Id, address accessed and whether it was a write
+    Watchpoint(u32, u32, bool),
+}
+
+fn physical(address: u32) -> u32 {
+    address & 0x1fff_ffff
+}
+
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    next_id: u32,
+    /// Whether any enabled breakpoint or watchpoint exists, checked on
+    /// every instruction and access
+    executing: bool,
+    watching: bool,
+    /// Execution breakpoint just hit: the instruction runs when resuming
+    resume_at: Option<u32>,
+}
+
+impl Default for Breakpoints {
+    fn default() -> Breakpoints {
+        Breakpoints::new()
+    }
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints {
+            list: vec![],
+            next_id: 1,
+            executing: false,
+            watching: false,
+            resume_at: None,
+        }
+    }
+
+    pub fn list(&self) -> &[Breakpoint] {
+        &self.list
+    }
+
+    /// Returns the id of the new breakpoint
+    pub fn add(&mut self, kind: Kind, condition: Option<Condition>) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Breakpoint {
+            id,
+            kind,
+            condition,
+            enabled: true,
+            hits: 0,
+        });
+        self.update();
+        id
+    }
+
+    /// Returns false if there's no such breakpoint
+    pub fn remove(&mut self, id: u32) -> bool {
+        let len = self.list.len();
+        self.list.retain(|breakpoint| breakpoint.id != id);
+        self.update();
+        self.list.len() != len
+    }
+
+    /// Returns false if there's no such breakpoint
+    pub fn set_enabled(&mut self, id: u32, enabled: bool) -> bool {
+        let found = match self.list.iter_mut().find(|breakpoint| breakpoint.id == id) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                true
+            }
+            None => false,
+        };
+        self.update();
+        found
+    }
+
+    fn update(&mut self) {
+        let enabled = || self.list.iter().filter(|breakpoint| breakpoint.enabled);
+        self.executing = enabled().any(|b| matches!(b.kind, Kind::Execute(_)));
+        self.watching = enabled().any(|b| matches!(b.kind, Kind::Watch { .. }));
+    }
+
+    #[inline(always)]
+    pub fn executing(&self) -> bool {
+        self.executing
+    }
+
+    #[inline(always)]
+    pub fn watching(&self) -> bool {
+        self.watching
+    }
+
+    /// Lets the instruction at `pc` run without stopping, once
+    pub fn skip(&mut self, pc: u32) {
+        self.resume_at = Some(pc);
+    }
+
+    /// The breakpoint stopping before the instruction at `pc`, if any
+    pub fn check_execute(&mut self, pc: u32, regs: &[u32]) -> Option<u32> {
+        if self.resume_at.take() == Some(pc) {
+            return None;
+        }
+
+        let breakpoint = self.list.iter_mut().find(|breakpoint| {
+            let at = |address| physical(address) == physical(pc);
+            breakpoint.enabled
+                && matches!(breakpoint.kind, Kind::Execute(address) if at(address))
+                && breakpoint.condition.is_none_or(|condition| condition.holds(regs))
+        })?;
+        breakpoint.hits += 1;
+        self.resume_at = Some(pc);
+        Some(breakpoint.id)
+    }
+
+    /// The watchpoint hit by an access of `size` bytes at `address`, if any
+    pub fn check_access(
+        &mut self,
+        address: u32,
+        size: u32,
+        write: bool,
+        regs: &[u32],
+    ) -> Option<u32> {
+        let address = physical(address);
+        let breakpoint = self.list.iter_mut().find(|breakpoint| match breakpoint.kind {
+            Kind::Watch {
+                address: start,
+                len,
+                read,
+                write: on_write,
+            } => {
+                let start = physical(start);
+                let watched = if write { on_write } else { read };
+                breakpoint.enabled
+                    && watched
+                    && address < start.wrapping_add(len)
+                    && start < address.wrapping_add(size)
+                    && breakpoint.condition.is_none_or(|condition| condition.holds(regs))
+            }
+            Kind::Execute(_) => false,
+        })?;
+        breakpoint.hits += 1;
+        Some(breakpoint.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breaks_once_before_the_instruction() {
+        let mut breakpoints = Breakpoints::new();
+        let regs = [0; 33];
+        let id = breakpoints.add(Kind::Execute(0x8001_0000), None);
+        assert!(breakpoints.executing() && !breakpoints.watching());
+
+        // Mirrors hit too
+        assert_eq!(breakpoints.check_execute(0xa001_0000, &regs), Some(id));
+        // Resuming runs the instruction
+        assert_eq!(breakpoints.check_execute(0xa001_0000, &regs), None);
+        assert_eq!(breakpoints.check_execute(0x8001_0004, &regs), None);
+        assert_eq!(breakpoints.check_execute(0x8001_0000, &regs), Some(id));
+        assert_eq!(breakpoints.list()[0].hits, 2);
+
+        assert!(breakpoints.set_enabled(id, false));
+        assert!(!breakpoints.executing());
+        assert!(breakpoints.remove(id));
+        assert!(!breakpoints.remove(id));
+    }
+
+    #[test]
+    fn conditions_compare_registers() {
+        let mut breakpoints = Breakpoints::new();
+        let condition = Condition {
+            reg: 4,
+            comparison: Comparison::from_symbol(">=").unwrap(),
+            value: 0x10,
+        };
+        let id = breakpoints.add(Kind::Execute(0xbfc0_0000), Some(condition));
+
+        let mut regs = [0; 33];
+        regs[4] = 0xf;
+        assert_eq!(breakpoints.check_execute(0xbfc0_0000, &regs), None);
+        regs[4] = 0x10;
+        assert_eq!(breakpoints.check_execute(0xbfc0_0000, &regs), Some(id));
+        assert_eq!(
+            breakpoints.list()[0].to_string(),
+            "1: break at bfc00000 if r4 >= 00000010, hit 1 times"
+        );
+    }
+
+    #[test]
+    fn watchpoints_cover_a_range() {
+        let mut breakpoints = Breakpoints::new();
+        let regs = [0; 33];
+        let kind = Kind::Watch {
+            address: 0x8000_0100,
+            len: 8,
+            read: false,
+            write: true,
+        };
+        let id = breakpoints.add(kind, None);
+
+        assert_eq!(breakpoints.check_access(0x0000_0104, 4, false, &regs), None);
+        assert_eq!(breakpoints.check_access(0x0000_0104, 4, true, &regs), Some(id));
+        // A word overlapping the first byte
+        assert_eq!(breakpoints.check_access(0x0000_00fe, 4, true, &regs), Some(id));
+        assert_eq!(breakpoints.check_access(0x0000_0108, 4, true, &regs), None);
+        assert_eq!(breakpoints.check_access(0x0000_00fc, 4, true, &regs), None);
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{Cpu, Exception, PsxBus};
+use std::time::Instant;
+
+use crate::gte::Gte;
+use crate::{Cpu, Exception, PsxBus, Vector};
 
 use crustationlogger::*;
 
@@ -19,6 +22,10 @@ impl<T: PsxBus> Cpu<T> {
             cause,
             self.pc.wrapping_sub(4)
         );
+        // System calls and BREAK are deliberate, the rest are crashes
+        if !matches!(cause, Exception::Syscall | Exception::Breakpoint) {
+            self.trace_fault();
+        }
 
         self.cop0
             .enter_exception(cause, self.pc.wrapping_sub(4), self.in_delay, 0);
@@ -26,12 +33,26 @@ impl<T: PsxBus> Cpu<T> {
         self.pc = self.cop0.exception_handler(cause);
     }
 
+    /// A hardware breakpoint hit by the instruction at `pc`. Goes through
+    /// the debug vector rather than the general one. On a data breakpoint
+    /// the access that hit it still completes.
+    pub fn breakpoint_exception(&mut self, pc: u32, is_delay_slot: bool) {
+        debug!(self.logger, "Hardware breakpoint hit at {:08x}", pc);
+
+        self.cop0
+            .enter_exception(Exception::Breakpoint, pc, is_delay_slot, 0);
+
+        self.branch_delay_slot = None;
+        self.pc = self.cop0.vector_address(Vector::Debug);
+    }
+
     pub fn coprocessor_exception(&mut self, cop_number: u32) {
         err!(
             self.logger,
             "Coprocessor Unusable Exception for COP{}",
             cop_number
         );
+        self.trace_fault();
 
         self.cop0.enter_exception(
             Exception::CoprocessorUnusable,
@@ -67,7 +88,7 @@ impl<T: PsxBus> Cpu<T> {
                     // MFC
                     match self.cop0.read_reg(self.current_instruction.rd()) {
                         Some(value) => {
-                            self.write_reg(self.current_instruction.rt(), value);
+                            self.delayed_load(self.current_instruction.rt(), value);
                         }
                         None => self.coprocessor_exception(0),
                     }
@@ -79,11 +100,8 @@ impl<T: PsxBus> Cpu<T> {
                 0x04 => {
                     // MTC
                     let value = self.r_rt();
-                    match self.cop0.write_reg(self.current_instruction.rd(), value) {
-                        Ok(_) => {
-                            self.check_interrupts();
-                        }
-                        Err(_) => self.coprocessor_exception(0),
+                    if self.cop0.write_reg(self.current_instruction.rd(), value).is_err() {
+                        self.coprocessor_exception(0);
                     }
 
                     if self.cop0.isolate_cache {
@@ -153,19 +171,31 @@ impl<T: PsxBus> Cpu<T> {
 
         let is_op = self.current_instruction.0 & (1 << 25) != 0;
         if is_op {
-            self.gte.execute(self.current_instruction.0 & 0x1ff_ffff);
+            self.wait_for_gte();
+            let start = self.gte_time.is_some().then(Instant::now);
+            let command = self.current_instruction.0 & 0x1ff_ffff;
+            self.gte.execute(command);
+            self.gte_done = self.bus_cycles() + Gte::command_cycles(command & 0x3f) as u64;
+            if let (Some(start), Some(time)) = (start, &mut self.gte_time) {
+                *time += start.elapsed();
+            }
         } else {
             match (self.current_instruction.0 >> 21) & 0xf {
+                // Moves to the CPU go through the load delay slot, like loads
                 0x00 => {
                     // mfc
+                    self.wait_for_gte();
                     let value = self.gte.read_reg(self.current_instruction.rd());
-                    self.write_reg(self.current_instruction.rt(), value);
+                    self.delayed_load(self.current_instruction.rt(), value);
                 }
                 0x02 => {
                     // cfc
+                    self.wait_for_gte();
                     let value = self.gte.read_reg(self.current_instruction.rd() + 32);
-                    self.write_reg(self.current_instruction.rt(), value);
+                    self.delayed_load(self.current_instruction.rt(), value);
                 }
+                // Moves to the GTE take effect at once: a command right after
+                // sees the new value
                 0x04 => {
                     // mtc
                     self.gte
@@ -187,13 +217,19 @@ impl<T: PsxBus> Cpu<T> {
         }
     }
 
+    /// The loaded word goes straight to the GTE, without a delay slot. A
+    /// pending load to the CPU register with the same number is unaffected.
     pub fn ins_lwc2(&mut self) {
         if !self.cop0.cop2_enabled {
             self.coprocessor_exception(2);
+            return;
         }
 
         let address = self.ls_address();
-        let value = self.load::<4>(address);
+        if !self.check_access(address, 4, Exception::AddressErrorLoad) {
+            return;
+        }
+        let value = self.load_data::<4>(address);
 
         self.gte.write_reg(self.current_instruction.rt(), value);
     }
@@ -201,13 +237,28 @@ impl<T: PsxBus> Cpu<T> {
     pub fn ins_swc2(&mut self) {
         if !self.cop0.cop2_enabled {
             self.coprocessor_exception(2);
+            return;
         }
 
         let address = self.ls_address();
+        if !self.check_access(address, 4, Exception::AddressErrorStore) {
+            return;
+        }
+        self.wait_for_gte();
         let value = self.gte.read_reg(self.current_instruction.rt());
         self.store::<4>(address, value);
     }
 
+    /// Stalls until the GTE command is done. Writes to its registers don't.
+    fn wait_for_gte(&mut self) {
+        let cycles = self.gte_done.saturating_sub(self.bus_cycles());
+        if cycles > 0 {
+            unsafe {
+                (*self.bus).update_cycles(cycles);
+            }
+        }
+    }
+
     pub fn ins_cop3(&mut self) {
         if !self.cop0.cop3_enabled {
             self.coprocessor_exception(3);
@@ -232,3 +283,86 @@ impl<T: PsxBus> Cpu<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_bus::{CycleBus, NullBus};
+    use crate::Instruction;
+
+    fn make_cpu_with_irq_pending(status: u32) -> Cpu<NullBus> {
+        let mut cpu = Cpu::new();
+        cpu.pc = 0x8001_0004;
+        cpu.cop0.write_reg(12, status).unwrap();
+
+        // The interrupt controller raises its line
+        cpu.cop0.request_interrupt(10);
+
+        cpu
+    }
+
+    #[test]
+    fn test_interrupt_with_bev_set_jumps_to_rom() {
+        let mut cpu = make_cpu_with_irq_pending((1 << 22) | (1 << 10) | 1);
+        assert!(cpu.cop0.should_interrupt());
+
+        cpu.interrupt();
+
+        assert_eq!(cpu.pc, 0xbfc0_0180);
+    }
+
+    #[test]
+    fn test_interrupt_with_bev_clear_jumps_to_ram() {
+        let mut cpu = make_cpu_with_irq_pending((1 << 10) | 1);
+        assert!(cpu.cop0.should_interrupt());
+
+        cpu.interrupt();
+
+        assert_eq!(cpu.pc, 0x8000_0080);
+        assert_eq!(cpu.cop0.regs[14], 0x8001_0004);
+    }
+
+    #[test]
+    fn test_syscall_follows_bev_flips() {
+        let mut cpu: Cpu<NullBus> = Cpu::new();
+        cpu.pc = 0x8001_0004;
+
+        cpu.ins_syscall();
+        assert_eq!(cpu.pc, 0xbfc0_0180);
+
+        cpu.cop0.write_reg(12, 0).unwrap();
+        cpu.pc = 0x8001_0004;
+        cpu.ins_syscall();
+        assert_eq!(cpu.pc, 0x8000_0080);
+    }
+
+    #[test]
+    fn test_reading_the_gte_waits_for_the_command() {
+        let bus = CycleBus::default();
+        let mut cpu = Cpu::new();
+        cpu.link(&bus);
+        cpu.cop0.cop2_enabled = true;
+
+        // RTPS, then 5 cycles of other instructions
+        cpu.current_instruction = Instruction(0x4a18_0001);
+        cpu.ins_cop2();
+        bus.update_cycles(5);
+
+        // MFC2 t0, SXY2
+        cpu.current_instruction = Instruction(0x4808_7000);
+        cpu.ins_cop2();
+        assert_eq!(bus.cycles.get(), 15);
+
+        // The command is done
+        cpu.ins_cop2();
+        assert_eq!(bus.cycles.get(), 15);
+
+        // RTPS, then a load with 20 cycles of wait states
+        cpu.current_instruction = Instruction(0x4a18_0001);
+        cpu.ins_cop2();
+        bus.update_cycles(20);
+        cpu.current_instruction = Instruction(0x4808_7000);
+        cpu.ins_cop2();
+        assert_eq!(bus.cycles.get(), 35);
+    }
+}
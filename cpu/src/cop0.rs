@@ -1,5 +1,7 @@
 use crustationlogger::*;
 
+use crate::state::{Savestate, StateError, StateReader, StateWriter};
+
 /// The MIPS R3000A System Coprocessor
 ///
 /// The most important task of this chip is Exception handling.
@@ -83,8 +85,29 @@ use crustationlogger::*;
 /// ## PRID: Processor ID
 /// A fixed 2
 ///
+/// ## Hardware breakpoints
+/// An instruction fetched from an address matching BPC in the bits set in
+/// BPCM, or a load or store matching BDA in the bits set in BDAM, raises
+/// a Breakpoint exception through the debug vector (0x8000_0040, or
+/// 0xbfc0_0140 with BEV set).
+///
+/// DCIC bits | Description
+/// ----------|------------
+///         0 | Any break happened
+///         1 | Code break happened (BPC)
+///         2 | Data break happened (BDA)
+///      3, 4 | The data break was a read, a write
+///        23 | Master enable for bits 24-29, with bit 31
+///        24 | Code break enable
+///        25 | Data break enable, with bits 26 (reads) and 27 (writes)
+///        30 | Master enable for bits 24-27
+///        31 | Master enable for bits 24-29, with bit 23
+///
+/// The status bits (0-4) are set by the hardware, the handler clears them.
+/// Jump breaks (TAR, bits 5 and 28-29) are not emulated.
+///
 /// # TODOs
-/// - Breakpoints (r3, r5, r7, r8, r9, r11)
+/// - Jump breakpoints (r6, DCIC.b5, b28, b29)
 /// - Check read behaviour of r6 (TAR) and garbage (r16 - r31)
 pub struct Cop0 {
     logger: Logger,
@@ -114,6 +137,15 @@ pub struct Cop0 {
 
     /// Mirror of SR.b31
     pub cop3_enabled: bool,
+
+    /// DCIC enables code breakpoints
+    code_breakpoints: bool,
+
+    /// DCIC enables data breakpoints on reads
+    read_breakpoints: bool,
+
+    /// DCIC enables data breakpoints on writes
+    write_breakpoints: bool,
 }
 
 /// List of supported COP0 exceptions.
@@ -129,6 +161,17 @@ pub enum Exception {
     Overflow = 12,
 }
 
+/// Entry points the CPU can jump to when entering an exception
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Vector {
+    /// User TLB miss. Unreachable on the PlayStation, which has no TLB
+    TlbMiss,
+    /// Hardware breakpoints (BPC/BDA)
+    Debug,
+    /// Every other exception
+    General,
+}
+
 /// Masks of bits allowed to be set by the CPU with MTC
 const WRITE_MASKS: [u32; 16] = [
     0,
@@ -149,6 +192,10 @@ const WRITE_MASKS: [u32; 16] = [
     0,
 ];
 
+const BPC: usize = 3;
+const BDA: usize = 5;
+const DCIC: usize = 7;
+const BADA: usize = 8;
 const BDAM: usize = 9;
 const BPCM: usize = 11;
 const STATUS: usize = 12;
@@ -177,6 +224,9 @@ impl Cop0 {
             cop1_enabled: false,
             cop2_enabled: false,
             cop3_enabled: false,
+            code_breakpoints: false,
+            read_breakpoints: false,
+            write_breakpoints: false,
         }
     }
 
@@ -223,6 +273,8 @@ impl Cop0 {
 
                 if index == STATUS {
                     self.update_status();
+                } else if index == DCIC {
+                    self.update_breakpoints();
                 }
 
                 Ok(())
@@ -332,22 +384,30 @@ impl Cop0 {
         );
     }
 
-    /// Returns the PC that is expected to handle a given exception
-    /// The returned value will depend on the kind of exception and on SR.b22
-    pub fn exception_handler(&self, cause: Exception) -> u32 {
-        if self.boot_vectors {
-            if cause == Exception::Breakpoint {
-                0xbfc0_0140
-            } else {
-                0xbfc0_0180
-            }
-        } else if cause == Exception::Breakpoint {
-            0x8000_0040
+    /// Returns the address of an exception vector.
+    /// The base depends on SR.b22 (BEV): ROM (0xbfc0_01xx) while booting,
+    /// RAM (0x8000_00xx) once the kernel installs its handlers.
+    pub fn vector_address(&self, vector: Vector) -> u32 {
+        let base = if self.boot_vectors {
+            0xbfc0_0100
         } else {
-            0x8000_0080
+            0x8000_0000
+        };
+
+        base + match vector {
+            Vector::TlbMiss => 0x00,
+            Vector::Debug => 0x40,
+            Vector::General => 0x80,
         }
     }
 
+    /// Returns the PC that is expected to handle a given exception.
+    /// All the exceptions we raise go through the general vector: there's no
+    /// TLB, and BREAK is a regular exception, not a debug one.
+    pub fn exception_handler(&self, _cause: Exception) -> u32 {
+        self.vector_address(Vector::General)
+    }
+
     /// An interrupt has to be handled if:
     /// - The master interrupt flag is set (SR.b0)
     /// - One or more interrupts are requested in CAUSE.b8-15
@@ -363,6 +423,42 @@ impl Cop0 {
         }
     }
 
+    /// Whether fetching the instruction at `pc` hits the code breakpoint.
+    /// Records the hit in DCIC.
+    #[inline(always)]
+    pub fn code_breakpoint(&mut self, pc: u32) -> bool {
+        if !self.code_breakpoints || (pc ^ self.regs[BPC]) & self.regs[BPCM] != 0 {
+            return false;
+        }
+
+        self.regs[DCIC] |= 0x3;
+        true
+    }
+
+    /// Whether a load (or store, when `write`) at `address` hits the data
+    /// breakpoint. Records the hit in DCIC.
+    #[inline(always)]
+    pub fn data_breakpoint(&mut self, address: u32, write: bool) -> bool {
+        let enabled = match write {
+            true => self.write_breakpoints,
+            false => self.read_breakpoints,
+        };
+        if !enabled || (address ^ self.regs[BDA]) & self.regs[BDAM] != 0 {
+            return false;
+        }
+
+        self.regs[DCIC] |= match write {
+            true => 0x15,
+            false => 0x0d,
+        };
+        true
+    }
+
+    /// Records the address that caused an address error
+    pub fn set_bad_address(&mut self, address: u32) {
+        self.regs[BADA] = address;
+    }
+
     /// Sets a bit in the CAUSE register to indicate an IRQ
     pub fn request_interrupt(&mut self, n: u32) {
         self.regs[CAUSE] |= 1 << n;
@@ -384,4 +480,114 @@ impl Cop0 {
         self.cop2_enabled = self.regs[STATUS] & (1 << 30) != 0;
         self.cop3_enabled = self.regs[STATUS] & (1 << 31) != 0;
     }
+
+    /// Updates the breakpoint flags based on DCIC. Bits 23, 30 and 31 must
+    /// all be set for any breakpoint to fire.
+    fn update_breakpoints(&mut self) {
+        let dcic = self.regs[DCIC];
+        let master = dcic & 0xc080_0000 == 0xc080_0000;
+        let data = master && dcic & (1 << 25) != 0;
+
+        self.code_breakpoints = master && dcic & (1 << 24) != 0;
+        self.read_breakpoints = data && dcic & (1 << 26) != 0;
+        self.write_breakpoints = data && dcic & (1 << 27) != 0;
+    }
+}
+
+impl Savestate for Cop0 {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"COP0");
+        for reg in self.regs {
+            state.write_u32(reg);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"COP0", "COP0")?;
+        for reg in &mut self.regs {
+            *reg = state.read_u32()?;
+        }
+
+        // The flags are mirrors of SR and DCIC
+        self.update_status();
+        self.update_breakpoints();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_vectors_after_reset() {
+        let cop0 = Cop0::new();
+
+        assert_eq!(cop0.vector_address(Vector::TlbMiss), 0xbfc0_0100);
+        assert_eq!(cop0.vector_address(Vector::Debug), 0xbfc0_0140);
+        assert_eq!(cop0.vector_address(Vector::General), 0xbfc0_0180);
+    }
+
+    #[test]
+    fn test_clearing_bev_selects_ram_vectors() {
+        let mut cop0 = Cop0::new();
+        cop0.write_reg(STATUS as u32, 0).unwrap();
+
+        assert_eq!(cop0.vector_address(Vector::TlbMiss), 0x8000_0000);
+        assert_eq!(cop0.vector_address(Vector::Debug), 0x8000_0040);
+        assert_eq!(cop0.vector_address(Vector::General), 0x8000_0080);
+
+        cop0.write_reg(STATUS as u32, 1 << 22).unwrap();
+        assert_eq!(cop0.vector_address(Vector::General), 0xbfc0_0180);
+    }
+
+    #[test]
+    fn test_break_uses_the_general_vector() {
+        let mut cop0 = Cop0::new();
+        assert_eq!(cop0.exception_handler(Exception::Breakpoint), 0xbfc0_0180);
+
+        cop0.write_reg(STATUS as u32, 0).unwrap();
+        assert_eq!(cop0.exception_handler(Exception::Breakpoint), 0x8000_0080);
+        assert_eq!(cop0.exception_handler(Exception::Syscall), 0x8000_0080);
+    }
+
+    #[test]
+    fn test_breakpoints_need_the_master_enables() {
+        let mut cop0 = Cop0::new();
+        cop0.write_reg(BPC as u32, 0x8001_0000).unwrap();
+        cop0.write_reg(BPCM as u32, 0xffff_fff0).unwrap();
+        cop0.write_reg(DCIC as u32, 1 << 24).unwrap();
+        assert!(!cop0.code_breakpoint(0x8001_0000));
+
+        cop0.write_reg(DCIC as u32, 0xc180_0000).unwrap();
+        assert!(!cop0.code_breakpoint(0x8001_0010));
+        assert_eq!(cop0.read_reg(DCIC as u32), Some(0xc180_0000));
+        assert!(cop0.code_breakpoint(0x8001_000c));
+        assert_eq!(cop0.read_reg(DCIC as u32), Some(0xc180_0003));
+    }
+
+    #[test]
+    fn test_data_breakpoints_check_the_direction() {
+        let mut cop0 = Cop0::new();
+        cop0.write_reg(BDA as u32, 0x1f80_1070).unwrap();
+        cop0.write_reg(BDAM as u32, 0x1fff_fffc).unwrap();
+        cop0.write_reg(DCIC as u32, 0xca80_0000).unwrap();
+
+        assert!(!cop0.data_breakpoint(0x1f80_1070, false));
+        assert!(!cop0.data_breakpoint(0x1f80_1074, true));
+        assert!(cop0.data_breakpoint(0xbf80_1072, true));
+        assert_eq!(cop0.read_reg(DCIC as u32), Some(0xca80_0015));
+    }
+
+    #[test]
+    fn test_exception_does_not_change_bev() {
+        let mut cop0 = Cop0::new();
+        cop0.write_reg(STATUS as u32, 0x0000_0401).unwrap();
+
+        cop0.enter_exception(Exception::Interrupt, 0x8001_0000, false, 0);
+
+        assert!(!cop0.boot_vectors);
+        assert_eq!(cop0.regs[EPC], 0x8001_0000);
+        assert_eq!(cop0.exception_handler(Exception::Interrupt), 0x8000_0080);
+    }
 }
@@ -1,6 +1,8 @@
 use bitfield::bitfield;
 use crustationlogger::*;
 
+use crate::state::{Savestate, StateError, StateReader, StateWriter};
+
 mod division;
 mod operations;
 
@@ -573,6 +575,35 @@ impl Gte {
         self.cr[31] = self.flags.0;
     }
 
+    /// Cycles taken by each command, including the COP2 instruction itself
+    pub fn command_cycles(function: u32) -> u32 {
+        match function {
+            0x01 => 15,
+            0x06 => 8,
+            0x0c => 6,
+            0x10 => 8,
+            0x11 => 8,
+            0x12 => 8,
+            0x13 => 19,
+            0x14 => 13,
+            0x16 => 44,
+            0x1b => 17,
+            0x1c => 11,
+            0x1e => 14,
+            0x20 => 30,
+            0x28 => 5,
+            0x29 => 8,
+            0x2a => 17,
+            0x2d => 5,
+            0x2e => 6,
+            0x30 => 23,
+            0x3d => 5,
+            0x3e => 5,
+            0x3f => 39,
+            _ => 1,
+        }
+    }
+
     fn sat5(cc: i16) -> u8 {
         if cc < 0 {
             0
@@ -591,3 +622,138 @@ impl Gte {
         self.current_instruction & (1 << 19) != 0
     }
 }
+
+impl RGB {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&[self.r, self.g, self.b, self.code]);
+    }
+
+    fn load(state: &mut StateReader) -> Result<RGB, StateError> {
+        let mut bytes = [0; 4];
+        state.read_bytes(&mut bytes)?;
+
+        Ok(RGB {
+            r: bytes[0],
+            g: bytes[1],
+            b: bytes[2],
+            code: bytes[3],
+        })
+    }
+}
+
+fn save_i16s(state: &mut StateWriter, values: &[i16]) {
+    for &value in values {
+        state.write_i16(value);
+    }
+}
+
+fn load_i16s(state: &mut StateReader, values: &mut [i16]) -> Result<(), StateError> {
+    for value in values {
+        *value = state.read_i16()?;
+    }
+    Ok(())
+}
+
+fn save_i32s(state: &mut StateWriter, values: &[i32]) {
+    for &value in values {
+        state.write_i32(value);
+    }
+}
+
+fn load_i32s(state: &mut StateReader, values: &mut [i32]) -> Result<(), StateError> {
+    for value in values {
+        *value = state.read_i32()?;
+    }
+    Ok(())
+}
+
+impl Savestate for Gte {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"GTE ");
+        state.write_u32(self.current_instruction);
+        for cr in self.cr {
+            state.write_u32(cr);
+        }
+
+        for matrix in [&self.rotation, &self.light, &self.color] {
+            save_i16s(state, matrix.as_flattened());
+        }
+        for vector in [&self.t, &self.b, &self.fc, &self.null] {
+            save_i32s(state, vector);
+        }
+
+        state.write_i32(self.ofx);
+        state.write_i32(self.ofy);
+        state.write_u16(self.h);
+        state.write_i16(self.dqa);
+        state.write_i32(self.dqb);
+        state.write_i16(self.zsf3);
+        state.write_i16(self.zsf4);
+
+        save_i16s(state, self.vectors.as_flattened());
+        self.rgb.save(state);
+        state.write_u16(self.otz);
+
+        save_i16s(state, &self.ir);
+        for xy in self.xy_fifo {
+            state.write_i16(xy.x);
+            state.write_i16(xy.y);
+        }
+        for z in self.z_fifo {
+            state.write_u16(z);
+        }
+        for rgb in &self.rgb_fifo {
+            rgb.save(state);
+        }
+        save_i32s(state, &self.mac);
+        state.write_u32(self.lzcs);
+        state.write_u32(self.lzcr);
+        state.write_u32(self.r23);
+        state.write_u32(self.flags.0);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"GTE ", "GTE")?;
+        self.current_instruction = state.read_u32()?;
+        for cr in &mut self.cr {
+            *cr = state.read_u32()?;
+        }
+
+        for matrix in [&mut self.rotation, &mut self.light, &mut self.color] {
+            load_i16s(state, matrix.as_flattened_mut())?;
+        }
+        for vector in [&mut self.t, &mut self.b, &mut self.fc, &mut self.null] {
+            load_i32s(state, vector)?;
+        }
+
+        self.ofx = state.read_i32()?;
+        self.ofy = state.read_i32()?;
+        self.h = state.read_u16()?;
+        self.dqa = state.read_i16()?;
+        self.dqb = state.read_i32()?;
+        self.zsf3 = state.read_i16()?;
+        self.zsf4 = state.read_i16()?;
+
+        load_i16s(state, self.vectors.as_flattened_mut())?;
+        self.rgb = RGB::load(state)?;
+        self.otz = state.read_u16()?;
+
+        load_i16s(state, &mut self.ir)?;
+        for xy in &mut self.xy_fifo {
+            xy.x = state.read_i16()?;
+            xy.y = state.read_i16()?;
+        }
+        for z in &mut self.z_fifo {
+            *z = state.read_u16()?;
+        }
+        for rgb in &mut self.rgb_fifo {
+            *rgb = RGB::load(state)?;
+        }
+        load_i32s(state, &mut self.mac)?;
+        self.lzcs = state.read_u32()?;
+        self.lzcr = state.read_u32()?;
+        self.r23 = state.read_u32()?;
+        self.flags.0 = state.read_u32()?;
+        Ok(())
+    }
+}
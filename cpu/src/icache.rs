@@ -1,3 +1,5 @@
+use crate::state::{Savestate, StateError, StateReader, StateWriter};
+
 #[derive(Copy, Clone)]
 struct Entry {
     tag: u32,
@@ -56,9 +58,70 @@ impl InstructionCache {
         entry.data = value;
     }
 
+    /// Invalidates the entry caching `pc`, if any.
+    /// Returns true if a valid entry was dropped.
+    pub fn invalidate(&mut self, pc: u32) -> bool {
+        let pc = pc & !(1 << 31);
+
+        let entry_number = ((pc >> 2) & 0x3ff) as usize;
+        let entry = &mut self.entries[entry_number];
+
+        if entry.valid && entry.tag == pc >> 12 {
+            entry.valid = false;
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn flush(&mut self) {
         for entry in &mut self.entries {
             entry.valid = false;
         }
     }
 }
+
+impl Savestate for InstructionCache {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"ICAC");
+        for entry in &self.entries {
+            state.write_u32(entry.tag);
+            state.write_u32(entry.data);
+            state.write_bool(entry.valid);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"ICAC", "I-Cache")?;
+        for entry in &mut self.entries {
+            entry.tag = state.read_u32()?;
+            entry.data = state.read_u32()?;
+            entry.valid = state.read_bool()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalidate_drops_matching_entry() {
+        let mut icache = InstructionCache::new();
+        icache.store(0x8001_0000, 0x2408_0001);
+
+        assert!(icache.invalidate(0x0001_0000));
+        assert_eq!(icache.load(0x8001_0000), None);
+    }
+
+    #[test]
+    fn invalidate_keeps_entries_with_another_tag() {
+        let mut icache = InstructionCache::new();
+        icache.store(0x8001_0000, 0x2408_0001);
+
+        // Same line, different tag
+        assert!(!icache.invalidate(0x8002_0000));
+        assert_eq!(icache.load(0x8001_0000), Some(0x2408_0001));
+    }
+}
@@ -1,6 +1,7 @@
 mod arith;
 mod biu;
 mod branch;
+pub mod breakpoints;
 mod cop;
 mod cop0;
 pub mod gte;
@@ -8,30 +9,117 @@ mod icache;
 mod instruction;
 mod load_store;
 mod scratchpad;
-
+mod segment;
+pub mod state;
+pub mod stats;
+#[cfg(test)]
+mod test_bus;
+pub mod trace;
+pub mod tty;
+
+#[cfg(feature = "savestates")]
+use std::fs::File;
+use std::io::{Read, Write};
+#[cfg(feature = "savestates")]
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
+use std::time::Duration;
 // use std::time::{SystemTime, UNIX_EPOCH};
 
 use crustationlogger::*;
 
 use biu::BIUCacheControl;
-use cop0::{Cop0, Exception};
+use breakpoints::{Breakpoints, DebugStop};
+use cop0::{Cop0, Exception, Vector};
 use gte::Gte;
 use icache::InstructionCache;
 use instruction::Instruction;
 use scratchpad::Scratchpad;
+use segment::Segment;
+use state::{Savestate, StateError, StateReader, StateWriter, MAGIC, VERSION};
+use stats::InstructionCounts;
+use trace::{TraceRegisters, Tracer};
+use tty::Tty;
 
 pub trait PsxBus {
     fn read<const T: u32>(&self, address: u32) -> u32;
     fn write<const T: u32>(&self, address: u32, value: u32);
     fn update_cycles(&self, cycles: u64);
+    /// Cycles since power on: the ones passed to `update_cycles`, and the
+    /// ones the bus adds itself, e.g. for wait states
+    fn cycles(&self) -> u64;
+
+    /// Whether the interrupt controller interrupts the CPU (COP0 CAUSE
+    /// bit 10). Checked after every instruction.
+    fn interrupt_pending(&self) -> bool {
+        false
+    }
+
+    /// A BIOS function was called: `vector` is 0xa0, 0xb0 or 0xc0, `function`
+    /// its number (t1), `args` a0-a3 and `ra` where it returns to
+    fn bios_call(&self, _vector: u32, _function: u32, _args: [u32; 4], _ra: u32) {}
+
+    /// The CPU is about to run the instruction at `pc`, which makes the
+    /// accesses that follow. Called before every instruction.
+    fn instruction_pc(&self, _pc: u32) {}
+
+    /// The CPU reached `Cpu::pc_hook`, before running the instruction there.
+    /// It may change the CPU state, e.g. to start a side-loaded executable.
+    fn pc_hook(&self, _cpu: &mut Cpu<Self>)
+    where
+        Self: Sized,
+    {
+    }
+
+    /// Appends the state of everything on the bus, after the CPU's
+    fn save_state(&self, _state: &mut StateWriter) {}
+    fn load_state(&self, _state: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
 }
 
 pub enum CpuCommand {
     Break,
-    Irq(u32),
+    /// Stops `run` like Break, for the debugger
+    Debug,
+    /// Saves the machine state to a file, between two instructions
+    #[cfg(feature = "savestates")]
+    SaveState(PathBuf),
+    #[cfg(feature = "savestates")]
+    LoadState(PathBuf),
 }
 
+/// How stores hitting instructions held in the I-Cache are handled
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IcacheMode {
+    /// Stores don't touch the I-Cache, stale instructions keep running until
+    /// the line gets replaced. Some loaders depend on the cache being flushed
+    /// and break with this mode.
+    Fast,
+    /// Stores to cached addresses invalidate the matching entry
+    Accurate,
+}
+
+impl IcacheMode {
+    pub fn from_name(name: &str) -> Option<IcacheMode> {
+        match name {
+            "fast" => Some(IcacheMode::Fast),
+            "accurate" => Some(IcacheMode::Accurate),
+            _ => None,
+        }
+    }
+}
+
+/// A register write waiting for the end of the next instruction. Loads,
+/// MFC0, MFC2 and CFC2 go through it.
+///
+/// `Cpu::load_delay_slot[1]` is filled by the current instruction,
+/// `[0]` by the previous one and lands once the current one is done.
+/// Register 32 is a sink for empty slots. The rules:
+/// - a load to a register pending in `[0]` replaces that load;
+/// - any other write to that register (ALU, MFHI...) cancels it;
+/// - LWL/LWR merge with the value pending in `[0]`, so pairs run back to
+///   back without the old register contents leaking in.
 #[derive(Copy, Clone, Eq, PartialEq)]
 struct LoadDelaySlot {
     register: u32,
@@ -50,21 +138,43 @@ pub struct Cpu<T: PsxBus> {
     pub regs: [u32; 33],
     pub hi: u32,
     pub lo: u32,
+    /// Bus cycle at which the multiplier/divider result is in HI/LO
+    muldiv_done: u64,
+    /// Bus cycle at which the running GTE command is done. Reading its
+    /// results or starting another command waits for it.
+    gte_done: u64,
 
     pub cop0: Cop0,
     pub gte: Gte,
 
     icache: InstructionCache,
+    pub icache_mode: IcacheMode,
     dcache: Scratchpad,
 
     biu_cc: BIUCacheControl,
-    i_stat: u32,
-    i_mask: u32,
 
     current_instruction: Instruction,
+    /// Address of the instruction being executed
+    current_pc: u32,
+    /// Set when a Break command is received, stops `run`
+    break_requested: bool,
+    pub breakpoints: Breakpoints,
+    /// Why the last `run` stopped for the debugger, if it did
+    debug_stop: Option<DebugStop>,
+    /// `PsxBus::pc_hook` is called once when execution gets there
+    pub pc_hook: Option<u32>,
+    /// Records the last instructions when set, see `trace`
+    pub tracer: Option<Tracer>,
+    pub tty: Tty,
     branch_delay_slot: Option<(u32, u32)>,
     load_delay_slot: [LoadDelaySlot; 2],
     in_delay: bool,
+    /// Executed since power on, for statistics. Not saved in states.
+    instructions: u64,
+    /// Executed since power on by class, see `stats`
+    counts: InstructionCounts,
+    /// Host time spent in GTE commands, measured when set (for benchmarks)
+    pub gte_time: Option<Duration>,
 }
 
 impl<T: PsxBus> Cpu<T> {
@@ -82,18 +192,26 @@ impl<T: PsxBus> Cpu<T> {
             regs: [0; 33],
             hi: 0,
             lo: 0,
+            muldiv_done: 0,
+            gte_done: 0,
 
             cop0: Cop0::new(),
             gte: Gte::new(),
 
             icache: InstructionCache::new(),
+            icache_mode: IcacheMode::Fast,
             dcache: Scratchpad::new(),
 
             biu_cc: BIUCacheControl(0),
-            i_stat: 0,
-            i_mask: 0,
 
             current_instruction: Instruction(0),
+            current_pc: 0xbfc0_0000,
+            break_requested: false,
+            breakpoints: Breakpoints::new(),
+            debug_stop: None,
+            pc_hook: None,
+            tracer: None,
+            tty: Tty::new(),
             branch_delay_slot: None,
             load_delay_slot: [
                 LoadDelaySlot {
@@ -106,6 +224,9 @@ impl<T: PsxBus> Cpu<T> {
                 },
             ],
             in_delay: false,
+            instructions: 0,
+            counts: InstructionCounts::default(),
+            gte_time: None,
             // ips: 0,
             // ips_start: SystemTime::now()
             //     .duration_since(UNIX_EPOCH)
@@ -118,6 +239,125 @@ impl<T: PsxBus> Cpu<T> {
         self.bus = bus as *const T;
     }
 
+    /// Writes the state of the whole machine: the CPU and its coprocessors
+    /// and caches, then the bus devices.
+    pub fn save_state(&mut self, writer: &mut dyn Write) -> Result<(), StateError> {
+        let mut state = StateWriter::new();
+        state.write_bytes(MAGIC);
+        state.write_u32(VERSION);
+
+        self.save_registers(&mut state);
+        self.cop0.save_state(&mut state);
+        self.gte.save_state(&mut state);
+        self.icache.save_state(&mut state);
+        self.dcache.save_state(&mut state);
+        unsafe {
+            (*self.bus).save_state(&mut state);
+        }
+
+        writer.write_all(&state.into_inner())?;
+        Ok(())
+    }
+
+    /// Restores a state written by `save_state`. States from other versions
+    /// are rejected before anything is changed, but if a later section turns
+    /// out to be corrupted the machine is left half restored.
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> Result<(), StateError> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        let mut state = StateReader::new(&data);
+
+        let mut magic = [0; 8];
+        state.read_bytes(&mut magic).map_err(|_| StateError::BadMagic)?;
+        if magic != *MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = state.read_u32()?;
+        if version != VERSION {
+            return Err(StateError::Version(version));
+        }
+
+        self.load_registers(&mut state)?;
+        self.cop0.load_state(&mut state)?;
+        self.gte.load_state(&mut state)?;
+        self.icache.load_state(&mut state)?;
+        self.dcache.load_state(&mut state)?;
+        unsafe {
+            (*self.bus).load_state(&mut state)?;
+        }
+
+        match state.is_empty() {
+            true => Ok(()),
+            false => Err(StateError::Invalid("trailing data")),
+        }
+    }
+
+    #[cfg(feature = "savestates")]
+    fn save_state_file(&mut self, path: &Path) -> Result<(), StateError> {
+        self.save_state(&mut File::create(path)?)
+    }
+
+    #[cfg(feature = "savestates")]
+    fn load_state_file(&mut self, path: &Path) -> Result<(), StateError> {
+        self.load_state(&mut File::open(path)?)
+    }
+
+    fn save_registers(&self, state: &mut StateWriter) {
+        state.tag(b"CPU ");
+        state.write_u32(self.pc);
+        for reg in self.regs {
+            state.write_u32(reg);
+        }
+        state.write_u32(self.hi);
+        state.write_u32(self.lo);
+        state.write_u64(self.muldiv_done);
+        state.write_u64(self.gte_done);
+
+        state.write_u32(self.biu_cc.0);
+
+        state.write_u32(self.current_instruction.0);
+        state.write_u32(self.current_pc);
+        state.write_bool(self.branch_delay_slot.is_some());
+        let (target, instruction) = self.branch_delay_slot.unwrap_or((0, 0));
+        state.write_u32(target);
+        state.write_u32(instruction);
+        for slot in self.load_delay_slot {
+            state.write_u32(slot.register);
+            state.write_u32(slot.value);
+        }
+        state.write_bool(self.in_delay);
+    }
+
+    fn load_registers(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"CPU ", "CPU")?;
+        self.pc = state.read_u32()?;
+        for reg in &mut self.regs {
+            *reg = state.read_u32()?;
+        }
+        self.hi = state.read_u32()?;
+        self.lo = state.read_u32()?;
+        self.muldiv_done = state.read_u64()?;
+        self.gte_done = state.read_u64()?;
+
+        self.biu_cc.0 = state.read_u32()?;
+
+        self.current_instruction.0 = state.read_u32()?;
+        self.current_pc = state.read_u32()?;
+        let in_branch_delay = state.read_bool()?;
+        let target = state.read_u32()?;
+        let instruction = state.read_u32()?;
+        self.branch_delay_slot = in_branch_delay.then_some((target, instruction));
+        for slot in &mut self.load_delay_slot {
+            slot.register = state.read_u32()?;
+            slot.value = state.read_u32()?;
+            if slot.register > 32 {
+                return Err(StateError::Invalid("load delay register"));
+            }
+        }
+        self.in_delay = state.read_bool()?;
+        Ok(())
+    }
+
     #[inline(always)]
     pub fn fetch_at_pc(&mut self) -> u32 {
         // Uncomment for hardware-faithful implementation
@@ -125,7 +365,7 @@ impl<T: PsxBus> Cpu<T> {
         //     return self.load::<u32>(self.pc);
         // }
 
-        if self.pc >= 0xa000_0000 {
+        if !Segment::of(self.pc).cached() {
             return self.load::<4>(self.pc);
         }
 
@@ -153,10 +393,13 @@ impl<T: PsxBus> Cpu<T> {
         }
     }
 
+    /// Runs until a Break command is received
     pub fn run(&mut self) {
-        loop {
+        while !self.break_requested {
             self.cycle();
         }
+
+        self.break_requested = false;
     }
 
     pub fn run_until(&mut self, desired_pc: u32) {
@@ -169,32 +412,107 @@ impl<T: PsxBus> Cpu<T> {
         }
     }
 
-    pub fn cycle(&mut self) {
-        if let Ok(command) = self.command_rx.try_recv() {
-            match command {
-                CpuCommand::Break => {
-                    // println!();
-                    // debug::Debugger::enter(self);
+    fn handle_command(&mut self, command: CpuCommand) {
+        match command {
+            CpuCommand::Break => {
+                // println!();
+                // debug::Debugger::enter(self);
+                self.break_requested = true;
+            }
+            CpuCommand::Debug => self.stop_for_debugger(DebugStop::Requested),
+            #[cfg(feature = "savestates")]
+            CpuCommand::SaveState(path) => match self.save_state_file(&path) {
+                Ok(()) => info!(self.logger, "Saved state to {}", path.display()),
+                Err(err) => {
+                    err!(self.logger, "Could not save state to {}: {}", path.display(), err)
                 }
-                CpuCommand::Irq(n) => {
-                    self.request_interrupt(n);
+            },
+            #[cfg(feature = "savestates")]
+            CpuCommand::LoadState(path) => match self.load_state_file(&path) {
+                Ok(()) => info!(self.logger, "Loaded state from {}", path.display()),
+                Err(err) => {
+                    err!(self.logger, "Could not load state from {}: {}", path.display(), err)
                 }
+            },
+        }
+    }
+
+    /// Called once emulation has stopped for good. Save states still queued
+    /// (e.g. a hotkey pressed in the frame the window was closed) are
+    /// written, the other commands are dropped.
+    pub fn flush_commands(&mut self) {
+        while let Ok(command) = self.command_rx.try_recv() {
+            match command {
+                #[cfg(feature = "savestates")]
+                CpuCommand::SaveState(_) => self.handle_command(command),
+                #[cfg(feature = "savestates")]
+                CpuCommand::LoadState(path) => {
+                    info!(self.logger, "Not loading {}, shutting down", path.display())
+                }
+                CpuCommand::Break | CpuCommand::Debug => {}
             }
         }
+        self.break_requested = false;
+        self.debug_stop = None;
+    }
+
+    pub fn cycle(&mut self) {
+        if let Ok(command) = self.command_rx.try_recv() {
+            self.handle_command(command);
+        }
 
         // if debug::Debugger::should_break(self) {
         //     debug::Debugger::enter(self);
         // }
 
-        self.step();
+        if self.pc_hook.is_some_and(|pc| pc == self.pc()) {
+            self.pc_hook = None;
+            unsafe {
+                (*self.bus).pc_hook(self);
+            }
+            if self.break_requested {
+                return;
+            }
+        }
 
-        // match self.pc() {
-        //     0xa0 => Bios::call_a(self),
-        //     0xb0 => Bios::call_b(self),
-        //     0xc0 => Bios::call_c(self),
-        //     _ => {}
-        // }
+        if self.breakpoints.executing() {
+            let pc = self.pc();
+            if let Some(id) = self.breakpoints.check_execute(pc, &self.regs) {
+                return self.stop_for_debugger(DebugStop::Breakpoint(id));
+            }
+        }
 
+        let pc = self.pc();
+        let traced = self.tracer.as_ref().is_some_and(|tracer| tracer.traces(pc));
+        let before = traced.then(|| self.trace_registers());
+        self.step();
+        if let Some(before) = before {
+            let after = self.trace_registers();
+            let instruction = self.current_instruction.0;
+            if let Some(tracer) = &mut self.tracer {
+                tracer.record(self.current_pc, instruction, &before, &after);
+            }
+        }
+        if self.tracer.as_ref().is_some_and(|tracer| tracer.fault) {
+            self.dump_trace();
+        }
+        self.instructions += 1;
+
+        // BIOS functions are called through A0h, B0h and C0h, with their
+        // number in t1. Checked once the jump's delay slot has run.
+        let vector = self.pc() & 0x1fff_ffff;
+        if let 0xa0 | 0xb0 | 0xc0 = vector {
+            let function = self.regs[9];
+            if let (0xa0, 0x3c) | (0xb0, 0x3d) = (vector, function) {
+                self.tty.putchar(self.regs[4] as u8);
+            }
+            let args = [self.regs[4], self.regs[5], self.regs[6], self.regs[7]];
+            unsafe {
+                (*self.bus).bios_call(vector, function, args, self.regs[31]);
+            }
+        }
+
+        self.check_interrupts();
         if self.cop0.should_interrupt() {
             self.interrupt();
         }
@@ -204,6 +522,11 @@ impl<T: PsxBus> Cpu<T> {
         }
     }
 
+    /// See `PsxBus::cycles`
+    fn bus_cycles(&self) -> u64 {
+        unsafe { (*self.bus).cycles() }
+    }
+
     #[inline(always)]
     pub fn pc(&self) -> u32 {
         if let Some((pc, _)) = self.branch_delay_slot {
@@ -213,116 +536,299 @@ impl<T: PsxBus> Cpu<T> {
         }
     }
 
+    fn trace_registers(&self) -> TraceRegisters {
+        let mut regs = [0; 34];
+        regs[..32].copy_from_slice(&self.regs[..32]);
+        regs[32] = self.hi;
+        regs[33] = self.lo;
+        regs
+    }
+
+    /// Dumps the trace once the faulting instruction is recorded
+    fn trace_fault(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.fault = true;
+        }
+    }
+
+    /// Writes the execution trace to its file, if tracing. Returns whether
+    /// it was written.
+    pub fn dump_trace(&mut self) -> bool {
+        let Some(tracer) = &mut self.tracer else {
+            return false;
+        };
+        tracer.fault = false;
+        match tracer.dump() {
+            Ok(()) => {
+                info!(self.logger, "Trace written to {}", tracer.path().display());
+                true
+            }
+            Err(err) => {
+                err!(self.logger, "Could not write the trace: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Jumps to `pc`, dropping a pending branch. For debuggers.
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+        self.branch_delay_slot = None;
+    }
+
+    /// Drops the cached instructions, after code was patched behind the
+    /// CPU's back
+    pub fn flush_icache(&mut self) {
+        self.icache.flush();
+    }
+
+    /// Address of the instruction currently being executed (or the last one
+    /// executed, when called between steps)
+    #[inline(always)]
+    pub fn current_pc(&self) -> u32 {
+        self.current_pc
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn instruction_counts(&self) -> InstructionCounts {
+        self.counts
+    }
+
+    /// Stops `run` after the current instruction
+    /// Stops `run` before the next instruction, `take_debug_stop` then
+    /// returns `stop`
+    pub fn stop_for_debugger(&mut self, stop: DebugStop) {
+        self.debug_stop = Some(stop);
+        self.break_requested = true;
+    }
+
+    /// Runs one instruction for the debugger, even if there's a breakpoint
+    /// on it. Returns the watchpoint it hit, if any.
+    pub fn debug_step(&mut self) -> Option<DebugStop> {
+        if self.breakpoints.executing() {
+            self.breakpoints.skip(self.pc());
+        }
+        self.cycle();
+        self.break_requested = false;
+        self.debug_stop.take()
+    }
+
+    /// Why the last `run` stopped for the debugger. None if it stopped for
+    /// another reason, e.g. quitting.
+    pub fn take_debug_stop(&mut self) -> Option<DebugStop> {
+        self.debug_stop.take()
+    }
+
     #[inline(always)]
     pub fn step(&mut self) {
-        if let Some((_pc, ins)) = self.branch_delay_slot {
+        let (pc, in_delay) = match self.branch_delay_slot {
+            Some((pc, _)) => (pc, true),
+            None => (self.pc, false),
+        };
+        if self.cop0.code_breakpoint(pc) {
+            self.breakpoint_exception(pc, in_delay);
+            return;
+        }
+
+        if let Some((pc, ins)) = self.branch_delay_slot {
+            self.current_pc = pc;
             self.in_delay = true;
             self.current_instruction.0 = ins;
             self.branch_delay_slot = None;
         } else {
             self.in_delay = false;
 
-            if self.pc % 4 != 0 {
-                self.exception(Exception::AddressErrorLoad);
+            if !self.check_access(self.pc, 4, Exception::AddressErrorLoad) {
                 return;
             }
 
+            self.current_pc = self.pc;
             self.current_instruction.0 = self.fetch_at_pc();
             self.pc = self.pc.wrapping_add(4);
         }
 
-        match self.current_instruction.opcode() {
-            0x00 => match self.current_instruction.special_opcode() {
-                0x00 => self.ins_sll(),
-                0x02 => self.ins_srl(),
-                0x03 => self.ins_sra(),
-                0x04 => self.ins_sllv(),
-                0x06 => self.ins_srlv(),
-                0x07 => self.ins_srav(),
-                0x08 => self.ins_jr(),
-                0x09 => self.ins_jalr(),
-                0x0C => self.ins_syscall(),
-                0x0D => self.ins_break(),
-                0x10 => self.ins_mfhi(),
-                0x11 => self.ins_mthi(),
-                0x12 => self.ins_mflo(),
-                0x13 => self.ins_mtlo(),
-                0x18 => self.ins_mult(),
-                0x19 => self.ins_multu(),
-                0x1A => self.ins_div(),
-                0x1B => self.ins_divu(),
-                0x20 => self.ins_add(),
-                0x21 => self.ins_addu(),
-                0x22 => self.ins_sub(),
-                0x23 => self.ins_subu(),
-                0x24 => self.ins_and(),
-                0x25 => self.ins_or(),
-                0x26 => self.ins_xor(),
-                0x27 => self.ins_nor(),
-                0x2A => self.ins_slt(),
-                0x2B => self.ins_sltu(),
-                _ => {
-                    warn!(
-                        self.logger,
-                        "Unhandled instruction {:08x} at {:08x}",
-                        self.current_instruction.0,
-                        self.pc
-                    );
-                    self.exception(Exception::ReservedInstruction);
-                }
-            },
-            0x01 => self.ins_bcondz(),
-            0x02 => self.ins_j(),
-            0x03 => self.ins_jal(),
-            0x04 => self.ins_beq(),
-            0x05 => self.ins_bne(),
-            0x06 => self.ins_blez(),
-            0x07 => self.ins_bgtz(),
-            0x08 => self.ins_addi(),
-            0x09 => self.ins_addiu(),
-            0x0A => self.ins_slti(),
-            0x0B => self.ins_sltiu(),
-            0x0C => self.ins_andi(),
-            0x0D => self.ins_ori(),
-            0x0E => self.ins_xori(),
-            0x0F => self.ins_lui(),
-            0x10 => self.ins_cop0(),
-            0x11 => self.ins_cop1(),
-            0x12 => self.ins_cop2(),
-            0x13 => self.ins_cop3(),
-            0x20 => self.ins_lb(),
-            0x21 => self.ins_lh(),
-            0x22 => self.ins_lwl(),
-            0x23 => self.ins_lw(),
-            0x24 => self.ins_lbu(),
-            0x25 => self.ins_lhu(),
-            0x26 => self.ins_lwr(),
-            0x28 => self.ins_sb(),
-            0x29 => self.ins_sh(),
-            0x2A => self.ins_swl(),
-            0x2B => self.ins_sw(),
-            0x2E => self.ins_swr(),
-            0x30 => self.ins_lwc0(),
-            0x31 => self.ins_lwc1(),
-            0x32 => self.ins_lwc2(),
-            0x33 => self.ins_lwc3(),
-            0x38 => self.ins_swc0(),
-            0x39 => self.ins_swc1(),
-            0x3A => self.ins_swc2(),
-            0x3B => self.ins_swc3(),
-            _ => {
-                warn!(
-                    self.logger,
-                    "Unhandled instruction {:08x} at {:08x}", self.current_instruction.0, self.pc
-                );
-                self.exception(Exception::ReservedInstruction);
-            }
+        unsafe {
+            (*self.bus).instruction_pc(self.current_pc);
         }
 
+        self.counts.record(self.current_instruction.0);
+        let opcode = self.current_instruction.opcode() as usize;
+        Self::PRIMARY_OPCODES[opcode](self);
+
         self.in_delay = false;
         self.load_delays();
     }
 
+    /// Handlers for the primary opcode (bits 26-31). Opcode 0 is further
+    /// decoded through `SPECIAL_OPCODES`.
+    const PRIMARY_OPCODES: [fn(&mut Cpu<T>); 64] = [
+        /* 0x00 */
+        Cpu::ins_special,
+        Cpu::ins_bcondz,
+        Cpu::ins_j,
+        Cpu::ins_jal,
+        Cpu::ins_beq,
+        Cpu::ins_bne,
+        Cpu::ins_blez,
+        Cpu::ins_bgtz,
+        /* 0x08 */
+        Cpu::ins_addi,
+        Cpu::ins_addiu,
+        Cpu::ins_slti,
+        Cpu::ins_sltiu,
+        Cpu::ins_andi,
+        Cpu::ins_ori,
+        Cpu::ins_xori,
+        Cpu::ins_lui,
+        /* 0x10 */
+        Cpu::ins_cop0,
+        Cpu::ins_cop1,
+        Cpu::ins_cop2,
+        Cpu::ins_cop3,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        /* 0x18 */
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        /* 0x20 */
+        Cpu::ins_lb,
+        Cpu::ins_lh,
+        Cpu::ins_lwl,
+        Cpu::ins_lw,
+        Cpu::ins_lbu,
+        Cpu::ins_lhu,
+        Cpu::ins_lwr,
+        Cpu::ins_reserved,
+        /* 0x28 */
+        Cpu::ins_sb,
+        Cpu::ins_sh,
+        Cpu::ins_swl,
+        Cpu::ins_sw,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_swr,
+        Cpu::ins_reserved,
+        /* 0x30 */
+        Cpu::ins_lwc0,
+        Cpu::ins_lwc1,
+        Cpu::ins_lwc2,
+        Cpu::ins_lwc3,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        /* 0x38 */
+        Cpu::ins_swc0,
+        Cpu::ins_swc1,
+        Cpu::ins_swc2,
+        Cpu::ins_swc3,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+    ];
+
+    /// Handlers for the SPECIAL function field (bits 0-5) of opcode 0.
+    const SPECIAL_OPCODES: [fn(&mut Cpu<T>); 64] = [
+        /* 0x00 */
+        Cpu::ins_sll,
+        Cpu::ins_reserved,
+        Cpu::ins_srl,
+        Cpu::ins_sra,
+        Cpu::ins_sllv,
+        Cpu::ins_reserved,
+        Cpu::ins_srlv,
+        Cpu::ins_srav,
+        /* 0x08 */
+        Cpu::ins_jr,
+        Cpu::ins_jalr,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_syscall,
+        Cpu::ins_break,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        /* 0x10 */
+        Cpu::ins_mfhi,
+        Cpu::ins_mthi,
+        Cpu::ins_mflo,
+        Cpu::ins_mtlo,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        /* 0x18 */
+        Cpu::ins_mult,
+        Cpu::ins_multu,
+        Cpu::ins_div,
+        Cpu::ins_divu,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        /* 0x20 */
+        Cpu::ins_add,
+        Cpu::ins_addu,
+        Cpu::ins_sub,
+        Cpu::ins_subu,
+        Cpu::ins_and,
+        Cpu::ins_or,
+        Cpu::ins_xor,
+        Cpu::ins_nor,
+        /* 0x28 */
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_slt,
+        Cpu::ins_sltu,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        /* 0x30 */
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        /* 0x38 */
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+        Cpu::ins_reserved,
+    ];
+
+    #[inline(always)]
+    fn ins_special(&mut self) {
+        let function = self.current_instruction.special_opcode() as usize;
+        Self::SPECIAL_OPCODES[function](self);
+    }
+
+    fn ins_reserved(&mut self) {
+        warn!(
+            self.logger,
+            "Unhandled instruction {:08x} at {:08x}", self.current_instruction.0, self.pc
+        );
+        self.exception(Exception::ReservedInstruction);
+    }
+
     #[inline(always)]
     fn load_delays(&mut self) {
         self.regs[self.load_delay_slot[0].register as usize] = self.load_delay_slot[0].value;
@@ -331,31 +837,32 @@ impl<T: PsxBus> Cpu<T> {
         self.load_delay_slot[1].register = 32;
     }
 
-    pub fn request_interrupt(&mut self, irq_number: u32) {
-        self.i_stat |= 1 << irq_number;
-        self.check_interrupts();
-    }
-
+    /// Mirrors the interrupt controller in COP0 CAUSE bit 10
     #[inline(always)]
-    pub fn check_interrupts(&mut self) {
-        let stat = self.i_stat;
-        let mask = self.i_mask;
-
-        if stat & mask == 0 {
-            self.cop0.clear_interrupt(10);
-        } else {
+    fn check_interrupts(&mut self) {
+        if unsafe { (*self.bus).interrupt_pending() } {
             self.cop0.request_interrupt(10);
+        } else {
+            self.cop0.clear_interrupt(10);
         }
     }
 
     #[inline(always)]
     fn r_rs(&self) -> u32 {
-        self.regs[self.current_instruction.rs() as usize]
+        self.reg(self.current_instruction.rs())
     }
 
     #[inline(always)]
     fn r_rt(&self) -> u32 {
-        self.regs[self.current_instruction.rt() as usize]
+        self.reg(self.current_instruction.rt())
+    }
+
+    /// Reads a general purpose register without bounds checking.
+    /// Register fields are 5 bits wide, so the mask keeps the index within
+    /// the 33 entries of `regs` (r0-r31, plus the load delay sink).
+    #[inline(always)]
+    fn reg(&self, index: u32) -> u32 {
+        unsafe { *self.regs.get_unchecked((index & 0x1f) as usize) }
     }
 
     #[inline(always)]
@@ -364,7 +871,9 @@ impl<T: PsxBus> Cpu<T> {
             return;
         }
 
-        self.regs[reg as usize] = value;
+        unsafe {
+            *self.regs.get_unchecked_mut((reg & 0x1f) as usize) = value;
+        }
 
         if self.load_delay_slot[0].register == reg {
             self.load_delay_slot[0].register = 32;
@@ -1,6 +1,43 @@
-use crate::{Cpu, Exception, LoadDelaySlot, PsxBus};
+use crate::breakpoints::DebugStop;
+use crate::segment::Segment;
+use crate::{Cpu, Exception, IcacheMode, LoadDelaySlot, PsxBus};
+
+use crustationlogger::*;
 
 impl<B: PsxBus> Cpu<B> {
+    /// Load done by an instruction, checked against the watchpoints
+    #[inline(always)]
+    pub(crate) fn load_data<const T: u32>(&mut self, address: u32) -> u32 {
+        if self.breakpoints.watching() {
+            self.watch(address, T, false);
+        }
+        if self.cop0.data_breakpoint(address, false) {
+            self.breakpoint_exception(self.current_pc, self.in_delay);
+        }
+        self.load::<T>(address)
+    }
+
+    fn watch(&mut self, address: u32, size: u32, write: bool) {
+        if let Some(id) = self.breakpoints.check_access(address, size, write, &self.regs) {
+            self.stop_for_debugger(DebugStop::Watchpoint(id, address, write));
+        }
+    }
+
+    /// Whether an instruction may access `size` bytes at `address`: it must
+    /// be aligned, and in KUSEG when running in user mode. Raises `cause`
+    /// otherwise. LWL, LWR, SWL and SWR pass a size of 1.
+    #[inline(always)]
+    pub(crate) fn check_access(&mut self, address: u32, size: u32, cause: Exception) -> bool {
+        let misaligned = address & (size - 1) != 0;
+        let forbidden = self.cop0.is_user && Segment::of(address).kernel_only();
+        if misaligned || forbidden {
+            self.cop0.set_bad_address(address);
+            self.exception(cause);
+            return false;
+        }
+        true
+    }
+
     #[inline(always)]
     pub fn ls_address(&self) -> u32 {
         let imm = self.current_instruction.simm16() as u32;
@@ -9,32 +46,38 @@ impl<B: PsxBus> Cpu<B> {
 
     #[inline(always)]
     pub fn ins_lb(&mut self) {
-        let value = self.load::<1>(self.ls_address()) as i8 as u32;
-
-        self.delayed_load(self.current_instruction.rt(), value);
+        let address = self.ls_address();
+        if self.check_access(address, 1, Exception::AddressErrorLoad) {
+            let value = self.load_data::<1>(address) as i8 as u32;
+            self.delayed_load(self.current_instruction.rt(), value);
+        }
     }
 
     #[inline(always)]
     pub fn ins_lh(&mut self) {
         let address = self.ls_address();
-
-        if address % 2 == 0 {
-            let value = self.load::<2>(address) as i16 as u32;
+        if self.check_access(address, 2, Exception::AddressErrorLoad) {
+            let value = self.load_data::<2>(address) as i16 as u32;
             self.delayed_load(self.current_instruction.rt(), value);
-        } else {
-            self.exception(Exception::AddressErrorLoad);
         }
     }
 
+    /// LWL and LWR merge the loaded bytes with the current value of rt.
+    /// If a load to rt is still in its delay slot, they merge with the value
+    /// being loaded rather than the stale register, which lets unaligned
+    /// LWL/LWR pairs run back to back. The merged result is delayed as usual.
     pub fn ins_lwl(&mut self) {
         let addr = self.ls_address();
+        if !self.check_access(addr, 1, Exception::AddressErrorLoad) {
+            return;
+        }
         let cur_v = if self.load_delay_slot[0].register == self.current_instruction.rt() {
             self.load_delay_slot[0].value
         } else {
             self.r_rt()
         };
 
-        let aligned_word = self.load::<4>(addr & !3);
+        let aligned_word = self.load_data::<4>(addr & !3);
         let v = match addr & 3 {
             0 => (cur_v & 0x00ffffff) | (aligned_word << 24),
             1 => (cur_v & 0x0000ffff) | (aligned_word << 16),
@@ -49,42 +92,42 @@ impl<B: PsxBus> Cpu<B> {
     #[inline(always)]
     pub fn ins_lw(&mut self) {
         let address = self.ls_address();
-        if address % 4 == 0 {
-            let value = self.load::<4>(address);
+        if self.check_access(address, 4, Exception::AddressErrorLoad) {
+            let value = self.load_data::<4>(address);
             self.delayed_load(self.current_instruction.rt(), value);
-        } else {
-            self.exception(Exception::AddressErrorLoad);
         }
     }
 
     #[inline(always)]
     pub fn ins_lbu(&mut self) {
         let address = self.ls_address();
-        let value = self.load::<1>(address) as u32;
-
-        self.delayed_load(self.current_instruction.rt(), value);
+        if self.check_access(address, 1, Exception::AddressErrorLoad) {
+            let value = self.load_data::<1>(address);
+            self.delayed_load(self.current_instruction.rt(), value);
+        }
     }
 
     #[inline(always)]
     pub fn ins_lhu(&mut self) {
         let address = self.ls_address();
-        if address % 2 == 0 {
-            let value = self.load::<2>(address) as u32;
+        if self.check_access(address, 2, Exception::AddressErrorLoad) {
+            let value = self.load_data::<2>(address);
             self.delayed_load(self.current_instruction.rt(), value);
-        } else {
-            self.exception(Exception::AddressErrorLoad);
         }
     }
 
     pub fn ins_lwr(&mut self) {
         let addr = self.ls_address();
+        if !self.check_access(addr, 1, Exception::AddressErrorLoad) {
+            return;
+        }
         let cur_v = if self.load_delay_slot[0].register == self.current_instruction.rt() {
             self.load_delay_slot[0].value
         } else {
             self.r_rt()
         };
 
-        let aligned_word = self.load::<4>(addr & !3);
+        let aligned_word = self.load_data::<4>(addr & !3);
         let v = match addr & 3 {
             0 => aligned_word,
             1 => (cur_v & 0xff000000) | (aligned_word >> 8),
@@ -98,21 +141,25 @@ impl<B: PsxBus> Cpu<B> {
 
     #[inline(always)]
     pub fn ins_sb(&mut self) {
-        self.store::<1>(self.ls_address(), self.r_rt() & 0xff);
+        let address = self.ls_address();
+        if self.check_access(address, 1, Exception::AddressErrorStore) {
+            self.store::<1>(address, self.r_rt() & 0xff);
+        }
     }
 
     #[inline(always)]
     pub fn ins_sh(&mut self) {
         let address = self.ls_address();
-        if address % 2 == 0 {
+        if self.check_access(address, 2, Exception::AddressErrorStore) {
             self.store::<2>(address, self.r_rt() & 0xffff);
-        } else {
-            self.exception(Exception::AddressErrorStore);
         }
     }
 
     pub fn ins_swl(&mut self) {
         let addr = self.ls_address();
+        if !self.check_access(addr, 1, Exception::AddressErrorStore) {
+            return;
+        }
         let v = self.r_rt();
         let aligned_addr = addr & !3;
         let cur_v = self.load::<4>(aligned_addr);
@@ -131,16 +178,16 @@ impl<B: PsxBus> Cpu<B> {
     #[inline(always)]
     pub fn ins_sw(&mut self) {
         let address = self.ls_address();
-
-        if address % 4 == 0 {
+        if self.check_access(address, 4, Exception::AddressErrorStore) {
             self.store::<4>(address, self.r_rt());
-        } else {
-            self.exception(Exception::AddressErrorStore);
         }
     }
 
     pub fn ins_swr(&mut self) {
         let addr = self.ls_address();
+        if !self.check_access(addr, 1, Exception::AddressErrorStore) {
+            return;
+        }
         let v = self.r_rt();
         let aligned_addr = addr & !3;
         let cur_v = self.load::<4>(aligned_addr);
@@ -156,8 +203,10 @@ impl<B: PsxBus> Cpu<B> {
         self.store::<4>(aligned_addr, v)
     }
 
+    /// Writes `reg` after the next instruction. A load to the same register
+    /// still pending is dropped: only the latest one lands.
     #[inline(always)]
-    fn delayed_load(&mut self, reg: u32, value: u32) {
+    pub(crate) fn delayed_load(&mut self, reg: u32, value: u32) {
         if reg == 0 {
             return;
         }
@@ -198,18 +247,6 @@ impl<B: PsxBus> Cpu<B> {
                 let address = address & 0x1fff_ffff;
                 match address {
                     0x1f80_0000..=0x1f80_03ff => self.dcache.read::<T>(address & 0x3ff),
-                    0x1f80_1070 => {
-                        unsafe {
-                            (*self.bus).update_cycles(2);
-                        }
-                        self.i_stat
-                    }
-                    0x1f80_1074 => {
-                        unsafe {
-                            (*self.bus).update_cycles(2);
-                        }
-                        self.i_mask
-                    }
                     _ => unsafe { (*self.bus).read::<T>(address) },
                 }
             }
@@ -220,6 +257,23 @@ impl<B: PsxBus> Cpu<B> {
         if self.cop0.isolate_cache {
             return;
         }
+        if self.breakpoints.watching() {
+            self.watch(address, T, true);
+        }
+        if self.cop0.data_breakpoint(address, true) {
+            self.breakpoint_exception(self.current_pc, self.in_delay);
+        }
+
+        // KSEG1 is uncached, KUSEG and KSEG0 may be in the I-Cache
+        if self.icache_mode == IcacheMode::Accurate
+            && Segment::of(address).cached()
+            && self.icache.invalidate(address)
+        {
+            info!(
+                self.logger,
+                "Store to cached code at {:08x} from {:08x}", address, self.current_pc
+            );
+        }
 
         match address {
             0xfffe_0130 => {
@@ -246,14 +300,6 @@ impl<B: PsxBus> Cpu<B> {
                     0x1f80_0000..=0x1f80_03ff => {
                         self.dcache.write::<T>(address & 0x3ff, value);
                     }
-                    0x1f80_1070 => {
-                        self.i_stat &= value;
-                        self.check_interrupts();
-                    }
-                    0x1f80_1074 => {
-                        self.i_mask = value & !0xf800;
-                        self.check_interrupts();
-                    }
                     _ => unsafe {
                         (*self.bus).write::<T>(address, value);
                     },
@@ -262,3 +308,378 @@ impl<B: PsxBus> Cpu<B> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    struct RamBus {
+        ram: RefCell<Vec<u8>>,
+        /// Vector, function and first argument of the BIOS calls made
+        bios_calls: RefCell<Vec<(u32, u32, u32)>>,
+    }
+
+    impl PsxBus for RamBus {
+        fn read<const S: u32>(&self, address: u32) -> u32 {
+            let ram = self.ram.borrow();
+            let address = (address & 0xfff) as usize;
+
+            (0..S as usize).fold(0, |v, i| v | (ram[address + i] as u32) << (i * 8))
+        }
+
+        fn write<const S: u32>(&self, address: u32, value: u32) {
+            let mut ram = self.ram.borrow_mut();
+            let address = (address & 0xfff) as usize;
+
+            for i in 0..S as usize {
+                ram[address + i] = (value >> (i * 8)) as u8;
+            }
+        }
+
+        fn update_cycles(&self, _: u64) {}
+
+        fn cycles(&self) -> u64 {
+            0
+        }
+
+        fn bios_call(&self, vector: u32, function: u32, args: [u32; 4], _: u32) {
+            self.bios_calls.borrow_mut().push((vector, function, args[0]));
+        }
+
+        /// Jumps to 0x10, as a side-loaded executable would start
+        fn pc_hook(&self, cpu: &mut Cpu<RamBus>) {
+            cpu.pc = 0x10;
+        }
+    }
+
+    /// Loads `program` at address 0, with 0x1122_3344 and 0x5566_7788 stored
+    /// at 0x100 and 0x104, then runs `steps` instructions.
+    fn run(bus: &RamBus, cpu: &mut Cpu<RamBus>, program: &[u32], steps: usize) {
+        bus.write::<4>(0x100, 0x1122_3344);
+        bus.write::<4>(0x104, 0x5566_7788);
+
+        for (i, ins) in program.iter().enumerate() {
+            bus.write::<4>(i as u32 * 4, *ins);
+        }
+
+        cpu.link(bus);
+        cpu.pc = 0;
+
+        for _ in 0..steps {
+            cpu.step();
+        }
+    }
+
+    fn make_bus() -> RamBus {
+        RamBus {
+            ram: RefCell::new(vec![0; 0x1000]),
+            bios_calls: RefCell::new(vec![]),
+        }
+    }
+
+    #[test]
+    fn test_lwl_lwr_unaligned_pair() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.regs[8] = 0xaaaa_aaaa;
+
+        // LWR t0, 0x101(zero)
+        // LWL t0, 0x104(zero)
+        // NOP
+        run(&bus, &mut cpu, &[0x9808_0101, 0x8808_0104, 0], 3);
+
+        assert_eq!(cpu.regs[8], 0x8811_2233);
+    }
+
+    #[test]
+    fn test_lwl_lwr_aligned_pair() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.regs[8] = 0xaaaa_aaaa;
+
+        // LWL t0, 0x103(zero)
+        // LWR t0, 0x100(zero)
+        // NOP
+        run(&bus, &mut cpu, &[0x8808_0103, 0x9808_0100, 0], 3);
+
+        assert_eq!(cpu.regs[8], 0x1122_3344);
+    }
+
+    #[test]
+    fn test_lwl_alone_keeps_low_bytes() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.regs[8] = 0xaaaa_aaaa;
+
+        // LWL t0, 0x101(zero)
+        // NOP
+        run(&bus, &mut cpu, &[0x8808_0101, 0], 2);
+
+        assert_eq!(cpu.regs[8], 0x3344_aaaa);
+    }
+
+    #[test]
+    fn test_lwr_alone_keeps_high_bytes() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.regs[8] = 0xaaaa_aaaa;
+
+        // LWR t0, 0x102(zero)
+        // NOP
+        run(&bus, &mut cpu, &[0x9808_0102, 0], 2);
+
+        assert_eq!(cpu.regs[8], 0xaaaa_1122);
+    }
+
+    #[test]
+    fn test_lwr_merges_with_pending_lw() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.regs[8] = 0xaaaa_aaaa;
+
+        // LW  t0, 0x104(zero)
+        // LWR t0, 0x101(zero)
+        // NOP
+        run(&bus, &mut cpu, &[0x8c08_0104, 0x9808_0101, 0], 3);
+
+        // The high byte comes from the in-flight LW, not from the old t0
+        assert_eq!(cpu.regs[8], 0x5511_2233);
+    }
+
+    #[test]
+    fn test_lwl_merges_with_pending_lw() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.regs[8] = 0xaaaa_aaaa;
+
+        // LW  t0, 0x104(zero)
+        // LWL t0, 0x100(zero)
+        // NOP
+        run(&bus, &mut cpu, &[0x8c08_0104, 0x8808_0100, 0], 3);
+
+        assert_eq!(cpu.regs[8], 0x4466_7788);
+    }
+
+    #[test]
+    fn test_pending_lw_is_never_visible_when_merged() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.regs[8] = 0xaaaa_aaaa;
+
+        // LW  t0, 0x104(zero)
+        // LWR t0, 0x101(zero)
+        run(&bus, &mut cpu, &[0x8c08_0104, 0x9808_0101], 2);
+
+        // The LW result was superseded by the LWR, still in its delay slot
+        assert_eq!(cpu.regs[8], 0xaaaa_aaaa);
+    }
+
+    #[test]
+    fn test_lwl_does_not_merge_with_other_register() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.regs[8] = 0xaaaa_aaaa;
+
+        // LW  t1, 0x104(zero)
+        // LWL t0, 0x101(zero)
+        // NOP
+        run(&bus, &mut cpu, &[0x8c09_0104, 0x8808_0101, 0], 3);
+
+        assert_eq!(cpu.regs[8], 0x3344_aaaa);
+        assert_eq!(cpu.regs[9], 0x5566_7788);
+    }
+
+    #[test]
+    fn test_lwl_result_is_delayed() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.regs[8] = 0xaaaa_aaaa;
+
+        // LWL  t0, 0x103(zero)
+        // ADDU t1, t0, zero
+        // NOP
+        run(&bus, &mut cpu, &[0x8808_0103, 0x0100_4821, 0], 3);
+
+        assert_eq!(cpu.regs[9], 0xaaaa_aaaa);
+        assert_eq!(cpu.regs[8], 0x1122_3344);
+    }
+
+    #[test]
+    fn test_alu_write_cancels_pending_load() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+
+        // LW    t0, 0x100(zero)
+        // ADDIU t0, zero, 7
+        // LWR   t0, 0x103(zero)
+        // NOP
+        run(&bus, &mut cpu, &[0x8c08_0100, 0x2408_0007, 0x9808_0103, 0], 4);
+
+        // LWR merges with the ALU result, the LW is discarded
+        assert_eq!(cpu.regs[8], 0x0000_0011);
+    }
+
+    #[test]
+    fn test_swl_swr_unaligned_pair() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.regs[8] = 0xdead_beef;
+
+        // SWR t0, 0x101(zero)
+        // SWL t0, 0x104(zero)
+        run(&bus, &mut cpu, &[0xb808_0101, 0xa808_0104], 2);
+
+        assert_eq!(bus.read::<4>(0x100), 0xadbe_ef44);
+        assert_eq!(bus.read::<4>(0x104), 0x5566_77de);
+    }
+
+    #[test]
+    fn test_swl_swr_aligned_pair() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.regs[8] = 0xdead_beef;
+
+        // SWL t0, 0x103(zero)
+        // SWR t0, 0x100(zero)
+        run(&bus, &mut cpu, &[0xa808_0103, 0xb808_0100], 2);
+
+        assert_eq!(bus.read::<4>(0x100), 0xdead_beef);
+        assert_eq!(bus.read::<4>(0x104), 0x5566_7788);
+    }
+
+    #[test]
+    fn test_watchpoints_stop_after_the_access() {
+        use crate::breakpoints::Kind;
+
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        let watch = Kind::Watch {
+            address: 0x8000_0104,
+            len: 4,
+            read: false,
+            write: true,
+        };
+        let id = cpu.breakpoints.add(watch, None);
+
+        // LW t0, 0x104(zero)
+        // SB t0, 0x106(zero)
+        run(&bus, &mut cpu, &[0x8c08_0104, 0xa008_0106], 1);
+        assert_eq!(cpu.take_debug_stop(), None);
+
+        cpu.step();
+        assert_eq!(cpu.take_debug_stop(), Some(DebugStop::Watchpoint(id, 0x106, true)));
+        // In the load delay slot, t0 is still 0
+        assert_eq!(bus.read::<4>(0x104), 0x5500_7788);
+    }
+
+    #[test]
+    fn test_code_breakpoints_stop_before_the_instruction() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.cop0.write_reg(3, 0x8000_0004).unwrap();
+        cpu.cop0.write_reg(11, 0x1fff_ffff).unwrap();
+        cpu.cop0.write_reg(7, 0xc180_0000).unwrap();
+
+        // ADDIU t0, t0, 1 (x2)
+        run(&bus, &mut cpu, &[0x2508_0001, 0x2508_0001], 2);
+
+        assert_eq!(cpu.regs[8], 1);
+        assert_eq!(cpu.pc, 0xbfc0_0140);
+        assert_eq!(cpu.cop0.read_reg(13), Some(9 << 2));
+        assert_eq!(cpu.cop0.read_reg(14), Some(4));
+    }
+
+    #[test]
+    fn test_data_breakpoints_go_through_the_debug_vector() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.cop0.write_reg(5, 0x104).unwrap();
+        cpu.cop0.write_reg(7, 0xc680_0000).unwrap();
+        cpu.cop0.write_reg(12, 0).unwrap();
+
+        // SW t0, 0x104(zero)
+        // LW t0, 0x104(zero)
+        run(&bus, &mut cpu, &[0xac08_0104, 0x8c08_0104], 1);
+        assert_eq!(cpu.pc, 4);
+
+        cpu.step();
+        assert_eq!(cpu.pc, 0x8000_0040);
+        assert_eq!(cpu.cop0.read_reg(7), Some(0xc680_000d));
+        assert_eq!(cpu.cop0.read_reg(14), Some(4));
+    }
+
+    #[test]
+    fn test_user_mode_cannot_reach_kernel_segments() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+        cpu.cop0.write_reg(12, 0x2).unwrap();
+        cpu.regs[9] = 0x8000_0104;
+
+        // LW t0, 0x100(zero)
+        // LW t0, 0(t1)
+        run(&bus, &mut cpu, &[0x8c08_0100, 0x8d28_0000], 2);
+
+        assert_eq!(cpu.regs[8], 0x1122_3344);
+        assert_eq!(cpu.pc, 0x8000_0080);
+        assert_eq!(cpu.cop0.read_reg(13), Some(4 << 2));
+        assert_eq!(cpu.cop0.read_reg(8), Some(0x8000_0104));
+        assert_eq!(cpu.cop0.read_reg(14), Some(4));
+
+        // The handler runs in kernel mode, where KSEG0 is fine
+        cpu.pc = 4;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.regs[8], 0x5566_7788);
+    }
+
+    #[test]
+    fn test_misaligned_stores_record_the_bad_address() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+
+        // SH t0, 0x101(zero)
+        run(&bus, &mut cpu, &[0xa408_0101], 1);
+
+        assert_eq!(cpu.cop0.read_reg(13), Some(5 << 2));
+        assert_eq!(cpu.cop0.read_reg(8), Some(0x101));
+        assert_eq!(bus.read::<4>(0x100), 0x1122_3344);
+    }
+
+    #[test]
+    fn test_putchar_calls_reach_the_tty() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+
+        // LI a0, 'A'
+        // LI t2, 0xa0
+        // JR t2
+        // LI t1, 0x3c (in the delay slot)
+        run(&bus, &mut cpu, &[0x2404_0041, 0x240a_00a0, 0x0140_0008, 0x2409_003c], 0);
+        for _ in 0..4 {
+            cpu.cycle();
+        }
+        assert_eq!(cpu.pc, 0xa0);
+
+        let mut buf = [0; 2];
+        assert_eq!(cpu.tty.read(&mut buf), 1);
+        assert_eq!(buf[0], b'A');
+        assert_eq!(*bus.bios_calls.borrow(), [(0xa0, 0x3c, 0x41)]);
+    }
+
+    #[test]
+    fn test_pc_hook_runs_before_the_instruction() {
+        let bus = make_bus();
+        let mut cpu = Cpu::new();
+
+        // ADDIU t0, t0, 1 (x4)
+        // LI t2, 7
+        let program = [0x2508_0001, 0x2508_0001, 0x2508_0001, 0x2508_0001, 0x240a_0007];
+        run(&bus, &mut cpu, &program, 0);
+        cpu.pc_hook = Some(4);
+        cpu.cycle();
+        cpu.cycle();
+        assert_eq!((cpu.regs[8], cpu.regs[10]), (1, 7));
+        assert_eq!(cpu.pc_hook, None);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::state::{Savestate, StateError, StateReader, StateWriter};
+
 pub(crate) struct Scratchpad {
     data: Vec<u8>,
 }
@@ -46,3 +48,15 @@ impl Scratchpad {
         }
     }
 }
+
+impl Savestate for Scratchpad {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"SPAD");
+        state.write_bytes(&self.data);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"SPAD", "scratchpad")?;
+        state.read_bytes(&mut self.data)
+    }
+}
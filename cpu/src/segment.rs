@@ -0,0 +1,70 @@
+//! The segments of the R3000A address space. Without a TLB every segment
+//! maps to the same 512MB of physical memory (KSEG2 aside), they only
+//! differ in who may access them and whether accesses are cached.
+//!
+//! Segment | Addresses             | Access      | Cached
+//! --------|-----------------------|-------------|-------
+//! KUSEG   | 0000_0000 - 7fff_ffff | User/kernel | Yes
+//! KSEG0   | 8000_0000 - 9fff_ffff | Kernel      | Yes
+//! KSEG1   | a000_0000 - bfff_ffff | Kernel      | No
+//! KSEG2   | c000_0000 - ffff_ffff | Kernel      | No
+//!
+//! On the PlayStation only the first 512MB of KUSEG are decoded, and KSEG2
+//! only holds the cache control register (fffe_0130).
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Segment {
+    Kuseg,
+    Kseg0,
+    Kseg1,
+    Kseg2,
+}
+
+impl Segment {
+    #[inline(always)]
+    pub fn of(address: u32) -> Segment {
+        match address >> 29 {
+            0..=3 => Segment::Kuseg,
+            4 => Segment::Kseg0,
+            5 => Segment::Kseg1,
+            _ => Segment::Kseg2,
+        }
+    }
+
+    /// Whether accesses go through the I-Cache
+    #[inline(always)]
+    pub fn cached(self) -> bool {
+        matches!(self, Segment::Kuseg | Segment::Kseg0)
+    }
+
+    /// Whether user mode accesses raise an address error
+    #[inline(always)]
+    pub fn kernel_only(self) -> bool {
+        self != Segment::Kuseg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment_boundaries() {
+        assert_eq!(Segment::of(0x0000_0000), Segment::Kuseg);
+        assert_eq!(Segment::of(0x7fff_ffff), Segment::Kuseg);
+        assert_eq!(Segment::of(0x8000_0000), Segment::Kseg0);
+        assert_eq!(Segment::of(0x9fff_ffff), Segment::Kseg0);
+        assert_eq!(Segment::of(0xa000_0000), Segment::Kseg1);
+        assert_eq!(Segment::of(0xbfff_ffff), Segment::Kseg1);
+        assert_eq!(Segment::of(0xc000_0000), Segment::Kseg2);
+        assert_eq!(Segment::of(0xfffe_0130), Segment::Kseg2);
+    }
+
+    #[test]
+    fn test_segment_attributes() {
+        assert!(Segment::Kuseg.cached() && !Segment::Kuseg.kernel_only());
+        assert!(Segment::Kseg0.cached() && Segment::Kseg0.kernel_only());
+        assert!(!Segment::Kseg1.cached() && Segment::Kseg1.kernel_only());
+        assert!(!Segment::Kseg2.cached() && Segment::Kseg2.kernel_only());
+    }
+}
@@ -0,0 +1,332 @@
+//! Savestates: the whole machine state in a little-endian binary format.
+//!
+//! A state starts with `MAGIC` and `VERSION`, followed by the sections of
+//! the CPU and of the bus devices, each introduced by a 4-byte tag. There
+//! is no attempt at converting states between versions: the version must
+//! be bumped whenever the layout of any section changes, and states from
+//! other versions are rejected.
+
+use std::fmt;
+use std::io;
+
+pub const MAGIC: &[u8; 8] = b"CRUSTATE";
+pub const VERSION: u32 = 20;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    /// Not a savestate
+    BadMagic,
+    /// A savestate from another version of the emulator
+    Version(u32),
+    /// The state ended in the middle of a section
+    Truncated,
+    /// A section is missing or has bad contents
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Io(err) => write!(f, "{}", err),
+            StateError::BadMagic => write!(f, "not a savestate"),
+            StateError::Version(version) => write!(
+                f,
+                "savestate version {} is not supported (expected {})",
+                version, VERSION
+            ),
+            StateError::Truncated => write!(f, "truncated savestate"),
+            StateError::Invalid(what) => write!(f, "invalid savestate: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<io::Error> for StateError {
+    fn from(err: io::Error) -> StateError {
+        StateError::Io(err)
+    }
+}
+
+/// Implemented by every component holding emulated state. Host side
+/// settings (renderer options, logging, speed...) are not part of it.
+/// Saving takes `&mut self` so that pending work, like primitives queued
+/// by the renderer, can be completed first.
+pub trait Savestate {
+    fn save_state(&mut self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: vec![] }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Starts a section
+    pub fn tag(&mut self, tag: &[u8; 4]) {
+        self.data.extend_from_slice(tag);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_i16(&mut self, value: i16) {
+        self.write_u16(value as u16);
+    }
+
+    pub fn write_i32(&mut self, value: i32) {
+        self.write_u32(value as u32);
+    }
+
+    /// Fixed size data, the reader must know the length
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Variable size data, preceded by its length
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    /// Checks that the section `tag` starts here
+    pub fn expect_tag(&mut self, tag: &[u8; 4], section: &'static str) -> Result<(), StateError> {
+        match self.take(4)? == tag {
+            true => Ok(()),
+            false => Err(StateError::Invalid(section)),
+        }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_i16(&mut self) -> Result<i16, StateError> {
+        Ok(self.read_u16()? as i16)
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, StateError> {
+        Ok(self.read_u32()? as i32)
+    }
+
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+
+    pub fn read_vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let len = self.read_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_bus::NullBus;
+    use crate::{Cpu, CpuCommand};
+
+    fn make_cpu(bus: &NullBus) -> Cpu<NullBus> {
+        let mut cpu = Cpu::new();
+        cpu.link(bus);
+        cpu
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.tag(b"TEST");
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_i16(-2);
+        writer.write_u32(0x789a_bcde);
+        writer.write_i32(-100_000);
+        writer.write_u64(0x0123_4567_89ab_cdef);
+        writer.write_vec(&[1, 2, 3]);
+        let data = writer.into_inner();
+
+        let mut reader = StateReader::new(&data);
+        reader.expect_tag(b"TEST", "test").unwrap();
+        assert_eq!(reader.read_u8().unwrap(), 0x12);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_u16().unwrap(), 0x3456);
+        assert_eq!(reader.read_i16().unwrap(), -2);
+        assert_eq!(reader.read_u32().unwrap(), 0x789a_bcde);
+        assert_eq!(reader.read_i32().unwrap(), -100_000);
+        assert_eq!(reader.read_u64().unwrap(), 0x0123_4567_89ab_cdef);
+        assert_eq!(reader.read_vec().unwrap(), vec![1, 2, 3]);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn short_or_misplaced_data_is_an_error() {
+        let mut reader = StateReader::new(&[1, 2, 3]);
+        assert!(matches!(reader.read_u32(), Err(StateError::Truncated)));
+
+        let mut reader = StateReader::new(b"GPU ");
+        assert!(matches!(
+            reader.expect_tag(b"SPU ", "SPU"),
+            Err(StateError::Invalid("SPU"))
+        ));
+    }
+
+    #[test]
+    fn cpu_state_round_trips() {
+        let bus = NullBus;
+        let mut cpu = make_cpu(&bus);
+        cpu.pc = 0x8001_0000;
+        cpu.regs[29] = 0x801f_fff0;
+        cpu.hi = 7;
+        cpu.branch_delay_slot = Some((0x8001_0100, 0x2408_0001));
+        cpu.cop0.write_reg(12, 0x4000_0401).unwrap();
+        cpu.gte.write_reg(32 + 26, 0x155);
+        cpu.gte.write_reg(12, 0x0010_0020);
+        cpu.dcache.write::<4>(0x10, 0xdead_beef);
+        cpu.icache.store(0x8001_0000, 0x0000_000c);
+
+        let mut data = vec![];
+        cpu.save_state(&mut data).unwrap();
+
+        let mut restored = make_cpu(&bus);
+        restored.load_state(&mut data.as_slice()).unwrap();
+
+        assert_eq!(restored.pc, 0x8001_0000);
+        assert_eq!(restored.regs, cpu.regs);
+        assert_eq!(restored.hi, 7);
+        assert_eq!(restored.branch_delay_slot, Some((0x8001_0100, 0x2408_0001)));
+        assert!(restored.cop0.interrupts_enabled && restored.cop0.cop2_enabled);
+        assert!(!restored.cop0.boot_vectors);
+        for reg in 0..64 {
+            assert_eq!(
+                restored.gte.read_reg(reg),
+                cpu.gte.read_reg(reg),
+                "GTE r{}",
+                reg
+            );
+        }
+        assert_eq!(restored.dcache.read::<4>(0x10), 0xdead_beef);
+        assert_eq!(restored.icache.load(0x8001_0000), Some(0x0000_000c));
+    }
+
+    #[test]
+    fn foreign_states_are_rejected_untouched() {
+        let bus = NullBus;
+        let mut cpu = make_cpu(&bus);
+        cpu.pc = 0x8001_0000;
+
+        let mut data = vec![];
+        cpu.save_state(&mut data).unwrap();
+        data[8] = data[8].wrapping_add(1);
+
+        let mut other = make_cpu(&bus);
+        assert!(matches!(
+            other.load_state(&mut data.as_slice()),
+            Err(StateError::Version(_))
+        ));
+        assert_eq!(other.pc, 0xbfc0_0000);
+
+        assert!(matches!(
+            other.load_state(&mut &b"PS-X EXE"[..]),
+            Err(StateError::BadMagic)
+        ));
+
+        // Cut in the middle of the GTE
+        data.clear();
+        cpu.save_state(&mut data).unwrap();
+        assert!(matches!(
+            other.load_state(&mut &data[..600]),
+            Err(StateError::Truncated)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "savestates")]
+    fn queued_save_states_are_flushed() {
+        let bus = NullBus;
+        let mut cpu = make_cpu(&bus);
+        let path = std::env::temp_dir().join(format!("flush-{}.state", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        cpu.command_tx.send(CpuCommand::Break).unwrap();
+        cpu.command_tx.send(CpuCommand::SaveState(path.clone())).unwrap();
+        cpu.command_tx.send(CpuCommand::LoadState(path.clone())).unwrap();
+        cpu.run();
+        assert!(!path.exists());
+
+        cpu.pc = 0x8001_0000;
+        cpu.flush_commands();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut restored = make_cpu(&bus);
+        restored.load_state(&mut data.as_slice()).unwrap();
+        assert_eq!(restored.pc, 0x8001_0000);
+    }
+}
@@ -0,0 +1,71 @@
+//! Instructions executed by class, for statistics and heuristics that
+//! depend on what a game spends its time on: loads and stores, GTE commands,
+//! multiplications and divisions.
+
+/// Totals since power on. Not saved in states.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct InstructionCounts {
+    /// LB to LWR, and LWC2
+    pub loads: u64,
+    /// SB to SWR, and SWC2
+    pub stores: u64,
+    /// GTE commands, not the register transfers
+    pub gte: u64,
+    /// MULT, MULTU, DIV and DIVU
+    pub mult_div: u64,
+}
+
+impl InstructionCounts {
+    pub(crate) fn record(&mut self, instruction: u32) {
+        match instruction >> 26 {
+            0x20..=0x26 | 0x32 => self.loads += 1,
+            0x28..=0x2e | 0x3a => self.stores += 1,
+            0x12 if instruction & (1 << 25) != 0 => self.gte += 1,
+            0x00 if (0x18..=0x1b).contains(&(instruction & 0x3f)) => self.mult_div += 1,
+            _ => {}
+        }
+    }
+
+    /// Executed since `earlier`, a previous reading
+    pub fn since(&self, earlier: &InstructionCounts) -> InstructionCounts {
+        InstructionCounts {
+            loads: self.loads.saturating_sub(earlier.loads),
+            stores: self.stores.saturating_sub(earlier.stores),
+            gte: self.gte.saturating_sub(earlier.gte),
+            mult_div: self.mult_div.saturating_sub(earlier.mult_div),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_by_class() {
+        let mut counts = InstructionCounts::default();
+        // LW, LWC2, SH, SWC2, RTPS, MFC2, MULT, DIVU, MFLO, ADDIU
+        for instruction in [
+            0x8c88_0000,
+            0xc880_0000,
+            0xa488_0000,
+            0xe880_0000,
+            0x4a18_0001,
+            0x4808_0000,
+            0x0085_0018,
+            0x0085_001b,
+            0x0000_4012,
+            0x2508_0001,
+        ] {
+            counts.record(instruction);
+        }
+        let expected = InstructionCounts {
+            loads: 2,
+            stores: 2,
+            gte: 1,
+            mult_div: 2,
+        };
+        assert_eq!(counts, expected);
+        assert_eq!(counts.since(&expected), InstructionCounts::default());
+    }
+}
@@ -0,0 +1,38 @@
+//! Buses for the unit tests of the CPU
+
+use std::cell::Cell;
+
+use crate::PsxBus;
+
+/// Reads zeroes and ignores everything else
+pub struct NullBus;
+
+impl PsxBus for NullBus {
+    fn read<const S: u32>(&self, _: u32) -> u32 {
+        0
+    }
+    fn write<const S: u32>(&self, _: u32, _: u32) {}
+    fn update_cycles(&self, _: u64) {}
+    fn cycles(&self) -> u64 {
+        0
+    }
+}
+
+/// Like `NullBus`, counting the cycles the CPU spends
+#[derive(Default)]
+pub struct CycleBus {
+    pub cycles: Cell<u64>,
+}
+
+impl PsxBus for CycleBus {
+    fn read<const S: u32>(&self, _: u32) -> u32 {
+        0
+    }
+    fn write<const S: u32>(&self, _: u32, _: u32) {}
+    fn update_cycles(&self, cycles: u64) {
+        self.cycles.set(self.cycles.get() + cycles);
+    }
+    fn cycles(&self) -> u64 {
+        self.cycles.get()
+    }
+}
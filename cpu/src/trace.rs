@@ -0,0 +1,203 @@
+//! Execution trace: the last instructions run, in a ring buffer, with the
+//! registers each one changed. Written to a file on demand, or when the CPU
+//! faults, to see how it got there.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// An instruction can change a register, have a load land and write HI/LO
+const MAX_CHANGES: usize = 4;
+
+/// r0-r31, then HI and LO
+pub type TraceRegisters = [u32; 34];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    pub pc: u32,
+    pub instruction: u32,
+    /// Register (32 is HI, 33 LO) and its new value
+    changes: [(u8, u32); MAX_CHANGES],
+    changed: u8,
+}
+
+impl TraceEntry {
+    pub fn changes(&self) -> &[(u8, u32)] {
+        &self.changes[..self.changed as usize]
+    }
+}
+
+/// Which code is traced, by where it runs from
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TraceFilter {
+    pub bios: bool,
+    /// Anything but the BIOS, in practice main RAM
+    pub ram: bool,
+}
+
+impl TraceFilter {
+    pub const ALL: TraceFilter = TraceFilter {
+        bios: true,
+        ram: true,
+    };
+
+    pub fn from_name(name: &str) -> Option<TraceFilter> {
+        match name {
+            "all" => Some(TraceFilter::ALL),
+            "bios" => Some(TraceFilter {
+                bios: true,
+                ram: false,
+            }),
+            "ram" => Some(TraceFilter {
+                bios: false,
+                ram: true,
+            }),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, pc: u32) -> bool {
+        match pc & 0x1fff_ffff {
+            0x1fc0_0000..=0x1fc7_ffff => self.bios,
+            _ => self.ram,
+        }
+    }
+}
+
+pub struct Tracer {
+    entries: VecDeque<TraceEntry>,
+    capacity: usize,
+    filter: TraceFilter,
+    path: PathBuf,
+    /// From instruction and pc, the disassembler lives with the frontend
+    disasm: fn(u32, u32) -> String,
+    /// The CPU faulted, the trace is written after this instruction
+    pub(crate) fault: bool,
+}
+
+impl Tracer {
+    pub fn new(
+        capacity: usize,
+        filter: TraceFilter,
+        path: &Path,
+        disasm: fn(u32, u32) -> String,
+    ) -> Tracer {
+        Tracer {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            filter,
+            path: path.to_path_buf(),
+            disasm,
+            fault: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Oldest first
+    pub fn entries(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.entries.iter()
+    }
+
+    pub fn traces(&self, pc: u32) -> bool {
+        self.filter.matches(pc)
+    }
+
+    pub fn record(
+        &mut self,
+        pc: u32,
+        instruction: u32,
+        before: &TraceRegisters,
+        after: &TraceRegisters,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+
+        let mut entry = TraceEntry {
+            pc,
+            instruction,
+            changes: [(0, 0); MAX_CHANGES],
+            changed: 0,
+        };
+        let changes = (0..before.len()).filter(|&reg| before[reg] != after[reg]);
+        for reg in changes.take(MAX_CHANGES) {
+            entry.changes[entry.changed as usize] = (reg as u8, after[reg]);
+            entry.changed += 1;
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Writes the trace to its file
+    pub fn dump(&self) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        self.write(&mut out)?;
+        out.flush()
+    }
+
+    /// One line per instruction: address, opcode, disassembly and the
+    /// registers it changed
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "# Last {} instructions, oldest first", self.entries.len())?;
+        for entry in &self.entries {
+            let disasm = (self.disasm)(entry.instruction, entry.pc);
+            write!(out, "{:08x}  {:08x}  {:<32}", entry.pc, entry.instruction, disasm)?;
+            for &(reg, value) in entry.changes() {
+                match reg {
+                    32 => write!(out, " hi={:08x}", value)?,
+                    33 => write!(out, " lo={:08x}", value)?,
+                    _ => write!(out, " r{}={:08x}", reg, value)?,
+                }
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disasm(instruction: u32, _: u32) -> String {
+        format!("op {:x}", instruction >> 26)
+    }
+
+    #[test]
+    fn keeps_the_last_instructions() {
+        let mut tracer = Tracer::new(2, TraceFilter::ALL, Path::new("trace.txt"), disasm);
+        let mut regs = [0; 34];
+        for i in 0..3 {
+            let before = regs;
+            regs[8] += 1;
+            regs[33] = i;
+            tracer.record(0x8001_0000 + i * 4, 0x2508_0001, &before, &regs);
+        }
+
+        let pcs: Vec<u32> = tracer.entries().map(|entry| entry.pc).collect();
+        assert_eq!(pcs, [0x8001_0004, 0x8001_0008]);
+        assert_eq!(tracer.entries().last().unwrap().changes(), [(8, 3), (33, 2)]);
+
+        let mut out = vec![];
+        tracer.write(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let last = text.lines().last().unwrap();
+        assert!(last.starts_with("80010008  25080001  op 9 "));
+        assert!(last.ends_with(" r8=00000003 lo=00000002"));
+    }
+
+    #[test]
+    fn filters_by_region() {
+        let bios = TraceFilter::from_name("bios").unwrap();
+        assert!(bios.matches(0xbfc0_0180) && !bios.matches(0x8001_0000));
+        let ram = TraceFilter::from_name("ram").unwrap();
+        assert!(!ram.matches(0xbfc0_0180) && ram.matches(0x0001_0000));
+        assert_eq!(TraceFilter::from_name("cdrom"), None);
+    }
+}
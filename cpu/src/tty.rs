@@ -0,0 +1,94 @@
+//! Console output of the BIOS: characters written with putchar, A(3Ch) and
+//! B(3Dh). The BIOS prints its boot messages there, and most test ROMs
+//! their results. Lines are logged, and the text is kept for frontends.
+
+use std::collections::VecDeque;
+
+use crustationlogger::*;
+
+/// Output kept until read, older text is dropped
+const MAX_OUTPUT: usize = 64 * 1024;
+/// Logged even without a newline, so a runaway loop shows up
+const MAX_LINE: usize = 256;
+
+pub struct Tty {
+    logger: Logger,
+    line: Vec<u8>,
+    output: VecDeque<u8>,
+}
+
+impl Default for Tty {
+    fn default() -> Tty {
+        Tty::new()
+    }
+}
+
+impl Tty {
+    pub fn new() -> Tty {
+        Tty {
+            logger: Logger::new("TTY", Level::Info),
+            line: vec![],
+            output: VecDeque::new(),
+        }
+    }
+
+    pub fn putchar(&mut self, c: u8) {
+        if self.output.len() == MAX_OUTPUT {
+            self.output.pop_front();
+        }
+        self.output.push_back(c);
+
+        match c {
+            b'\n' => self.flush_line(),
+            b'\r' => {}
+            _ => {
+                self.line.push(c);
+                if self.line.len() == MAX_LINE {
+                    self.flush_line();
+                }
+            }
+        }
+    }
+
+    fn flush_line(&mut self) {
+        info!(self.logger, "{}", String::from_utf8_lossy(&self.line));
+        self.line.clear();
+    }
+
+    /// Moves the output not read yet into `buf`. Returns the number of
+    /// bytes copied.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.output.len());
+        for (byte, c) in buf.iter_mut().zip(self.output.drain(..count)) {
+            *byte = c;
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_output_until_read() {
+        let mut tty = Tty::new();
+        for &c in b"PASS\r\nok" {
+            tty.putchar(c);
+        }
+        assert_eq!(tty.line, b"ok");
+
+        let mut buf = [0; 4];
+        assert_eq!(tty.read(&mut buf), 4);
+        assert_eq!(&buf, b"PASS");
+        assert_eq!(tty.read(&mut buf), 4);
+        assert_eq!(&buf, b"\r\nok");
+        assert_eq!(tty.read(&mut buf), 0);
+
+        for _ in 0..MAX_OUTPUT + 1 {
+            tty.putchar(b'.');
+        }
+        assert_eq!(tty.output.len(), MAX_OUTPUT);
+        assert!(tty.line.len() < MAX_LINE);
+    }
+}
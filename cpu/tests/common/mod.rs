@@ -0,0 +1,42 @@
+//! Fixtures shared by the integration tests
+
+use std::cell::RefCell;
+
+use crustationcpu::PsxBus;
+
+/// 4 KiB of RAM, mirrored over the whole address space
+pub struct RamBus {
+    ram: RefCell<Vec<u8>>,
+}
+
+impl RamBus {
+    pub fn new() -> RamBus {
+        RamBus {
+            ram: RefCell::new(vec![0; 0x1000]),
+        }
+    }
+}
+
+impl PsxBus for RamBus {
+    fn read<const S: u32>(&self, address: u32) -> u32 {
+        let ram = self.ram.borrow();
+        let address = (address & 0xfff) as usize;
+
+        (0..S as usize).fold(0, |v, i| v | (ram[address + i] as u32) << (i * 8))
+    }
+
+    fn write<const S: u32>(&self, address: u32, value: u32) {
+        let mut ram = self.ram.borrow_mut();
+        let address = (address & 0xfff) as usize;
+
+        for i in 0..S as usize {
+            ram[address + i] = (value >> (i * 8)) as u8;
+        }
+    }
+
+    fn update_cycles(&self, _: u64) {}
+
+    fn cycles(&self) -> u64 {
+        0
+    }
+}
@@ -1,6 +1,9 @@
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
 
 #[derive(Serialize, Deserialize)]
 struct GteFuzzTest {
@@ -23,7 +26,7 @@ fn load_fuzz_tests(set_name: &str) -> Vec<GteFuzzTest> {
 }
 
 extern crate crustationcpu;
-use crustationcpu::gte::{Flags, Gte};
+use crustationcpu::gte::Gte;
 
 #[test]
 fn registers() {
@@ -50,7 +53,40 @@ fn registers() {
     }
 }
 
-fn run_gte_fuzz_test(test: &GteFuzzTest) -> bool {
+/// GTE registers, data then control, for the failure tables
+const REGISTER_NAMES: [&str; 64] = [
+    "VXY0", "VZ0", "VXY1", "VZ1", "VXY2", "VZ2", "RGBC", "OTZ", "IR0", "IR1", "IR2", "IR3", "SXY0",
+    "SXY1", "SXY2", "SXYP", "SZ0", "SZ1", "SZ2", "SZ3", "RGB0", "RGB1", "RGB2", "RES1", "MAC0",
+    "MAC1", "MAC2", "MAC3", "IRGB", "ORGB", "LZCS", "LZCR", "RT11RT12", "RT13RT21", "RT22RT23",
+    "RT31RT32", "RT33", "TRX", "TRY", "TRZ", "L11L12", "L13L21", "L22L23", "L31L32", "L33", "RBK",
+    "GBK", "BBK", "LR1LR2", "LR3LG1", "LG2LG3", "LB1LB2", "LB3", "RFC", "GFC", "BFC", "OFX",
+    "OFY", "H", "DQA", "DQB", "ZSF3", "ZSF4", "FLAG",
+];
+
+#[derive(Serialize)]
+struct Mismatch {
+    register: usize,
+    actual: u32,
+    expected: u32,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    index: usize,
+    name: String,
+    mismatches: Vec<Mismatch>,
+}
+
+/// Written for each suite when GTE_FUZZ_REPORT names a directory
+#[derive(Serialize)]
+struct SuiteReport {
+    suite: String,
+    tests: usize,
+    failures: Vec<Failure>,
+}
+
+/// The registers that don't hold what the test expects
+fn run_gte_fuzz_test(test: &GteFuzzTest) -> Vec<Mismatch> {
     let mut gte = Gte::new();
 
     for (r, val) in test.input.iter().enumerate() {
@@ -61,183 +97,232 @@ fn run_gte_fuzz_test(test: &GteFuzzTest) -> bool {
     let opcode = u32::from_str_radix(&test.opcode[2..], 16).unwrap();
     gte.execute(opcode);
 
-    let mut pass = true;
+    let mut mismatches = vec![];
     for (r, val) in test.output.iter().enumerate() {
         let expected = u32::from_str_radix(&val[2..], 16).unwrap();
         let actual = gte.read_reg(r as u32);
 
         if expected != actual {
-            if r == 63 {
-                let expected = Flags(expected);
-                let actual = Flags(actual);
-
-                println!(
-                    "Error flags contains {:#?} but {:#?} was expected",
-                    actual, expected
-                );
-            } else {
-                println!(
-                    "r{} contains {:08x}, but was expecting {:08x}",
-                    r, actual, expected
-                );
-            }
-
-            pass = false;
+            mismatches.push(Mismatch {
+                register: r,
+                actual,
+                expected,
+            });
         }
     }
 
-    pass
+    mismatches
 }
 
-fn run_gte_fuzz_suite(tests: Vec<GteFuzzTest>) {
+/// One line per register that failed: how many tests, and the first one
+fn diff_table(suite: &str, tests: usize, failures: &[Failure]) -> String {
+    let mut out = format!("{}: {} of {} tests failed\n", suite, failures.len(), tests);
+    out += "  register   tests  first failure\n";
+
+    let mut registers = BTreeMap::new();
+    for failure in failures {
+        for mismatch in &failure.mismatches {
+            let entry = registers.entry(mismatch.register).or_insert((0, failure, mismatch));
+            entry.0 += 1;
+        }
+    }
+
+    for (register, (count, failure, mismatch)) in registers {
+        out += &format!(
+            "  {:<9} {:>6}  test {}: {:08x}, expected {:08x} (bits {:08x} differ)\n",
+            REGISTER_NAMES[register],
+            count,
+            failure.index,
+            mismatch.actual,
+            mismatch.expected,
+            mismatch.actual ^ mismatch.expected
+        );
+    }
+    out
+}
+
+fn write_report(dir: &Path, report: &SuiteReport) {
+    std::fs::create_dir_all(dir).expect("Could not create the report directory");
+    let path = dir.join(format!("{}.json", report.suite));
+    let json = serde_json::to_string_pretty(report).expect("Could not serialize the report");
+    std::fs::write(&path, json)
+        .unwrap_or_else(|err| panic!("Could not write {}: {}", path.display(), err));
+}
+
+/// Runs the tests in parallel. A test index as the last argument runs just
+/// that test, with its mismatches printed.
+fn run_gte_fuzz_suite(suite: &str, tests: Vec<GteFuzzTest>) {
     let n = std::env::args().last().unwrap();
-    if let Ok(n) = usize::from_str_radix(&n, 10) {
+    if let Ok(n) = n.parse::<usize>() {
         let test = &tests[n];
         println!("Running test {}: {}", n, test.name);
-        assert_eq!(run_gte_fuzz_test(test), true);
-    } else {
-        let mut successes = 0;
-
-        for (idx, test) in tests.iter().enumerate() {
-            println!("Running test {}: {}", idx, test.name);
-            if run_gte_fuzz_test(test) {
-                successes += 1;
-            }
-            println!()
-        }
+        let failure = Failure {
+            index: n,
+            name: test.name.clone(),
+            mismatches: run_gte_fuzz_test(test),
+        };
+        let failed = !failure.mismatches.is_empty();
+        assert!(!failed, "{}", diff_table(suite, 1, &[failure]));
+        return;
+    }
 
-        assert_eq!(successes, tests.len());
+    let failures: Vec<Failure> = tests
+        .par_iter()
+        .enumerate()
+        .filter_map(|(index, test)| {
+            let mismatches = run_gte_fuzz_test(test);
+            (!mismatches.is_empty()).then(|| Failure {
+                index,
+                name: test.name.clone(),
+                mismatches,
+            })
+        })
+        .collect();
+
+    let report = SuiteReport {
+        suite: suite.to_string(),
+        tests: tests.len(),
+        failures,
+    };
+    if let Some(dir) = std::env::var_os("GTE_FUZZ_REPORT") {
+        write_report(Path::new(&dir), &report);
     }
+
+    assert!(
+        report.failures.is_empty(),
+        "{}",
+        diff_table(suite, report.tests, &report.failures)
+    );
 }
 
 #[test]
 fn avsz3() {
     let tests = load_fuzz_tests("avsz3");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("avsz3", tests);
 }
 
 #[test]
 fn avsz4() {
     let tests = load_fuzz_tests("avsz4");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("avsz4", tests);
 }
 
 #[test]
 fn cc() {
     let tests = load_fuzz_tests("cc");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("cc", tests);
 }
 
 #[test]
 fn cdp() {
     let tests = load_fuzz_tests("cdp");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("cdp", tests);
 }
 
 #[test]
 fn dcpl() {
     let tests = load_fuzz_tests("dcpl");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("dcpl", tests);
 }
 
 #[test]
 fn dpcs() {
     let tests = load_fuzz_tests("dpcs");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("dpcs", tests);
 }
 
 #[test]
 fn dpct() {
     let tests = load_fuzz_tests("dpct");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("dpct", tests);
 }
 
 #[test]
 fn gpf() {
     let tests = load_fuzz_tests("gpf");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("gpf", tests);
 }
 
 #[test]
 fn gpl() {
     let tests = load_fuzz_tests("gpl");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("gpl", tests);
 }
 
 #[test]
 fn intpl() {
     let tests = load_fuzz_tests("intpl");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("intpl", tests);
 }
 
 #[test]
 fn mvmva() {
     let tests = load_fuzz_tests("mvmva");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("mvmva", tests);
 }
 
 #[test]
 fn nccs() {
     let tests = load_fuzz_tests("nccs");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("nccs", tests);
 }
 
 #[test]
 fn ncct() {
     let tests = load_fuzz_tests("ncct");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("ncct", tests);
 }
 
 #[test]
 fn ncds() {
     let tests = load_fuzz_tests("ncds");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("ncds", tests);
 }
 
 #[test]
 fn ncdt() {
     let tests = load_fuzz_tests("ncdt");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("ncdt", tests);
 }
 
 #[test]
 fn nclip() {
     let tests = load_fuzz_tests("nclip");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("nclip", tests);
 }
 
 #[test]
 fn ncs() {
     let tests = load_fuzz_tests("ncs");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("ncs", tests);
 }
 
 #[test]
 fn nct() {
     let tests = load_fuzz_tests("nct");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("nct", tests);
 }
 
 #[test]
 fn op() {
     let tests = load_fuzz_tests("op");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("op", tests);
 }
 
 #[test]
 fn rtps() {
     let tests = load_fuzz_tests("rtps");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("rtps", tests);
 }
 
 #[test]
 fn rtpt() {
     let tests = load_fuzz_tests("rtpt");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("rtpt", tests);
 }
 
 #[test]
 fn sqr() {
     let tests = load_fuzz_tests("sqr");
-    run_gte_fuzz_suite(tests);
+    run_gte_fuzz_suite("sqr", tests);
 }
@@ -0,0 +1,47 @@
+//! Stores to instructions that are already in the I-Cache
+
+use crustationcpu::{Cpu, IcacheMode, PsxBus};
+
+use crate::common::RamBus;
+
+const T1: usize = 9;
+
+/// Runs from KSEG0 a program that replaces `ADDIU t1, zero, 1` with
+/// `ADDIU t1, zero, 2` after its cache line was filled, then runs it.
+/// Returns t1.
+fn self_modify(mode: IcacheMode) -> u32 {
+    let program = [
+        0x3c08_2409, // LUI   t0, 0x2409
+        0x3508_0002, // ORI   t0, t0, 2
+        0x3c0a_8000, // LUI   t2, 0x8000
+        0x0000_0000, // NOP
+        0xad48_001c, // SW    t0, 0x1c(t2), the line is fetched already
+        0x0000_0000, // NOP
+        0x0000_0000, // NOP
+        0x2409_0001, // ADDIU t1, zero, 1
+    ];
+
+    let bus = RamBus::new();
+    for (i, ins) in program.iter().enumerate() {
+        bus.write::<4>(i as u32 * 4, *ins);
+    }
+
+    let mut cpu = Cpu::new();
+    cpu.link(&bus);
+    cpu.icache_mode = mode;
+    cpu.pc = 0x8000_0000;
+    for _ in program {
+        cpu.step();
+    }
+    cpu.regs[T1]
+}
+
+#[test]
+fn stores_to_cached_code_are_fetched_again_when_accurate() {
+    assert_eq!(self_modify(IcacheMode::Accurate), 2);
+}
+
+#[test]
+fn stores_to_cached_code_are_not_seen_when_fast() {
+    assert_eq!(self_modify(IcacheMode::Fast), 1);
+}
@@ -1 +1,4 @@
+mod common;
 mod gte;
+mod icache;
+mod load_delay;
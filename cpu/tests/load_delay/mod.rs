@@ -0,0 +1,130 @@
+//! Corner cases of the load delay slot: what the instruction after a load
+//! (or a move from a coprocessor) sees, and which write wins when several
+//! target the same register.
+
+use crustationcpu::{Cpu, PsxBus};
+
+use crate::common::RamBus;
+
+const T0: usize = 8;
+const T1: usize = 9;
+const T2: usize = 10;
+
+/// Runs `program` from address 0, with 0x1122_3344 and 0x5566_7788 at
+/// 0x100 and 0x104, t0 set to 0xaaaa_aaaa and the GTE enabled. Stops
+/// after each instruction has run.
+fn run(program: &[u32]) -> (RamBus, Cpu<RamBus>) {
+    let bus = RamBus::new();
+    bus.write::<4>(0x100, 0x1122_3344);
+    bus.write::<4>(0x104, 0x5566_7788);
+    for (i, ins) in program.iter().enumerate() {
+        bus.write::<4>(i as u32 * 4, *ins);
+    }
+
+    let mut cpu = Cpu::new();
+    cpu.link(&bus);
+    cpu.pc = 0;
+    cpu.regs[T0] = 0xaaaa_aaaa;
+    cpu.cop0.write_reg(12, 1 << 30).unwrap();
+
+    for _ in program {
+        cpu.step();
+    }
+    (bus, cpu)
+}
+
+#[test]
+fn loads_are_not_seen_by_the_next_instruction() {
+    // LW   t0, 0x100(zero)
+    // ADDU t1, t0, zero
+    // ADDU t2, t0, zero
+    let (_, cpu) = run(&[0x8c08_0100, 0x0100_4821, 0x0100_5021]);
+
+    assert_eq!(cpu.regs[T1], 0xaaaa_aaaa);
+    assert_eq!(cpu.regs[T2], 0x1122_3344);
+}
+
+#[test]
+fn back_to_back_loads_to_one_register_keep_the_last() {
+    // LW   t0, 0x100(zero)
+    // LW   t0, 0x104(zero)
+    // ADDU t1, t0, zero
+    // NOP
+    let (_, cpu) = run(&[0x8c08_0100, 0x8c08_0104, 0x0100_4821, 0]);
+
+    // The first load never lands
+    assert_eq!(cpu.regs[T1], 0xaaaa_aaaa);
+    assert_eq!(cpu.regs[T0], 0x5566_7788);
+}
+
+#[test]
+fn writes_in_the_delay_slot_win_over_the_load() {
+    // LW    t0, 0x100(zero)
+    // ADDIU t0, zero, 7
+    // NOP
+    let (_, cpu) = run(&[0x8c08_0100, 0x2408_0007, 0]);
+
+    assert_eq!(cpu.regs[T0], 7);
+}
+
+#[test]
+fn loads_to_zero_are_dropped() {
+    // LW   zero, 0x100(zero)
+    // NOP
+    let (_, cpu) = run(&[0x8c00_0100, 0]);
+
+    assert_eq!(cpu.regs[0], 0);
+}
+
+#[test]
+fn unaligned_pairs_merge_in_flight() {
+    // LWR  t0, 0x101(zero)
+    // LWL  t0, 0x104(zero)
+    // ADDU t1, t0, zero
+    // NOP
+    let (_, cpu) = run(&[0x9808_0101, 0x8808_0104, 0x0100_4821, 0]);
+
+    // Nothing of the pair is visible right after it
+    assert_eq!(cpu.regs[T1], 0xaaaa_aaaa);
+    assert_eq!(cpu.regs[T0], 0x8811_2233);
+}
+
+#[test]
+fn moves_from_coprocessors_are_delayed() {
+    // MFC0 t0, SR
+    // ADDU t1, t0, zero
+    // NOP
+    let (_, cpu) = run(&[0x4008_6000, 0x0100_4821, 0]);
+
+    assert_eq!(cpu.regs[T1], 0xaaaa_aaaa);
+    assert_eq!(cpu.regs[T0], 1 << 30);
+
+    // MTC2 t0, VXY0
+    // MFC2 t1, VXY0
+    // ADDU t2, t1, zero
+    // NOP
+    let (_, cpu) = run(&[0x4888_0000, 0x4809_0000, 0x0120_5021, 0]);
+
+    assert_eq!(cpu.regs[T2], 0);
+    assert_eq!(cpu.regs[T1], 0xaaaa_aaaa);
+}
+
+#[test]
+fn gte_loads_are_seen_at_once() {
+    // LWC2 VXY0, 0x100(zero)
+    // SWC2 VXY0, 0x108(zero)
+    let (bus, mut cpu) = run(&[0xc800_0100, 0xe800_0108]);
+
+    assert_eq!(bus.read::<4>(0x108), 0x1122_3344);
+    assert_eq!(cpu.gte.read_reg(0), 0x1122_3344);
+}
+
+#[test]
+fn gte_loads_leave_pending_cpu_loads_alone() {
+    // LW   t0, 0x104(zero)
+    // LWC2 r8, 0x100(zero)
+    // NOP
+    let (_, cpu) = run(&[0x8c08_0104, 0xc808_0100, 0]);
+
+    assert_eq!(cpu.regs[T0], 0x5566_7788);
+}
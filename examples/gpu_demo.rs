@@ -0,0 +1,221 @@
+//! Drives the GPU and the GTE directly, without a BIOS or a game: a rotating
+//! cube, projected with RTPT, over a gradient backdrop.
+//!
+//!     cargo run --example gpu_demo [-- --frames=N]
+//!
+//! Handy to check the renderer end to end when changing it.
+
+use std::cell::RefCell;
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+use crustationcpu::gte::Gte;
+use crustationcpu::PsxBus;
+use psx::hw::bus::Bus;
+use psx::hw::RendererOptions;
+
+const GP0: u32 = 0x1f80_1810;
+const GP1: u32 = 0x1f80_1814;
+
+const CYCLES_PER_FRAME: u64 = 33_868_800 / 60;
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+
+/// Half the side of the cube
+const SIZE: i16 = 256;
+
+/// Corners in Z order (two triangles: 0-1-2 and 1-2-3), clockwise on screen
+/// when facing the camera. Vertex n is at (±SIZE, ±SIZE, ±SIZE), with bit 0,
+/// 1 and 2 of n selecting the sign of x, y and z.
+const FACES: [[usize; 4]; 6] = [
+    [0, 1, 2, 3],
+    [4, 6, 5, 7],
+    [0, 2, 4, 6],
+    [1, 5, 3, 7],
+    [0, 4, 1, 5],
+    [2, 3, 6, 7],
+];
+
+fn gp0(bus: &Bus, words: &[u32]) {
+    for &word in words {
+        bus.write::<4>(GP0, word);
+    }
+}
+
+fn gp1(bus: &Bus, command: u32) {
+    bus.write::<4>(GP1, command);
+}
+
+fn vertex(x: u32, y: u32) -> u32 {
+    (y << 16) | x
+}
+
+fn setup_display(bus: &Bus) {
+    gp1(bus, 0x0000_0000); // Reset
+    gp1(bus, 0x0800_0001); // 320x240
+    gp1(bus, 0x0500_0000); // Display from (0, 0)
+    gp1(bus, 0x0300_0000); // Display on
+
+    gp0(bus, &[0xe300_0000]); // Drawing area from (0, 0)...
+    gp0(bus, &[0xe400_0000 | ((HEIGHT - 1) << 10) | (WIDTH - 1)]); // ...to (319, 239)
+    gp0(bus, &[0xe500_0000]); // No offset
+}
+
+fn draw_backdrop(bus: &Bus) {
+    let (top, bottom) = (0x40_1010, 0x10_1040);
+
+    // Gradient triangles
+    gp0(bus, &[0x3000_0000 | top, vertex(0, 0), top, vertex(WIDTH, 0), bottom, vertex(0, HEIGHT)]);
+    gp0(bus, &[0x3000_0000 | top, vertex(WIDTH, 0), bottom, vertex(0, HEIGHT), bottom, vertex(WIDTH, HEIGHT)]);
+
+    // Textured quad, raw texture, page 0 and CLUT at (0, 480)
+    gp0(
+        bus,
+        &[
+            0x2d80_8080,
+            vertex(16, 16),
+            0x7800 << 16,
+            vertex(80, 16),
+            0x003f,
+            vertex(16, 80),
+            0x3f00,
+            vertex(80, 80),
+            0x3f3f,
+        ],
+    );
+}
+
+/// Rotation matrix around the Y then the X axis, in 1.3.12 fixed point
+fn rotation(yaw: f32, pitch: f32) -> [[i16; 3]; 3] {
+    let (sy, cy) = yaw.sin_cos();
+    let (sp, cp) = pitch.sin_cos();
+    let m = [[cy, 0.0, sy], [sp * sy, cp, -sp * cy], [-cp * sy, sp, cp * cy]];
+
+    m.map(|row| row.map(|v| (v * 4096.0) as i16))
+}
+
+fn setup_gte(gte: &mut Gte, m: [[i16; 3]; 3]) {
+    let pair = |a: i16, b: i16| (a as u16 as u32) | ((b as u16 as u32) << 16);
+
+    // Rotation
+    gte.write_reg(32, pair(m[0][0], m[0][1]));
+    gte.write_reg(33, pair(m[0][2], m[1][0]));
+    gte.write_reg(34, pair(m[1][1], m[1][2]));
+    gte.write_reg(35, pair(m[2][0], m[2][1]));
+    gte.write_reg(36, m[2][2] as u32);
+
+    // Translation: in front of the camera
+    gte.write_reg(37, 0);
+    gte.write_reg(38, 0);
+    gte.write_reg(39, 1024);
+
+    // Screen offset (16.16) and projection plane distance
+    gte.write_reg(56, (WIDTH / 2) << 16);
+    gte.write_reg(57, (HEIGHT / 2) << 16);
+    gte.write_reg(58, 240);
+}
+
+/// Screen coordinates of the corners of the cube, as GP0 vertices
+fn project(gte: &mut Gte) -> [u32; 8] {
+    let corner = |n: usize| {
+        let sign = |bit: usize| if n & (1 << bit) != 0 { SIZE } else { -SIZE };
+        (sign(0), sign(1), sign(2))
+    };
+
+    let mut screen = [0; 8];
+    for first in (0..8).step_by(3) {
+        for i in 0..3 {
+            // The last RTPT projects corner 7 three times
+            let (x, y, z) = corner((first + i).min(7));
+            gte.write_reg(2 * i as u32, (x as u16 as u32) | ((y as u16 as u32) << 16));
+            gte.write_reg(2 * i as u32 + 1, z as u32);
+        }
+
+        // RTPT, with the fraction shifted out
+        gte.execute(0x0008_0030);
+
+        for i in 0..3 {
+            if first + i < 8 {
+                screen[first + i] = gte.read_reg(12 + i as u32);
+            }
+        }
+    }
+
+    screen
+}
+
+/// Whether the face is towards the camera
+fn visible(gte: &mut Gte, corners: [u32; 3]) -> bool {
+    for (i, &corner) in corners.iter().enumerate() {
+        gte.write_reg(12 + i as u32, corner);
+    }
+
+    // NCLIP
+    gte.execute(0x0000_0006);
+    (gte.read_reg(24) as i32) > 0
+}
+
+fn draw_cube(bus: &Bus, gte: &mut Gte, frame: u32) {
+    let angle = frame as f32 * PI / 120.0;
+    setup_gte(gte, rotation(angle, angle * 0.6));
+
+    let screen = project(gte);
+    let color = |n: usize| {
+        let channel = |bit: usize| if n & (1 << bit) != 0 { 0xf0 } else { 0x30 };
+        channel(0) | (channel(1) << 8) | (channel(2) << 16)
+    };
+
+    for face in FACES {
+        if !visible(gte, [screen[face[0]], screen[face[1]], screen[face[2]]]) {
+            continue;
+        }
+
+        // Shaded quad
+        gp0(
+            bus,
+            &[
+                0x3800_0000 | color(face[0]),
+                screen[face[0]],
+                color(face[1]),
+                screen[face[1]],
+                color(face[2]),
+                screen[face[2]],
+                color(face[3]),
+                screen[face[3]],
+            ],
+        );
+    }
+}
+
+fn main() {
+    let mut frames = None;
+    for arg in std::env::args().skip(1) {
+        if let Some(n) = arg.strip_prefix("--frames=") {
+            frames = Some(n.parse::<u32>().expect("Invalid --frames value"));
+        }
+    }
+
+    let bus = Rc::new(RefCell::new(Bus::new()));
+    bus.borrow().link(bus.clone());
+    bus.borrow().load_renderer(&RendererOptions::default());
+
+    let bus = bus.borrow();
+    let mut gte = Gte::new();
+
+    setup_display(&bus);
+
+    // Fill the whole VRAM with black
+    gp0(&bus, &[0x0200_0000, vertex(0, 0), vertex(1024, 512)]);
+
+    let mut frame = 0;
+    while frames.is_none_or(|frames| frame < frames) {
+        draw_backdrop(&bus);
+        draw_cube(&bus, &mut gte, frame);
+
+        // Nothing runs on the CPU: just let the time of a frame go by, the
+        // VBlank shows what was drawn
+        bus.update_cycles(CYCLES_PER_FRAME);
+        frame += 1;
+    }
+}
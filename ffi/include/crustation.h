@@ -0,0 +1,127 @@
+/*
+ * C interface to the cruStation emulator core.
+ *
+ * The emulator runs headless: no window is opened. All functions must be
+ * called from the thread that created the emulator. Functions returning an
+ * int return 0 on success and -1 on failure (including when the emulated
+ * machine crashes, after which the emulator must be destroyed).
+ */
+
+#ifndef CRUSTATION_H
+#define CRUSTATION_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Crustation Crustation;
+
+/* Digital pad buttons, for crustation_set_buttons. Pressed buttons are set. */
+#define CRUSTATION_BUTTON_SELECT   (1 << 0)
+#define CRUSTATION_BUTTON_START    (1 << 3)
+#define CRUSTATION_BUTTON_UP       (1 << 4)
+#define CRUSTATION_BUTTON_RIGHT    (1 << 5)
+#define CRUSTATION_BUTTON_DOWN     (1 << 6)
+#define CRUSTATION_BUTTON_LEFT     (1 << 7)
+#define CRUSTATION_BUTTON_L2       (1 << 8)
+#define CRUSTATION_BUTTON_R2       (1 << 9)
+#define CRUSTATION_BUTTON_L1       (1 << 10)
+#define CRUSTATION_BUTTON_R1       (1 << 11)
+#define CRUSTATION_BUTTON_TRIANGLE (1 << 12)
+#define CRUSTATION_BUTTON_CIRCLE   (1 << 13)
+#define CRUSTATION_BUTTON_CROSS    (1 << 14)
+#define CRUSTATION_BUTTON_SQUARE   (1 << 15)
+
+/* Creates a new emulator. Returns NULL on failure. */
+Crustation *crustation_create(void);
+
+/* Destroys an emulator. Passing NULL is allowed. */
+void crustation_destroy(Crustation *emu);
+
+/* Loads the BIOS image at `path`. Must be called before running. */
+int crustation_load_bios(Crustation *emu, const char *path);
+
+/*
+ * Plugs the cartridge ROM at `path` (e.g. Caetla or Unirom) into the
+ * parallel port. The BIOS starts it at boot.
+ */
+int crustation_load_exp1_rom(Crustation *emu, const char *path);
+
+/*
+ * Inserts the disc image at `path`, a .cue sheet or a single .bin track.
+ * The BIOS boots it.
+ */
+int crustation_load_disc(Crustation *emu, const char *path);
+
+/* Boots the BIOS up to the shell, then side-loads the PS-X EXE at `path`. */
+int crustation_load_exe(Crustation *emu, const char *path);
+
+/* Runs the emulator until the next VBlank. */
+int crustation_run_frame(Crustation *emu);
+
+/* Sets the pressed buttons of the pad in port 1 (CRUSTATION_BUTTON_*). */
+int crustation_set_buttons(Crustation *emu, uint16_t buttons);
+
+/*
+ * Stores in `frame` the number of the frame crustation_run_frame runs next,
+ * counting VBlanks from power on.
+ */
+int crustation_frame(Crustation *emu, uint64_t *frame);
+
+/*
+ * Sets the pressed buttons at the start of `frame`, where interactive input
+ * is read too, so that scripted runs replay exactly. Fails if the frame is
+ * not after the current one.
+ */
+int crustation_schedule_buttons(Crustation *emu, uint64_t frame, uint16_t buttons);
+
+/*
+ * Copies `len` bytes of main RAM starting at `addr` into `buf`. Any KUSEG,
+ * KSEG0 or KSEG1 address of the 8MB RAM window is accepted. Fails if the
+ * range extends outside RAM.
+ */
+int crustation_read_memory(Crustation *emu, uint32_t addr, uint8_t *buf, size_t len);
+
+/* Copies `len` bytes from `buf` into main RAM, starting at `addr`. */
+int crustation_write_memory(Crustation *emu, uint32_t addr, const uint8_t *buf, size_t len);
+
+/*
+ * Moves up to `len` bytes of BIOS console output (putchar) not read yet
+ * into `buf`, without a terminating NUL. Test ROMs usually report their
+ * results there. Returns the number of bytes copied, or -1 on failure.
+ */
+int crustation_read_tty(Crustation *emu, char *buf, size_t len);
+
+/*
+ * Copies up to `len` stereo samples (44.1 kHz, left then right) produced
+ * while `frame` ran into `buf`. The sound follows the emulated cycles, so
+ * a scripted run gives the same samples every time. The last 60 frames
+ * are kept. Returns the number of samples of the frame, which may be more
+ * than `len`, or -1 if the frame is not kept.
+ */
+int crustation_get_audio_samples(Crustation *emu, uint64_t frame, int16_t *buf, size_t len);
+
+/*
+ * Copies up to `len` pixels (0x00RRGGBB, line by line) of the picture
+ * displayed at the last VBlank into `buf`, and stores its size in `width`
+ * and `height` unless they are NULL. Returns the number of pixels of the
+ * picture, which may be more than `len`, or -1 on failure.
+ */
+int crustation_get_video_frame(Crustation *emu, uint32_t *buf, size_t len, uint16_t *width,
+                               uint16_t *height);
+
+/*
+ * Stores in `hash` the 64-bit FNV-1a hash of the size and pixels of the
+ * picture displayed at the last VBlank, the one printed by --screenshot.
+ * Cheaper than comparing the pixels of whole runs.
+ */
+int crustation_video_frame_hash(Crustation *emu, uint64_t *hash);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CRUSTATION_H */
@@ -0,0 +1,230 @@
+"""Python bindings to the cruStation emulator core, for scripted testing.
+
+Build the shared library first:
+
+    cd ffi && cargo build --release
+
+then point CRUSTATION_LIB at ffi/target/release/libcrustation.so (or
+place this file next to it). Example:
+
+    from crustation import Button, Emulator
+
+    with Emulator("bios/SCPH1001.BIN") as emu:
+        emu.load_exe("hello.exe")
+        emu.run_frames(60)
+        emu.set_buttons(Button.START)
+        emu.run_frames(10)
+        print(emu.read_u32(0x8001_0000), emu.ram_hash())
+"""
+
+import ctypes
+import enum
+import hashlib
+import os
+import struct
+
+RAM_SIZE = 2 * 1024 * 1024
+
+
+class CrustationError(Exception):
+    pass
+
+
+class Button(enum.IntFlag):
+    SELECT = 1 << 0
+    START = 1 << 3
+    UP = 1 << 4
+    RIGHT = 1 << 5
+    DOWN = 1 << 6
+    LEFT = 1 << 7
+    L2 = 1 << 8
+    R2 = 1 << 9
+    L1 = 1 << 10
+    R1 = 1 << 11
+    TRIANGLE = 1 << 12
+    CIRCLE = 1 << 13
+    CROSS = 1 << 14
+    SQUARE = 1 << 15
+
+
+def _default_library_path():
+    if "CRUSTATION_LIB" in os.environ:
+        return os.environ["CRUSTATION_LIB"]
+
+    here = os.path.dirname(os.path.abspath(__file__))
+    for candidate in (
+        os.path.join(here, "libcrustation.so"),
+        os.path.join(here, "..", "target", "release", "libcrustation.so"),
+        os.path.join(here, "..", "target", "debug", "libcrustation.so"),
+    ):
+        if os.path.exists(candidate):
+            return candidate
+
+    raise CrustationError("libcrustation not found, set CRUSTATION_LIB")
+
+
+def _load_library(path):
+    lib = ctypes.CDLL(path)
+
+    lib.crustation_create.restype = ctypes.c_void_p
+    lib.crustation_create.argtypes = []
+    lib.crustation_destroy.restype = None
+    lib.crustation_destroy.argtypes = [ctypes.c_void_p]
+
+    for name in ("crustation_load_bios", "crustation_load_disc", "crustation_load_exe"):
+        getattr(lib, name).restype = ctypes.c_int
+        getattr(lib, name).argtypes = [ctypes.c_void_p, ctypes.c_char_p]
+
+    lib.crustation_run_frame.restype = ctypes.c_int
+    lib.crustation_run_frame.argtypes = [ctypes.c_void_p]
+    lib.crustation_set_buttons.restype = ctypes.c_int
+    lib.crustation_set_buttons.argtypes = [ctypes.c_void_p, ctypes.c_uint16]
+    lib.crustation_frame.restype = ctypes.c_int
+    lib.crustation_frame.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_uint64)]
+    lib.crustation_schedule_buttons.restype = ctypes.c_int
+    lib.crustation_schedule_buttons.argtypes = [ctypes.c_void_p, ctypes.c_uint64, ctypes.c_uint16]
+
+    for name in ("crustation_read_memory", "crustation_write_memory"):
+        getattr(lib, name).restype = ctypes.c_int
+        getattr(lib, name).argtypes = [
+            ctypes.c_void_p,
+            ctypes.c_uint32,
+            ctypes.c_char_p,
+            ctypes.c_size_t,
+        ]
+
+    lib.crustation_read_tty.restype = ctypes.c_int
+    lib.crustation_read_tty.argtypes = [ctypes.c_void_p, ctypes.c_char_p, ctypes.c_size_t]
+
+    lib.crustation_get_audio_samples.restype = ctypes.c_int
+    lib.crustation_get_audio_samples.argtypes = [
+        ctypes.c_void_p,
+        ctypes.c_uint64,
+        ctypes.POINTER(ctypes.c_int16),
+        ctypes.c_size_t,
+    ]
+
+    lib.crustation_video_frame_hash.restype = ctypes.c_int
+    lib.crustation_video_frame_hash.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_uint64)]
+
+    return lib
+
+
+class Emulator:
+    """A headless emulator instance. Not thread safe."""
+
+    def __init__(self, bios, library=None):
+        self._lib = _load_library(library or _default_library_path())
+        self._emu = self._lib.crustation_create()
+        if not self._emu:
+            raise CrustationError("Could not create the emulator")
+
+        self._check(self._lib.crustation_load_bios(self._emu, os.fsencode(bios)), "load_bios")
+
+    def close(self):
+        if self._emu:
+            self._lib.crustation_destroy(self._emu)
+            self._emu = None
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        self.close()
+
+    def __del__(self):
+        self.close()
+
+    def _check(self, result, what):
+        if result != 0:
+            raise CrustationError(what + " failed")
+
+    def load_disc(self, path):
+        """Inserts a disc image, .cue or .bin. The BIOS boots it."""
+        self._check(self._lib.crustation_load_disc(self._emu, os.fsencode(path)), "load_disc")
+
+    def load_exe(self, path):
+        """Boots the BIOS up to the shell, then side-loads a PS-X EXE."""
+        self._check(self._lib.crustation_load_exe(self._emu, os.fsencode(path)), "load_exe")
+
+    def run_frame(self):
+        self._check(self._lib.crustation_run_frame(self._emu), "run_frame")
+
+    def run_frames(self, count):
+        for _ in range(count):
+            self.run_frame()
+
+    def set_buttons(self, buttons):
+        """Sets the pressed buttons of the pad in port 1 (a Button mask)."""
+        self._check(self._lib.crustation_set_buttons(self._emu, int(buttons)), "set_buttons")
+
+    @property
+    def frame(self):
+        """The frame run_frame runs next, counting VBlanks from power on."""
+        frame = ctypes.c_uint64()
+        self._check(self._lib.crustation_frame(self._emu, ctypes.byref(frame)), "frame")
+        return frame.value
+
+    def schedule_buttons(self, frame, buttons):
+        """Sets the pressed buttons at the start of a frame after the current one."""
+        self._check(
+            self._lib.crustation_schedule_buttons(self._emu, frame, int(buttons)),
+            "schedule_buttons",
+        )
+
+    def read(self, addr, length):
+        buf = ctypes.create_string_buffer(length)
+        self._check(self._lib.crustation_read_memory(self._emu, addr, buf, length), "read")
+        return buf.raw
+
+    def write(self, addr, data):
+        data = bytes(data)
+        self._check(self._lib.crustation_write_memory(self._emu, addr, data, len(data)), "write")
+
+    def read_tty(self):
+        """BIOS console output since the last call, as text."""
+        buf = ctypes.create_string_buffer(4096)
+        output = b""
+        while True:
+            count = self._lib.crustation_read_tty(self._emu, buf, len(buf))
+            if count < 0:
+                raise CrustationError("read_tty failed")
+            if count == 0:
+                return output.decode("latin-1")
+            output += buf.raw[:count]
+
+    def _audio(self, frame):
+        count = self._lib.crustation_get_audio_samples(self._emu, frame, None, 0)
+        if count < 0:
+            raise CrustationError("get_audio_samples failed")
+        buf = (ctypes.c_int16 * (2 * count))()
+        self._lib.crustation_get_audio_samples(self._emu, frame, buf, count)
+        return buf
+
+    def get_audio_samples(self, frame):
+        """Sound of one of the last 60 frames, as (left, right) pairs at 44.1 kHz."""
+        buf = self._audio(frame)
+        return list(zip(buf[0::2], buf[1::2]))
+
+    def audio_hash(self, frame):
+        """SHA-1 of the sound of a frame, to compare runs like ram_hash."""
+        return hashlib.sha1(bytes(self._audio(frame))).hexdigest()
+
+    def frame_hash(self):
+        """Hash of the picture of the last frame, as printed by --screenshot."""
+        frame_hash = ctypes.c_uint64()
+        self._check(
+            self._lib.crustation_video_frame_hash(self._emu, ctypes.byref(frame_hash)),
+            "frame_hash",
+        )
+        return "%016x" % frame_hash.value
+
+    def read_u32(self, addr):
+        return struct.unpack("<I", self.read(addr, 4))[0]
+
+    def write_u32(self, addr, value):
+        self.write(addr, struct.pack("<I", value))
+
+    def ram_hash(self):
+        """SHA-1 of the whole main RAM, to compare emulator states."""
+        return hashlib.sha1(self.read(0, RAM_SIZE)).hexdigest()
@@ -0,0 +1,260 @@
+//! The emulator as a library, for frontends that bring their own window,
+//! input and sound: libretro cores, tests, benchmarks. It runs headless,
+//! one frame at a time, and hands out the picture and the sound.
+
+use std::cell::{Ref, RefCell};
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc;
+
+use psx::hw::bus::Bus;
+use psx::hw::exe::ExeError;
+use psx::hw::fill::Fill;
+use psx::hw::input::{InputError, PadInput};
+use psx::hw::{DiscError, GpuAccuracy, IcacheMode, VideoFrame};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// Keep the sound output for `audio_samples`. Without it, it's dropped.
+    pub audio: bool,
+    /// Frames whose sound `frame_audio` can return, counting back from the
+    /// last one run
+    pub audio_frames: usize,
+    /// Start the pad in analog mode, for games that don't switch it
+    pub analog: bool,
+    /// Content of main RAM at power on
+    pub ram_fill: Fill,
+    /// How closely drawing to VRAM follows the hardware
+    pub gpu_accuracy: GpuAccuracy,
+    /// Whether stores to cached code are seen by the CPU
+    pub icache: IcacheMode,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            audio: true,
+            audio_frames: 60,
+            analog: false,
+            ram_fill: Fill::Zeros,
+            gpu_accuracy: GpuAccuracy::Balanced,
+            icache: IcacheMode::Fast,
+        }
+    }
+}
+
+/// The pad, as held by the player
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InputState {
+    /// Pressed buttons, in the pad's bit order (Select is bit 0, Square 15)
+    pub buttons: u16,
+    /// Right X, right Y, left X and left Y, 0x80 at the center
+    pub axes: [u8; 4],
+}
+
+impl Default for InputState {
+    fn default() -> InputState {
+        InputState {
+            buttons: 0,
+            axes: [0x80; 4],
+        }
+    }
+}
+
+impl InputState {
+    fn pad_input(&self) -> PadInput {
+        PadInput {
+            // The pad reports pressed buttons as 0
+            buttons: !self.buttons,
+            axes: self.axes,
+        }
+    }
+}
+
+pub struct Emulator {
+    bus: Rc<RefCell<Bus>>,
+    audio: Option<mpsc::Receiver<[i16; 2]>>,
+}
+
+impl Emulator {
+    pub fn new(config: Config) -> Emulator {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+
+        let emu = Emulator {
+            audio: config.audio.then(|| bus.borrow().connect_audio()),
+            bus,
+        };
+
+        let bus = emu.bus();
+        // The frontend decides how fast to call run_frame
+        bus.set_speed(None);
+        bus.set_ram_fill(config.ram_fill);
+        bus.set_analog(config.analog);
+        bus.set_gpu_accuracy(config.gpu_accuracy);
+        bus.set_icache_mode(config.icache);
+        if config.audio_frames > 0 {
+            bus.capture_audio(config.audio_frames);
+        }
+        drop(bus);
+        emu
+    }
+
+    pub fn load_bios(&self, path: &Path) -> io::Result<()> {
+        self.bus().load_rom(path)
+    }
+
+    /// Plugs a cartridge ROM into the parallel port, started by the BIOS
+    pub fn load_exp1_rom(&self, path: &Path) -> io::Result<()> {
+        self.bus().load_exp1_rom(path)
+    }
+
+    /// Inserts a disc image, .cue or .bin. The BIOS boots it.
+    pub fn load_disc(&self, path: &Path) -> Result<(), DiscError> {
+        self.bus().load_disc(path)
+    }
+
+    /// Runs a PS-X EXE in place of the BIOS shell, once it has booted
+    pub fn load_exe(&self, path: &Path, args: &[&str]) -> Result<(), ExeError> {
+        self.bus().sideload_exe(path, args, false)
+    }
+
+    /// Runs until the next VBlank
+    pub fn run_frame(&self) {
+        self.bus().run_frame();
+    }
+
+    /// Sets the pad now, for the rest of the current frame
+    pub fn set_input_state(&self, input: &InputState) {
+        let input = input.pad_input();
+        let bus = self.bus();
+        bus.set_buttons(input.buttons);
+        bus.set_axes(input.axes);
+    }
+
+    /// The number of the frame `run_frame` runs next, counting from power
+    /// on. Its input is set already.
+    pub fn frame(&self) -> u64 {
+        self.bus().frame()
+    }
+
+    /// Sets the pad at the start of `frame`, at the same point as the input
+    /// of the window in interactive play, so that scripted runs replay
+    /// exactly. Only frames after `frame()` can be scheduled.
+    pub fn schedule_input(&self, frame: u64, input: &InputState) -> Result<(), InputError> {
+        self.bus().schedule_input(frame, input.pad_input())
+    }
+
+    /// The picture of the last frame
+    pub fn video_frame(&self) -> VideoFrame {
+        self.bus().video_frame()
+    }
+
+    /// Stereo samples at 44.1 kHz produced since the last call, about 735
+    /// per NTSC frame. Empty if the config has no audio.
+    pub fn audio_samples(&self) -> Vec<[i16; 2]> {
+        match &self.audio {
+            Some(audio) => audio.try_iter().collect(),
+            None => vec![],
+        }
+    }
+
+    /// Stereo samples at 44.1 kHz produced while `frame` ran. They only
+    /// depend on the emulated machine, never on how fast frames are run or
+    /// whether `audio_samples` is called, so tests can hash them. None if
+    /// the frame hasn't run yet or is older than `Config::audio_frames`.
+    pub fn frame_audio(&self, frame: u64) -> Option<Vec<[i16; 2]>> {
+        self.bus().audio_samples(frame)
+    }
+
+    /// The machine, for what the facade doesn't cover (memory, debugging)
+    pub fn bus(&self) -> Ref<'_, Bus> {
+        self.bus.borrow()
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        self.bus().shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boots_the_bios_headless() {
+        let emu = Emulator::new(Config::default());
+        assert!(emu.load_bios(Path::new("../bios/missing.bin")).is_err());
+        emu.load_bios(Path::new("../bios/SCPH1001.BIN")).unwrap();
+
+        // The Sony logo is drawn after about 3 seconds
+        for _ in 0..240 {
+            emu.set_input_state(&InputState::default());
+            emu.run_frame();
+        }
+        let frame = emu.video_frame();
+        assert_eq!(frame.pixels.len(), frame.width as usize * frame.height as usize);
+        assert!(frame.pixels.iter().any(|&pixel| pixel != 0));
+
+        // Only what was produced since the last call
+        let samples = emu.audio_samples().len();
+        assert!(samples > 0 && samples <= 4096);
+        emu.run_frame();
+        assert!((700..800).contains(&emu.audio_samples().len()));
+    }
+
+    #[test]
+    fn frames_replay_the_same_picture_and_sound() {
+        let run = || {
+            let emu = Emulator::new(Config {
+                audio: false,
+                ..Config::default()
+            });
+            emu.load_bios(Path::new("../bios/SCPH1001.BIN")).unwrap();
+
+            let mut frames = vec![];
+            for frame in 0..240 {
+                emu.run_frame();
+                frames.push((emu.video_frame().pixels, emu.frame_audio(frame).unwrap()));
+            }
+            assert_eq!(emu.frame_audio(240), None);
+            assert_eq!(emu.frame_audio(100), None);
+            frames
+        };
+
+        // One sample every 768 cycles, however late the SPU event runs: an
+        // NTSC frame lasts 743.8 samples, a bit less when interlaced
+        let frames = run();
+        let samples: usize = frames.iter().map(|(_, audio)| audio.len()).sum();
+        assert!((240 * 743..=240 * 744).contains(&samples));
+        assert!(frames == run());
+    }
+
+    #[test]
+    fn scheduled_input_follows_the_frames() {
+        let emu = Emulator::new(Config {
+            audio: false,
+            ..Config::default()
+        });
+        emu.load_bios(Path::new("../bios/SCPH1001.BIN")).unwrap();
+        emu.run_frame();
+        emu.run_frame();
+        assert_eq!(emu.frame(), 2);
+
+        let start = InputState {
+            buttons: 1 << 3,
+            ..InputState::default()
+        };
+        assert!(emu.schedule_input(2, &start).is_err());
+        emu.schedule_input(4, &start).unwrap();
+
+        emu.run_frame();
+        assert_eq!(emu.bus().pad_input().buttons, 0xffff);
+        emu.run_frame();
+        assert_eq!(emu.frame(), 4);
+        assert_eq!(emu.bus().pad_input().buttons, !(1 << 3));
+    }
+}
@@ -0,0 +1,434 @@
+//! The emulator core as a library: `Emulator` for Rust frontends, and a C
+//! interface over it, see include/crustation.h
+
+mod emulator;
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+
+pub use emulator::{Config, Emulator, InputState};
+pub use psx::hw::exe::ExeError;
+pub use psx::hw::input::InputError;
+pub use psx::hw::{GpuAccuracy, IcacheMode, VideoFrame};
+
+pub struct Crustation {
+    emu: Emulator,
+}
+
+impl Crustation {
+    fn new() -> Crustation {
+        // The sound is only kept by frame, see crustation_get_audio_samples
+        let config = Config {
+            audio: false,
+            ..Config::default()
+        };
+        Crustation {
+            emu: Emulator::new(config),
+        }
+    }
+}
+
+/// Runs `f` on the emulator, turning null pointers, failures (`f` returning
+/// false) and emulator panics into an error code. Panics must not unwind
+/// into C.
+fn with_emu<F: FnOnce(&Emulator) -> bool>(emu: *mut Crustation, f: F) -> c_int {
+    let emu = match unsafe { emu.as_ref() } {
+        Some(emu) => emu,
+        None => return -1,
+    };
+
+    match catch_unwind(AssertUnwindSafe(|| f(&emu.emu))) {
+        Ok(true) => 0,
+        Ok(false) | Err(_) => -1,
+    }
+}
+
+fn path_arg(path: *const c_char) -> Option<String> {
+    if path.is_null() {
+        return None;
+    }
+
+    let path = unsafe { CStr::from_ptr(path) };
+    path.to_str().ok().map(String::from)
+}
+
+#[no_mangle]
+pub extern "C" fn crustation_create() -> *mut Crustation {
+    match catch_unwind(Crustation::new) {
+        Ok(emu) => Box::into_raw(Box::new(emu)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `emu` must be NULL or a pointer returned by crustation_create, not
+/// destroyed yet.
+#[no_mangle]
+pub unsafe extern "C" fn crustation_destroy(emu: *mut Crustation) {
+    if !emu.is_null() {
+        let emu = Box::from_raw(emu);
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(emu)));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn crustation_load_bios(emu: *mut Crustation, path: *const c_char) -> c_int {
+    match path_arg(path) {
+        Some(path) => with_emu(emu, |emu| emu.load_bios(Path::new(&path)).is_ok()),
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn crustation_load_exp1_rom(emu: *mut Crustation, path: *const c_char) -> c_int {
+    match path_arg(path) {
+        Some(path) => with_emu(emu, |emu| emu.load_exp1_rom(Path::new(&path)).is_ok()),
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn crustation_load_disc(emu: *mut Crustation, path: *const c_char) -> c_int {
+    match path_arg(path) {
+        Some(path) => with_emu(emu, |emu| emu.load_disc(Path::new(&path)).is_ok()),
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn crustation_load_exe(emu: *mut Crustation, path: *const c_char) -> c_int {
+    match path_arg(path) {
+        Some(path) => with_emu(emu, |emu| {
+            // Let the BIOS initialize the hardware, then replace the shell
+            if emu.load_exe(Path::new(&path), &[]).is_err() {
+                return false;
+            }
+            while emu.bus().sideload_pending() {
+                emu.run_frame();
+            }
+            true
+        }),
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn crustation_run_frame(emu: *mut Crustation) -> c_int {
+    with_emu(emu, |emu| {
+        emu.run_frame();
+        true
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn crustation_set_buttons(emu: *mut Crustation, buttons: u16) -> c_int {
+    with_emu(emu, |emu| {
+        let input = InputState {
+            buttons,
+            ..InputState::default()
+        };
+        emu.set_input_state(&input);
+        true
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn crustation_schedule_buttons(
+    emu: *mut Crustation,
+    frame: u64,
+    buttons: u16,
+) -> c_int {
+    with_emu(emu, |emu| {
+        let input = InputState {
+            buttons,
+            ..InputState::default()
+        };
+        emu.schedule_input(frame, &input).is_ok()
+    })
+}
+
+/// # Safety
+///
+/// `frame` must be valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn crustation_frame(emu: *mut Crustation, frame: *mut u64) -> c_int {
+    if frame.is_null() {
+        return -1;
+    }
+    with_emu(emu, |emu| {
+        *frame = emu.frame();
+        true
+    })
+}
+
+/// # Safety
+///
+/// `buf` must be valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn crustation_read_memory(
+    emu: *mut Crustation,
+    addr: u32,
+    buf: *mut u8,
+    len: usize,
+) -> c_int {
+    if buf.is_null() {
+        return -1;
+    }
+
+    let buf = std::slice::from_raw_parts_mut(buf, len);
+    with_emu(emu, |emu| {
+        let bus = emu.bus();
+        for (i, byte) in buf.iter_mut().enumerate() {
+            match bus.peek_ram(addr.wrapping_add(i as u32)) {
+                Some(value) => *byte = value,
+                None => return false,
+            }
+        }
+        true
+    })
+}
+
+/// # Safety
+///
+/// `buf` must be valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn crustation_write_memory(
+    emu: *mut Crustation,
+    addr: u32,
+    buf: *const u8,
+    len: usize,
+) -> c_int {
+    if buf.is_null() {
+        return -1;
+    }
+
+    let buf = std::slice::from_raw_parts(buf, len);
+    with_emu(emu, |emu| {
+        let bus = emu.bus();
+        buf.iter()
+            .enumerate()
+            .all(|(i, &value)| bus.poke_ram(addr.wrapping_add(i as u32), value))
+    })
+}
+
+/// Returns the number of bytes copied, or -1.
+///
+/// # Safety
+///
+/// `buf` must be valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn crustation_read_tty(
+    emu: *mut Crustation,
+    buf: *mut c_char,
+    len: usize,
+) -> c_int {
+    if buf.is_null() {
+        return -1;
+    }
+
+    // Counts are returned as an int
+    let len = len.min(c_int::MAX as usize);
+    let buf = std::slice::from_raw_parts_mut(buf as *mut u8, len);
+    let mut count = 0;
+    match with_emu(emu, |emu| {
+        count = emu.bus().read_tty(buf);
+        true
+    }) {
+        0 => count as c_int,
+        error => error,
+    }
+}
+
+/// Returns the number of stereo samples of `frame`, of which at most `len`
+/// are copied, or -1.
+///
+/// # Safety
+///
+/// `buf` must be valid for writes of `len` pairs of `i16`.
+#[no_mangle]
+pub unsafe extern "C" fn crustation_get_audio_samples(
+    emu: *mut Crustation,
+    frame: u64,
+    buf: *mut i16,
+    len: usize,
+) -> c_int {
+    if buf.is_null() && len > 0 {
+        return -1;
+    }
+
+    let mut count = 0;
+    match with_emu(emu, |emu| match emu.frame_audio(frame) {
+        Some(samples) => {
+            let copied = samples.len().min(len);
+            if copied > 0 {
+                let buf = std::slice::from_raw_parts_mut(buf as *mut [i16; 2], copied);
+                buf.copy_from_slice(&samples[..copied]);
+            }
+            count = samples.len();
+            true
+        }
+        None => false,
+    }) {
+        0 => count as c_int,
+        error => error,
+    }
+}
+
+/// Returns the number of pixels of the picture, of which at most `len`
+/// are copied, or -1.
+///
+/// # Safety
+///
+/// `buf` must be valid for writes of `len` `u32`, `width` and `height` for
+/// a write or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn crustation_get_video_frame(
+    emu: *mut Crustation,
+    buf: *mut u32,
+    len: usize,
+    width: *mut u16,
+    height: *mut u16,
+) -> c_int {
+    if buf.is_null() && len > 0 {
+        return -1;
+    }
+
+    let mut count = 0;
+    match with_emu(emu, |emu| {
+        let frame = emu.video_frame();
+        let copied = frame.pixels.len().min(len);
+        if copied > 0 {
+            let buf = std::slice::from_raw_parts_mut(buf, copied);
+            buf.copy_from_slice(&frame.pixels[..copied]);
+        }
+        if let Some(width) = width.as_mut() {
+            *width = frame.width;
+        }
+        if let Some(height) = height.as_mut() {
+            *height = frame.height;
+        }
+        count = frame.pixels.len();
+        true
+    }) {
+        0 => count as c_int,
+        error => error,
+    }
+}
+
+/// # Safety
+///
+/// `hash` must be valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn crustation_video_frame_hash(
+    emu: *mut Crustation,
+    hash: *mut u64,
+) -> c_int {
+    if hash.is_null() {
+        return -1;
+    }
+    with_emu(emu, |emu| {
+        *hash = emu.video_frame().hash();
+        true
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn null_emulator_is_an_error() {
+        let path = CString::new("../bios/SCPH1001.BIN").unwrap();
+
+        assert_eq!(crustation_load_bios(std::ptr::null_mut(), path.as_ptr()), -1);
+        assert_eq!(crustation_run_frame(std::ptr::null_mut()), -1);
+        let mut buf = [0; 16];
+        let tty = unsafe { crustation_read_tty(std::ptr::null_mut(), buf.as_mut_ptr(), 16) };
+        assert_eq!(tty, -1);
+        unsafe { crustation_destroy(std::ptr::null_mut()) };
+    }
+
+    #[test]
+    fn missing_bios_is_an_error() {
+        let emu = crustation_create();
+        let path = CString::new("../bios/missing.bin").unwrap();
+
+        assert_eq!(crustation_load_bios(emu, path.as_ptr()), -1);
+        assert_eq!(crustation_load_bios(emu, std::ptr::null()), -1);
+        let disc = CString::new("../bios/missing.cue").unwrap();
+        assert_eq!(crustation_load_disc(emu, disc.as_ptr()), -1);
+
+        unsafe { crustation_destroy(emu) };
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        let emu = crustation_create();
+        let data = [0x12, 0x34, 0x56, 0x78];
+        let mut out = [0; 4];
+
+        unsafe {
+            assert_eq!(crustation_write_memory(emu, 0x8001_0000, data.as_ptr(), 4), 0);
+            assert_eq!(crustation_read_memory(emu, 0xa001_0000, out.as_mut_ptr(), 4), 0);
+            assert_eq!(out, data);
+
+            // Outside of RAM
+            assert_eq!(crustation_read_memory(emu, 0x1f80_1000, out.as_mut_ptr(), 4), -1);
+
+            crustation_destroy(emu);
+        }
+    }
+
+    #[test]
+    fn runs_bios_frames() {
+        let emu = crustation_create();
+        let path = CString::new("../bios/SCPH1001.BIN").unwrap();
+
+        assert_eq!(crustation_load_bios(emu, path.as_ptr()), 0);
+        for _ in 0..10 {
+            assert_eq!(crustation_run_frame(emu), 0);
+        }
+        assert_eq!(crustation_set_buttons(emu, 1 << 3), 0);
+        assert_eq!(crustation_run_frame(emu), 0);
+
+        let mut frame = 0;
+        assert_eq!(unsafe { crustation_frame(emu, &mut frame) }, 0);
+        assert_eq!(frame, 11);
+        assert_eq!(crustation_schedule_buttons(emu, 11, 0), -1);
+        assert_eq!(crustation_schedule_buttons(emu, 12, 0), 0);
+
+        // An NTSC frame lasts 743.8 samples
+        let mut samples = [0; 2 * 1024];
+        let count = unsafe { crustation_get_audio_samples(emu, 10, samples.as_mut_ptr(), 1024) };
+        assert!((743..=744).contains(&count));
+        let size = unsafe { crustation_get_audio_samples(emu, 10, std::ptr::null_mut(), 0) };
+        assert_eq!(size, count);
+        let missing = unsafe { crustation_get_audio_samples(emu, 11, samples.as_mut_ptr(), 1024) };
+        assert_eq!(missing, -1);
+
+        let (mut width, mut height) = (0, 0);
+        let pixels = unsafe {
+            crustation_get_video_frame(emu, std::ptr::null_mut(), 0, &mut width, &mut height)
+        };
+        assert!(pixels > 0);
+        assert_eq!(pixels, width as c_int * height as c_int);
+        let mut picture = vec![1; pixels as usize];
+        let copied = unsafe {
+            let (buf, len) = (picture.as_mut_ptr(), picture.len());
+            crustation_get_video_frame(emu, buf, len, std::ptr::null_mut(), std::ptr::null_mut())
+        };
+        assert_eq!(copied, pixels);
+        assert!(picture.iter().all(|&pixel| pixel == 0));
+
+        let mut hash = 0;
+        assert_eq!(unsafe { crustation_video_frame_hash(emu, &mut hash) }, 0);
+        let frame = VideoFrame { width, height, pixels: picture };
+        assert_eq!(hash, frame.hash());
+
+        unsafe { crustation_destroy(emu) };
+    }
+}
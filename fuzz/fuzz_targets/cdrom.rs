@@ -0,0 +1,63 @@
+//! Drives the CD-ROM controller registers with random sequences of reads,
+//! writes and delays, looking for panics and inconsistent FIFO state.
+//!
+//!     cd fuzz && cargo +nightly fuzz run cdrom
+//!
+//! Every operation is one byte: bits 0-1 select the register, bits 2-3 the
+//! access. Writes take their value from the following byte.
+
+#![no_main]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crustationcpu::PsxBus;
+use libfuzzer_sys::fuzz_target;
+use psx::hw::bus::Bus;
+
+const CDROM_BASE: u32 = 0x1f80_1800;
+
+/// A little more than the delay of a CD-ROM response
+const RESPONSE_DELAY: u64 = 50_001;
+
+fuzz_target!(|data: &[u8]| {
+    let bus = Rc::new(RefCell::new(Bus::new()));
+    bus.borrow().link(bus.clone());
+    let bus = bus.borrow();
+
+    let mut index = 0;
+    let mut bytes = data.iter().copied();
+
+    while let Some(op) = bytes.next() {
+        let register = (op & 3) as u32;
+
+        match (op >> 2) & 3 {
+            0 => {
+                bus.read::<1>(CDROM_BASE + register);
+            }
+            1 => {
+                let value = match bytes.next() {
+                    Some(value) => value,
+                    None => break,
+                };
+                bus.write::<1>(CDROM_BASE + register, value as u32);
+
+                if register == 0 {
+                    index = value & 3;
+                }
+            }
+            2 => {
+                bus.update_cycles(RESPONSE_DELAY);
+            }
+            _ => {
+                // Wider reads repeat the byte in every lane
+                let value = bus.read::<4>(CDROM_BASE + register);
+                assert_eq!(value, (value & 0xff) * 0x0101_0101);
+            }
+        }
+
+        // Only writes to the status register change the index
+        let status = bus.read::<1>(CDROM_BASE) as u8;
+        assert_eq!(status & 3, index, "index changed behind our back");
+    }
+});
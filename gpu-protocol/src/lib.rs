@@ -0,0 +1,105 @@
+//! What the emulated GPU hands to a renderer: vertices, texturing
+//! attributes and the displayed part of the VRAM. Has no dependencies, so
+//! frontends can draw the core's output without pulling in its own window
+//! and OpenGL code.
+
+/// VRAM size in 16-bit pixels
+pub const VRAM_WIDTH: usize = 1024;
+pub const VRAM_HEIGHT: usize = 512;
+
+/// Part of the VRAM sent to the TV, in VRAM coordinates
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DisplayArea {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Position(pub i16, pub i16);
+
+impl Position {
+    pub fn parse(value: u32) -> Position {
+        let x = value & 0xfff;
+        let y = value >> 16;
+
+        Position(x as i16, y as i16)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Color(pub u8, pub u8, pub u8);
+
+impl Color {
+    pub fn parse(value: u32) -> Color {
+        let r = value & 0xff;
+        let g = (value >> 8) & 0xff;
+        let b = (value >> 16) & 0xff;
+
+        Color(r as u8, g as u8, b as u8)
+    }
+}
+
+/// Texture coordinates in the texture page, 0 to 255
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TexCoord(pub u16, pub u16);
+
+impl TexCoord {
+    pub fn parse(value: u32) -> TexCoord {
+        TexCoord((value & 0xff) as u16, ((value >> 8) & 0xff) as u16)
+    }
+}
+
+/// How a primitive is textured: the texture page and CLUT attributes of
+/// the command, and `Texture::*` flags. The default is untextured.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Texture {
+    pub page: u16,
+    pub clut: u16,
+    pub flags: u16,
+}
+
+impl Texture {
+    pub const TEXTURED: u16 = 1;
+    /// Texels are used as is, rather than modulated by the vertex colors
+    pub const RAW: u16 = 2;
+    /// Blended with the scene. For textured primitives, only the texels
+    /// with bit 15 set.
+    pub const SEMI_TRANSPARENT: u16 = 4;
+    /// Colors are dithered when cut from 8 to 5 bits per channel
+    pub const DITHERED: u16 = 8;
+
+    /// Blending mode of semi-transparent primitives: 0 is B/2+F/2, 1 is
+    /// B+F, 2 is B-F and 3 is B+F/4
+    pub fn semi_transparency(&self) -> Option<u16> {
+        match self.flags & Texture::SEMI_TRANSPARENT {
+            0 => None,
+            _ => Some((self.page >> 5) & 3),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_command_words() {
+        let position = Position::parse(0x00f0_0140);
+        assert_eq!((position.0, position.1), (0x140, 0xf0));
+        let color = Color::parse(0x2c30_4050);
+        assert_eq!((color.0, color.1, color.2), (0x50, 0x40, 0x30));
+        assert_eq!(TexCoord::parse(0x7fff_1234), TexCoord(0x34, 0x12));
+
+        let texture = Texture {
+            page: 0x40,
+            clut: 0,
+            flags: Texture::TEXTURED | Texture::SEMI_TRANSPARENT,
+        };
+        assert_eq!(texture.semi_transparency(), Some(2));
+        assert_eq!(Texture::default().semi_transparency(), None);
+    }
+}
@@ -0,0 +1,128 @@
+//! Benchmark mode: runs the loaded BIOS or executable headless and as fast
+//! as possible for a fixed emulated time, then reports where the host time
+//! went as JSON, to compare builds before and after an optimization.
+//!
+//! The GTE, GPU and renderer times are measured, the CPU time is what's left:
+//! the interpreter and the other devices. Headless, the renderer is the
+//! software rasterizer.
+
+use std::time::{Duration, Instant};
+
+use crate::hw::bus::Bus;
+
+/// Cycles per second of the real hardware
+const CPU_FREQ: f64 = 33_868_800.0;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BenchReport {
+    pub emulated_seconds: f64,
+    pub frames: u64,
+    pub instructions: u64,
+    /// Host time of the whole run
+    pub total: Duration,
+    /// Interpreter and devices, everything not measured separately
+    pub cpu: Duration,
+    pub gte: Duration,
+    /// Handling GP0 commands, drawing excluded
+    pub gpu: Duration,
+    pub renderer: Duration,
+}
+
+/// Runs whole frames from the current state until `seconds` of emulated
+/// time have passed, or the quit hotkey is pressed
+pub fn run(bus: &Bus, seconds: f64) -> BenchReport {
+    bus.set_speed(None);
+    bus.set_profiling(true);
+
+    let start_cycles = *bus.total_cycles.borrow();
+    let target = start_cycles + (seconds * CPU_FREQ) as u64;
+    let start_instructions = bus.cpu.borrow().instructions();
+    let start = Instant::now();
+
+    let mut frames = 0;
+    while *bus.total_cycles.borrow() < target && !bus.quit_requested() {
+        bus.run_frame();
+        frames += 1;
+    }
+
+    let total = start.elapsed();
+    let (gte, gpu) = bus.profile().unwrap_or_default();
+    bus.set_profiling(false);
+
+    BenchReport {
+        emulated_seconds: (*bus.total_cycles.borrow() - start_cycles) as f64 / CPU_FREQ,
+        frames,
+        instructions: bus.cpu.borrow().instructions() - start_instructions,
+        total,
+        cpu: total.saturating_sub(gte + gpu.commands),
+        gte,
+        gpu: gpu.commands.saturating_sub(gpu.raster),
+        renderer: gpu.raster,
+    }
+}
+
+impl BenchReport {
+    /// Emulated time per host time, in percent of the real hardware
+    pub fn speed(&self) -> f64 {
+        self.emulated_seconds * 100. / self.total.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// On one line, times in seconds
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"emulated_seconds\": {:.3}, \"frames\": {}, \"instructions\": {}, \
+             \"speed\": {:.1}, \"seconds\": {{\"total\": {:.6}, \"cpu\": {:.6}, \"gte\": {:.6}, \
+             \"gpu\": {:.6}, \"renderer\": {:.6}}}}}\n",
+            self.emulated_seconds,
+            self.frames,
+            self.instructions,
+            self.speed(),
+            self.total.as_secs_f64(),
+            self.cpu.as_secs_f64(),
+            self.gte.as_secs_f64(),
+            self.gpu.as_secs_f64(),
+            self.renderer.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn runs_for_the_emulated_time() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+        bus.load_rom("bios/SCPH1001.BIN").unwrap();
+
+        let report = run(&bus, 0.2);
+        assert!(report.emulated_seconds >= 0.2 && report.emulated_seconds < 0.25);
+        assert!(report.frames >= 11 && report.instructions > 0);
+        assert_eq!(report.cpu + report.gte + report.gpu + report.renderer, report.total);
+        assert!(bus.profile().is_none());
+    }
+
+    #[test]
+    fn reports_the_breakdown_as_json() {
+        let report = BenchReport {
+            emulated_seconds: 2.,
+            frames: 120,
+            instructions: 1000,
+            total: Duration::from_secs(1),
+            cpu: Duration::from_millis(700),
+            gte: Duration::from_millis(100),
+            gpu: Duration::from_millis(150),
+            renderer: Duration::from_millis(50),
+        };
+        assert_eq!(
+            report.to_json(),
+            "{\"emulated_seconds\": 2.000, \"frames\": 120, \"instructions\": 1000, \
+             \"speed\": 200.0, \"seconds\": {\"total\": 1.000000, \"cpu\": 0.700000, \
+             \"gte\": 0.100000, \"gpu\": 0.150000, \"renderer\": 0.050000}}\n"
+        );
+    }
+}
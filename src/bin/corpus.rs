@@ -0,0 +1,214 @@
+//! Compatibility regression runner.
+//!
+//! Runs every PS-X EXE in a directory headless for a number of frames,
+//! hashing the picture and the sound of each frame, and compares the
+//! results with a baseline file.
+//!
+//!     corpus <bios> <exe directory> [--frames=N] [--baseline=FILE] [--update]
+//!
+//! The baseline defaults to `baseline.txt` in the exe directory. With
+//! `--update`, the baseline is rewritten with the current results.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+use std::rc::Rc;
+
+use psx::hash::{fnv1a, Fnv1a};
+use psx::hw::bus::Bus;
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// Hash of all the frames
+    Hash(u64),
+    /// The emulator panicked
+    Crash,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Hash(hash) => write!(f, "{:016x}", hash),
+            Outcome::Crash => write!(f, "crash"),
+        }
+    }
+}
+
+/// Runs `exe` for `frames` frames. The frame signature is the picture
+/// displayed at each VBlank and the sound produced during the frame.
+fn run(bios: &Path, exe: &Path, frames: u32) -> Outcome {
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+
+        let bus = bus.borrow();
+        bus.set_speed(None);
+        bus.capture_audio(1);
+        bus.load_rom(bios).unwrap();
+        bus.sideload_exe(exe, &[], false).unwrap();
+        while bus.sideload_pending() {
+            bus.run_frame();
+        }
+
+        let mut hash = Fnv1a::default();
+        for _ in 0..frames {
+            bus.run_frame();
+            hash.write_u64(bus.video_frame().hash());
+
+            let samples = bus.audio_samples(bus.frame() - 1).unwrap_or_default();
+            let sound = samples.iter().flatten().flat_map(|sample| sample.to_le_bytes());
+            hash.write_u64(fnv1a(sound));
+        }
+
+        hash.finish()
+    }));
+
+    match result {
+        Ok(hash) => Outcome::Hash(hash),
+        Err(_) => Outcome::Crash,
+    }
+}
+
+/// Baseline lines are `<exe name> <frames> <outcome>`
+fn parse_baseline(contents: &str) -> BTreeMap<String, (u32, String)> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let name = parts.next()?;
+            let frames = parts.next()?.parse().ok()?;
+            let outcome = parts.next()?;
+
+            Some((name.to_string(), (frames, outcome.to_string())))
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum Status {
+    Pass,
+    Fail,
+    New,
+}
+
+fn compare(expected: Option<&(u32, String)>, frames: u32, outcome: &Outcome) -> Status {
+    match expected {
+        Some((f, o)) if *f == frames && *o == outcome.to_string() => Status::Pass,
+        Some(_) => Status::Fail,
+        None => Status::New,
+    }
+}
+
+fn current_commit() -> String {
+    Command::new("git")
+        .args(["describe", "--always", "--dirty"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: corpus <bios> <exe directory> [--frames=N] [--baseline=FILE] [--update]");
+    exit(2);
+}
+
+fn main() {
+    let mut frames = 300;
+    let mut baseline_path = None;
+    let mut update = false;
+    let mut positional = vec![];
+
+    for arg in std::env::args().skip(1) {
+        if let Some(n) = arg.strip_prefix("--frames=") {
+            frames = n.parse().unwrap_or_else(|_| usage());
+        } else if let Some(path) = arg.strip_prefix("--baseline=") {
+            baseline_path = Some(PathBuf::from(path));
+        } else if arg == "--update" {
+            update = true;
+        } else {
+            positional.push(PathBuf::from(arg));
+        }
+    }
+
+    if positional.len() != 2 {
+        usage();
+    }
+
+    let bios = &positional[0];
+    let dir = &positional[1];
+    let baseline_path = baseline_path.unwrap_or_else(|| dir.join("baseline.txt"));
+    let baseline = parse_baseline(&fs::read_to_string(&baseline_path).unwrap_or_default());
+
+    let mut exes: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("Could not read the exe directory")
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("exe"))
+        })
+        .collect();
+    exes.sort();
+
+    let mut report = format!("Compatibility report for {}\n", current_commit());
+    let mut new_baseline = String::new();
+    let mut failures = 0;
+
+    for exe in &exes {
+        let name = exe.file_name().unwrap().to_string_lossy().to_string();
+        let outcome = run(bios, exe, frames);
+        let status = compare(baseline.get(&name), frames, &outcome);
+
+        if status == Status::Fail {
+            failures += 1;
+        }
+
+        report += &format!("{:<4} {:<32} {}\n", format!("{:?}", status).to_uppercase(), name, outcome);
+        new_baseline += &format!("{} {} {}\n", name, frames, outcome);
+    }
+
+    for name in baseline.keys() {
+        if !exes.iter().any(|exe| exe.file_name().unwrap().to_string_lossy() == *name) {
+            report += &format!("GONE {}\n", name);
+        }
+    }
+
+    report += &format!("{} run, {} changed\n", exes.len(), failures);
+    print!("{}", report);
+
+    if update {
+        fs::write(&baseline_path, new_baseline).expect("Could not write the baseline");
+    } else if failures > 0 {
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baseline_skips_comments_and_junk() {
+        let baseline = parse_baseline("# comment\n\na.exe 60 00000000deadbeef\nbroken\nb.exe 60 crash\n");
+
+        assert_eq!(baseline.len(), 2);
+        assert_eq!(baseline["a.exe"], (60, String::from("00000000deadbeef")));
+        assert_eq!(baseline["b.exe"], (60, String::from("crash")));
+    }
+
+    #[test]
+    fn comparison_needs_same_frames_and_outcome() {
+        let expected = (60, String::from("00000000deadbeef"));
+        let outcome = Outcome::Hash(0xdead_beef);
+
+        assert_eq!(compare(Some(&expected), 60, &outcome), Status::Pass);
+        assert_eq!(compare(Some(&expected), 30, &outcome), Status::Fail);
+        assert_eq!(compare(Some(&expected), 60, &Outcome::Crash), Status::Fail);
+        assert_eq!(compare(None, 60, &outcome), Status::New);
+    }
+}
@@ -1,197 +1,401 @@
-// use rustyline::error::ReadlineError;
-// use rustyline::Editor;
-// use std::process;
-
-// use crate::hw::cpu::{Cpu, PsxBus};
-// use crate::hw::disasm::Disasm;
-
-// fn die() {
-//     println!("Quitting...");
-//     process::exit(-1);
-// }
-
-// pub struct Debugger {
-//     readline: Editor<()>,
-//     breakpoints: Vec<u32>,
-//     pub stepping: bool,
-//     next_breakpoint: u32,
-//     last_line: String,
-// }
-
-// fn effective_pc<T: PsxBus>(cpu: &Cpu<T>) -> u32 {
-//     if let Some((branch_delay, _)) = cpu.branch_delay_slot {
-//         branch_delay
-//     } else {
-//         cpu.pc
-//     }
-// }
-
-// impl Debugger {
-//     pub fn new() -> Debugger {
-//         Debugger {
-//             readline: Editor::<()>::new(),
-//             breakpoints: vec![],
-//             stepping: false,
-//             next_breakpoint: 0xffff_ffff,
-//             last_line: String::from(""),
-//         }
-//     }
-
-//     pub fn enter<T: PsxBus>(cpu: &mut Cpu<T>) {
-//         cpu.debugger.stepping = false;
-//         cpu.debugger.next_breakpoint = 0xffff_ffff;
-
-//         let pc = effective_pc(cpu);
-//         let instruction = cpu.load::<u32>(pc);
-//         let disasm = Disasm::disasm(instruction, pc);
-//         println!(
-//             "[{:08x}] {} ({:08x})",
-//             effective_pc(cpu),
-//             disasm,
-//             instruction
-//         );
-
-//         loop {
-//             match cpu.debugger.readline.readline("psx> ") {
-//                 Ok(line) => {
-//                     let mut line = line.trim().to_string();
-
-//                     if line.is_empty() {
-//                         line = cpu.debugger.last_line.clone();
-//                     } else {
-//                         cpu.debugger.last_line = line.clone();
-//                         cpu.debugger.readline.add_history_entry(line.as_str());
-//                     }
-
-//                     if Debugger::run_debug_command(line, cpu) {
-//                         break;
-//                     }
-//                 }
-//                 Err(ReadlineError::Interrupted) => {
-//                     die();
-//                 }
-//                 Err(err) => {
-//                     println!("Error: {:?}", err);
-//                 }
-//             }
-//         }
-//     }
-
-//     pub fn should_break<T: PsxBus>(cpu: &Cpu<T>) -> bool {
-//         let pc = effective_pc(cpu);
-//         cpu.debugger.stepping
-//             || cpu.debugger.next_breakpoint == pc
-//             || cpu.debugger.breakpoints.contains(&pc)
-//     }
-
-//     fn run_debug_command<T: PsxBus>(line: String, cpu: &mut Cpu<T>) -> bool {
-//         let mut parsed = line.split_whitespace();
-//         let command = parsed.next().unwrap_or("");
-
-//         match command {
-//             "q" | "quit" => {
-//                 die();
-//                 false
-//             }
-//             "c" | "continue" => true,
-//             "s" | "step" => {
-//                 cpu.debugger.stepping = true;
-//                 true
-//             }
-//             "n" | "next" => {
-//                 let pc = effective_pc(cpu);
-//                 let is_call = Disasm::is_function_call(cpu.load::<u32>(pc));
-//                 if is_call {
-//                     cpu.debugger.next_breakpoint = pc.wrapping_add(8)
-//                 } else {
-//                     cpu.debugger.stepping = true
-//                 }
-
-//                 true
-//             }
-//             "r" | "regs" => {
-//                 println!("PC:  {:08x}", effective_pc(cpu));
-//                 for i in 0..8 {
-//                     for j in 0..4 {
-//                         let idx = j * 8 + i;
-//                         print!(
-//                             "{}: {:08x}  ",
-//                             Disasm::reg_name(idx),
-//                             cpu.regs[idx as usize]
-//                         );
-//                     }
-//                     println!();
-//                 }
-//                 println!("HI:  {:08x}  LO:  {:08x}", cpu.hi, cpu.lo);
-
-//                 false
-//             }
-//             "rm" | "read-mem" => {
-//                 let address = parsed.next().unwrap_or("");
-//                 if let Ok(address) = u32::from_str_radix(address.trim_start_matches("0x"), 16) {
-//                     let value = cpu.load::<u32>(address);
-//                     println!("Read at {:08x}: {:08x}", address, value);
-//                 } else {
-//                     println!("Usage: read-mem [address] (eg: read-mem bfc01234)")
-//                 }
-
-//                 false
-//             }
-//             "b" | "breakpoint" => {
-//                 let address = parsed.next().unwrap_or("");
-//                 if let Ok(address) = u32::from_str_radix(address.trim_start_matches("0x"), 16) {
-//                     cpu.debugger.breakpoints.push(address);
-//                 } else {
-//                     println!("Usage: breakpoint [address] (eg: breakpoint bfc01234)")
-//                 }
-
-//                 false
-//             }
-//             "lb" | "list-breakpoints" => {
-//                 if !cpu.debugger.breakpoints.is_empty() {
-//                     for i in 0..cpu.debugger.breakpoints.len() {
-//                         println!("{}: {:08x}", i, cpu.debugger.breakpoints[i]);
-//                     }
-//                 } else {
-//                     println!("No breakpoint has been set.");
-//                 }
-
-//                 false
-//             }
-//             "db" | "delete-breakpoint" => {
-//                 let index = parsed.next().unwrap_or("");
-//                 if let Ok(index) = index.parse::<usize>() {
-//                     if index < cpu.debugger.breakpoints.len() {
-//                         cpu.debugger.breakpoints.remove(index);
-//                     } else {
-//                         println!("There's no breakpoint #{}", index);
-//                     }
-//                 } else {
-//                     println!("Usage: delete-breakpoint [index] (eg: delete-breakpoint 0)");
-//                 }
-
-//                 false
-//             }
-//             "h" | "help" => {
-//                 println!("  h, help                      Shows this message");
-//                 println!("  c, continue                  Resumes emulation");
-//                 println!("  s, step                      Run 1 instruction and break again");
-//                 println!("  n, next                      Like step, but skipping function calls");
-//                 println!("  r, regs                      Dumps Cpu registers");
-//                 println!(" rm, read-mem                  Reads a bus address");
-//                 println!("  b, breakpoint [address]      Sets a breakpoint");
-//                 println!(" lb, list-breakpoints          Lists all breakpoints");
-//                 println!(" db, delete-breakpoint [index] Deletes a breakpoints");
-//                 println!("  q, quit                      Terminates the emulator");
-//                 false
-//             }
-//             _ => {
-//                 println!(
-//                     "Unknown debugger command {}. Type <help> for help.",
-//                     command
-//                 );
-//                 false
-//             }
-//         }
-//     }
-// }
+//! Command line debugger, entered with Ctrl-C under `--debug`, on a
+//! breakpoint or watchpoint, or when a device asks for it (`--strict-memory`,
+//! `--break-on-gpu-hang`).
+//!
+//! Addresses and values are hexadecimal, ids and counts decimal. Registers
+//! are named as in the disassembly (`a0`) or by number (`r4`).
+
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+
+use crustationcpu::breakpoints::{Comparison, Condition, DebugStop, Kind};
+
+use crate::hw::bus::Bus;
+use crate::hw::disasm::Disasm;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Continue,
+    Step(u32),
+    Regs,
+    /// Address and number of words
+    ReadMem(u32, u32),
+    MemoryMap,
+    Break(u32, Option<Condition>),
+    Watch(Kind, Option<Condition>),
+    List,
+    Delete(u32),
+    Enable(u32, bool),
+    Trace,
+    Help,
+    Quit,
+}
+
+const HELP: &str = "\
+  h, help                        Shows this message
+  c, continue                    Resumes emulation
+  s, step [count]                Runs instructions and breaks again
+  r, regs                        Dumps the CPU registers
+ rm, read-mem addr [count]       Reads words of RAM or BIOS
+ mm, memory-map                  Lists the devices on the bus
+  b, break addr [if cond]        Breaks before the instruction at addr
+  w, watch addr[:len] [r|w|rw] [if cond]
+                                 Breaks after an access, writes by default
+ lb, list-breakpoints            Lists breakpoints and watchpoints
+ db, delete-breakpoint id        Deletes a breakpoint or watchpoint
+ en, enable id / dis, disable id Enables or disables one
+  t, trace                       Writes the execution trace (--trace=N)
+  q, quit                        Terminates the emulator
+
+Conditions compare a register with a value, e.g. `if a0 == 8001fc00`.
+Comparisons are unsigned: == != < <= > >=";
+
+fn parse_hex(text: &str) -> Result<u32, String> {
+    let digits = text.trim_start_matches("0x");
+    u32::from_str_radix(digits, 16).map_err(|_| format!("Invalid address or value: {}", text))
+}
+
+fn parse_id(text: Option<&str>) -> Result<u32, String> {
+    let text = text.ok_or("Missing breakpoint id")?;
+    text.parse().map_err(|_| format!("Invalid breakpoint id: {}", text))
+}
+
+fn parse_reg(text: &str) -> Result<u32, String> {
+    let number = text.strip_prefix('r').or_else(|| text.strip_prefix('$'));
+    if let Some(reg) = number.and_then(|n| n.parse().ok()).filter(|&reg| reg < 32) {
+        return Ok(reg);
+    }
+    (0..32)
+        .find(|&reg| Disasm::reg_name(reg) == text)
+        .ok_or_else(|| format!("Unknown register: {}", text))
+}
+
+/// `if reg op value`, or nothing
+fn parse_condition<'a>(
+    mut words: impl Iterator<Item = &'a str>,
+) -> Result<Option<Condition>, String> {
+    match words.next() {
+        None => return Ok(None),
+        Some("if") => {}
+        Some(word) => return Err(format!("Expected `if`, found {}", word)),
+    }
+
+    let usage = "Conditions are `if reg op value`";
+    let (Some(reg), Some(op), Some(value), None) =
+        (words.next(), words.next(), words.next(), words.next())
+    else {
+        return Err(usage.to_string());
+    };
+    Ok(Some(Condition {
+        reg: parse_reg(reg)?,
+        comparison: Comparison::from_symbol(op).ok_or(usage)?,
+        value: parse_hex(value)?,
+    }))
+}
+
+/// `addr[:len] [r|w|rw] [if cond]`
+fn parse_watch<'a>(
+    mut words: std::iter::Peekable<impl Iterator<Item = &'a str>>,
+) -> Result<Command, String> {
+    let target = words.next().ok_or("Usage: watch addr[:len] [r|w|rw] [if cond]")?;
+    let (address, len) = match target.split_once(':') {
+        Some((address, len)) => {
+            let len = len.parse().map_err(|_| format!("Invalid length: {}", len))?;
+            (parse_hex(address)?, len)
+        }
+        None => (parse_hex(target)?, 4),
+    };
+    if len == 0 {
+        return Err(String::from("Watchpoints cover at least a byte"));
+    }
+
+    let (read, write) = match words.peek() {
+        Some(&"r") => (true, false),
+        Some(&"w") => (false, true),
+        Some(&"rw") => (true, true),
+        _ => (false, true),
+    };
+    if words.peek().is_some_and(|word| matches!(*word, "r" | "w" | "rw")) {
+        words.next();
+    }
+
+    let kind = Kind::Watch {
+        address,
+        len,
+        read,
+        write,
+    };
+    Ok(Command::Watch(kind, parse_condition(words)?))
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or("");
+
+    let command = match command {
+        "c" | "continue" => Command::Continue,
+        "s" | "step" => match words.next() {
+            Some(count) => Command::Step(count.parse().map_err(|_| "Usage: step [count]")?),
+            None => Command::Step(1),
+        },
+        "r" | "regs" => Command::Regs,
+        "rm" | "read-mem" => {
+            let address = words.next().ok_or("Usage: read-mem addr [count]")?;
+            let count = match words.next() {
+                Some(count) => count.parse().map_err(|_| "Usage: read-mem addr [count]")?,
+                None => 1,
+            };
+            Command::ReadMem(parse_hex(address)?, count)
+        }
+        "mm" | "memory-map" => Command::MemoryMap,
+        "b" | "break" => {
+            let address = words.next().ok_or("Usage: break addr [if cond]")?;
+            Command::Break(parse_hex(address)?, parse_condition(&mut words)?)
+        }
+        "w" | "watch" => return parse_watch(words.peekable()),
+        "lb" | "list-breakpoints" => Command::List,
+        "db" | "delete-breakpoint" => Command::Delete(parse_id(words.next())?),
+        "en" | "enable" => Command::Enable(parse_id(words.next())?, true),
+        "dis" | "disable" => Command::Enable(parse_id(words.next())?, false),
+        "t" | "trace" => Command::Trace,
+        "h" | "help" => Command::Help,
+        "q" | "quit" => Command::Quit,
+        _ => {
+            return Err(format!(
+                "Unknown debugger command {}. Type <help> for help.",
+                command
+            ))
+        }
+    };
+
+    match words.next() {
+        Some(word) => Err(format!("Unexpected {}", word)),
+        None => Ok(command),
+    }
+}
+
+pub struct Debugger {
+    editor: Editor<()>,
+    last_line: String,
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            editor: Editor::<()>::new(),
+            last_line: String::new(),
+        }
+    }
+
+    /// Prompts for commands until emulation should resume. Returns false to
+    /// quit.
+    pub fn enter(&mut self, bus: &Bus, stop: DebugStop) -> bool {
+        Debugger::print_stop(stop);
+        Debugger::print_instruction(bus);
+
+        loop {
+            let line = match self.editor.readline("psx> ") {
+                Ok(line) => line.trim().to_string(),
+                Err(ReadlineError::Interrupted | ReadlineError::Eof) => return false,
+                Err(err) => {
+                    println!("Error: {:?}", err);
+                    continue;
+                }
+            };
+
+            // An empty line repeats the last command, e.g. to keep stepping
+            let line = if line.is_empty() {
+                self.last_line.clone()
+            } else {
+                self.editor.add_history_entry(line.as_str());
+                self.last_line = line.clone();
+                line
+            };
+            if line.is_empty() {
+                continue;
+            }
+
+            match parse(&line) {
+                Ok(Command::Continue) => return true,
+                Ok(Command::Quit) => return false,
+                Ok(command) => Debugger::run(bus, command),
+                Err(err) => println!("{}", err),
+            }
+        }
+    }
+
+    fn print_stop(stop: DebugStop) {
+        match stop {
+            DebugStop::Requested => println!("Stopped"),
+            DebugStop::Breakpoint(id) => println!("Breakpoint {}", id),
+            DebugStop::Watchpoint(id, address, write) => {
+                let access = if write { "Write" } else { "Read" };
+                println!("Watchpoint {}: {} at {:08x}", id, access, address);
+            }
+        }
+    }
+
+    fn print_instruction(bus: &Bus) {
+        let pc = bus.cpu.borrow().pc();
+        match bus.peek_word(pc) {
+            Some(instruction) => println!(
+                "[{:08x}] {} ({:08x})",
+                pc,
+                Disasm::disasm(instruction, pc),
+                instruction
+            ),
+            None => println!("[{:08x}] ?", pc),
+        }
+    }
+
+    fn run(bus: &Bus, command: Command) {
+        match command {
+            Command::Step(count) => {
+                for _ in 0..count {
+                    let stop = bus.cpu.borrow_mut().debug_step();
+                    if let Some(stop) = stop {
+                        Debugger::print_stop(stop);
+                        break;
+                    }
+                }
+                Debugger::print_instruction(bus);
+            }
+            Command::Regs => {
+                let cpu = bus.cpu.borrow();
+                println!("PC:  {:08x}", cpu.pc());
+                for i in 0..8 {
+                    for j in 0..4 {
+                        let reg = j * 8 + i;
+                        print!("{:>4}: {:08x}  ", Disasm::reg_name(reg), cpu.regs[reg as usize]);
+                    }
+                    println!();
+                }
+                println!("  HI: {:08x}    LO: {:08x}", cpu.hi, cpu.lo);
+            }
+            Command::ReadMem(address, count) => {
+                let address = address & !3;
+                for line in 0..count.div_ceil(4) {
+                    let start = address.wrapping_add(line * 16);
+                    print!("{:08x}:", start);
+                    for word in 0..(count - line * 4).min(4) {
+                        match bus.peek_word(start.wrapping_add(word * 4)) {
+                            Some(value) => print!(" {:08x}", value),
+                            None => print!(" --------"),
+                        }
+                    }
+                    println!();
+                }
+            }
+            Command::MemoryMap => print!("{}", bus.memory_map()),
+            Command::Break(address, condition) => {
+                let id = bus.cpu.borrow_mut().breakpoints.add(Kind::Execute(address), condition);
+                println!("Breakpoint {} at {:08x}", id, address);
+            }
+            Command::Watch(kind, condition) => {
+                let id = bus.cpu.borrow_mut().breakpoints.add(kind, condition);
+                println!("Watchpoint {}", id);
+            }
+            Command::List => {
+                let cpu = bus.cpu.borrow();
+                if cpu.breakpoints.list().is_empty() {
+                    println!("No breakpoint has been set.");
+                }
+                for breakpoint in cpu.breakpoints.list() {
+                    println!("{}", breakpoint);
+                }
+            }
+            Command::Delete(id) => {
+                if !bus.cpu.borrow_mut().breakpoints.remove(id) {
+                    println!("There's no breakpoint #{}", id);
+                }
+            }
+            Command::Enable(id, enabled) => {
+                if !bus.cpu.borrow_mut().breakpoints.set_enabled(id, enabled) {
+                    println!("There's no breakpoint #{}", id);
+                }
+            }
+            Command::Trace => {
+                if !bus.cpu.borrow_mut().dump_trace() {
+                    println!("Not tracing, start with --trace=N");
+                }
+            }
+            Command::Help => println!("{}", HELP),
+            Command::Continue | Command::Quit => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_breakpoints() {
+        assert_eq!(parse("b 0x80010000"), Ok(Command::Break(0x8001_0000, None)));
+        let condition = Condition {
+            reg: 4,
+            comparison: Comparison::Ne,
+            value: 0x1f,
+        };
+        assert_eq!(
+            parse("break bfc00180 if a0 != 1f"),
+            Ok(Command::Break(0xbfc0_0180, Some(condition)))
+        );
+        assert_eq!(parse("b bfc00180 if r4 != 1f"), parse("b bfc00180 if a0 != 1f"));
+
+        assert!(parse("b").is_err());
+        assert!(parse("b bfc00180 if a0 =~ 1f").is_err());
+        assert!(parse("b bfc00180 if k9 == 1f").is_err());
+        assert!(parse("b bfc00180 when a0 == 1f").is_err());
+    }
+
+    #[test]
+    fn parses_watchpoints() {
+        let watch = |address, len, read, write| Kind::Watch {
+            address,
+            len,
+            read,
+            write,
+        };
+        assert_eq!(
+            parse("w 1f801070"),
+            Ok(Command::Watch(watch(0x1f80_1070, 4, false, true), None))
+        );
+        assert_eq!(
+            parse("watch 80001000:16 rw"),
+            Ok(Command::Watch(watch(0x8000_1000, 16, true, true), None))
+        );
+        let condition = Condition {
+            reg: 31,
+            comparison: Comparison::Ge,
+            value: 0x8003_0000,
+        };
+        assert_eq!(
+            parse("w 80001000 r if ra >= 80030000"),
+            Ok(Command::Watch(watch(0x8000_1000, 4, true, false), Some(condition)))
+        );
+        assert!(parse("w 80001000:0").is_err());
+        assert!(parse("w 80001000 x").is_err());
+    }
+
+    #[test]
+    fn parses_the_other_commands() {
+        assert_eq!(parse("s"), Ok(Command::Step(1)));
+        assert_eq!(parse("step 100"), Ok(Command::Step(100)));
+        assert_eq!(parse("rm 80010000 8"), Ok(Command::ReadMem(0x8001_0000, 8)));
+        assert_eq!(parse("db 3"), Ok(Command::Delete(3)));
+        assert_eq!(parse("dis 2"), Ok(Command::Enable(2, false)));
+        assert_eq!(parse("en 2"), Ok(Command::Enable(2, true)));
+        assert_eq!(parse("t"), Ok(Command::Trace));
+        assert_eq!(parse("memory-map"), Ok(Command::MemoryMap));
+        assert!(parse("db").is_err());
+        assert!(parse("c now").is_err());
+        assert!(parse("frobnicate").is_err());
+    }
+}
@@ -0,0 +1,353 @@
+//! GDB remote serial protocol server, started with `--gdb=PORT`. The
+//! emulator waits for gdb (`target remote :PORT`) or an IDE to attach, and
+//! gdb drives it until it detaches.
+//!
+//! Breakpoints and watchpoints are the debugger's, see
+//! `crustationcpu::breakpoints`. The protocol doesn't tell the length of a
+//! watchpoint, a word is watched. Memory is main RAM and the BIOS, and
+//! only RAM can be written. Continuing runs frame by frame, so an interrupt
+//! from gdb is noticed at the next VBlank.
+
+use std::net::{TcpListener, TcpStream};
+
+use gdbstub::arch::Arch;
+use gdbstub::target::ext::base::singlethread::{
+    GdbInterrupt, ResumeAction, SingleThreadOps, StopReason,
+};
+use gdbstub::target::ext::base::BaseOps;
+use gdbstub::target::ext::breakpoints::{
+    Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps, HwWatchpoint, HwWatchpointOps,
+    SwBreakpoint, SwBreakpointOps, WatchKind,
+};
+use gdbstub::target::{Target, TargetError, TargetResult};
+use gdbstub::{DisconnectReason, GdbStub};
+use gdbstub_arch::mips::{Mips, MipsBreakpointKind};
+
+use crustationcpu::breakpoints::{DebugStop, Kind};
+
+use crate::hw::bus::Bus;
+
+type Registers = <Mips as Arch>::Registers;
+
+/// Waits for gdb on `port` and serves one session. Returns false if the
+/// emulator should quit, e.g. gdb killed it.
+pub fn serve(bus: &Bus, port: u16) -> bool {
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("[GDB] Could not listen on port {}: {}", port, err);
+            return false;
+        }
+    };
+    println!("[GDB] Waiting for gdb on 127.0.0.1:{}", port);
+    let stream = match listener.accept() {
+        Ok((stream, address)) => {
+            println!("[GDB] Attached from {}", address);
+            stream
+        }
+        Err(err) => {
+            println!("[GDB] Accept failed: {}", err);
+            return false;
+        }
+    };
+
+    let mut target = GdbTarget::new(bus);
+    let mut stub = GdbStub::<GdbTarget, TcpStream>::new(stream);
+    let result = stub.run(&mut target);
+    target.remove_all();
+    match result {
+        Ok(DisconnectReason::Disconnect) => {
+            println!("[GDB] Detached, resuming");
+            true
+        }
+        Ok(reason) => {
+            println!("[GDB] Session ended: {:?}", reason);
+            false
+        }
+        Err(err) => {
+            println!("[GDB] Session failed: {:?}", err);
+            false
+        }
+    }
+}
+
+pub struct GdbTarget<'a> {
+    bus: &'a Bus,
+    /// What gdb inserted, with the ids of the matching breakpoints
+    inserted: Vec<(Kind, u32)>,
+}
+
+impl<'a> GdbTarget<'a> {
+    pub fn new(bus: &'a Bus) -> GdbTarget<'a> {
+        GdbTarget {
+            bus,
+            inserted: vec![],
+        }
+    }
+
+    /// Runs until a breakpoint or watchpoint, a quit or `interrupted` says
+    /// so. `interrupted` is checked once per frame.
+    fn run_until_stop(&mut self, mut interrupted: impl FnMut() -> bool) -> StopReason<u32> {
+        loop {
+            self.bus.run_frame();
+            if let Some(stop) = self.bus.take_debug_stop() {
+                return self.stop_reason(stop);
+            }
+            if self.bus.quit_requested() {
+                return StopReason::Exited(0);
+            }
+            if interrupted() {
+                return StopReason::GdbInterrupt;
+            }
+        }
+    }
+
+    fn stop_reason(&self, stop: DebugStop) -> StopReason<u32> {
+        match stop {
+            DebugStop::Requested => StopReason::GdbInterrupt,
+            DebugStop::Breakpoint(_) => StopReason::SwBreak,
+            DebugStop::Watchpoint(id, address, write) => {
+                // gdb matches the stop with the address it watched
+                let cpu = self.bus.cpu.borrow();
+                let watched = cpu.breakpoints.list().iter().find(|b| b.id == id);
+                let addr = match watched.map(|b| b.kind) {
+                    Some(Kind::Watch { address, .. }) => address,
+                    _ => address,
+                };
+                let kind = if write { WatchKind::Write } else { WatchKind::Read };
+                StopReason::Watch { kind, addr }
+            }
+        }
+    }
+
+    fn insert(&mut self, kind: Kind) -> bool {
+        let id = self.bus.cpu.borrow_mut().breakpoints.add(kind, None);
+        self.inserted.push((kind, id));
+        true
+    }
+
+    fn remove(&mut self, kind: Kind) -> bool {
+        let Some(index) = self.inserted.iter().position(|(k, _)| *k == kind) else {
+            return false;
+        };
+        let (_, id) = self.inserted.remove(index);
+        self.bus.cpu.borrow_mut().breakpoints.remove(id)
+    }
+
+    /// gdb removes what it inserted when detaching, unless the connection
+    /// is lost
+    fn remove_all(&mut self) {
+        let mut cpu = self.bus.cpu.borrow_mut();
+        for (_, id) in self.inserted.drain(..) {
+            cpu.breakpoints.remove(id);
+        }
+    }
+
+    fn watch(address: u32, kind: WatchKind) -> Kind {
+        Kind::Watch {
+            address,
+            len: 4,
+            read: kind != WatchKind::Write,
+            write: kind != WatchKind::Read,
+        }
+    }
+}
+
+impl Target for GdbTarget<'_> {
+    type Arch = Mips;
+    type Error = &'static str;
+
+    fn base_ops(&mut self) -> BaseOps<'_, Self::Arch, Self::Error> {
+        BaseOps::SingleThread(self)
+    }
+
+    fn breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadOps for GdbTarget<'_> {
+    fn resume(
+        &mut self,
+        action: ResumeAction,
+        gdb_interrupt: GdbInterrupt<'_>,
+    ) -> Result<StopReason<u32>, Self::Error> {
+        match action {
+            ResumeAction::Step | ResumeAction::StepWithSignal(_) => {
+                let stop = self.bus.cpu.borrow_mut().debug_step();
+                Ok(stop.map_or(StopReason::DoneStep, |stop| self.stop_reason(stop)))
+            }
+            ResumeAction::Continue | ResumeAction::ContinueWithSignal(_) => {
+                let mut gdb_interrupt = gdb_interrupt.no_async();
+                Ok(self.run_until_stop(|| gdb_interrupt.pending()))
+            }
+        }
+    }
+
+    fn read_registers(&mut self, regs: &mut Registers) -> TargetResult<(), Self> {
+        let cpu = self.bus.cpu.borrow();
+        regs.r.copy_from_slice(&cpu.regs[..32]);
+        regs.lo = cpu.lo;
+        regs.hi = cpu.hi;
+        regs.pc = cpu.pc();
+        regs.cp0.status = cpu.cop0.read_reg(12).unwrap_or(0);
+        regs.cp0.badvaddr = cpu.cop0.read_reg(8).unwrap_or(0);
+        regs.cp0.cause = cpu.cop0.read_reg(13).unwrap_or(0);
+        Ok(())
+    }
+
+    /// COP0 is read only, and r0 stays zero
+    fn write_registers(&mut self, regs: &Registers) -> TargetResult<(), Self> {
+        let mut cpu = self.bus.cpu.borrow_mut();
+        cpu.regs[1..32].copy_from_slice(&regs.r[1..]);
+        cpu.lo = regs.lo;
+        cpu.hi = regs.hi;
+        // Writing back the same pc keeps a pending branch
+        if regs.pc != cpu.pc() {
+            cpu.set_pc(regs.pc);
+        }
+        Ok(())
+    }
+
+    fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<(), Self> {
+        for (i, byte) in data.iter_mut().enumerate() {
+            let addr = start_addr.wrapping_add(i as u32);
+            let word = self.bus.peek_word(addr).ok_or(TargetError::NonFatal)?;
+            *byte = (word >> ((addr & 3) * 8)) as u8;
+        }
+        Ok(())
+    }
+
+    fn write_addrs(&mut self, start_addr: u32, data: &[u8]) -> TargetResult<(), Self> {
+        for (i, byte) in data.iter().enumerate() {
+            if !self.bus.poke_ram(start_addr.wrapping_add(i as u32), *byte) {
+                return Err(TargetError::NonFatal);
+            }
+        }
+        // The write may patch code
+        self.bus.cpu.borrow_mut().flush_icache();
+        Ok(())
+    }
+}
+
+impl Breakpoints for GdbTarget<'_> {
+    fn sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn hw_breakpoint(&mut self) -> Option<HwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn hw_watchpoint(&mut self) -> Option<HwWatchpointOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+/// Software and hardware breakpoints are the same thing here
+impl SwBreakpoint for GdbTarget<'_> {
+    fn add_sw_breakpoint(&mut self, addr: u32, _: MipsBreakpointKind) -> TargetResult<bool, Self> {
+        Ok(self.insert(Kind::Execute(addr)))
+    }
+
+    fn remove_sw_breakpoint(
+        &mut self,
+        addr: u32,
+        _: MipsBreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        Ok(self.remove(Kind::Execute(addr)))
+    }
+}
+
+impl HwBreakpoint for GdbTarget<'_> {
+    fn add_hw_breakpoint(&mut self, addr: u32, _: MipsBreakpointKind) -> TargetResult<bool, Self> {
+        Ok(self.insert(Kind::Execute(addr)))
+    }
+
+    fn remove_hw_breakpoint(
+        &mut self,
+        addr: u32,
+        _: MipsBreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        Ok(self.remove(Kind::Execute(addr)))
+    }
+}
+
+impl HwWatchpoint for GdbTarget<'_> {
+    fn add_hw_watchpoint(&mut self, addr: u32, kind: WatchKind) -> TargetResult<bool, Self> {
+        Ok(self.insert(GdbTarget::watch(addr, kind)))
+    }
+
+    fn remove_hw_watchpoint(&mut self, addr: u32, kind: WatchKind) -> TargetResult<bool, Self> {
+        Ok(self.remove(GdbTarget::watch(addr, kind)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gdbstub_arch::mips::reg::MipsCoreRegs;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn registers_and_memory_map_onto_the_cpu() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+        let mut target = GdbTarget::new(&bus);
+
+        let mut regs = MipsCoreRegs::default();
+        regs.r[0] = 0xdead;
+        regs.r[4] = 0x1234_5678;
+        regs.hi = 7;
+        regs.pc = 0x8001_0000;
+        assert!(target.write_registers(&regs).is_ok());
+
+        let mut read = MipsCoreRegs::default();
+        assert!(target.read_registers(&mut read).is_ok());
+        assert_eq!(read.r[0], 0);
+        assert_eq!(read.r[4], 0x1234_5678);
+        assert_eq!(read.hi, 7);
+        assert_eq!(read.pc, 0x8001_0000);
+
+        assert!(target.write_addrs(0x8000_0102, &[0xaa, 0xbb]).is_ok());
+        let mut data = [0; 4];
+        assert!(target.read_addrs(0x8000_0100, &mut data).is_ok());
+        assert_eq!(data, [0, 0, 0xaa, 0xbb]);
+        assert_eq!(bus.peek_word(0x100), Some(0xbbaa_0000));
+
+        // Only RAM is writable
+        assert!(target.write_addrs(0xbfc0_0000, &[0]).is_err());
+        assert!(target.read_addrs(0x1f80_1000, &mut data).is_err());
+    }
+
+    #[test]
+    fn continues_to_a_breakpoint() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+        let mut target = GdbTarget::new(&bus);
+
+        // addiu t0, t0, 1; addiu t0, t0, 1; j 0x80001000; nop
+        let code = [0x2508_0001u32, 0x2508_0001, 0x0800_0400, 0];
+        for (i, word) in code.iter().enumerate() {
+            let addr = 0x8000_1000 + i as u32 * 4;
+            assert!(target.write_addrs(addr, &word.to_le_bytes()).is_ok());
+        }
+        bus.cpu.borrow_mut().set_pc(0x8000_1000);
+        let kind = || MipsBreakpointKind::Mips32;
+
+        assert_eq!(target.add_sw_breakpoint(0x8000_1004, kind()).ok(), Some(true));
+        assert_eq!(target.run_until_stop(|| false), StopReason::SwBreak);
+        assert_eq!(bus.cpu.borrow().pc(), 0x8000_1004);
+        assert_eq!(bus.cpu.borrow().regs[8], 1);
+
+        // Stepping runs the instruction under the breakpoint
+        assert_eq!(target.remove_sw_breakpoint(0x8000_1004, kind()).ok(), Some(true));
+        assert_eq!(target.remove_sw_breakpoint(0x8000_1004, kind()).ok(), Some(false));
+        assert!(bus.cpu.borrow().breakpoints.list().is_empty());
+        assert_eq!(bus.cpu.borrow_mut().debug_step(), None);
+        assert_eq!(bus.cpu.borrow().regs[8], 2);
+    }
+}
@@ -1,29 +1,38 @@
 use crate::hw::bus::{BusDevice};
-// use crate::hw::cpu::{Cpu, PsxBus};
+use crate::hw::rom_writes::RomWriteWarnings;
 use crate::hw::vec::ByteSerialized;
 
-use std::fs::File;
-use std::io::Read;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const BIOS_SIZE: usize = 512 * 1024;
 
 pub struct Bios {
     memory: Vec<u8>,
+    ignored_writes: RomWriteWarnings,
 }
 
 impl Bios {
     pub fn new() -> Bios {
         Bios {
-            memory: vec![0; 512 * 1024],
+            memory: vec![0; BIOS_SIZE],
+            ignored_writes: RomWriteWarnings::new("BIOS", 5),
         }
     }
 
-    pub fn load(&mut self, file: &mut File) {
-        if let Ok(count) = file.read(&mut self.memory) {
-            if count != 512 * 1024 {
-                println!("BIOS ROM size is not 512 * 1024, proceeding nonetheless");
-            }
-        } else {
-            panic!("Could not read BIOS file");
+    /// Loads the ROM image at `path`. Images of another size are cut, or
+    /// padded with zeroes.
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let image = fs::read(path)?;
+        if image.len() != BIOS_SIZE {
+            println!("BIOS ROM size is not 512 * 1024, proceeding nonetheless");
         }
+
+        let len = image.len().min(BIOS_SIZE);
+        self.memory[..len].copy_from_slice(&image[..len]);
+        self.memory[len..].fill(0);
+        Ok(())
     }
 }
 
@@ -32,947 +41,292 @@ impl BusDevice for Bios {
         self.memory.read::<S>(addr)
     }
 
-    fn write<const S: u32>(&mut self, _addr: u32, _value: u32) {
-        panic!("Attempt to write in the BIOS ROM");
+    /// The ROM can't be written, the value is dropped as on hardware
+    fn write<const S: u32>(&mut self, addr: u32, value: u32) {
+        self.ignored_writes.ignore::<S>(addr, value);
+    }
+}
+
+// BIOS functions are called by jumping to A0h, B0h or C0h with their number
+// in t1. Calls can be logged with their arguments, see `describe_call`.
+
+/// Names of the A(A0h) functions, by number
+const A_FUNCTIONS: [&str; 0xb5] = [
+    "FileOpen", "FileSeek", "FileRead", "FileWrite", "FileClose", "FileIoctl", "exit",
+    "FileGetDeviceFlag", "FileGetc", "FilePutc", "todigit", "atof", "strtoul", "strtol", "abs",
+    "labs", "atoi", "atol", "atob", "SaveState", "RestoreState", "strcat", "strncat", "strcmp",
+    "strncmp", "strcpy", "strncpy", "strlen", "index", "rindex", "strchr", "strrchr", "strpbrk",
+    "strspn", "strcspn", "strtok", "strstr", "toupper", "tolower", "bcopy", "bzero", "bcmp",
+    "memcpy", "memset", "memmove", "memcmp", "memchr", "rand", "srand", "qsort", "strtod", "malloc",
+    "free", "lsearch", "bsearch", "calloc", "realloc", "InitHeap", "SystemErrorExit",
+    "std_in_getchar", "putchar", "std_in_gets", "std_out_puts", "printf",
+    "SystemErrorUnresolvedException", "LoadExeHeader", "LoadExeFile", "DoExecute", "FlushCache",
+    "init_a0_b0_c0_vectors", "GPU_dw", "gpu_send_dma", "SendGP1Command", "GPU_cw", "GPU_cwp",
+    "send_gpu_linked_list", "gpu_abort_dma", "GetGPUStatus", "gpu_sync", "SystemError",
+    "SystemError", "LoadAndExecute", "SystemError", "SystemError", "CdInit", "_bu_init", "CdRemove",
+    "return", "return", "return", "return", "dev_tty_init", "dev_tty_open", "dev_tty_in_out",
+    "dev_tty_ioctl", "dev_cd_open", "dev_cd_read", "dev_cd_close", "dev_cd_firstfile",
+    "dev_cd_nextfile", "dev_cd_chdir", "dev_card_open", "dev_card_read", "dev_card_write",
+    "dev_card_close", "dev_card_firstfile", "dev_card_nextfile", "dev_card_erase",
+    "dev_card_undelete", "dev_card_format", "dev_card_rename", "card_clear_error?", "_bu_init",
+    "CdInit", "CdRemove", "return", "return", "return", "return", "return", "CdAsyncSeekL",
+    "return", "return", "return", "CdAsyncGetStatus", "return", "CdAsyncReadSector", "return",
+    "return", "CdAsyncSetMode", "return", "return", "return", "return", "return", "return",
+    "return", "return", "return", "return", "return", "return", "return", "return",
+    "CdromIoIrqFunc1", "CdromDmaIrqFunc1", "CdromIoIrqFunc2", "CdromDmaIrqFunc2",
+    "CdromGetInt5errCode", "CdInitSubFunc", "AddCDROMDevice", "AddMemCardDevice",
+    "AddDuartTtyDevice", "AddDummyTtyDevice", "SystemError", "SystemError", "SetConf", "GetConf",
+    "SetCdromIrqAutoAbort", "SetMemSize", "WarmBoot", "SystemErrorBootOrDiskFailure",
+    "EnqueueCdIntr", "DequeueCdIntr", "CdGetLbn", "CdReadSector", "CdGetStatus", "bu_callback_okay",
+    "bu_callback_err_write", "bu_callback_err_busy", "bu_callback_err_eject", "_card_info",
+    "_card_async_load_directory", "set_card_auto_format", "bu_callback_err_prev_write",
+    "card_write_test", "return", "return", "ioabort_raw", "return", "GetSystemInfo",
+];
+
+/// Names of the B(B0h) functions, by number
+const B_FUNCTIONS: [&str; 0x5f] = [
+    "alloc_kernel_memory", "free_kernel_memory", "init_timer", "get_timer", "enable_timer_irq",
+    "disable_timer_irq", "restart_timer", "DeliverEvent", "OpenEvent", "CloseEvent", "WaitEvent",
+    "TestEvent", "EnableEvent", "DisableEvent", "OpenThread", "CloseThread", "ChangeThread",
+    "jump_to_00000000h", "InitPad", "StartPad", "StopPad", "OutdatedPadInitAndStart",
+    "OutdatedPadGetButtons", "ReturnFromException", "SetDefaultExitFromException",
+    "SetCustomExitFromException", "SystemError", "SystemError", "SystemError", "SystemError",
+    "SystemError", "SystemError", "UnDeliverEvent", "SystemError", "SystemError", "SystemError",
+    "jump_to_00000000h", "jump_to_00000000h", "jump_to_00000000h", "jump_to_00000000h",
+    "jump_to_00000000h", "jump_to_00000000h", "SystemError", "SystemError", "jump_to_00000000h",
+    "jump_to_00000000h", "jump_to_00000000h", "jump_to_00000000h", "jump_to_00000000h",
+    "jump_to_00000000h", "FileOpen", "FileSeek", "FileRead", "FileWrite", "FileClose", "FileIoctl",
+    "exit", "FileGetDeviceFlag", "FileGetc", "FilePutc", "std_in_getchar", "putchar", "std_in_gets",
+    "std_out_puts", "chdir", "FormatDevice", "firstfile", "nextfile", "FileRename", "FileDelete",
+    "FileUndelete", "AddDevice", "RemoveDevice", "PrintInstalledDevices", "InitCard", "StartCard",
+    "StopCard", "_card_info_subfunc", "write_card_sector", "read_card_sector", "allow_new_card",
+    "Krom2RawAdd", "SystemError", "Krom2Offset", "GetLastError", "GetLastFileError", "GetC0Table",
+    "GetB0Table", "get_bu_callback_port", "testdevice", "SystemError", "ChangeClearPad",
+    "get_card_status", "wait_card_status", "N/A",
+];
+
+/// Names of the C(C0h) functions, by number
+const C_FUNCTIONS: [&str; 0x1e] = [
+    "EnqueueTimerAndVblankIrqs", "EnqueueSyscallHandler", "SysEnqIntRP", "SysDeqIntRP",
+    "get_free_EvCB_slot", "get_free_TCB_slot", "ExceptionHandler", "InstallExceptionHandlers",
+    "SysInitMemory", "SysInitKernelVariables", "ChangeClearRCnt", "SystemError", "InitDefInt",
+    "SetIrqAutoAck", "return", "return", "return", "return", "InstallDevices", "FlushStdInOutPut",
+    "return", "tty_cdevinput", "tty_cdevscan", "tty_circgetc", "tty_circputc", "ioabort",
+    "set_card_find_mode", "KernelRedirect", "AdjustA0Table", "get_card_find_mode",
+];
+/// How an argument of a BIOS function is shown
+#[derive(Copy, Clone)]
+enum Arg {
+    Hex,
+    Dec,
+    /// Pointer to a NUL terminated string, e.g. a file name
+    Str,
+    /// Event class, the device or software that raises the event
+    Class,
+    /// Event spec, what happened to the class
+    Spec,
+    /// Handle returned by OpenEvent
+    Event,
+    /// Handle returned by OpenThread
+    Thread,
+}
+
+/// Strings are cut there, a bad pointer shouldn't flood the log
+const MAX_STRING: usize = 64;
+
+/// Arguments shown for a function, the others are logged by name only
+fn arguments(vector: u32, function: u32) -> &'static [Arg] {
+    use Arg::*;
+
+    match (vector, function) {
+        // FileOpen(name, mode)
+        (0xa0, 0x00) | (0xb0, 0x32) => &[Str, Hex],
+        // FileSeek(fd, offset, origin), FileRead/FileWrite(fd, buffer, length)
+        (0xa0, 0x01..=0x03) | (0xb0, 0x33..=0x35) => &[Dec, Hex, Dec],
+        // FileClose, exit, FileGetDeviceFlag, FileGetc
+        (0xa0, 0x04 | 0x06..=0x08) | (0xb0, 0x36 | 0x38..=0x3a) => &[Dec],
+        (0xa0, 0x05) | (0xb0, 0x37) => &[Dec, Hex, Hex],
+        (0xa0, 0x09) | (0xb0, 0x3b) => &[Hex, Dec],
+        (0xa0, 0x17) => &[Str, Str],
+        (0xa0, 0x18) => &[Str, Str, Dec],
+        (0xa0, 0x19) => &[Hex, Str],
+        (0xa0, 0x1b) | (0xa0, 0x3e | 0x3f) | (0xb0, 0x3f) => &[Str],
+        (0xa0, 0x2a | 0x2b) => &[Hex, Hex, Dec],
+        (0xa0, 0x33 | 0x9f) | (0xb0, 0x00) => &[Dec],
+        (0xa0, 0x34) | (0xb0, 0x01 | 0x43) => &[Hex],
+        (0xa0, 0x37) => &[Dec, Dec],
+        (0xa0, 0x38 | 0x39) => &[Hex, Dec],
+        // LoadExeHeader/LoadExeFile(name, header)
+        (0xa0, 0x41 | 0x42) => &[Str, Hex],
+        (0xa0, 0x43) => &[Hex, Hex, Hex],
+        // LoadAndExecute(name, stack base, stack offset)
+        (0xa0, 0x51) => &[Str, Hex, Hex],
+        (0xa0, 0x9c) => &[Dec, Dec, Hex],
+        // CdReadSector(count, sector, buffer)
+        (0xa0, 0xa5) => &[Dec, Dec, Hex],
+        (0xb0, 0x07 | 0x20) => &[Class, Spec],
+        // OpenEvent(class, spec, mode, handler)
+        (0xb0, 0x08) => &[Class, Spec, Hex, Hex],
+        (0xb0, 0x09..=0x0d) => &[Event],
+        // OpenThread(pc, sp, gp)
+        (0xb0, 0x0e) => &[Hex, Hex, Hex],
+        (0xb0, 0x0f | 0x10) => &[Thread],
+        // chdir, FormatDevice, FileDelete, FileUndelete, RemoveDevice, testdevice
+        (0xb0, 0x40 | 0x41 | 0x45 | 0x46 | 0x48 | 0x5b) => &[Str],
+        (0xb0, 0x42) => &[Str, Hex],
+        (0xb0, 0x44) => &[Str, Str],
+        // SysEnqIntRP/SysDeqIntRP(priority, handler)
+        (0xc0, 0x02 | 0x03) => &[Dec, Hex],
+        _ => &[],
+    }
+}
+
+fn class_name(class: u32) -> Option<&'static str> {
+    Some(match class {
+        0xf000_0001 => "VBLANK",
+        0xf000_0002 => "GPU",
+        0xf000_0003 => "CDROM",
+        0xf000_0004 => "DMA",
+        0xf000_0005 => "RTC0",
+        0xf000_0006 => "RTC1",
+        0xf000_0008 => "CONTROLLER",
+        0xf000_0009 => "SPU",
+        0xf000_000a => "PIO",
+        0xf000_000b => "SIO",
+        0xf000_0010 => "CPU",
+        0xf000_0011 => "CARD",
+        0xf200_0000 => "RCNT0",
+        0xf200_0001 => "RCNT1",
+        0xf200_0002 => "RCNT2",
+        0xf200_0003 => "RCNT3",
+        0xf400_0001 => "CARD_BIOS",
+        0xf400_0002 => "MATH",
+        _ => return None,
+    })
+}
+
+fn spec_name(spec: u32) -> Option<&'static str> {
+    Some(match spec {
+        0x0001 => "COUNTER",
+        0x0002 => "INT",
+        0x0004 => "IOE",
+        0x0008 => "CLOSE",
+        0x0010 => "ACK",
+        0x0020 => "COMP",
+        0x0040 => "DR",
+        0x0080 => "DE",
+        0x0100 => "TIMOUT",
+        0x0200 => "UNKNOWN",
+        0x0400 => "IOER",
+        0x0800 => "IOEW",
+        0x1000 => "TRAP",
+        0x2000 => "NEW",
+        0x4000 => "SYSCALL",
+        0x8000 => "ERROR",
+        0x8001 => "PREWRITE",
+        _ => return None,
+    })
+}
+
+fn read_string(addr: u32, peek: &impl Fn(u32) -> Option<u8>) -> Option<String> {
+    let mut bytes = vec![];
+    for i in 0..=MAX_STRING as u32 {
+        match peek(addr.wrapping_add(i))? {
+            0 => return Some(format!("{:?}", String::from_utf8_lossy(&bytes))),
+            _ if i == MAX_STRING as u32 => break,
+            c => bytes.push(c),
+        }
+    }
+    Some(format!("{:?}...", String::from_utf8_lossy(&bytes)))
+}
+
+fn format_arg(arg: Arg, value: u32, peek: &impl Fn(u32) -> Option<u8>) -> String {
+    let name = match arg {
+        Arg::Hex => None,
+        Arg::Dec => return format!("{}", value as i32),
+        Arg::Str => read_string(value, peek),
+        Arg::Class => class_name(value).map(String::from),
+        Arg::Spec => spec_name(value).map(String::from),
+        Arg::Event if value >> 16 == 0xf100 => Some(format!("event {}", value & 0xffff)),
+        Arg::Thread if value >> 16 == 0xff00 => Some(format!("thread {}", value & 0xffff)),
+        Arg::Event | Arg::Thread => None,
+    };
+    name.unwrap_or_else(|| format!("{:#x}", value))
+}
+
+/// One line for the call of BIOS function `function` through `vector`, with
+/// its arguments read from a0-a3 and the memory behind `peek`. None for the
+/// calls not worth logging: putchar is on the TTY already, and
+/// ReturnFromException ends every interrupt.
+pub fn describe_call(
+    vector: u32,
+    function: u32,
+    args: &[u32; 4],
+    ra: u32,
+    peek: impl Fn(u32) -> Option<u8>,
+) -> Option<String> {
+    let (letter, names, function): (char, &[&str], u32) = match vector {
+        0xa0 => ('A', &A_FUNCTIONS, function & 0xff),
+        0xb0 => ('B', &B_FUNCTIONS, function & 0xff),
+        0xc0 => ('C', &C_FUNCTIONS, function & 0x7f),
+        _ => return None,
+    };
+    if let (0xa0, 0x3c) | (0xb0, 0x3d | 0x17) = (vector, function) {
+        return None;
     }
+
+    let name = names.get(function as usize).copied().unwrap_or("unknown");
+    let args: Vec<String> = arguments(vector, function)
+        .iter()
+        .zip(args)
+        .map(|(&arg, &value)| format_arg(arg, value, &peek))
+        .collect();
+    Some(format!(
+        "{}({:02x}h) {}({}) from {:08x}",
+        letter,
+        function,
+        name,
+        args.join(", "),
+        ra
+    ))
 }
 
-// impl Bios {
-//     pub fn call_a<T: PsxBus>(cpu: &mut Cpu<T>) {
-//         match cpu.regs[9] {
-//             0x00 => {
-//                 println!("FileOpen");
-//             }
-//             0x01 => {
-//                 println!("FileSeek");
-//             }
-//             0x02 => {
-//                 println!("FileRead");
-//             }
-//             0x03 => {
-//                 println!("FileWrite");
-//             }
-//             0x04 => {
-//                 println!("FileClose");
-//             }
-//             0x05 => {
-//                 println!("FileIoctl");
-//             }
-//             0x06 => {
-//                 println!("exit");
-//             }
-//             0x07 => {
-//                 println!("FileGetDeviceFlag");
-//             }
-//             0x08 => {
-//                 println!("FileGetc");
-//             }
-//             0x09 => {
-//                 println!("FilePutc");
-//             }
-//             0x0A => {
-//                 println!("todigit");
-//             }
-//             0x0B => {
-//                 println!("atof");
-//             }
-//             0x0C => {
-//                 println!("strtoul");
-//             }
-//             0x0D => {
-//                 println!("strtol");
-//             }
-//             0x0E => {
-//                 println!("abs");
-//             }
-//             0x0F => {
-//                 println!("labs");
-//             }
-//             0x10 => {
-//                 println!("atoi");
-//             }
-//             0x11 => {
-//                 println!("atol");
-//             }
-//             0x12 => {
-//                 println!("atob");
-//             }
-//             0x13 => {
-//                 println!("SaveState");
-//             }
-//             0x14 => {
-//                 println!("RestoreState");
-//             }
-//             0x15 => {
-//                 println!("strcat");
-//             }
-//             0x16 => {
-//                 println!("strncat");
-//             }
-//             0x17 => {
-//                 println!("strcmp");
-//             }
-//             0x18 => {
-//                 println!("strncmp");
-//             }
-//             0x19 => {
-//                 println!("strcpy");
-//             }
-//             0x1A => {
-//                 println!("strncpy");
-//             }
-//             0x1B => {
-//                 println!("strlen");
-//             }
-//             0x1C => {
-//                 println!("index");
-//             }
-//             0x1D => {
-//                 println!("rindex");
-//             }
-//             0x1E => {
-//                 println!("strchr");
-//             }
-//             0x1F => {
-//                 println!("strrchr");
-//             }
-//             0x20 => {
-//                 println!("strpbrk");
-//             }
-//             0x21 => {
-//                 println!("strspn");
-//             }
-//             0x22 => {
-//                 println!("strcspn");
-//             }
-//             0x23 => {
-//                 println!("strtok");
-//             }
-//             0x24 => {
-//                 println!("strstr");
-//             }
-//             0x25 => {
-//                 println!("toupper");
-//             }
-//             0x26 => {
-//                 println!("tolower");
-//             }
-//             0x27 => {
-//                 println!("bcopy");
-//             }
-//             0x28 => {
-//                 println!("bzero");
-//             }
-//             0x29 => {
-//                 println!("bcmp");
-//             }
-//             0x2A => {
-//                 println!("memcpy");
-//             }
-//             0x2B => {
-//                 println!("memset");
-//             }
-//             0x2C => {
-//                 println!("memmove");
-//             }
-//             0x2D => {
-//                 println!("memcmp");
-//             }
-//             0x2E => {
-//                 println!("memchr");
-//             }
-//             0x2F => {
-//                 println!("rand");
-//             }
-//             0x30 => {
-//                 println!("srand");
-//             }
-//             0x31 => {
-//                 println!("qsort");
-//             }
-//             0x32 => {
-//                 println!("strtod");
-//             }
-//             0x33 => {
-//                 println!("malloc");
-//             }
-//             0x34 => {
-//                 println!("free");
-//             }
-//             0x35 => {
-//                 println!("lsearch");
-//             }
-//             0x36 => {
-//                 println!("bsearch");
-//             }
-//             0x37 => {
-//                 println!("calloc");
-//             }
-//             0x38 => {
-//                 println!("realloc");
-//             }
-//             0x39 => {
-//                 println!("InitHeap");
-//             }
-//             0x3A => {
-//                 println!("SystemErrorExit");
-//             }
-//             0x3B => {
-//                 println!("std_in_getchar");
-//             }
-//             0x3C => {
-//                 print!("{}", cpu.regs[4] as u8 as char);
-//             }
-//             0x3D => {
-//                 println!("std_in_gets");
-//             }
-//             0x3E => {
-//                 println!("std_out_puts");
-//             }
-//             0x3F => { /*println!("printf");*/ }
-//             0x40 => {
-//                 panic!("SystemErrorUnresolvedException");
-//             }
-//             0x41 => {
-//                 println!("LoadExeHeader");
-//             }
-//             0x42 => {
-//                 println!("LoadExeFile");
-//             }
-//             0x43 => {
-//                 println!("DoExecute");
-//             }
-//             0x44 => {
-//                 println!("FlushCache");
-//             }
-//             0x45 => {
-//                 println!("init_a0_b0_c0_vectors");
-//             }
-//             0x46 => {
-//                 println!("GPU_dw");
-//             }
-//             0x47 => {
-//                 println!("gpu_send_dma");
-//             }
-//             0x48 => {
-//                 println!("SendGP1Command");
-//             }
-//             0x49 => {
-//                 println!("GPU_cw");
-//             }
-//             0x4A => {
-//                 println!("GPU_cwp");
-//             }
-//             0x4B => {
-//                 println!("send_gpu_linked_list");
-//             }
-//             0x4C => {
-//                 println!("gpu_abort_dma");
-//             }
-//             0x4D => {
-//                 println!("GetGPUStatus");
-//             }
-//             0x4E => {
-//                 println!("gpu_sync");
-//             }
-//             0x4F => {
-//                 println!("SystemError");
-//             }
-//             0x50 => {
-//                 println!("SystemError");
-//             }
-//             0x51 => {
-//                 println!("LoadAndExecute");
-//             }
-//             0x52 => {
-//                 println!("SystemError");
-//             }
-//             0x53 => {
-//                 println!("SystemError");
-//             }
-//             0x54 => {
-//                 println!("CdInit");
-//             }
-//             0x55 => {
-//                 println!("_bu_init");
-//             }
-//             0x56 => {
-//                 println!("CdRemove");
-//             }
-//             0x57 => {
-//                 println!("return");
-//             }
-//             0x58 => {
-//                 println!("return");
-//             }
-//             0x59 => {
-//                 println!("return");
-//             }
-//             0x5A => {
-//                 println!("return");
-//             }
-//             0x5B => {
-//                 println!("dev_tty_init");
-//             }
-//             0x5C => {
-//                 println!("dev_tty_open");
-//             }
-//             0x5D => {
-//                 println!("dev_tty_in_out");
-//             }
-//             0x5E => {
-//                 println!("dev_tty_ioctl");
-//             }
-//             0x5F => {
-//                 println!("dev_cd_open");
-//             }
-//             0x60 => {
-//                 println!("dev_cd_read");
-//             }
-//             0x61 => {
-//                 println!("dev_cd_close");
-//             }
-//             0x62 => {
-//                 println!("dev_cd_firstfile");
-//             }
-//             0x63 => {
-//                 println!("dev_cd_nextfile");
-//             }
-//             0x64 => {
-//                 println!("dev_cd_chdir");
-//             }
-//             0x65 => {
-//                 println!("dev_card_open");
-//             }
-//             0x66 => {
-//                 println!("dev_card_read");
-//             }
-//             0x67 => {
-//                 println!("dev_card_write");
-//             }
-//             0x68 => {
-//                 println!("dev_card_close");
-//             }
-//             0x69 => {
-//                 println!("dev_card_firstfile");
-//             }
-//             0x6A => {
-//                 println!("dev_card_nextfile");
-//             }
-//             0x6B => {
-//                 println!("dev_card_erase");
-//             }
-//             0x6C => {
-//                 println!("dev_card_undelete");
-//             }
-//             0x6D => {
-//                 println!("dev_card_format");
-//             }
-//             0x6E => {
-//                 println!("dev_card_rename");
-//             }
-//             0x6F => {
-//                 println!("card_clear_error?");
-//             }
-//             0x70 => {
-//                 println!("_bu_init");
-//             }
-//             0x71 => {
-//                 println!("CdInit");
-//             }
-//             0x72 => {
-//                 println!("CdRemove");
-//             }
-//             0x73 => {
-//                 println!("return");
-//             }
-//             0x74 => {
-//                 println!("return");
-//             }
-//             0x75 => {
-//                 println!("return");
-//             }
-//             0x76 => {
-//                 println!("return");
-//             }
-//             0x77 => {
-//                 println!("return");
-//             }
-//             0x78 => {
-//                 println!("CdAsyncSeekL");
-//             }
-//             0x79 => {
-//                 println!("return");
-//             }
-//             0x7A => {
-//                 println!("return");
-//             }
-//             0x7B => {
-//                 println!("return");
-//             }
-//             0x7C => {
-//                 println!("CdAsyncGetStatus");
-//             }
-//             0x7D => {
-//                 println!("return");
-//             }
-//             0x7E => {
-//                 println!("CdAsyncReadSector");
-//             }
-//             0x7F => {
-//                 println!("return");
-//             }
-//             0x80 => {
-//                 println!("return");
-//             }
-//             0x81 => {
-//                 println!("CdAsyncSetMode");
-//             }
-//             0x82 => {
-//                 println!("return");
-//             }
-//             0x83 => {
-//                 println!("return");
-//             }
-//             0x84 => {
-//                 println!("return");
-//             }
-//             0x85 => {
-//                 println!("return");
-//             }
-//             0x86 => {
-//                 println!("return");
-//             }
-//             0x87 => {
-//                 println!("return");
-//             }
-//             0x88 => {
-//                 println!("return");
-//             }
-//             0x89 => {
-//                 println!("return");
-//             }
-//             0x8A => {
-//                 println!("return");
-//             }
-//             0x8B => {
-//                 println!("return");
-//             }
-//             0x8C => {
-//                 println!("return");
-//             }
-//             0x8D => {
-//                 println!("return");
-//             }
-//             0x8E => {
-//                 println!("return");
-//             }
-//             0x8F => {
-//                 println!("return");
-//             }
-//             0x90 => {
-//                 println!("CdromIoIrqFunc1");
-//             }
-//             0x91 => {
-//                 println!("CdromDmaIrqFunc1");
-//             }
-//             0x92 => {
-//                 println!("CdromIoIrqFunc2");
-//             }
-//             0x93 => {
-//                 println!("CdromDmaIrqFunc2");
-//             }
-//             0x94 => {
-//                 println!("CdromGetInt5errCode");
-//             }
-//             0x95 => {
-//                 println!("CdInitSubFunc");
-//             }
-//             0x96 => {
-//                 println!("AddCDROMDevice");
-//             }
-//             0x97 => {
-//                 println!("AddMemCardDevice");
-//             }
-//             0x98 => {
-//                 println!("AddDuartTtyDevice");
-//             }
-//             0x99 => {
-//                 println!("AddDummyTtyDevice");
-//             }
-//             0x9A => {
-//                 println!("SystemError");
-//             }
-//             0x9B => {
-//                 println!("SystemError");
-//             }
-//             0x9C => {
-//                 println!("SetConf");
-//             }
-//             0x9D => {
-//                 println!("GetConf");
-//             }
-//             0x9E => {
-//                 println!("SetCdromIrqAutoAbort");
-//             }
-//             0x9F => {
-//                 println!("SetMemSize");
-//             }
-//             0xA0 => {
-//                 println!("WarmBoot");
-//             }
-//             0xA1 => {
-//                 println!("SystemErrorBootOrDiskFailure");
-//             }
-//             0xA2 => {
-//                 println!("EnqueueCdIntr");
-//             }
-//             0xA3 => {
-//                 println!("DequeueCdIntr");
-//             }
-//             0xA4 => {
-//                 println!("CdGetLbn");
-//             }
-//             0xA5 => {
-//                 println!("CdReadSector");
-//             }
-//             0xA6 => {
-//                 println!("CdGetStatus");
-//             }
-//             0xA7 => {
-//                 println!("bu_callback_okay");
-//             }
-//             0xA8 => {
-//                 println!("bu_callback_err_write");
-//             }
-//             0xA9 => {
-//                 println!("bu_callback_err_busy");
-//             }
-//             0xAA => {
-//                 println!("bu_callback_err_eject");
-//             }
-//             0xAB => {
-//                 println!("_card_info");
-//             }
-//             0xAC => {
-//                 println!("_card_async_load_directory");
-//             }
-//             0xAD => {
-//                 println!("set_card_auto_format");
-//             }
-//             0xAE => {
-//                 println!("bu_callback_err_prev_write");
-//             }
-//             0xAF => {
-//                 println!("card_write_test");
-//             }
-//             0xB0 => {
-//                 println!("return");
-//             }
-//             0xB1 => {
-//                 println!("return");
-//             }
-//             0xB2 => {
-//                 println!("ioabort_raw");
-//             }
-//             0xB3 => {
-//                 println!("return");
-//             }
-//             0xB4 => {
-//                 println!("GetSystemInfo");
-//             }
-//             _ => {
-//                 println!("Unhandled syscall A({:02x})", cpu.regs[9] & 0xff);
-//             }
-//         }
-//     }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-//     pub fn call_b<T: PsxBus>(cpu: &mut Cpu<T>) {
-//         match cpu.regs[9] {
-//             0x00 => {
-//                 println!("alloc_kernel_memory");
-//             }
-//             0x01 => {
-//                 println!("free_kernel_memory");
-//             }
-//             0x02 => {
-//                 println!("init_timer");
-//             }
-//             0x03 => {
-//                 println!("get_timer");
-//             }
-//             0x04 => {
-//                 println!("enable_timer_irq");
-//             }
-//             0x05 => {
-//                 println!("disable_timer_irq");
-//             }
-//             0x06 => {
-//                 println!("restart_timer");
-//             }
-//             0x07 => {
-//                 println!("DeliverEvent");
-//             }
-//             0x08 => {
-//                 println!("OpenEvent");
-//             }
-//             0x09 => {
-//                 println!("CloseEvent");
-//             }
-//             0x0A => {
-//                 println!("WaitEvent");
-//             }
-//             0x0B => {
-//                 println!("TestEvent");
-//             }
-//             0x0C => {
-//                 println!("EnableEvent");
-//             }
-//             0x0D => {
-//                 println!("DisableEvent");
-//             }
-//             0x0E => {
-//                 println!("OpenThread");
-//             }
-//             0x0F => {
-//                 println!("CloseThread");
-//             }
-//             0x10 => {
-//                 println!("ChangeThread");
-//             }
-//             0x11 => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x12 => {
-//                 println!("InitPad");
-//             }
-//             0x13 => {
-//                 println!("StartPad");
-//             }
-//             0x14 => {
-//                 println!("StopPad");
-//             }
-//             0x15 => {
-//                 println!("OutdatedPadInitAndStart");
-//             }
-//             0x16 => {
-//                 println!("OutdatedPadGetButtons");
-//             }
-//             0x17 => { /* println!("ReturnFromException"); */ }
-//             0x18 => {
-//                 println!("SetDefaultExitFromException");
-//             }
-//             0x19 => {
-//                 println!("SetCustomExitFromException");
-//             }
-//             0x1A => {
-//                 println!("SystemError");
-//             }
-//             0x1B => {
-//                 println!("SystemError");
-//             }
-//             0x1C => {
-//                 println!("SystemError");
-//             }
-//             0x1D => {
-//                 println!("SystemError");
-//             }
-//             0x1E => {
-//                 println!("SystemError");
-//             }
-//             0x1F => {
-//                 println!("SystemError");
-//             }
-//             0x20 => {
-//                 println!("UnDeliverEvent");
-//             }
-//             0x21 => {
-//                 println!("SystemError");
-//             }
-//             0x22 => {
-//                 println!("SystemError");
-//             }
-//             0x23 => {
-//                 println!("SystemError");
-//             }
-//             0x24 => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x25 => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x26 => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x27 => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x28 => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x29 => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x2A => {
-//                 println!("SystemError");
-//             }
-//             0x2B => {
-//                 println!("SystemError");
-//             }
-//             0x2C => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x2D => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x2E => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x2F => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x30 => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x31 => {
-//                 println!("jump_to_00000000h");
-//             }
-//             0x32 => {
-//                 println!("FileOpen");
-//             }
-//             0x33 => {
-//                 println!("FileSeek");
-//             }
-//             0x34 => {
-//                 println!("FileRead");
-//             }
-//             0x35 => {
-//                 println!("FileWrite");
-//             }
-//             0x36 => {
-//                 println!("FileClose");
-//             }
-//             0x37 => {
-//                 println!("FileIoctl");
-//             }
-//             0x38 => {
-//                 println!("exit");
-//             }
-//             0x39 => {
-//                 println!("FileGetDeviceFlag");
-//             }
-//             0x3A => {
-//                 println!("FileGetc");
-//             }
-//             0x3B => {
-//                 println!("FilePutc");
-//             }
-//             0x3C => {
-//                 println!("std_in_getchar");
-//             }
-//             0x3D => {
-//                 print!("{}", cpu.regs[4] as u8 as char);
-//             }
-//             0x3E => {
-//                 println!("std_in_gets");
-//             }
-//             0x3F => {
-//                 println!("std_out_puts");
-//             }
-//             0x40 => {
-//                 println!("chdir");
-//             }
-//             0x41 => {
-//                 println!("FormatDevice");
-//             }
-//             0x42 => {
-//                 println!("firstfile");
-//             }
-//             0x43 => {
-//                 println!("nextfile");
-//             }
-//             0x44 => {
-//                 println!("FileRename");
-//             }
-//             0x45 => {
-//                 println!("FileDelete");
-//             }
-//             0x46 => {
-//                 println!("FileUndelete");
-//             }
-//             0x47 => {
-//                 println!("AddDevice");
-//             }
-//             0x48 => {
-//                 println!("RemoveDevice");
-//             }
-//             0x49 => {
-//                 println!("PrintInstalledDevices");
-//             }
-//             0x4A => {
-//                 println!("InitCard");
-//             }
-//             0x4B => {
-//                 println!("StartCard");
-//             }
-//             0x4C => {
-//                 println!("StopCard");
-//             }
-//             0x4D => {
-//                 println!("_card_info_subfunc");
-//             }
-//             0x4E => {
-//                 println!("write_card_sector");
-//             }
-//             0x4F => {
-//                 println!("read_card_sector");
-//             }
-//             0x50 => {
-//                 println!("allow_new_card");
-//             }
-//             0x51 => {
-//                 println!("Krom2RawAdd");
-//             }
-//             0x52 => {
-//                 println!("SystemError");
-//             }
-//             0x53 => {
-//                 println!("Krom2Offset");
-//             }
-//             0x54 => {
-//                 println!("GetLastError");
-//             }
-//             0x55 => {
-//                 println!("GetLastFileError");
-//             }
-//             0x56 => {
-//                 println!("GetC0Table");
-//             }
-//             0x57 => {
-//                 println!("GetB0Table");
-//             }
-//             0x58 => {
-//                 println!("get_bu_callback_port");
-//             }
-//             0x59 => {
-//                 println!("testdevice");
-//             }
-//             0x5A => {
-//                 println!("SystemError");
-//             }
-//             0x5B => {
-//                 println!("ChangeClearPad");
-//             }
-//             0x5C => {
-//                 println!("get_card_status");
-//             }
-//             0x5D => {
-//                 println!("wait_card_status");
-//             }
-//             0x5E => {
-//                 println!("N/A");
-//             }
-//             _ => {
-//                 println!("Unhandled syscall B({:02x})", cpu.regs[9] & 0xff);
-//             }
-//         }
-//     }
+    fn memory(addr: u32) -> Option<u8> {
+        let text = b"cdrom:\\SYSTEM.CNF;1\0";
+        text.get(addr.checked_sub(0x100)? as usize).copied()
+    }
 
-//     pub fn call_c<T: PsxBus>(cpu: &mut Cpu<T>) {
-//         match cpu.regs[9] & 0x7f {
-//             0x00 => {
-//                 println!("EnqueueTimerAndVblankIrqs");
-//             }
-//             0x01 => {
-//                 println!("EnqueueSyscallHandler");
-//             }
-//             0x02 => {
-//                 println!("SysEnqIntRP");
-//             }
-//             0x03 => {
-//                 println!("SysDeqIntRP");
-//             }
-//             0x04 => {
-//                 println!("get_free_EvCB_slot");
-//             }
-//             0x05 => {
-//                 println!("get_free_TCB_slot");
-//             }
-//             0x06 => {
-//                 println!("ExceptionHandler");
-//             }
-//             0x07 => {
-//                 println!("InstallExceptionHandlers");
-//             }
-//             0x08 => {
-//                 println!("SysInitMemory");
-//             }
-//             0x09 => {
-//                 println!("SysInitKernelVariables");
-//             }
-//             0x0A => {
-//                 println!("ChangeClearRCnt");
-//             }
-//             0x0B => {
-//                 println!("SystemError");
-//             }
-//             0x0C => {
-//                 println!("InitDefInt");
-//             }
-//             0x0D => {
-//                 println!("SetIrqAutoAck");
-//             }
-//             0x0E => {
-//                 println!("return");
-//             }
-//             0x0F => {
-//                 println!("return");
-//             }
-//             0x10 => {
-//                 println!("return");
-//             }
-//             0x11 => {
-//                 println!("return");
-//             }
-//             0x12 => {
-//                 println!("InstallDevices");
-//             }
-//             0x13 => {
-//                 println!("FlushStdInOutPut");
-//             }
-//             0x14 => {
-//                 println!("return");
-//             }
-//             0x15 => {
-//                 println!("tty_cdevinput");
-//             }
-//             0x16 => {
-//                 println!("tty_cdevscan");
-//             }
-//             0x17 => {
-//                 println!("tty_circgetc");
-//             }
-//             0x18 => {
-//                 println!("tty_circputc");
-//             }
-//             0x19 => {
-//                 println!("ioabort");
-//             }
-//             0x1A => {
-//                 println!("set_card_find_mode");
-//             }
-//             0x1B => {
-//                 println!("KernelRedirect");
-//             }
-//             0x1C => {
-//                 println!("AdjustA0Table");
-//             }
-//             0x1D => {
-//                 println!("get_card_find_mode");
-//             }
-//             _ => {
-//                 println!("Unhandled syscall C({:02x})", cpu.regs[9] & 0x7f);
-//             }
-//         }
-//     }
-// }
+    #[test]
+    fn decodes_the_arguments_of_calls() {
+        let describe =
+            |vector, function, args| describe_call(vector, function, &args, 0x8001_0000, memory);
+
+        assert_eq!(
+            describe(0xa0, 0x00, [0x100, 1, 0, 0]).unwrap(),
+            "A(00h) FileOpen(\"cdrom:\\\\SYSTEM.CNF;1\", 0x1) from 80010000"
+        );
+        assert_eq!(
+            describe(0xb0, 0x08, [0xf000_0003, 0x0020, 0x2000, 0]).unwrap(),
+            "B(08h) OpenEvent(CDROM, COMP, 0x2000, 0x0) from 80010000"
+        );
+        assert_eq!(
+            describe(0xb0, 0x10, [0xff00_0001, 0, 0, 0]).unwrap(),
+            "B(10h) ChangeThread(thread 1) from 80010000"
+        );
+        assert_eq!(
+            describe(0xb0, 0x0b, [0x1234, 0, 0, 0]).unwrap(),
+            "B(0bh) TestEvent(0x1234) from 80010000"
+        );
+        // A bad pointer is shown as is
+        assert_eq!(
+            describe(0xb0, 0x45, [0x2000, 0, 0, 0]).unwrap(),
+            "B(45h) FileDelete(0x2000) from 80010000"
+        );
+        assert_eq!(describe(0xc0, 0x7f, [0; 4]).unwrap(), "C(7fh) unknown() from 80010000");
+        assert_eq!(describe(0xa0, 0x3c, [0x41, 0, 0, 0]), None);
+    }
+}
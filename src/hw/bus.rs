@@ -1,13 +1,39 @@
 use crate::hw::vec::ByteSerialized;
 
-use std::fs::File;
-use std::sync::mpsc;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{atomic, mpsc};
+use std::time::{Duration, Instant};
 
-use crustationcpu::{Cpu, CpuCommand, PsxBus};
-use crate::hw::dma::{ChannelLink, Direction, SyncMode};
-use crate::hw::{Bios, Cdrom, Dma, Gpu, JoypadMemorycard, Ram, Spu, Timers};
+use crustationcpu::breakpoints::DebugStop;
+use crustationcpu::state::{Savestate, StateError, StateReader, StateWriter};
+use crustationcpu::{Cpu, CpuCommand, IcacheMode, PsxBus};
+use crate::hw::compat::{Access, UnimplementedLog};
+use crate::hw::status;
+use crate::hw::bios;
+use crate::hw::gpu::Hotkey;
+use crate::hw::postprocess::{FrameBlend, Pass};
+use crate::hw::spu::{SpeedAudio, SAMPLE_CYCLES};
+use crate::hw::exe::{ExeError, ExeHeader};
+use crate::hw::fill::Fill;
+use crate::hw::input::{InputError, InputQueue, PadInput};
+use crate::hw::memctrl::{MemoryControl, Region};
+use crate::hw::memmap::{self, Device};
+use crate::hw::sio::backend::SerialBackend;
+use crate::hw::sio::SerialPort;
+use crate::hw::dma::{Channel, ChannelLink, Direction, DmaActivity, SyncMode, Transfer};
+use crate::hw::{
+    Bios, Cdrom, ColorProfile, Disc, DiscError, Dma, Expansion1, Expansion2, Gpu, GpuProfile,
+    GpuStateHandle, GpuAccuracy, JoypadMemorycard, Mdec, Ram, Rumble, Spu, Timers, VideoFrame,
+    WindowGeometry,
+};
+#[cfg(feature = "gui")]
+use crate::hw::RendererOptions;
+use crate::hw::irq::{InterruptController, Irq};
+use crate::hw::metrics::{Counters, MetricsHandle, MetricsSampler};
+use crate::limiter::FrameLimiter;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 
 use std::cmp::Ordering;
@@ -25,22 +51,96 @@ pub struct Bus {
     pub total_cycles: RefCell<u64>,
 
     ram: RefCell<Ram>,
+    irq: RefCell<InterruptController>,
     bios: RefCell<Bios>,
     io: RefCell<Vec<u8>>,
+    /// Access times, following the memory control registers in `io`
+    memctrl: RefCell<MemoryControl>,
     cdrom: RefCell<Cdrom>,
     dma: RefCell<Dma>,
     spu: RefCell<Spu>,
     gpu: RefCell<Gpu>,
+    mdec: RefCell<Mdec>,
     timers: RefCell<Timers>,
     joy_mc: RefCell<JoypadMemorycard>,
+    sio: RefCell<SerialPort>,
+    exp1: RefCell<Expansion1>,
+    exp2: RefCell<Expansion2>,
 
     events: RefCell<BinaryHeap<PsxEvent>>,
+
+    /// Reused by block DMA transfers, so that they don't allocate
+    dma_words: RefCell<Vec<u32>>,
+
+    unimplemented: RefCell<UnimplementedLog>,
+    /// Of the instruction being run, see `PsxBus::instruction_pc`
+    current_pc: Cell<u32>,
+    dma_activity: RefCell<DmaActivity>,
+    limiter: RefCell<FrameLimiter>,
+
+    /// VBlanks since power on, the number of the current frame
+    frame: RefCell<u64>,
+    /// Pad states for frames to come, see `schedule_input`
+    scheduled_input: RefCell<InputQueue>,
+    /// When set, the CPU is stopped at the next VBlank
+    stop_on_vblank: RefCell<bool>,
+    /// Set by the quit hotkey, for callers that run frame by frame
+    quit_requested: RefCell<bool>,
+    /// Stop the emulation on writes to the BIOS ROM
+    strict_memory: RefCell<bool>,
+    /// The preset the GPU settings come from
+    gpu_accuracy: RefCell<GpuAccuracy>,
+    /// Log the BIOS functions called, with their arguments
+    log_bios: RefCell<bool>,
+    /// Returned by reads of the expansion regions nothing is plugged in
+    open_bus: RefCell<Fill>,
+
+    /// Frames since the status panel was printed, None when it is hidden
+    status_panel: RefCell<Option<u32>>,
+    metrics: RefCell<MetricsSampler>,
+
+    /// Where the save and load state hotkeys write and read the state
+    state_path: RefCell<Option<PathBuf>>,
+    /// Executable started when the BIOS reaches its shell
+    sideload: RefCell<Option<Sideload>>,
+}
+
+/// Where the BIOS jumps to the shell, once the hardware and the kernel are
+/// initialized. Side-loaded executables replace the shell there.
+pub const SHELL_ENTRY: u32 = 0x8003_0000;
+
+/// A PS-X EXE waiting for the BIOS shell, see `Bus::sideload_exe`
+struct Sideload {
+    header: ExeHeader,
+    code: Vec<u8>,
+    args: Vec<String>,
+    /// Stop for the debugger before its first instruction
+    stop: bool,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd)]
 pub enum PsxEventType {
     DeliverCDRomResponse,
     VBlank,
+    CDRomSector,
+    SpuSample,
+    DmaFinish,
+    JoypadTransfer,
+    SerialTick,
+    Timers,
+}
+
+impl PsxEventType {
+    const ALL: [PsxEventType; 8] = [
+        PsxEventType::DeliverCDRomResponse,
+        PsxEventType::VBlank,
+        PsxEventType::CDRomSector,
+        PsxEventType::SpuSample,
+        PsxEventType::DmaFinish,
+        PsxEventType::JoypadTransfer,
+        PsxEventType::SerialTick,
+        PsxEventType::Timers,
+    ];
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -62,29 +162,69 @@ impl PartialOrd for PsxEvent {
     }
 }
 
+impl Default for Bus {
+    fn default() -> Bus {
+        Bus::new()
+    }
+}
+
 impl Bus {
     pub fn new() -> Bus {
         let cpu = RefCell::new(Cpu::new());
         let cpu_tx = cpu.borrow().command_tx.clone();
 
+        let memctrl = MemoryControl::new();
+        let mut io = vec![0; 0x1000 + 8 * 1024];
+        for (addr, value) in memctrl.registers() {
+            io.write::<4>(addr, value);
+        }
+
         Bus {
             total_cycles: RefCell::new(0),
 
             ram: RefCell::new(Ram::new()),
+            irq: RefCell::new(InterruptController::new()),
             bios: RefCell::new(Bios::new()),
-            io: RefCell::new(vec![0; 0x1000 + 8 * 1024]),
+            io: RefCell::new(io),
+            memctrl: RefCell::new(memctrl),
 
             cdrom: RefCell::new(Cdrom::new()),
             dma: RefCell::new(Dma::new()),
             spu: RefCell::new(Spu::new()),
             gpu: RefCell::new(Gpu::new()),
+            mdec: RefCell::new(Mdec::new()),
             timers: RefCell::new(Timers::new()),
             joy_mc: RefCell::new(JoypadMemorycard::new()),
+            sio: RefCell::new(SerialPort::new()),
+            exp1: RefCell::new(Expansion1::new()),
+            exp2: RefCell::new(Expansion2::new()),
 
             cpu,
             cpu_tx,
 
             events: RefCell::new(BinaryHeap::new()),
+
+            dma_words: RefCell::new(vec![]),
+
+            unimplemented: RefCell::new(UnimplementedLog::new()),
+            current_pc: Cell::new(0),
+            dma_activity: RefCell::new(DmaActivity::new()),
+            limiter: RefCell::new(FrameLimiter::new()),
+
+            frame: RefCell::new(0),
+            scheduled_input: RefCell::new(InputQueue::new()),
+            stop_on_vblank: RefCell::new(false),
+            quit_requested: RefCell::new(false),
+            log_bios: RefCell::new(false),
+            strict_memory: RefCell::new(false),
+            gpu_accuracy: RefCell::new(GpuAccuracy::Balanced),
+            open_bus: RefCell::new(Fill::Ones),
+
+            status_panel: RefCell::new(None),
+            metrics: RefCell::new(MetricsSampler::new(Instant::now())),
+
+            state_path: RefCell::new(None),
+            sideload: RefCell::new(None),
         }
     }
 
@@ -96,6 +236,223 @@ impl Bus {
         self.cpu.borrow_mut().run_until(target_pc);
     }
 
+    /// Runs until the next VBlank
+    pub fn run_frame(&self) {
+        *self.stop_on_vblank.borrow_mut() = true;
+        self.cpu.borrow_mut().run();
+        *self.stop_on_vblank.borrow_mut() = false;
+    }
+
+    /// The number of the current frame, counting VBlanks since power on
+    pub fn frame(&self) -> u64 {
+        *self.frame.borrow()
+    }
+
+    /// Sets the pad to `input` at the start of `frame`, like the window
+    /// does with the keyboard, and until something else changes it. The
+    /// frame must not have started yet: see `frame`.
+    pub fn schedule_input(&self, frame: u64, input: PadInput) -> Result<(), InputError> {
+        self.scheduled_input.borrow_mut().schedule(frame, self.frame(), input)
+    }
+
+    /// Drops the inputs scheduled and not applied yet
+    pub fn clear_scheduled_input(&self) {
+        self.scheduled_input.borrow_mut().clear();
+    }
+
+    /// Whether the window was closed or the quit hotkey pressed. `run_frame`
+    /// returns at the next VBlank either way.
+    pub fn quit_requested(&self) -> bool {
+        *self.quit_requested.borrow()
+    }
+
+    /// Moves BIOS console output not read yet into `buf`, see
+    /// `crustationcpu::tty`. Returns the number of bytes copied.
+    pub fn read_tty(&self, buf: &mut [u8]) -> usize {
+        self.cpu.borrow_mut().tty.read(buf)
+    }
+
+    /// Reads a byte of main RAM, bypassing timings and the CPU. Returns None
+    /// if `addr` is not in the RAM window.
+    pub fn peek_ram(&self, addr: u32) -> Option<u8> {
+        match Bus::strip_region(addr) {
+            addr @ 0x0000_0000..=0x007f_ffff => Some(self.ram.borrow_mut().read::<1>(addr) as u8),
+            _ => None,
+        }
+    }
+
+    /// Copies the physical RAM from `addr` to `buf`, wrapping around at its
+    /// end. Bypasses timings, the CPU and RAM_SIZE.
+    pub fn peek_ram_slice(&self, addr: u32, buf: &mut [u8]) {
+        self.ram.borrow().read_slice(addr, buf);
+    }
+
+    /// Reads a word of main RAM, of the BIOS or of the EXP1 ROM, bypassing
+    /// timings and the CPU. Returns None elsewhere.
+    pub fn peek_word(&self, addr: u32) -> Option<u32> {
+        match Bus::strip_region(addr) & !3 {
+            addr @ 0x0000_0000..=0x007f_ffff => Some(self.ram.borrow_mut().read::<4>(addr)),
+            addr @ 0x1f00_0000..=0x1f7f_ffff => self.read_exp1::<4>(addr - 0x1f00_0000),
+            addr @ 0x1fc0_0000..=0x1fc7_ffff => {
+                Some(self.bios.borrow_mut().read::<4>(addr & 0xf_ffff))
+            }
+            _ => None,
+        }
+    }
+
+    /// The regions of `memmap::MEMORY_MAP`, one per line, with the layout
+    /// currently set by RAM_SIZE and the memory control registers
+    pub fn memory_map(&self) -> String {
+        let mut out = String::new();
+        for mapping in &memmap::MEMORY_MAP {
+            let notes = match mapping.device {
+                Device::Ram => {
+                    format!("{} ({:08x})", mapping.mirrors, self.ram.borrow().ram_size())
+                }
+                Device::Exp1 => format!(
+                    "{} ({}, {} ROM)",
+                    mapping.mirrors,
+                    size_text(self.memctrl.borrow().size(Region::Exp1)),
+                    size_text(self.exp1.borrow().rom_size() as u32)
+                ),
+                _ => mapping.mirrors.to_string(),
+            };
+            let line = format!(
+                "{:08x}-{:08x} {:>8}  {:<18} {}",
+                mapping.start,
+                mapping.end,
+                size_text(mapping.end - mapping.start + 1),
+                mapping.name,
+                notes
+            );
+            out += line.trim_end();
+            out += "\n";
+        }
+        out
+    }
+
+    /// Why the last run stopped for the debugger, if it did
+    pub fn take_debug_stop(&self) -> Option<DebugStop> {
+        self.cpu.borrow_mut().take_debug_stop()
+    }
+
+    /// Writes a byte of main RAM, bypassing timings and the CPU. Returns
+    /// false if `addr` is not in the RAM window.
+    pub fn poke_ram(&self, addr: u32, value: u8) -> bool {
+        match Bus::strip_region(addr) {
+            addr @ 0x0000_0000..=0x007f_ffff => {
+                self.ram.borrow_mut().write::<1>(addr, value as u32);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Last BIOS boot progress code, from the POST register
+    pub fn post_code(&self) -> u8 {
+        self.exp2.borrow().post()
+    }
+
+    /// Horizontal blanks since power on, a clock source of timer 1
+    pub fn hblanks(&self) -> u64 {
+        self.gpu.borrow().hblanks()
+    }
+
+    /// Pixels output by the GPU in `cycles`, a clock source of timer 0
+    pub fn dots(&self, cycles: u64) -> u64 {
+        self.gpu.borrow().dots(cycles)
+    }
+
+    /// CPU cycles until `n` more horizontal blanks, see `Gpu::hblank_cycles`
+    pub fn hblank_cycles(&self, n: u64) -> u64 {
+        self.gpu.borrow().hblank_cycles(n)
+    }
+
+    /// CPU cycles until `n` more pixels, see `Gpu::dot_cycles`
+    pub fn dot_cycles(&self, cycles: u64, n: u64) -> u64 {
+        self.gpu.borrow().dot_cycles(cycles, n)
+    }
+
+    /// Boot mode switches of dev boards, 0xff (the default) for none
+    pub fn set_dip_switches(&self, value: u8) {
+        self.exp2.borrow_mut().set_dip_switches(value);
+    }
+
+    /// Sets the state of the buttons of the digital pad, active low
+    pub fn set_buttons(&self, buttons: u16) {
+        self.joy_mc.borrow_mut().set_buttons(buttons);
+    }
+
+    /// Sets the sticks of the pad: right X, right Y, left X and left Y,
+    /// 0x80 at the center
+    pub fn set_axes(&self, axes: [u8; 4]) {
+        self.joy_mc.borrow_mut().set_axes(axes);
+    }
+
+    /// The state of the pad the game reads now
+    pub fn pad_input(&self) -> PadInput {
+        let joy_mc = self.joy_mc.borrow();
+        PadInput {
+            buttons: joy_mc.buttons(),
+            axes: joy_mc.axes(),
+        }
+    }
+
+    /// Whether stores to code held in the I-Cache are seen by the CPU. Fast
+    /// by default: only self-modifying code that skips the cache flush
+    /// needs it.
+    pub fn set_icache_mode(&self, mode: IcacheMode) {
+        self.cpu.borrow_mut().icache_mode = mode;
+    }
+
+    /// Switches the pad to analog mode, like its Analog button. Games that
+    /// know about it can also switch it themselves.
+    pub fn set_analog(&self, analog: bool) {
+        self.joy_mc.borrow_mut().set_analog(analog);
+    }
+
+    /// Called every time the game changes the rumble motors of the pad.
+    /// Controllers in the window rumble on their own.
+    pub fn set_rumble_handler(&self, handler: Option<Box<dyn FnMut(Rumble)>>) {
+        self.joy_mc.borrow_mut().set_rumble_handler(handler);
+    }
+
+    /// What is plugged into the serial port, nothing by default
+    pub fn set_serial_backend(&self, backend: Box<dyn SerialBackend>) {
+        self.sio.borrow_mut().set_backend(backend);
+    }
+
+    /// File used by the save (F5) and load (F7) state hotkeys. Without one
+    /// they do nothing.
+    pub fn set_state_path(&self, path: Option<PathBuf>) {
+        *self.state_path.borrow_mut() = path;
+    }
+
+    /// Sets the emulation speed in percent of the real hardware (e.g. 50 for
+    /// slow motion, 200 for fast forward). None runs unthrottled.
+    pub fn set_speed(&self, percent: Option<u32>) {
+        self.limiter.borrow_mut().set_speed(percent);
+        self.update_audio_speed();
+    }
+
+    /// Speed while fast forwarding with the hotkey, None (the default) is
+    /// unlimited
+    pub fn set_fast_forward_speed(&self, percent: Option<u32>) {
+        self.limiter.borrow_mut().set_fast_forward_speed(percent);
+        self.update_audio_speed();
+    }
+
+    /// What the sound does away from 100% speed, dropped samples or gaps
+    /// by default
+    pub fn set_speed_audio(&self, speed_audio: SpeedAudio) {
+        self.spu.borrow_mut().set_speed_audio(speed_audio);
+    }
+
+    fn update_audio_speed(&self) {
+        let speed = self.limiter.borrow().speed();
+        self.spu.borrow_mut().set_output_speed(speed);
+    }
+
     // pub fn run_for(&self, cycles: u64) {
     //     let target = *self.total_cycles.borrow() + cycles;
     //     while *self.total_cycles.borrow() < target {
@@ -109,17 +466,168 @@ impl Bus {
         self.cpu.borrow_mut().link(self);
         self.timers.borrow_mut().link(Rc::downgrade(&self_ref));
         self.gpu.borrow_mut().link(Rc::downgrade(&self_ref));
-        self.gpu.borrow_mut().load_renderer();
         self.cdrom.borrow_mut().link(Rc::downgrade(&self_ref));
+        self.joy_mc.borrow_mut().link(Rc::downgrade(&self_ref));
+        self.sio.borrow_mut().link(Rc::downgrade(&self_ref));
+
+        self.add_event(PsxEventType::SpuSample, 0, SAMPLE_CYCLES);
+    }
+
+    /// Opens the output window and the audio device. Without them, the
+    /// emulator runs headless.
+    #[cfg(feature = "gui")]
+    pub fn load_renderer(&self, options: &RendererOptions) {
+        self.gpu.borrow_mut().load_renderer(options);
+
+        #[cfg(feature = "audio")]
+        match self.gpu.borrow().audio_subsystem() {
+            Some(Ok(audio)) => self.spu.borrow_mut().open_output(&audio),
+            Some(Err(err)) => println!("[SPU] No audio: {}", err),
+            None => {}
+        }
+    }
+
+    /// Sends the sound output, stereo samples at 44.1 kHz, to the returned
+    /// channel instead of the audio device. About 90ms are buffered, later
+    /// samples are dropped until they are received.
+    pub fn connect_audio(&self) -> mpsc::Receiver<[i16; 2]> {
+        self.spu.borrow_mut().connect_output()
+    }
+
+    /// Keeps the sound output of the last `frames` frames, for
+    /// `audio_samples`. Unlike `connect_audio`, nothing is ever dropped.
+    pub fn capture_audio(&self, frames: usize) {
+        self.spu.borrow_mut().capture_output(frames);
+    }
+
+    /// Stereo samples at 44.1 kHz produced while `frame` ran, None if it
+    /// hasn't run yet or isn't kept. See `capture_audio`.
+    pub fn audio_samples(&self, frame: u64) -> Option<Vec<[i16; 2]>> {
+        self.spu.borrow().captured_samples(frame).map(<[_]>::to_vec)
+    }
+
+    /// The picture displayed at the last VBlank, see `run_frame`
+    pub fn video_frame(&self) -> VideoFrame {
+        self.gpu.borrow().video_frame()
+    }
+
+    /// None when running headless
+    pub fn window_state(&self) -> Option<(WindowGeometry, bool)> {
+        self.gpu.borrow().window_state()
     }
 
-    pub fn load_rom(&self, path: &str) {
-        let mut file = File::open(path).unwrap();
-        self.bios.borrow_mut().load(&mut file);
+    pub fn set_color_profile(&self, profile: ColorProfile) {
+        self.gpu.borrow_mut().set_color_profile(profile);
+    }
+
+    pub fn set_post_processing(&self, passes: &[Pass]) {
+        self.gpu.borrow_mut().set_post_processing(passes);
+    }
+
+    /// Stop the emulation when a malformed command gets the GPU FIFO stuck
+    pub fn set_break_on_gpu_hang(&self, enabled: bool) {
+        self.gpu.borrow_mut().set_break_on_hang(enabled);
+    }
+
+    /// Stop the emulation on unknown GP0 and GP1 commands, instead of
+    /// ignoring them
+    pub fn set_strict_gpu(&self, strict: bool) {
+        self.gpu.borrow_mut().set_strict_commands(strict);
+    }
+
+    /// Writes to the BIOS ROM are ignored like on hardware. When strict,
+    /// they also drop into the debugger.
+    pub fn set_strict_memory(&self, strict: bool) {
+        *self.strict_memory.borrow_mut() = strict;
+    }
+
+    /// Contents of main RAM at power-on. Call before anything is loaded.
+    pub fn set_ram_fill(&self, fill: Fill) {
+        self.ram.borrow_mut().power_on(fill);
+    }
+
+    /// What reads return where no device answers. All ones by default,
+    /// like the pull-ups of the data bus.
+    pub fn set_open_bus(&self, fill: Fill) {
+        self.ram.borrow_mut().set_open_bus(fill);
+        *self.open_bus.borrow_mut() = fill;
+    }
+
+    /// The GPU state published at every VBlank, which other threads can
+    /// read without locking
+    pub fn gpu_state(&self) -> GpuStateHandle {
+        self.gpu.borrow().state_handle()
+    }
+
+    pub fn set_latch_display(&self, latch: bool) {
+        self.gpu.borrow_mut().set_latch_display(latch);
+    }
+
+    /// Takes effect from the next primitive drawn
+    pub fn set_gpu_accuracy(&self, accuracy: GpuAccuracy) {
+        *self.gpu_accuracy.borrow_mut() = accuracy;
+        self.gpu.borrow_mut().set_accuracy(accuracy.config());
+    }
+
+    pub fn gpu_accuracy(&self) -> GpuAccuracy {
+        *self.gpu_accuracy.borrow()
+    }
+
+    /// Shows the mouse pointer as a light gun crosshair over the display
+    pub fn set_crosshair(&self, shown: bool) {
+        self.gpu.borrow_mut().set_crosshair(shown);
+    }
+
+    /// Shows the pad buttons over the picture
+    pub fn set_input_display(&self, shown: bool) {
+        self.gpu.borrow_mut().set_input_display(shown);
+    }
+
+    /// Can be changed at any time, takes effect on the next frame
+    pub fn set_frame_blend(&self, frame_blend: FrameBlend) {
+        self.gpu.borrow_mut().set_frame_blend(frame_blend);
+    }
+
+    /// Inserts a disc image, a .cue sheet or a single track .bin
+    pub fn load_disc(&self, path: impl AsRef<Path>) -> Result<(), DiscError> {
+        let disc = Disc::open(path.as_ref())?;
+        self.cdrom.borrow_mut().insert_disc(disc);
+        Ok(())
+    }
+
+    /// Sound decoded by the CD-ROM, see `Spu::push_cd_audio`
+    pub fn push_cd_audio(&self, samples: &[[i16; 2]], rate: u32) {
+        self.spu.borrow_mut().push_cd_audio(samples, rate);
+    }
+
+    /// The serial number of the game in the drive, like SLUS_005.94
+    pub fn disc_serial(&self) -> Option<String> {
+        self.cdrom.borrow_mut().disc_serial()
+    }
+
+    /// Loads the BIOS image at `path`
+    pub fn load_rom(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.bios.borrow_mut().load(path.as_ref())
+    }
+
+    /// Plugs a cartridge ROM, like Caetla or Unirom, into the parallel
+    /// port. The BIOS starts it at boot.
+    pub fn load_exp1_rom(&self, path: &Path) -> io::Result<()> {
+        self.exp1.borrow_mut().load(path)
+    }
+
+    /// The last value written to a memory control port
+    #[cfg(feature = "debug-registers")]
+    pub(crate) fn read_io<const S: u32>(&self, addr: u32) -> u32 {
+        self.io.borrow().read::<S>(addr)
     }
 
     pub fn write_io<const S: u32>(&self, addr: u32, value: u32) {
         self.io.borrow_mut().write::<S>(addr as u32, value);
+        if let 0x1008..=0x1023 = addr {
+            let register = self.io.borrow().read::<4>(addr & !3);
+            self.memctrl.borrow_mut().write(addr & !3, register);
+        }
 
         match addr {
             0x1000 => {
@@ -151,6 +659,7 @@ impl Bus {
             }
             0x1060 => {
                 println!("Set RAM_SIZE to {:x}", value);
+                self.ram.borrow_mut().set_ram_size(value);
             }
             0x2041 => {
                 println!("Set POST 7-segments to {:x}", value);
@@ -172,27 +681,33 @@ impl Bus {
     }
 
     pub fn process_events(&self) {
-        let total = self.total_cycles.borrow();
-        let mut events = self.events.borrow_mut();
+        let total = *self.total_cycles.borrow();
 
-        while let Some(ev) = events.peek() {
-            if ev.cycles_target < *total {
-                let ev = events.pop().unwrap();
-
-                self.process_event(ev.kind);
-
-                if ev.repeat > 0 {
-                    events.push(PsxEvent {
-                        kind: ev.kind,
-                        repeat: ev.repeat,
-                        cycles_target: *total + ev.repeat,
-                    });
-                }
-            } else {
+        loop {
+            // Not borrowed while processing: handlers may add or remove events
+            let mut events = self.events.borrow_mut();
+            match events.peek() {
+                Some(ev) if ev.cycles_target < total => {}
                 // The item at the head of the heap isn't ready to be processed
                 // yet. So non of them are.
-                break;
+                _ => break,
             }
+
+            let ev = events.pop().unwrap();
+
+            // Rescheduled first, so that the handler can cancel it. Counted
+            // from when it was due, so that late events don't drift: the SPU
+            // makes exactly one sample every SAMPLE_CYCLES.
+            if ev.repeat > 0 {
+                events.push(PsxEvent {
+                    kind: ev.kind,
+                    repeat: ev.repeat,
+                    cycles_target: ev.cycles_target + ev.repeat,
+                });
+            }
+            drop(events);
+
+            self.process_event(ev.kind);
         }
     }
 
@@ -217,31 +732,306 @@ impl Bus {
         });
     }
 
-    // pub fn remove_event(&self, kind: PsxEventType) {
-    //     let mut events = self.events.borrow_mut();
-
-    //     // If an event of the same type exists, remove it
-    //     // TODO: retain is unstable API. Alternatives?
-    //     events.retain(|ev| ev.kind != kind);
-    // }
+    pub fn remove_event(&self, kind: PsxEventType) {
+        self.events.borrow_mut().retain(|ev| ev.kind != kind);
+    }
 
     pub fn process_event(&self, kind: PsxEventType) {
         match kind {
             PsxEventType::DeliverCDRomResponse => {
                 self.cdrom.borrow_mut().next_response();
             }
+            PsxEventType::CDRomSector => {
+                self.cdrom.borrow_mut().sector_ready();
+            }
+            PsxEventType::JoypadTransfer => {
+                self.joy_mc.borrow_mut().transfer_event();
+            }
+            PsxEventType::SerialTick => {
+                self.sio.borrow_mut().tick();
+            }
+            PsxEventType::Timers => {
+                self.timers.borrow_mut().update();
+            }
+            PsxEventType::SpuSample => {
+                let mut spu = self.spu.borrow_mut();
+                spu.tick();
+                if spu.take_irq() {
+                    self.send_irq(Irq::Spu);
+                }
+            }
+            PsxEventType::DmaFinish => {
+                let mut dma = self.dma.borrow_mut();
+                let next = dma.finish_due(*self.total_cycles.borrow());
+                let irq = dma.take_irq();
+                drop(dma);
+
+                if let Some(next) = next {
+                    self.add_event(PsxEventType::DmaFinish, next, 0);
+                }
+                if irq {
+                    self.send_irq(Irq::Dma);
+                }
+            }
             PsxEventType::VBlank => {
+                let frame_cycles = self.gpu.borrow().frame_cycles();
                 self.gpu.borrow_mut().vblank();
+                self.dma_activity.borrow_mut().end_frame();
+
+                let hotkeys = self.gpu.borrow_mut().take_hotkeys();
+                for hotkey in hotkeys {
+                    self.handle_hotkey(hotkey);
+                }
+                let (buttons, axes) = {
+                    let gpu = self.gpu.borrow();
+                    (gpu.pad_buttons(), gpu.pad_axes())
+                };
+                if let Some(buttons) = buttons {
+                    self.set_buttons(buttons);
+                }
+                if let Some(axes) = axes {
+                    self.set_axes(axes);
+                }
+                self.spu.borrow_mut().end_frame(self.frame());
+                *self.frame.borrow_mut() += 1;
+                let scheduled = self.scheduled_input.borrow_mut().take(self.frame());
+                if let Some(input) = scheduled {
+                    self.set_buttons(input.buttons);
+                    self.set_axes(input.axes);
+                }
+                let rumble = self.joy_mc.borrow().rumble();
+                self.gpu.borrow_mut().set_rumble(rumble);
+                self.refresh_status_panel();
+                self.sample_metrics();
+                self.limiter.borrow_mut().wait(frame_cycles);
+
+                if *self.stop_on_vblank.borrow() {
+                    self.send_command(CpuCommand::Break);
+                }
             }
         }
     }
 
-    pub fn send_irq(&self, irq_num: u32) {
-        if irq_num > 10 {
-            panic!("[BUS] Invalid IRQ number");
+    /// States are saved and loaded by the CPU, between two instructions
+    fn handle_hotkey(&self, hotkey: Hotkey) {
+        let path = self.state_path.borrow().clone();
+
+        let command = match (hotkey, path) {
+            #[cfg(feature = "savestates")]
+            (Hotkey::SaveState, Some(path)) => CpuCommand::SaveState(path),
+            #[cfg(feature = "savestates")]
+            (Hotkey::LoadState, Some(path)) => CpuCommand::LoadState(path),
+            #[cfg(not(feature = "savestates"))]
+            (Hotkey::SaveState | Hotkey::LoadState, _) => {
+                println!("[BUS] Built without savestates, ignoring {:?}", hotkey);
+                return;
+            }
+            (Hotkey::Quit, _) => {
+                *self.quit_requested.borrow_mut() = true;
+                CpuCommand::Break
+            }
+            (Hotkey::StatusPanel, _) => {
+                let shown = self.status_panel.borrow().is_some();
+                self.show_status_panel(!shown);
+                return;
+            }
+            (Hotkey::Calibration, _) => {
+                self.gpu.borrow_mut().toggle_calibration();
+                return;
+            }
+            (Hotkey::GpuAccuracy, _) => {
+                let accuracy = self.gpu_accuracy().next();
+                self.set_gpu_accuracy(accuracy);
+                println!("[GPU] Accuracy: {}", accuracy.name());
+                return;
+            }
+            (Hotkey::InputDisplay, _) => {
+                self.gpu.borrow_mut().toggle_input_display();
+                return;
+            }
+            (Hotkey::FastForward, _) => {
+                let mut limiter = self.limiter.borrow_mut();
+                let enabled = !limiter.fast_forward();
+                limiter.set_fast_forward(enabled);
+                drop(limiter);
+                self.update_audio_speed();
+                return;
+            }
+            #[cfg(feature = "savestates")]
+            (_, None) => {
+                println!("[BUS] No savestate file set, ignoring {:?}", hotkey);
+                return;
+            }
+        };
+
+        self.send_command(command);
+    }
+
+    /// Saves the state to `path` between two instructions. Queued at exit,
+    /// it is written by `shutdown`.
+    pub fn queue_save_state(&self, path: &Path) {
+        #[cfg(feature = "savestates")]
+        self.send_command(CpuCommand::SaveState(path.to_path_buf()));
+        #[cfg(not(feature = "savestates"))]
+        println!("[BUS] Built without savestates, not saving {}", path.display());
+    }
+
+    /// Loads the state in `path` before the next instruction
+    pub fn queue_load_state(&self, path: &Path) {
+        #[cfg(feature = "savestates")]
+        self.send_command(CpuCommand::LoadState(path.to_path_buf()));
+        #[cfg(not(feature = "savestates"))]
+        println!("[BUS] Built without savestates, not loading {}", path.display());
+    }
+
+    /// Queues `command` for the CPU, between two instructions. Once the CPU
+    /// is gone (i.e. during shutdown), it is dropped.
+    pub fn send_command(&self, command: CpuCommand) {
+        if self.cpu_tx.send(command).is_err() {
+            println!("[BUS] Emulation has stopped, dropping a CPU command");
+        }
+    }
+
+    /// Orderly teardown, once emulation has stopped: queued save states are
+    /// written, then the audio device is closed, then the window. SDL audio
+    /// must stop before the context the window owns goes away.
+    pub fn shutdown(&self) {
+        self.cpu.borrow_mut().flush_commands();
+        self.spu.borrow_mut().close_output();
+        self.gpu.borrow_mut().close_renderer();
+    }
+
+    /// Prints the interrupt and DMA registers, decoded, once per second.
+    /// Shows at a glance what an IRQ or DMA deadlock is waiting on.
+    pub fn show_status_panel(&self, shown: bool) {
+        *self.status_panel.borrow_mut() = shown.then_some(0);
+        if shown {
+            print!("{}", self.status());
+        }
+    }
+
+    fn refresh_status_panel(&self) {
+        let mut frames = self.status_panel.borrow_mut();
+        if let Some(frames) = frames.as_mut() {
+            *frames += 1;
+            if *frames == 60 {
+                *frames = 0;
+                print!("{}", self.status());
+                if let Some(latency) = self.gpu.borrow_mut().take_render_latency() {
+                    print!("{}", latency);
+                }
+            }
         }
+    }
+
+    /// Measure the host time spent in the GTE, the GPU and the rasterizer,
+    /// from zero. Costs a clock read per GTE command and GP0 word.
+    pub fn set_profiling(&self, enabled: bool) {
+        self.cpu.borrow_mut().gte_time = enabled.then(Duration::default);
+        self.gpu.borrow_mut().set_profiling(enabled);
+    }
+
+    /// GTE time and GPU profile, when profiling
+    pub fn profile(&self) -> Option<(Duration, GpuProfile)> {
+        let gte = self.cpu.borrow().gte_time?;
+        Some((gte, self.gpu.borrow().profile()?))
+    }
+
+    /// Statistics for monitoring, updated once per second
+    pub fn metrics_handle(&self) -> MetricsHandle {
+        self.metrics.borrow().handle()
+    }
 
-        self.cpu_tx.send(CpuCommand::Irq(irq_num)).unwrap();
+    /// Also writes the statistics to `path` once per second, as JSON or in
+    /// Prometheus format if it ends in .prom
+    pub fn set_metrics_file(&self, path: Option<PathBuf>) {
+        self.metrics.borrow_mut().set_file(path);
+    }
+
+    fn sample_metrics(&self) {
+        let now = Instant::now();
+        let mut metrics = self.metrics.borrow_mut();
+        if !metrics.end_frame(now) {
+            return;
+        }
+
+        // Called while the CPU runs, and is mutably borrowed by it
+        let cpu = unsafe { &*self.cpu.as_ptr() };
+        let audio = self.spu.borrow().audio_stats();
+        metrics.sample(
+            now,
+            Counters {
+                instructions: cpu.instructions(),
+                instruction_counts: cpu.instruction_counts(),
+                cycles: *self.total_cycles.borrow(),
+                dma_words_per_second: self.dma_activity.borrow().rates(),
+                audio_queued: audio.queued.load(atomic::Ordering::Relaxed),
+                audio_dropped: audio.dropped.load(atomic::Ordering::Relaxed),
+                audio_underruns: audio.underruns.load(atomic::Ordering::Relaxed),
+            },
+        );
+
+        let sample = metrics.handle().load();
+        let fast_forward = self.limiter.borrow().fast_forward();
+        self.gpu.borrow_mut().show_rates(sample.fps, sample.speed, fast_forward);
+    }
+
+    fn status(&self) -> String {
+        // Called while the CPU runs, and is mutably borrowed by it
+        let cpu = unsafe { &*self.cpu.as_ptr() };
+        let (i_stat, i_mask) = self.irq.borrow().registers();
+
+        let mut out = String::from("[STATUS] --------------------------------\n");
+        out += &status::interrupts(i_stat, i_mask);
+        out += &status::cop0(cpu.cop0.regs[12], cpu.cop0.regs[13]);
+        out += &self.dma.borrow().status();
+        out += &self.gpu.borrow().state_handle().load().to_string();
+        out
+    }
+
+    pub fn send_irq(&self, irq: Irq) {
+        self.irq.borrow_mut().raise(irq);
+    }
+
+    /// For the sources that hold their line, see `InterruptController::set_line`
+    pub fn set_irq_line(&self, irq: Irq, high: bool) {
+        self.irq.borrow_mut().set_line(irq, high);
+    }
+
+    /// Records an access to a register that isn't emulated yet
+    fn unimplemented(&self, subsystem: &'static str, addr: u32, access: Access) {
+        let pc = self.current_pc.get();
+        self.unimplemented
+            .borrow_mut()
+            .touch(subsystem, addr, access, pc);
+    }
+
+    pub fn print_compatibility_summary(&self) {
+        print!("{}", self.unimplemented.borrow().summary());
+        print!("{}", self.dma_activity.borrow().summary());
+    }
+
+    /// Print every DMA transfer, and a per-channel activity graph every second
+    pub fn set_dma_logging(&self, logging: bool) {
+        self.dma_activity.borrow_mut().set_logging(logging);
+    }
+
+    /// Print the BIOS functions called, by name and with their arguments
+    pub fn set_bios_logging(&self, logging: bool) {
+        *self.log_bios.borrow_mut() = logging;
+    }
+
+    #[inline(always)]
+    fn access_time<const S: u32>(&self, region: Region) -> u64 {
+        self.memctrl.borrow().access_time::<S>(region)
+    }
+
+    /// The EXP1 ROM, within the size set by the delay/size register
+    fn read_exp1<const S: u32>(&self, offset: u32) -> Option<u32> {
+        if offset >= self.memctrl.borrow().size(Region::Exp1) {
+            return None;
+        }
+        self.exp1.borrow().read::<S>(offset)
     }
 
     #[inline(always)]
@@ -259,138 +1049,258 @@ impl PsxBus for Bus {
         self.process_events();
     }
 
+    fn cycles(&self) -> u64 {
+        *self.total_cycles.borrow()
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.irq.borrow().pending()
+    }
+
+    fn instruction_pc(&self, pc: u32) {
+        self.current_pc.set(pc);
+    }
+
+    /// Starts the side-loaded executable, at the shell entry
+    fn pc_hook(&self, cpu: &mut Cpu<Bus>) {
+        if let Some(exe) = self.sideload.borrow_mut().take() {
+            println!("[BUS] Side-loading the executable, entry point {:08x}", exe.header.pc);
+            if let Err(err) = self.place_exe(cpu, &exe.header, &exe.code, &exe.args) {
+                println!("[BUS] Could not side-load the executable: {}", err);
+            }
+            if exe.stop {
+                cpu.stop_for_debugger(DebugStop::Requested);
+            }
+        }
+    }
+
+    fn bios_call(&self, vector: u32, function: u32, args: [u32; 4], ra: u32) {
+        if !*self.log_bios.borrow() {
+            return;
+        }
+        // Strings can be constants of the ROM, so peek at whole words
+        let peek = |addr: u32| self.peek_word(addr).map(|word| (word >> ((addr & 3) * 8)) as u8);
+        if let Some(call) = bios::describe_call(vector, function, &args, ra, peek) {
+            println!("[BIOS] {}", call);
+        }
+    }
+
+    /// The BIOS ROM and the disc are not saved, a state must be loaded with
+    /// the same BIOS and disc it was saved with
+    fn save_state(&self, state: &mut StateWriter) {
+        state.tag(b"BUS ");
+        state.write_u64(*self.total_cycles.borrow());
+        state.write_u64(self.frame());
+
+        let events = self.events.borrow();
+        state.write_u32(events.len() as u32);
+        for event in events.iter() {
+            state.write_u8(event.kind as u8);
+            state.write_u64(event.cycles_target);
+            state.write_u64(event.repeat);
+        }
+        drop(events);
+
+        state.write_bytes(&self.io.borrow());
+
+        self.ram.borrow_mut().save_state(state);
+        self.irq.borrow_mut().save_state(state);
+        self.cdrom.borrow_mut().save_state(state);
+        self.dma.borrow_mut().save_state(state);
+        self.spu.borrow_mut().save_state(state);
+        self.gpu.borrow_mut().save_state(state);
+        self.mdec.borrow_mut().save_state(state);
+        self.timers.borrow_mut().save_state(state);
+        self.joy_mc.borrow_mut().save_state(state);
+        self.sio.borrow_mut().save_state(state);
+        self.exp2.borrow_mut().save_state(state);
+    }
+
+    fn load_state(&self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"BUS ", "bus")?;
+        *self.total_cycles.borrow_mut() = state.read_u64()?;
+        *self.frame.borrow_mut() = state.read_u64()?;
+
+        let mut events = self.events.borrow_mut();
+        events.clear();
+        for _ in 0..state.read_u32()? {
+            let kind = *PsxEventType::ALL
+                .get(state.read_u8()? as usize)
+                .ok_or(StateError::Invalid("event type"))?;
+            events.push(PsxEvent {
+                kind,
+                cycles_target: state.read_u64()?,
+                repeat: state.read_u64()?,
+            });
+        }
+        drop(events);
+
+        state.read_bytes(&mut self.io.borrow_mut())?;
+        for addr in (0x1008..=0x1020).step_by(4) {
+            let register = self.io.borrow().read::<4>(addr);
+            self.memctrl.borrow_mut().write(addr, register);
+        }
+
+        self.ram.borrow_mut().load_state(state)?;
+        self.irq.borrow_mut().load_state(state)?;
+        self.cdrom.borrow_mut().load_state(state)?;
+        self.dma.borrow_mut().load_state(state)?;
+        self.spu.borrow_mut().load_state(state)?;
+        self.gpu.borrow_mut().load_state(state)?;
+        self.mdec.borrow_mut().load_state(state)?;
+        self.timers.borrow_mut().load_state(state)?;
+        self.joy_mc.borrow_mut().load_state(state)?;
+        self.sio.borrow_mut().load_state(state)?;
+        self.exp2.borrow_mut().load_state(state)
+    }
+
     fn read<const S: u32>(&self, addr: u32) -> u32 {
         let addr = Bus::strip_region(addr);
+        let Some(mapping) = memmap::decode(addr) else {
+            panic!("Read in memory hole at {:08x}", addr);
+        };
+        let offset = addr - mapping.start;
 
-        match addr {
-            0x0000_0000..=0x001f_ffff => {
+        match mapping.device {
+            Device::Ram => {
                 self.add_cycles(4);
                 self.ram.borrow_mut().read::<S>(addr)
             }
-            0x1f00_0000..=0x1f7f_ffff => {
-                self.add_cycles(6 * S as u64);
-                0xffffffff
+            Device::Exp1 => {
+                self.add_cycles(self.access_time::<S>(Region::Exp1));
+                self.read_exp1::<S>(offset)
+                    .unwrap_or_else(|| self.open_bus.borrow().word(addr))
+            }
+            Device::MemControl => {
+                panic!("Read of the write-only memory control at {:08x}", addr);
+            }
+            Device::JoyMc => {
+                self.add_cycles(2);
+                self.joy_mc.borrow_mut().read::<S>(offset)
             }
-            0x1f80_1040..=0x1f80_104f => {
+            Device::Sio => {
                 self.add_cycles(2);
-                self.joy_mc.borrow_mut().read::<S>(addr - 0x1f80_1040)
+                self.sio.borrow_mut().read::<S>(offset)
             }
-            0x1f80_1050..=0x1f80_105f => {
-                // SIO
+            Device::RamSize => {
                 self.add_cycles(2);
-                0
+                self.ram.borrow().ram_size()
             }
-            0x1f80_1060 => {
-                // RAM SIZE
+            Device::Irq => {
                 self.add_cycles(2);
-                0
+                self.irq.borrow_mut().read::<S>(offset)
             }
-            0x1f80_1080..=0x1f80_10f4 => {
+            Device::Dma => {
                 self.add_cycles(2);
-                self.dma.borrow_mut().read::<S>(addr - 0x1f80_1080)
+                self.dma.borrow_mut().read::<S>(offset)
             }
-            0x1f80_1100..=0x1f80_112f => {
+            Device::Timers => {
                 self.add_cycles(2);
-                self.timers.borrow_mut().read::<S>(addr - 0x1f80_1100)
+                self.timers.borrow_mut().read::<S>(offset)
             }
-            0x1f80_1800..=0x1f80_1803 => {
-                self.add_cycles(6 * S as u64 + 1);
-                self.cdrom.borrow_mut().read::<S>(addr - 0x1f80_1800)
+            Device::Cdrom => {
+                self.add_cycles(self.access_time::<S>(Region::Cdrom));
+                self.cdrom.borrow_mut().read::<S>(offset)
             }
-            0x1f80_1810..=0x1f80_1814 => {
+            Device::Gpu => {
                 self.add_cycles(2);
-                self.gpu.borrow_mut().read::<S>(addr - 0x1f80_1810)
+                self.gpu.borrow_mut().read::<S>(offset)
             }
-            0x1f80_1820..=0x1f80_1824 => {
-                // MDEC
+            Device::Mdec => {
                 self.add_cycles(2);
-                0
+                self.mdec.borrow_mut().read::<S>(offset)
             }
-            0x1f80_1c00..=0x1f80_1fff => {
-                self.add_cycles(17);
-                self.spu.borrow_mut().read::<S>(addr - 0x1f80_1c00)
+            Device::Spu => {
+                self.add_cycles(self.access_time::<S>(Region::Spu));
+                self.spu.borrow_mut().read::<S>(offset)
             }
-            0x1f80_2000..=0x1f80_2080 => {
+            Device::Exp2 => {
                 // EXP2 has some weeeeeird timings
                 // 10 cycles for 1 byte
                 // 25 for 2 bytes
                 // 55 for 4 bytes
+                // That's shorter than what its delay register gives (14, 28
+                // and 56 with the BIOS settings), so keep the measurements
                 self.add_cycles((15 * S - 5) as u64);
-                0xffffffff
-            }
-            0x1fa0_0000 => {
-                // EXP3 is not sane either
-                // 5 cycles for 1/2 bytes
-                // 9 cycles for 4 bytes
-                if S == 4 {
-                    self.add_cycles(9);
-                } else {
-                    self.add_cycles(5);
-                }
-
-                0xffffffff
+                self.exp2.borrow_mut().read::<S>(offset)
             }
-            0x1fc0_0000..=0x1fc8_0000 => {
-                (*self.total_cycles.borrow_mut()) += 6 * S as u64;
-                self.bios.borrow_mut().read::<S>(addr & 0xf_ffff)
+            Device::Exp3 => {
+                // 5 cycles for 1/2 bytes, 9 for 4 with the BIOS settings
+                self.add_cycles(self.access_time::<S>(Region::Exp3));
+                self.open_bus.borrow().word(addr)
             }
-            _ => {
-                panic!("Read in memory hole at {:08x}", addr);
+            Device::Bios => {
+                self.add_cycles(self.access_time::<S>(Region::Bios));
+                self.bios.borrow_mut().read::<S>(offset)
             }
         }
     }
 
     fn write<const S: u32>(&self, addr: u32, value: u32) {
-        match addr {
-            0x0000_0000..=0x0020_0000 => {
+        let Some(mapping) = memmap::decode(addr) else {
+            panic!("Cannot write value {:x} at {:x}", value, addr);
+        };
+        let offset = addr - mapping.start;
+
+        match mapping.device {
+            Device::Ram => {
                 self.ram.borrow_mut().write::<S>(addr, value);
             }
-            0x1f80_1040..=0x1f80_104f => {
-                self.joy_mc
-                    .borrow_mut()
-                    .write::<S>(addr - 0x1f80_1040, value);
+            Device::Exp1 => {
+                self.exp1.borrow_mut().write::<S>(offset, value);
+            }
+            Device::JoyMc => {
+                self.joy_mc.borrow_mut().write::<S>(offset, value);
+            }
+            Device::Sio => {
+                self.sio.borrow_mut().write::<S>(offset, value);
             }
-            0x1f80_1050..=0x1f80_105f => {
-                // SIO: TODO
+            Device::Irq => {
+                self.irq.borrow_mut().write::<S>(offset, value);
             }
-            0x1f80_1080..=0x1f80_10f4 => {
-                self.dma.borrow_mut().write::<S>(addr - 0x1f80_1080, value);
+            Device::Dma => {
+                self.dma.borrow_mut().write::<S>(offset, value);
                 self.handle_dma_write();
             }
-            0x1f80_1100..=0x1f80_112f => {
-                self.timers
-                    .borrow_mut()
-                    .write::<S>(addr - 0x1f80_1100, value);
+            Device::Timers => {
+                self.timers.borrow_mut().write::<S>(offset, value);
             }
-            0x1f80_1800..=0x1f80_1803 => {
-                self.cdrom
-                    .borrow_mut()
-                    .write::<S>(addr - 0x1f80_1800, value);
+            Device::Cdrom => {
+                self.cdrom.borrow_mut().write::<S>(offset, value);
             }
-            0x1f80_1810..=0x1f80_1814 => {
-                self.gpu.borrow_mut().write::<S>(addr - 0x1f80_1810, value);
+            Device::Gpu => {
+                self.gpu.borrow_mut().write::<S>(offset, value);
             }
-            0x1f80_1820..=0x1f80_1824 => {
-                // MDEC: TODO
+            Device::Mdec => {
+                self.mdec.borrow_mut().write::<S>(offset, value);
+                // Output may be waiting for the data written by the CPU
+                self.handle_dma_write();
             }
-            0x1f80_1c00..=0x1f80_1fff => {
-                self.spu.borrow_mut().write::<S>(addr - 0x1f80_1c00, value);
+            Device::Spu => {
+                if (0x1f80_1dc0..=0x1f80_1dff).contains(&addr) {
+                    self.unimplemented("SPU reverb", addr, Access::Write);
+                }
+
+                let mut spu = self.spu.borrow_mut();
+                spu.write::<S>(offset, value);
+                if spu.take_irq() {
+                    self.send_irq(Irq::Spu);
+                }
             }
-            0x1f80_2000..=0x1f80_207f => {
-                // EXP2: ignore
-                // However at 2041, there's the POST 7seg display
+            Device::Exp2 => {
+                self.exp2.borrow_mut().write::<S>(offset, value);
             }
-            0x1f80_1000..=0x1f80_1020 | 0x1f80_1060 => {
+            Device::MemControl | Device::RamSize => {
                 self.write_io::<S>(addr & 0xffff, value);
             }
-            0x1fa0_0000 => {
+            Device::Exp3 => {
                 // EXP3: ignore
             }
-            0x1fc0_0000..=0x1fc8_0000 => {
-                // Ignore writes to the ROM
-            }
-            _ => {
-                panic!("Cannot write value {:x} at {:x}", value, addr);
+            Device::Bios => {
+                self.bios.borrow_mut().write::<S>(offset, value);
+                if *self.strict_memory.borrow() {
+                    self.send_command(CpuCommand::Debug);
+                }
             }
         }
     }
@@ -398,55 +1308,77 @@ impl PsxBus for Bus {
 
 impl Bus {
     fn handle_dma_write(&self) {
-        if let Some(active_channel) = self.dma.borrow_mut().active_channel() {
+        // A transfer can let another one start, e.g. MDEC input lets the
+        // output go
+        while let Some((n, cycles_left)) = self.run_dma() {
+            let mut dma = self.dma.borrow_mut();
+            match cycles_left {
+                0 => dma.transfer_complete(n),
+                _ => {
+                    dma.finish_at(n, *self.total_cycles.borrow() + cycles_left);
+                    let next = dma.finish_due(*self.total_cycles.borrow());
+                    drop(dma);
+                    self.add_event(PsxEventType::DmaFinish, next.unwrap(), 0);
+                }
+            }
+        }
+
+        let mut dma = self.dma.borrow_mut();
+        if dma.take_irq() {
+            self.send_irq(Irq::Dma);
+        }
+    }
+
+    /// Runs the active DMA transfer, if any. Returns its channel number and
+    /// the cycles left before it ends, 0 if it's over.
+    fn run_dma(&self) -> Option<(u32, u64)> {
+        // MDEC output can only be read once decoded
+        let mdec_output = self.mdec.borrow().output_len() as u32;
+        let ready = |channel: &Channel| {
+            let (blocks, block_size) = channel.transfer_size();
+            channel.link() != ChannelLink::MdecOut || blocks * block_size <= mdec_output
+        };
+
+        if let Some(active_channel) = self.dma.borrow_mut().active_channel(ready) {
             let step = active_channel.step();
             let mut addr = active_channel.base();
 
             let (blocks, block_size) = active_channel.transfer_size();
 
-            match active_channel.sync_mode() {
+            let words = match active_channel.sync_mode() {
                 SyncMode::Immediate => match active_channel.link() {
                     ChannelLink::Otc => {
-                        let mut remaining_words = block_size;
-                        // println!("[DMA6] OTC -> RAM @ 0x{:08x}, block, count: 0x{:04x}\n", addr, remaining_words);
-                        while remaining_words > 0 {
-                            match active_channel.direction() {
-                                Direction::FromRam => {
-                                    panic!("Cannot OTC from RAM");
-                                }
-                                Direction::ToRam => {
-                                    let word = match remaining_words {
-                                        1 => 0xff_ffff,
-                                        _ => addr.wrapping_add(step as u32) & 0x1f_fffc,
-                                    };
-                                    self.ram.borrow_mut().write::<4>(addr, word);
-                                }
-                            }
-                            addr = addr.wrapping_add(step as u32) & 0x1f_fffc;
-                            remaining_words -= 1;
+                        if active_channel.direction() == Direction::FromRam {
+                            panic!("Cannot OTC from RAM");
                         }
-                        active_channel.done();
-                        // if let Some(d) = &self.debug_tx {
-                        //     d.send(true);
-                        // }
+
+                        // Each entry points to the previous one, the first
+                        // written ends the list
+                        let mut words = self.dma_words.borrow_mut();
+                        words.clear();
+                        if block_size > 0 {
+                            words.extend((1..block_size).map(|n| {
+                                addr.wrapping_add((step as u32).wrapping_mul(n)) & 0x1f_fffc
+                            }));
+                            words.push(0xff_ffff);
+                        }
+                        self.ram.borrow_mut().dma_write_words(addr, step, &words);
+                        block_size
                     }
                     ChannelLink::Cdrom => {
-                        let mut remaining_words = block_size * blocks;
+                        if active_channel.direction() == Direction::FromRam {
+                            panic!("Writing to CDROM? Not happening");
+                        }
+
+                        let mut words = self.dma_words.borrow_mut();
+                        words.resize((blocks * block_size) as usize, 0);
                         let mut cdrom = self.cdrom.borrow_mut();
-                        while remaining_words > 0 {
-                            match active_channel.direction() {
-                                Direction::ToRam => {
-                                    let value = cdrom.read::<1>(2) | cdrom.read::<1>(2) << 8 | cdrom.read::<1>(2) << 16 | cdrom.read::<1>(2) << 24;
-                                    self.ram.borrow_mut().write::<4>(addr, value);
-                                    addr = addr.wrapping_add(4);
-                                    remaining_words -= 1;
-                                }
-                                Direction::FromRam => {
-                                    panic!("Writing to CDROM? Not happening");
-                                }
-                            }
+                        for word in words.iter_mut() {
+                            let bytes = [(); 4].map(|_| cdrom.read::<1>(2) as u8);
+                            *word = u32::from_le_bytes(bytes);
                         }
-                        active_channel.done();
+                        self.ram.borrow_mut().dma_write_words(addr, 4, &words);
+                        block_size * blocks
                     }
                     _ => {
                         panic!("Cannot handle link {:?}", active_channel.link());
@@ -455,34 +1387,29 @@ impl Bus {
                 SyncMode::LinkedList => {
                     match active_channel.link() {
                         ChannelLink::Gpu => {
+                            if active_channel.direction() == Direction::ToRam {
+                                panic!("Cannot DMA2-GPU to ram");
+                            }
+
+                            let ram = self.ram.borrow();
+                            let mut gpu = self.gpu.borrow_mut();
+                            // A node holds at most 255 words
+                            let mut packet = [0; 255];
+                            let mut total = 0;
                             loop {
-                                match active_channel.direction() {
-                                    Direction::FromRam => {
-                                        let header = self.ram.borrow_mut().read::<4>(addr);
-                                        let word_count = header >> 24;
-                 
-                                        // if word_count > 0 {
-                                        //     println!("[DMA2] GPU <- RAM @ 0x{:08x}, count: {}, nextAddr: 0x{:08x}",
-                                        //     addr, word_count, header);
-                                        // }
-                 
-                                        for _ in 0..word_count {
-                                            addr = addr.wrapping_add(step as u32);
-                                            let cmd = self.ram.borrow_mut().read::<4>(addr);
-                                            self.gpu.borrow_mut().process_gp0(cmd);
-                                        }
-
-                                        addr = header & 0xffffff;
-                                        if addr == 0xffffff {
-                                            break;
-                                        }
-                                    }
-                                    Direction::ToRam => {
-                                        panic!("Cannot DMA2-GPU to ram");
-                                    }
+                                let header = ram.dma_read(addr);
+                                let words = &mut packet[..(header >> 24) as usize];
+                                ram.dma_read_words(addr.wrapping_add(step as u32), step, words);
+                                gpu.dma_write(words);
+                                // Headers included
+                                total += words.len() as u32 + 1;
+
+                                addr = header & 0xff_ffff;
+                                if addr == 0xff_ffff {
+                                    break;
                                 }
                             }
-                            active_channel.done();
+                            total
                         }
                         _ => {
                             panic!("Linked list is for gpu only");
@@ -491,19 +1418,54 @@ impl Bus {
                 }
                 SyncMode::Sync => match active_channel.link() {
                     ChannelLink::Gpu => {
-                        for _ in 0..(blocks * block_size) as usize {
-                            match active_channel.direction() {
-                                Direction::FromRam => {
-                                    let value = self.ram.borrow_mut().read::<4>(addr);
-                                    self.gpu.borrow_mut().process_gp0(value);
-                                    addr = addr.wrapping_add(step as u32);
-                                }
-                                Direction::ToRam => {
-                                    panic!("Cannot DMA2-GPU to ram");
-                                }
+                        let mut words = self.dma_words.borrow_mut();
+                        words.resize((blocks * block_size) as usize, 0);
+                        match active_channel.direction() {
+                            Direction::FromRam => {
+                                self.ram.borrow().dma_read_words(addr, step, &mut words);
+                                self.gpu.borrow_mut().dma_write(&words);
+                            }
+                            Direction::ToRam => {
+                                self.gpu.borrow_mut().dma_read(&mut words);
+                                self.ram.borrow_mut().dma_write_words(addr, step, &words);
+                            }
+                        }
+                        blocks * block_size
+                    }
+                    ChannelLink::Spu => {
+                        let mut words = self.dma_words.borrow_mut();
+                        words.resize((blocks * block_size) as usize, 0);
+                        let mut spu = self.spu.borrow_mut();
+                        match active_channel.direction() {
+                            Direction::FromRam => {
+                                self.ram.borrow().dma_read_words(addr, step, &mut words);
+                                spu.dma_write(&words);
                             }
+                            Direction::ToRam => {
+                                spu.dma_read(&mut words);
+                                self.ram.borrow_mut().dma_write_words(addr, step, &words);
+                            }
+                        }
+                        if spu.take_irq() {
+                            self.send_irq(Irq::Spu);
                         }
-                        active_channel.done();
+                        blocks * block_size
+                    }
+                    ChannelLink::MdecIn | ChannelLink::MdecOut => {
+                        let mut words = self.dma_words.borrow_mut();
+                        words.resize((blocks * block_size) as usize, 0);
+                        let mut mdec = self.mdec.borrow_mut();
+                        match active_channel.direction() {
+                            Direction::FromRam => {
+                                self.ram.borrow().dma_read_words(addr, step, &mut words);
+                                mdec.dma_write(&words);
+                            }
+                            Direction::ToRam => {
+                                mdec.dma_read(&mut words);
+                                self.ram.borrow_mut().dma_write_words(addr, step, &words);
+                            }
+                        }
+                        blocks * block_size
                     }
                     _ => {
                         panic!("Linked list is for gpu only");
@@ -511,67 +1473,596 @@ impl Bus {
                 },
                 _ => {
                     println!("Unhandled sync mode {:?}", active_channel.sync_mode());
-                    active_channel.done();
+                    0
                 }
             };
+
+            let cycles = active_channel.transfer_cycles(words);
+            self.dma_activity.borrow_mut().record(&Transfer {
+                link: active_channel.link(),
+                direction: active_channel.direction(),
+                sync_mode: active_channel.sync_mode(),
+                base: active_channel.base(),
+                words,
+                cycle: *self.total_cycles.borrow(),
+                cycles,
+            });
+
+            // The CPU is stopped while the data moves. With chopping it
+            // runs in between, and the transfer ends later.
+            self.add_cycles(cycles);
+            let cpu_windows = active_channel.cpu_window_cycles(words);
+            if cpu_windows == 0 {
+                active_channel.done();
+            }
+
+            Some((active_channel.number(), cpu_windows))
+        } else {
+            None
         }
     }
 
-    pub fn load_exe(&self, path: &str) {
-        use std::io::BufReader;
-        use std::io::Read;
-        use std::io::Seek;
-        use std::mem;
-
-        let mut header = PsxExeHeader::default();
-        let file = File::open(path).unwrap();
-        let mut reader = BufReader::new(file);
+    pub fn load_exe(&self, path: impl AsRef<Path>) -> Result<(), ExeError> {
+        self.load_exe_with_args(path, &[])
+    }
 
-        unsafe {
-            let buffer: &mut [u8] = std::slice::from_raw_parts_mut(
-                &mut header as *mut _ as *mut u8,
-                mem::size_of::<PsxExeHeader>(),
-            );
+    /// Loads a PS-X EXE and points the CPU to its entry point right away.
+    /// The hardware is left as it is, see `sideload_exe` to have the BIOS
+    /// initialize it first.
+    pub fn load_exe_with_args(
+        &self,
+        path: impl AsRef<Path>,
+        args: &[&str],
+    ) -> Result<(), ExeError> {
+        let (header, code) = Bus::read_exe(path.as_ref())?;
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        self.place_exe(&mut self.cpu.borrow_mut(), &header, &code, &args)
+    }
 
-            reader.read_exact(buffer).unwrap();
-        }
+    /// Runs a PS-X EXE in place of the BIOS shell: it is loaded when the
+    /// BIOS jumps to `SHELL_ENTRY`, as the shell would load it from a disc.
+    /// With `stop`, the CPU then stops for the debugger before its first
+    /// instruction.
+    pub fn sideload_exe(
+        &self,
+        path: impl AsRef<Path>,
+        args: &[&str],
+        stop: bool,
+    ) -> Result<(), ExeError> {
+        let (header, code) = Bus::read_exe(path.as_ref())?;
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        // Refused now rather than when the shell starts
+        header.argument_layout(&args)?;
+        *self.sideload.borrow_mut() = Some(Sideload {
+            header,
+            code,
+            args,
+            stop,
+        });
+        self.cpu.borrow_mut().pc_hook = Some(SHELL_ENTRY);
+        Ok(())
+    }
 
-        reader.seek(std::io::SeekFrom::Start(0x800)).unwrap();
-        let mut code = vec![0_u8; header.size as usize];
-        reader.read_exact(&mut code).unwrap();
+    /// Whether an executable passed to `sideload_exe` is still waiting for
+    /// the BIOS shell
+    pub fn sideload_pending(&self) -> bool {
+        self.sideload.borrow().is_some()
+    }
 
-        let mut addr = header.destination & 0x1f_fffc;
+    fn read_exe(path: &Path) -> Result<(ExeHeader, Vec<u8>), ExeError> {
+        let data = std::fs::read(path)?;
+        let header = ExeHeader::parse(&data)?;
+        let code = header.code(&data)?.to_vec();
+        Ok((header, code))
+    }
 
+    /// Copies the code, clears the memfill area and points the CPU to the
+    /// entry point, with argc in r4 and argv in r5. The arguments are stored
+    /// at the top of the stack.
+    fn place_exe(
+        &self,
+        cpu: &mut Cpu<Bus>,
+        header: &ExeHeader,
+        code: &[u8],
+        args: &[String],
+    ) -> Result<(), ExeError> {
+        let layout = header.argument_layout(args)?;
         let mut ram = self.ram.borrow_mut();
 
-        for b in code.iter() {
-            ram.write::<1>(addr, *b as u32);
-            addr = (addr + 1) & 0x3f_ffff;
+        // Usually the BSS section
+        if header.memfill_size != 0 {
+            ram.fill(header.memfill_address, header.memfill_size, 0);
+        }
+
+        ram.write_slice(header.destination, code);
+        // The shell's code may still be cached
+        cpu.flush_icache();
+
+        let mut string_addr = layout.strings;
+        for (i, arg) in args.iter().enumerate() {
+            ram.write_slice(layout.argv + 4 * i as u32, &string_addr.to_le_bytes());
+            ram.write_slice(string_addr, arg.as_bytes());
+            ram.write_slice(string_addr + arg.len() as u32, &[0]);
+            string_addr += arg.len() as u32 + 1;
         }
 
-        let mut cpu = self.cpu.borrow_mut();
         cpu.pc = header.pc;
         cpu.regs[28] = header.r28;
-        cpu.regs[29] = header.r29_base + header.r29_offset;
-
-        if cpu.regs[29] == 0 {
-            cpu.regs[29] = 0x801f_fff0;
+        cpu.regs[29] = layout.sp;
+        if !args.is_empty() {
+            cpu.regs[4] = args.len() as u32;
+            cpu.regs[5] = layout.argv;
         }
+        Ok(())
     }
 }
 
-#[derive(Debug, Default)]
-#[repr(C)]
-pub struct PsxExeHeader {
-    signature: [u8; 8],
-    zero1: [u8; 8],
-    pc: u32,
-    r28: u32,
-    destination: u32,
-    size: u32,
-    zero2: [u32; 2],
-    memfill_address: u32,
-    memfill_size: u32,
-    r29_base: u32,
-    r29_offset: u32,
+fn size_text(bytes: u32) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=0xf_ffff => format!("{} KiB", bytes / 1024),
+        _ => format!("{} MiB", bytes >> 20),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a PS-X EXE with `code` loaded at 0x80010000
+    fn write_exe(name: &str, signature: &[u8; 8], code: &[u8], memfill: (u32, u32)) -> PathBuf {
+        let mut exe = vec![0; 0x800];
+        exe[0..8].copy_from_slice(signature);
+        exe[0x10..0x14].copy_from_slice(&0x8001_0000_u32.to_le_bytes());
+        exe[0x18..0x1c].copy_from_slice(&0x8001_0000_u32.to_le_bytes());
+        exe[0x1c..0x20].copy_from_slice(&(code.len() as u32).to_le_bytes());
+        exe[0x28..0x2c].copy_from_slice(&memfill.0.to_le_bytes());
+        exe[0x2c..0x30].copy_from_slice(&memfill.1.to_le_bytes());
+        exe.extend_from_slice(code);
+
+        let path = crate::hw::test_file(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, exe).unwrap();
+        path
+    }
+
+    #[test]
+    fn loads_code_clears_memfill_and_passes_arguments() {
+        let bus = Bus::new();
+        let path = write_exe(
+            "load.exe",
+            b"PS-X EXE",
+            &[1, 2, 3, 4],
+            (0x8002_0000, 8),
+        );
+        for addr in 0x2_0000..0x2_0010 {
+            bus.poke_ram(addr, 0xff);
+        }
+
+        bus.load_exe_with_args(&path, &["-v", "file"]).unwrap();
+
+        assert_eq!(bus.peek_ram(0x1_0003), Some(4));
+        assert_eq!(bus.peek_ram(0x2_0007), Some(0));
+        assert_eq!(bus.peek_ram(0x2_0008), Some(0xff));
+
+        let cpu = bus.cpu.borrow();
+        assert_eq!(cpu.pc, 0x8001_0000);
+        assert_eq!(cpu.regs[4], 2);
+
+        let read_u32 = |addr: u32| {
+            (0..4)
+                .map(|i| (bus.peek_ram(addr + i).unwrap() as u32) << (8 * i))
+                .sum::<u32>()
+        };
+        let read_str = |addr: u32| {
+            (addr..)
+                .map(|a| bus.peek_ram(a).unwrap())
+                .take_while(|&c| c != 0)
+                .map(char::from)
+                .collect::<String>()
+        };
+        assert_eq!(read_str(read_u32(cpu.regs[5])), "-v");
+        assert_eq!(read_str(read_u32(cpu.regs[5] + 4)), "file");
+        assert!(cpu.regs[29] < cpu.regs[5]);
+    }
+
+    #[test]
+    fn sideloads_when_the_bios_reaches_the_shell() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+        bus.set_speed(None);
+        bus.load_rom("bios/SCPH1001.BIN").unwrap();
+
+        // J 0x80010000, NOP
+        let code = [0x00, 0x40, 0x00, 0x08, 0, 0, 0, 0];
+        let path = write_exe("sideload.exe", b"PS-X EXE", &code, (0, 0));
+        bus.sideload_exe(&path, &[], true).unwrap();
+        assert!(bus.sideload_pending());
+
+        // Stops for the debugger before the first instruction
+        bus.run();
+        assert_eq!(bus.take_debug_stop(), Some(DebugStop::Requested));
+        assert!(!bus.sideload_pending());
+        assert_eq!(bus.cpu.borrow().pc(), 0x8001_0000);
+        assert_eq!(bus.peek_word(0x8001_0000), Some(0x0800_4000));
+    }
+
+    #[test]
+    fn unimplemented_accesses_name_their_instruction() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+        bus.set_speed(None);
+        bus.load_rom("bios/SCPH1001.BIN").unwrap();
+
+        // lui t0, 0x1f80; sh zero, 0x1dc0(t0); j .; nop
+        let code: Vec<u8> = [0x3c08_1f80u32, 0xa500_1dc0, 0x0800_4002, 0]
+            .iter()
+            .flat_map(|ins| ins.to_le_bytes())
+            .collect();
+        let path = write_exe("unimplemented.exe", b"PS-X EXE", &code, (0, 0));
+        bus.sideload_exe(&path, &[], false).unwrap();
+        bus.run_until(0x8001_0008);
+
+        let summary = bus.unimplemented.borrow().summary();
+        assert!(summary.contains("1f801dc0 Write x1        first at pc 80010004"), "{}", summary);
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let bus = Bus::new();
+        let path = write_exe("bad.exe", b"ELF\0\0\0\0\0", &[], (0, 0));
+
+        let err = bus.load_exe(&path).unwrap_err();
+        assert!(err.to_string().starts_with("not a PS-X EXE"));
+    }
+
+    #[test]
+    fn loads_from_unicode_paths() {
+        let bus = Bus::new();
+        let path = write_exe("ゲーム/démo.exe", b"PS-X EXE", &[1, 2, 3, 4], (0, 0));
+
+        bus.load_exe(&path).unwrap();
+        assert_eq!(bus.peek_ram(0x1_0000), Some(1));
+
+        let missing = path.with_file_name("manquant.exe");
+        assert!(matches!(bus.load_exe(missing), Err(ExeError::Io(_))));
+        assert!(bus.load_rom(path.with_file_name("bios.bin")).is_err());
+    }
+
+    #[test]
+    fn states_resume_where_they_were_saved() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+        bus.set_speed(None);
+        bus.load_rom("bios/SCPH1001.BIN").unwrap();
+        for _ in 0..30 {
+            bus.run_frame();
+        }
+
+        let mut state = vec![];
+        bus.cpu.borrow_mut().save_state(&mut state).unwrap();
+
+        let run = || {
+            for _ in 0..10 {
+                bus.run_frame();
+            }
+            let ram: Vec<u8> = (0..0x20_0000).map(|addr| bus.peek_ram(addr).unwrap()).collect();
+            (bus.cpu.borrow().pc, *bus.total_cycles.borrow(), ram)
+        };
+
+        let first = run();
+        bus.cpu.borrow_mut().load_state(&mut state.as_slice()).unwrap();
+        let second = run();
+
+        assert_eq!(first.0, second.0);
+        assert_eq!(first.1, second.1);
+        assert!(first.2 == second.2, "RAM differs after loading the state");
+    }
+
+    /// An ISO 9660 directory record
+    fn dir_record(name: &[u8], sector: u32, size: u32, is_dir: bool) -> Vec<u8> {
+        let len = 33 + name.len() + (name.len() + 1) % 2;
+        let mut record = vec![0; len];
+        record[0] = len as u8;
+        record[2..6].copy_from_slice(&sector.to_le_bytes());
+        record[6..10].copy_from_slice(&sector.to_be_bytes());
+        record[10..14].copy_from_slice(&size.to_le_bytes());
+        record[14..18].copy_from_slice(&size.to_be_bytes());
+        record[25] = if is_dir { 2 } else { 0 };
+        record[28] = 1;
+        record[31] = 1;
+        record[32] = name.len() as u8;
+        record[33..33 + name.len()].copy_from_slice(name);
+        record
+    }
+
+    /// Writes a disc image with `exe` as its boot executable, and its cue
+    /// sheet. Just enough ISO 9660 for the BIOS to find the files.
+    fn write_disc(name: &str, exe: &[u8]) -> PathBuf {
+        let cnf = b"BOOT = cdrom:\\MAIN.EXE;1\r\nTCB = 4\r\nEVENT = 10\r\nSTACK = 801FFF00\r\n";
+        let exe_sectors = exe.len().div_ceil(2048);
+        let total = 22 + exe_sectors;
+        let mut data = vec![0; total * 2048];
+
+        // Primary volume descriptor
+        let pvd = &mut data[16 * 2048..17 * 2048];
+        pvd[0] = 1;
+        pvd[1..6].copy_from_slice(b"CD001");
+        pvd[6] = 1;
+        pvd[8..19].copy_from_slice(b"PLAYSTATION");
+        pvd[80..84].copy_from_slice(&(total as u32).to_le_bytes());
+        pvd[84..88].copy_from_slice(&(total as u32).to_be_bytes());
+        pvd[120..124].copy_from_slice(&[1, 0, 0, 1]);
+        pvd[124..128].copy_from_slice(&[1, 0, 0, 1]);
+        pvd[128..132].copy_from_slice(&[0, 8, 8, 0]);
+        pvd[132..140].copy_from_slice(&[10, 0, 0, 0, 0, 0, 0, 10]);
+        pvd[140..144].copy_from_slice(&18u32.to_le_bytes());
+        pvd[148..152].copy_from_slice(&19u32.to_be_bytes());
+        pvd[156..190].copy_from_slice(&dir_record(&[0], 20, 2048, true));
+        pvd[881] = 1;
+
+        // Terminator, path tables and root directory
+        data[17 * 2048] = 0xff;
+        data[17 * 2048 + 1..17 * 2048 + 7].copy_from_slice(b"CD001\x01");
+        data[18 * 2048..18 * 2048 + 10].copy_from_slice(&[1, 0, 20, 0, 0, 0, 1, 0, 0, 0]);
+        data[19 * 2048..19 * 2048 + 10].copy_from_slice(&[1, 0, 0, 0, 0, 20, 0, 1, 0, 0]);
+        let root = [
+            dir_record(&[0], 20, 2048, true),
+            dir_record(&[1], 20, 2048, true),
+            dir_record(b"SYSTEM.CNF;1", 21, cnf.len() as u32, false),
+            dir_record(b"MAIN.EXE;1", 22, exe.len() as u32, false),
+        ]
+        .concat();
+        data[20 * 2048..20 * 2048 + root.len()].copy_from_slice(&root);
+        data[21 * 2048..21 * 2048 + cnf.len()].copy_from_slice(cnf);
+        data[22 * 2048..22 * 2048 + exe.len()].copy_from_slice(exe);
+
+        // Raw Mode 2 Form 1 sectors, without error correction
+        let bcd = |value: u32| (((value / 10) << 4) | (value % 10)) as u8;
+        let mut image = vec![];
+        for (n, sector) in data.chunks(2048).enumerate() {
+            let position = n as u32 + 150;
+            image.push(0);
+            image.extend_from_slice(&[0xff; 10]);
+            image.push(0);
+            let (m, s, f) = (position / 4500, position / 75 % 60, position % 75);
+            image.extend_from_slice(&[bcd(m), bcd(s), bcd(f), 2]);
+            image.extend_from_slice(&[0, 0, 8, 0, 0, 0, 8, 0]);
+            image.extend_from_slice(sector);
+            image.extend_from_slice(&[0; 280]);
+        }
+
+        std::fs::write(crate::hw::test_file(&format!("{}.bin", name)), image).unwrap();
+        let cue = crate::hw::test_file(&format!("{}.cue", name));
+        let sheet = format!("FILE \"{}.bin\" BINARY\n  TRACK 01 MODE2/2352\n", name);
+        std::fs::write(&cue, sheet + "    INDEX 01 00:00:00\n").unwrap();
+        cue
+    }
+
+    #[test]
+    fn boots_a_disc_through_the_bios() {
+        // lui t0, 0x1234; ori t0, 0x5678; lui t1, 0x8002; sw t0, 0(t1); j .
+        let code: Vec<u8> = [0x3c08_1234u32, 0x3508_5678, 0x3c09_8002, 0xad28_0000, 0x0800_4004, 0]
+            .iter()
+            .flat_map(|ins| ins.to_le_bytes())
+            .collect();
+        let mut exe = vec![0; 0x800];
+        exe[0..8].copy_from_slice(b"PS-X EXE");
+        exe[0x10..0x14].copy_from_slice(&0x8001_0000_u32.to_le_bytes());
+        exe[0x18..0x1c].copy_from_slice(&0x8001_0000_u32.to_le_bytes());
+        exe[0x1c..0x20].copy_from_slice(&0x800_u32.to_le_bytes());
+        exe.extend_from_slice(&code);
+        exe.resize(0x1000, 0);
+
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+        bus.set_speed(None);
+        bus.load_rom("bios/SCPH1001.BIN").unwrap();
+        bus.load_disc(write_disc("boot", &exe)).unwrap();
+
+        let booted = (0..1200).any(|_| {
+            bus.run_frame();
+            bus.peek_ram(0x2_0000) == Some(0x78)
+        });
+        assert!(booted, "the disc did not boot");
+    }
+
+    #[test]
+    fn delay_registers_set_the_access_times() {
+        let bus = Bus::new();
+        let cycles = |bus: &Bus| *bus.total_cycles.borrow();
+
+        bus.read::<4>(0xbfc0_0000);
+        assert_eq!(cycles(&bus), 24);
+
+        // The BIOS ROM on a 16-bit bus
+        bus.write::<4>(0x1f80_1010, 0x0013_1010);
+        bus.read::<4>(0xbfc0_0000);
+        assert_eq!(cycles(&bus), 24 + 8);
+    }
+
+    #[test]
+    fn exp1_roms_are_mapped_in_the_configured_size() {
+        let bus = Bus::new();
+        let path = crate::hw::test_file("exp1.rom");
+        let mut rom = vec![0; 0x100];
+        rom[0x84..0x8c].copy_from_slice(b"Licensed");
+        std::fs::write(&path, &rom).unwrap();
+
+        // Nothing plugged in
+        bus.set_open_bus(Fill::Zeros);
+        assert_eq!(bus.read::<4>(0x1f00_0084), 0);
+
+        bus.load_exp1_rom(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bus.read::<4>(0x1f00_0084), u32::from_le_bytes(*b"Lice"));
+        assert_eq!(bus.peek_word(0x9f00_0088), Some(u32::from_le_bytes(*b"nsed")));
+        // Past the end of the image
+        assert_eq!(bus.read::<1>(0x1f00_0100), 0);
+
+        // An 8-bit bus: a word takes 4 accesses
+        let start = *bus.total_cycles.borrow();
+        bus.read::<4>(0x1f00_0000);
+        assert_eq!(*bus.total_cycles.borrow() - start, 24);
+
+        // A 128-byte region leaves the licence string out
+        bus.write::<4>(0x1f80_1008, 0x0007_243f);
+        assert_eq!(bus.read::<4>(0x1f00_0084), 0);
+        assert_eq!(bus.peek_word(0x1f00_0084), None);
+    }
+
+    #[test]
+    fn otc_dma_links_the_entries_backwards() {
+        let bus = Bus::new();
+        bus.write::<4>(0x1f80_10f0, 0x0f65_4321);
+        bus.write::<4>(0x1f80_10e0, 0x100);
+        bus.write::<4>(0x1f80_10e4, 4);
+        bus.write::<4>(0x1f80_10e8, 0x1100_0002);
+
+        let entries: Vec<u32> = (0..4).map(|n| bus.peek_word(0x100 - 4 * n).unwrap()).collect();
+        assert_eq!(entries, [0xfc, 0xf8, 0xf4, 0xff_ffff]);
+        assert_eq!(bus.peek_word(0xf0), Some(0));
+    }
+
+    #[test]
+    fn memory_map_shows_the_current_layout() {
+        let bus = Bus::new();
+        bus.write::<4>(0x1f80_1008, 0x0007_243f);
+
+        let map = bus.memory_map();
+        assert_eq!(map.lines().count(), memmap::MEMORY_MAP.len());
+        assert!(map.starts_with(
+            "00000000-007fffff    8 MiB  Main RAM           2 MiB, layout set by RAM_SIZE \
+             (00000b88)\n1f000000-1f7fffff    8 MiB  Expansion 1        Size set at 1F801008h \
+             (128 B, 0 B ROM)\n"
+        ));
+        assert!(map.contains("\n1f801810-1f801817      8 B  GPU\n"));
+    }
+
+    #[test]
+    fn reads_the_serial_of_the_disc() {
+        let bus = Bus::new();
+        assert_eq!(bus.disc_serial(), None);
+
+        let cue = write_disc("serial", &[0; 0x800]);
+        bus.load_disc(&cue).unwrap();
+        assert_eq!(bus.disc_serial().as_deref(), Some("MAIN.EXE"));
+    }
+
+    #[test]
+    fn video_timings_clock_timer_1() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+        bus.set_speed(None);
+
+        // Timer 1 counting horizontal blanks
+        bus.write::<4>(0x1f80_1114, 0x100);
+        bus.update_cycles(10 * 2172);
+        assert_eq!(bus.read::<2>(0x1f80_1110), 10);
+
+        // The first frame is NTSC, then switch to PAL
+        bus.write::<4>(0x1f80_1814, 0x0800_0008);
+        bus.update_cycles(571_213 - 10 * 2172);
+        assert_eq!(bus.read::<2>(0x1f80_1110), 263);
+
+        bus.update_cycles(680_581);
+        assert_eq!(bus.read::<2>(0x1f80_1110), 263 + 314);
+    }
+
+    #[test]
+    fn gpustat_follows_the_command_fifo() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+        bus.set_speed(None);
+        let (gp0, gpustat) = (0x1f80_1810, 0x1f80_1814);
+        let status = || bus.read::<4>(gpustat) & 0x1e00_0000;
+
+        // Idle, DMA requests off
+        assert_eq!(status(), 0x1400_0000);
+        // Not ready for a command while one is partially received
+        bus.write::<4>(gp0, 0x0200_0000);
+        assert_eq!(status(), 0x1000_0000);
+
+        // A 256x256 fill keeps the GPU busy, the next words wait
+        bus.write::<4>(gp0, 0);
+        bus.write::<4>(gp0, 0x0100_0100);
+        for page in 0..16 {
+            bus.write::<4>(gp0, 0xe100_0000 | page);
+        }
+        bus.write::<4>(gpustat, 0x0400_0001);
+        assert_eq!(status(), 0);
+        assert_eq!(bus.read::<4>(gpustat) & 0x1ff, 0);
+
+        bus.update_cycles(7_000);
+        assert_eq!(status(), 0x1600_0000);
+        assert_eq!(bus.read::<4>(gpustat) & 0x1ff, 15);
+
+        // VRAM to CPU, requests follow GPUREAD
+        bus.write::<4>(gpustat, 0x0400_0003);
+        for word in [0xc000_0000, 0, 0x0001_0002] {
+            bus.write::<4>(gp0, word);
+        }
+        assert_eq!(status(), 0x1e00_0000);
+        bus.read::<4>(gp0);
+        assert_eq!(status(), 0x1400_0000);
+    }
+
+    #[test]
+    fn scheduled_input_starts_with_its_frame() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+        bus.set_speed(None);
+
+        let input = PadInput {
+            buttons: !0x4000,
+            axes: [0, 0x80, 0xff, 0x80],
+        };
+        assert!(bus.schedule_input(0, input).is_err());
+        bus.schedule_input(2, input).unwrap();
+
+        bus.process_event(PsxEventType::VBlank);
+        assert_eq!(bus.frame(), 1);
+        assert_eq!(bus.pad_input().buttons, 0xffff);
+        bus.process_event(PsxEventType::VBlank);
+        assert_eq!(bus.frame(), 2);
+        assert_eq!(bus.pad_input(), input);
+        assert_eq!(
+            bus.schedule_input(2, input),
+            Err(InputError::Past {
+                frame: 2,
+                current: 2
+            })
+        );
+    }
+
+    #[test]
+    fn stores_to_the_bios_are_ignored() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+
+        // lui t0, 0xbfc0; lui t1, 0x1234; sw t1, 0(t0); sb t1, 3(t0);
+        // lw t2, 0(t0); nop
+        let code = [0x3c08_bfc0, 0x3c09_1234, 0xad09_0000, 0xa109_0003, 0x8d0a_0000, 0];
+        for (i, word) in code.iter().enumerate() {
+            bus.write::<4>(0x1000 + i as u32 * 4, *word);
+        }
+
+        let mut cpu = bus.cpu.borrow_mut();
+        cpu.pc = 0x8000_1000;
+        for _ in 0..code.len() {
+            cpu.step();
+        }
+        assert_eq!(cpu.regs[10], 0);
+        drop(cpu);
+
+        assert_eq!(bus.read::<4>(0x1fc0_0000), 0);
+    }
 }
@@ -0,0 +1,735 @@
+//! Disc images: a .cue sheet with its .bin files, or a lone .bin.
+//!
+//! Positions on the disc are absolute sector numbers, as addressed by the
+//! controller: sector 0 is 00:00:00, and the first track normally starts
+//! after the 2 seconds of lead-in, at 00:02:00 (sector 150).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::hw::cdrom::subchannel::{self, SubchannelQ};
+
+/// Raw sector size, including sync, header and error correction
+pub const SECTOR_SIZE: usize = 2352;
+
+const SECTORS_PER_SECOND: u32 = 75;
+
+/// 00:02:00, where the first track starts
+const LEAD_IN: u32 = 2 * SECTORS_PER_SECOND;
+
+pub fn msf_to_sector(m: u8, s: u8, f: u8) -> u32 {
+    (m as u32 * 60 + s as u32) * SECTORS_PER_SECOND + f as u32
+}
+
+pub fn sector_to_msf(sector: u32) -> (u8, u8, u8) {
+    let f = sector % SECTORS_PER_SECOND;
+    let s = sector / SECTORS_PER_SECOND % 60;
+    let m = sector / SECTORS_PER_SECOND / 60;
+
+    (m as u8, s as u8, f as u8)
+}
+
+pub fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+pub fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+#[derive(Debug)]
+pub enum DiscError {
+    Io(io::Error),
+    /// The cue sheet is malformed. Line number and reason.
+    Cue(usize, &'static str),
+    /// The .sbi or .lsd file is malformed
+    Subchannel(&'static str),
+}
+
+impl fmt::Display for DiscError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscError::Io(err) => write!(f, "{}", err),
+            DiscError::Cue(line, what) => write!(f, "cue sheet line {}: {}", line, what),
+            DiscError::Subchannel(what) => write!(f, "subchannel file: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for DiscError {}
+
+impl From<io::Error> for DiscError {
+    fn from(err: io::Error) -> DiscError {
+        DiscError::Io(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TrackKind {
+    Mode1,
+    Mode2,
+    Audio,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Track {
+    pub number: u8,
+    pub kind: TrackKind,
+    /// Sector of INDEX 00, where the pregap starts. The first track's is
+    /// the lead-in.
+    pub pregap: u32,
+    /// Sector of INDEX 01
+    pub start: u32,
+}
+
+/// Where a sector is, as reported by the Q subchannel
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Location {
+    pub track: u8,
+    /// 0 in the pregap, 1 after
+    pub index: u8,
+    /// Distance to INDEX 01, counting down in the pregap
+    pub relative: u32,
+}
+
+/// Sectors stored contiguously in one of the files. Pregaps that are not in
+/// the files split them in more segments.
+#[derive(Debug, PartialEq)]
+struct Segment {
+    file: usize,
+    /// First sector of the segment in the file
+    offset: u32,
+    /// Disc position of the first sector
+    first: u32,
+    sectors: u32,
+}
+
+#[derive(Debug, PartialEq)]
+struct Layout {
+    files: Vec<String>,
+    segments: Vec<Segment>,
+    tracks: Vec<Track>,
+    end: u32,
+}
+
+pub struct Disc {
+    files: Vec<File>,
+    segments: Vec<Segment>,
+    tracks: Vec<Track>,
+    /// Lead-out, the first sector after the last track
+    end: u32,
+    /// Q subchannels that differ from the ones of the layout, by sector
+    subchannels: HashMap<u32, SubchannelQ>,
+}
+
+impl Disc {
+    pub fn open(path: impl AsRef<Path>) -> Result<Disc, DiscError> {
+        let path = path.as_ref();
+        let is_cue = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"));
+
+        if !is_cue {
+            // A single data track
+            let file = File::open(path)?;
+            let sectors = (file.metadata()?.len() / SECTOR_SIZE as u64) as u32;
+
+            return Ok(Disc {
+                files: vec![file],
+                segments: vec![Segment {
+                    file: 0,
+                    offset: 0,
+                    first: LEAD_IN,
+                    sectors,
+                }],
+                tracks: vec![Track {
+                    number: 1,
+                    kind: TrackKind::Mode2,
+                    pregap: 0,
+                    start: LEAD_IN,
+                }],
+                end: LEAD_IN + sectors,
+                subchannels: subchannel::load(path)?,
+            });
+        }
+
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let cue = decode_cue(std::fs::read(path)?);
+        let layout = parse_cue(&cue, |name| {
+            let len = std::fs::metadata(dir.join(cue_file_path(name)))?.len();
+            Ok((len / SECTOR_SIZE as u64) as u32)
+        })?;
+
+        let files = layout
+            .files
+            .iter()
+            .map(|name| File::open(dir.join(cue_file_path(name))))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Disc {
+            files,
+            segments: layout.segments,
+            tracks: layout.tracks,
+            end: layout.end,
+            subchannels: subchannel::load(path)?,
+        })
+    }
+
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    /// The track and index of `sector`. None in the lead-out.
+    pub fn locate(&self, sector: u32) -> Option<Location> {
+        if sector >= self.end {
+            return None;
+        }
+        let track = self.tracks.iter().rev().find(|track| track.pregap <= sector)?;
+
+        Some(match sector < track.start {
+            true => Location {
+                track: track.number,
+                index: 0,
+                relative: track.start - sector,
+            },
+            false => Location {
+                track: track.number,
+                index: 1,
+                relative: sector - track.start,
+            },
+        })
+    }
+
+    /// The Q subchannel of `sector`, if it's not the one given by its
+    /// location (LibCrypt)
+    pub fn subchannel_q(&self, sector: u32) -> Option<&SubchannelQ> {
+        self.subchannels.get(&sector)
+    }
+
+    /// Reads the sector at disc position `sector`. Pregaps that are not
+    /// stored in the image read as zeroes. Returns false past the end of
+    /// the disc.
+    pub fn read_sector(&mut self, sector: u32, buffer: &mut [u8]) -> io::Result<bool> {
+        if sector >= self.end {
+            return Ok(false);
+        }
+
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| (segment.first..segment.first + segment.sectors).contains(&sector));
+
+        match segment {
+            Some(segment) => {
+                let offset = (segment.offset + sector - segment.first) as u64;
+                let file = &mut self.files[segment.file];
+                file.seek(SeekFrom::Start(offset * SECTOR_SIZE as u64))?;
+                file.read_exact(&mut buffer[..SECTOR_SIZE])?;
+            }
+            None => {
+                buffer[..SECTOR_SIZE].fill(0);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// The 2048 bytes of data of a Mode 1 or Mode 2 Form 1 sector
+    fn read_data(&mut self, sector: u32) -> Option<Vec<u8>> {
+        let mut raw = [0; SECTOR_SIZE];
+        if !self.read_sector(sector, &mut raw).ok()? {
+            return None;
+        }
+        let start = if raw[15] == 1 { 16 } else { 24 };
+        Some(raw[start..start + 2048].to_vec())
+    }
+
+    /// The name of the boot executable in SYSTEM.CNF, like SLUS_005.94: the
+    /// serial number of the game. None on discs that don't have one.
+    pub fn serial(&mut self) -> Option<String> {
+        // ISO 9660, the primary volume descriptor holds the root directory
+        let pvd = self.read_data(LEAD_IN + 16)?;
+        if &pvd[1..6] != b"CD001" {
+            return None;
+        }
+        let extent = |record: &[u8]| {
+            let sector = u32::from_le_bytes(record[2..6].try_into().unwrap());
+            let size = u32::from_le_bytes(record[10..14].try_into().unwrap());
+            (sector, size)
+        };
+        let (root, root_size) = extent(&pvd[156..190]);
+
+        let mut cnf = None;
+        for n in 0..root_size.div_ceil(2048).min(16) {
+            let dir = self.read_data(LEAD_IN + root + n)?;
+            let mut offset = 0;
+            while offset + 33 < dir.len() && dir[offset] != 0 {
+                let record = &dir[offset..];
+                let name = record.get(33..33 + record[32] as usize)?;
+                if name.eq_ignore_ascii_case(b"SYSTEM.CNF;1") {
+                    cnf = Some(extent(record));
+                }
+                offset += record[0] as usize;
+            }
+        }
+
+        let (sector, size) = cnf?;
+        let data = self.read_data(LEAD_IN + sector)?;
+        let text = String::from_utf8_lossy(&data[..(size as usize).min(2048)]).into_owned();
+        boot_file(&text)
+    }
+}
+
+/// Cue sheets are mostly ASCII, but the file names are in the encoding of
+/// the tool that wrote them. Sheets that aren't UTF-8 are read as Latin-1.
+fn decode_cue(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes)
+        .unwrap_or_else(|err| err.into_bytes().iter().map(|&byte| byte as char).collect())
+}
+
+/// Sheets written on Windows separate directories with backslashes
+fn cue_file_path(name: &str) -> PathBuf {
+    match cfg!(windows) {
+        true => PathBuf::from(name),
+        false => PathBuf::from(name.replace('\\', "/")),
+    }
+}
+
+/// The file name of `BOOT = cdrom:\DIR\SLUS_005.94;1` in SYSTEM.CNF
+fn boot_file(cnf: &str) -> Option<String> {
+    let line = cnf.lines().find(|line| line.trim_start().starts_with("BOOT"))?;
+    // Anything after the path is passed to the executable
+    let path = line.split_once('=')?.1.split_whitespace().next()?;
+    let name = path.rsplit(['\\', ':']).next()?;
+    let name = name.split(';').next()?;
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+fn parse_msf(text: Option<&str>, line: usize) -> Result<u32, DiscError> {
+    let parts: Vec<u8> = text
+        .ok_or(DiscError::Cue(line, "missing time"))?
+        .split(':')
+        .map(|part| part.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| DiscError::Cue(line, "bad time"))?;
+
+    match parts[..] {
+        [m, s, f] if s < 60 && f < SECTORS_PER_SECOND as u8 => Ok(msf_to_sector(m, s, f)),
+        _ => Err(DiscError::Cue(line, "bad time")),
+    }
+}
+
+/// Parses a cue sheet. `file_sectors` returns the size in sectors of the
+/// files it references.
+fn parse_cue(
+    cue: &str,
+    file_sectors: impl Fn(&str) -> io::Result<u32>,
+) -> Result<Layout, DiscError> {
+    let mut layout = Layout {
+        files: vec![],
+        segments: vec![],
+        tracks: vec![],
+        end: LEAD_IN,
+    };
+
+    // The segment being built, and the size of its file
+    let mut segment: Option<Segment> = None;
+    let mut file_size = 0;
+    // Of the current track, until its INDEX 01: the length of the pregap
+    // missing from the file, and where the one in the file starts
+    let mut track: Option<(u8, TrackKind)> = None;
+    let mut pregap = 0;
+    let mut index0 = None;
+    let last_line = cue.lines().count();
+
+    for (n, line) in cue.lines().enumerate() {
+        let n = n + 1;
+        // Some tools save a byte order mark, or separate with tabs
+        let line = line.trim_start_matches('\u{feff}').trim();
+        let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim();
+
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                // The name is quoted, and may contain spaces
+                let name = match args.rsplit_once(' ') {
+                    Some((name, _)) => name.trim().trim_matches('"'),
+                    None => return Err(DiscError::Cue(n, "missing file type")),
+                };
+
+                if let Some(segment) = segment.take() {
+                    layout.end = segment.first + file_size - segment.offset;
+                    layout.segments.push(Segment {
+                        sectors: file_size - segment.offset,
+                        ..segment
+                    });
+                }
+
+                file_size = file_sectors(name)?;
+                segment = Some(Segment {
+                    file: layout.files.len(),
+                    offset: 0,
+                    first: layout.end,
+                    sectors: 0,
+                });
+                layout.files.push(name.to_string());
+            }
+            "TRACK" => {
+                let mut args = args.split_whitespace();
+                let number = args
+                    .next()
+                    .and_then(|number| number.parse().ok())
+                    .ok_or(DiscError::Cue(n, "bad track number"))?;
+                let kind = match args.next().map(str::to_ascii_uppercase).as_deref() {
+                    Some("MODE1/2352") => TrackKind::Mode1,
+                    Some("MODE2/2352") => TrackKind::Mode2,
+                    Some("AUDIO") => TrackKind::Audio,
+                    _ => return Err(DiscError::Cue(n, "unsupported track type")),
+                };
+
+                if segment.is_none() {
+                    return Err(DiscError::Cue(n, "track outside of a file"));
+                }
+                if track.is_some() {
+                    return Err(DiscError::Cue(n, "previous track has no INDEX 01"));
+                }
+                track = Some((number, kind));
+                pregap = 0;
+                index0 = None;
+            }
+            "PREGAP" => {
+                pregap = parse_msf(args.split_whitespace().next(), n)?;
+            }
+            "INDEX" => {
+                let mut args = args.split_whitespace();
+                let index: u8 = args
+                    .next()
+                    .and_then(|index| index.parse().ok())
+                    .ok_or(DiscError::Cue(n, "bad index number"))?;
+                let offset = parse_msf(args.next(), n)?;
+
+                if index == 0 {
+                    // The pregap is stored in the file, it just plays
+                    // through
+                    index0 = Some(offset);
+                }
+                if index != 1 {
+                    // Further indexes only split the track for players
+                    continue;
+                }
+
+                let (number, kind) = track
+                    .take()
+                    .ok_or(DiscError::Cue(n, "index outside of a track"))?;
+                let current = segment.as_mut().unwrap();
+                if offset < current.offset || offset > file_size {
+                    return Err(DiscError::Cue(n, "index out of the file"));
+                }
+                if index0.is_some_and(|index0| index0 < current.offset || index0 > offset) {
+                    return Err(DiscError::Cue(n, "INDEX 00 is not before INDEX 01"));
+                }
+
+                if layout.tracks.is_empty() {
+                    // The pregap of the first track is the lead-in, even
+                    // when the sheet says so with PREGAP. If the file has
+                    // it, the track still starts at 00:02:00.
+                    if offset - current.offset > LEAD_IN {
+                        return Err(DiscError::Cue(n, "first track starts after 00:02:00"));
+                    }
+                    current.first = LEAD_IN + current.offset - offset;
+                    layout.tracks.push(Track {
+                        number,
+                        kind,
+                        pregap: 0,
+                        start: LEAD_IN,
+                    });
+                    continue;
+                }
+
+                // Disc position of the pregap stored in the file, if any
+                let stored = index0.map(|index0| current.first + index0 - current.offset);
+                if pregap > 0 {
+                    // The pregap is not in the file: split the segment
+                    let first = current.first + offset - current.offset + pregap;
+                    layout.segments.push(Segment {
+                        sectors: offset - current.offset,
+                        ..*current
+                    });
+                    *current = Segment {
+                        file: current.file,
+                        offset,
+                        first,
+                        sectors: 0,
+                    };
+                }
+
+                let start = current.first + offset - current.offset;
+                layout.tracks.push(Track {
+                    number,
+                    kind,
+                    pregap: stored.unwrap_or(start - pregap),
+                    start,
+                });
+            }
+            _ => {
+                // REM, CATALOG, PERFORMER, TITLE, FLAGS...
+            }
+        }
+    }
+
+    match segment {
+        Some(segment) => {
+            layout.end = segment.first + file_size - segment.offset;
+            layout.segments.push(Segment {
+                sectors: file_size - segment.offset,
+                ..segment
+            });
+        }
+        None => return Err(DiscError::Cue(last_line, "no files")),
+    }
+
+    if track.is_some() {
+        return Err(DiscError::Cue(last_line, "last track has no INDEX 01"));
+    }
+
+    layout.segments.retain(|segment| segment.sectors > 0);
+    Ok(layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sizes(name: &str) -> io::Result<u32> {
+        match name {
+            "Game (Track 1).bin" => Ok(1000),
+            "Game (Track 2).bin" => Ok(500),
+            "game.bin" => Ok(1500),
+            _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+
+    #[test]
+    fn msf_and_bcd_conversions() {
+        assert_eq!(msf_to_sector(0, 2, 0), 150);
+        assert_eq!(sector_to_msf(150 + 75 * 60 + 3), (1, 2, 3));
+        assert_eq!(from_bcd(0x59), 59);
+        assert_eq!(to_bcd(74), 0x74);
+    }
+
+    #[test]
+    fn one_file_per_track() {
+        let cue = "FILE \"Game (Track 1).bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n\
+                   FILE \"Game (Track 2).bin\" BINARY\n  TRACK 02 AUDIO\n    INDEX 00 00:00:00\n    INDEX 01 00:02:00\n";
+        let layout = parse_cue(cue, sizes).unwrap();
+
+        assert_eq!(
+            layout.files,
+            vec!["Game (Track 1).bin", "Game (Track 2).bin"]
+        );
+        assert_eq!(
+            layout.tracks,
+            vec![
+                Track {
+                    number: 1,
+                    kind: TrackKind::Mode2,
+                    pregap: 0,
+                    start: 150
+                },
+                Track {
+                    number: 2,
+                    kind: TrackKind::Audio,
+                    pregap: 1150,
+                    start: 1150 + 150
+                },
+            ]
+        );
+        assert_eq!(layout.end, 150 + 1500);
+        assert_eq!(
+            layout.segments[1],
+            Segment {
+                file: 1,
+                offset: 0,
+                first: 1150,
+                sectors: 500
+            }
+        );
+    }
+
+    #[test]
+    fn pregaps_outside_the_file_split_it() {
+        let cue = "FILE \"game.bin\" BINARY\nTRACK 01 MODE2/2352\nINDEX 01 00:00:00\n\
+                   TRACK 02 AUDIO\nPREGAP 00:02:00\nINDEX 01 00:13:25\n";
+        let layout = parse_cue(cue, sizes).unwrap();
+
+        assert_eq!(layout.tracks[1].start, 150 + 1000 + 150);
+        assert_eq!(layout.tracks[1].pregap, 150 + 1000);
+        assert_eq!(
+            layout.segments,
+            vec![
+                Segment {
+                    file: 0,
+                    offset: 0,
+                    first: 150,
+                    sectors: 1000
+                },
+                Segment {
+                    file: 0,
+                    offset: 1000,
+                    first: 1300,
+                    sectors: 500
+                },
+            ]
+        );
+        assert_eq!(layout.end, 1800);
+    }
+
+    #[test]
+    fn single_file_with_pregaps_in_it() {
+        // Redump style, with a byte order mark and tabs from a Windows tool
+        let cue = "\u{feff}FILE \"game.bin\" BINARY\r\n\tTRACK 01 MODE2/2352\r\n\t\tINDEX 01 00:00:00\r\n\
+                   \tTRACK 02 AUDIO\r\n\t\tINDEX 00 00:10:00\r\n\t\tINDEX 01 00:12:00\r\n\
+                   \tTRACK 03 AUDIO\r\n\t\tINDEX 00 00:15:00\r\n\t\tINDEX 01 00:15:00\r\n\
+                   \t\tINDEX 02 00:16:00\r\n";
+        let layout = parse_cue(cue, sizes).unwrap();
+
+        let starts: Vec<_> = layout.tracks.iter().map(|t| (t.number, t.pregap, t.start)).collect();
+        assert_eq!(starts, vec![(1, 0, 150), (2, 900, 1050), (3, 1275, 1275)]);
+        assert_eq!(layout.segments.len(), 1);
+        assert_eq!(layout.end, 1650);
+    }
+
+    #[test]
+    fn first_track_pregap_is_the_lead_in() {
+        // The lead-in is in the file
+        let cue = "FILE \"game.bin\" BINARY\nTRACK 01 MODE2/2352\nINDEX 00 00:00:00\n\
+                   INDEX 01 00:02:00\nTRACK 02 AUDIO\nINDEX 01 00:13:25\n";
+        let layout = parse_cue(cue, sizes).unwrap();
+        assert_eq!(layout.tracks[0].start, 150);
+        assert_eq!(layout.tracks[1].start, 1000);
+        assert_eq!(layout.segments[0].first, 0);
+        assert_eq!(layout.end, 1500);
+
+        // Or said again with PREGAP
+        let cue = "FILE \"game.bin\" BINARY\nTRACK 01 MODE2/2352\nPREGAP 00:02:00\n\
+                   INDEX 01 00:00:00\n";
+        let layout = parse_cue(cue, sizes).unwrap();
+        assert_eq!(layout.tracks[0].start, 150);
+        assert_eq!(layout.end, 1650);
+
+        let cue = "FILE \"game.bin\" BINARY\nTRACK 01 MODE2/2352\nINDEX 01 00:02:01\n";
+        assert!(matches!(parse_cue(cue, sizes), Err(DiscError::Cue(3, _))));
+    }
+
+    #[test]
+    fn locates_sectors_in_tracks_and_pregaps() {
+        let cue = "FILE \"game.bin\" BINARY\nTRACK 01 MODE2/2352\nINDEX 01 00:00:00\n\
+                   TRACK 02 AUDIO\nINDEX 00 00:10:00\nINDEX 01 00:12:00\n";
+        let layout = parse_cue(cue, sizes).unwrap();
+        let disc = Disc {
+            files: vec![],
+            segments: layout.segments,
+            tracks: layout.tracks,
+            end: layout.end,
+            subchannels: HashMap::new(),
+        };
+
+        let location = |track, index, relative| {
+            Some(Location {
+                track,
+                index,
+                relative,
+            })
+        };
+        assert_eq!(disc.locate(0), location(1, 0, 150));
+        assert_eq!(disc.locate(160), location(1, 1, 10));
+        assert_eq!(disc.locate(1049), location(2, 0, 1));
+        assert_eq!(disc.locate(1050), location(2, 1, 0));
+        assert_eq!(disc.locate(1650), None);
+    }
+
+    #[test]
+    fn malformed_sheets_are_rejected() {
+        assert!(matches!(
+            parse_cue("TRACK 01 MODE2/2352\n", sizes),
+            Err(DiscError::Cue(1, _))
+        ));
+        assert!(matches!(
+            parse_cue("FILE \"game.bin\" BINARY\nTRACK 01 MODE2/2048\n", sizes),
+            Err(DiscError::Cue(2, _))
+        ));
+        assert!(matches!(
+            parse_cue("FILE \"game.bin\" BINARY\nTRACK 01 MODE2/2352\n", sizes),
+            Err(DiscError::Cue(2, _))
+        ));
+        assert!(matches!(
+            parse_cue("FILE \"missing.bin\" BINARY\n", sizes),
+            Err(DiscError::Io(_))
+        ));
+        assert!(matches!(
+            parse_cue(
+                "FILE \"game.bin\" BINARY\nTRACK 01 AUDIO\nINDEX 01 0:0:0\nTRACK 02 AUDIO\n\
+                 INDEX 00 00:05:00\nINDEX 01 00:04:00\n",
+                sizes
+            ),
+            Err(DiscError::Cue(6, _))
+        ));
+    }
+
+    #[test]
+    fn boot_file_of_system_cnf() {
+        let cnf = "BOOT = cdrom:\\SLUS_005.94;1\r\nTCB = 4\r\n";
+        assert_eq!(boot_file(cnf).as_deref(), Some("SLUS_005.94"));
+        let cnf = "BOOT=cdrom:\\GAME\\SCES_001.01;1\r\n";
+        assert_eq!(boot_file(cnf).as_deref(), Some("SCES_001.01"));
+        assert_eq!(boot_file("BOOT = cdrom:PSX.EXE;1 arg").as_deref(), Some("PSX.EXE"));
+        assert_eq!(boot_file("TCB = 4\r\n"), None);
+    }
+
+    #[test]
+    fn cue_sheets_in_other_encodings() {
+        assert_eq!(decode_cue(b"FILE \"j\xc3\xa4g.bin\"".to_vec()), "FILE \"j\u{e4}g.bin\"");
+        assert_eq!(decode_cue(b"FILE \"j\xe4g.bin\"".to_vec()), "FILE \"j\u{e4}g.bin\"");
+        if !cfg!(windows) {
+            assert_eq!(cue_file_path("CD1\\game.bin"), Path::new("CD1/game.bin"));
+        }
+    }
+
+    #[test]
+    fn opens_cue_sheets_in_unicode_folders() {
+        let dir = crate::hw::test_file("ディスク");
+        std::fs::create_dir_all(dir.join("tracks")).unwrap();
+        std::fs::write(dir.join("tracks").join("jeu.bin"), vec![0; SECTOR_SIZE * 2]).unwrap();
+        let cue = "FILE \"tracks\\jeu.bin\" BINARY\nTRACK 01 MODE2/2352\nINDEX 01 00:00:00\n";
+        std::fs::write(dir.join("jeu.cue"), cue).unwrap();
+
+        let disc = Disc::open(dir.join("jeu.cue")).unwrap();
+        assert_eq!(disc.end(), 152);
+    }
+
+    #[test]
+    fn reads_sectors_from_the_image() {
+        let path = crate::hw::test_file("disc.bin");
+        let mut image = vec![0; SECTOR_SIZE * 3];
+        image[SECTOR_SIZE * 2] = 0xaa;
+        std::fs::write(&path, image).unwrap();
+
+        let mut disc = Disc::open(&path).unwrap();
+        let mut sector = [0xff; SECTOR_SIZE];
+
+        assert_eq!(disc.end(), 153);
+        assert!(disc.read_sector(152, &mut sector).unwrap());
+        assert_eq!(sector[0], 0xaa);
+        // The lead-in is not in the image
+        assert!(disc.read_sector(10, &mut sector).unwrap());
+        assert_eq!(sector[0], 0);
+        assert!(!disc.read_sector(153, &mut sector).unwrap());
+    }
+}
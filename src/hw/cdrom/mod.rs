@@ -1,10 +1,38 @@
+mod disc;
+mod subchannel;
+mod xa;
+
+pub use disc::{Disc, DiscError};
+
 use crate::hw::bus::{Bus, BusDevice, PsxEventType};
+use crate::hw::irq::Irq;
+use crustationcpu::state::{Savestate, StateError, StateReader, StateWriter};
 use bitfield::bitfield;
 use ringbuffer::{AllocRingBuffer, RingBuffer, RingBufferExt, RingBufferRead, RingBufferWrite};
 
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::rc::Weak;
 
+use disc::{from_bcd, msf_to_sector, sector_to_msf, to_bcd, SECTOR_SIZE};
+use xa::{decode_sound_group, SOUND_GROUP_SIZE};
+
+const CPU_FREQ: u64 = 33_868_800;
+
+/// Time to read a sector at single speed, 75 sectors per second
+const SECTOR_CYCLES: u64 = CPU_FREQ / 75;
+
+/// Delay of the responses
+const RESPONSE_CYCLES: u64 = 50000;
+
+/// Mode bits
+const MODE_XA_ADPCM: u8 = 1 << 6;
+const MODE_XA_FILTER: u8 = 1 << 3;
+
+/// Submode bits of Mode 2 sectors
+const SUBMODE_AUDIO: u8 = 1 << 2;
+const SUBMODE_REAL_TIME: u8 = 1 << 6;
+
 bitfield! {
     struct ControllerStatus(u8);
     impl Debug;
@@ -16,7 +44,7 @@ bitfield! {
     pub parameter_fifo_empty, set_parameter_fifo_empty: 3;
     pub parameter_fifo_writeable, set_parameter_fifo_writeable: 4;
     pub response_ready, set_response_ready: 5;
-    pub data_fifo_notempty, _: 6;
+    pub data_fifo_notempty, set_data_fifo_notempty: 6;
     pub busy, _: 7;
 }
 
@@ -27,7 +55,7 @@ bitfield! {
     /// Invalid Command / parameters (followed by error)
     pub error, _: 0;
     /// 0 = Motor off, or in spin-up phase, 1 = Motor on
-    pub motor, _: 1;
+    pub motor, set_motor: 1;
     /// Seek error, followed by error
     pub seek_error, _: 2;
     /// GetID failed
@@ -36,7 +64,7 @@ bitfield! {
     pub shel_open, _: 4;
     
     /// Only one of reading, seeking and playing can be 1 at any point in time
-    pub reading, _: 5;
+    pub reading, set_reading: 5;
     pub seeking, _: 6;
     pub playing, _: 7;
 }
@@ -44,7 +72,6 @@ bitfield! {
 struct Interrupt {
     number: u32,
     data: Vec<u8>,
-    acknowledged: bool,
 }
 
 pub struct Cdrom {
@@ -56,6 +83,27 @@ pub struct Cdrom {
     parameters: AllocRingBuffer<u8>,
     pending_irqs: AllocRingBuffer<Interrupt>,
     interrupt_enable: u8,
+    /// The interrupt being delivered, 0 once acknowledged
+    interrupt_flag: u8,
+    /// Response of the last delivered interrupt
+    response: VecDeque<u8>,
+
+    disc: Option<Disc>,
+    mode: u8,
+    /// Target of the last SetLoc, used by the next read or seek
+    location: Option<u32>,
+    /// Next sector to be read
+    position: u32,
+    /// The last sector read
+    sector: Vec<u8>,
+    /// Data FIFO, loaded from `sector` on request
+    data: VecDeque<u8>,
+    /// File and channel of the XA-ADPCM sectors played, set by Setfilter
+    filter: [u8; 2],
+    /// Set by Mute, XA-ADPCM sectors are still consumed but not played
+    muted: bool,
+    /// Of the ADPCM decoder, left and right
+    xa_history: [[i16; 2]; 2],
 }
 
 impl Cdrom {
@@ -69,12 +117,35 @@ impl Cdrom {
             parameters: AllocRingBuffer::with_capacity(16),
             pending_irqs: AllocRingBuffer::with_capacity(16),
             interrupt_enable: 0,
+            interrupt_flag: 0,
+            response: VecDeque::with_capacity(16),
+
+            disc: None,
+            mode: 0,
+            location: None,
+            position: 0,
+            sector: vec![0; SECTOR_SIZE],
+            data: VecDeque::with_capacity(SECTOR_SIZE),
+            filter: [0; 2],
+            muted: false,
+            xa_history: [[0; 2]; 2],
         }
     }
 
     pub fn link(&mut self, bus: Weak<RefCell<Bus>>) {
         self.bus = bus;
     }
+
+    /// Inserts a disc with the shell closed, and spins it up
+    pub fn insert_disc(&mut self, disc: Disc) {
+        self.disc = Some(disc);
+        self.stat.set_motor(true);
+    }
+
+    /// See `Disc::serial`
+    pub fn disc_serial(&mut self) -> Option<String> {
+        self.disc.as_mut()?.serial()
+    }
 }
 
 // When reading from the CDROM controller, reads of sizes larger than 1 byte are
@@ -99,42 +170,27 @@ impl BusDevice for Cdrom {
                 self.controller_status
                     .set_parameter_fifo_empty(self.parameters.is_empty());
                 self.controller_status
-                    .set_parameter_fifo_writeable(self.parameters.is_full());
-                self.controller_status.set_response_ready(
-                    !self.pending_irqs.is_full(), /* && self.pending_irqs[0].response.len() > 0; */
-                );
+                    .set_parameter_fifo_writeable(!self.parameters.is_full());
+                self.controller_status
+                    .set_response_ready(!self.response.is_empty());
+                self.controller_status
+                    .set_data_fifo_notempty(!self.data.is_empty());
 
                 self.controller_status.0
             }
             1 => {
-                if let Some(irq) = self.pending_irqs.front_mut() {
-                    if !irq.data.is_empty() {
-                        let value = irq.data.remove(0);
-                        if irq.data.is_empty() && irq.acknowledged {
-                            self.pending_irqs.dequeue();
-                            if !self.pending_irqs.is_empty() {
-                                self.bus.upgrade().unwrap().borrow().add_event(
-                                    PsxEventType::DeliverCDRomResponse,
-                                    50000,
-                                    0,
-                                );
-                            }
-                        }
-
-                        value
-                    } else {
+                // The response stays readable after the acknowledge, until
+                // the next interrupt is delivered
+                match self.response.pop_front() {
+                    Some(value) => value,
+                    None => {
+                        println!("[CDR] Tried to read response when none was available");
                         0
                     }
-                } else {
-                    println!("[CDR] Tried to read response when none was available");
-                    0
                 }
                 // TODO: When reading further bytes: The buffer is padded with 00h's to the end of the 16-bytes, and does then restart at the first response byte (that, without receiving a new response, so it'll always return the same 16 bytes, until a new command/response has been sent/received).
             }
-            2 => {
-                // println!("[CDR] Trying to read cd data");
-                0
-            }
+            2 => self.data.pop_front().unwrap_or(0),
             3 => {
                 match self.controller_status.index() & 1 {
                     0 => {
@@ -144,12 +200,7 @@ impl BusDevice for Cdrom {
                     }
                     1 => {
                         println!("[CDR] Read Int flag");
-                        if let Some(int) = self.pending_irqs.front() {
-                            // TODO only set this after the IRQ has actually been delivered
-                            0xe0 | ((int.number as u8) & 7)
-                        } else {
-                            0xe0
-                        }
+                        0xe0 | self.interrupt_flag
                     }
                     _ => unreachable!(),
                 }
@@ -225,6 +276,7 @@ impl BusDevice for Cdrom {
                     0 => {
                         // Request Register
                         println!("[CDR] Wrote request {:02x}", value);
+                        self.request_data(value & 0x80 != 0);
                     }
                     1 => {
                         // Interrupt Flag Register
@@ -234,18 +286,10 @@ impl BusDevice for Cdrom {
                             self.parameters.clear();
                         }
 
-                        if let Some(irq) = self.pending_irqs.front_mut() {
-                            irq.acknowledged = true;
-
-                            if irq.data.is_empty() {
-                                self.pending_irqs.dequeue();
-                                if !self.pending_irqs.is_empty() {
-                                    self.bus.upgrade().unwrap().borrow().add_event(
-                                        PsxEventType::DeliverCDRomResponse,
-                                        50000,
-                                        0,
-                                    );
-                                }
+                        if value & 7 != 0 && self.interrupt_flag != 0 {
+                            self.interrupt_flag = 0;
+                            if !self.pending_irqs.is_empty() {
+                                self.schedule_response();
                             }
                         }
                     }
@@ -265,33 +309,91 @@ impl BusDevice for Cdrom {
     }
 }
 
+/// Error codes, sent after the stat byte in INT5 responses
+const ERROR_SEEK_FAILED: u8 = 0x04;
+const ERROR_INVALID_SUBFUNCTION: u8 = 0x10;
+const ERROR_WRONG_PARAMETER_COUNT: u8 = 0x20;
+const ERROR_INVALID_COMMAND: u8 = 0x40;
+const ERROR_NO_DISC: u8 = 0x80;
+
+/// Number of parameters taken by the commands that have any
+fn parameter_count(command: u8) -> Option<usize> {
+    match command {
+        0x02 => Some(3),
+        0x0d => Some(2),
+        0x0e | 0x14 => Some(1),
+        _ => None,
+    }
+}
+
 impl Cdrom {
     fn handle_command(&mut self, command: u8) {
+        if let Some(count) = parameter_count(command) {
+            if self.parameters.len() != count {
+                self.error_response(ERROR_WRONG_PARAMETER_COUNT);
+                self.parameters.clear();
+                return;
+            }
+        }
+
         match command {
             0x01 => {
                 println!("Started CDROM stat");
                 self.enqueue_interrupt(3, &[self.stat.0]);
             }
             0x02 => {
+                let m = from_bcd(*self.parameters.get(0).unwrap());
+                let s = from_bcd(*self.parameters.get(1).unwrap());
+                let f = from_bcd(*self.parameters.get(2).unwrap());
+                self.location = Some(msf_to_sector(m, s, f));
                 self.enqueue_interrupt(3, &[self.stat.0]);
             }
-            0x06 => {
+            0x06 | 0x1b => {
                 println!("ReadN");
-                self.enqueue_interrupt(3, &[0x20]);
-                self.enqueue_interrupt(1, &[]);
-                self.enqueue_interrupt(1, &[]);
-                self.enqueue_interrupt(1, &[]);
+                self.start_reading();
+            }
+            0x08 => {
+                println!("Stop");
+                self.stop_reading();
+                self.enqueue_interrupt(3, &[self.stat.0]);
+                self.stat.set_motor(false);
+                self.enqueue_interrupt(2, &[self.stat.0]);
             }
             0x09 => {
                 println!("Pause");
                 self.enqueue_interrupt(3, &[self.stat.0]);
+                self.stop_reading();
                 self.enqueue_interrupt(2, &[self.stat.0]);
             }
+            0x0a => {
+                println!("Init");
+                self.mode = 0;
+                self.stop_reading();
+                self.stat.set_motor(self.disc.is_some());
+                self.enqueue_interrupt(3, &[self.stat.0]);
+                self.enqueue_interrupt(2, &[self.stat.0]);
+            }
+            0x0b | 0x0c => {
+                self.muted = command == 0x0b;
+                self.enqueue_interrupt(3, &[self.stat.0]);
+            }
+            0x0d => {
+                self.filter = [self.parameters.get(0), self.parameters.get(1)].map(|p| *p.unwrap());
+                self.enqueue_interrupt(3, &[self.stat.0]);
+            }
             0x0e => {
-                println!("Set mode {:02x}", self.parameters.get(0).unwrap());
+                self.mode = *self.parameters.get(0).unwrap();
+                println!("Set mode {:02x}", self.mode);
                 self.enqueue_interrupt(3, &[self.stat.0]);
             }
-            0x15 => {
+            0x11 => self.command_getloc_p(),
+            0x13 => self.command_get_tn(),
+            0x14 => self.command_get_td(),
+            0x15 | 0x16 => {
+                self.stop_reading();
+                if let Some(location) = self.location.take() {
+                    self.position = location;
+                }
                 self.enqueue_interrupt(3, &[self.stat.0]);
                 self.enqueue_interrupt(2, &[self.stat.0]);
             }
@@ -300,44 +402,582 @@ impl Cdrom {
             }
             0x1a => {
                 self.enqueue_interrupt(3, &[self.stat.0]);
-                self.enqueue_interrupt(5, &[2, 0, 0x20, 0, b'S', b'C', b'E', b'A']);
+                match self.disc {
+                    // Licensed, mode 2
+                    Some(_) => self.enqueue_interrupt(2, &[2, 0, 0x20, 0, b'S', b'C', b'E', b'A']),
+                    None => self.enqueue_interrupt(5, &[2, 0, 0x20, 0, b'S', b'C', b'E', b'A']),
+                }
+            }
+            0x1e => {
+                // The TOC is known from the start
+                self.enqueue_interrupt(3, &[self.stat.0]);
+                self.enqueue_interrupt(2, &[self.stat.0]);
             }
             _ => {
-                panic!("[CDR] Cannot do {:02x}", command);
+                println!("[CDR] Cannot do {:02x}", command);
+                self.error_response(ERROR_INVALID_COMMAND);
             }
         }
+
+        // The parameters are consumed by the command, even on errors
+        self.parameters.clear();
     }
 
     fn command_test(&mut self) {
-        let subcommand = self.parameters.get(0).unwrap();
+        let subcommand = match self.parameters.get(0) {
+            Some(subcommand) => *subcommand,
+            None => return self.error_response(ERROR_WRONG_PARAMETER_COUNT),
+        };
 
         match subcommand {
             0x20 => {
                 println!("Started CDROM identify");
                 self.enqueue_interrupt(3, &[0x94, 0x09, 0x19, 0xc0]);
             }
-            _ => unimplemented!(),
+            _ => {
+                println!("[CDR] Cannot do test {:02x}", subcommand);
+                self.error_response(ERROR_INVALID_SUBFUNCTION);
+            }
+        }
+    }
+
+    /// GetlocP: the Q subchannel where the head is. Track, index, time
+    /// relative to INDEX 01 (counting down in pregaps) and absolute time.
+    fn command_getloc_p(&mut self) {
+        let disc = match &self.disc {
+            Some(disc) => disc,
+            None => return self.error_response(ERROR_NO_DISC),
+        };
+
+        if let Some(q) = disc.subchannel_q(self.position) {
+            let response = [&q[1..6], &q[7..10]].concat();
+            return self.enqueue_interrupt(3, &response);
+        }
+
+        let (track, index, relative) = match disc.locate(self.position) {
+            Some(location) => (to_bcd(location.track), location.index, location.relative),
+            // Lead-out
+            None => (0xaa, 1, self.position - disc.end()),
+        };
+
+        let (m, s, f) = sector_to_msf(relative);
+        let (am, as_, af) = sector_to_msf(self.position);
+        let times = [m, s, f, am, as_, af].map(to_bcd);
+        self.enqueue_interrupt(3, &[&[track, to_bcd(index)][..], &times].concat());
+    }
+
+    /// GetTN: first and last track numbers
+    fn command_get_tn(&mut self) {
+        let tracks = match &self.disc {
+            Some(disc) => disc.tracks(),
+            None => return self.error_response(ERROR_NO_DISC),
+        };
+
+        let first = to_bcd(tracks[0].number);
+        let last = to_bcd(tracks[tracks.len() - 1].number);
+        self.enqueue_interrupt(3, &[self.stat.0, first, last]);
+    }
+
+    /// GetTD: start of a track, or of the lead-out for track 0
+    fn command_get_td(&mut self) {
+        let disc = match &self.disc {
+            Some(disc) => disc,
+            None => return self.error_response(ERROR_NO_DISC),
+        };
+
+        let track = from_bcd(*self.parameters.get(0).unwrap());
+        let start = match track {
+            0 => Some(disc.end()),
+            n => disc.tracks().iter().find(|t| t.number == n).map(|t| t.start),
+        };
+
+        match start {
+            Some(start) => {
+                let (m, s, _) = sector_to_msf(start);
+                self.enqueue_interrupt(3, &[self.stat.0, to_bcd(m), to_bcd(s)]);
+            }
+            None => self.error_response(ERROR_INVALID_SUBFUNCTION),
         }
     }
 
+    /// ReadN and ReadS: from the SetLoc target, or where the last read
+    /// stopped. Sectors are delivered with INT1 at the speed set by the mode.
+    fn start_reading(&mut self) {
+        if self.disc.is_none() {
+            return self.error_response(ERROR_NO_DISC);
+        }
+
+        if let Some(location) = self.location.take() {
+            self.position = location;
+        }
+
+        self.stat.set_reading(true);
+        self.enqueue_interrupt(3, &[self.stat.0]);
+
+        let cycles = match self.mode & 0x80 {
+            0 => SECTOR_CYCLES,
+            _ => SECTOR_CYCLES / 2,
+        };
+        self.bus
+            .upgrade()
+            .unwrap()
+            .borrow()
+            .add_event(PsxEventType::CDRomSector, 0, cycles);
+    }
+
+    fn stop_reading(&mut self) {
+        if self.stat.reading() {
+            self.stat.set_reading(false);
+            self.bus
+                .upgrade()
+                .unwrap()
+                .borrow()
+                .remove_event(PsxEventType::CDRomSector);
+        }
+    }
+
+    /// Called by the bus when the next sector is under the head
+    pub fn sector_ready(&mut self) {
+        if !self.stat.reading() {
+            return;
+        }
+
+        let disc = self.disc.as_mut().unwrap();
+        match disc.read_sector(self.position, &mut self.sector) {
+            Ok(true) => {
+                self.position += 1;
+                if self.mode & MODE_XA_ADPCM != 0 && self.is_xa_audio() {
+                    self.play_xa_audio();
+                } else {
+                    self.enqueue_interrupt(1, &[self.stat.0]);
+                }
+            }
+            result => {
+                if let Err(err) = result {
+                    println!("[CDR] Could not read sector {}: {}", self.position, err);
+                } else {
+                    println!("[CDR] Read past the end of the disc at {}", self.position);
+                }
+
+                self.stop_reading();
+                self.enqueue_interrupt(5, &[self.stat.0 | 0x05, ERROR_SEEK_FAILED]);
+            }
+        }
+    }
+
+    /// Real-time audio sectors, played instead of being delivered when
+    /// XA-ADPCM is enabled
+    fn is_xa_audio(&self) -> bool {
+        let flags = SUBMODE_AUDIO | SUBMODE_REAL_TIME;
+        self.sector[15] == 2 && self.sector[18] & flags == flags
+    }
+
+    /// Sends the sound of the last sector read to the SPU, unless it's
+    /// muted or filtered out
+    fn play_xa_audio(&mut self) {
+        let filtered = self.mode & MODE_XA_FILTER != 0 && self.sector[16..18] != self.filter;
+        if filtered || self.muted {
+            return;
+        }
+
+        let (samples, rate) = self.decode_xa_audio();
+        let bus = self.bus.upgrade().unwrap();
+        bus.borrow().push_cd_audio(&samples, rate);
+    }
+
+    /// The samples of the last sector read and their rate
+    fn decode_xa_audio(&mut self) -> (Vec<[i16; 2]>, u32) {
+        let coding = self.sector[19];
+        let stereo = coding & 1 != 0;
+        let rate = if coding & 4 != 0 { 18900 } else { 37800 };
+        let eight_bit = coding & 0x10 != 0;
+
+        let mut samples = Vec::with_capacity(18 * 8 * 28);
+        for group in self.sector[24..].chunks_exact(SOUND_GROUP_SIZE).take(18) {
+            let group = group.try_into().unwrap();
+            let units = decode_sound_group(group, eight_bit, stereo, &mut self.xa_history);
+            if stereo {
+                for pair in units.chunks(2) {
+                    samples.extend(pair[0].iter().zip(&pair[1]).map(|(&l, &r)| [l, r]));
+                }
+            } else {
+                samples.extend(units.iter().flatten().map(|&sample| [sample; 2]));
+            }
+        }
+
+        (samples, rate)
+    }
+
+    /// Writes to the request register: with BFRD set the data FIFO is filled
+    /// with the last sector read, without it the FIFO is emptied
+    fn request_data(&mut self, load: bool) {
+        if !load {
+            self.data.clear();
+        } else if self.data.is_empty() {
+            // Whole sector minus the sync bytes, or just the data of a
+            // Mode 2 Form 1 sector
+            let range = match self.mode & 0x20 {
+                0 => 24..24 + 0x800,
+                _ => 12..SECTOR_SIZE,
+            };
+            self.data.extend(&self.sector[range]);
+        }
+    }
+
+    /// INT5 with the error bit set in stat, followed by the error code
+    fn error_response(&mut self, code: u8) {
+        self.enqueue_interrupt(5, &[self.stat.0 | 1, code]);
+    }
+
     fn enqueue_interrupt(&mut self, irq: u32, response: &[u8]) {
         self.pending_irqs.push(Interrupt {
             number: irq,
             data: response.to_vec(),
-            acknowledged: false,
         });
 
-        self.bus.upgrade().unwrap().borrow().add_event(
-            PsxEventType::DeliverCDRomResponse,
-            50000,
-            0,
-        );
+        self.schedule_response();
+    }
+
+    /// Responses come some time after the command, or after the previous
+    /// response is acknowledged
+    fn schedule_response(&self) {
+        let bus = self.bus.upgrade().unwrap();
+        let bus = bus.borrow();
+        let target = *bus.total_cycles.borrow() + RESPONSE_CYCLES;
+        bus.add_event(PsxEventType::DeliverCDRomResponse, target, 0);
     }
 
+    /// Delivers the next queued interrupt, unless the current one is still
+    /// to be acknowledged
     pub fn next_response(&mut self) {
-        // let response = self.pending_irqs.get(0).unwrap();
+        if self.interrupt_flag != 0 {
+            return;
+        }
+
+        let irq = match self.pending_irqs.dequeue() {
+            Some(irq) => irq,
+            None => return,
+        };
+        self.interrupt_flag = irq.number as u8;
+        self.response = irq.data.into();
 
         println!("Deliver CDROM response");
-        self.bus.upgrade().unwrap().borrow().send_irq(2);
+        self.bus.upgrade().unwrap().borrow().send_irq(Irq::Cdrom);
+    }
+}
+
+impl Savestate for Cdrom {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"CDRM");
+        state.write_u8(self.controller_status.0);
+        state.write_u8(self.stat.0);
+        state.write_u8(self.interrupt_enable);
+
+        let parameters: Vec<u8> = self.parameters.iter().copied().collect();
+        state.write_vec(&parameters);
+
+        state.write_u32(self.pending_irqs.len() as u32);
+        for irq in self.pending_irqs.iter() {
+            state.write_u32(irq.number);
+            state.write_vec(&irq.data);
+        }
+        state.write_u8(self.interrupt_flag);
+        let response: Vec<u8> = self.response.iter().copied().collect();
+        state.write_vec(&response);
+
+        state.write_u8(self.mode);
+        state.write_bool(self.location.is_some());
+        state.write_u32(self.location.unwrap_or(0));
+        state.write_u32(self.position);
+        state.write_bytes(&self.sector);
+        let data: Vec<u8> = self.data.iter().copied().collect();
+        state.write_vec(&data);
+        state.write_bytes(&self.filter);
+        state.write_bool(self.muted);
+        for history in self.xa_history.iter().flatten() {
+            state.write_i16(*history);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"CDRM", "CD-ROM")?;
+        self.controller_status.0 = state.read_u8()?;
+        self.stat.0 = state.read_u8()?;
+        self.interrupt_enable = state.read_u8()?;
+
+        self.parameters.clear();
+        for value in state.read_vec()? {
+            self.parameters.push(value);
+        }
+
+        self.pending_irqs.clear();
+        for _ in 0..state.read_u32()? {
+            let irq = Interrupt {
+                number: state.read_u32()?,
+                data: state.read_vec()?,
+            };
+            self.pending_irqs.push(irq);
+        }
+        self.interrupt_flag = state.read_u8()?;
+        self.response = state.read_vec()?.into();
+
+        self.mode = state.read_u8()?;
+        let has_location = state.read_bool()?;
+        let location = state.read_u32()?;
+        self.location = has_location.then_some(location);
+        self.position = state.read_u32()?;
+        state.read_bytes(&mut self.sector)?;
+        self.data = state.read_vec()?.into();
+        state.read_bytes(&mut self.filter)?;
+        self.muted = state.read_bool()?;
+        for history in self.xa_history.iter_mut().flatten() {
+            *history = state.read_i16()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    /// The CD-ROM schedules its responses on the bus
+    fn make_cdrom(bus: &Rc<RefCell<Bus>>) -> Cdrom {
+        let mut cdrom = Cdrom::new();
+        cdrom.link(Rc::downgrade(bus));
+        cdrom
+    }
+
+    fn send(cdrom: &mut Cdrom, command: u8, parameters: &[u8]) {
+        cdrom.write::<1>(0, 0);
+        for parameter in parameters {
+            cdrom.write::<1>(2, *parameter as u32);
+        }
+        cdrom.write::<1>(1, command as u32);
+    }
+
+    fn responses(cdrom: &Cdrom) -> Vec<(u32, Vec<u8>)> {
+        cdrom
+            .pending_irqs
+            .iter()
+            .map(|irq| (irq.number, irq.data.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn unknown_commands_are_errors() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        let mut cdrom = make_cdrom(&bus);
+
+        send(&mut cdrom, 0x00, &[]);
+        send(&mut cdrom, 0x1f, &[1, 2]);
+
+        assert_eq!(responses(&cdrom), vec![(5, vec![0x01, 0x40]), (5, vec![0x01, 0x40])]);
+        assert!(cdrom.parameters.is_empty());
+    }
+
+    #[test]
+    fn bad_parameters_are_errors() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        let mut cdrom = make_cdrom(&bus);
+
+        send(&mut cdrom, 0x0e, &[]);
+        send(&mut cdrom, 0x19, &[]);
+        send(&mut cdrom, 0x19, &[0x99]);
+
+        assert_eq!(
+            responses(&cdrom),
+            vec![
+                (5, vec![0x01, 0x20]),
+                (5, vec![0x01, 0x20]),
+                (5, vec![0x01, 0x10])
+            ]
+        );
+    }
+
+    #[test]
+    fn parameters_do_not_leak_into_the_next_command() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        let mut cdrom = make_cdrom(&bus);
+
+        send(&mut cdrom, 0x0e, &[0x80]);
+        send(&mut cdrom, 0x19, &[0x20]);
+        // No parameters this time: the previous 0x80 must not be used
+        send(&mut cdrom, 0x0e, &[]);
+
+        assert_eq!(
+            responses(&cdrom),
+            vec![
+                (3, vec![0x00]),
+                (3, vec![0x94, 0x09, 0x19, 0xc0]),
+                (5, vec![0x01, 0x20])
+            ]
+        );
+    }
+
+    /// A single track disc whose sectors hold their number in the first data
+    /// byte
+    fn insert_disc(cdrom: &mut Cdrom, name: &str, sectors: usize) {
+        let path = crate::hw::test_file(name);
+        let mut image = vec![0; SECTOR_SIZE * sectors];
+        for n in 0..sectors {
+            image[n * SECTOR_SIZE + 24] = n as u8;
+            image[n * SECTOR_SIZE + 12] = 0xee;
+        }
+        std::fs::write(&path, image).unwrap();
+
+        cdrom.insert_disc(Disc::open(&path).unwrap());
+    }
+
+    fn acknowledge_all(cdrom: &mut Cdrom) {
+        cdrom.pending_irqs.clear();
+    }
+
+    fn read_data(cdrom: &mut Cdrom, len: usize) -> Vec<u8> {
+        (0..len).map(|_| cdrom.read::<1>(2) as u8).collect()
+    }
+
+    #[test]
+    fn reads_sectors_from_the_set_location() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        let mut cdrom = make_cdrom(&bus);
+        insert_disc(&mut cdrom, "cdrom-read.bin", 4);
+
+        // 00:02:02, the third sector of the image
+        send(&mut cdrom, 0x02, &[0x00, 0x02, 0x02]);
+        send(&mut cdrom, 0x06, &[]);
+        assert_eq!(responses(&cdrom), vec![(3, vec![0x02]), (3, vec![0x22])]);
+        acknowledge_all(&mut cdrom);
+
+        cdrom.sector_ready();
+        assert_eq!(responses(&cdrom), vec![(1, vec![0x22])]);
+
+        // BFRD loads the data FIFO with the 2048 data bytes
+        cdrom.write::<1>(0, 0);
+        cdrom.write::<1>(3, 0x80);
+        assert_ne!(cdrom.read::<1>(0) & 0x40, 0);
+        let data = read_data(&mut cdrom, 0x800);
+        assert_eq!(data[0], 2);
+        assert_eq!(cdrom.read::<1>(0) & 0x40, 0);
+
+        // The next sector follows, now whole
+        send(&mut cdrom, 0x0e, &[0x20]);
+        cdrom.sector_ready();
+        cdrom.write::<1>(0, 0);
+        cdrom.write::<1>(3, 0x80);
+        let data = read_data(&mut cdrom, 0x924);
+        assert_eq!((data[0], data[12]), (0xee, 3));
+
+        // Reading past the end stops with an error
+        acknowledge_all(&mut cdrom);
+        cdrom.sector_ready();
+        assert_eq!(responses(&cdrom), vec![(5, vec![0x07, 0x04])]);
+        assert!(!cdrom.stat.reading());
+
+        send(&mut cdrom, 0x09, &[]);
+        cdrom.sector_ready();
+        assert_eq!(responses(&cdrom).len(), 3);
+    }
+
+    #[test]
+    fn reports_the_table_of_contents() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        let mut cdrom = make_cdrom(&bus);
+
+        send(&mut cdrom, 0x13, &[]);
+        assert_eq!(responses(&cdrom), vec![(5, vec![0x01, 0x80])]);
+        acknowledge_all(&mut cdrom);
+
+        // 75 * 60 sectors, one minute
+        insert_disc(&mut cdrom, "cdrom-toc.bin", 4500);
+        send(&mut cdrom, 0x13, &[]);
+        send(&mut cdrom, 0x14, &[0x01]);
+        send(&mut cdrom, 0x14, &[0x00]);
+        send(&mut cdrom, 0x14, &[0x02]);
+
+        assert_eq!(
+            responses(&cdrom),
+            vec![
+                (3, vec![0x02, 0x01, 0x01]),
+                (3, vec![0x02, 0x00, 0x02]),
+                (3, vec![0x02, 0x01, 0x02]),
+                (5, vec![0x03, 0x10])
+            ]
+        );
+        acknowledge_all(&mut cdrom);
+
+        // The first track's pregap is the lead-in, before it is the lead-out
+        send(&mut cdrom, 0x11, &[]);
+        cdrom.position = 150 + 4500 + 76;
+        send(&mut cdrom, 0x11, &[]);
+        assert_eq!(
+            responses(&cdrom),
+            vec![
+                (3, vec![0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00]),
+                (3, vec![0xaa, 0x01, 0x00, 0x01, 0x01, 0x01, 0x03, 0x01])
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_libcrypt_subchannels() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        let mut cdrom = make_cdrom(&bus);
+        // 00:02:03 reports the time of 00:02:01
+        let q = [0x41, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x02, 0x01];
+        let sbi = [&b"SBI\0"[..], &[0x00, 0x02, 0x03, 0x01], &q].concat();
+        std::fs::write(crate::hw::test_file("cdrom-libcrypt.sbi"), sbi).unwrap();
+        insert_disc(&mut cdrom, "cdrom-libcrypt.bin", 4);
+
+        cdrom.position = 152;
+        send(&mut cdrom, 0x11, &[]);
+        cdrom.position = 153;
+        send(&mut cdrom, 0x11, &[]);
+        assert_eq!(
+            responses(&cdrom),
+            vec![
+                (3, vec![0x01, 0x01, 0x00, 0x00, 0x02, 0x00, 0x02, 0x02]),
+                (3, vec![0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x02, 0x01])
+            ]
+        );
+    }
+
+    #[test]
+    fn plays_xa_adpcm_sectors() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        let mut cdrom = make_cdrom(&bus);
+        // Real-time audio sectors of file 1, channel 2, mono at 18.9 kHz
+        let path = crate::hw::test_file("cdrom-xa.bin");
+        let mut image = vec![0; SECTOR_SIZE * 4];
+        for sector in image.chunks_mut(SECTOR_SIZE) {
+            sector[15..20].copy_from_slice(&[2, 1, 2, 0x64, 0x04]);
+        }
+        std::fs::write(&path, image).unwrap();
+        cdrom.insert_disc(Disc::open(&path).unwrap());
+
+        // Played instead of delivered, whether filtered out or not
+        send(&mut cdrom, 0x0e, &[0x48]);
+        send(&mut cdrom, 0x0d, &[0x01, 0x03]);
+        send(&mut cdrom, 0x02, &[0x00, 0x02, 0x00]);
+        send(&mut cdrom, 0x06, &[]);
+        acknowledge_all(&mut cdrom);
+        cdrom.sector_ready();
+        send(&mut cdrom, 0x0d, &[0x01, 0x02]);
+        acknowledge_all(&mut cdrom);
+        cdrom.sector_ready();
+        assert!(responses(&cdrom).is_empty());
+        assert_eq!(cdrom.position, 152);
+
+        let (samples, rate) = cdrom.decode_xa_audio();
+        assert_eq!((samples.len(), rate), (18 * 8 * 28, 18900));
+        cdrom.sector[19] = 0x01;
+        assert_eq!(cdrom.decode_xa_audio().0.len(), 18 * 4 * 28);
+
+        // Delivered with XA-ADPCM disabled
+        send(&mut cdrom, 0x0e, &[0x00]);
+        acknowledge_all(&mut cdrom);
+        cdrom.sector_ready();
+        assert_eq!(responses(&cdrom), vec![(1, vec![0x22])]);
     }
 }
@@ -0,0 +1,109 @@
+//! Q subchannel corrections, for discs protected by LibCrypt.
+//!
+//! The protection deliberately corrupts the Q subchannel of a few sectors,
+//! and the game checks for the corruption with GetlocP. Images don't keep
+//! the subchannels, so the corrupted entries come from a file next to the
+//! image, with the same name: .sbi or .lsd.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::hw::cdrom::disc::{from_bcd, msf_to_sector, DiscError};
+
+/// Q subchannel of a sector, less the CRC: control/ADR, track, index,
+/// relative time, a zero and absolute time, in BCD
+pub type SubchannelQ = [u8; 10];
+
+/// The corrections for the image at `path`, empty if it has none
+pub fn load(path: &Path) -> Result<HashMap<u32, SubchannelQ>, DiscError> {
+    for extension in ["sbi", "lsd"] {
+        let bytes = match std::fs::read(path.with_extension(extension)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let subchannels = match extension {
+            "sbi" => parse_sbi(&bytes),
+            _ => parse_lsd(&bytes),
+        };
+        return subchannels.map_err(DiscError::Subchannel);
+    }
+    Ok(HashMap::new())
+}
+
+/// Sector of an absolute time in BCD
+fn sector(msf: &[u8]) -> u32 {
+    msf_to_sector(from_bcd(msf[0]), from_bcd(msf[1]), from_bcd(msf[2]))
+}
+
+/// "SBI\0", then for each sector its time and the kind of the entry. Kind
+/// 1 is followed by the whole Q subchannel, the others patch only one time
+/// and are not used by LibCrypt.
+fn parse_sbi(bytes: &[u8]) -> Result<HashMap<u32, SubchannelQ>, &'static str> {
+    let mut entries = bytes.strip_prefix(b"SBI\0").ok_or("not an SBI file")?;
+    let mut subchannels = HashMap::new();
+
+    while !entries.is_empty() {
+        if entries.len() < 14 {
+            return Err("truncated SBI entry");
+        }
+        if entries[3] != 1 {
+            return Err("SBI entry is not a whole Q subchannel");
+        }
+        subchannels.insert(sector(entries), entries[4..14].try_into().unwrap());
+        entries = &entries[14..];
+    }
+
+    Ok(subchannels)
+}
+
+/// For each sector its time and the Q subchannel with its CRC
+fn parse_lsd(bytes: &[u8]) -> Result<HashMap<u32, SubchannelQ>, &'static str> {
+    if !bytes.len().is_multiple_of(15) {
+        return Err("truncated LSD entry");
+    }
+    Ok(bytes
+        .chunks(15)
+        .map(|entry| (sector(entry), entry[3..13].try_into().unwrap()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const Q: SubchannelQ = [0x41, 0x01, 0x01, 0x07, 0x06, 0x05, 0x00, 0x23, 0x08, 0x05];
+
+    #[test]
+    fn reads_sbi_and_lsd_files() {
+        let sbi = [&b"SBI\0"[..], &[0x03, 0x08, 0x05, 0x01], &Q].concat();
+        let subchannels = parse_sbi(&sbi).unwrap();
+        assert_eq!(subchannels.len(), 1);
+        assert_eq!(subchannels[&msf_to_sector(3, 8, 5)], Q);
+
+        let lsd = [&[0x03, 0x08, 0x05][..], &Q, &[0x12, 0x34]].concat();
+        assert_eq!(parse_lsd(&lsd).unwrap(), subchannels);
+
+        assert!(parse_sbi(&sbi[..sbi.len() - 1]).is_err());
+        assert!(parse_sbi(&[&b"SBI\0"[..], &[0x03, 0x08, 0x05, 0x02, 0, 0, 0]].concat()).is_err());
+        assert!(parse_sbi(b"LSD\0").is_err());
+        assert!(parse_lsd(&lsd[1..]).is_err());
+    }
+
+    #[test]
+    fn finds_the_file_next_to_the_image() {
+        let path = crate::hw::test_file("libcrypt.cue");
+        std::fs::remove_file(path.with_extension("sbi")).ok();
+        std::fs::remove_file(path.with_extension("lsd")).ok();
+        assert!(load(&path).unwrap().is_empty());
+
+        let lsd = [&[0x03, 0x08, 0x05][..], &Q, &[0; 2]].concat();
+        std::fs::write(path.with_extension("lsd"), lsd).unwrap();
+        assert_eq!(load(&path).unwrap()[&msf_to_sector(3, 8, 5)], Q);
+
+        // The SBI file comes first
+        std::fs::write(path.with_extension("sbi"), b"SBI\0").unwrap();
+        assert!(load(&path).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,94 @@
+//! CD-XA ADPCM decoding. Audio sectors carry 18 sound groups of 128 bytes:
+//! 16 header bytes, then the samples of 8 (4-bit) or 4 (8-bit) sound units
+//! interleaved word by word.
+
+use crate::hw::spu::adpcm::{filter_sample, header_shift, SAMPLES_PER_BLOCK};
+
+pub const SOUND_GROUP_SIZE: usize = 128;
+
+/// Decodes one sound group into its sound units, in order. In stereo
+/// sectors even units are the left channel and odd units the right one,
+/// each with its own `history` entry; mono sectors only use the first.
+pub fn decode_sound_group(
+    group: &[u8; SOUND_GROUP_SIZE],
+    eight_bit: bool,
+    stereo: bool,
+    history: &mut [[i16; 2]; 2],
+) -> Vec<[i16; SAMPLES_PER_BLOCK]> {
+    let units = if eight_bit { 4 } else { 8 };
+
+    (0..units)
+        .map(|unit| {
+            // Headers are stored twice, the first copy starts at byte 4
+            let header = group[4 + unit];
+            let shift = header_shift(header);
+            let filter = ((header >> 4) & 3) as usize;
+            let history = &mut history[if stereo { unit & 1 } else { 0 }];
+
+            let mut samples = [0; SAMPLES_PER_BLOCK];
+            for (i, sample) in samples.iter_mut().enumerate() {
+                let value = if eight_bit {
+                    ((group[16 + i * 4 + unit] as i16) << 8) as i32 >> shift
+                } else {
+                    let nibble = (group[16 + i * 4 + unit / 2] >> ((unit & 1) * 4)) & 0xf;
+                    ((nibble as i16) << 12) as i32 >> shift
+                };
+                *sample = filter_sample(value, filter, history);
+            }
+
+            samples
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(header: u8) -> [u8; SOUND_GROUP_SIZE] {
+        let mut group = [0; SOUND_GROUP_SIZE];
+        group[4..12].fill(header);
+        group[12..16].fill(header);
+        group
+    }
+
+    #[test]
+    fn four_bit_units_are_interleaved_by_nibble() {
+        let mut group = group(0x0c);
+        group[16] = 0x21;
+        group[19] = 0x7f;
+        group[20] = 0x03;
+
+        let units = decode_sound_group(&group, false, false, &mut [[0; 2]; 2]);
+        assert_eq!(units.len(), 8);
+        assert_eq!(units[0][..2], [1, 3]);
+        assert_eq!(units[1][0], 2);
+        assert_eq!(units[6][0], -1);
+        assert_eq!(units[7][0], 7);
+    }
+
+    #[test]
+    fn eight_bit_units_are_interleaved_by_byte() {
+        let mut group = group(0x08);
+        group[16 + 4 + 2] = 0x80;
+        group[16 + 3] = 0x7f;
+
+        let units = decode_sound_group(&group, true, false, &mut [[0; 2]; 2]);
+        assert_eq!(units.len(), 4);
+        assert_eq!(units[2][1], -128);
+        assert_eq!(units[3][0], 127);
+    }
+
+    #[test]
+    fn stereo_channels_keep_their_own_history() {
+        let group = group(0x1c);
+        let mut history = [[0, 0], [64, 0]];
+
+        let units = decode_sound_group(&group, false, true, &mut history);
+        assert_eq!(units[0][..3], [0, 0, 0]);
+        assert_eq!(units[1][..3], [60, 56, 53]);
+        // Unit 3 continues where unit 1 left off
+        assert_eq!(units[3][0], ((units[1][27] as i32 * 60 + 32) >> 6) as i16);
+        assert_eq!(history[1][0], units[7][27]);
+    }
+}
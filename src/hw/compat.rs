@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Access {
+    /// Every register read is emulated for now
+    #[allow(dead_code)]
+    Read,
+    Write,
+}
+
+struct Touch {
+    /// PC of the instruction that first touched the register
+    first_pc: u32,
+    count: u64,
+}
+
+/// Keeps track of the unimplemented registers a game touches.
+///
+/// Every register is logged the first time it is accessed, with the PC that
+/// accessed it, and a summary can be printed when the emulator exits. This
+/// helps deciding which subsystem is worth implementing next for a title.
+pub struct UnimplementedLog {
+    touched: BTreeMap<(&'static str, u32, Access), Touch>,
+}
+
+impl UnimplementedLog {
+    pub fn new() -> UnimplementedLog {
+        UnimplementedLog {
+            touched: BTreeMap::new(),
+        }
+    }
+
+    /// Records an access to an unimplemented register of `subsystem`.
+    /// Returns true if this is the first time the register is touched.
+    pub fn touch(&mut self, subsystem: &'static str, addr: u32, access: Access, pc: u32) -> bool {
+        let mut first = false;
+
+        self.touched
+            .entry((subsystem, addr, access))
+            .or_insert_with(|| {
+                first = true;
+                Touch {
+                    first_pc: pc,
+                    count: 0,
+                }
+            })
+            .count += 1;
+
+        if first {
+            println!(
+                "[COMPAT] First {:?} of unimplemented {} register {:08x} at pc {:08x}",
+                access, subsystem, addr, pc
+            );
+        }
+
+        first
+    }
+
+    pub fn summary(&self) -> String {
+        if self.touched.is_empty() {
+            return String::from("No unimplemented hardware was touched\n");
+        }
+
+        let mut out = String::from("Unimplemented hardware touched:\n");
+        let mut current = "";
+
+        for ((subsystem, addr, access), touch) in &self.touched {
+            if *subsystem != current {
+                current = subsystem;
+                out += &format!("  {}\n", subsystem);
+            }
+
+            out += &format!(
+                "    {:08x} {:<5} x{:<8} first at pc {:08x}\n",
+                addr,
+                format!("{:?}", access),
+                touch.count,
+                touch.first_pc
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_first_touch_is_reported() {
+        let mut log = UnimplementedLog::new();
+
+        assert!(log.touch("SPU", 0x1f80_1c00, Access::Write, 0x8001_0000));
+        assert!(!log.touch("SPU", 0x1f80_1c00, Access::Write, 0x8001_0004));
+        assert!(log.touch("SPU", 0x1f80_1c00, Access::Read, 0x8001_0008));
+    }
+
+    #[test]
+    fn summary_groups_by_subsystem() {
+        let mut log = UnimplementedLog::new();
+        log.touch("SIO", 0x1f80_1050, Access::Read, 0x8000_1000);
+        log.touch("MDEC", 0x1f80_1820, Access::Write, 0x8000_2000);
+        log.touch("MDEC", 0x1f80_1820, Access::Write, 0x8000_3000);
+
+        let summary = log.summary();
+        assert!(summary.contains("  MDEC\n    1f801820 Write x2        first at pc 80002000"));
+        assert!(summary.contains("  SIO\n    1f801050 Read  x1        first at pc 80001000"));
+    }
+}
@@ -1,3 +1,6 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
 #[derive(Copy, Clone)]
 enum ArgumentFormats {
     Missing,
@@ -68,6 +71,39 @@ pub struct Instruction {
 
 pub struct Disasm;
 
+/// Names of addresses, e.g. functions, to label a listing with
+#[derive(Default)]
+pub struct Symbols(BTreeMap<u32, String>);
+
+impl Symbols {
+    /// One "address name" pair per line, the address in hex. Blank lines
+    /// and lines starting with '#' or ';' are skipped.
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = BTreeMap::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            let (addr, name) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format!("line {}: expected an address and a name", n + 1))?;
+            let addr = addr.trim_start_matches("0x");
+            let addr = u32::from_str_radix(addr, 16)
+                .map_err(|_| format!("line {}: bad address {}", n + 1, addr))?;
+            symbols.insert(addr, name.trim().to_string());
+        }
+
+        Ok(Symbols(symbols))
+    }
+
+    pub fn get(&self, addr: u32) -> Option<&str> {
+        self.0.get(&addr).map(String::as_str)
+    }
+}
+
 const ALL_INSTRUCTIONS: [Instruction; 79] = [
     Instruction {
         opcode: 0x01,
@@ -588,6 +624,58 @@ impl Disasm {
         return format!("invalid ({:8x})", instruction);
     }
 
+    /// Destination of jumps and branches, `None` for register jumps and
+    /// other instructions
+    pub fn branch_target(instruction: u32, pc: u32) -> Option<u32> {
+        let opcode = Opcode(instruction);
+
+        match opcode.main_opcode() {
+            // j, jal
+            0x02 | 0x03 => Some((pc & 0xf000_0000) | ((instruction & 0x3ff_ffff) << 2)),
+            // bcondz, beq, bne, blez, bgtz
+            0x01 | 0x04..=0x07 => {
+                let offset = (opcode.simm16() << 2) as u32;
+                Some(pc.wrapping_add(4).wrapping_add(offset))
+            }
+            _ => None,
+        }
+    }
+
+    /// Disassembles `code`, loaded at `base`. Branch targets get labels,
+    /// from `symbols` or made up from their address.
+    pub fn listing(code: &[u32], base: u32, symbols: &Symbols) -> String {
+        let end = base.wrapping_add(4 * code.len() as u32);
+        let pcs = (0..code.len() as u32).map(|i| base.wrapping_add(4 * i));
+
+        let targets: BTreeSet<u32> = pcs
+            .clone()
+            .zip(code)
+            .filter_map(|(pc, &instruction)| Disasm::branch_target(instruction, pc))
+            .filter(|target| (base..end).contains(target))
+            .collect();
+
+        let label = |addr: u32| match symbols.get(addr) {
+            Some(name) => Some(name.to_string()),
+            None if targets.contains(&addr) => Some(format!("loc_{:08x}", addr)),
+            None => None,
+        };
+
+        let mut out = String::new();
+        for (pc, &instruction) in pcs.zip(code) {
+            if let Some(label) = label(pc) {
+                writeln!(out, "\n{}:", label).unwrap();
+            }
+
+            let disasm = Disasm::disasm(instruction, pc);
+            let mut line = format!("{:08x}  {:08x}  {}", pc, instruction, disasm);
+            if let Some(target) = Disasm::branch_target(instruction, pc).and_then(label) {
+                line = format!("{:<52}; {}", line, target);
+            }
+            writeln!(out, "{}", line.trim_end()).unwrap();
+        }
+        out
+    }
+
     pub fn is_function_call(instruction: u32) -> bool {
         let opcode = Opcode(instruction);
 
@@ -770,3 +858,40 @@ impl Disasm {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_branch_targets() {
+        // j 0x80010040
+        assert_eq!(Disasm::branch_target(0x0800_4010, 0x8001_0000), Some(0x8001_0040));
+        // bne a0, zero, -2
+        assert_eq!(Disasm::branch_target(0x1480_fffe, 0x8001_0010), Some(0x8001_000c));
+        // jr ra, addiu
+        assert_eq!(Disasm::branch_target(0x03e0_0008, 0x8001_0000), None);
+        assert_eq!(Disasm::branch_target(0x2484_0001, 0x8001_0000), None);
+    }
+
+    #[test]
+    fn listings_label_branch_targets() {
+        let symbols = Symbols::parse("# comment\n\n0x80010000 main\n80020000\tprintf\n").unwrap();
+        assert_eq!(symbols.get(0x8002_0000), Some("printf"));
+        assert!(Symbols::parse("main").is_err());
+
+        // main: addiu a0, a0, 1; bne a0, zero, main; nop; jal printf
+        let code = [0x2484_0001, 0x1480_fffe, 0x0000_0000, 0x0c00_8000];
+        let listing = Disasm::listing(&code, 0x8001_0000, &symbols);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[1], "main:");
+        assert!(lines[3].starts_with("80010004  1480fffe  bne"));
+        assert!(lines[3].ends_with("; main"));
+        // Targets out of the listing only have symbols
+        assert!(lines[5].ends_with("; printf"));
+
+        let listing = Disasm::listing(&code, 0x8001_0000, &Symbols::default());
+        assert!(listing.starts_with("\nloc_80010000:\n"));
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::VecDeque;
+
+use crate::hw::dma::{ChannelLink, Direction, SyncMode};
+
+/// Frames of history kept for the activity graph, one second of NTSC video
+const HISTORY_FRAMES: usize = 60;
+
+/// Graph levels, from idle to the busiest frame of the window
+const LEVELS: [char; 8] = [' ', '.', ':', '-', '=', '+', '*', '#'];
+
+pub struct Transfer {
+    pub link: ChannelLink,
+    pub direction: Direction,
+    pub sync_mode: SyncMode,
+    pub base: u32,
+    pub words: u32,
+    /// Bus cycle count when the transfer was started
+    pub cycle: u64,
+    /// Cycles the CPU is stopped for
+    pub cycles: u64,
+}
+
+#[derive(Copy, Clone, Default)]
+struct ChannelTotals {
+    transfers: u64,
+    words: u64,
+}
+
+/// Keeps track of the DMA transfers, per channel and per frame.
+///
+/// When logging is enabled every transfer is printed, and once per second
+/// a graph of the words moved by each channel in the last 60 frames. This
+/// helps spotting channels that stop (hangs waiting on a transfer) or that
+/// move far more data than expected.
+pub struct DmaActivity {
+    logging: bool,
+    totals: [ChannelTotals; 7],
+    /// Words moved by each channel in the current frame
+    current: [u32; 7],
+    history: VecDeque<[u32; 7]>,
+    frames: u64,
+}
+
+impl DmaActivity {
+    pub fn new() -> DmaActivity {
+        DmaActivity {
+            logging: false,
+            totals: [ChannelTotals::default(); 7],
+            current: [0; 7],
+            history: VecDeque::with_capacity(HISTORY_FRAMES),
+            frames: 0,
+        }
+    }
+
+    pub fn set_logging(&mut self, logging: bool) {
+        self.logging = logging;
+    }
+
+    pub fn record(&mut self, transfer: &Transfer) {
+        let n = transfer.link as usize;
+
+        if self.logging {
+            println!(
+                "[DMA] ch{} {:?} {:?} {:?} base {:08x} words {} at cycle {}, {} cycles",
+                n,
+                transfer.link,
+                transfer.direction,
+                transfer.sync_mode,
+                transfer.base,
+                transfer.words,
+                transfer.cycle,
+                transfer.cycles
+            );
+        }
+
+        self.totals[n].transfers += 1;
+        self.totals[n].words += transfer.words as u64;
+        self.current[n] = self.current[n].saturating_add(transfer.words);
+    }
+
+    /// Called at VBlank
+    pub fn end_frame(&mut self) {
+        if self.history.len() == HISTORY_FRAMES {
+            self.history.pop_front();
+        }
+        self.history.push_back(std::mem::take(&mut self.current));
+        self.frames += 1;
+
+        if self.logging && self.frames.is_multiple_of(HISTORY_FRAMES as u64) {
+            print!("{}", self.graph());
+        }
+    }
+
+    /// Average words per second of each channel over the recent frames
+    pub fn rates(&self) -> [u32; 7] {
+        let mut rates = [0; 7];
+        if self.history.is_empty() {
+            return rates;
+        }
+
+        for (n, rate) in rates.iter_mut().enumerate() {
+            let words: u64 = self.history.iter().map(|frame| frame[n] as u64).sum();
+            *rate = (words * 60 / self.history.len() as u64) as u32;
+        }
+
+        rates
+    }
+
+    /// One line per channel: the words/s rate, then a bar per frame (oldest
+    /// first) scaled to the busiest frame of that channel
+    pub fn graph(&self) -> String {
+        let rates = self.rates();
+        let mut out = String::from("[DMA] Activity over the last second (words/s):\n");
+
+        for (n, rate) in rates.iter().enumerate() {
+            let peak = self.history.iter().map(|frame| frame[n]).max().unwrap_or(0);
+            let bars: String = self
+                .history
+                .iter()
+                .map(|frame| match peak {
+                    0 => LEVELS[0],
+                    _ => LEVELS[((frame[n] as u64 * 7).div_ceil(peak as u64)) as usize],
+                })
+                .collect();
+
+            out += &format!(
+                "  ch{} {:<8} {:>9} |{}|\n",
+                n,
+                format!("{:?}", ChannelLink::get(n as u32)),
+                rate,
+                bars
+            );
+        }
+
+        out
+    }
+
+    pub fn summary(&self) -> String {
+        let mut out = String::from("DMA transfers:\n");
+
+        for (n, totals) in self.totals.iter().enumerate() {
+            if totals.transfers > 0 {
+                out += &format!(
+                    "  ch{} {:<8} x{:<8} {} words\n",
+                    n,
+                    format!("{:?}", ChannelLink::get(n as u32)),
+                    totals.transfers,
+                    totals.words
+                );
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(link: ChannelLink, words: u32) -> Transfer {
+        Transfer {
+            link,
+            direction: Direction::FromRam,
+            sync_mode: SyncMode::Sync,
+            base: 0x1000,
+            words,
+            cycle: 0,
+            cycles: words as u64,
+        }
+    }
+
+    #[test]
+    fn rates_average_the_recent_frames() {
+        let mut activity = DmaActivity::new();
+
+        activity.record(&transfer(ChannelLink::Gpu, 100));
+        activity.record(&transfer(ChannelLink::Gpu, 20));
+        activity.end_frame();
+        activity.record(&transfer(ChannelLink::Otc, 10));
+        activity.end_frame();
+
+        let rates = activity.rates();
+        assert_eq!(rates[2], 120 * 60 / 2);
+        assert_eq!(rates[6], 10 * 60 / 2);
+        assert_eq!(rates[3], 0);
+    }
+
+    #[test]
+    fn history_is_limited_to_a_second() {
+        let mut activity = DmaActivity::new();
+
+        activity.record(&transfer(ChannelLink::Cdrom, 512));
+        for _ in 0..HISTORY_FRAMES + 1 {
+            activity.end_frame();
+        }
+
+        assert_eq!(activity.rates()[3], 0);
+        assert!(activity.summary().contains("ch3 Cdrom    x1        512 words"));
+    }
+
+    #[test]
+    fn graph_scales_to_the_peak() {
+        let mut activity = DmaActivity::new();
+
+        activity.record(&transfer(ChannelLink::Gpu, 8));
+        activity.end_frame();
+        activity.end_frame();
+        activity.record(&transfer(ChannelLink::Gpu, 1));
+        activity.end_frame();
+
+        let graph = activity.graph();
+        assert!(graph.contains("ch2 Gpu            180 |# .|"));
+        assert!(graph.contains("ch0 MdecIn           0 |   |"));
+    }
+}
@@ -1,5 +1,10 @@
 // use crate::hw::vec::ByteSerialized;
 use crate::hw::bus::{BusDevice};
+use crustationcpu::state::{Savestate, StateError, StateReader, StateWriter};
+
+mod activity;
+
+pub use activity::{DmaActivity, Transfer};
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Direction {
@@ -63,6 +68,16 @@ impl ChannelLink {
             _ => unreachable!(),
         }
     }
+
+    /// Cycles taken by 0x100 words, with the BIOS memory delay settings
+    fn cycles_per_0x100_words(self) -> u64 {
+        match self {
+            ChannelLink::Cdrom => 0x2800,
+            ChannelLink::Spu => 0x420,
+            ChannelLink::Pio => 0x1400,
+            _ => 0x110,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -90,6 +105,13 @@ pub struct Dma {
     dpcr: u32,
     dicr: u32,
     channels: [Channel; 7],
+
+    /// Set when DICR.b31 goes from 0 to 1, which raises IRQ3
+    irq_pending: bool,
+
+    /// Chopped transfers whose data has moved, with the cycle at which they
+    /// end. Their channels stay busy until then.
+    finishing: Vec<(u32, u64)>,
 }
 
 impl Dma {
@@ -106,14 +128,20 @@ impl Dma {
                 Channel::new(5),
                 Channel::new(6),
             ],
+
+            irq_pending: false,
+
+            finishing: vec![],
         }
     }
 
     // TODO: potentially multiple channels might be active
     // use priorities (??)
-    pub fn active_channel(&mut self) -> Option<&mut Channel> {
+    /// Channels for which `ready` is false wait for their device, e.g. MDEC
+    /// output before the data to decode came in
+    pub fn active_channel(&mut self, ready: impl Fn(&Channel) -> bool) -> Option<&mut Channel> {
         for ch in &mut self.channels {
-            if ch.active() {
+            if ch.active() && ready(ch) {
                 return Some(ch);
             }
         }
@@ -132,28 +160,112 @@ impl Dma {
         self.dicr &= !0xff_ffff;
         self.dicr |= rw_parts;
 
-        // Ack IRQs in bits 24-30
-        let currently_active_irqs = self.dicr & 0x7f00_0000;
-        let new_active_irqs = currently_active_irqs & !acks;
+        // Ack IRQs in bits 24-30: writing 1 clears the flag
+        self.dicr &= !acks;
+
+        self.update_master_flag();
+    }
 
-        // Replace the IRQ flags
-        self.dicr &= !0x7f00_0000;
-        self.dicr |= new_active_irqs << 24;
+    /// Called when channel `n` finishes a transfer. Sets its IRQ flag if the
+    /// channel has interrupts enabled.
+    pub fn transfer_complete(&mut self, n: u32) {
+        if self.dicr & (1 << (16 + n)) != 0 {
+            self.dicr |= 1 << (24 + n);
+        }
 
-        // Compute bit31 (IRQ active)
+        self.update_master_flag();
+    }
+
+    /// Lets channel `n` end at `cycle`, see `finish_due`
+    pub fn finish_at(&mut self, n: u32, cycle: u64) {
+        self.channels[n as usize].detach();
+        self.finishing.push((n, cycle));
+    }
+
+    /// Ends the transfers due by `now`. Returns when the next one ends.
+    pub fn finish_due(&mut self, now: u64) -> Option<u64> {
+        let (due, later): (Vec<_>, Vec<_>) =
+            self.finishing.iter().partition(|(_, end)| *end <= now);
+
+        for (n, _) in due {
+            self.channels[n as usize].done();
+            self.transfer_complete(n);
+        }
+
+        self.finishing = later;
+        self.finishing.iter().map(|(_, end)| *end).min()
+    }
+
+    /// Returns true (once) if the DMA controller raised an interrupt
+    pub fn take_irq(&mut self) -> bool {
+        std::mem::replace(&mut self.irq_pending, false)
+    }
+
+    /// DPCR, DICR and the registers of each channel, decoded
+    pub fn status(&self) -> String {
+        let channels = |bits: u32| -> String {
+            let list: Vec<String> = (0..7)
+                .filter(|n| bits & (1 << n) != 0)
+                .map(|n| n.to_string())
+                .collect();
+            match list.is_empty() {
+                true => String::from("-"),
+                false => list.join(","),
+            }
+        };
+
+        // DPCR has a priority and an enable bit per channel
+        let enabled = (0..7).fold(0, |bits, n| bits | ((self.dpcr >> (4 * n + 3)) & 1) << n);
+        let dicr = self.dicr;
+
+        let mut out = format!("DMA: DPCR {:08x} enabled {}\n", self.dpcr, channels(enabled));
+        out += &format!(
+            "     DICR {:08x} master enable {} irq enabled {} flags {} master flag {}\n",
+            dicr,
+            (dicr >> 23) & 1,
+            channels((dicr >> 16) & 0x7f),
+            channels((dicr >> 24) & 0x7f),
+            dicr >> 31,
+        );
+
+        for ch in &self.channels {
+            out += &format!(
+                "  ch{} {:<8} MADR {:06x} BCR {:08x} CHCR {:08x} {:?} {:?} {}\n",
+                ch.n,
+                format!("{:?}", ch.link),
+                ch.base,
+                ch.read_block_control(),
+                ch.channel_control,
+                ch.direction,
+                ch.sync_mode,
+                match ch.active() {
+                    true => "active",
+                    false if self.finishing.iter().any(|(n, _)| *n == ch.n) => "finishing",
+                    false => "idle",
+                },
+            );
+        }
+
+        out
+    }
+
+    /// Computes DICR.b31, raising an IRQ on its rising edge
+    fn update_master_flag(&mut self) {
         let force_irq = self.dicr & (1 << 15) != 0;
         let master_enable = self.dicr & (1 << 23) != 0;
         let enabled_irqs = (self.dicr >> 16) & 0x7f;
         let active_irqs = (self.dicr >> 24) & 0x7f;
 
-        let irq_active = if force_irq || (master_enable && (active_irqs & enabled_irqs) != 0) {
-            1 << 31
-        } else {
-            0
-        };
+        let irq_active = force_irq || (master_enable && (active_irqs & enabled_irqs) != 0);
+
+        if irq_active && self.dicr & (1 << 31) == 0 {
+            self.irq_pending = true;
+        }
 
         self.dicr &= !(1 << 31);
-        self.dicr |= irq_active;
+        if irq_active {
+            self.dicr |= 1 << 31;
+        }
     }
 }
 
@@ -240,6 +352,10 @@ impl Channel {
         self.link
     }
 
+    pub fn number(&self) -> u32 {
+        self.n
+    }
+
     pub fn step(&self) -> i32 {
         match self.step {
             Step::Backward => -4,
@@ -263,6 +379,30 @@ impl Channel {
         self.direction
     }
 
+    /// Cycles the CPU is stopped for, while `words` words are transferred
+    pub fn transfer_cycles(&self, words: u32) -> u64 {
+        words as u64 * self.link.cycles_per_0x100_words() / 0x100
+    }
+
+    /// With chopping, the CPU gets the bus back for a while after each
+    /// DMA window. Returns the cycles it runs until the transfer is over.
+    pub fn cpu_window_cycles(&self, words: u32) -> u64 {
+        match self.chopping {
+            Chopping::Disabled => 0,
+            Chopping::Enabled => {
+                let windows = words.saturating_sub(1) >> self.chopping_dma_window;
+                (windows as u64) << self.chopping_cpu_window
+            }
+        }
+    }
+
+    /// Stops the transfer from running again, but leaves CHCR busy until
+    /// `done`
+    fn detach(&mut self) {
+        self.busy = Busy::Available;
+        self.trigger = Trigger::Stop;
+    }
+
     pub fn done(&mut self) {
         self.busy = Busy::Available;
         self.trigger = Trigger::Stop;
@@ -369,3 +509,258 @@ impl Channel {
         // self.n, self.channel_control, self.busy, self.trigger, self.sync_mode, self.direction, self.step, self.chopping);
     }
 }
+
+impl Savestate for Dma {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"DMA ");
+        state.write_u32(self.dpcr);
+        state.write_u32(self.dicr);
+        state.write_bool(self.irq_pending);
+
+        for channel in &self.channels {
+            state.write_u32(channel.base);
+            state.write_u32(channel.block_size);
+            state.write_u32(channel.block_count);
+            state.write_u32(channel.channel_control);
+        }
+
+        state.write_u32(self.finishing.len() as u32);
+        for &(n, end) in &self.finishing {
+            state.write_u32(n);
+            state.write_u64(end);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"DMA ", "DMA")?;
+        self.dpcr = state.read_u32()?;
+        self.dicr = state.read_u32()?;
+        self.irq_pending = state.read_bool()?;
+
+        for channel in &mut self.channels {
+            channel.base = state.read_u32()?;
+            channel.block_size = state.read_u32()?;
+            channel.block_count = state.read_u32()?;
+
+            // The decoded fields all come from CHCR
+            let control = state.read_u32()?;
+            if (control >> 9) & 3 == 3 {
+                return Err(StateError::Invalid("DMA sync mode"));
+            }
+            channel.set_channel_control(control);
+        }
+
+        self.finishing.clear();
+        for _ in 0..state.read_u32()? {
+            let n = state.read_u32()?;
+            if n > 6 {
+                return Err(StateError::Invalid("DMA channel"));
+            }
+            self.finish_at(n, state.read_u64()?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORCE: u32 = 1 << 15;
+    const MASTER_ENABLE: u32 = 1 << 23;
+    const MASTER_FLAG: u32 = 1 << 31;
+
+    fn enable(n: u32) -> u32 {
+        1 << (16 + n)
+    }
+
+    fn flag(n: u32) -> u32 {
+        1 << (24 + n)
+    }
+
+    fn dicr(dma: &mut Dma) -> u32 {
+        dma.read::<4>(0x74)
+    }
+
+    #[test]
+    fn fixed_zero_bits_are_dropped() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x74, 0x0000_7fff);
+
+        assert_eq!(dicr(&mut dma), 0x0000_003f);
+    }
+
+    #[test]
+    fn enables_and_master_enable_read_back() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x74, MASTER_ENABLE | 0x007f_0000);
+
+        assert_eq!(dicr(&mut dma), MASTER_ENABLE | 0x007f_0000);
+    }
+
+    #[test]
+    fn flags_cannot_be_set_by_writes() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x74, 0x7f00_0000);
+        assert_eq!(dicr(&mut dma), 0);
+
+        // Nor can the master flag
+        dma.write::<4>(0x74, MASTER_FLAG);
+        assert_eq!(dicr(&mut dma), 0);
+    }
+
+    #[test]
+    fn completion_sets_flag_only_when_enabled() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x74, enable(2));
+
+        dma.transfer_complete(6);
+        assert_eq!(dicr(&mut dma) & 0x7f00_0000, 0);
+
+        dma.transfer_complete(2);
+        assert_eq!(dicr(&mut dma) & 0x7f00_0000, flag(2));
+    }
+
+    #[test]
+    fn writing_one_acks_only_that_flag() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x74, enable(2) | enable(6));
+        dma.transfer_complete(2);
+        dma.transfer_complete(6);
+
+        dma.write::<4>(0x74, enable(2) | enable(6) | flag(2));
+        assert_eq!(dicr(&mut dma) & 0x7f00_0000, flag(6));
+    }
+
+    #[test]
+    fn writing_zero_keeps_flags() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x74, enable(3));
+        dma.transfer_complete(3);
+
+        dma.write::<4>(0x74, enable(3));
+        assert_eq!(dicr(&mut dma) & 0x7f00_0000, flag(3));
+    }
+
+    #[test]
+    fn flags_survive_disabling_the_channel() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x74, enable(4));
+        dma.transfer_complete(4);
+
+        dma.write::<4>(0x74, 0);
+        assert_eq!(dicr(&mut dma), flag(4));
+    }
+
+    #[test]
+    fn master_flag_needs_master_enable() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x74, enable(2));
+        dma.transfer_complete(2);
+        assert_eq!(dicr(&mut dma) & MASTER_FLAG, 0);
+        assert!(!dma.take_irq());
+
+        dma.write::<4>(0x74, MASTER_ENABLE | enable(2));
+        assert_eq!(dicr(&mut dma) & MASTER_FLAG, MASTER_FLAG);
+        assert!(dma.take_irq());
+    }
+
+    #[test]
+    fn master_flag_follows_completion() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x74, MASTER_ENABLE | enable(0));
+        assert_eq!(dicr(&mut dma) & MASTER_FLAG, 0);
+
+        dma.transfer_complete(0);
+        assert_eq!(dicr(&mut dma) & MASTER_FLAG, MASTER_FLAG);
+        assert!(dma.take_irq());
+    }
+
+    #[test]
+    fn force_bit_sets_master_flag() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x74, FORCE);
+
+        assert_eq!(dicr(&mut dma), FORCE | MASTER_FLAG);
+        assert!(dma.take_irq());
+
+        dma.write::<4>(0x74, 0);
+        assert_eq!(dicr(&mut dma), 0);
+    }
+
+    #[test]
+    fn irq_is_raised_on_rising_edge_only() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x74, MASTER_ENABLE | enable(1) | enable(2));
+
+        dma.transfer_complete(1);
+        assert!(dma.take_irq());
+        assert!(!dma.take_irq());
+
+        // b31 is still set, a second channel completing is not a new edge
+        dma.transfer_complete(2);
+        assert!(!dma.take_irq());
+
+        // Ack both, then complete again: new edge
+        dma.write::<4>(0x74, MASTER_ENABLE | enable(1) | enable(2) | flag(1) | flag(2));
+        assert_eq!(dicr(&mut dma) & MASTER_FLAG, 0);
+
+        dma.transfer_complete(2);
+        assert!(dma.take_irq());
+    }
+
+    #[test]
+    fn ack_of_one_flag_keeps_master_flag_if_others_pending() {
+        let mut dma = Dma::new();
+        let base = MASTER_ENABLE | enable(2) | enable(6);
+        dma.write::<4>(0x74, base);
+        dma.transfer_complete(2);
+        dma.transfer_complete(6);
+
+        dma.write::<4>(0x74, base | flag(2));
+        assert_eq!(dicr(&mut dma), base | flag(6) | MASTER_FLAG);
+    }
+
+    #[test]
+    fn chopped_transfers_end_after_the_cpu_windows() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x74, MASTER_ENABLE | enable(2));
+
+        // GPU, 0x100 words in windows of 16 words, 32 CPU cycles between
+        dma.write::<4>(0x28, 0x0154_0301);
+        let channel = dma.active_channel(|_| true).unwrap();
+        assert_eq!(channel.transfer_cycles(0x100), 0x110);
+        assert_eq!(channel.cpu_window_cycles(0x100), 15 * 32);
+        assert_eq!(channel.cpu_window_cycles(16), 0);
+
+        dma.finish_at(2, 1000);
+        assert!(dma.active_channel(|_| true).is_none());
+        assert_eq!(dma.finish_due(999), Some(1000));
+        assert_eq!(dma.read::<4>(0x28) & (1 << 24), 1 << 24);
+
+        assert_eq!(dma.finish_due(1000), None);
+        assert_eq!(dma.read::<4>(0x28) & (1 << 24), 0);
+        assert_eq!(dicr(&mut dma) & flag(2), flag(2));
+    }
+
+    #[test]
+    fn status_decodes_the_registers() {
+        let mut dma = Dma::new();
+        dma.write::<4>(0x70, 0x0000_0800);
+        dma.write::<4>(0x74, MASTER_ENABLE | enable(2));
+        dma.transfer_complete(2);
+        dma.write::<4>(0x20, 0x0001_0000);
+        dma.write::<4>(0x24, 0x0004_0010);
+        dma.write::<4>(0x28, 0x0100_0201);
+
+        let status = dma.status();
+        assert!(status.contains("DPCR 00000800 enabled 2\n"));
+        assert!(status.contains("master enable 1 irq enabled 2 flags 2 master flag 1\n"));
+        assert!(status.contains(
+            "  ch2 Gpu      MADR 010000 BCR 00040010 CHCR 01000201 FromRam Sync active\n"
+        ));
+        assert!(status.contains(
+            "  ch6 Otc      MADR 000000 BCR 00000000 CHCR 00000001 ToRam Immediate idle\n"
+        ));
+    }
+}
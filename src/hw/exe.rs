@@ -0,0 +1,200 @@
+//! PS-X EXE executables: a 2KB header followed by the code
+
+use std::fmt;
+use std::io;
+
+/// The code starts after the header
+pub const CODE_OFFSET: usize = 0x800;
+
+#[derive(Debug)]
+pub enum ExeError {
+    Io(io::Error),
+    /// Shorter than the header, or than the code the header announces
+    Truncated,
+    /// Does not start with "PS-X EXE", holds the first 8 bytes
+    Signature([u8; 8]),
+    /// The arguments don't fit below the initial stack, which is held
+    Arguments(u32),
+}
+
+impl fmt::Display for ExeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExeError::Io(err) => write!(f, "{}", err),
+            ExeError::Truncated => write!(f, "a truncated PS-X EXE"),
+            ExeError::Signature(signature) => write!(
+                f,
+                "not a PS-X EXE: it starts with {:?}",
+                String::from_utf8_lossy(signature)
+            ),
+            ExeError::Arguments(sp) => write!(f, "no room for the arguments below {:08x}", sp),
+        }
+    }
+}
+
+impl std::error::Error for ExeError {}
+
+impl From<io::Error> for ExeError {
+    fn from(err: io::Error) -> ExeError {
+        ExeError::Io(err)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExeHeader {
+    /// Entry point
+    pub pc: u32,
+    /// Initial r28 (gp)
+    pub r28: u32,
+    /// Where the code is loaded
+    pub destination: u32,
+    /// Size of the code, usually a multiple of 2KB but not always
+    pub size: u32,
+    /// Area cleared before running, usually the BSS section
+    pub memfill_address: u32,
+    pub memfill_size: u32,
+    /// Initial r29 (sp) is the base plus the offset, 0 keeps the BIOS one
+    pub r29_base: u32,
+    pub r29_offset: u32,
+    /// ASCII marker checked by the BIOS, e.g. "Sony Computer Entertainment
+    /// Inc. for North America area"
+    pub region: String,
+}
+
+/// Where the arguments go in memory, below the initial stack
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ArgumentLayout {
+    /// The first string, the others follow
+    pub strings: u32,
+    /// The array of pointers to the strings
+    pub argv: u32,
+    /// Initial r29, below the array
+    pub sp: u32,
+}
+
+impl ExeHeader {
+    pub fn parse(data: &[u8]) -> Result<ExeHeader, ExeError> {
+        if data.len() < CODE_OFFSET {
+            return Err(ExeError::Truncated);
+        }
+
+        if &data[0..8] != b"PS-X EXE" {
+            return Err(ExeError::Signature(data[0..8].try_into().unwrap()));
+        }
+
+        let word = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let region = &data[0x4c..CODE_OFFSET];
+        let region_len = region.iter().position(|&c| c == 0).unwrap_or(region.len());
+
+        Ok(ExeHeader {
+            pc: word(0x10),
+            r28: word(0x14),
+            destination: word(0x18),
+            size: word(0x1c),
+            memfill_address: word(0x28),
+            memfill_size: word(0x2c),
+            r29_base: word(0x30),
+            r29_offset: word(0x34),
+            region: String::from_utf8_lossy(&region[..region_len]).to_string(),
+        })
+    }
+
+    /// Initial r29, the BIOS uses 0x801ffff0 when the header has none
+    pub fn stack(&self) -> u32 {
+        match self.r29_base.wrapping_add(self.r29_offset) {
+            0 => 0x801f_fff0,
+            sp => sp,
+        }
+    }
+
+    /// Places the argv strings, then the argv array, below the stack
+    pub fn argument_layout(&self, args: &[String]) -> Result<ArgumentLayout, ExeError> {
+        let stack = self.stack();
+        if args.is_empty() {
+            return Ok(ArgumentLayout { strings: stack, argv: stack, sp: stack });
+        }
+
+        let strings_size: u32 = args.iter().map(|arg| arg.len() as u32 + 1).sum();
+        let layout = stack.checked_sub(strings_size).and_then(|strings| {
+            let strings = strings & !3;
+            let argv = strings.checked_sub(4 * args.len() as u32)?;
+            // Keep the stack 8-byte aligned
+            let sp = argv.checked_sub(8)? & !7;
+            Some(ArgumentLayout { strings, argv, sp })
+        });
+        layout.ok_or(ExeError::Arguments(stack))
+    }
+
+    /// The code following the header in `data`
+    pub fn code<'a>(&self, data: &'a [u8]) -> Result<&'a [u8], ExeError> {
+        let end = CODE_OFFSET + self.size as usize;
+        data.get(CODE_OFFSET..end).ok_or(ExeError::Truncated)
+    }
+}
+
+impl fmt::Display for ExeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Entry point   {:08x}", self.pc)?;
+        writeln!(f, "r28 (gp)      {:08x}", self.r28)?;
+        writeln!(f, "Load address  {:08x}", self.destination)?;
+        writeln!(f, "Code size     {:#x}", self.size)?;
+        writeln!(
+            f,
+            "Memfill       {:08x}, {:#x} bytes",
+            self.memfill_address, self.memfill_size
+        )?;
+        writeln!(f, "r29 (sp)      {:08x} + {:#x}", self.r29_base, self.r29_offset)?;
+        write!(f, "Region        {}", self.region)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_header_and_finds_the_code() {
+        let mut exe = vec![0; CODE_OFFSET];
+        exe[0..8].copy_from_slice(b"PS-X EXE");
+        exe[0x10..0x14].copy_from_slice(&0x8001_0040_u32.to_le_bytes());
+        exe[0x18..0x1c].copy_from_slice(&0x8001_0000_u32.to_le_bytes());
+        exe[0x1c..0x20].copy_from_slice(&8_u32.to_le_bytes());
+        exe[0x30..0x34].copy_from_slice(&0x801f_fff0_u32.to_le_bytes());
+        exe[0x4c..0x52].copy_from_slice(b"Europe");
+        exe.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7]);
+
+        let header = ExeHeader::parse(&exe).unwrap();
+        assert_eq!(header.pc, 0x8001_0040);
+        assert_eq!(header.destination, 0x8001_0000);
+        assert_eq!(header.r29_base, 0x801f_fff0);
+        assert_eq!(header.region, "Europe");
+
+        // One byte short of the announced size
+        assert!(matches!(header.code(&exe), Err(ExeError::Truncated)));
+        exe.push(8);
+        assert_eq!(header.code(&exe).unwrap(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        exe[0] = b'X';
+        assert!(matches!(ExeHeader::parse(&exe), Err(ExeError::Signature(_))));
+        assert!(matches!(ExeHeader::parse(&exe[..0x100]), Err(ExeError::Truncated)));
+    }
+
+    #[test]
+    fn arguments_go_below_the_stack() {
+        let args = [String::from("a"), String::from("bcd")];
+        let header = ExeHeader::default();
+        assert_eq!(header.stack(), 0x801f_fff0);
+        assert_eq!(header.argument_layout(&args).unwrap(), ArgumentLayout {
+            strings: 0x801f_ffe8,
+            argv: 0x801f_ffe0,
+            sp: 0x801f_ffd8,
+        });
+
+        // Wrapping below address 0
+        let header = ExeHeader { r29_base: 0x10, ..ExeHeader::default() };
+        assert!(matches!(header.argument_layout(&args), Err(ExeError::Arguments(0x10))));
+        let low = ExeHeader { r29_base: 6, ..ExeHeader::default() };
+        assert!(matches!(low.argument_layout(&args), Err(ExeError::Arguments(6))));
+        assert_eq!(low.argument_layout(&[]).unwrap().sp, 6);
+    }
+}
@@ -0,0 +1,88 @@
+use crate::hw::rom_writes::RomWriteWarnings;
+use crate::hw::vec::ByteSerialized;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Largest image the region can hold, 1F000000h-1F7FFFFFh
+const MAX_ROM_SIZE: usize = 8 * 1024 * 1024;
+
+/// Expansion region 1 (0x1f000000), behind the parallel port of early
+/// models. Cheat carts and dev cartridges (Caetla, Unirom...) put a ROM
+/// there. The BIOS runs it at boot when "Licensed by Sony Computer
+/// Entertainment Inc." is found at 1F000084h.
+///
+/// The size of the region comes from its delay/size register, read by the
+/// bus. The base address register at 1F801000h is assumed to keep the
+/// BIOS value.
+pub struct Expansion1 {
+    /// Nothing plugged in when empty
+    rom: Vec<u8>,
+    ignored_writes: RomWriteWarnings,
+}
+
+impl Expansion1 {
+    pub fn new() -> Expansion1 {
+        Expansion1 {
+            rom: vec![],
+            ignored_writes: RomWriteWarnings::new("EXP1", 6),
+        }
+    }
+
+    /// Plugs in the ROM image at `path`
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let mut rom = fs::read(path)?;
+        if rom.len() > MAX_ROM_SIZE {
+            println!("[EXP1] ROM larger than 8 MiB, the end is left out");
+            rom.truncate(MAX_ROM_SIZE);
+        }
+        // Whole words, like an erased flash
+        rom.resize(rom.len().next_multiple_of(4), 0xff);
+
+        println!("[EXP1] {} KiB ROM from {}", rom.len() / 1024, path.display());
+        self.rom = rom;
+        Ok(())
+    }
+
+    /// Size of the ROM in bytes, 0 when nothing is plugged in
+    pub fn rom_size(&self) -> usize {
+        self.rom.len()
+    }
+
+    /// The ROM at `addr`, None past its end, where the bus floats
+    pub fn read<const S: u32>(&self, addr: u32) -> Option<u32> {
+        match (addr as usize) < self.rom.len() {
+            true => Some(self.rom.read::<S>(addr)),
+            false => None,
+        }
+    }
+
+    /// Flash programming isn't emulated, the value is dropped
+    pub fn write<const S: u32>(&mut self, addr: u32, value: u32) {
+        if self.rom.is_empty() {
+            return;
+        }
+
+        self.ignored_writes.ignore::<S>(addr, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_are_padded_to_words() {
+        let path = crate::hw::test_file("exp1-padding.rom");
+        fs::write(&path, [1, 2, 3, 4, 5]).unwrap();
+        let mut exp1 = Expansion1::new();
+        assert_eq!(exp1.read::<1>(0), None);
+
+        exp1.load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(exp1.read::<4>(0), Some(0x0403_0201));
+        assert_eq!(exp1.read::<4>(4), Some(0xffff_ff05));
+        assert_eq!(exp1.read::<1>(8), None);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::hw::bus::BusDevice;
+use crustationcpu::state::{Savestate, StateError, StateReader, StateWriter};
+
+/// DIP switches (DTL-H2000 dev boards), read at 0x1f802040
+const DIP_SWITCHES: u32 = 0x40;
+/// 7-segment display showing the BIOS boot progress (4 bits)
+const POST: u32 = 0x41;
+/// 8-bit boot progress LEDs
+const POST_LED: u32 = 0x42;
+/// Boot progress register used by later models
+const POST2: u32 = 0x70;
+
+/// Expansion region 2 (0x1f802000-0x1f80207f). Retail units have nothing
+/// connected to it, but the BIOS still writes its boot progress codes there,
+/// which tell how far it got.
+pub struct Expansion2 {
+    /// Last value written to each of the POST registers
+    post: u8,
+    post_led: u8,
+    post2: u8,
+
+    dip_switches: u8,
+}
+
+impl Expansion2 {
+    pub fn new() -> Expansion2 {
+        Expansion2 {
+            post: 0,
+            post_led: 0,
+            post2: 0,
+
+            // Nothing fitted: the bus floats high
+            dip_switches: 0xff,
+        }
+    }
+
+    /// Last boot progress code shown on the 7-segment display
+    pub fn post(&self) -> u8 {
+        self.post
+    }
+
+    pub fn set_dip_switches(&mut self, value: u8) {
+        self.dip_switches = value;
+    }
+
+    fn read_byte(&self, addr: u32) -> u8 {
+        match addr {
+            DIP_SWITCHES => self.dip_switches,
+            _ => 0xff,
+        }
+    }
+
+    fn write_byte(&mut self, addr: u32, value: u8) {
+        match addr {
+            POST => {
+                let value = value & 0xf;
+                if value != self.post {
+                    println!("[EXP2] POST {:x}", value);
+                }
+                self.post = value;
+            }
+            POST_LED => {
+                if value != self.post_led {
+                    println!("[EXP2] POST LED {:02x}", value);
+                }
+                self.post_led = value;
+            }
+            POST2 => {
+                if value != self.post2 {
+                    println!("[EXP2] POST2 {:02x}", value);
+                }
+                self.post2 = value;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl BusDevice for Expansion2 {
+    /// The region is 8 bits wide, wider accesses are split in bytes
+    fn read<const S: u32>(&mut self, addr: u32) -> u32 {
+        (0..S).fold(0, |value, i| value | (self.read_byte(addr + i) as u32) << (8 * i))
+    }
+
+    fn write<const S: u32>(&mut self, addr: u32, value: u32) {
+        for i in 0..S {
+            self.write_byte(addr + i, (value >> (8 * i)) as u8);
+        }
+    }
+}
+
+/// The DIP switches are part of the setup, not of the state
+impl Savestate for Expansion2 {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"EXP2");
+        state.write_bytes(&[self.post, self.post_led, self.post2]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"EXP2", "expansion 2")?;
+        self.post = state.read_u8()?;
+        self.post_led = state.read_u8()?;
+        self.post2 = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_post_code() {
+        let mut exp2 = Expansion2::new();
+        exp2.write::<1>(POST, 0x37);
+
+        assert_eq!(exp2.post(), 7);
+    }
+
+    #[test]
+    fn reads_dip_switches_and_floats_elsewhere() {
+        let mut exp2 = Expansion2::new();
+        exp2.set_dip_switches(0x12);
+
+        assert_eq!(exp2.read::<1>(DIP_SWITCHES), 0x12);
+        assert_eq!(exp2.read::<2>(DIP_SWITCHES), 0xff12);
+        assert_eq!(exp2.read::<4>(0), 0xffff_ffff);
+    }
+}
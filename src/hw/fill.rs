@@ -0,0 +1,95 @@
+//! What reads of memory nobody wrote return: main RAM at power-on, and
+//! addresses where no device drives the bus. Some games depend on the
+//! patterns of a typical console. The random fill is derived from a seed
+//! and the address only, so runs with the same seed are identical.
+
+/// Seed of `random` without one
+const DEFAULT_SEED: u32 = 0x5053_5821;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Fill {
+    Zeros,
+    Ones,
+    Random(u32),
+}
+
+impl Fill {
+    /// `zeros`, `ones`, `random` or `random:SEED`
+    pub fn from_name(name: &str) -> Option<Fill> {
+        match name {
+            "zeros" => Some(Fill::Zeros),
+            "ones" => Some(Fill::Ones),
+            "random" => Some(Fill::Random(DEFAULT_SEED)),
+            _ => {
+                let seed = name.strip_prefix("random:")?;
+                let seed = match seed.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => seed.parse().ok()?,
+                };
+                Some(Fill::Random(seed))
+            }
+        }
+    }
+
+    /// What a read at `addr` returns, from its byte up
+    pub fn word(self, addr: u32) -> u32 {
+        match self {
+            Fill::Zeros => 0,
+            Fill::Ones => 0xffff_ffff,
+            Fill::Random(seed) => {
+                let word = mix(seed ^ (addr & !3).wrapping_mul(0x9e37_79b9));
+                word.rotate_right((addr & 3) * 8)
+            }
+        }
+    }
+
+    /// Fills `memory`, which starts at address 0
+    pub fn apply(self, memory: &mut [u8]) {
+        for (addr, word) in memory.chunks_mut(4).enumerate() {
+            let value = self.word(addr as u32 * 4).to_le_bytes();
+            word.copy_from_slice(&value[..word.len()]);
+        }
+    }
+}
+
+/// Final mix of MurmurHash3: every input bit flips half of the output bits
+fn mix(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x85eb_ca6b);
+    x ^= x >> 13;
+    x = x.wrapping_mul(0xc2b2_ae35);
+    x ^ (x >> 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_policies() {
+        assert_eq!(Fill::from_name("zeros"), Some(Fill::Zeros));
+        assert_eq!(Fill::from_name("ones"), Some(Fill::Ones));
+        assert_eq!(Fill::from_name("random"), Some(Fill::Random(DEFAULT_SEED)));
+        assert_eq!(Fill::from_name("random:42"), Some(Fill::Random(42)));
+        assert_eq!(Fill::from_name("random:0xdead"), Some(Fill::Random(0xdead)));
+        assert_eq!(Fill::from_name("random:"), None);
+        assert_eq!(Fill::from_name("garbage"), None);
+    }
+
+    #[test]
+    fn random_fill_depends_on_the_seed_only() {
+        let mut a = vec![0; 4096];
+        let mut b = vec![0; 4096];
+        Fill::Random(1).apply(&mut a);
+        Fill::Random(1).apply(&mut b);
+        assert_eq!(a, b);
+        assert_eq!(u32::from_le_bytes(a[8..12].try_into().unwrap()), Fill::Random(1).word(8));
+
+        Fill::Random(2).apply(&mut b);
+        assert_ne!(a, b);
+
+        // Not a constant pattern
+        assert!(a.chunks(4).any(|word| word != &a[..4]));
+        assert_eq!(Fill::Random(1).word(9) as u8, a[9]);
+    }
+}
@@ -0,0 +1,96 @@
+//! How closely drawing follows the hardware. The GP0 layer decides which
+//! pixels are drawn and how, the software rasterizer and the renderer
+//! both follow it.
+
+/// Named sets of `GpuAccuracyConfig` settings
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GpuAccuracy {
+    /// Ignores the mask bit, for games that look the same without it
+    Fast,
+    /// Everything games commonly rely on, without the dithering pattern
+    Balanced,
+    /// As close to the hardware as the emulator gets
+    Accurate,
+}
+
+impl GpuAccuracy {
+    pub fn from_name(name: &str) -> Option<GpuAccuracy> {
+        match name {
+            "fast" => Some(GpuAccuracy::Fast),
+            "balanced" => Some(GpuAccuracy::Balanced),
+            "accurate" => Some(GpuAccuracy::Accurate),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GpuAccuracy::Fast => "fast",
+            GpuAccuracy::Balanced => "balanced",
+            GpuAccuracy::Accurate => "accurate",
+        }
+    }
+
+    /// The next preset, back to the first after the last one
+    pub fn next(self) -> GpuAccuracy {
+        match self {
+            GpuAccuracy::Fast => GpuAccuracy::Balanced,
+            GpuAccuracy::Balanced => GpuAccuracy::Accurate,
+            GpuAccuracy::Accurate => GpuAccuracy::Fast,
+        }
+    }
+
+    pub fn config(self) -> GpuAccuracyConfig {
+        match self {
+            GpuAccuracy::Fast => GpuAccuracyConfig {
+                mask_bits: false,
+                dithering: false,
+                interlaced_fields: false,
+            },
+            GpuAccuracy::Balanced => GpuAccuracyConfig {
+                mask_bits: true,
+                dithering: false,
+                interlaced_fields: false,
+            },
+            GpuAccuracy::Accurate => GpuAccuracyConfig {
+                mask_bits: true,
+                dithering: true,
+                interlaced_fields: true,
+            },
+        }
+    }
+}
+
+/// The settings that trade accuracy for speed or looks
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GpuAccuracyConfig {
+    /// GP0(E6): set bit 15 of drawn pixels and leave the pixels that have
+    /// it alone
+    pub mask_bits: bool,
+    /// With GPUSTAT.9 set, shaded and modulated pixels are dithered
+    /// before being cut to 5 bits per channel
+    pub dithering: bool,
+    /// In 480-line interlaced modes with GPUSTAT.10 clear, the lines of
+    /// the field on screen are not drawn to
+    pub interlaced_fields: bool,
+}
+
+impl Default for GpuAccuracyConfig {
+    fn default() -> GpuAccuracyConfig {
+        GpuAccuracy::Balanced.config()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_by_name() {
+        for preset in [GpuAccuracy::Fast, GpuAccuracy::Balanced, GpuAccuracy::Accurate] {
+            assert_eq!(GpuAccuracy::from_name(preset.name()), Some(preset));
+        }
+        assert_eq!(GpuAccuracy::from_name("exact"), None);
+        assert_eq!(GpuAccuracy::Accurate.next(), GpuAccuracy::Fast);
+    }
+}
@@ -0,0 +1,143 @@
+//! Crosshair for light guns: the mouse pointer, mapped from the window to
+//! the pixels of the emulated display. The calibration mode prints the
+//! mapping of every click, to check it against what a game expects.
+
+use sdl2::event::{Event, WindowEvent};
+
+use crate::hw::gpu::types::DisplayArea;
+
+/// Where the display area is shown, in window coordinates
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Viewport {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Viewport {
+    /// The display area stretched to the whole window, as it is drawn
+    pub fn stretched((width, height): (u32, u32)) -> Viewport {
+        Viewport {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    /// The display pixel under the window point `(x, y)`, relative to the
+    /// top left corner of `area`. None outside of the picture.
+    pub fn to_display(self, (x, y): (i32, i32), area: DisplayArea) -> Option<(u16, u16)> {
+        let (x, y) = ((x - self.x) as i64, (y - self.y) as i64);
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return None;
+        }
+
+        let x = x * area.width as i64 / self.width as i64;
+        let y = y * area.height as i64 / self.height as i64;
+        Some((x as u16, y as u16))
+    }
+
+    /// The window point at the center of the display pixel `(x, y)`
+    pub fn to_window(self, (x, y): (u16, u16), area: DisplayArea) -> (i32, i32) {
+        let x = (2 * x as i64 + 1) * self.width as i64 / (2 * area.width as i64);
+        let y = (2 * y as i64 + 1) * self.height as i64 / (2 * area.height as i64);
+        (self.x + x as i32, self.y + y as i32)
+    }
+}
+
+pub struct Crosshair {
+    pub shown: bool,
+    pub calibrating: bool,
+    /// Last position of the mouse in the window, None when it left
+    pointer: Option<(i32, i32)>,
+}
+
+impl Crosshair {
+    pub fn new() -> Crosshair {
+        Crosshair {
+            shown: false,
+            calibrating: false,
+            pointer: None,
+        }
+    }
+
+    /// The display pixel under the mouse, if it is shown
+    pub fn target(&self, viewport: Viewport, area: DisplayArea) -> Option<(u16, u16)> {
+        match self.shown || self.calibrating {
+            true => viewport.to_display(self.pointer?, area),
+            false => None,
+        }
+    }
+
+    /// Follows the mouse. Returns false for the events it doesn't use.
+    pub fn handle_event(&mut self, event: &Event, viewport: Viewport, area: DisplayArea) -> bool {
+        match *event {
+            Event::MouseMotion { x, y, .. } => self.pointer = Some((x, y)),
+            Event::Window {
+                win_event: WindowEvent::Leave,
+                ..
+            } => self.pointer = None,
+            Event::MouseButtonDown { x, y, .. } if self.calibrating => {
+                match viewport.to_display((x, y), area) {
+                    Some((dx, dy)) => println!(
+                        "[GPU] Window ({}, {}) is display pixel ({}, {}), VRAM ({}, {})",
+                        x,
+                        y,
+                        dx,
+                        dy,
+                        area.x + dx,
+                        area.y + dy
+                    ),
+                    None => println!("[GPU] Window ({}, {}) is outside the display", x, y),
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AREA: DisplayArea = DisplayArea {
+        x: 0,
+        y: 256,
+        width: 320,
+        height: 240,
+    };
+
+    #[test]
+    fn maps_the_window_to_display_pixels() {
+        // Twice the size of the display, HiDPI scaling aside
+        let viewport = Viewport::stretched((640, 480));
+        assert_eq!(viewport.to_display((0, 0), AREA), Some((0, 0)));
+        assert_eq!(viewport.to_display((1, 1), AREA), Some((0, 0)));
+        assert_eq!(viewport.to_display((2, 3), AREA), Some((1, 1)));
+        assert_eq!(viewport.to_display((639, 479), AREA), Some((319, 239)));
+        assert_eq!(viewport.to_display((640, 0), AREA), None);
+
+        // With bars on the sides
+        let viewport = Viewport {
+            x: 80,
+            y: 0,
+            width: 640,
+            height: 480,
+        };
+        assert_eq!(viewport.to_display((79, 100), AREA), None);
+        assert_eq!(viewport.to_display((80 + 321, 101), AREA), Some((160, 50)));
+    }
+
+    #[test]
+    fn snaps_to_the_center_of_the_pixels() {
+        let viewport = Viewport::stretched((1000, 700));
+        for (x, y) in [(0, 0), (123, 45), (319, 239)] {
+            let point = viewport.to_window((x, y), AREA);
+            assert_eq!(viewport.to_display(point, AREA), Some((x, y)));
+        }
+        assert_eq!(viewport.to_window((0, 0), AREA), (1, 1));
+    }
+}
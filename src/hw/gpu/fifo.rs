@@ -0,0 +1,163 @@
+//! The GP0 command FIFO. Drawing is instant in the emulator, so every
+//! command is charged an estimate of the time it takes on hardware, and
+//! the words the CPU writes in the meantime wait here, 16 at most. GPUSTAT
+//! reports the state of the FIFO to the games polling it.
+//!
+//! DMA doesn't go through the FIFO: hardware stalls the transfer until the
+//! GPU takes each word, the emulator hands over the whole block at once.
+
+use std::collections::VecDeque;
+
+use super::timing;
+use super::types::Texture;
+
+/// Words the FIFO holds
+pub const FIFO_WORDS: usize = 16;
+
+/// GPU cycles spent on each triangle before drawing its pixels
+const TRIANGLE_SETUP: u64 = 64;
+/// GPU cycles spent on a fill before its first line
+const FILL_SETUP: u64 = 46;
+
+pub struct CommandFifo {
+    words: VecDeque<u32>,
+    /// CPU cycle count at which the GPU is done with the last command
+    busy_until: u64,
+    /// Words executed early because the FIFO was full
+    overflows: u64,
+}
+
+impl CommandFifo {
+    pub fn new() -> CommandFifo {
+        CommandFifo {
+            words: VecDeque::with_capacity(FIFO_WORDS),
+            busy_until: 0,
+            overflows: 0,
+        }
+    }
+
+    /// Whether a word written at `now` is executed at once
+    pub fn idle(&self, now: u64) -> bool {
+        self.words.is_empty() && now >= self.busy_until
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.words.len() >= FIFO_WORDS
+    }
+
+    /// Queues `word`. When the FIFO is full the oldest word is returned,
+    /// to be executed early: hardware would drop one of them, which turns
+    /// the rest of the command stream into garbage.
+    pub fn push(&mut self, word: u32) -> Option<u32> {
+        let oldest = match self.is_full() {
+            true => self.words.pop_front(),
+            false => None,
+        };
+        if oldest.is_some() {
+            self.overflows += 1;
+            if self.overflows == 1 {
+                println!("[GPU] GP0 written to a full FIFO, executing the oldest word early");
+            }
+        }
+
+        self.words.push_back(word);
+        oldest
+    }
+
+    /// Next word to execute if the GPU is done with the previous command
+    /// by `now`, or unconditionally without a time
+    pub fn pop(&mut self, now: Option<u64>) -> Option<u32> {
+        match now {
+            Some(now) if now < self.busy_until => None,
+            _ => self.words.pop_front(),
+        }
+    }
+
+    /// The GPU starts working at `now`, unless it's still busy
+    pub fn start(&mut self, now: u64) {
+        self.busy_until = self.busy_until.max(now);
+    }
+
+    /// Keeps the GPU busy for `ticks` more GPU cycles
+    pub fn charge(&mut self, ticks: u64) {
+        self.busy_until += timing::cpu_cycles(ticks);
+    }
+
+    /// GP1(01): the queued words are lost, drawing goes on
+    pub fn clear(&mut self) {
+        self.words.clear();
+    }
+
+    pub fn words(&self) -> impl Iterator<Item = &u32> {
+        self.words.iter()
+    }
+
+    pub fn busy_until(&self) -> u64 {
+        self.busy_until
+    }
+
+    /// From a savestate, `words` holding at most `FIFO_WORDS`
+    pub fn restore(&mut self, words: &[u32], busy_until: u64) {
+        self.words.clear();
+        self.words.extend(words);
+        self.busy_until = busy_until;
+    }
+}
+
+/// GPU cycles to draw `triangles` covering `pixels`: textures and
+/// semi-transparency read VRAM for every pixel
+pub fn draw_ticks(triangles: u64, pixels: u64, texture: Texture) -> u64 {
+    let textured = texture.flags & Texture::TEXTURED != 0;
+    let reads = 1 + textured as u64 + texture.semi_transparency().is_some() as u64;
+    triangles * TRIANGLE_SETUP + pixels * reads
+}
+
+/// GPU cycles of GP0(02), which writes 8 pixels at a time
+pub fn fill_ticks(width: u32, height: u32) -> u64 {
+    FILL_SETUP + (width as u64 / 8 + 9) * height as u64
+}
+
+/// GPU cycles of GP0(80), reading and writing each pixel
+pub fn copy_ticks(width: u32, height: u32) -> u64 {
+    width as u64 * height as u64 * 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_wait_for_the_gpu() {
+        let mut fifo = CommandFifo::new();
+        assert!(fifo.idle(0));
+
+        fifo.start(100);
+        fifo.charge(110);
+        assert_eq!(fifo.busy_until(), 170);
+        assert!(!fifo.idle(169));
+        assert!(fifo.idle(170));
+
+        assert_eq!(fifo.push(1), None);
+        assert!(!fifo.idle(200));
+        assert_eq!(fifo.pop(Some(169)), None);
+        assert_eq!(fifo.pop(Some(170)), Some(1));
+
+        // A later start doesn't move the end back
+        fifo.charge(11);
+        fifo.start(150);
+        assert_eq!(fifo.busy_until(), 177);
+    }
+
+    #[test]
+    fn full_fifos_give_back_the_oldest_word() {
+        let mut fifo = CommandFifo::new();
+        for word in 0..FIFO_WORDS as u32 {
+            assert_eq!(fifo.push(word), None);
+        }
+        assert!(fifo.is_full());
+
+        assert_eq!(fifo.push(16), Some(0));
+        assert_eq!(fifo.words().copied().collect::<Vec<_>>(), (1..=16).collect::<Vec<_>>());
+        assert_eq!(fifo.pop(None), Some(1));
+    }
+}
@@ -0,0 +1,206 @@
+//! The displayed picture, read from VRAM for frontends that don't use the
+//! window (embedding, tests, screenshots).
+
+use std::io;
+use std::path::Path;
+
+use crate::hash::fnv1a;
+use crate::hw::gpu::types::{DisplayArea, VRAM_HEIGHT, VRAM_WIDTH};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VideoFrame {
+    pub width: u16,
+    pub height: u16,
+    /// 0x00RRGGBB, line by line
+    pub pixels: Vec<u32>,
+}
+
+impl VideoFrame {
+    /// Reads `area` of `vram`, wrapping around at its edges. In 24-bit mode
+    /// the pixels are packed, 3 bytes each from the first column.
+    pub fn from_vram(vram: &[u16], area: DisplayArea, color_24bit: bool) -> VideoFrame {
+        let mut pixels = Vec::with_capacity(area.width as usize * area.height as usize);
+        for line in 0..area.height as usize {
+            let row = &vram[(area.y as usize + line) % VRAM_HEIGHT * VRAM_WIDTH..][..VRAM_WIDTH];
+            let halfword = |column: usize| row[(area.x as usize + column) % VRAM_WIDTH];
+
+            for x in 0..area.width as usize {
+                let pixel = if color_24bit {
+                    let byte = |n: usize| (halfword(n / 2) >> (8 * (n % 2))) as u32 & 0xff;
+                    byte(x * 3) << 16 | byte(x * 3 + 1) << 8 | byte(x * 3 + 2)
+                } else {
+                    let color = halfword(x) as u32;
+                    let channel = |shift: u32| {
+                        let value = (color >> shift) & 0x1f;
+                        value << 3 | value >> 2
+                    };
+                    channel(0) << 16 | channel(5) << 8 | channel(10)
+                };
+                pixels.push(pixel);
+            }
+        }
+
+        VideoFrame {
+            width: area.width,
+            height: area.height,
+            pixels,
+        }
+    }
+
+    /// A black picture, while the display is disabled
+    pub fn black(width: u16, height: u16) -> VideoFrame {
+        VideoFrame {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize],
+        }
+    }
+
+    /// 64-bit FNV-1a of the size and pixels, to compare the output of test
+    /// programs across runs
+    pub fn hash(&self) -> u64 {
+        let size = [self.width.to_le_bytes(), self.height.to_le_bytes()].concat();
+        let pixels = self.pixels.iter().flat_map(|pixel| pixel.to_le_bytes());
+        fnv1a(size.into_iter().chain(pixels))
+    }
+
+    /// As a PNG file, 24-bit RGB. The image data is not compressed, which
+    /// zlib allows, to keep this free of dependencies.
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut ihdr = vec![];
+        ihdr.extend((self.width as u32).to_be_bytes());
+        ihdr.extend((self.height as u32).to_be_bytes());
+        // 8 bits per channel, RGB, then the default methods, no interlacing
+        ihdr.extend([8, 2, 0, 0, 0]);
+
+        // Each line starts with its filter type, 0 for none
+        let mut raw = Vec::with_capacity(self.pixels.len() * 3 + self.height as usize);
+        for line in self.pixels.chunks(self.width.max(1) as usize) {
+            raw.push(0);
+            for pixel in line {
+                raw.extend(&pixel.to_be_bytes()[1..]);
+            }
+        }
+
+        // zlib header, stored deflate blocks of up to 65535 bytes, Adler-32
+        let mut zlib = vec![0x78, 0x01];
+        let blocks: Vec<&[u8]> = raw.chunks(0xffff).collect();
+        for (n, block) in blocks.iter().enumerate() {
+            zlib.push((n + 1 == blocks.len()) as u8);
+            zlib.extend((block.len() as u16).to_le_bytes());
+            zlib.extend((!(block.len() as u16)).to_le_bytes());
+            zlib.extend(*block);
+        }
+        if blocks.is_empty() {
+            zlib.extend([1, 0, 0, 0xff, 0xff]);
+        }
+        zlib.extend(adler32(&raw).to_be_bytes());
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, data) in [(b"IHDR", &ihdr), (b"IDAT", &zlib), (b"IEND", &vec![])] {
+            png.extend((data.len() as u32).to_be_bytes());
+            let start = png.len();
+            png.extend(kind);
+            png.extend(data);
+            let crc = crc32(&png[start..]);
+            png.extend(crc.to_be_bytes());
+        }
+        png
+    }
+
+    pub fn write_png(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.to_png())
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_15_and_24_bit_pixels() {
+        let mut vram = vec![0; VRAM_WIDTH * VRAM_HEIGHT];
+        let area = DisplayArea {
+            x: 1023,
+            y: 511,
+            width: 2,
+            height: 2,
+        };
+        // Red, then green at the wrapped column 0
+        vram[511 * VRAM_WIDTH + 1023] = 0x001f;
+        vram[511 * VRAM_WIDTH] = 0x03e0;
+        // Blue, at the wrapped line 0
+        vram[1023] = 0x7c00 | 0x8000;
+
+        let frame = VideoFrame::from_vram(&vram, area, false);
+        assert_eq!(frame.pixels, [0xff_0000, 0x00_ff00, 0x00_00ff, 0]);
+
+        // R, G, B of two pixels: 11 22 33 44 55 66
+        vram[..3].copy_from_slice(&[0x2211, 0x4433, 0x6655]);
+        let area = DisplayArea {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        let frame = VideoFrame::from_vram(&vram, area, true);
+        assert_eq!(frame.pixels, [0x11_2233, 0x44_5566]);
+    }
+
+    #[test]
+    fn encodes_png() {
+        let frame = VideoFrame {
+            width: 2,
+            height: 1,
+            pixels: vec![0x11_2233, 0xff_ffff],
+        };
+        let png = frame.to_png();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        // IHDR: 2x1, 8-bit RGB
+        assert_eq!(&png[8..16], b"\0\0\0\x0dIHDR");
+        assert_eq!(&png[16..29], [0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+        assert_eq!(crc32(&png[12..29]), u32::from_be_bytes(png[29..33].try_into().unwrap()));
+
+        // One stored block with the filter byte and the two pixels
+        let idat = &png[33..];
+        assert_eq!(&idat[..8], b"\0\0\0\x12IDAT");
+        let raw = [0, 0x11, 0x22, 0x33, 0xff, 0xff, 0xff];
+        assert_eq!(&idat[8..15], [0x78, 0x01, 1, 7, 0, 0xf8, 0xff]);
+        assert_eq!(&idat[15..22], raw);
+        assert_eq!(&idat[22..26], adler32(&raw).to_be_bytes());
+        assert!(png.ends_with(b"\0\0\0\0IEND\xae\x42\x60\x82"));
+
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn hashes_size_and_pixels() {
+        let a = VideoFrame::black(2, 1);
+        assert_eq!(a.hash(), VideoFrame::black(2, 1).hash());
+        assert_ne!(a.hash(), VideoFrame::black(1, 2).hash());
+        let mut b = a.clone();
+        b.pixels[1] = 1;
+        assert_ne!(a.hash(), b.hash());
+    }
+}
@@ -0,0 +1,117 @@
+//! Stands in for the renderer in a core built without the `gui` feature.
+//! There's no way to create one, so the GPU always runs headless and none
+//! of these methods can be called.
+
+use crate::hw::gpu::latency::LatencyHistogram;
+use crate::hw::gpu::packet::Polygon;
+use crate::hw::gpu::postprocess::{FrameBlend, Pass};
+use crate::hw::gpu::types::{ColorProfile, DisplayArea, Hotkey, WindowGeometry};
+use crate::hw::joy_mc::Rumble;
+
+pub enum Renderer {}
+
+impl Renderer {
+    pub fn push_polygon(&mut self, _: &Polygon) {
+        match *self {}
+    }
+
+    pub fn take_latency(&mut self) -> LatencyHistogram {
+        match *self {}
+    }
+
+    pub fn set_skipped_field(&mut self, _: Option<i32>) {
+        match *self {}
+    }
+
+    pub fn write_vram(&mut self, _: u16, _: &[u16]) {
+        match *self {}
+    }
+
+    pub fn write_texels(&mut self, _: u16, _: &[u16]) {
+        match *self {}
+    }
+
+    pub fn draw(&mut self, _: DisplayArea) {
+        match *self {}
+    }
+
+    pub fn poll_hotkeys(&mut self, _: &mut Vec<Hotkey>) {
+        match *self {}
+    }
+
+    pub fn pad_buttons(&self) -> u16 {
+        match *self {}
+    }
+
+    pub fn pad_axes(&self) -> [u8; 4] {
+        match *self {}
+    }
+
+    pub fn set_rumble(&mut self, _: Rumble) {
+        match *self {}
+    }
+
+    pub fn set_crosshair(&mut self, _: bool) {
+        match *self {}
+    }
+
+    pub fn toggle_calibration(&mut self) -> bool {
+        match *self {}
+    }
+
+    pub fn show_rates(&mut self, _: f64, _: f64, _: bool) {
+        match *self {}
+    }
+
+    pub fn set_input_display(&mut self, _: bool) {
+        match *self {}
+    }
+
+    pub fn input_display(&self) -> bool {
+        match *self {}
+    }
+
+    pub fn read_scene(&mut self) -> Vec<u8> {
+        match *self {}
+    }
+
+    pub fn write_scene(&mut self, _: &[u8]) {
+        match *self {}
+    }
+
+    pub fn window_geometry(&self) -> WindowGeometry {
+        match *self {}
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        match *self {}
+    }
+
+    pub fn context_lost(&self) -> bool {
+        match *self {}
+    }
+
+    pub fn recreate(self) -> Renderer {
+        match self {}
+    }
+
+    pub fn set_post_processing(&mut self, _: &[Pass]) {
+        match *self {}
+    }
+
+    pub fn set_frame_blend(&mut self, _: FrameBlend) {
+        match *self {}
+    }
+
+    pub fn set_draw_offset(&mut self, _: i16, _: i16) {
+        match *self {}
+    }
+
+    pub fn set_color_profile(&mut self, _: ColorProfile) {
+        match *self {}
+    }
+
+    pub fn set_drawing_area(&mut self, _: u16, _: u16, _: u16, _: u16) {
+        match *self {}
+    }
+}
@@ -0,0 +1,376 @@
+//! The pad, played with the keyboard or a game controller. Both drive the
+//! same pad: a button is reported as pressed while any of its keys or
+//! controller buttons is held. The sticks and the rumble motors need a
+//! controller.
+
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::GameControllerSubsystem;
+
+use crate::hw::joy_mc::Rumble;
+
+/// Bits of the pad buttons, in the order the pad sends them
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PadButton {
+    Select = 0,
+    L3 = 1,
+    R3 = 2,
+    Start = 3,
+    Up = 4,
+    Right = 5,
+    Down = 6,
+    Left = 7,
+    L2 = 8,
+    R2 = 9,
+    L1 = 10,
+    R1 = 11,
+    Triangle = 12,
+    Circle = 13,
+    Cross = 14,
+    Square = 15,
+}
+
+impl PadButton {
+    pub const ALL: [PadButton; 16] = [
+        PadButton::Select,
+        PadButton::L3,
+        PadButton::R3,
+        PadButton::Start,
+        PadButton::Up,
+        PadButton::Right,
+        PadButton::Down,
+        PadButton::Left,
+        PadButton::L2,
+        PadButton::R2,
+        PadButton::L1,
+        PadButton::R1,
+        PadButton::Triangle,
+        PadButton::Circle,
+        PadButton::Cross,
+        PadButton::Square,
+    ];
+
+    /// Whether the button is pressed in `buttons`, as sent by the pad
+    pub fn pressed(self, buttons: u16) -> bool {
+        buttons & (1 << self as u16) == 0
+    }
+
+    /// Where the input display draws the button: x, y, width and height,
+    /// in cells of a `INPUT_DISPLAY_CELLS` grid from the bottom left
+    pub fn input_display_cells(self) -> (i32, i32, i32, i32) {
+        match self {
+            PadButton::L2 => (0, 6, 3, 1),
+            PadButton::L1 => (0, 5, 3, 1),
+            PadButton::R2 => (12, 6, 3, 1),
+            PadButton::R1 => (12, 5, 3, 1),
+            PadButton::Up => (1, 3, 1, 1),
+            PadButton::Left => (0, 2, 1, 1),
+            PadButton::Right => (2, 2, 1, 1),
+            PadButton::Down => (1, 1, 1, 1),
+            PadButton::Select => (5, 2, 2, 1),
+            PadButton::Start => (8, 2, 2, 1),
+            PadButton::L3 => (4, 0, 2, 1),
+            PadButton::R3 => (9, 0, 2, 1),
+            PadButton::Triangle => (13, 3, 1, 1),
+            PadButton::Square => (12, 2, 1, 1),
+            PadButton::Circle => (14, 2, 1, 1),
+            PadButton::Cross => (13, 1, 1, 1),
+        }
+    }
+}
+
+/// Size of the input display, in cells
+pub const INPUT_DISPLAY_CELLS: (i32, i32) = (15, 7);
+/// Bottom left cell of the left and right sticks, which are 3 cells wide
+pub const INPUT_DISPLAY_STICKS: [(i32, i32); 2] = [(4, 3), (8, 3)];
+
+/// Triggers are axes, pressed past half of their travel
+const TRIGGER_THRESHOLD: i16 = 0x4000;
+/// Motors are kept running for this long, and refreshed every frame while
+/// the game wants them on. They stop by themselves if emulation does.
+const RUMBLE_MS: u32 = 100;
+
+/// Position of a stick axis as the pad sends it, 0x80 at the center
+fn stick_axis(value: i16) -> u8 {
+    ((value as i32 + 0x8000) >> 8) as u8
+}
+
+fn key_button(key: Keycode) -> Option<PadButton> {
+    let button = match key {
+        Keycode::Up => PadButton::Up,
+        Keycode::Down => PadButton::Down,
+        Keycode::Left => PadButton::Left,
+        Keycode::Right => PadButton::Right,
+        Keycode::Return => PadButton::Start,
+        Keycode::Backspace => PadButton::Select,
+        Keycode::W => PadButton::Triangle,
+        Keycode::D => PadButton::Circle,
+        Keycode::S => PadButton::Cross,
+        Keycode::A => PadButton::Square,
+        Keycode::Q => PadButton::L1,
+        Keycode::E => PadButton::R1,
+        Keycode::Num1 => PadButton::L2,
+        Keycode::Num3 => PadButton::R2,
+        _ => return None,
+    };
+    Some(button)
+}
+
+/// By position, A is the bottom face button like Cross
+fn controller_button(button: Button) -> Option<PadButton> {
+    let button = match button {
+        Button::DPadUp => PadButton::Up,
+        Button::DPadDown => PadButton::Down,
+        Button::DPadLeft => PadButton::Left,
+        Button::DPadRight => PadButton::Right,
+        Button::Start => PadButton::Start,
+        Button::Back => PadButton::Select,
+        Button::Y => PadButton::Triangle,
+        Button::B => PadButton::Circle,
+        Button::A => PadButton::Cross,
+        Button::X => PadButton::Square,
+        Button::LeftShoulder => PadButton::L1,
+        Button::RightShoulder => PadButton::R1,
+        Button::LeftStick => PadButton::L3,
+        Button::RightStick => PadButton::R3,
+        _ => return None,
+    };
+    Some(button)
+}
+
+pub struct Pad {
+    /// Keys and controller buttons held for each button
+    held: [u8; 16],
+    /// L2 and R2 pressed with the triggers
+    triggers: u16,
+    /// Right X, right Y, left X and left Y
+    axes: [u8; 4],
+    rumble: Rumble,
+    subsystem: Option<GameControllerSubsystem>,
+    /// Opened as they are plugged in, SDL reports those already present
+    /// at startup as plugged in too
+    controllers: Vec<GameController>,
+}
+
+impl Pad {
+    pub fn new(subsystem: Option<GameControllerSubsystem>) -> Pad {
+        Pad {
+            held: [0; 16],
+            triggers: 0,
+            axes: [0x80; 4],
+            rumble: Rumble::default(),
+            subsystem,
+            controllers: vec![],
+        }
+    }
+
+    /// Pressed buttons as 0, like the pad sends them
+    pub fn buttons(&self) -> u16 {
+        let held = self
+            .held
+            .iter()
+            .enumerate()
+            .fold(0, |buttons, (bit, &held)| buttons | ((held > 0) as u16) << bit);
+        !(held | self.triggers)
+    }
+
+    /// Stick axes, in the order the pad sends them
+    pub fn axes(&self) -> [u8; 4] {
+        self.axes
+    }
+
+    /// Runs the motors of the controllers. Called every frame.
+    pub fn set_rumble(&mut self, rumble: Rumble) {
+        if rumble.is_off() && self.rumble.is_off() {
+            return;
+        }
+        self.rumble = rumble;
+
+        // The large motor is the low frequency one
+        let low = rumble.large as u16 * 0x101;
+        let high = if rumble.small { 0xffff } else { 0 };
+        for controller in &mut self.controllers {
+            // Not every controller can rumble
+            let _ = controller.set_rumble(low, high, RUMBLE_MS);
+        }
+    }
+
+    fn press(&mut self, button: PadButton, pressed: bool) {
+        let held = &mut self.held[button as usize];
+        *held = if pressed {
+            held.saturating_add(1)
+        } else {
+            held.saturating_sub(1)
+        };
+    }
+
+    /// Updates the buttons from a keyboard or controller event. Key repeats
+    /// are ignored.
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::KeyDown {
+                keycode: Some(key),
+                repeat: false,
+                ..
+            } => {
+                if let Some(button) = key_button(key) {
+                    self.press(button, true);
+                }
+            }
+            Event::KeyUp {
+                keycode: Some(key), ..
+            } => {
+                if let Some(button) = key_button(key) {
+                    self.press(button, false);
+                }
+            }
+            Event::ControllerButtonDown { button, .. } => {
+                if let Some(button) = controller_button(button) {
+                    self.press(button, true);
+                }
+            }
+            Event::ControllerButtonUp { button, .. } => {
+                if let Some(button) = controller_button(button) {
+                    self.press(button, false);
+                }
+            }
+            Event::ControllerAxisMotion { axis, value, .. } => {
+                let button = match axis {
+                    Axis::TriggerLeft => PadButton::L2,
+                    Axis::TriggerRight => PadButton::R2,
+                    stick => {
+                        let index = match stick {
+                            Axis::RightX => 0,
+                            Axis::RightY => 1,
+                            Axis::LeftX => 2,
+                            _ => 3,
+                        };
+                        self.axes[index] = stick_axis(value);
+                        return;
+                    }
+                };
+                let bit = 1 << button as u16;
+                match value > TRIGGER_THRESHOLD {
+                    true => self.triggers |= bit,
+                    false => self.triggers &= !bit,
+                }
+            }
+            Event::ControllerDeviceAdded { which, .. } => {
+                if let Some(subsystem) = &self.subsystem {
+                    match subsystem.open(which) {
+                        Ok(controller) => {
+                            println!("[PAD] Controller connected: {}", controller.name());
+                            self.controllers.push(controller);
+                        }
+                        Err(err) => println!("[PAD] Could not open controller {}: {}", which, err),
+                    }
+                }
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.controllers.retain(|controller| controller.instance_id() != which);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdl2::keyboard::Mod;
+
+    fn key(keycode: Keycode, down: bool) -> Event {
+        match down {
+            true => Event::KeyDown {
+                timestamp: 0,
+                window_id: 0,
+                keycode: Some(keycode),
+                scancode: None,
+                keymod: Mod::NOMOD,
+                repeat: false,
+            },
+            false => Event::KeyUp {
+                timestamp: 0,
+                window_id: 0,
+                keycode: Some(keycode),
+                scancode: None,
+                keymod: Mod::NOMOD,
+                repeat: false,
+            },
+        }
+    }
+
+    #[test]
+    fn keys_and_buttons_press_the_pad() {
+        let mut pad = Pad::new(None);
+        assert_eq!(pad.buttons(), 0xffff);
+
+        pad.handle_event(&key(Keycode::S, true));
+        pad.handle_event(&key(Keycode::Return, true));
+        assert_eq!(pad.buttons(), !(1 << 14 | 1 << 3));
+
+        // Cross stays pressed while the controller holds it
+        let button = |button, down| match down {
+            true => Event::ControllerButtonDown {
+                timestamp: 0,
+                which: 0,
+                button,
+            },
+            false => Event::ControllerButtonUp {
+                timestamp: 0,
+                which: 0,
+                button,
+            },
+        };
+        pad.handle_event(&button(Button::A, true));
+        pad.handle_event(&key(Keycode::S, false));
+        pad.handle_event(&key(Keycode::Return, false));
+        assert_eq!(pad.buttons(), !(1 << 14));
+        pad.handle_event(&button(Button::A, false));
+        assert_eq!(pad.buttons(), 0xffff);
+
+        let trigger = |value| Event::ControllerAxisMotion {
+            timestamp: 0,
+            which: 0,
+            axis: Axis::TriggerRight,
+            value,
+        };
+        pad.handle_event(&trigger(0x5000));
+        pad.handle_event(&trigger(0x7fff));
+        assert_eq!(pad.buttons(), !(1 << 9));
+        pad.handle_event(&trigger(0));
+        assert_eq!(pad.buttons(), 0xffff);
+
+        let stick = |axis, value| Event::ControllerAxisMotion {
+            timestamp: 0,
+            which: 0,
+            axis,
+            value,
+        };
+        pad.handle_event(&stick(Axis::LeftX, -0x8000));
+        pad.handle_event(&stick(Axis::LeftY, 0x7fff));
+        pad.handle_event(&stick(Axis::RightX, 0));
+        assert_eq!(pad.axes(), [0x80, 0x80, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn input_display_cells_do_not_overlap() {
+        let (columns, rows) = INPUT_DISPLAY_CELLS;
+        let mut cells = vec![None; (columns * rows) as usize];
+        let sticks = INPUT_DISPLAY_STICKS.map(|(x, y)| (x, y, 3, 3));
+        let buttons = PadButton::ALL.map(|button| button.input_display_cells());
+        for (x, y, width, height) in sticks.into_iter().chain(buttons) {
+            assert!(x >= 0 && y >= 0 && x + width <= columns && y + height <= rows);
+            for cell in (y..y + height).flat_map(|y| (x..x + width).map(move |x| y * columns + x)) {
+                assert_eq!(cells[cell as usize], None, "{:?} overlaps", (x, y));
+                cells[cell as usize] = Some((x, y));
+            }
+        }
+
+        for button in PadButton::ALL {
+            assert!(button.pressed(!(1 << button as u16)));
+            assert!(!button.pressed(0xffff));
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! How long primitives wait in the renderer's vertex buffers: from the
+//! first one of a batch being queued to the batch being drawn. Batches
+//! that grow too long make the picture lag behind the emulation.
+
+use std::fmt;
+use std::time::Duration;
+
+/// Upper bounds of the buckets in microseconds, the last one takes the rest
+const BUCKET_LIMITS: [u64; 7] = [16, 64, 256, 1_000, 4_000, 16_000, 64_000];
+const BAR_WIDTH: u64 = 30;
+
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKET_LIMITS.len() + 1],
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        let bucket = BUCKET_LIMITS
+            .iter()
+            .position(|&limit| micros < limit)
+            .unwrap_or(BUCKET_LIMITS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+}
+
+fn bucket_name(micros: u64) -> String {
+    match micros {
+        0..=999 => format!("{}us", micros),
+        _ => format!("{}ms", micros / 1000),
+    }
+}
+
+/// For the status panel: one line per bucket, with a bar
+impl fmt::Display for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.count == 0 {
+            return writeln!(f, "Render latency: nothing drawn");
+        }
+
+        writeln!(
+            f,
+            "Render latency: {} batches, mean {:.2} ms, max {:.2} ms",
+            self.count,
+            self.total.as_secs_f64() * 1000.0 / self.count as f64,
+            self.max.as_secs_f64() * 1000.0,
+        )?;
+        let highest = self.buckets.iter().copied().max().unwrap_or(0).max(1);
+        for (n, &count) in self.buckets.iter().enumerate() {
+            let name = match BUCKET_LIMITS.get(n) {
+                Some(&limit) => format!("<{}", bucket_name(limit)),
+                None => format!(">={}", bucket_name(BUCKET_LIMITS[n - 1])),
+            };
+            let bar = "#".repeat((count * BAR_WIDTH).div_ceil(highest) as usize);
+            writeln!(f, "  {:>7} {:<30} {}", name, bar, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latencies_go_to_their_bucket() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.to_string(), "Render latency: nothing drawn\n");
+
+        for micros in [10, 15, 16, 900, 2_000, 100_000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.buckets, [2, 1, 0, 1, 1, 0, 0, 1]);
+        assert_eq!(histogram.count, 6);
+
+        let text = histogram.to_string();
+        assert!(text.starts_with("Render latency: 6 batches, mean 17.16 ms, max 100.00 ms\n"));
+        assert!(text.contains(&format!("    <16us {} 2\n", "#".repeat(30))));
+        assert!(text.contains(&format!("     <1ms {:<30} 1\n", "#".repeat(15))));
+        assert!(text.contains("   >=64ms "));
+    }
+}
@@ -1,13 +1,66 @@
+mod accuracy;
+#[cfg(feature = "gui")]
+mod crosshair;
+mod fifo;
+mod frame;
+#[cfg(not(feature = "gui"))]
+mod headless;
+#[cfg(feature = "gui")]
+mod input;
+mod latency;
+mod packet;
+pub mod postprocess;
+mod raster;
+#[cfg(feature = "gui")]
 mod renderer;
+#[cfg(feature = "gui")]
 mod shaders;
+mod snapshot;
+mod timing;
+mod types;
 
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::rc::Weak;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
 use bitfield::bitfield;
-use renderer::{Color, Position, Renderer};
+use fifo::CommandFifo;
+use raster::{Area, DrawState};
+#[cfg(feature = "gui")]
+use renderer::Renderer;
+#[cfg(not(feature = "gui"))]
+use headless::Renderer;
+use packet::{Line, Polygon, Rect, Transfer};
+use types::{Color, Texture, VRAM_HEIGHT, VRAM_WIDTH};
+use timing::{HorizontalRes, VideoTiming};
+
+pub use types::{
+    ColorProfile, DisplayArea, GpuPreference, Hotkey, RendererOptions, WindowGeometry,
+};
+pub use accuracy::{GpuAccuracy, GpuAccuracyConfig};
+pub use frame::VideoFrame;
+pub use latency::LatencyHistogram;
+pub use snapshot::{DisplayCommand, GpuState, GpuStateHandle};
+
+use crustationcpu::state::{Savestate, StateError, StateReader, StateWriter};
+use crustationcpu::CpuCommand;
 
 use crate::hw::bus::{Bus, BusDevice, PsxEventType};
+use crate::hw::irq::Irq;
+use crate::hw::joy_mc::Rumble;
+
+/// Longest command accepted before deciding that the FIFO is stuck, e.g. a
+/// polyline whose terminator was lost
+const MAX_COMMAND_WORDS: usize = 1024;
+/// Frames a command may stay incomplete before the FIFO is considered stuck
+const MAX_STALLED_FRAMES: u32 = 60;
+/// Unknown commands reported per frame, the others are only counted
+const MAX_UNKNOWN_WARNINGS: u32 = 4;
+/// Display commands logged per frame, the others are dropped
+const MAX_DISPLAY_COMMANDS: usize = 64;
 
 bitfield! {
     struct GpuStat(u32);
@@ -36,16 +89,38 @@ bitfield! {
     pub ready_for_command, _: 26;
     pub ready_to_send, _: 27;
     pub ready_to_receive, _: 28;
-    pub dma_direction, _: 30, 29;
+    pub dma_direction, set_dma_direction: 30, 29;
     pub even_odd, set_even_odd: 31;
 }
 
+/// Host time spent by the GPU, measured for benchmarks
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GpuProfile {
+    /// Handling GP0 words, drawing included
+    pub commands: Duration,
+    /// Drawing to VRAM in the software rasterizer
+    pub raster: Duration,
+}
+
 pub struct Gpu {
     renderer: Option<Renderer>,
 
     gpustat: GpuStat,
     buffer: Vec<u32>,
     remaining_words: usize,
+    /// VRAM, drawn to by the software rasterizer. Transfers and reads work
+    /// on it, the renderer gets the lines they change, and the drawn lines
+    /// once they are used as a texture.
+    vram: Vec<u16>,
+    /// Part of VRAM drawn by the rasterizer since it was last sent to the
+    /// renderer, whose textures would miss it
+    drawn: Option<Area>,
+    /// Words of a VRAM to CPU copy not read from GPUREAD yet
+    readback: VecDeque<u32>,
+    /// Last word read from GPUREAD, returned again once the copy is over
+    gpuread: u32,
+    /// GP0 words written by the CPU while the GPU is busy drawing
+    fifo: CommandFifo,
 
     /// Left-most column of drawing area
     drawing_area_left: u16,
@@ -58,9 +133,41 @@ pub struct Gpu {
     /// Drawing offset in the framebuffer
     drawing_offset: (i16, i16),
 
+    /// Top-left corner of the displayed area in VRAM, from GP1(05)
+    display_start: (u16, u16),
+    /// Displayed area for the current frame
+    display_area: DisplayArea,
+    /// Whether display changes wait for the next vblank, as on hardware
+    latch_display: bool,
+    accuracy: GpuAccuracyConfig,
+
     bus: Weak<RefCell<Bus>>,
 
-    set: bool,
+    /// Cycle count at the beginning of the last vblank
+    vblank_start: u64,
+    /// Timings of the frame in progress, mode changes apply from the next
+    timing: VideoTiming,
+    /// Horizontal blanks before `vblank_start`, since power on
+    hblanks: u64,
+
+    /// Consecutive vblanks with an incomplete command in the FIFO
+    stalled_frames: u32,
+    /// Stop the emulation when the FIFO gets stuck
+    break_on_hang: bool,
+    /// Unknown GP0 and GP1 commands received during this frame
+    unknown_commands: u32,
+    /// Stop the emulation on unknown commands
+    strict_commands: bool,
+
+    /// Pressed in the window, for the bus to handle
+    hotkeys: Vec<Hotkey>,
+
+    /// Decoded state as of the last VBlank, for other threads
+    published: GpuStateHandle,
+    /// Display commands received during this frame
+    display_commands: Vec<DisplayCommand>,
+    /// Measured when set, see `set_profiling`
+    profile: Option<GpuProfile>,
 }
 
 impl Gpu {
@@ -69,8 +176,14 @@ impl Gpu {
             renderer: None,
 
             gpustat: GpuStat(0x1480_2000),
-            buffer: vec![],
+            // Never grows past this, see process_gp0
+            buffer: Vec::with_capacity(MAX_COMMAND_WORDS + 1),
             remaining_words: 0,
+            vram: vec![0; VRAM_WIDTH * VRAM_HEIGHT],
+            drawn: None,
+            readback: VecDeque::new(),
+            gpuread: 0,
+            fifo: CommandFifo::new(),
 
             drawing_area_left: 0,
             drawing_area_top: 0,
@@ -78,111 +191,616 @@ impl Gpu {
             drawing_area_bottom: 0,
             drawing_offset: (0, 0),
 
+            display_start: (0, 0),
+            display_area: DisplayArea {
+                x: 0,
+                y: 0,
+                width: 256,
+                height: 240,
+            },
+            latch_display: true,
+            accuracy: GpuAccuracyConfig::default(),
+
             bus: Weak::new(),
 
-            set: false,
+            vblank_start: 0,
+            timing: VideoTiming {
+                pal: false,
+                interlaced: false,
+                odd_field: false,
+            },
+            hblanks: 0,
+
+            stalled_frames: 0,
+            break_on_hang: false,
+            unknown_commands: 0,
+            strict_commands: false,
+
+            hotkeys: vec![],
+
+            published: Arc::new(ArcSwap::from_pointee(GpuState::default())),
+            display_commands: vec![],
+            profile: None,
         }
     }
 
     pub fn link(&mut self, bus: Weak<RefCell<Bus>>) {
         self.bus = bus;
+
+        // The video timings run from power on, whether the GPU is in use
+        // or not
+        let bus = self.bus.upgrade().unwrap();
+        let bus = bus.borrow();
+        self.vblank_start = *bus.total_cycles.borrow();
+        bus.add_event(
+            PsxEventType::VBlank,
+            self.vblank_start + self.timing.frame_cycles(),
+            0,
+        );
+    }
+
+    #[cfg(feature = "gui")]
+    pub fn load_renderer(&mut self, options: &RendererOptions) {
+        self.renderer = Some(Renderer::new(options));
+    }
+
+    /// Measure the host time spent in commands and drawing, from zero
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profile = enabled.then(GpuProfile::default);
+    }
+
+    pub fn profile(&self) -> Option<GpuProfile> {
+        self.profile
+    }
+
+    /// How long primitives waited in the renderer since the last call,
+    /// None without a window
+    pub fn take_render_latency(&mut self) -> Option<LatencyHistogram> {
+        self.renderer.as_mut().map(Renderer::take_latency)
+    }
+
+    /// Closes the window, the GPU runs headless afterwards
+    pub fn close_renderer(&mut self) {
+        self.renderer = None;
+    }
+
+    pub fn set_color_profile(&mut self, profile: ColorProfile) {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.set_color_profile(profile);
+        }
+    }
+
+    pub fn set_post_processing(&mut self, passes: &[postprocess::Pass]) {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.set_post_processing(passes);
+        }
+    }
+
+    /// None when running headless
+    #[cfg(feature = "audio")]
+    pub fn audio_subsystem(&self) -> Option<Result<sdl2::AudioSubsystem, String>> {
+        self.renderer.as_ref().map(|renderer| renderer.audio_subsystem())
+    }
+
+    /// Window position and size, and whether it is fullscreen
+    pub fn window_state(&self) -> Option<(WindowGeometry, bool)> {
+        self.renderer
+            .as_ref()
+            .map(|renderer| (renderer.window_geometry(), renderer.is_fullscreen()))
     }
 
-    pub fn load_renderer(&mut self) {
-        self.renderer = Some(Renderer::new());
+    /// Loads the state published at the last VBlank, from any thread
+    pub fn state_handle(&self) -> GpuStateHandle {
+        self.published.clone()
+    }
+
+    /// The picture displayed at the last VBlank, from VRAM as it is now
+    pub fn video_frame(&self) -> VideoFrame {
+        let state = self.published.load();
+        let area = state.display_area;
+        if state.display_enabled {
+            VideoFrame::from_vram(&self.vram, area, state.color_24bit)
+        } else {
+            VideoFrame::black(area.width, area.height)
+        }
+    }
+
+    pub fn set_break_on_hang(&mut self, enabled: bool) {
+        self.break_on_hang = enabled;
+    }
+
+    pub fn set_strict_commands(&mut self, strict: bool) {
+        self.strict_commands = strict;
+    }
+
+    /// With `latch` false, display changes are shown on the frame they are
+    /// made in. Only useful for debugging.
+    pub fn set_latch_display(&mut self, latch: bool) {
+        self.latch_display = latch;
+    }
+
+    pub fn set_accuracy(&mut self, accuracy: GpuAccuracyConfig) {
+        self.accuracy = accuracy;
+    }
+
+    pub fn set_frame_blend(&mut self, frame_blend: postprocess::FrameBlend) {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.set_frame_blend(frame_blend);
+        }
+    }
+
+    /// Hotkeys pressed since the last call
+    pub fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        std::mem::take(&mut self.hotkeys)
+    }
+
+    /// Shows the mouse pointer as a light gun crosshair
+    pub fn set_crosshair(&mut self, shown: bool) {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.set_crosshair(shown);
+        }
+    }
+
+    /// Toggles the calibration mode, which shows the crosshair and prints
+    /// where each click lands on the display
+    pub fn toggle_calibration(&mut self) {
+        if let Some(renderer) = &mut self.renderer {
+            let calibrating = renderer.toggle_calibration();
+            println!(
+                "[GPU] Crosshair calibration {}",
+                if calibrating { "on" } else { "off" }
+            );
+        }
+    }
+
+    /// Shows the buttons of the pad over the picture, for streaming or to
+    /// check the input mapping
+    pub fn set_input_display(&mut self, shown: bool) {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.set_input_display(shown);
+        }
+    }
+
+    /// Length of the current frame, from this VBlank to the next
+    pub fn frame_cycles(&self) -> u64 {
+        self.timing.frame_cycles()
+    }
+
+    /// See `Renderer::show_rates`, nothing when headless
+    pub fn show_rates(&mut self, fps: f64, speed: f64, fast_forward: bool) {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.show_rates(fps, speed, fast_forward);
+        }
+    }
+
+    pub fn toggle_input_display(&mut self) {
+        if let Some(renderer) = &mut self.renderer {
+            let shown = !renderer.input_display();
+            renderer.set_input_display(shown);
+        }
+    }
+
+    /// Buttons of the pad played in the window, None when headless
+    pub fn pad_buttons(&self) -> Option<u16> {
+        self.renderer.as_ref().map(|renderer| renderer.pad_buttons())
+    }
+
+    /// Sticks of the pad played in the window, None when headless
+    pub fn pad_axes(&self) -> Option<[u8; 4]> {
+        self.renderer.as_ref().map(|renderer| renderer.pad_axes())
+    }
+
+    /// Runs the motors of the game controllers
+    pub fn set_rumble(&mut self, rumble: Rumble) {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.set_rumble(rumble);
+        }
     }
 }
 
 impl BusDevice for Gpu {
     fn write<const S: u32>(&mut self, addr: u32, value: u32) {
-        if !self.set {
-            let cpu_freq = 33868800;
-            let vblank_freq = 60;
-            let vblank_cycles = cpu_freq / vblank_freq;
-            self.bus
-                .upgrade()
-                .unwrap()
-                .borrow()
-                .add_event(PsxEventType::VBlank, 0, vblank_cycles);
-            self.set = true;
-        }
-
         if S != 4 {
             // println!("Unhandled {}-bytes GPU read", std::mem::size_of::<T>());
         }
 
         match addr {
-            0 => self.process_gp0(value),
+            0 => self.write_gp0(value),
             4 => self.process_gp1(value),
             _ => panic!("Invalid write to gpu"),
         }
     }
 
+    /// 8 and 16-bit reads return the addressed byte lanes of the register,
+    /// e.g. a halfword read of 0x1f801816 gets the top half of GPUSTAT.
     fn read<const S: u32>(&mut self, addr: u32) -> u32 {
-        if S != 4 {
-            // println!("Unhandled {}-bytes GPU read", std::mem::size_of::<T>());
-            return 0;
-        }
-
-        match addr {
+        let value = match addr & !3 {
             0 => {
                 // println!("Read GPUREAD");
-                0
+                // A copy to the CPU may still be waiting in the FIFO
+                self.run_fifo();
+                self.gpuread()
             }
             4 => {
                 // println!("Read GPUSTAT");
-                self.gpustat.0 | (1 << 27)
+                let line = self.current_line();
+                let mut stat = self.gpustat.0 & !0x9e00_0000;
+                stat |= self.fifo_status();
+                stat |= (self.even_odd(line) as u32) << 31;
+                stat
             }
             _ => panic!("Invalid read to gpu"),
+        };
+
+        let value = value >> ((addr & 3) * 8);
+        match S {
+            1 => value & 0xff,
+            2 => value & 0xffff,
+            4 => value,
+            _ => unreachable!(),
         }
     }
 }
 
 impl Gpu {
     pub fn vblank(&mut self) {
-        if !self.gpustat.vertical_res() {
-            // 240 lines
-            // println!("Don't know how to handle VBlank in 240 mode");
-            // self.gpustat.set_even_odd(!self.gpustat.even_odd());
-        } else {
-            // 480 lines
+        // Counted from when it was due, so that late events don't drift
+        self.hblanks += self.timing.lines_per_frame();
+        self.vblank_start += self.timing.frame_cycles();
+
+        if self.gpustat.vertical_res() {
+            // 480 lines: a new field is displayed every frame
             self.gpustat.set_even_odd(!self.gpustat.even_odd());
         }
 
+        self.timing = self.video_timing();
+        self.run_fifo();
+        if let Some(bus) = self.bus.upgrade() {
+            let next = self.vblank_start + self.timing.frame_cycles();
+            bus.borrow().add_event(PsxEventType::VBlank, next, 0);
+        }
+
+        if self.remaining_words == 0 {
+            self.stalled_frames = 0;
+        } else {
+            self.stalled_frames += 1;
+            if self.stalled_frames >= MAX_STALLED_FRAMES {
+                self.fifo_stuck("command incomplete for too long");
+            }
+        }
+
+        if self.unknown_commands > MAX_UNKNOWN_WARNINGS {
+            println!(
+                "[GPU] {} more unknown commands ignored this frame",
+                self.unknown_commands - MAX_UNKNOWN_WARNINGS
+            );
+        }
+        self.unknown_commands = 0;
+
         // println!("VSync");
         self.gpustat.set_irq(true);
-        self.bus.upgrade().unwrap().borrow().send_irq(0);
+        self.bus.upgrade().unwrap().borrow().send_irq(Irq::VBlank);
 
+        let area = if self.latch_display {
+            self.display_area
+        } else {
+            self.requested_display_area()
+        };
+        self.published.store(Arc::new(self.snapshot(area)));
+        self.display_commands.clear();
         if let Some(renderer) = &mut self.renderer {
-            renderer.draw();
+            renderer.draw(area);
+            renderer.poll_hotkeys(&mut self.hotkeys);
+
+            if renderer.context_lost() {
+                self.recover_renderer();
+            }
+        }
+
+        // Changes made during this frame are used from the next one
+        self.display_area = self.requested_display_area();
+    }
+
+    /// Starts over with a new renderer after a driver reset. Until the game
+    /// redraws it, the content of VRAM is lost.
+    fn recover_renderer(&mut self) {
+        println!("[GPU] Graphics context lost, recreating the renderer");
+
+        self.renderer = self.renderer.take().map(Renderer::recreate);
+
+        self.update_drawing_area();
+        let (x, y) = self.drawing_offset;
+        if let Some(renderer) = &mut self.renderer {
+            renderer.set_draw_offset(x, y);
+        }
+        self.write_vram_lines(0, 512);
+    }
+
+    /// The decoded GPUSTAT and drawing settings, with `area` displayed
+    fn snapshot(&self, area: DisplayArea) -> GpuState {
+        GpuState {
+            display_area: area,
+            display_enabled: !self.gpustat.display_enable(),
+            interlaced: self.gpustat.vertical_interlace(),
+            pal: self.gpustat.video_mode(),
+            color_24bit: self.gpustat.color_depth(),
+
+            texture_page: (
+                self.gpustat.texture_page_x_base() as u16 * 64,
+                self.gpustat.texture_page_y_base() as u16 * 256,
+            ),
+            texture_bits: match self.gpustat.texture_page_colors() {
+                0 => 4,
+                1 => 8,
+                _ => 15,
+            },
+
+            drawing_area: (
+                self.drawing_area_left,
+                self.drawing_area_top,
+                self.drawing_area_right,
+                self.drawing_area_bottom,
+            ),
+            drawing_offset: self.drawing_offset,
+
+            display_commands: self.display_commands.clone(),
+        }
+    }
+
+    /// Displayed area according to the last GP1(05) and GP1(08)
+    fn requested_display_area(&self) -> DisplayArea {
+        let width = self.horizontal_res().width();
+
+        let height = if self.gpustat.vertical_res() && self.gpustat.vertical_interlace() {
+            480
+        } else {
+            240
+        };
+
+        DisplayArea {
+            x: self.display_start.0,
+            y: self.display_start.1,
+            width,
+            height,
+        }
+    }
+
+    /// Timings for the mode set in GPUSTAT
+    fn video_timing(&self) -> VideoTiming {
+        VideoTiming {
+            pal: self.gpustat.video_mode(),
+            interlaced: self.gpustat.vertical_interlace(),
+            odd_field: self.gpustat.even_odd(),
+        }
+    }
+
+    /// CPU cycles since the beginning of the last vblank
+    fn cycles_since_vblank(&self) -> u64 {
+        match self.bus.upgrade() {
+            Some(bus) => bus.borrow().total_cycles.borrow().saturating_sub(self.vblank_start),
+            None => 0,
+        }
+    }
+
+    /// Scanline being displayed right now, counting from the first visible
+    /// one. Lines from `visible_lines()` onwards are in the vertical blank.
+    fn current_line(&self) -> u64 {
+        self.timing.line_at(self.cycles_since_vblank())
+    }
+
+    /// Horizontal blanks since power on, the clock of timer 1
+    pub fn hblanks(&self) -> u64 {
+        let lines = self.timing.lines_after(self.cycles_since_vblank());
+        self.hblanks + lines.min(self.timing.lines_per_frame())
+    }
+
+    /// Pixels output since power on, the clock of timer 0. Changes of
+    /// resolution are applied as if they had always been in effect.
+    pub fn dots(&self, cycles: u64) -> u64 {
+        timing::gpu_ticks(cycles) / self.horizontal_res().dot_divider()
+    }
+
+    /// CPU cycles from now until `n` more horizontal blanks
+    pub fn hblank_cycles(&self, n: u64) -> u64 {
+        let since_vblank = self.cycles_since_vblank();
+        let line = self.timing.lines_after(since_vblank) + n;
+        timing::cpu_cycles(line * self.timing.ticks_per_line()).saturating_sub(since_vblank)
+    }
+
+    /// CPU cycles from `cycles` until `n` more pixels are output
+    pub fn dot_cycles(&self, cycles: u64, n: u64) -> u64 {
+        let divider = self.horizontal_res().dot_divider();
+        timing::cpu_cycles((self.dots(cycles) + n) * divider).saturating_sub(cycles)
+    }
+
+    fn horizontal_res(&self) -> HorizontalRes {
+        HorizontalRes::from_gpustat(self.gpustat.horizontal_res2(), self.gpustat.horizontal_res1())
+    }
+
+    /// Value of GPUSTAT.b31 while `line` is being displayed. In 480-line
+    /// mode it tells which field is displayed, and flips every frame. In
+    /// 240-line mode it follows the parity of the current scanline, so it
+    /// toggles on every line (and from one frame to the next, as there is an
+    /// odd number of lines per frame). It always reads 0 during vblank.
+    fn even_odd(&self, line: u64) -> bool {
+        if line >= self.timing.visible_lines() {
+            false
+        } else if self.gpustat.vertical_res() {
+            self.gpustat.even_odd()
+        } else {
+            line & 1 != 0
+        }
+    }
+
+    /// Recovers from a malformed command that keeps swallowing the words
+    /// sent to GP0, as a GP1(01) would
+    fn fifo_stuck(&mut self, reason: &str) {
+        println!(
+            "[GPU] FIFO stuck, {}: command {:08x}, {} words received. Clearing it.",
+            reason,
+            self.buffer[0],
+            self.buffer.len()
+        );
+
+        self.buffer.clear();
+        self.remaining_words = 0;
+        self.stalled_frames = 0;
+
+        if self.break_on_hang {
+            self.break_cpu();
+        }
+    }
+
+    /// Games send garbage during transitions, e.g. while a DMA linked list
+    /// is half rebuilt. Those commands are ignored, and reported up to
+    /// `MAX_UNKNOWN_WARNINGS` times per frame.
+    fn unknown_command(&mut self, port: &str, command: u32) {
+        self.unknown_commands += 1;
+        if self.unknown_commands <= MAX_UNKNOWN_WARNINGS {
+            println!("[GPU] Ignoring unknown {} command {:08x}", port, command);
+        }
+
+        if self.strict_commands {
+            self.break_cpu();
+        }
+    }
+
+    /// Drops into the debugger
+    fn break_cpu(&self) {
+        if let Some(bus) = self.bus.upgrade() {
+            bus.borrow().send_command(CpuCommand::Debug);
+        }
+    }
+
+    /// Pops the next word of a VRAM to CPU copy. Once the copy is over, the
+    /// last word is read again.
+    fn gpuread(&mut self) -> u32 {
+        if let Some(word) = self.readback.pop_front() {
+            self.gpuread = word;
+        }
+        self.gpuread
+    }
+
+    /// CPU cycles since power on, None when not on a bus
+    fn clock(&self) -> Option<u64> {
+        self.bus.upgrade().map(|bus| *bus.borrow().total_cycles.borrow())
+    }
+
+    /// GP0 words written by the CPU. They wait in the FIFO while the GPU
+    /// is busy, and are executed as soon as it's done.
+    fn write_gp0(&mut self, word: u32) {
+        let Some(now) = self.clock() else {
+            return self.process_gp0(word);
+        };
+
+        self.run_fifo();
+        if self.fifo.idle(now) {
+            self.fifo.start(now);
+            self.process_gp0(word);
+        } else if let Some(oldest) = self.fifo.push(word) {
+            self.process_gp0(oldest);
+        }
+    }
+
+    /// Executes the words of the FIFO that the GPU got to by now
+    fn run_fifo(&mut self) {
+        let now = self.clock();
+        while let Some(word) = self.fifo.pop(now) {
+            self.process_gp0(word);
+        }
+    }
+
+    /// GPUSTAT bits 25-28, from the FIFO and the command in progress
+    fn fifo_status(&mut self) -> u32 {
+        self.run_fifo();
+        let idle = self.clock().is_none_or(|now| self.fifo.idle(now));
+
+        // Not while a command is partially received, uploads included
+        let ready_for_command = idle && self.remaining_words == 0;
+        let ready_to_send = !self.readback.is_empty();
+        let ready_to_receive = !self.fifo.is_full();
+        // GP1(04) picks what DMA requests follow
+        let dma_request = match self.gpustat.dma_direction() {
+            0 => false,
+            1 | 2 => ready_to_receive,
+            _ => ready_to_send,
+        };
+
+        (dma_request as u32) << 25
+            | (ready_for_command as u32) << 26
+            | (ready_to_send as u32) << 27
+            | (ready_to_receive as u32) << 28
+    }
+
+    /// GP0 words sent by DMA channel 2, in one burst. The transfer waits
+    /// for the words already in the FIFO to be taken.
+    pub fn dma_write(&mut self, words: &[u32]) {
+        while let Some(word) = self.fifo.pop(None) {
+            self.process_gp0(word);
+        }
+        if let Some(now) = self.clock() {
+            self.fifo.start(now);
+        }
+
+        for &word in words {
+            self.process_gp0(word);
+        }
+    }
+
+    /// GPUREAD words requested by DMA channel 2, in one burst
+    pub fn dma_read(&mut self, words: &mut [u32]) {
+        for word in words {
+            *word = self.gpuread();
         }
     }
 
     pub fn process_gp0(&mut self, command: u32) {
+        let start = self.profile.is_some().then(Instant::now);
+        self.execute_gp0(command);
+        if let (Some(start), Some(profile)) = (start, &mut self.profile) {
+            profile.commands += start.elapsed();
+        }
+    }
+
+    fn execute_gp0(&mut self, command: u32) {
         // println!("[GP0] {:08x}", command);
 
+        if self.uploading() {
+            // Image data goes straight to VRAM, only the 3 header words
+            // are buffered
+            self.upload_word(command);
+            return;
+        }
+
         self.buffer.push(command);
 
+        if self.buffer.len() > MAX_COMMAND_WORDS {
+            self.fifo_stuck("command too long");
+            return;
+        }
+
         if self.remaining_words == 0 {
             // First command in a possible list
             let opcode = command >> 24;
             self.remaining_words = match opcode {
                 0x68 | 0x6a | 0x70 | 0x72 | 0x78 | 0x7a => 1,
                 0x02 | 0x60 | 0x62 | 0x6c | 0x6d | 0x6e | 0x6f | 0x74 | 0x75 | 0x76 | 0x77
-                | 0x7c | 0x7d | 0x7e | 0x7f | 0xa0 | 0xc0 => 2,
+                | 0x7c | 0x7d | 0x7e | 0x7f | 0xa0 | 0xc0..=0xdf => 2,
                 0x20 | 0x22 | 0x64 | 0x65 | 0x66 | 0x67 | 0x80 => 3,
                 0x28 | 0x2a => 4,
                 0x30 | 0x32 => 5,
                 0x24 | 0x25 | 0x26 | 0x27 => 6,
                 0x38 | 0x3a => 7,
-                0x2c | 0x2d | 0x2e | 0x2f | 0x34 | 0x36 => 8,
+                0x2c | 0x2d | 0x2e | 0x2f | 0x34..=0x37 => 8,
                 0x3c | 0x3e => 11,
-                0x40 | 0x42 | 0x48 | 0x4a | 0x50 | 0x52 | 0x58 | 0x5a => 0x55555555,
+                0x40 | 0x42 => 2,
+                0x50 | 0x52 => 3,
+                0x48 | 0x4a | 0x58 | 0x5a => 0x55555555,
                 _ => 0,
             };
         } else if self.remaining_words == 0x5555_5555 {
             // List terminator
-            if command == 0x5555_5555 {
+            if packet::terminator(command) {
                 self.remaining_words = 0
             }
         } else {
@@ -199,58 +817,11 @@ impl Gpu {
                 0x02 => self.gp0_02_fill_rectangle(),
                 0x03 => self.gp0_03_nop2(),
                 0x1f => self.gp0_1f_interrupt_request(),
-                0x20 => self.gp0_20_mono_triangle(),
-                0x22 => self.gp0_22_mono_triangle_alpha(),
-                0x24 => self.gp0_24_triangle_texture_blended(),
-                0x25 => self.gp0_25_triangle_texture_raw(),
-                0x26 => self.gp0_26_triangle_alpha_texture_blended(),
-                0x27 => self.gp0_27_triangle_alpha_texture_raw(),
-                0x28 => self.gp0_28_mono_square(),
-                0x2a => self.gp0_2a_mono_square_alpha(),
-                0x2c => self.gp0_2c_square_texture_blended(),
-                0x2d => self.gp0_2d_square_texture_raw(),
-                0x2e => self.gp0_2e_square_alpha_texture_blended(),
-                0x2f => self.gp0_2f_square_alpha_texture_raw(),
-                0x30 => self.gp0_30_shaded_triangle(),
-                0x32 => self.gp0_32_shaded_triangle_alpha(),
-                0x34 => self.gp0_34_shaded_textured_triangle_blend(),
-                0x36 => self.gp0_36_shaded_textured_triangle_alpha_blend(),
-                0x38 => self.gp0_38_shaded_square(),
-                0x3a => self.gp0_3a_shaded_square_alpha(),
-                0x3c => self.gp0_3c_shaded_textured_square_blend(),
-                0x3e => self.gp0_3e_shaded_textured_square_alpha_blend(),
-                0x40 => self.gp0_40_mono_line(),
-                0x42 => self.gp0_42_mono_line_alpha(),
-                0x48 => self.gp0_48_mono_polyline(),
-                0x4a => self.gp0_4a_mono_polyline_alpha(),
-                0x50 => self.gp0_50_shaded_line(),
-                0x52 => self.gp0_52_shaded_line_alpha(),
-                0x58 => self.gp0_58_shaded_polyline(),
-                0x5a => self.gp0_5a_shaded_polyline_alpha(),
-                0x60 => self.gp0_60_mono_rectangle(),
-                0x62 => self.gp0_62_mono_rectangle_alpha(),
-                0x68 => self.gp0_68_mono_rectangle_dot(),
-                0x6a => self.gp0_6a_mono_rectangle_dot_alpha(),
-                0x70 => self.gp0_70_mono_rectangle_8(),
-                0x72 => self.gp0_72_mono_rectangle_8_alpha(),
-                0x78 => self.gp0_78_mono_rectangle_16(),
-                0x7a => self.gp0_7a_mono_rectangle_16_alpha(),
-                0x64 => self.gp0_64_textured_rectangle_blend(),
-                0x65 => self.gp0_65_textured_rectangle_raw(),
-                0x66 => self.gp0_66_textured_rectangle_alpha_blend(),
-                0x67 => self.gp0_67_textured_rectangle_alpha_raw(),
-                0x6c => self.gp0_6c_textured_rectangle_dot_blend(),
-                0x6d => self.gp0_6d_textured_rectangle_dot_raw(),
-                0x6e => self.gp0_6e_textured_rectangle_dot_alpha_blend(),
-                0x6f => self.gp0_6f_textured_rectangle_dot_alpha_raw(),
-                0x74 => self.gp0_74_textured_rectangle_8_blend(),
-                0x75 => self.gp0_75_textured_rectangle_8_raw(),
-                0x76 => self.gp0_76_textured_rectangle_8_alpha_blend(),
-                0x77 => self.gp0_77_textured_rectangle_8_alpha_raw(),
-                0x7c => self.gp0_7c_textured_rectangle_16_blend(),
-                0x7d => self.gp0_7d_textured_rectangle_16_raw(),
-                0x7e => self.gp0_7e_textured_rectangle_16_alpha_blend(),
-                0x7f => self.gp0_7f_textured_rectangle_16_alpha_raw(),
+                0x20 | 0x22 | 0x24..=0x28 | 0x2a | 0x2c..=0x30 | 0x32 | 0x34..=0x38 | 0x3a | 0x3c
+                | 0x3e => self.gp0_20_polygon(),
+                0x40 | 0x42 | 0x48 | 0x4a | 0x50 | 0x52 | 0x58 | 0x5a => self.gp0_40_line(),
+                0x60 | 0x62 | 0x64..=0x68 | 0x6a | 0x6c..=0x70 | 0x72 | 0x74..=0x78 | 0x7a
+                | 0x7c..=0x7f => self.gp0_60_rectangle(),
                 0x80..=0x9f => self.gp0_80_copy_vram_vram(),
                 0xa0..=0xbf => self.gp0_a0_copy_cpu_vram(),
                 0xc0..=0xdf => self.gp0_c0_copy_vram_cpu(),
@@ -266,8 +837,6 @@ impl Gpu {
                 | 0x2b
                 | 0x31
                 | 0x33
-                | 0x35
-                | 0x37
                 | 0x39
                 | 0x3b
                 | 0x3d
@@ -288,9 +857,7 @@ impl Gpu {
                 | 0x73
                 | 0x79
                 | 0x7b
-                | 0xf0..=0xff => {
-                    // println!("[GPU] GP0({:02x}): unknown/garbage", opcode);
-                }
+                | 0xf0..=0xff => self.unknown_command("GP0", command),
             }
 
             if !(0xa0..=0xbf).contains(&opcode) {
@@ -299,458 +866,288 @@ impl Gpu {
         }
     }
 
-    // also GP0(04..=1E, E0, E7..=EF)
-    fn gp0_00_nop(&mut self) {
-        // println!("[GPU] GP0(00): Nop");
-    }
-
-    fn gp0_01_clear_cache(&mut self) {
-        // flush texture cache?
-        // println!("[GPU] GP0(01): Flush texture cache");
-    }
-
-    // +2
-    fn gp0_02_fill_rectangle(&mut self) {
-        let color_bgr24 = self.buffer[0] & 0xff_ffff;
-        let top_left_x = self.buffer[1] & 0xffff;
-        let top_left_y = self.buffer[1] >> 16;
-        let width = self.buffer[2] & 0xffff;
-        let height = self.buffer[2] >> 16;
-
-        println!("[GPU] GP0(02): Fill rectangle from ({}, {}) with size {}x{} with BGR {:06x}",
-            top_left_x, top_left_y,
-            width, height,
-            color_bgr24);
-    }
-
-    fn gp0_03_nop2(&mut self) {
-        // println!("[GPU] GP0(03): Unknown (nop?)");
-    }
-
-    fn gp0_1f_interrupt_request(&mut self) {
-        // println!("[GPU] GP0(1F): Interrupt request");
-    }
-
-    // +3
-    fn gp0_20_mono_triangle(&mut self) {
-        println!("[GPU] GP0(20): mono_triangle");
-
-        let vertices = [
-            Position::parse(self.buffer[1]),
-            Position::parse(self.buffer[2]),
-            Position::parse(self.buffer[3]),
-        ];
-
-        let colors = [
-            Color::parse(self.buffer[0]),
-            Color::parse(self.buffer[0]),
-            Color::parse(self.buffer[0]),
-        ];
-
-        println!("Triangle at {:?} with colors {:?}", vertices, colors);
+    /// Stores the next two pixels of a CPU to VRAM copy. The image wraps
+    /// around the edges of VRAM.
+    fn upload_word(&mut self, word: u32) {
+        let Transfer { x, y, width, height } = Transfer::parse(self.buffer[1], self.buffer[2]);
+        let size = width * height;
+        let first = (size.div_ceil(2) - self.remaining_words as u32) * 2;
 
-        if let Some(renderer) = &mut self.renderer {
-            renderer.push_triangle(vertices, colors);
+        // The mask bit settings apply to uploads too
+        let state = self.draw_state();
+        for (i, pixel) in [(first, word as u16), (first + 1, (word >> 16) as u16)] {
+            if i < size {
+                state.write(&mut self.vram, x + i % width, y + i / width, pixel);
+            }
         }
-    }
-
-    // 21 garbage
-
-    // +3
-    fn gp0_22_mono_triangle_alpha(&mut self) {
-        // println!("[GPU] GP0(22): mono_triangle_alpha");
-    }
 
-    // 23 garbage
-
-    // +6
-    fn gp0_24_triangle_texture_blended(&mut self) {
-        // println!("[GPU] GP0(24): triangle_texture_blended");
-    }
-
-    // +6
-    fn gp0_25_triangle_texture_raw(&mut self) {
-        // println!("[GPU] GP0(25): triangle_texture_raw");
-    }
-
-    // +6
-    fn gp0_26_triangle_alpha_texture_blended(&mut self) {
-        // println!("[GPU] GP0(26): triangle_alpha_texture_blended");
-    }
-
-    // +6
-    fn gp0_27_triangle_alpha_texture_raw(&mut self) {
-        // println!("[GPU] GP0(27): triangle_alpha_texture_raw");
-    }
-
-    // +4
-    fn gp0_28_mono_square(&mut self) {
-        // println!("[GPU] GP0(28): mono_square");
-
-        let positions = [
-            Position::parse(self.buffer[1]),
-            Position::parse(self.buffer[2]),
-            Position::parse(self.buffer[3]),
-            Position::parse(self.buffer[4]),
-        ];
-
-        // Only one color repeated 4 times
-        let colors = [Color::parse(self.buffer[0]); 4];
-
-        if let Some(renderer) = &mut self.renderer {
-            renderer.push_quad(positions, colors);
+        self.remaining_words -= 1;
+        if self.remaining_words == 0 {
+            self.buffer.clear();
+            self.write_vram_lines(y, height);
         }
     }
 
-    // 29 garbage
-
-    // +4
-    fn gp0_2a_mono_square_alpha(&mut self) {
-        // println!("[GPU] GP0(2a): mono_square_alpha");
-    }
-
-    // 2b garbage
-
-    // +8
-    fn gp0_2c_square_texture_blended(&mut self) {
-        // println!("[GPU] GP0(2c): square_texture_blended");
-    }
-
-    // +8
-    fn gp0_2d_square_texture_raw(&mut self) {
-        // println!("[GPU] GP0(2d): square_texture_raw");
-    }
-
-    // +8
-    fn gp0_2e_square_alpha_texture_blended(&mut self) {
-        // println!("[GPU] GP0(2e): square_alpha_texture_blended");
-    }
-
-    // +8
-    fn gp0_2f_square_alpha_texture_raw(&mut self) {
-        // println!("[GPU] GP0(2f): square_alpha_texture_raw");
-    }
-
-    // +5
-    fn gp0_30_shaded_triangle(&mut self) {
-        // println!("[GPU] GP0(30): shaded_triangle");
-
-        let vertices = [
-            Position::parse(self.buffer[1]),
-            Position::parse(self.buffer[3]),
-            Position::parse(self.buffer[5]),
-        ];
-
-        let colors = [
-            Color::parse(self.buffer[0]),
-            Color::parse(self.buffer[2]),
-            Color::parse(self.buffer[4]),
-        ];
-
-        if let Some(renderer) = &mut self.renderer {
-            renderer.push_triangle(vertices, colors);
+    /// Sends `lines` lines of VRAM from `top` to the renderer
+    fn write_vram_lines(&mut self, top: u32, lines: u32) {
+        if lines >= 512 {
+            self.drawn = None;
         }
-    }
-
-    // 31 garbage
+        if let Some(renderer) = &mut self.renderer {
+            let end = top + lines.min(512);
+            let vram = &self.vram;
 
-    // +5
-    fn gp0_32_shaded_triangle_alpha(&mut self) {
-        // println!("[GPU] GP0(32): shaded_triangle_alpha");
+            let lines = &vram[top as usize * 1024..end.min(512) as usize * 1024];
+            renderer.write_vram(top as u16, lines);
+            if end > 512 {
+                renderer.write_vram(0, &vram[..(end - 512) as usize * 1024]);
+            }
+        }
     }
 
-    // 33 garbage
-
-    // +8
-    fn gp0_34_shaded_textured_triangle_blend(&mut self) {
-        // println!("[GPU] GP0(34): shaded_textured_triangle_blend");
+    /// Whether the next GP0 words are the image data of a CPU to VRAM copy
+    fn uploading(&self) -> bool {
+        self.remaining_words > 0
+            && self.buffer.len() == 3
+            && (0xa0..=0xbf).contains(&(self.buffer[0] >> 24))
+    }
+
+    /// Drawing area, offset and mask settings of the rasterizer
+    fn draw_state(&self) -> DrawState {
+        let mask_bits = self.accuracy.mask_bits;
+        DrawState {
+            left: self.drawing_area_left as i32,
+            top: self.drawing_area_top as i32,
+            right: self.drawing_area_right as i32,
+            bottom: self.drawing_area_bottom as i32,
+            offset: (self.drawing_offset.0 as i32, self.drawing_offset.1 as i32),
+            set_mask: mask_bits && self.gpustat.mask_bit_while_drawing(),
+            check_mask: mask_bits && self.gpustat.draw_pixels(),
+            skip_field: self.skipped_field(),
+        }
     }
 
-    // 35 garbage
-
-    // +8
-    fn gp0_36_shaded_textured_triangle_alpha_blend(&mut self) {
-        // println!("[GPU] GP0(36): shaded_textured_triangle_alpha_blend");
+    /// In 480-line interlaced modes, the GPU doesn't draw to the lines of
+    /// the field being displayed, unless GPUSTAT.10 allows it
+    fn skipped_field(&self) -> Option<i32> {
+        let interlaced = self.gpustat.vertical_interlace() && self.gpustat.vertical_res();
+        let skipped = interlaced && !self.gpustat.drawing_to_display_allowed();
+        (self.accuracy.interlaced_fields && skipped).then(|| self.gpustat.even_odd() as i32)
+    }
+
+    /// Sets `Texture::DITHERED` on the primitives of the current command
+    /// that the GPU dithers: shaded and modulated polygons, with GPUSTAT.9
+    /// set. Rectangles never are.
+    fn dithered(&self, texture: Texture) -> Texture {
+        let command = self.buffer[0] >> 24;
+        let shaded = command & 0x10 != 0;
+        let modulated = texture.flags & (Texture::TEXTURED | Texture::RAW) == Texture::TEXTURED;
+        let dithered = self.accuracy.dithering
+            && self.gpustat.dither_24_to_15()
+            && command < 0x60
+            && (shaded || modulated);
+        if !dithered {
+            return texture;
+        }
+        Texture {
+            flags: texture.flags | Texture::DITHERED,
+            ..texture
+        }
     }
 
-    // 37 garbage
-
-    // +7
-    fn gp0_38_shaded_square(&mut self) {
-        // println!("[GPU] GP0(38): shaded_square");
-
-        let positions = [
-            Position::parse(self.buffer[1]),
-            Position::parse(self.buffer[3]),
-            Position::parse(self.buffer[5]),
-            Position::parse(self.buffer[7]),
-        ];
+    /// Draws to VRAM, and has the renderer draw the same for display
+    fn draw_polygon(&mut self, polygon: &Polygon) {
+        let state = self.draw_state();
+        let polygon = Polygon {
+            texture: self.dithered(polygon.texture),
+            ..*polygon
+        };
+        if polygon.texture.flags & Texture::TEXTURED != 0 {
+            self.sync_drawn(polygon.texture);
+        }
 
-        let colors = [
-            Color::parse(self.buffer[0]),
-            Color::parse(self.buffer[2]),
-            Color::parse(self.buffer[4]),
-            Color::parse(self.buffer[6]),
-        ];
+        let start = self.profile.is_some().then(Instant::now);
+        let pixels = raster::polygon(&mut self.vram, &state, &polygon);
+        self.add_raster_time(start);
+        if let Some(area) = raster::drawn_area(&state, &polygon) {
+            self.drawn = Some(self.drawn.map_or(area, |drawn| drawn.union(area)));
+        }
+        let triangles = polygon.triangles().len() as u64;
+        self.fifo.charge(fifo::draw_ticks(triangles, pixels, polygon.texture));
 
         if let Some(renderer) = &mut self.renderer {
-            renderer.push_quad(positions, colors);
+            renderer.set_skipped_field(state.skip_field);
+            renderer.push_polygon(&polygon);
         }
     }
 
-    // 39 garbage
-
-    // +7
-    fn gp0_3a_shaded_square_alpha(&mut self) {
-        // println!("[GPU] GP0(3a): shaded_square_alpha");
-    }
-
-    // 3b garbage
-
-    // +11
-    fn gp0_3c_shaded_textured_square_blend(&mut self) {
-        // println!("[GPU] GP0(3c): shaded_textured_square_blend");
-    }
-
-    // 3d garbage
-
-    // +11
-    fn gp0_3e_shaded_textured_square_alpha_blend(&mut self) {
-        // println!("[GPU] GP0(3e): shaded_textured_square_alpha_blend");
-    }
-
-    // 3f garbage
-
-    // +infinite until 0x5555_5555
-    fn gp0_40_mono_line(&mut self) {
-        // println!("[GPU] GP0(40): mono_line");
-    }
-
-    // +infinite until 0x5555_5555
-    fn gp0_42_mono_line_alpha(&mut self) {
-        // println!("[GPU] GP0(42): mono_line_alpha");
-    }
-
-    // +infinite until 0x5555_5555
-    fn gp0_48_mono_polyline(&mut self) {
-        // println!("[GPU] GP0(48): mono_polyline");
-    }
-
-    // +infinite until 0x5555_5555
-    fn gp0_4a_mono_polyline_alpha(&mut self) {
-        // println!("[GPU] GP0(4a): mono_polyline_alpha");
-    }
-
-    // +2*infinite until 0x5555_5555
-    fn gp0_50_shaded_line(&mut self) {
-        // println!("[GPU] GP0(50): shaded_line");
-    }
-
-    // +2*infinite until 0x5555_5555
-    fn gp0_52_shaded_line_alpha(&mut self) {
-        // println!("[GPU] GP0(52): shaded_line_alpha");
-    }
-
-    // +2*infinite until 0x5555_5555
-    fn gp0_58_shaded_polyline(&mut self) {
-        // println!("[GPU] GP0(58): shaded_polyline");
-    }
-
-    // +2*infinite until 0x5555_5555
-    fn gp0_5a_shaded_polyline_alpha(&mut self) {
-        // println!("[GPU] GP0(5a): shaded_polyline_alpha");
-    }
-
-    // +2
-    fn gp0_60_mono_rectangle(&mut self) {
-        // println!("[GPU] GP0(60): mono_rectangle");
-    }
-
-    // +2
-    fn gp0_62_mono_rectangle_alpha(&mut self) {
-        // println!("[GPU] GP0(62): mono_rectangle_alpha");
-    }
-
-    // +1
-    fn gp0_68_mono_rectangle_dot(&mut self) {
-        // println!("[GPU] GP0(68): mono_rectangle_dot");
-    }
-
-    // +1
-    fn gp0_6a_mono_rectangle_dot_alpha(&mut self) {
-        // println!("[GPU] GP0(6a): mono_rectangle_dot_alpha");
-    }
-
-    // +1
-    fn gp0_70_mono_rectangle_8(&mut self) {
-        // println!("[GPU] GP0(70): mono_rectangle_8");
-    }
-
-    // +1
-    fn gp0_72_mono_rectangle_8_alpha(&mut self) {
-        println!("[GPU] GP0(72): mono_rectangle_8_alpha");
-    }
-
-    // +1
-    fn gp0_78_mono_rectangle_16(&mut self) {
-        println!("[GPU] GP0(78): mono_rectangle_16");
-    }
-
-    // +1
-    fn gp0_7a_mono_rectangle_16_alpha(&mut self) {
-        println!("[GPU] GP0(7a): mono_rectangle_16_alpha");
-    }
-
-    // +3
-    fn gp0_64_textured_rectangle_blend(&mut self) {
-        println!("[GPU] GP0(64): textured_rectangle_blend");
-
-        let top_left = Position::parse(self.buffer[1]);
-
-        let size = Position::parse(self.buffer[3]);
-
-        let positions = [
-            top_left,
-            Position(top_left.0 + size.0, top_left.1),
-            Position(top_left.0, top_left.1 + size.1),
-            Position(top_left.0 + size.0, top_left.1 + size.1),
-        ];
-
-        let colors = [Color::parse(self.buffer[0]); 4];
+    /// Sends the lines drawn to since the last call to the renderer, if
+    /// `texture` reads from them: the game is drawing to a texture
+    fn sync_drawn(&mut self, texture: Texture) {
+        let Some(drawn) = self.drawn else {
+            return;
+        };
+        if !raster::texture_areas(texture).iter().any(|area| area.overlaps(&drawn)) {
+            return;
+        }
 
+        self.drawn = None;
         if let Some(renderer) = &mut self.renderer {
-            renderer.push_quad(positions, colors);
+            let lines = &self.vram[drawn.top as usize * 1024..(drawn.bottom as usize + 1) * 1024];
+            renderer.write_texels(drawn.top as u16, lines);
         }
     }
 
-    // +3
-    fn gp0_65_textured_rectangle_raw(&mut self) {
-        println!("[GPU] GP0(65): textured_rectangle_raw");
-    }
-
-    // +3
-    fn gp0_66_textured_rectangle_alpha_blend(&mut self) {
-        println!("[GPU] GP0(66): textured_rectangle_alpha_blend");
-    }
-
-    // +3
-    fn gp0_67_textured_rectangle_alpha_raw(&mut self) {
-        println!("[GPU] GP0(67): textured_rectangle_alpha_raw");
+    fn add_raster_time(&mut self, start: Option<Instant>) {
+        if let (Some(start), Some(profile)) = (start, &mut self.profile) {
+            profile.raster += start.elapsed();
+        }
     }
 
-    // +2
-    fn gp0_6c_textured_rectangle_dot_blend(&mut self) {
-        println!("[GPU] GP0(6c): textured_rectangle_dot_blend");
+    /// Texture page and blending of the last GP0(E1) or textured polygon,
+    /// used by rectangles and untextured semi-transparent primitives
+    fn draw_mode(&self) -> u16 {
+        (self.gpustat.0 & 0x1ff) as u16
     }
 
-    // +2
-    fn gp0_6d_textured_rectangle_dot_raw(&mut self) {
-        println!("[GPU] GP0(6d): textured_rectangle_dot_raw");
+    // also GP0(04..=1E, E0, E7..=EF)
+    fn gp0_00_nop(&mut self) {
+        // println!("[GPU] GP0(00): Nop");
     }
 
-    // +2
-    fn gp0_6e_textured_rectangle_dot_alpha_blend(&mut self) {
-        println!("[GPU] GP0(6e): textured_rectangle_dot_alpha_blend");
+    fn gp0_01_clear_cache(&mut self) {
+        // flush texture cache?
+        // println!("[GPU] GP0(01): Flush texture cache");
     }
 
     // +2
-    fn gp0_6f_textured_rectangle_dot_alpha_raw(&mut self) {
-        println!("[GPU] GP0(6f): textured_rectangle_dot_alpha_raw");
-    }
+    fn gp0_02_fill_rectangle(&mut self) {
+        // println!("[GPU] GP0(02): fill_rectangle");
+
+        let color = Color::parse(self.buffer[0]);
+        let pixel =
+            (color.0 >> 3) as u16 | ((color.1 >> 3) as u16) << 5 | ((color.2 >> 3) as u16) << 10;
+
+        // X and the width are in steps of 16 pixels. The fill ignores the
+        // drawing area and the mask bit, and wraps around VRAM.
+        let x = self.buffer[1] & 0x3f0;
+        let y = (self.buffer[1] >> 16) & 0x1ff;
+        let width = ((self.buffer[2] & 0x3ff) + 0xf) & !0xf;
+        let height = (self.buffer[2] >> 16) & 0x1ff;
+
+        for line in y..y + height {
+            for column in x..x + width {
+                self.vram[((line & 0x1ff) * 1024 + (column & 0x3ff)) as usize] = pixel;
+            }
+        }
+        self.fifo.charge(fifo::fill_ticks(width, height));
 
-    // +2
-    fn gp0_74_textured_rectangle_8_blend(&mut self) {
-        // println!("[GPU] GP0(74): textured_rectangle_8_blend");
+        self.write_vram_lines(y, height);
     }
 
-    // +2
-    fn gp0_75_textured_rectangle_8_raw(&mut self) {
-        // println!("[GPU] GP0(75): textured_rectangle_8_raw");
+    fn gp0_03_nop2(&mut self) {
+        // println!("[GPU] GP0(03): Unknown (nop?)");
     }
 
-    // +2
-    fn gp0_76_textured_rectangle_8_alpha_blend(&mut self) {
-        // println!("[GPU] GP0(76): textured_rectangle_8_alpha_blend");
+    fn gp0_1f_interrupt_request(&mut self) {
+        // println!("[GPU] GP0(1F): Interrupt request");
     }
 
-    // +2
-    fn gp0_77_textured_rectangle_8_alpha_raw(&mut self) {
-        // println!("[GPU] GP0(77): textured_rectangle_8_alpha_raw");
-    }
+    // +3 to +11: GP0(20..=3F) but the odd untextured ones, and 3D and 3F
+    fn gp0_20_polygon(&mut self) {
+        let polygon = Polygon::parse(&self.buffer, self.draw_mode());
 
-    // +2
-    fn gp0_7c_textured_rectangle_16_blend(&mut self) {
-        // println!("[GPU] GP0(7c): textured_rectangle_16_blend");
-    }
+        // Textured polygons select the texture page, like GP0(E1)
+        if polygon.texture.flags & Texture::TEXTURED != 0 {
+            self.gpustat.0 = (self.gpustat.0 & !0x1ff) | (polygon.texture.page & 0x1ff) as u32;
+        }
 
-    // +2
-    fn gp0_7d_textured_rectangle_16_raw(&mut self) {
-        // println!("[GPU] GP0(7d): textured_rectangle_16_raw");
+        self.draw_polygon(&polygon);
     }
 
-    // +2
-    fn gp0_7e_textured_rectangle_16_alpha_blend(&mut self) {
-        // println!("[GPU] GP0(7e): textured_rectangle_16_alpha_blend");
+    // +2 or +3, polylines until a 5000_5000h word: GP0(40, 42, 48, 4A, 50,
+    // 52, 58, 5A)
+    fn gp0_40_line(&mut self) {
+        let line = Line::parse(&self.buffer, self.draw_mode());
+        for segment in line.segments() {
+            self.draw_polygon(&segment);
+        }
     }
 
-    // +2
-    fn gp0_7f_textured_rectangle_16_alpha_raw(&mut self) {
-        // println!("[GPU] GP0(7f): textured_rectangle_16_alpha_raw");
+    // +1 to +3: GP0(60..=7F) but the odd untextured ones
+    fn gp0_60_rectangle(&mut self) {
+        let rect = Rect::parse(&self.buffer, self.draw_mode());
+        self.draw_polygon(&rect.polygon());
     }
 
     // +3
     fn gp0_80_copy_vram_vram(&mut self) {
         // println!("[GPU] GP0(80): copy_vram_vram");
+
+        let src = Transfer::parse(self.buffer[1], self.buffer[3]);
+        let dst = Transfer::parse(self.buffer[2], self.buffer[3]);
+        let (width, height) = (src.width, src.height);
+
+        // Line by line, so that overlapping copies read each source line
+        // before it's overwritten
+        let state = self.draw_state();
+        let mut line = Vec::with_capacity(width as usize);
+        for row in 0..height {
+            line.clear();
+            line.extend((0..width).map(|column| {
+                let x = (src.x + column) & 0x3ff;
+                let y = (src.y + row) & 0x1ff;
+                self.vram[(y * 1024 + x) as usize]
+            }));
+
+            for (column, &pixel) in line.iter().enumerate() {
+                state.write(&mut self.vram, dst.x + column as u32, dst.y + row, pixel);
+            }
+        }
+        self.fifo.charge(fifo::copy_ticks(width, height));
+
+        self.write_vram_lines(dst.y, height);
     }
 
     // +2 +(width * height)
     fn gp0_a0_copy_cpu_vram(&mut self) {
         // println!("[GPU] GP0(a0): copy_cpu_vram");
-        if self.buffer.len() == 3 {
-            // Check 3rd word, multiply high and low halfword
-            // that's the number of remaining halfwords to read.
-
-            let size = self.buffer[2] as usize;
-            let width = size & 0xffff;
-            let height = size >> 16;
-            let size = width * height;
-            // println!("Remaining {}x{} = {}", width, height, size);
-            self.remaining_words = if size % 2 == 0 {
-                size / 2
-            } else {
-                size / 2 + 1
-            };
-        } else {
-            // println!("[GPU] Copy with {} words", self.buffer.len());
-        }
 
-        if self.remaining_words == 0 {
-            self.buffer.clear();
-        }
+        // The 3rd word has the size in halfwords, rounded up to words
+        let transfer = Transfer::parse(self.buffer[1], self.buffer[2]);
+        self.remaining_words = (transfer.width * transfer.height).div_ceil(2) as usize;
     }
 
     // +2 +(width * height)
     fn gp0_c0_copy_vram_cpu(&mut self) {
         // println!("[GPU] GP0(c0): copy_vram_cpu");
-        // if self.buffer.len() == 3 {
-        // Check 3rd word, multiply high and low halfword
-        // that's the number of remaining halfwords to read.
 
-        // let size = self.buffer[2] as usize;
-        // let width = size & 0xffff;
-        // let height = size >> 16;
-        // let size = width * height;
-        // println!("Remaining {}x{} = {}", width, height, size);
-
-        // Yeah, the other way around...
-        // self.remaining_words = if size % 2 == 0 { size / 2 } else { size / 2 + 1};
-        // } else {
-        // println!("[GPU] Copy with {} words", self.buffer.len());
-        // }
+        let Transfer { x, y, width, height } = Transfer::parse(self.buffer[1], self.buffer[2]);
+
+        let pixels: Vec<u16> = (0..width * height)
+            .map(|i| {
+                let px = (x + i % width) & 0x3ff;
+                let py = (y + i / width) & 0x1ff;
+                self.vram[(py * 1024 + px) as usize]
+            })
+            .collect();
+
+        // Two pixels per word, the first in the low half
+        self.readback.clear();
+        for pair in pixels.chunks(2) {
+            let high = pair.get(1).copied().unwrap_or(0) as u32;
+            self.readback.push_back(pair[0] as u32 | high << 16);
+        }
     }
 
     fn gp0_e1_draw_mode(&mut self) {
         // println!("[GPU] GP0(e1): draw_mode");
+
+        let val = self.buffer[0];
+
+        // Texture page, semi-transparency, dithering and drawing to the
+        // display area go to GPUSTAT.0-10, texture disable to GPUSTAT.15
+        self.gpustat.0 = (self.gpustat.0 & !0x87ff) | (val & 0x7ff) | ((val >> 11) & 1) << 15;
     }
 
     fn gp0_e2_texture_window(&mut self) {
@@ -812,21 +1209,39 @@ impl Gpu {
 
     fn gp0_e6_mask_bit(&mut self) {
         // println!("[GPU] GP0(e6): mask_bit");
+
+        // Set and check the mask bit, GPUSTAT.11-12
+        let val = self.buffer[0];
+        self.gpustat.0 = (self.gpustat.0 & !0x1800) | (val & 3) << 11;
     }
 
     fn process_gp1(&mut self, command: u32) {
         let opcode = command >> 24;
         let arguments = command & 0xff_ffff;
 
+        if matches!(opcode, 0x03 | 0x05..=0x08)
+            && self.display_commands.len() < MAX_DISPLAY_COMMANDS
+        {
+            let cycles = self.cycles_since_vblank();
+            self.display_commands.push(DisplayCommand {
+                command,
+                line: self.timing.line_at(cycles),
+                cycles,
+            });
+        }
+
         match opcode {
             0x00 => {
                 // println!("[GPU] GP1(0): NOP");
                 self.gpustat.0 = 0x1480_2000;
+                self.readback.clear();
+                self.fifo.clear();
             }
             0x01 => {
                 // println!("[GPU] GP1(1): clear fifo");
                 self.buffer.clear();
                 self.remaining_words = 0;
+                self.fifo.clear();
             }
             0x02 => {
                 // println!("[GPU] GP1(2): ACK IRQ");
@@ -837,9 +1252,11 @@ impl Gpu {
             }
             0x04 => {
                 // println!("[GPU] GP1(4): DMA Direction: {}", arguments & 3);
+                self.gpustat.set_dma_direction(arguments & 3);
             }
             0x05 => {
                 // println!("[GPU] GP1(5): Start of display area {} {}", arguments & 0x3ff, (arguments >> 10) & 0x1ff);
+                self.display_start = ((arguments & 0x3ff) as u16, ((arguments >> 10) & 0x1ff) as u16);
             }
             0x06 => {
                 // println!("[GPU] GP1(6): Horizontal display range {} {}", arguments & 0xfff, (arguments >> 12) & 0xfff);
@@ -853,19 +1270,12 @@ impl Gpu {
                 self.gpustat.0 |= (arguments & 0x40) << 10;
                 self.gpustat.0 |= (arguments & 0x3f) << 17;
 
-                // let cpu_freq = 33868800;
-                // let vblank_freq = 60;
-                // let vblank_cycles = cpu_freq / vblank_freq;
-                // self.bus.upgrade().unwrap().borrow().add_event(PsxEventType::VBlank, 0, vblank_cycles);
-
                 // println!("[GPU] GP1(08) - New GPUSTAT: {:08x}", self.gpustat.0);
             }
             0x10..=0x1f => {
                 // println!("[GPU] Unimplemented GP1(0x10): Get GPU info");
             }
-            _ => {
-                panic!("[GPU] Unknown GP1 opcode: {:02x}", opcode)
-            }
+            _ => self.unknown_command("GP1", command),
         }
     }
 
@@ -885,3 +1295,590 @@ impl Gpu {
     //     }
     // }
 }
+
+/// VRAM is only kept by the renderer: when running headless it is not
+/// saved, and states saved headless restore the registers only.
+impl Savestate for Gpu {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"GPU ");
+        state.write_u32(self.gpustat.0);
+        state.write_u32(self.buffer.len() as u32);
+        for &word in &self.buffer {
+            state.write_u32(word);
+        }
+        state.write_u32(self.remaining_words as u32);
+        state.write_u32(self.readback.len() as u32);
+        for &word in &self.readback {
+            state.write_u32(word);
+        }
+        state.write_u32(self.gpuread);
+        let fifo: Vec<u32> = self.fifo.words().copied().collect();
+        state.write_u32(fifo.len() as u32);
+        for word in fifo {
+            state.write_u32(word);
+        }
+        state.write_u64(self.fifo.busy_until());
+
+        state.write_u16(self.drawing_area_left);
+        state.write_u16(self.drawing_area_top);
+        state.write_u16(self.drawing_area_right);
+        state.write_u16(self.drawing_area_bottom);
+        state.write_i16(self.drawing_offset.0);
+        state.write_i16(self.drawing_offset.1);
+
+        state.write_u16(self.display_start.0);
+        state.write_u16(self.display_start.1);
+        let area = self.display_area;
+        for value in [area.x, area.y, area.width, area.height] {
+            state.write_u16(value);
+        }
+
+        state.write_u64(self.vblank_start);
+        state.write_bool(self.timing.pal);
+        state.write_bool(self.timing.interlaced);
+        state.write_bool(self.timing.odd_field);
+        state.write_u64(self.hblanks);
+        state.write_u32(self.stalled_frames);
+
+        for &pixel in &self.vram {
+            state.write_u16(pixel);
+        }
+
+        match &mut self.renderer {
+            Some(renderer) => state.write_vec(&renderer.read_scene()),
+            None => state.write_vec(&[]),
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"GPU ", "GPU")?;
+        self.gpustat.0 = state.read_u32()?;
+        let words = state.read_u32()? as usize;
+        if words > MAX_COMMAND_WORDS {
+            return Err(StateError::Invalid("GPU command buffer"));
+        }
+        self.buffer.clear();
+        for _ in 0..words {
+            self.buffer.push(state.read_u32()?);
+        }
+        self.remaining_words = state.read_u32()? as usize;
+        let words = state.read_u32()? as usize;
+        if words > VRAM_WIDTH * VRAM_HEIGHT / 2 {
+            return Err(StateError::Invalid("GPU read-back"));
+        }
+        self.readback.clear();
+        for _ in 0..words {
+            self.readback.push_back(state.read_u32()?);
+        }
+        self.gpuread = state.read_u32()?;
+        let words = state.read_u32()? as usize;
+        if words > fifo::FIFO_WORDS {
+            return Err(StateError::Invalid("GPU FIFO"));
+        }
+        let fifo = (0..words).map(|_| state.read_u32()).collect::<Result<Vec<_>, _>>()?;
+        self.fifo.restore(&fifo, state.read_u64()?);
+
+        self.drawing_area_left = state.read_u16()?;
+        self.drawing_area_top = state.read_u16()?;
+        self.drawing_area_right = state.read_u16()?;
+        self.drawing_area_bottom = state.read_u16()?;
+        self.drawing_offset = (state.read_i16()?, state.read_i16()?);
+
+        self.display_start = (state.read_u16()?, state.read_u16()?);
+        self.display_area = DisplayArea {
+            x: state.read_u16()?,
+            y: state.read_u16()?,
+            width: state.read_u16()?,
+            height: state.read_u16()?,
+        };
+
+        self.vblank_start = state.read_u64()?;
+        self.timing = VideoTiming {
+            pal: state.read_bool()?,
+            interlaced: state.read_bool()?,
+            odd_field: state.read_bool()?,
+        };
+        self.hblanks = state.read_u64()?;
+        self.stalled_frames = state.read_u32()?;
+
+        for pixel in self.vram.iter_mut() {
+            *pixel = state.read_u16()?;
+        }
+
+        let vram = state.read_vec()?;
+
+        // Before the scene, which has the drawn pixels too
+        self.write_vram_lines(0, 512);
+
+        self.update_drawing_area();
+        let (x, y) = self.drawing_offset;
+        if let Some(renderer) = &mut self.renderer {
+            renderer.set_draw_offset(x, y);
+            if !vram.is_empty() {
+                renderer.write_scene(&vram);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::TexCoord;
+
+    fn gpu_240p() -> Gpu {
+        let mut gpu = Gpu::new();
+        gpu.process_gp1(0x0800_0000);
+        gpu
+    }
+
+    #[test]
+    fn progressive_mode_follows_line_parity() {
+        let gpu = gpu_240p();
+
+        assert!(!gpu.even_odd(0));
+        assert!(gpu.even_odd(1));
+        assert!(!gpu.even_odd(238));
+        assert!(gpu.even_odd(239));
+    }
+
+    #[test]
+    fn reads_zero_during_vblank() {
+        let mut gpu = gpu_240p();
+        let timing = gpu.timing;
+        for line in timing.visible_lines()..timing.lines_per_frame() {
+            assert!(!gpu.even_odd(line));
+        }
+
+        // 480 lines, odd field
+        gpu.process_gp1(0x0800_0024);
+        gpu.gpustat.set_even_odd(true);
+        assert!(!gpu.even_odd(timing.visible_lines()));
+    }
+
+    #[test]
+    fn color_profiles_by_name() {
+        assert_eq!(ColorProfile::from_name("dac"), Some(ColorProfile::Dac));
+        assert_eq!(ColorProfile::from_name("composite"), Some(ColorProfile::Composite));
+        assert_eq!(ColorProfile::from_name("sepia"), None);
+    }
+
+    #[test]
+    fn interlaced_mode_reports_the_field() {
+        let mut gpu = Gpu::new();
+        gpu.process_gp1(0x0800_0024);
+
+        gpu.gpustat.set_even_odd(false);
+        assert!(!gpu.even_odd(0));
+        assert!(!gpu.even_odd(1));
+
+        gpu.gpustat.set_even_odd(true);
+        assert!(gpu.even_odd(0));
+        assert!(gpu.even_odd(1));
+    }
+
+    #[test]
+    fn accuracy_presets_decide_fields_and_dithering() {
+        let mut gpu = Gpu::new();
+        // 480 lines interlaced, odd field, dithering on
+        gpu.process_gp1(0x0800_0024);
+        gpu.gpustat.set_even_odd(true);
+        gpu.process_gp0(0xe100_0200);
+
+        let shaded = Texture::default();
+        gpu.buffer = vec![0x3000_0000];
+        assert_eq!(gpu.skipped_field(), None);
+        assert_eq!(gpu.dithered(shaded), shaded);
+
+        gpu.set_accuracy(GpuAccuracy::Accurate.config());
+        assert_eq!(gpu.skipped_field(), Some(1));
+        assert_eq!(gpu.dithered(shaded).flags, Texture::DITHERED);
+
+        // Rectangles are never dithered, raw textures neither
+        let raw = Texture {
+            flags: Texture::TEXTURED | Texture::RAW,
+            ..Texture::default()
+        };
+        gpu.buffer = vec![0x7400_0000];
+        assert_eq!(gpu.dithered(shaded), shaded);
+        gpu.buffer = vec![0x2500_0000];
+        assert_eq!(gpu.dithered(raw), raw);
+        gpu.buffer.clear();
+
+        // Drawing to the displayed field allowed
+        gpu.process_gp0(0xe100_0600);
+        assert_eq!(gpu.skipped_field(), None);
+    }
+
+    #[test]
+    fn narrow_reads_return_gpustat_lanes() {
+        let mut gpu = gpu_240p();
+        let stat = gpu.read::<4>(4);
+
+        assert_eq!(gpu.read::<2>(4), stat & 0xffff);
+        assert_eq!(gpu.read::<2>(6), stat >> 16);
+        assert_eq!(gpu.read::<1>(5), (stat >> 8) & 0xff);
+        assert_eq!(gpu.read::<1>(7), stat >> 24);
+    }
+
+    #[test]
+    fn unterminated_polylines_are_dropped() {
+        let mut gpu = gpu_240p();
+
+        // Monochrome polyline, the terminator never comes
+        gpu.process_gp0(0x4800_00ff);
+        for i in 0..MAX_COMMAND_WORDS as u32 {
+            gpu.process_gp0(i << 16);
+        }
+
+        assert_eq!(gpu.remaining_words, 0);
+        assert!(gpu.buffer.is_empty());
+
+        // The next command is parsed normally
+        gpu.process_gp0(0xe500_0801);
+        assert_eq!(gpu.drawing_offset, (1, 1));
+    }
+
+    #[test]
+    fn snapshots_decode_gpustat() {
+        let mut gpu = gpu_240p();
+
+        // PAL, 24-bit, display on, 8-bit texels from (192, 256)
+        gpu.process_gp1(0x0800_0019);
+        gpu.process_gp1(0x0300_0000);
+        gpu.process_gp0(0xe100_0093);
+        gpu.process_gp0(0xe300_0000);
+        gpu.process_gp0(0xe403_bd3f);
+
+        let state = gpu.snapshot(gpu.requested_display_area());
+        assert_eq!((state.display_area.width, state.display_area.height), (320, 240));
+        assert!(state.display_enabled && state.pal && state.color_24bit);
+        assert!(!state.interlaced);
+        assert_eq!(state.texture_page, (192, 256));
+        assert_eq!(state.texture_bits, 8);
+        assert_eq!(state.drawing_area, (0, 0, 319, 239));
+    }
+
+    #[test]
+    fn logs_the_display_commands() {
+        // GP1(08) of gpu_240p, then a GP1(10) which is not logged
+        let mut gpu = gpu_240p();
+        gpu.process_gp1(0x1000_0007);
+        gpu.process_gp1(0x0500_0000 | 256 << 10);
+
+        let commands: Vec<u32> = gpu.display_commands.iter().map(|c| c.command).collect();
+        assert_eq!(commands, [0x0800_0000, 0x0504_0000]);
+        // Without a bus, at the start of the vblank
+        assert_eq!(gpu.display_commands[1].line, 240);
+
+        for _ in 0..100 {
+            gpu.process_gp1(0x0300_0001);
+        }
+        assert_eq!(gpu.display_commands.len(), MAX_DISPLAY_COMMANDS);
+    }
+
+    #[test]
+    fn unknown_commands_are_ignored() {
+        let mut gpu = gpu_240p();
+
+        gpu.process_gp1(0x3f00_0000);
+        gpu.process_gp0(0xf123_4567);
+        assert_eq!(gpu.unknown_commands, 2);
+        assert!(gpu.buffer.is_empty());
+
+        // The next command is parsed normally
+        gpu.process_gp0(0xe500_0801);
+        assert_eq!(gpu.drawing_offset, (1, 1));
+    }
+
+    #[test]
+    fn draw_mode_sets_the_blending_of_untextured_primitives() {
+        let mut gpu = gpu_240p();
+
+        // Page 3 of 15-bit texels, B-F blending, texture disable
+        gpu.process_gp0(0xe100_0953);
+        assert_eq!(gpu.gpustat.texture_page_x_base(), 3);
+        assert_eq!(gpu.gpustat.semi_transparency(), 2);
+        assert_eq!(gpu.gpustat.texture_page_colors(), 2);
+        assert!(gpu.gpustat.texture_disable());
+
+        let texture = Polygon::parse(&[0x2200_0000, 0, 0, 0], gpu.draw_mode()).texture;
+        assert_eq!(texture.flags, Texture::SEMI_TRANSPARENT);
+        assert_eq!(texture.semi_transparency(), Some(2));
+
+        // Textured polygons select another page and mode
+        for word in [0x2600_0000, 0, 0, 0, 0x0020_0000, 0, 0] {
+            gpu.process_gp0(word);
+        }
+        let texture = Polygon::parse(&[0x2200_0000, 0, 0, 0], gpu.draw_mode()).texture;
+        assert_eq!(texture.semi_transparency(), Some(1));
+        assert!(gpu.gpustat.texture_disable());
+    }
+
+    #[test]
+    fn vram_copies_to_the_cpu_go_through_gpuread() {
+        let mut gpu = gpu_240p();
+
+        // 3x2 at (1023, 0), wrapping horizontally
+        gpu.process_gp0(0xa000_0000);
+        gpu.process_gp0(0x0000_03ff);
+        gpu.process_gp0(0x0002_0003);
+        for word in [0x0002_8001, 0x0004_0003, 0x0006_0005] {
+            gpu.process_gp0(word);
+        }
+
+        gpu.process_gp0(0xc000_0000);
+        gpu.process_gp0(0x0000_03ff);
+        gpu.process_gp0(0x0002_0003);
+        assert!(gpu.buffer.is_empty());
+
+        assert_eq!(gpu.read::<4>(0), 0x0002_8001);
+        let mut words = [0; 3];
+        gpu.dma_read(&mut words);
+        assert_eq!(words, [0x0004_0003, 0x0006_0005, 0x0006_0005]);
+
+        // Odd sizes are padded with a 0 halfword, sizes of 0 mean the whole
+        // width or height
+        gpu.process_gp0(0xc000_0000);
+        gpu.process_gp0(0x0000_03ff);
+        gpu.process_gp0(0x0001_0001);
+        assert_eq!(gpu.read::<4>(0), 0x0000_8001);
+
+        gpu.process_gp0(0xc100_0000);
+        gpu.process_gp0(0x0000_0000);
+        gpu.process_gp0(0x0000_0000);
+        assert_eq!(gpu.readback.len(), 1024 * 512 / 2);
+    }
+
+    #[test]
+    fn fills_copies_and_polygons_draw_to_vram() {
+        let mut gpu = gpu_240p();
+        let pixel = |gpu: &Gpu, x: usize, y: usize| gpu.vram[y * 1024 + x];
+
+        // X and the width are rounded to 16 pixels
+        gpu.process_gp0(0x0200_00ff);
+        gpu.process_gp0(0x0002_0013);
+        gpu.process_gp0(0x0002_0003);
+        assert_eq!(pixel(&gpu, 15, 2), 0);
+        assert_eq!(pixel(&gpu, 16, 3), 0x001f);
+        assert_eq!(pixel(&gpu, 31, 3), 0x001f);
+        assert_eq!(pixel(&gpu, 32, 3), 0);
+
+        // Copies set the mask bit...
+        gpu.process_gp0(0xe600_0001);
+        gpu.process_gp0(0x8000_0000);
+        gpu.process_gp0(0x0002_0010);
+        gpu.process_gp0(0x000a_0064);
+        gpu.process_gp0(0x0001_0004);
+        assert_eq!(pixel(&gpu, 100, 10), 0x801f);
+        assert_eq!(pixel(&gpu, 103, 10), 0x801f);
+        assert_eq!(pixel(&gpu, 104, 10), 0);
+
+        // ...and check it
+        gpu.process_gp0(0xe600_0002);
+        gpu.process_gp0(0x8000_0000);
+        gpu.process_gp0(0x0000_0000);
+        gpu.process_gp0(0x000a_0063);
+        gpu.process_gp0(0x0001_0004);
+        assert_eq!(pixel(&gpu, 99, 10), 0);
+        assert_eq!(pixel(&gpu, 100, 10), 0x801f);
+
+        // Polygons are read back as drawn
+        gpu.process_gp0(0xe300_0000);
+        gpu.process_gp0(0xe407_fbff);
+        gpu.process_gp0(0x2000_ff00);
+        gpu.process_gp0(0x0000_00c8);
+        gpu.process_gp0(0x0000_00cc);
+        gpu.process_gp0(0x0004_00c8);
+        gpu.process_gp0(0xc000_0000);
+        gpu.process_gp0(0x0000_00c8);
+        gpu.process_gp0(0x0001_0002);
+        assert_eq!(gpu.read::<4>(0), 0x03e0_03e0);
+    }
+
+    #[test]
+    fn image_uploads_land_in_vram() {
+        let mut gpu = gpu_240p();
+
+        // 3x2 at the bottom right corner, wrapping on both axes
+        gpu.process_gp0(0xa000_0000);
+        gpu.process_gp0(0x01ff_03ff);
+        gpu.process_gp0(0x0002_0003);
+        for word in [0x0002_0001, 0x0004_0003, 0x0006_0005] {
+            gpu.process_gp0(word);
+        }
+
+        assert_eq!(gpu.vram[511 * 1024 + 1023], 1);
+        assert_eq!(gpu.vram[511 * 1024], 2);
+        assert_eq!(gpu.vram[511 * 1024 + 1], 3);
+        assert_eq!(gpu.vram[1023], 4);
+        assert_eq!(gpu.vram[0], 5);
+        assert_eq!(gpu.vram[1], 6);
+    }
+
+    #[test]
+    fn textured_triangles_read_their_attributes() {
+        let mut gpu = gpu_240p();
+
+        // GP0(35) is 9 words, like GP0(34)
+        let words = [
+            0x3500_0010,
+            0x0001_0002,
+            0x7fc0_0403,
+            0x0000_0020,
+            0x0003_0004,
+            0x001d_0605,
+            0x0000_0030,
+            0x0005_0006,
+            0x0000_0807,
+        ];
+        for word in &words[..8] {
+            gpu.process_gp0(*word);
+        }
+        assert_eq!(gpu.buffer.len(), 8);
+        gpu.process_gp0(words[8]);
+        assert!(gpu.buffer.is_empty());
+
+        let triangle = Polygon::parse(&words, gpu.draw_mode());
+        assert_eq!(triangle.vertices, 3);
+        assert_eq!(triangle.positions[..3].iter().map(|p| (p.0, p.1)).collect::<Vec<_>>(), [
+            (2, 1),
+            (4, 3),
+            (6, 5)
+        ]);
+        assert_eq!(triangle.texcoords[..3], [TexCoord(3, 4), TexCoord(5, 6), TexCoord(7, 8)]);
+        assert_eq!(triangle.texture.clut, 0x7fc0);
+        assert_eq!(triangle.texture.page, 0x001d);
+        assert_eq!(triangle.texture.flags, Texture::TEXTURED | Texture::RAW);
+
+        // The texture page of the polygon is selected
+        assert_eq!(gpu.gpustat.0 & 0x1ff, 0x1d);
+    }
+
+    #[test]
+    fn textured_rectangles_use_the_draw_mode_page() {
+        let mut gpu = gpu_240p();
+        gpu.process_gp0(0xe300_0000);
+        gpu.process_gp0(0xe407_ffff);
+
+        // 15-bit texture page at (128, 0)
+        gpu.process_gp0(0xe100_0102);
+        assert_eq!(gpu.gpustat.0 & 0x7ff, 0x102);
+        gpu.vram[4 * 1024 + 128 + 3] = 0x1234;
+
+        // GP0(7D), 16x16 raw at (16, 32) from texel (0, 0)
+        for word in [0x7d00_0000, 0x0020_0010, 0x0000_0000] {
+            gpu.process_gp0(word);
+        }
+        assert_eq!(gpu.vram[36 * 1024 + 19], 0x1234);
+
+        // GP0(65) has its size in a 4th word, and keeps the page
+        for word in [0x6500_0000, 0x0040_0000, 0x0000_0403, 0x0001_0001] {
+            gpu.process_gp0(word);
+        }
+        assert_eq!(gpu.vram[64 * 1024], 0x1234);
+        assert_eq!(gpu.gpustat.0 & 0x7ff, 0x102);
+    }
+
+    #[test]
+    fn drawn_lines_are_sent_before_being_sampled() {
+        let mut gpu = gpu_240p();
+        gpu.process_gp0(0xe300_0000);
+        gpu.process_gp0(0xe407_ffff);
+
+        // 16x16 at (512, 300)
+        for word in [0x6000_00ff, 0x012c_0200, 0x0010_0010] {
+            gpu.process_gp0(word);
+        }
+        let drawn = Area { left: 512, top: 300, right: 528, bottom: 316 };
+        assert_eq!(gpu.drawn, Some(drawn));
+
+        // A texture page at (0, 256) and a CLUT at (0, 0) don't read it
+        gpu.process_gp0(0xe100_0010);
+        for word in [0x7c00_0000, 0x0000_0000, 0x0000_0000] {
+            gpu.process_gp0(word);
+        }
+        assert_eq!(gpu.drawn, Some(drawn.union(Area { left: 0, top: 0, right: 16, bottom: 16 })));
+
+        // 15-bit page at (512, 256)
+        gpu.process_gp0(0xe100_0118);
+        for word in [0x7c00_0000, 0x0000_0000, 0x0000_0000] {
+            gpu.process_gp0(word);
+        }
+        assert_eq!(gpu.drawn, Some(Area { left: 0, top: 0, right: 16, bottom: 16 }));
+    }
+
+    #[test]
+    fn image_uploads_are_not_buffered() {
+        let mut gpu = gpu_240p();
+        let capacity = gpu.buffer.capacity();
+
+        // 320x240 halfwords, far more than a command can hold
+        gpu.process_gp0(0xa000_0000);
+        gpu.process_gp0(0x0000_0000);
+        gpu.process_gp0(0x00f0_0140);
+        assert_eq!(gpu.remaining_words, 320 * 240 / 2);
+
+        for _ in 0..320 * 240 / 2 {
+            gpu.process_gp0(0x5555_5555);
+            assert!(gpu.buffer.len() <= 3);
+        }
+        assert_eq!(gpu.remaining_words, 0);
+        assert!(gpu.buffer.is_empty());
+        assert_eq!(gpu.buffer.capacity(), capacity);
+
+        // Odd sizes are padded to a whole word
+        gpu.process_gp0(0xa000_0000);
+        gpu.process_gp0(0x0000_0000);
+        gpu.process_gp0(0x0001_0003);
+        assert_eq!(gpu.remaining_words, 2);
+        gpu.process_gp0(0);
+        gpu.process_gp0(0);
+
+        gpu.process_gp0(0xe500_0801);
+        assert_eq!(gpu.drawing_offset, (1, 1));
+    }
+
+    #[test]
+    fn gp1_08_sets_the_width_and_dotclock() {
+        let mut gpu = gpu_240p();
+        // 7000 CPU cycles are 11000 GPU cycles
+        let modes = [
+            (0x00, 256, 1100),
+            (0x01, 320, 1375),
+            (0x02, 512, 2200),
+            (0x03, 640, 2750),
+            (0x40, 368, 1571),
+            (0x43, 368, 1571),
+        ];
+        for (mode, width, dots) in modes {
+            gpu.process_gp1(0x0800_0000 | mode);
+            assert_eq!(gpu.requested_display_area().width, width, "GP1(08) {:02x}", mode);
+            assert_eq!(gpu.dots(7000), dots, "GP1(08) {:02x}", mode);
+        }
+        assert!(gpu.gpustat.horizontal_res2());
+        assert_eq!(gpu.gpustat.horizontal_res1(), 3);
+    }
+
+    #[test]
+    fn display_changes_wait_for_vblank() {
+        let mut gpu = gpu_240p();
+        let shown = gpu.display_area;
+
+        // 640x480 interlaced, starting at (0, 256)
+        gpu.process_gp1(0x0504_0000);
+        gpu.process_gp1(0x0800_0027);
+
+        assert_eq!(gpu.display_area, shown);
+        assert_eq!(
+            gpu.requested_display_area(),
+            DisplayArea {
+                x: 0,
+                y: 256,
+                width: 640,
+                height: 480
+            }
+        );
+    }
+}
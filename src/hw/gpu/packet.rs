@@ -0,0 +1,405 @@
+//! GP0 drawing and transfer commands, decoded from their words. The GPU
+//! parses a command once, then both the software rasterizer and the
+//! renderer draw the same `Polygon`s.
+
+use crate::hw::gpu::types::{Color, Position, TexCoord, Texture};
+
+// Bits of the command byte of polygons, lines and rectangles
+const RAW: u32 = 1 << 0;
+const SEMI_TRANSPARENT: u32 = 1 << 1;
+const TEXTURED: u32 = 1 << 2;
+const QUAD: u32 = 1 << 3;
+const POLYLINE: u32 = 1 << 3;
+const SHADED: u32 = 1 << 4;
+
+/// Texturing of a primitive with the `command` byte. Untextured
+/// semi-transparent primitives blend with the mode of `draw_mode`, the
+/// texture page of the last GP0(E1) or textured polygon.
+fn texture(command: u32, page: u16, clut: u16, draw_mode: u16) -> Texture {
+    let mut flags = 0;
+    if command & SEMI_TRANSPARENT != 0 {
+        flags |= Texture::SEMI_TRANSPARENT;
+    }
+    if command & TEXTURED != 0 {
+        flags |= Texture::TEXTURED;
+        if command & RAW != 0 {
+            flags |= Texture::RAW;
+        }
+        Texture { page, clut, flags }
+    } else if flags != 0 {
+        Texture {
+            page: draw_mode,
+            clut: 0,
+            flags,
+        }
+    } else {
+        Texture::default()
+    }
+}
+
+/// GP0(20..=3F), a triangle or a quad. Shaded commands have a color before
+/// each vertex but the first, the others only the one of the command.
+/// Textured ones have texture coordinates after each vertex, with the CLUT
+/// and the texture page in the top half of the first two.
+#[derive(Copy, Clone, Debug)]
+pub struct Polygon {
+    /// 3 or 4, the other entries of the arrays are unused
+    pub vertices: usize,
+    pub positions: [Position; 4],
+    pub colors: [Color; 4],
+    pub texcoords: [TexCoord; 4],
+    pub texture: Texture,
+}
+
+impl Polygon {
+    pub fn parse(words: &[u32], draw_mode: u16) -> Polygon {
+        let command = words[0] >> 24;
+        let shaded = command & SHADED != 0;
+        let textured = command & TEXTURED != 0;
+        let vertices = if command & QUAD != 0 { 4 } else { 3 };
+        let stride = 1 + shaded as usize + textured as usize;
+        let word = |vertex: usize, n: usize| words.get(vertex * stride + n).copied().unwrap_or(0);
+
+        let mut polygon = Polygon {
+            vertices,
+            positions: [Position::default(); 4],
+            colors: [Color::default(); 4],
+            texcoords: [TexCoord::default(); 4],
+            texture: texture(
+                command,
+                (word(1, 2) >> 16) as u16,
+                (word(0, 2) >> 16) as u16,
+                draw_mode,
+            ),
+        };
+        for v in 0..vertices {
+            polygon.positions[v] = Position::parse(word(v, 1));
+            polygon.colors[v] = Color::parse(if shaded { word(v, 0) } else { words[0] });
+            if textured {
+                polygon.texcoords[v] = TexCoord::parse(word(v, 2));
+            }
+        }
+        polygon
+    }
+
+    /// Vertices of the triangles to draw, quads are split in two
+    pub fn triangles(&self) -> &'static [[usize; 3]] {
+        match self.vertices {
+            4 => &[[0, 1, 2], [1, 2, 3]],
+            _ => &[[0, 1, 2]],
+        }
+    }
+}
+
+/// GP0(60..=7F): a color, the top left corner, the texture coordinates of
+/// that corner with the CLUT, then the size if it isn't fixed. Rectangles
+/// have no texture page attribute and use the one of the draw mode.
+#[derive(Copy, Clone, Debug)]
+pub struct Rect {
+    pub position: Position,
+    pub width: u16,
+    pub height: u16,
+    pub color: Color,
+    pub texcoord: TexCoord,
+    pub texture: Texture,
+}
+
+impl Rect {
+    pub fn parse(words: &[u32], draw_mode: u16) -> Rect {
+        let command = words[0] >> 24;
+        let textured = command & TEXTURED != 0;
+        let texcoord = if textured { words[2] } else { 0 };
+
+        let (width, height) = match (command >> 3) & 3 {
+            0 => {
+                let size = words[if textured { 3 } else { 2 }];
+                ((size & 0x3ff) as u16, ((size >> 16) & 0x1ff) as u16)
+            }
+            1 => (1, 1),
+            2 => (8, 8),
+            _ => (16, 16),
+        };
+
+        Rect {
+            position: Position::parse(words[1]),
+            width,
+            height,
+            color: Color::parse(words[0]),
+            texcoord: TexCoord::parse(texcoord),
+            texture: texture(command, draw_mode, (texcoord >> 16) as u16, draw_mode),
+        }
+    }
+
+    /// The quad covering the rectangle
+    pub fn polygon(&self) -> Polygon {
+        // Vertices are 11-bit signed values
+        let Position(x, y) = self.position;
+        let (x, y) = ((x << 5) >> 5, (y << 5) >> 5);
+        let TexCoord(u, v) = self.texcoord;
+        let (w, h) = (self.width as i16, self.height as i16);
+        let textured = self.texture.flags & Texture::TEXTURED != 0;
+        let texcoord = |du: u16, dv: u16| match textured {
+            true => TexCoord(u + du, v + dv),
+            false => TexCoord::default(),
+        };
+
+        Polygon {
+            vertices: 4,
+            positions: [
+                Position(x, y),
+                Position(x.wrapping_add(w), y),
+                Position(x, y.wrapping_add(h)),
+                Position(x.wrapping_add(w), y.wrapping_add(h)),
+            ],
+            colors: [self.color; 4],
+            texcoords: [
+                texcoord(0, 0),
+                texcoord(self.width, 0),
+                texcoord(0, self.height),
+                texcoord(self.width, self.height),
+            ],
+            texture: self.texture,
+        }
+    }
+}
+
+/// GP0(40..=5F): a line, or a polyline ended by a 5000_5000h word. Shaded
+/// commands have a color before each vertex but the first.
+#[derive(Clone, Debug)]
+pub struct Line {
+    pub positions: Vec<Position>,
+    pub colors: Vec<Color>,
+    pub texture: Texture,
+}
+
+/// Whether `word` ends a polyline, where a vertex or a color would be.
+/// Usually 5555_5555h, but only these bits are checked.
+pub fn terminator(word: u32) -> bool {
+    word & 0xf000_f000 == 0x5000_5000
+}
+
+impl Line {
+    pub fn parse(words: &[u32], draw_mode: u16) -> Line {
+        let command = words[0] >> 24;
+        let shaded = command & SHADED != 0;
+        let polyline = command & POLYLINE != 0;
+
+        let mut line = Line {
+            positions: vec![],
+            colors: vec![],
+            texture: texture(command & SEMI_TRANSPARENT, 0, 0, draw_mode),
+        };
+        let mut color = words[0];
+        let mut rest = &words[1..];
+        while let Some(&position) = rest.first() {
+            if polyline && terminator(position) {
+                break;
+            }
+            line.positions.push(Position::parse(position));
+            line.colors.push(Color::parse(color));
+            rest = &rest[1..];
+
+            if shaded {
+                match rest.first() {
+                    Some(&word) if !(polyline && terminator(word)) => color = word,
+                    _ => break,
+                }
+                rest = &rest[1..];
+            }
+        }
+        line
+    }
+
+    /// A quad for each segment, one pixel wide across its major axis, so
+    /// that lines are drawn like the other polygons
+    pub fn segments(&self) -> impl Iterator<Item = Polygon> + '_ {
+        let segments = self.positions.windows(2).zip(self.colors.windows(2));
+        segments.map(|(positions, colors)| {
+            segment([positions[0], positions[1]], [colors[0], colors[1]], self.texture)
+        })
+    }
+}
+
+fn segment(positions: [Position; 2], colors: [Color; 2], texture: Texture) -> Polygon {
+    // Vertices are 11-bit signed values
+    let [a, b] = positions.map(|p| ((p.0 << 5) >> 5, (p.1 << 5) >> 5));
+    let x_major = (b.0 - a.0).abs() >= (b.1 - a.1).abs();
+    let forward = if x_major { a.0 <= b.0 } else { a.1 <= b.1 };
+    let ((a, ca), (b, cb)) = match forward {
+        true => ((a, colors[0]), (b, colors[1])),
+        false => ((b, colors[1]), (a, colors[0])),
+    };
+
+    let (positions, colors) = if x_major {
+        let positions = [(a.0, a.1), (b.0 + 1, b.1), (a.0, a.1 + 1), (b.0 + 1, b.1 + 1)];
+        (positions, [ca, cb, ca, cb])
+    } else {
+        let positions = [(a.0, a.1), (a.0 + 1, a.1), (b.0, b.1 + 1), (b.0 + 1, b.1 + 1)];
+        (positions, [ca, ca, cb, cb])
+    };
+
+    Polygon {
+        vertices: 4,
+        positions: positions.map(|(x, y)| Position(x, y)),
+        colors,
+        texcoords: [TexCoord::default(); 4],
+        texture,
+    }
+}
+
+/// GP0(80..=DF): a rectangle of VRAM, copied within VRAM or to and from
+/// the CPU. A size of 0 is the whole width or height.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transfer {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Transfer {
+    pub fn parse(position: u32, size: u32) -> Transfer {
+        Transfer {
+            x: position & 0x3ff,
+            y: (position >> 16) & 0x1ff,
+            width: ((size & 0xffff).wrapping_sub(1) & 0x3ff) + 1,
+            height: ((size >> 16).wrapping_sub(1) & 0x1ff) + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xy(positions: &[Position]) -> Vec<(i16, i16)> {
+        positions.iter().map(|p| (p.0, p.1)).collect()
+    }
+
+    #[test]
+    fn shaded_textured_quads() {
+        // GP0(3C), 12 words
+        let words = [
+            0x3c00_0010,
+            0x0001_0002,
+            0x7fc0_0403,
+            0x0000_0020,
+            0x0003_0004,
+            0x001d_0605,
+            0x0000_0030,
+            0x0005_0006,
+            0x0000_0807,
+            0x0000_0040,
+            0x0007_0008,
+            0x0000_0a09,
+        ];
+        let polygon = Polygon::parse(&words, 0x1ff);
+
+        assert_eq!(polygon.vertices, 4);
+        assert_eq!(xy(&polygon.positions), [(2, 1), (4, 3), (6, 5), (8, 7)]);
+        assert_eq!(polygon.colors.map(|c| c.0), [0x10, 0x20, 0x30, 0x40]);
+        assert_eq!(polygon.texcoords.map(|t| (t.0, t.1)), [(3, 4), (5, 6), (7, 8), (9, 10)]);
+        assert_eq!(polygon.texture.clut, 0x7fc0);
+        assert_eq!(polygon.texture.page, 0x001d);
+        assert_eq!(polygon.texture.flags, Texture::TEXTURED);
+        assert_eq!(polygon.triangles().len(), 2);
+    }
+
+    #[test]
+    fn flat_polygons_repeat_the_command_color() {
+        // GP0(27), raw semi-transparent textured triangle
+        let words = [0x2700_00ff, 0x0001_0002, 0x0000_0000, 0x0003_0004, 0x0010_0000, 0, 0];
+        let polygon = Polygon::parse(&words, 0x1ff);
+        assert_eq!(polygon.vertices, 3);
+        assert_eq!(polygon.colors[..3].iter().map(|c| c.0).collect::<Vec<_>>(), [0xff; 3]);
+        assert_eq!(polygon.texture.page, 0x10);
+        let flags = Texture::TEXTURED | Texture::RAW | Texture::SEMI_TRANSPARENT;
+        assert_eq!(polygon.texture.flags, flags);
+
+        // GP0(2A), untextured quads blend with the draw mode
+        let words = [0x2a00_0000, 0, 0, 0, 0];
+        let polygon = Polygon::parse(&words, 0x47);
+        assert_eq!(polygon.texture.flags, Texture::SEMI_TRANSPARENT);
+        assert_eq!(polygon.texture.semi_transparency(), Some(2));
+
+        // GP0(20) is not textured at all
+        assert_eq!(Polygon::parse(&[0x2000_0000, 0, 0, 0], 0x47).texture, Texture::default());
+    }
+
+    #[test]
+    fn rectangle_sizes() {
+        // GP0(65): the size is the 4th word, and the page is the draw mode's
+        let rect = Rect::parse(&[0x6500_0000, 0x0040_0010, 0x7fc0_0403, 0x0002_0001], 0x102);
+        assert_eq!((rect.position.0, rect.position.1), (0x10, 0x40));
+        assert_eq!((rect.width, rect.height), (1, 2));
+        assert_eq!((rect.texcoord.0, rect.texcoord.1), (3, 4));
+        assert_eq!(rect.texture.page, 0x102);
+        assert_eq!(rect.texture.clut, 0x7fc0);
+
+        // GP0(60): the size is the 3rd word, masked to 10 and 9 bits
+        let rect = Rect::parse(&[0x6000_0000, 0, 0xffff_ffff], 0x102);
+        assert_eq!((rect.width, rect.height), (0x3ff, 0x1ff));
+        assert_eq!(rect.texture, Texture::default());
+
+        // GP0(6A), GP0(72) and GP0(7A) have fixed sizes
+        for (command, size) in [(0x6a00_0000, 1), (0x7200_0000, 8), (0x7a00_0000, 16)] {
+            let rect = Rect::parse(&[command, 0x0002_0001], 0);
+            assert_eq!((rect.width, rect.height), (size, size));
+            assert_eq!(rect.texture.flags, Texture::SEMI_TRANSPARENT);
+        }
+
+        let polygon = Rect::parse(&[0x7d00_0000, 0x0020_0010, 0x0000_0201], 0).polygon();
+        assert_eq!(xy(&polygon.positions), [(16, 32), (32, 32), (16, 48), (32, 48)]);
+        assert_eq!(polygon.texcoords[3], TexCoord(17, 18));
+
+        // Positions wrap at 11 bits, a y word of 7FFFh is -1
+        let polygon = Rect::parse(&[0x7800_0000, 0x7fff_07f8], 0).polygon();
+        assert_eq!(xy(&polygon.positions), [(-8, -1), (8, -1), (-8, 15), (8, 15)]);
+    }
+
+    #[test]
+    fn polylines_end_at_the_terminator() {
+        // GP0(58), shaded: the terminator comes where a color would
+        let words = [0x5800_0010, 0x0000_0000, 0x20, 0x0000_0004, 0x30, 0x0004_0004, 0x5555_5555];
+        let line = Line::parse(&words, 0);
+        assert_eq!(xy(&line.positions), [(0, 0), (4, 0), (4, 4)]);
+        assert_eq!(line.colors.iter().map(|c| c.0).collect::<Vec<_>>(), [0x10, 0x20, 0x30]);
+
+        // GP0(4A), the terminator is where a vertex would be, any of the
+        // 5000_5000h words
+        let line = Line::parse(&[0x4a00_0000, 0, 0x0001_0001, 0x5123_5456], 0x40);
+        assert_eq!(line.positions.len(), 2);
+        assert_eq!(line.texture.flags, Texture::SEMI_TRANSPARENT);
+
+        // GP0(40) is a single line, whatever its words are
+        let line = Line::parse(&[0x4000_0000, 0x5000_5000, 0x0001_0001], 0);
+        assert_eq!(xy(&line.positions), [(0, 0x5000), (1, 1)]);
+    }
+
+    #[test]
+    fn line_segments_are_a_pixel_wide() {
+        // From right to left, mostly horizontal: drawn from the left end
+        let line = Line::parse(&[0x5000_00ff, 0x0002_0008, 0x0000_0000, 0x0000_0000], 0);
+        let segments: Vec<Polygon> = line.segments().collect();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(xy(&segments[0].positions), [(0, 0), (9, 2), (0, 1), (9, 3)]);
+        assert_eq!(segments[0].colors.map(|c| c.0), [0, 0xff, 0, 0xff]);
+
+        // Mostly vertical, with a negative coordinate
+        let line = Line::parse(&[0x4000_0000, 0x0000_07ff, 0x0004_0000], 0);
+        let segment = line.segments().next().unwrap();
+        assert_eq!(xy(&segment.positions), [(-1, 0), (0, 0), (0, 5), (1, 5)]);
+    }
+
+    #[test]
+    fn transfer_sizes_wrap() {
+        let transfer = Transfer::parse(0xffff_ffff, 0x0002_0003);
+        assert_eq!(transfer, Transfer { x: 0x3ff, y: 0x1ff, width: 3, height: 2 });
+
+        // 0 is the whole VRAM, and sizes are masked
+        let transfer = Transfer::parse(0, 0);
+        assert_eq!((transfer.width, transfer.height), (1024, 512));
+        let transfer = Transfer::parse(0, 0x0201_0401);
+        assert_eq!((transfer.width, transfer.height), (1, 1));
+    }
+}
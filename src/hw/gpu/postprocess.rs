@@ -0,0 +1,458 @@
+//! Effects applied when presenting the frame. Only their settings exist
+//! without the `gui` feature.
+
+#[cfg(feature = "gui")]
+use gl::types::{GLint, GLsizei, GLuint};
+
+#[cfg(feature = "gui")]
+use std::ptr;
+
+#[cfg(feature = "gui")]
+use crate::hw::gpu::shaders::{compile_shader, find_program_uniform, link_program};
+#[cfg(feature = "gui")]
+use crate::hw::gpu::types::ColorProfile;
+
+/// A post-processing effect, applied when presenting the frame
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Effect {
+    /// Dark gaps between the lines
+    Scanlines,
+    /// RGB phosphor stripes
+    ApertureGrille,
+    /// Curved CRT glass
+    Curvature,
+    /// Chroma bleeding and dot crawl of a composite signal
+    Composite,
+}
+
+/// How consecutive frames are combined on the window, to reduce the judder
+/// of games running at 30fps or less
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FrameBlend {
+    Off,
+    /// Every frame is mixed 50/50 with the previous one
+    Mix,
+    /// A black frame follows every frame. Only useful on 120Hz displays.
+    BlackFrame,
+}
+
+impl FrameBlend {
+    pub fn from_name(name: &str) -> Option<FrameBlend> {
+        match name {
+            "off" => Some(FrameBlend::Off),
+            "mix" => Some(FrameBlend::Mix),
+            "bfi" => Some(FrameBlend::BlackFrame),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Pass {
+    pub effect: Effect,
+    /// Between 0 (no effect) and 1
+    pub strength: f32,
+}
+
+impl Pass {
+    /// Parses `effect` or `effect:strength`, e.g. "scanlines:0.3"
+    pub fn parse(spec: &str) -> Option<Pass> {
+        let (name, strength) = match spec.split_once(':') {
+            Some((name, strength)) => (name, strength.parse().ok()?),
+            None => (spec, 0.5),
+        };
+
+        let effect = match name {
+            "scanlines" => Effect::Scanlines,
+            "aperture" => Effect::ApertureGrille,
+            "curvature" => Effect::Curvature,
+            "composite" => Effect::Composite,
+            _ => return None,
+        };
+
+        if !(0.0..=1.0).contains(&strength) {
+            return None;
+        }
+
+        Some(Pass { effect, strength })
+    }
+
+    /// Parses a comma separated chain of passes, applied in order
+    pub fn parse_chain(spec: &str) -> Option<Vec<Pass>> {
+        spec.split(',')
+            .filter(|pass| !pass.is_empty())
+            .map(Pass::parse)
+            .collect()
+    }
+}
+
+#[cfg(feature = "gui")]
+/// An offscreen color buffer. Creating one binds it and disables the
+/// scissor test.
+pub struct Target {
+    pub framebuffer: GLuint,
+    pub texture: GLuint,
+}
+
+#[cfg(feature = "gui")]
+impl Target {
+    pub fn new(width: GLsizei, height: GLsizei) -> Target {
+        let mut framebuffer = 0;
+        let mut texture = 0;
+
+        unsafe {
+            gl::GenTextures(1, &mut texture);
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGBA8 as GLint,
+                width,
+                height,
+                0,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                ptr::null(),
+            );
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+
+            gl::GenFramebuffers(1, &mut framebuffer);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+            gl::FramebufferTexture2D(
+                gl::FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::TEXTURE_2D,
+                texture,
+                0,
+            );
+
+            if gl::CheckFramebufferStatus(gl::FRAMEBUFFER) != gl::FRAMEBUFFER_COMPLETE {
+                panic!("Could not create an offscreen framebuffer");
+            }
+
+            // Start from black rather than garbage
+            gl::Disable(gl::SCISSOR_TEST);
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+        }
+
+        Target {
+            framebuffer,
+            texture,
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+impl Drop for Target {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.framebuffer);
+            gl::DeleteTextures(1, &self.texture);
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+struct Program {
+    program: GLuint,
+    uniform_strength: GLint,
+    uniform_resolution: GLint,
+    strength: f32,
+}
+
+#[cfg(feature = "gui")]
+impl Program {
+    fn new(vertex_shader: GLuint, fragment_src: &str, pass: Option<Pass>) -> Program {
+        let fragment_shader = compile_shader(fragment_src, gl::FRAGMENT_SHADER);
+        let program = link_program(&[vertex_shader, fragment_shader]);
+
+        unsafe {
+            // Linked programs keep working after their shaders are deleted
+            gl::DeleteShader(fragment_shader);
+
+            gl::UseProgram(program);
+            gl::Uniform1i(find_program_uniform(program, "source"), 0);
+        }
+
+        // The copy pass has no parameters
+        let (uniform_strength, uniform_resolution) = match pass {
+            Some(_) => (
+                find_program_uniform(program, "strength"),
+                find_program_uniform(program, "resolution"),
+            ),
+            None => (-1, -1),
+        };
+
+        Program {
+            program,
+            uniform_strength,
+            uniform_resolution,
+            strength: pass.map_or(0.0, |pass| pass.strength),
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+impl Drop for Program {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteProgram(self.program);
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+/// Chain of fullscreen passes between the rendered frame and the window.
+/// Every pass reads the output of the previous one, the last one draws to
+/// the window.
+pub struct PostProcessor {
+    /// One program per pass, or a plain copy if there are no passes
+    programs: Vec<Program>,
+    /// Ping-pong buffers for the intermediate results
+    targets: Vec<Target>,
+    /// Empty VAO: the passes generate their vertices
+    vertex_array_object: GLuint,
+    width: GLsizei,
+    height: GLsizei,
+
+    frame_blend: FrameBlend,
+    /// Mixes the frame with the previous one
+    blend_program: Program,
+    /// Copy of the last frame, for FrameBlend::Mix
+    previous: Target,
+    /// Output of the blend program
+    blended: Target,
+
+    color_profile: ColorProfile,
+    /// Adjusts the frame for the host display, before everything else
+    profile_program: Program,
+    uniform_profile: GLint,
+    /// Output of the profile program
+    profiled: Target,
+}
+
+#[cfg(feature = "gui")]
+impl PostProcessor {
+    pub fn new(passes: &[Pass], width: GLsizei, height: GLsizei) -> PostProcessor {
+        let vertex_shader = compile_shader(include_str!("shaders/post_vertex.glsl"), gl::VERTEX_SHADER);
+
+        let mut programs: Vec<Program> = passes
+            .iter()
+            .map(|&pass| {
+                let src = match pass.effect {
+                    Effect::Scanlines => include_str!("shaders/post_scanlines.glsl"),
+                    Effect::ApertureGrille => include_str!("shaders/post_aperture.glsl"),
+                    Effect::Curvature => include_str!("shaders/post_curvature.glsl"),
+                    Effect::Composite => include_str!("shaders/post_composite.glsl"),
+                };
+
+                Program::new(vertex_shader, src, Some(pass))
+            })
+            .collect();
+
+        if programs.is_empty() {
+            programs.push(Program::new(
+                vertex_shader,
+                include_str!("shaders/post_copy.glsl"),
+                None,
+            ));
+        }
+
+        let intermediate = (programs.len() - 1).min(2);
+        let targets = (0..intermediate).map(|_| Target::new(width, height)).collect();
+
+        let blend_program = Program::new(
+            vertex_shader,
+            include_str!("shaders/post_blend.glsl"),
+            None,
+        );
+        unsafe {
+            gl::Uniform1i(find_program_uniform(blend_program.program, "previous"), 1);
+        }
+
+        let profile_program = Program::new(
+            vertex_shader,
+            include_str!("shaders/post_profile.glsl"),
+            None,
+        );
+        let uniform_profile = find_program_uniform(profile_program.program, "profile");
+
+        let mut vao = 0;
+        unsafe {
+            gl::DeleteShader(vertex_shader);
+            gl::GenVertexArrays(1, &mut vao);
+        }
+
+        PostProcessor {
+            programs,
+            targets,
+            vertex_array_object: vao,
+            width,
+            height,
+
+            frame_blend: FrameBlend::Off,
+            blend_program,
+            previous: Target::new(width, height),
+            blended: Target::new(width, height),
+
+            color_profile: ColorProfile::Dac,
+            profile_program,
+            uniform_profile,
+            profiled: Target::new(width, height),
+        }
+    }
+
+    pub fn color_profile(&self) -> ColorProfile {
+        self.color_profile
+    }
+
+    pub fn set_color_profile(&mut self, profile: ColorProfile) {
+        self.color_profile = profile;
+    }
+
+    pub fn frame_blend(&self) -> FrameBlend {
+        self.frame_blend
+    }
+
+    pub fn set_frame_blend(&mut self, frame_blend: FrameBlend) {
+        self.frame_blend = frame_blend;
+    }
+
+    /// Runs the chain on `source`, leaving the result in the window's
+    /// framebuffer, of size `output`. Changes the bound program, VAO,
+    /// framebuffer, texture and viewport: the caller must restore its own
+    /// state.
+    pub fn run(&self, source: &Target, output: (GLsizei, GLsizei)) {
+        unsafe {
+            gl::Disable(gl::SCISSOR_TEST);
+            gl::BindVertexArray(self.vertex_array_object);
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::Viewport(0, 0, self.width, self.height);
+        }
+
+        let mut input = source;
+
+        // The frame holds the DAC output, which is what Dac shows
+        if self.color_profile != ColorProfile::Dac {
+            unsafe {
+                gl::BindFramebuffer(gl::FRAMEBUFFER, self.profiled.framebuffer);
+                gl::UseProgram(self.profile_program.program);
+                gl::Uniform1i(self.uniform_profile, self.color_profile as GLint);
+                gl::BindTexture(gl::TEXTURE_2D, input.texture);
+                gl::DrawArrays(gl::TRIANGLES, 0, 3);
+            }
+
+            input = &self.profiled;
+        }
+
+        if self.frame_blend == FrameBlend::Mix {
+            unsafe {
+                gl::BindFramebuffer(gl::FRAMEBUFFER, self.blended.framebuffer);
+                gl::UseProgram(self.blend_program.program);
+                gl::ActiveTexture(gl::TEXTURE1);
+                gl::BindTexture(gl::TEXTURE_2D, self.previous.texture);
+                gl::ActiveTexture(gl::TEXTURE0);
+                gl::BindTexture(gl::TEXTURE_2D, input.texture);
+                gl::DrawArrays(gl::TRIANGLES, 0, 3);
+
+                // Keep this frame for the next one
+                gl::BindFramebuffer(gl::READ_FRAMEBUFFER, input.framebuffer);
+                gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, self.previous.framebuffer);
+                gl::BlitFramebuffer(
+                    0,
+                    0,
+                    self.width,
+                    self.height,
+                    0,
+                    0,
+                    self.width,
+                    self.height,
+                    gl::COLOR_BUFFER_BIT,
+                    gl::NEAREST,
+                );
+            }
+
+            input = &self.blended;
+        }
+
+        for (i, program) in self.programs.iter().enumerate() {
+            let last = i == self.programs.len() - 1;
+            let target = if last {
+                None
+            } else {
+                Some(&self.targets[i % 2])
+            };
+
+            unsafe {
+                if last {
+                    gl::Viewport(0, 0, output.0, output.1);
+                }
+                gl::BindFramebuffer(gl::FRAMEBUFFER, target.map_or(0, |t| t.framebuffer));
+                gl::UseProgram(program.program);
+                gl::Uniform1f(program.uniform_strength, program.strength);
+                gl::Uniform2f(
+                    program.uniform_resolution,
+                    self.width as f32,
+                    self.height as f32,
+                );
+                gl::BindTexture(gl::TEXTURE_2D, input.texture);
+                gl::DrawArrays(gl::TRIANGLES, 0, 3);
+            }
+
+            if let Some(target) = target {
+                input = target;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+impl Drop for PostProcessor {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteVertexArrays(1, &self.vertex_array_object);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_passes_with_and_without_strength() {
+        assert_eq!(
+            Pass::parse("scanlines:0.25"),
+            Some(Pass {
+                effect: Effect::Scanlines,
+                strength: 0.25
+            })
+        );
+        assert_eq!(Pass::parse("curvature").map(|p| p.strength), Some(0.5));
+        assert_eq!(Pass::parse("blur"), None);
+        assert_eq!(Pass::parse("scanlines:2"), None);
+    }
+
+    #[test]
+    fn frame_blend_modes_by_name() {
+        assert_eq!(FrameBlend::from_name("mix"), Some(FrameBlend::Mix));
+        assert_eq!(FrameBlend::from_name("bfi"), Some(FrameBlend::BlackFrame));
+        assert_eq!(FrameBlend::from_name("on"), None);
+    }
+
+    #[test]
+    fn chains_keep_their_order() {
+        let chain = Pass::parse_chain("composite,scanlines:1,curvature").unwrap();
+        let effects: Vec<Effect> = chain.iter().map(|p| p.effect).collect();
+
+        assert_eq!(
+            effects,
+            vec![Effect::Composite, Effect::Scanlines, Effect::Curvature]
+        );
+        assert_eq!(Pass::parse_chain(""), Some(vec![]));
+        assert_eq!(Pass::parse_chain("scanlines,bogus"), None);
+    }
+}
@@ -0,0 +1,478 @@
+//! Software rasterizer for the copy of VRAM kept by the Gpu.
+//!
+//! That copy is what VRAM holds as far as the emulated machine can tell:
+//! transfers, reads back and the mask bit work on it. The renderer draws
+//! the same primitives on its own, for display, and samples its textures
+//! from the lines of this copy it is sent.
+
+use crate::hw::gpu::packet::Polygon;
+use crate::hw::gpu::types::{Color, Position, TexCoord, Texture};
+
+/// Settings of the GPU that apply to every drawn pixel
+#[derive(Copy, Clone, Debug)]
+pub struct DrawState {
+    /// Drawing area, inclusive
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+    /// Added to each vertex
+    pub offset: (i32, i32),
+    /// GP0(E6).b0: set bit 15 of the drawn pixels
+    pub set_mask: bool,
+    /// GP0(E6).b1: leave the pixels with bit 15 set alone
+    pub check_mask: bool,
+    /// Lines with this parity are not drawn to, see
+    /// `GpuAccuracyConfig::interlaced_fields`
+    pub skip_field: Option<i32>,
+}
+
+impl DrawState {
+    /// Writes `pixel` to VRAM, unless the mask bit protects the old one
+    pub fn write(&self, vram: &mut [u16], x: u32, y: u32, pixel: u16) {
+        let index = (y as usize & 0x1ff) * 1024 + (x as usize & 0x3ff);
+        if self.check_mask && vram[index] & 0x8000 != 0 {
+            return;
+        }
+
+        vram[index] = pixel | if self.set_mask { 0x8000 } else { 0 };
+    }
+}
+
+/// Rectangle of VRAM, inclusive
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Area {
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+}
+
+impl Area {
+    pub fn union(self, other: Area) -> Area {
+        Area {
+            left: self.left.min(other.left),
+            top: self.top.min(other.top),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+
+    pub fn overlaps(&self, other: &Area) -> bool {
+        self.left <= other.right
+            && other.left <= self.right
+            && self.top <= other.bottom
+            && other.top <= self.bottom
+    }
+
+    /// `width` by `height` pixels from `x`, `y`. Areas wrapping around the
+    /// right edge of VRAM take its whole width.
+    fn at(x: u32, y: u32, width: u32, height: u32) -> Area {
+        let (left, right) = match x + width > 1024 {
+            true => (0, 1023),
+            false => (x, x + width - 1),
+        };
+        Area { left, top: y, right, bottom: (y + height - 1).min(511) }
+    }
+}
+
+/// Where `polygon` can draw: the box around its vertices, within the
+/// drawing area
+pub fn drawn_area(state: &DrawState, polygon: &Polygon) -> Option<Area> {
+    let vertices = polygon.positions.map(|position| vertex(position, state.offset));
+    let p = &vertices[..polygon.vertices];
+
+    let left = p.iter().map(|v| v.0).min()?.max(state.left).max(0);
+    let right = p.iter().map(|v| v.0).max()?.min(state.right).min(1023);
+    let top = p.iter().map(|v| v.1).min()?.max(state.top).max(0);
+    let bottom = p.iter().map(|v| v.1).max()?.min(state.bottom).min(511);
+
+    (left <= right && top <= bottom).then_some(Area {
+        left: left as u32,
+        top: top as u32,
+        right: right as u32,
+        bottom: bottom as u32,
+    })
+}
+
+/// The texture page and the CLUT `texture` reads its texels from
+pub fn texture_areas(texture: Texture) -> [Area; 2] {
+    let page_x = (texture.page as u32 & 0xf) * 64;
+    let page_y = ((texture.page as u32 >> 4) & 1) * 256;
+    let clut_x = (texture.clut as u32 & 0x3f) * 16;
+    let clut_y = (texture.clut as u32 >> 6) & 0x1ff;
+
+    let page = |width| Area::at(page_x, page_y, width, 256);
+    match (texture.page >> 7) & 3 {
+        0 => [page(64), Area::at(clut_x, clut_y, 16, 1)],
+        1 => [page(128), Area::at(clut_x, clut_y, 256, 1)],
+        _ => [page(256); 2],
+    }
+}
+
+/// Added to 8-bit channels before they are cut to 5 bits, by the position
+/// of the pixel
+const DITHER: [[i32; 4]; 4] = [[-4, 0, -3, 1], [2, -2, 3, -1], [-3, 1, -4, 0], [3, -1, 2, -2]];
+
+fn to_5bit(c8: u32, dither: i32) -> u16 {
+    ((c8.min(255) as i32 + dither).clamp(0, 255) >> 3) as u16
+}
+
+fn vram_pixel(vram: &[u16], x: u32, y: u32) -> u16 {
+    vram[(y as usize & 0x1ff) * 1024 + (x as usize & 0x3ff)]
+}
+
+/// Texel at `u`, `v` of the texture page, looked up in the CLUT for 4 and
+/// 8-bit textures. Same as `texel` in the fragment shader.
+fn texel(vram: &[u16], u: u32, v: u32, texture: Texture) -> u16 {
+    let page_x = (texture.page as u32 & 0xf) * 64;
+    let page_y = ((texture.page as u32 >> 4) & 1) * 256;
+    let clut_x = (texture.clut as u32 & 0x3f) * 16;
+    let clut_y = (texture.clut as u32 >> 6) & 0x1ff;
+
+    match (texture.page >> 7) & 3 {
+        0 => {
+            let pixel = vram_pixel(vram, page_x + (u >> 2), page_y + v);
+            let index = (pixel >> ((u & 3) * 4)) & 0xf;
+            vram_pixel(vram, clut_x + index as u32, clut_y)
+        }
+        1 => {
+            let pixel = vram_pixel(vram, page_x + (u >> 1), page_y + v);
+            let index = (pixel >> ((u & 1) * 8)) & 0xff;
+            vram_pixel(vram, clut_x + index as u32, clut_y)
+        }
+        _ => vram_pixel(vram, page_x + u, page_y + v),
+    }
+}
+
+/// Blends the 5-bit channels of `front` over `back`, with a semi
+/// transparency mode of `Texture::semi_transparency`
+fn blend(back: u16, front: u16, mode: u16) -> u16 {
+    let mut pixel = 0;
+
+    for shift in [0, 5, 10] {
+        let b = ((back >> shift) & 0x1f) as i32;
+        let f = ((front >> shift) & 0x1f) as i32;
+        let c = match mode {
+            0 => (b + f) / 2,
+            1 => b + f,
+            2 => b - f,
+            _ => b + f / 4,
+        };
+        pixel |= (c.clamp(0, 31) as u16) << shift;
+    }
+
+    pixel
+}
+
+/// Vertex coordinates are 11-bit signed values
+fn vertex(position: Position, offset: (i32, i32)) -> (i32, i32) {
+    let x = ((position.0 << 5) >> 5) as i32;
+    let y = ((position.1 << 5) >> 5) as i32;
+    (x + offset.0, y + offset.1)
+}
+
+/// Twice the signed area of `a`, `b`, `c`: positive when `c` is on the
+/// right of `a` -> `b`, with Y going down
+fn edge(a: (i32, i32), b: (i32, i32), c: (i32, i32)) -> i64 {
+    (b.0 - a.0) as i64 * (c.1 - a.1) as i64 - (b.1 - a.1) as i64 * (c.0 - a.0) as i64
+}
+
+/// Pixels on the edges going up, and on the top ones, belong to the
+/// triangle. The others go to its neighbours: the GPU leaves out the
+/// right and bottom edges.
+fn owns_edge(a: (i32, i32), b: (i32, i32)) -> bool {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    dy < 0 || (dy == 0 && dx > 0)
+}
+
+/// Draws `polygon`, quads as two triangles. Returns the pixels covered.
+pub fn polygon(vram: &mut [u16], state: &DrawState, polygon: &Polygon) -> u64 {
+    let mut pixels = 0;
+    for &[a, b, c] in polygon.triangles() {
+        pixels += triangle(
+            vram,
+            state,
+            [a, b, c].map(|v| polygon.positions[v]),
+            [a, b, c].map(|v| polygon.colors[v]),
+            [a, b, c].map(|v| polygon.texcoords[v]),
+            polygon.texture,
+        );
+    }
+    pixels
+}
+
+pub fn triangle(
+    vram: &mut [u16],
+    state: &DrawState,
+    positions: [Position; 3],
+    colors: [Color; 3],
+    texcoords: [TexCoord; 3],
+    texture: Texture,
+) -> u64 {
+    let mut p = positions.map(|position| vertex(position, state.offset));
+    let mut colors = colors;
+    let mut texcoords = texcoords;
+
+    let xs = p.map(|v| v.0);
+    let ys = p.map(|v| v.1);
+    let (min_x, max_x) = (*xs.iter().min().unwrap(), *xs.iter().max().unwrap());
+    let (min_y, max_y) = (*ys.iter().min().unwrap(), *ys.iter().max().unwrap());
+
+    // The GPU skips polygons larger than that
+    if max_x - min_x > 1023 || max_y - min_y > 511 {
+        return 0;
+    }
+
+    let mut area = edge(p[0], p[1], p[2]);
+    if area == 0 {
+        return 0;
+    }
+    if area < 0 {
+        p.swap(1, 2);
+        colors.swap(1, 2);
+        texcoords.swap(1, 2);
+        area = -area;
+    }
+
+    let textured = texture.flags & Texture::TEXTURED != 0;
+    let raw = texture.flags & Texture::RAW != 0;
+    let semi_transparency = texture.semi_transparency();
+
+    let left = min_x.max(state.left).max(0);
+    let right = max_x.min(state.right).min(1023);
+    let top = min_y.max(state.top).max(0);
+    let bottom = max_y.min(state.bottom).min(511);
+
+    let edges = [(p[1], p[2]), (p[2], p[0]), (p[0], p[1])];
+    let owned = edges.map(|(a, b)| owns_edge(a, b));
+
+    let mut pixels = 0;
+    for y in top..=bottom {
+        if state.skip_field == Some(y & 1) {
+            continue;
+        }
+        for x in left..=right {
+            let w = edges.map(|(a, b)| edge(a, b, (x, y)));
+            if (0..3).any(|i| w[i] < 0 || (w[i] == 0 && !owned[i])) {
+                continue;
+            }
+            pixels += 1;
+
+            let interpolate = |values: [u8; 3]| -> u32 {
+                let sum: i64 = (0..3).map(|i| w[i] * values[i] as i64).sum();
+                (sum / area) as u32
+            };
+
+            let dither = match texture.flags & Texture::DITHERED {
+                0 => 0,
+                _ => DITHER[y as usize & 3][x as usize & 3],
+            };
+            let r = interpolate(colors.map(|c| c.0));
+            let g = interpolate(colors.map(|c| c.1));
+            let b = interpolate(colors.map(|c| c.2));
+
+            let (mut pixel, blended) = if textured {
+                let u = interpolate(texcoords.map(|t| t.0 as u8));
+                let v = interpolate(texcoords.map(|t| t.1 as u8));
+                let t = texel(vram, u, v, texture);
+
+                // Black, without the semi-transparency bit, is transparent
+                if t == 0 {
+                    continue;
+                }
+
+                let pixel = match raw {
+                    true => t,
+                    false => {
+                        // 0x80 is the neutral vertex color
+                        let modulate = |shift: u16, c: u32| {
+                            let t5 = ((t >> shift) & 0x1f) as u32;
+                            to_5bit(t5 * 8 * c / 128, dither) << shift
+                        };
+                        (t & 0x8000) | modulate(0, r) | modulate(5, g) | modulate(10, b)
+                    }
+                };
+                (pixel, t & 0x8000 != 0)
+            } else {
+                let pixel = to_5bit(r, dither) | to_5bit(g, dither) << 5 | to_5bit(b, dither) << 10;
+                (pixel, true)
+            };
+
+            if let (Some(mode), true) = (semi_transparency, blended) {
+                let back = vram_pixel(vram, x as u32, y as u32);
+                pixel = (pixel & 0x8000) | blend(back, pixel, mode);
+            }
+
+            state.write(vram, x as u32, y as u32, pixel);
+        }
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hw::gpu::packet::Rect;
+
+    fn state() -> DrawState {
+        DrawState {
+            left: 0,
+            top: 0,
+            right: 1023,
+            bottom: 511,
+            offset: (0, 0),
+            set_mask: false,
+            check_mask: false,
+            skip_field: None,
+        }
+    }
+
+    fn quad(vram: &mut [u16], state: &DrawState, corners: [(i16, i16); 4], texture: Texture) {
+        let positions = corners.map(|(x, y)| Position(x, y));
+        let colors = [Color(0xff, 0, 0); 4];
+        let texcoords = corners.map(|(x, y)| TexCoord(x as u16, y as u16));
+        for [a, b, c] in [[0, 1, 2], [1, 2, 3]] {
+            triangle(
+                vram,
+                state,
+                [positions[a], positions[b], positions[c]],
+                [colors[a], colors[b], colors[c]],
+                [texcoords[a], texcoords[b], texcoords[c]],
+                texture,
+            );
+        }
+    }
+
+    fn count(vram: &[u16], pixel: u16) -> usize {
+        vram.iter().filter(|&&p| p == pixel).count()
+    }
+
+    #[test]
+    fn quads_cover_their_pixels_once() {
+        let mut vram = vec![0; 1024 * 512];
+        let corners = [(2, 1), (6, 1), (2, 5), (6, 5)];
+
+        // Pixels drawn twice would be blended twice
+        let texture = Texture {
+            flags: Texture::SEMI_TRANSPARENT,
+            ..Texture::default()
+        };
+        quad(&mut vram, &state(), corners, texture);
+
+        assert_eq!(count(&vram, 0x0f), 16);
+        assert_eq!(vram[1024 + 2], 0x0f);
+        assert_eq!(vram[4 * 1024 + 5], 0x0f);
+        assert_eq!(vram[5 * 1024 + 5], 0);
+        assert_eq!(vram[1024 + 6], 0);
+    }
+
+    #[test]
+    fn the_drawing_area_and_the_mask_bit_clip() {
+        let mut vram = vec![0; 1024 * 512];
+        let mut state = state();
+        state.right = 3;
+        state.set_mask = true;
+        quad(&mut vram, &state, [(0, 0), (8, 0), (0, 8), (8, 8)], Texture::default());
+        assert_eq!(count(&vram, 0x801f), 32);
+
+        // Masked pixels stay, whatever the blending
+        state.right = 1023;
+        state.set_mask = false;
+        state.check_mask = true;
+        let texture = Texture {
+            page: 2 << 5,
+            flags: Texture::SEMI_TRANSPARENT,
+            ..Texture::default()
+        };
+        quad(&mut vram, &state, [(0, 0), (8, 0), (0, 8), (8, 8)], texture);
+        assert_eq!(count(&vram, 0x801f), 32);
+        // B-F from black
+        assert_eq!(count(&vram, 0), 1024 * 512 - 32);
+    }
+
+    #[test]
+    fn textures_go_through_the_clut() {
+        let mut vram = vec![0; 1024 * 512];
+
+        // 4-bit texels 1, 2, 0, 3 at page 1, CLUT at (0, 256)
+        vram[64] = 0x3021;
+        vram[256 * 1024 + 1] = 0x7c00;
+        vram[256 * 1024 + 2] = 0x8010;
+        vram[256 * 1024 + 3] = 0x0010;
+        vram[1023] = 0x4210;
+
+        let texture = Texture {
+            page: 1 | (3 << 5),
+            clut: 256 << 6,
+            flags: Texture::TEXTURED | Texture::RAW | Texture::SEMI_TRANSPARENT,
+        };
+        let state = DrawState { offset: (1019, 0), ..state() };
+        quad(&mut vram, &state, [(0, 0), (4, 0), (0, 1), (4, 1)], texture);
+
+        // Opaque, blended with B+F/4, transparent, opaque
+        assert_eq!(&vram[1019..1023], &[0x7c00, 0x8004, 0, 0x0010]);
+        assert_eq!(vram[1023], 0x4210);
+    }
+
+    #[test]
+    fn areas_read_and_drawn() {
+        let texture = Texture {
+            page: 15 | (1 << 4) | (1 << 7),
+            clut: 3 | (480 << 6),
+            flags: Texture::TEXTURED,
+        };
+        let [page, clut] = texture_areas(texture);
+        assert_eq!(page, Area { left: 0, top: 256, right: 1023, bottom: 511 });
+        assert_eq!(clut, Area { left: 48, top: 480, right: 303, bottom: 480 });
+
+        let polygon = Rect::parse(&[0x6000_0000, 0x0010_fff0, 0x0100_0100], 0).polygon();
+        let state = DrawState { offset: (4, 8), bottom: 200, ..state() };
+        let drawn = drawn_area(&state, &polygon).unwrap();
+        assert_eq!(drawn, Area { left: 0, top: 24, right: 244, bottom: 200 });
+        assert!(!drawn.overlaps(&clut));
+        assert!(drawn.union(clut).overlaps(&page));
+
+        let state = DrawState { offset: (0, 300), ..state };
+        assert_eq!(drawn_area(&state, &polygon), None);
+    }
+
+    #[test]
+    fn dithering_follows_the_pixel_position() {
+        let mut vram = vec![0; 1024 * 512];
+        let positions = [Position(0, 0), Position(8, 0), Position(0, 8)];
+        // 0x86 is 16.75 in 5 bits
+        let colors = [Color(0x86, 0x86, 0x86); 3];
+        let texture = Texture {
+            flags: Texture::DITHERED,
+            ..Texture::default()
+        };
+        let texcoords = [TexCoord::default(); 3];
+        triangle(&mut vram, &state(), positions, colors, texcoords, texture);
+
+        let gray = |c: u16| c | c << 5 | c << 10;
+        // -4 at (0, 0), +2 at (0, 1), +3 at (2, 1)
+        assert_eq!(vram[0], gray(16));
+        assert_eq!(vram[1024], gray(17));
+        assert_eq!(vram[1024 + 2], gray(17));
+
+        // Without the flag, the bits are cut
+        triangle(&mut vram, &state(), positions, colors, texcoords, Texture::default());
+        assert_eq!(vram[1024], gray(16));
+        assert_eq!(vram[1024 + 2], gray(16));
+    }
+
+    #[test]
+    fn the_displayed_field_is_skipped() {
+        let mut vram = vec![0; 1024 * 512];
+        let state = DrawState {
+            skip_field: Some(1),
+            ..state()
+        };
+        quad(&mut vram, &state, [(0, 0), (4, 0), (0, 4), (4, 4)], Texture::default());
+
+        assert_eq!(count(&vram, 0x1f), 8);
+        assert_eq!(vram[2 * 1024], 0x1f);
+        assert_eq!(vram[1024], 0);
+    }
+}
@@ -1,13 +1,37 @@
-use gl::types::{GLint, GLshort, GLsizei, GLsizeiptr, GLubyte, GLuint};
+use gl::types::{GLenum, GLint, GLsizei, GLsizeiptr, GLuint};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
 use sdl2::video::GLProfile;
 
+use std::ffi::CStr;
 use std::mem::size_of;
 use std::ptr;
 use std::slice;
+use std::time::Instant;
 
+/// Texture unit of the VRAM copy, 0 and 1 are used by post-processing
+const VRAM_TEXTURE_UNIT: GLuint = 2;
+
+/// Size of the crosshair, in window pixels
+const CROSSHAIR_ARM: GLint = 8;
+const CROSSHAIR_THICKNESS: GLint = 2;
+/// Size of the input display cells and of its margin, in window pixels
+const INPUT_DISPLAY_CELL: GLint = 10;
+const INPUT_DISPLAY_MARGIN: GLint = 8;
+
+use crate::hw::gpu::crosshair::{Crosshair, Viewport};
+use crate::hw::gpu::input::{Pad, PadButton, INPUT_DISPLAY_CELLS, INPUT_DISPLAY_STICKS};
+use crate::hw::gpu::latency::LatencyHistogram;
+use crate::hw::gpu::packet::Polygon;
+use crate::hw::gpu::postprocess::{FrameBlend, Pass, PostProcessor, Target};
+use crate::hw::gpu::types::{
+    Color, ColorProfile, DisplayArea, GpuPreference, Hotkey, Position, RendererOptions, TexCoord,
+    Texture, WindowGeometry, VRAM_HEIGHT, VRAM_WIDTH,
+};
 use crate::hw::gpu::shaders::{
     compile_shader, find_program_attrib, find_program_uniform, link_program,
 };
+use crate::hw::joy_mc::Rumble;
 
 pub struct Renderer {
     /// SDL2 Window
@@ -16,6 +40,19 @@ pub struct Renderer {
     /// OpenGL Context
     #[allow(dead_code)]
     gl_context: sdl2::video::GLContext,
+    /// Window and keyboard events
+    events: sdl2::EventPump,
+    /// Played with the keyboard and the game controllers
+    pad: Pad,
+    crosshair: Crosshair,
+    /// Shows the pad buttons in a corner of the window
+    input_display: bool,
+    /// The area of VRAM shown by the last draw()
+    displayed: DisplayArea,
+    /// Pictures shown, black frames included, for the rate in the title
+    presented: u64,
+    /// When the title was last updated, and `presented` then
+    title_sample: (Instant, u64),
     /// Framebuffer horizontal resolution (native: 1024)
     fb_x_res: u16,
     /// Framebuffer vertical resolution (native: 512)
@@ -36,34 +73,123 @@ pub struct Renderer {
     positions: Buffer<Position>,
     /// Buffer containing the vertice colors
     colors: Buffer<Color>,
+    /// Buffer containing the vertice texture coordinates
+    texcoords: Buffer<TexCoord>,
+    /// Buffer containing the texture page and CLUT of each vertice
+    textures: Buffer<Texture>,
+    /// Copy of VRAM textures are read from, one 16-bit pixel per texel
+    vram: GLuint,
     /// Current number or vertices in the buffers
     nvertices: u32,
+    /// When the first of the queued primitives was pushed
+    queued_since: Option<Instant>,
+    /// Time from `queued_since` to the primitives being drawn
+    latency: LatencyHistogram,
     /// Index of the "offset" shader uniform
     uniform_offset: GLint,
+    /// Index of the "blend_pass" shader uniform
+    uniform_blend_pass: GLint,
+    /// Index of the "skip_field" shader uniform
+    uniform_skip_field: GLint,
+    /// Value of the "skip_field" uniform, see `set_skipped_field`
+    skipped_field: Option<i32>,
+    /// Whether the queued primitives subtract from the scene. That needs
+    /// another blend equation, so they are drawn separately.
+    subtracting: bool,
+    /// Offscreen buffer the primitives are drawn to
+    scene: Target,
+    /// The displayed area of the scene, stretched to the full target
+    display: Target,
+    /// Settings to restore if the context is lost
+    options: RendererOptions,
+    passes: Vec<Pass>,
+    /// Passes from the display to the window
+    post: PostProcessor,
+}
+
+/// RGBA color of a 15-bit VRAM pixel in the scene, like the fragment
+/// shader draws it: expanded by the video DAC, before the color profile
+fn expand(pixel: u16) -> [u8; 4] {
+    let channel = |shift: u16| {
+        let c5 = ((pixel >> shift) & 0x1f) as u8;
+        (c5 << 3) | (c5 >> 2)
+    };
+    [channel(0), channel(5), channel(10), 0xff]
 }
 
 impl Renderer {
-    pub fn new() -> Renderer {
+    pub fn new(options: &RendererOptions) -> Renderer {
+        if let Some(driver) = &options.video_driver {
+            sdl2::hint::set("SDL_VIDEODRIVER", driver);
+        }
+
+        // Read by the Mesa (DRI_PRIME) and NVIDIA (PRIME render offload)
+        // drivers on Linux when the context is created. Both follow the
+        // preference, whatever is inherited from the environment.
+        match options.gpu {
+            GpuPreference::Default => {}
+            GpuPreference::Integrated => {
+                std::env::set_var("DRI_PRIME", "0");
+                std::env::remove_var("__NV_PRIME_RENDER_OFFLOAD");
+                std::env::remove_var("__GLX_VENDOR_LIBRARY_NAME");
+            }
+            GpuPreference::Discrete => {
+                std::env::set_var("DRI_PRIME", "1");
+                std::env::set_var("__NV_PRIME_RENDER_OFFLOAD", "1");
+                // GLX fails to load the NVIDIA library without its driver
+                if std::path::Path::new("/proc/driver/nvidia").exists() {
+                    std::env::set_var("__GLX_VENDOR_LIBRARY_NAME", "nvidia");
+                }
+            }
+        }
+
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
 
         let gl_attr = video_subsystem.gl_attr();
         gl_attr.set_context_profile(GLProfile::Core);
-        gl_attr.set_context_flags().debug().set();
+        gl_attr.set_context_flags().debug().robust_access().set();
+        unsafe {
+            // Report driver resets (crashes, updates, GPU removal) instead
+            // of silently carrying on with a broken context
+            sdl2::sys::SDL_GL_SetAttribute(
+                sdl2::sys::SDL_GLattr::SDL_GL_CONTEXT_RESET_NOTIFICATION,
+                sdl2::sys::SDL_GLContextResetNotification::SDL_GL_CONTEXT_RESET_LOSE_CONTEXT as i32,
+            );
+        }
         gl_attr.set_context_version(3, 1);
         gl_attr.set_multisample_buffers(1);
         gl_attr.set_multisample_samples(4);
 
-        let window = video_subsystem
-            .window("RPSX", 1024, 512)
-            .opengl()
-            .build()
-            .unwrap();
+        let mut builder = match options.window {
+            Some(geometry) => {
+                let mut builder = video_subsystem.window("RPSX", geometry.width, geometry.height);
+                builder.position(geometry.x, geometry.y);
+                builder
+            }
+            None => video_subsystem.window("RPSX", 1024, 512),
+        };
+        builder.opengl().resizable();
+        if options.fullscreen {
+            builder.fullscreen_desktop();
+        }
+
+        let window = builder.build().unwrap();
+        let events = sdl_context.event_pump().unwrap();
+        let pad = Pad::new(sdl_context.game_controller().ok());
 
         let gl_context = window.gl_create_context().unwrap();
 
         gl::load_with(|s| video_subsystem.gl_get_proc_address(s) as *const std::os::raw::c_void);
 
+        println!(
+            "[GPU] OpenGL {} on {} ({}), video driver {}",
+            gl_string(gl::VERSION),
+            gl_string(gl::RENDERER),
+            gl_string(gl::VENDOR),
+            video_subsystem.current_video_driver()
+        );
+
         unsafe {
             gl::ClearColor(0., 0., 0., 1.0);
             gl::Clear(gl::COLOR_BUFFER_BIT);
@@ -122,14 +248,85 @@ impl Renderer {
             gl::VertexAttribPointer(index, 3, gl::UNSIGNED_BYTE, gl::TRUE, 0, ptr::null());
         }
 
+        // Setup the "texcoord" and "texture" attributes: 2 and 3
+        // GLushort, passed untouched
+        let texcoords = Buffer::new();
+
+        unsafe {
+            let index = find_program_attrib(program, "vertex_texcoord");
+            gl::EnableVertexAttribArray(index);
+            gl::VertexAttribIPointer(index, 2, gl::UNSIGNED_SHORT, 0, ptr::null());
+        }
+
+        let textures = Buffer::new();
+
+        unsafe {
+            let index = find_program_attrib(program, "vertex_texture");
+            gl::EnableVertexAttribArray(index);
+            gl::VertexAttribIPointer(index, 3, gl::UNSIGNED_SHORT, 0, ptr::null());
+        }
+
+        let mut vram = 0;
+        let blank = vec![0u16; VRAM_WIDTH * VRAM_HEIGHT];
+        unsafe {
+            gl::GenTextures(1, &mut vram);
+            gl::ActiveTexture(gl::TEXTURE0 + VRAM_TEXTURE_UNIT);
+            gl::BindTexture(gl::TEXTURE_2D, vram);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::R16UI as GLint,
+                VRAM_WIDTH as GLsizei,
+                VRAM_HEIGHT as GLsizei,
+                0,
+                gl::RED_INTEGER,
+                gl::UNSIGNED_SHORT,
+                blank.as_ptr() as *const _,
+            );
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as GLint);
+            gl::ActiveTexture(gl::TEXTURE0);
+
+            let uniform_vram = find_program_uniform(program, "vram");
+            gl::Uniform1i(uniform_vram, VRAM_TEXTURE_UNIT as GLint);
+        }
+
         let uniform_offset = find_program_uniform(program, "offset");
         unsafe {
             gl::Uniform2i(uniform_offset, 0, 0);
         }
 
-        Renderer {
+        let uniform_blend_pass = find_program_uniform(program, "blend_pass");
+        unsafe {
+            gl::Uniform1i(uniform_blend_pass, BlendPass::All as GLint);
+        }
+
+        let uniform_skip_field = find_program_uniform(program, "skip_field");
+        unsafe {
+            gl::Uniform1i(uniform_skip_field, -1);
+        }
+
+        let post = PostProcessor::new(&[], 1024, 512);
+
+        // Draw to the offscreen scene, it reaches the window in draw()
+        let scene = Target::new(1024, 512);
+        let display = Target::new(1024, 512);
+
+        let renderer = Renderer {
             window,
             gl_context,
+            events,
+            pad,
+            crosshair: Crosshair::new(),
+            input_display: false,
+            displayed: DisplayArea {
+                x: 0,
+                y: 0,
+                width: 256,
+                height: 240,
+            },
+            presented: 0,
+            title_sample: (Instant::now(), 0),
             fb_x_res: 1024,
             fb_y_res: 512,
             vertex_shader,
@@ -138,33 +335,300 @@ impl Renderer {
             vertex_array_object: vao,
             positions,
             colors,
+            texcoords,
+            textures,
+            vram,
             nvertices: 0,
+            queued_since: None,
+            latency: LatencyHistogram::default(),
             uniform_offset,
+            uniform_blend_pass,
+            uniform_skip_field,
+            skipped_field: None,
+            subtracting: false,
+            scene,
+            display,
+            options: options.clone(),
+            passes: vec![],
+            post,
+        };
+
+        renderer.bind_scene();
+        renderer
+    }
+
+    /// Texels are read from the copy of VRAM, see `write_vram`
+    pub fn push_polygon(&mut self, polygon: &Polygon) {
+        // Make sure we have enough room left to queue the vertices, quads
+        // are pushed as two triangles
+        let triangles = polygon.triangles();
+        if self.nvertices + 3 * triangles.len() as u32 > 64 * 1024 {
+            self.flush();
+        }
+        self.set_blending(polygon.texture);
+        self.queued_since.get_or_insert_with(Instant::now);
+
+        for triangle in triangles {
+            for &i in triangle {
+                self.push_vertex(
+                    polygon.positions[i],
+                    polygon.colors[i],
+                    polygon.texcoords[i],
+                    polygon.texture,
+                );
+            }
         }
     }
 
-    pub fn push_triangle(&mut self, positions: [Position; 3], colors: [Color; 3]) {
-        // Make sure we have enough room left to queue the vertex
-        if self.nvertices + 3 > 64 * 1024 {
-            println!("Vertex attribute buffers full, forcing draw");
-            self.draw();
+    /// Batches subtractive primitives apart from the others
+    fn set_blending(&mut self, texture: Texture) {
+        let subtracting = texture.semi_transparency() == Some(2);
+        if subtracting != self.subtracting {
+            self.flush();
+            self.subtracting = subtracting;
         }
+    }
 
-        for i in 0..3 {
-            // Push
-            self.positions.set(self.nvertices, positions[i]);
-            self.colors.set(self.nvertices, colors[i]);
-            self.nvertices += 1;
+    fn push_vertex(&mut self, position: Position, color: Color, texcoord: TexCoord, texture: Texture) {
+        self.positions.set(self.nvertices, position);
+        self.colors.set(self.nvertices, color);
+        self.texcoords.set(self.nvertices, texcoord);
+        self.textures.set(self.nvertices, texture);
+        self.nvertices += 1;
+    }
+
+    /// Writes whole lines of pixels to VRAM from line `top`: to the copy
+    /// used for texturing, and to the scene.
+    pub fn write_vram(&mut self, top: u16, pixels: &[u16]) {
+        self.write_texels(top, pixels);
+
+        let lines = (pixels.len() / 1024) as GLsizei;
+        let rgba: Vec<u8> = pixels
+            .chunks_exact(1024)
+            .rev()
+            .flatten()
+            .flat_map(|&pixel| expand(pixel))
+            .collect();
+
+        unsafe {
+            // The scene is upside down
+            gl::BindTexture(gl::TEXTURE_2D, self.scene.texture);
+            gl::TexSubImage2D(
+                gl::TEXTURE_2D,
+                0,
+                0,
+                512 - top as GLint - lines,
+                1024,
+                lines,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                rgba.as_ptr() as *const _,
+            );
+        }
+    }
+
+    /// Writes whole lines of pixels from line `top` to the copy of VRAM
+    /// used for texturing only, leaving the scene as drawn
+    pub fn write_texels(&mut self, top: u16, pixels: &[u16]) {
+        // Queued primitives use the old texels
+        self.flush();
+
+        unsafe {
+            gl::ActiveTexture(gl::TEXTURE0 + VRAM_TEXTURE_UNIT);
+            gl::BindTexture(gl::TEXTURE_2D, self.vram);
+            gl::TexSubImage2D(
+                gl::TEXTURE_2D,
+                0,
+                0,
+                top as GLint,
+                1024,
+                (pixels.len() / 1024) as GLsizei,
+                gl::RED_INTEGER,
+                gl::UNSIGNED_SHORT,
+                pixels.as_ptr() as *const _,
+            );
+            gl::ActiveTexture(gl::TEXTURE0);
+        }
+    }
+
+    /// Shows the displayed part of VRAM. Called once per frame, at VBlank.
+    pub fn draw(&mut self, area: DisplayArea) {
+        self.flush();
+
+        let fb_x_res = self.fb_x_res as GLint;
+        let fb_y_res = self.fb_y_res as GLint;
+
+        // Same scaling and vertical mirroring as the drawing area
+        let left = (area.x as GLint * fb_x_res) / 1024;
+        let right = ((area.x + area.width).min(1024) as GLint * fb_x_res) / 1024;
+        let top = fb_y_res - (area.y as GLint * fb_y_res) / 512;
+        let bottom = fb_y_res - ((area.y + area.height).min(512) as GLint * fb_y_res) / 512;
+
+        unsafe {
+            gl::Disable(gl::SCISSOR_TEST);
+            gl::Disable(gl::BLEND);
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.scene.framebuffer);
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, self.display.framebuffer);
+            gl::BlitFramebuffer(
+                left,
+                bottom,
+                right,
+                top,
+                0,
+                0,
+                fb_x_res,
+                fb_y_res,
+                gl::COLOR_BUFFER_BIT,
+                gl::NEAREST,
+            );
+        }
+
+        let (width, height) = self.window.drawable_size();
+        self.post.run(&self.display, (width as GLsizei, height as GLsizei));
+        self.draw_input_display();
+        self.draw_crosshair(area);
+        self.window.gl_swap_window();
+        self.presented += 1;
+        self.displayed = area;
+
+        if self.post.frame_blend() == FrameBlend::BlackFrame {
+            // Relies on vsync to keep the black frame on screen for one
+            // refresh of the display
+            unsafe {
+                gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+                gl::Clear(gl::COLOR_BUFFER_BIT);
+            }
+            self.window.gl_swap_window();
+            self.presented += 1;
+        }
+
+        self.bind_scene();
+    }
+
+    /// Draws the crosshair over the picture in the window, snapped to the
+    /// center of the display pixel under the mouse
+    fn draw_crosshair(&self, area: DisplayArea) {
+        let viewport = Viewport::stretched(self.window.size());
+        let (x, y) = match self.crosshair.target(viewport, area) {
+            Some(target) => viewport.to_window(target, area),
+            None => return,
+        };
+
+        // Window coordinates to drawable pixels, from the bottom
+        let (width, height) = self.window.size();
+        let (drawable_width, drawable_height) = self.window.drawable_size();
+        let x = (x as i64 * drawable_width as i64 / width as i64) as GLint;
+        let y = (y as i64 * drawable_height as i64 / height as i64) as GLint;
+        let y = drawable_height as GLint - 1 - y;
+        let scale = self.pixel_scale();
+        let (arm, thickness) = (CROSSHAIR_ARM * scale, CROSSHAIR_THICKNESS * scale);
+
+        let bars = [
+            (x - arm, y - thickness / 2, 2 * arm + 1, thickness),
+            (x - thickness / 2, y - arm, thickness, 2 * arm + 1),
+        ];
+        self.fill_rects(&bars, [1.0, 0.2, 0.2]);
+    }
+
+    /// Draws the buttons of the pad in the bottom left corner of the
+    /// window, lit while they are pressed, and the position of the sticks
+    fn draw_input_display(&self) {
+        if !self.input_display {
+            return;
         }
+
+        let scale = self.pixel_scale();
+        let (cell, margin) = (INPUT_DISPLAY_CELL * scale, INPUT_DISPLAY_MARGIN * scale);
+        let (columns, rows) = INPUT_DISPLAY_CELLS;
+        let background = (margin, margin, columns * cell + scale, rows * cell + scale);
+        self.fill_rects(&[background], [0.1, 0.1, 0.1]);
+
+        // A pixel of background is left around each button
+        let buttons = self.pad.buttons();
+        let (pressed, released): (Vec<_>, Vec<_>) =
+            PadButton::ALL.iter().partition(|button| button.pressed(buttons));
+        let rects = |buttons: Vec<&PadButton>| -> Vec<_> {
+            buttons
+                .iter()
+                .map(|button| {
+                    let (x, y, width, height) = button.input_display_cells();
+                    let (x, y) = (margin + x * cell + scale, margin + y * cell + scale);
+                    (x, y, width * cell - scale, height * cell - scale)
+                })
+                .collect()
+        };
+        self.fill_rects(&rects(released), [0.35, 0.35, 0.35]);
+        self.fill_rects(&rects(pressed), [0.95, 0.85, 0.2]);
+
+        // The knob moves over the box of the stick, one cell smaller
+        let [right_x, right_y, left_x, left_y] = self.pad.axes();
+        let positions = [(left_x, left_y), (right_x, right_y)];
+        let (mut boxes, mut knobs) = (vec![], vec![]);
+        for ((x, y), (axis_x, axis_y)) in INPUT_DISPLAY_STICKS.into_iter().zip(positions) {
+            let (x, y) = (margin + x * cell + scale, margin + y * cell + scale);
+            boxes.push((x, y, 3 * cell - scale, 3 * cell - scale));
+
+            // Up is 0x00, and the window counts from the bottom
+            let knob_x = x + axis_x as GLint * 2 * cell / 255;
+            let knob_y = y + (255 - axis_y as GLint) * 2 * cell / 255;
+            knobs.push((knob_x, knob_y, cell - scale, cell - scale));
+        }
+        self.fill_rects(&boxes, [0.2, 0.2, 0.2]);
+        self.fill_rects(&knobs, [0.95, 0.85, 0.2]);
+    }
+
+    /// Drawable pixels per window pixel, for HiDPI screens
+    fn pixel_scale(&self) -> GLint {
+        let (_, height) = self.window.size();
+        let (_, drawable_height) = self.window.drawable_size();
+        (drawable_height / height.max(1)).max(1) as GLint
     }
 
-    pub fn draw(&mut self) {
+    /// Fills rectangles of the window (left, bottom, width and height, in
+    /// drawable pixels) with `color`, over whatever was drawn
+    fn fill_rects(&self, rects: &[(GLint, GLint, GLint, GLint)], color: [f32; 3]) {
+        unsafe {
+            let mut scissor = [0; 4];
+            gl::GetIntegerv(gl::SCISSOR_BOX, scissor.as_mut_ptr());
+
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            gl::Enable(gl::SCISSOR_TEST);
+            gl::ClearColor(color[0], color[1], color[2], 1.0);
+            for &(left, bottom, width, height) in rects {
+                gl::Scissor(left, bottom, width, height);
+                gl::Clear(gl::COLOR_BUFFER_BIT);
+            }
+
+            gl::ClearColor(0., 0., 0., 1.0);
+            gl::Scissor(scissor[0], scissor[1], scissor[2], scissor[3]);
+            gl::Disable(gl::SCISSOR_TEST);
+        }
+    }
+
+    /// Draws the queued primitives to the scene
+    fn flush(&mut self) {
         unsafe {
             // Make sure all the data from the persistent mappings is
             // flushed to the buffer
             gl::MemoryBarrier(gl::CLIENT_MAPPED_BUFFER_BARRIER_BIT);
 
-            gl::DrawArrays(gl::TRIANGLES, 0, self.nvertices as GLsizei);
+            if self.subtracting {
+                // The opaque pixels first, then the semi-transparent ones
+                // are subtracted from the scene
+                gl::Uniform1i(self.uniform_blend_pass, BlendPass::Opaque as GLint);
+                gl::DrawArrays(gl::TRIANGLES, 0, self.nvertices as GLsizei);
+
+                gl::Uniform1i(self.uniform_blend_pass, BlendPass::Blended as GLint);
+                gl::BlendEquation(gl::FUNC_REVERSE_SUBTRACT);
+                gl::BlendFuncSeparate(gl::ONE, gl::ONE, gl::ZERO, gl::ONE);
+                gl::DrawArrays(gl::TRIANGLES, 0, self.nvertices as GLsizei);
+
+                gl::Uniform1i(self.uniform_blend_pass, BlendPass::All as GLint);
+                gl::BlendEquation(gl::FUNC_ADD);
+                gl::BlendFuncSeparate(gl::ONE, gl::SRC_ALPHA, gl::ZERO, gl::ONE);
+            } else {
+                gl::DrawArrays(gl::TRIANGLES, 0, self.nvertices as GLsizei);
+            }
         }
 
         // Wait for GPU to complete
@@ -183,13 +647,256 @@ impl Renderer {
 
         // Reset the buffers
         self.nvertices = 0;
+        if let Some(queued) = self.queued_since.take() {
+            self.latency.record(queued.elapsed());
+        }
+    }
 
-        self.window.gl_swap_window();
+    /// The latencies recorded since the last call
+    pub fn take_latency(&mut self) -> LatencyHistogram {
+        std::mem::take(&mut self.latency)
+    }
+
+    /// Gets back to drawing primitives to the scene
+    fn bind_scene(&self) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.scene.framebuffer);
+            gl::UseProgram(self.program);
+            gl::BindVertexArray(self.vertex_array_object);
+            gl::Viewport(0, 0, self.fb_x_res as GLsizei, self.fb_y_res as GLsizei);
+            gl::Enable(gl::SCISSOR_TEST);
+
+            // The fragment shader outputs the factor of the scene color as
+            // alpha: 0 for opaque pixels. The scene keeps its own alpha.
+            gl::Enable(gl::BLEND);
+            gl::BlendEquation(gl::FUNC_ADD);
+            gl::BlendFuncSeparate(gl::ONE, gl::SRC_ALPHA, gl::ZERO, gl::ONE);
+        }
+    }
+
+    /// Appends the hotkeys pressed since the last call to `hotkeys`. The
+    /// other events go to the pad.
+    pub fn poll_hotkeys(&mut self, hotkeys: &mut Vec<Hotkey>) {
+        for event in self.events.poll_iter() {
+            let hotkey = match event {
+                Event::Quit { .. } => Some(Hotkey::Quit),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    repeat: false,
+                    ..
+                } => Some(Hotkey::SaveState),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    repeat: false,
+                    ..
+                } => Some(Hotkey::LoadState),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    repeat: false,
+                    ..
+                } => Some(Hotkey::GpuAccuracy),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => Some(Hotkey::StatusPanel),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    repeat: false,
+                    ..
+                } => Some(Hotkey::Calibration),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } => Some(Hotkey::InputDisplay),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    repeat: false,
+                    ..
+                } => Some(Hotkey::FastForward),
+                _ => None,
+            };
+
+            let viewport = Viewport::stretched(self.window.size());
+            match hotkey {
+                Some(hotkey) => hotkeys.push(hotkey),
+                None if self.crosshair.handle_event(&event, viewport, self.displayed) => {}
+                None => self.pad.handle_event(&event),
+            }
+        }
+    }
+
+    /// Pressed buttons as 0
+    pub fn pad_buttons(&self) -> u16 {
+        self.pad.buttons()
+    }
+
+    pub fn pad_axes(&self) -> [u8; 4] {
+        self.pad.axes()
+    }
+
+    pub fn set_rumble(&mut self, rumble: Rumble) {
+        self.pad.set_rumble(rumble);
+    }
+
+    pub fn set_crosshair(&mut self, shown: bool) {
+        self.crosshair.shown = shown;
+    }
+
+    /// Returns whether the calibration mode is on now
+    pub fn toggle_calibration(&mut self) -> bool {
+        self.crosshair.calibrating = !self.crosshair.calibrating;
+        self.crosshair.calibrating
+    }
+
+    /// Shows in the title the emulated frame rate and speed, and how many
+    /// pictures the window showed per second since the last call
+    pub fn show_rates(&mut self, fps: f64, speed: f64, fast_forward: bool) {
+        let (last, presented) = self.title_sample;
+        let elapsed = last.elapsed().as_secs_f64().max(f64::EPSILON);
+        let host_fps = (self.presented - presented) as f64 / elapsed;
+        self.title_sample = (Instant::now(), self.presented);
+
+        let title = format!(
+            "RPSX - {:.1} FPS ({:.0}%), host {:.1} FPS{}",
+            fps,
+            speed,
+            host_fps,
+            if fast_forward { ", fast forward" } else { "" }
+        );
+        // Only fails on NUL characters
+        let _ = self.window.set_title(&title);
+    }
+
+    pub fn set_input_display(&mut self, shown: bool) {
+        self.input_display = shown;
+    }
+
+    pub fn input_display(&self) -> bool {
+        self.input_display
+    }
+
+    /// Copy of the scene, as RGBA rows from the bottom one
+    pub fn read_scene(&mut self) -> Vec<u8> {
+        self.flush();
+
+        let (width, height) = (self.fb_x_res as GLsizei, self.fb_y_res as GLsizei);
+        let mut pixels = vec![0; (width * height * 4) as usize];
+        unsafe {
+            gl::ReadPixels(
+                0,
+                0,
+                width,
+                height,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                pixels.as_mut_ptr() as *mut _,
+            );
+        }
+
+        pixels
+    }
+
+    /// Replaces the scene with pixels from `read_scene`. Primitives queued
+    /// and not drawn yet are dropped.
+    pub fn write_scene(&mut self, pixels: &[u8]) {
+        let (width, height) = (self.fb_x_res as GLsizei, self.fb_y_res as GLsizei);
+        if pixels.len() != (width * height * 4) as usize {
+            println!("[GPU] Saved VRAM has the wrong size, not restoring it");
+            return;
+        }
+
+        self.nvertices = 0;
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.scene.texture);
+            gl::TexSubImage2D(
+                gl::TEXTURE_2D,
+                0,
+                0,
+                0,
+                width,
+                height,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                pixels.as_ptr() as *const _,
+            );
+        }
+    }
+
+    /// Audio shares the SDL context of the window
+    #[cfg(feature = "audio")]
+    pub fn audio_subsystem(&self) -> Result<sdl2::AudioSubsystem, String> {
+        self.window.subsystem().sdl().audio()
+    }
+
+    pub fn window_geometry(&self) -> WindowGeometry {
+        let (x, y) = self.window.position();
+        let (width, height) = self.window.size();
+
+        WindowGeometry {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.window.fullscreen_state() != sdl2::video::FullscreenType::Off
+    }
+
+    /// Whether the driver reset the context. Everything stored on the
+    /// host GPU, including the scene, is gone.
+    pub fn context_lost(&self) -> bool {
+        // Needs GL 4.5 or KHR_robustness
+        gl::GetGraphicsResetStatus::is_loaded()
+            && unsafe { gl::GetGraphicsResetStatus() } != gl::NO_ERROR
+    }
+
+    /// Replaces a renderer whose context was lost with a new one, with the
+    /// same settings. The drawing area and offset must be set again.
+    pub fn recreate(self) -> Renderer {
+        let options = self.options.clone();
+        let color_profile = self.post.color_profile();
+        let passes = self.passes.clone();
+        let frame_blend = self.post.frame_blend();
+        let crosshair = (self.crosshair.shown, self.crosshair.calibrating);
+        let input_display = self.input_display;
+
+        // The old objects must go before the new context reuses their names
+        drop(self);
+
+        let mut renderer = Renderer::new(&options);
+        (renderer.crosshair.shown, renderer.crosshair.calibrating) = crosshair;
+        renderer.input_display = input_display;
+        renderer.set_color_profile(color_profile);
+        renderer.set_post_processing(&passes);
+        renderer.set_frame_blend(frame_blend);
+        renderer
+    }
+
+    /// Replaces the post-processing chain
+    pub fn set_post_processing(&mut self, passes: &[Pass]) {
+        let frame_blend = self.post.frame_blend();
+        let color_profile = self.post.color_profile();
+        self.passes = passes.to_vec();
+
+        self.post = PostProcessor::new(passes, self.fb_x_res as GLsizei, self.fb_y_res as GLsizei);
+        self.post.set_frame_blend(frame_blend);
+        self.post.set_color_profile(color_profile);
+
+        // Compiling the passes and creating their targets changed the state
+        self.bind_scene();
+    }
+
+    pub fn set_frame_blend(&mut self, frame_blend: FrameBlend) {
+        self.post.set_frame_blend(frame_blend);
     }
 
     pub fn set_draw_offset(&mut self, x: i16, y: i16) {
         // Force draw for the primitives with the current offset
-        self.draw();
+        self.flush();
 
         // Update the uniform value
         unsafe {
@@ -197,11 +904,31 @@ impl Renderer {
         }
     }
 
+    /// Lines of VRAM with this parity are left alone by the next
+    /// primitives, see `GpuAccuracyConfig::interlaced_fields`
+    pub fn set_skipped_field(&mut self, field: Option<i32>) {
+        if field == self.skipped_field {
+            return;
+        }
+
+        // Queued primitives were sent with the old field
+        self.flush();
+        self.skipped_field = field;
+        unsafe {
+            gl::Uniform1i(self.uniform_skip_field, field.unwrap_or(-1));
+        }
+    }
+
+    /// Applied when presenting, to everything in the frame
+    pub fn set_color_profile(&mut self, profile: ColorProfile) {
+        self.post.set_color_profile(profile);
+    }
+
     /// Set the drawing area. Coordinates are offsets in the
     /// PlayStation VRAM
     pub fn set_drawing_area(&mut self, left: u16, top: u16, right: u16, bottom: u16) {
         // Render any pending primitives
-        self.draw();
+        self.flush();
 
         let fb_x_res = self.fb_x_res as GLint;
         let fb_y_res = self.fb_y_res as GLint;
@@ -237,53 +964,25 @@ impl Renderer {
             }
         }
     }
-
-    pub fn push_quad(&mut self, positions: [Position; 4], colors: [Color; 4]) {
-        // Make sure we have enough room left to queue the vertex. We
-        // need to push two triangles to draw a quad, so 6 vertex
-        if self.nvertices + 6 > 64 * 1024 {
-            self.draw();
-        }
-
-        // Push the first triangle
-        for i in 0..3 {
-            self.positions.set(self.nvertices, positions[i]);
-            self.colors.set(self.nvertices, colors[i]);
-            self.nvertices += 1;
-        }
-
-        // Push the 2nd triangle
-        for i in 1..4 {
-            self.positions.set(self.nvertices, positions[i]);
-            self.colors.set(self.nvertices, colors[i]);
-            self.nvertices += 1;
-        }
-    }
 }
 
-#[derive(Copy, Clone, Debug, Default)]
-pub struct Position(pub GLshort, pub GLshort);
-
-impl Position {
-    pub fn parse(value: u32) -> Position {
-        let x = value & 0xfff;
-        let y = value >> 16;
-
-        Position(x as GLshort, y as GLshort)
+fn gl_string(name: GLenum) -> String {
+    unsafe {
+        let s = gl::GetString(name);
+        if s.is_null() {
+            String::from("unknown")
+        } else {
+            CStr::from_ptr(s as *const _).to_string_lossy().into_owned()
+        }
     }
 }
 
-#[derive(Copy, Clone, Debug, Default)]
-pub struct Color(pub GLubyte, pub GLubyte, pub GLubyte);
-
-impl Color {
-    pub fn parse(value: u32) -> Color {
-        let r = value & 0xff;
-        let g = (value >> 8) & 0xff;
-        let b = (value >> 16) & 0xff;
-
-        Color(r as GLubyte, g as GLubyte, b as GLubyte)
-    }
+/// Pixels drawn by a draw call, see the "blend_pass" shader uniform
+#[derive(Copy, Clone)]
+enum BlendPass {
+    All = 0,
+    Opaque = 1,
+    Blended = 2,
 }
 
 pub struct Buffer<T> {
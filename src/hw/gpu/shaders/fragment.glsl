@@ -1,8 +1,121 @@
 #version 330 core
 
 in vec3 color;
+in vec2 texcoord;
+flat in uvec3 texture_info;
 out vec4 frag_color;
 
+// Copy of VRAM, 16-bit pixels
+uniform usampler2D vram;
+
+// Texture flags, see Texture in renderer.rs
+const uint TEXTURED = 1u;
+const uint RAW = 2u;
+const uint SEMI_TRANSPARENT = 4u;
+const uint DITHERED = 8u;
+
+// Added to 8-bit channels before they are cut to 5 bits, by the position
+// of the pixel, 4 by 4. Same as DITHER in raster.rs.
+const int dither[16] = int[16](-4, 0, -3, 1, 2, -2, 3, -1, -3, 1, -4, 0, 3, -1, 2, -2);
+
+// Lines with this parity are not drawn to, -1 to draw them all
+uniform int skip_field;
+
+// Subtractive blending needs two draw calls with different blend
+// equations, each drawing part of the pixels. See BlendPass.
+uniform int blend_pass;
+
+const int PASS_ALL = 0;
+const int PASS_OPAQUE = 1;
+const int PASS_BLENDED = 2;
+
+uint vram_pixel(int x, int y) {
+  return texelFetch(vram, ivec2(x & 1023, y & 511), 0).r;
+}
+
+// 4 and 8-bit textures are indices into the CLUT, a row of 16 or 256
+// pixels. 15-bit textures hold the colors.
+uint texel(uvec2 uv, uint page, uint clut) {
+  int page_x = int(page & 0xfu) * 64;
+  int page_y = int((page >> 4) & 1u) * 256;
+  int clut_x = int(clut & 0x3fu) * 16;
+  int clut_y = int((clut >> 6) & 0x1ffu);
+
+  uint depth = (page >> 7) & 3u;
+  if (depth == 0u) {
+    uint pixel = vram_pixel(page_x + int(uv.x >> 2), page_y + int(uv.y));
+    uint index = (pixel >> ((uv.x & 3u) * 4u)) & 0xfu;
+    return vram_pixel(clut_x + int(index), clut_y);
+  } else if (depth == 1u) {
+    uint pixel = vram_pixel(page_x + int(uv.x >> 1), page_y + int(uv.y));
+    uint index = (pixel >> ((uv.x & 1u) * 8u)) & 0xffu;
+    return vram_pixel(clut_x + int(index), clut_y);
+  } else {
+    return vram_pixel(page_x + int(uv.x), page_y + int(uv.y));
+  }
+}
+
 void main() {
-    frag_color = vec4(color, 1.0);
-}
\ No newline at end of file
+  // The scene is upside down
+  ivec2 pixel = ivec2(int(gl_FragCoord.x), 511 - int(gl_FragCoord.y));
+  if (skip_field >= 0 && (pixel.y & 1) == skip_field) {
+    discard;
+  }
+
+  vec3 color8 = color * 255.0;
+  // Blend factor of the scene, 0 for opaque pixels
+  float alpha = 0.0;
+  bool blended = (texture_info.z & SEMI_TRANSPARENT) != 0u;
+
+  if ((texture_info.z & TEXTURED) != 0u) {
+    uvec2 uv = uvec2(floor(texcoord)) & 0xffu;
+    uint t = texel(uv, texture_info.x, texture_info.y);
+
+    // Black, without the semi-transparency bit, is transparent
+    if (t == 0u) {
+      discard;
+    }
+
+    vec3 t8 = vec3(t & 0x1fu, (t >> 5) & 0x1fu, (t >> 10) & 0x1fu) * 8.0;
+    if ((texture_info.z & RAW) != 0u) {
+      color8 = t8;
+    } else {
+      // 0x80 is the neutral vertex color
+      color8 = min(t8 * color8 / 128.0, vec3(255.0));
+    }
+
+    blended = blended && (t & 0x8000u) != 0u;
+  }
+
+  if ((blend_pass == PASS_OPAQUE && blended) || (blend_pass == PASS_BLENDED && !blended)) {
+    discard;
+  }
+
+  if ((texture_info.z & DITHERED) != 0u) {
+    color8 = clamp(color8 + float(dither[(pixel.y & 3) * 4 + (pixel.x & 3)]), 0.0, 255.0);
+  }
+
+  // VRAM only stores 5 bits per channel. The scene holds them expanded
+  // like the video DAC does, the color profile is applied when presenting.
+  ivec3 c5 = ivec3(round(color8)) >> 3;
+  vec3 rgb = vec3((c5 << 3) | (c5 >> 2)) / 255.0;
+
+  if (blended) {
+    // Semi-transparency mode, from the texture page
+    uint mode = (texture_info.x >> 5) & 3u;
+    if (mode == 0u) {
+      // Scene / 2 + pixel / 2
+      rgb *= 0.5;
+      alpha = 0.5;
+    } else if (mode == 3u) {
+      // Scene + pixel / 4
+      rgb *= 0.25;
+      alpha = 1.0;
+    } else {
+      // Scene + pixel, or scene - pixel with the subtracting equation
+      alpha = 1.0;
+    }
+  }
+
+  frag_color = vec4(rgb, alpha);
+}
@@ -0,0 +1,21 @@
+#version 330 core
+
+in vec2 uv;
+out vec4 frag_color;
+
+uniform sampler2D source;
+// 0: no effect, 1: only one phosphor lit per column
+uniform float strength;
+// Size of the source in pixels
+uniform vec2 resolution;
+
+void main() {
+  vec3 color = texture(source, uv).rgb;
+
+  // Trinitron-like vertical stripes of red, green and blue phosphors
+  int column = int(uv.x * resolution.x * 3.0) % 3;
+  vec3 mask = vec3(1.0 - strength);
+  mask[column] = 1.0;
+
+  frag_color = vec4(color * mask, 1.0);
+}
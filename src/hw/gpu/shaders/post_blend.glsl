@@ -0,0 +1,12 @@
+#version 330 core
+
+in vec2 uv;
+out vec4 frag_color;
+
+uniform sampler2D source;
+// The frame presented before this one
+uniform sampler2D previous;
+
+void main() {
+  frag_color = mix(texture(source, uv), texture(previous, uv), 0.5);
+}
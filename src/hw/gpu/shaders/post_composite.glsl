@@ -0,0 +1,41 @@
+#version 330 core
+
+in vec2 uv;
+out vec4 frag_color;
+
+uniform sampler2D source;
+// 0: clean RGB, 1: heavy composite artifacts
+uniform float strength;
+// Size of the source in pixels
+uniform vec2 resolution;
+
+const mat3 RGB_TO_YIQ = mat3(
+  0.299, 0.596, 0.211,
+  0.587, -0.274, -0.523,
+  0.114, -0.322, 0.312
+);
+
+const mat3 YIQ_TO_RGB = mat3(
+  1.0, 1.0, 1.0,
+  0.956, -0.272, -1.106,
+  0.621, -0.647, 1.703
+);
+
+void main() {
+  vec2 pixel = vec2(1.0 / resolution.x, 0.0);
+
+  // Luma keeps its full bandwidth, chroma is smeared horizontally
+  vec3 yiq = RGB_TO_YIQ * texture(source, uv).rgb;
+  vec2 chroma = vec2(0.0);
+  for (int i = -2; i <= 2; i++) {
+    chroma += (RGB_TO_YIQ * texture(source, uv + pixel * float(i)).rgb).yz;
+  }
+  yiq.yz = mix(yiq.yz, chroma / 5.0, strength);
+
+  // Dot crawl: chroma leaking into luma along a checkerboard that
+  // alternates every line
+  float phase = mod(floor(uv.x * resolution.x) + floor(uv.y * resolution.y), 2.0);
+  yiq.x += (phase - 0.5) * length(yiq.yz) * 0.1 * strength;
+
+  frag_color = vec4(YIQ_TO_RGB * yiq, 1.0);
+}
@@ -0,0 +1,10 @@
+#version 330 core
+
+in vec2 uv;
+out vec4 frag_color;
+
+uniform sampler2D source;
+
+void main() {
+  frag_color = texture(source, uv);
+}
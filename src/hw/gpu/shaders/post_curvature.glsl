@@ -0,0 +1,26 @@
+#version 330 core
+
+in vec2 uv;
+out vec4 frag_color;
+
+uniform sampler2D source;
+// 0: flat screen, 1: strongly curved
+uniform float strength;
+// Size of the source in pixels
+uniform vec2 resolution;
+
+void main() {
+  // Barrel distortion around the center of the screen
+  vec2 centered = uv * 2.0 - 1.0;
+  vec2 offset = centered.yx * strength * 0.25;
+  vec2 curved = centered + centered * offset * offset;
+  vec2 source_uv = curved * 0.5 + 0.5;
+
+  if (any(lessThan(source_uv, vec2(0.0))) || any(greaterThan(source_uv, vec2(1.0)))) {
+    frag_color = vec4(0.0, 0.0, 0.0, 1.0);
+  } else {
+    // Snap to the source pixels to keep the image sharp
+    vec2 texel = (floor(source_uv * resolution) + 0.5) / resolution;
+    frag_color = texture(source, texel);
+  }
+}
@@ -0,0 +1,38 @@
+#version 330 core
+
+in vec2 uv;
+out vec4 frag_color;
+
+uniform sampler2D source;
+
+// How the frame is adjusted for the host display, see ColorProfile. The
+// frame holds the output of the video DAC.
+uniform int profile;
+
+const int PROFILE_RAW = 0;
+const int PROFILE_GAMMA = 2;
+const int PROFILE_COMPOSITE = 3;
+
+void main() {
+  vec3 rgb = texture(source, uv).rgb;
+
+  if (profile == PROFILE_RAW) {
+    // What a plain shift gives: white is 248, not 255. The top 5 bits of
+    // the DAC output are the VRAM channel.
+    rgb = floor(round(rgb * 255.0) / 8.0) * 8.0 / 255.0;
+  }
+
+  if (profile == PROFILE_GAMMA || profile == PROFILE_COMPOSITE) {
+    // The console targets CRTs (gamma ~2.5), not sRGB displays (~2.2)
+    rgb = pow(rgb, vec3(2.5 / 2.2));
+  }
+
+  if (profile == PROFILE_COMPOSITE) {
+    // Composite video carries chroma with a lower bandwidth than luma,
+    // colors come out a bit washed out
+    float luma = dot(rgb, vec3(0.299, 0.587, 0.114));
+    rgb = mix(vec3(luma), rgb, 0.85);
+  }
+
+  frag_color = vec4(rgb, 1.0);
+}
@@ -0,0 +1,21 @@
+#version 330 core
+
+in vec2 uv;
+out vec4 frag_color;
+
+uniform sampler2D source;
+// 0: no effect, 1: black gaps between lines
+uniform float strength;
+// Size of the source in pixels
+uniform vec2 resolution;
+
+void main() {
+  vec3 color = texture(source, uv).rgb;
+
+  // Darken the edges of every line, as the CRT beam is narrower than the
+  // line pitch
+  float line = fract(uv.y * resolution.y);
+  float beam = 1.0 - strength * pow(abs(line - 0.5) * 2.0, 2.0);
+
+  frag_color = vec4(color * beam, 1.0);
+}
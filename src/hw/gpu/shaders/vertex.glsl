@@ -2,11 +2,16 @@
 
 in ivec2 vertex_position;
 in vec3 vertex_color;
+in uvec2 vertex_texcoord;
+// Texture page, CLUT and flags
+in uvec3 vertex_texture;
 
 // Drawing offset
 uniform ivec2 offset;
 
 out vec3 color;
+out vec2 texcoord;
+flat out uvec3 texture_info;
 
 void main() {
   ivec2 position = vertex_position + offset;
@@ -21,4 +26,6 @@ void main() {
   gl_Position.xyzw = vec4(xpos, ypos, 0.0, 1.0);
 
   color = vertex_color;
+  texcoord = vec2(vertex_texcoord);
+  texture_info = vertex_texture;
 }
\ No newline at end of file
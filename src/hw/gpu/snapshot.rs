@@ -0,0 +1,166 @@
+//! Decoded GPU state, published once per frame for the frontend.
+//!
+//! The emulation thread swaps in a new snapshot at every VBlank, and any
+//! other thread can load the latest one without locking: overlays and
+//! aspect ratio or crop decisions use it instead of guessing the state
+//! from the commands.
+
+use std::fmt;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::hw::gpu::types::DisplayArea;
+
+/// Where the snapshots are published, shared with the readers
+pub type GpuStateHandle = Arc<ArcSwap<GpuState>>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GpuState {
+    /// The part of VRAM shown in the last frame
+    pub display_area: DisplayArea,
+    pub display_enabled: bool,
+    pub interlaced: bool,
+    pub pal: bool,
+    /// 24-bit display, used by FMVs
+    pub color_24bit: bool,
+
+    /// Top left corner of the texture page in VRAM, and its texel depth
+    pub texture_page: (u16, u16),
+    pub texture_bits: u8,
+
+    /// Left, top, right, bottom, inclusive
+    pub drawing_area: (u16, u16, u16, u16),
+    pub drawing_offset: (i16, i16),
+
+    /// Display commands received during the frame, oldest first
+    pub display_commands: Vec<DisplayCommand>,
+}
+
+/// A GP1(03), (05), (06), (07) or (08), and when it was received. Games
+/// that show a black screen often got one of them wrong.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DisplayCommand {
+    pub command: u32,
+    /// Scanline being displayed, counting from the first visible one
+    pub line: u64,
+    /// Since the start of the vertical blank
+    pub cycles: u64,
+}
+
+impl Default for GpuState {
+    fn default() -> GpuState {
+        GpuState {
+            display_area: DisplayArea {
+                x: 0,
+                y: 0,
+                width: 256,
+                height: 240,
+            },
+            display_enabled: false,
+            interlaced: false,
+            pal: false,
+            color_24bit: false,
+            texture_page: (0, 0),
+            texture_bits: 4,
+            drawing_area: (0, 0, 0, 0),
+            drawing_offset: (0, 0),
+            display_commands: vec![],
+        }
+    }
+}
+
+impl fmt::Display for GpuState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let area = self.display_area;
+        let (left, top, right, bottom) = self.drawing_area;
+
+        writeln!(
+            f,
+            "GPU: {}x{} {} {} {}-bit at ({}, {}){}",
+            area.width,
+            area.height,
+            if self.pal { "PAL" } else { "NTSC" },
+            if self.interlaced { "interlaced" } else { "progressive" },
+            if self.color_24bit { 24 } else { 15 },
+            area.x,
+            area.y,
+            if self.display_enabled { "" } else { ", display off" },
+        )?;
+        writeln!(
+            f,
+            "     texpage ({}, {}) {}-bit, drawing area ({}, {})-({}, {}) offset ({}, {})",
+            self.texture_page.0,
+            self.texture_page.1,
+            self.texture_bits,
+            left,
+            top,
+            right,
+            bottom,
+            self.drawing_offset.0,
+            self.drawing_offset.1,
+        )?;
+
+        for command in &self.display_commands {
+            writeln!(f, "     {}", command)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DisplayCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GP1({:02x}) {:06x} at line {} (+{} cycles)",
+            self.command >> 24,
+            self.command & 0xff_ffff,
+            self.line,
+            self.cycles,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_for_the_status_panel() {
+        let state = GpuState {
+            display_area: DisplayArea {
+                x: 0,
+                y: 256,
+                width: 320,
+                height: 480,
+            },
+            interlaced: true,
+            texture_page: (640, 256),
+            texture_bits: 8,
+            drawing_area: (0, 0, 319, 239),
+            drawing_offset: (-8, 16),
+            ..GpuState::default()
+        };
+
+        assert_eq!(
+            state.to_string(),
+            "GPU: 320x480 NTSC interlaced 15-bit at (0, 256), display off\n     \
+             texpage (640, 256) 8-bit, drawing area (0, 0)-(319, 239) offset (-8, 16)\n"
+        );
+    }
+
+    #[test]
+    fn lists_the_display_commands_of_the_frame() {
+        let state = GpuState {
+            display_commands: vec![DisplayCommand {
+                command: 0x0300_0001,
+                line: 250,
+                cycles: 21_730,
+            }],
+            ..GpuState::default()
+        };
+
+        let text = state.to_string();
+        assert!(text.ends_with("\n     GP1(03) 000001 at line 250 (+21730 cycles)\n"));
+    }
+}
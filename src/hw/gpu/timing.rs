@@ -0,0 +1,173 @@
+//! Video timings. The GPU clock runs at 11/7 of the CPU clock; a scanline
+//! lasts 3413 GPU cycles in NTSC and 3406 in PAL, and a frame 263 or 314
+//! lines. Interlaced frames alternate between fields one line apart.
+//!
+//! Frames are counted from the start of the vertical blank, so the blank
+//! lines come first and are followed by the visible ones.
+
+/// GPU cycles per CPU cycle, as a fraction
+const GPU_CLOCK: (u64, u64) = (11, 7);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct VideoTiming {
+    pub pal: bool,
+    pub interlaced: bool,
+    /// Interlaced frames showing the odd field are one line shorter
+    pub odd_field: bool,
+}
+
+impl VideoTiming {
+    pub fn ticks_per_line(&self) -> u64 {
+        if self.pal {
+            3406
+        } else {
+            3413
+        }
+    }
+
+    pub fn lines_per_frame(&self) -> u64 {
+        let lines = if self.pal { 314 } else { 263 };
+        lines - (self.interlaced && self.odd_field) as u64
+    }
+
+    pub fn visible_lines(&self) -> u64 {
+        if self.pal {
+            288
+        } else {
+            240
+        }
+    }
+
+    /// From one vblank to the next, rounded up
+    pub fn frame_cycles(&self) -> u64 {
+        let ticks = self.lines_per_frame() * self.ticks_per_line();
+        (ticks * GPU_CLOCK.1).div_ceil(GPU_CLOCK.0)
+    }
+
+    /// Lines started in the `cycles` since the beginning of the vblank,
+    /// which is the count of horizontal blanks
+    pub fn lines_after(&self, cycles: u64) -> u64 {
+        gpu_ticks(cycles) / self.ticks_per_line()
+    }
+
+    /// Scanline shown `cycles` after the beginning of the vblank, counting
+    /// from the first visible one. Lines from `visible_lines()` onwards are
+    /// in the vertical blank.
+    pub fn line_at(&self, cycles: u64) -> u64 {
+        (self.lines_after(cycles) + self.visible_lines()) % self.lines_per_frame()
+    }
+}
+
+pub fn gpu_ticks(cycles: u64) -> u64 {
+    cycles * GPU_CLOCK.0 / GPU_CLOCK.1
+}
+
+/// CPU cycles lasting `ticks` GPU cycles, rounded up
+pub fn cpu_cycles(ticks: u64) -> u64 {
+    (ticks * GPU_CLOCK.1).div_ceil(GPU_CLOCK.0)
+}
+
+/// Horizontal resolution set by GP1(08): bits 0-1, or 368 pixels when
+/// bit 6 is set (GPUSTAT bits 17-18 and 16)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HorizontalRes {
+    H256,
+    H320,
+    H368,
+    H512,
+    H640,
+}
+
+impl HorizontalRes {
+    pub fn from_gpustat(horizontal_res2: bool, horizontal_res1: u32) -> HorizontalRes {
+        if horizontal_res2 {
+            return HorizontalRes::H368;
+        }
+
+        match horizontal_res1 & 3 {
+            0 => HorizontalRes::H256,
+            1 => HorizontalRes::H320,
+            2 => HorizontalRes::H512,
+            _ => HorizontalRes::H640,
+        }
+    }
+
+    /// GPU cycles per pixel, the dotclock timer 0 can count
+    pub fn dot_divider(self) -> u64 {
+        match self {
+            HorizontalRes::H256 => 10,
+            HorizontalRes::H320 => 8,
+            HorizontalRes::H368 => 7,
+            HorizontalRes::H512 => 5,
+            HorizontalRes::H640 => 4,
+        }
+    }
+
+    /// Pixels in a line with the standard display range, about 2560 GPU
+    /// cycles
+    pub fn width(self) -> u16 {
+        match self {
+            HorizontalRes::H256 => 256,
+            HorizontalRes::H320 => 320,
+            HorizontalRes::H368 => 368,
+            HorizontalRes::H512 => 512,
+            HorizontalRes::H640 => 640,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NTSC: VideoTiming = VideoTiming {
+        pal: false,
+        interlaced: false,
+        odd_field: false,
+    };
+
+    #[test]
+    fn frames_last_as_long_as_on_hardware() {
+        // About 59.29 Hz
+        assert_eq!(NTSC.frame_cycles(), 571_213);
+
+        let pal = VideoTiming { pal: true, ..NTSC };
+        assert_eq!(pal.frame_cycles(), 680_581);
+
+        // Odd fields are one line shorter, in interlaced mode only
+        let odd = VideoTiming { odd_field: true, ..NTSC };
+        assert_eq!(odd.lines_per_frame(), 263);
+        let odd = VideoTiming { interlaced: true, ..odd };
+        assert_eq!(odd.lines_per_frame(), 262);
+    }
+
+    #[test]
+    fn horizontal_resolutions() {
+        let modes = [
+            (false, 0, 10, 256),
+            (false, 1, 8, 320),
+            (false, 2, 5, 512),
+            (false, 3, 4, 640),
+            // Bit 6 overrides bits 0-1
+            (true, 0, 7, 368),
+            (true, 3, 7, 368),
+        ];
+        for (res2, res1, divider, width) in modes {
+            let res = HorizontalRes::from_gpustat(res2, res1);
+            assert_eq!(res.dot_divider(), divider, "{:?}", res);
+            assert_eq!(res.width(), width, "{:?}", res);
+            // The standard display range is 2560 GPU cycles wide
+            assert_eq!((2560 / divider as u16).div_ceil(16) * 16, width, "{:?}", res);
+        }
+    }
+
+    #[test]
+    fn blank_lines_come_first() {
+        assert_eq!(NTSC.line_at(0), 240);
+        assert_eq!(NTSC.line_at(2171), 240);
+        assert_eq!(NTSC.line_at(2172), 241);
+        assert_eq!(NTSC.lines_after(23 * 2172), 23);
+        assert_eq!(NTSC.line_at(23 * 2172), 0);
+        assert_eq!(NTSC.line_at(NTSC.frame_cycles() - 1), 239);
+    }
+}
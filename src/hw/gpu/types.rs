@@ -0,0 +1,104 @@
+//! Data exchanged between the GPU and the renderer, and the renderer
+//! options. Kept apart from the renderer, so a core built without the `gui`
+//! feature doesn't need SDL or OpenGL. The drawing data itself is in the
+//! `gpu-protocol` crate, shared with other frontends.
+
+pub use crustationgpu::{Color, DisplayArea, Position, TexCoord, Texture, VRAM_HEIGHT, VRAM_WIDTH};
+
+/// Keys handled by the emulator itself rather than the emulated pad
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub enum Hotkey {
+    /// F5
+    SaveState,
+    /// F7
+    LoadState,
+    /// F8, the next GPU accuracy preset
+    GpuAccuracy,
+    /// F9
+    StatusPanel,
+    /// F10, the light gun crosshair and its calibration mode
+    Calibration,
+    /// F11, the pad buttons shown over the picture
+    InputDisplay,
+    /// Tab, runs at the fast forward speed until pressed again
+    FastForward,
+    /// The window was closed
+    Quit,
+}
+
+/// How the 15-bit colors of the VRAM are converted for the host display
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorProfile {
+    /// Channels shifted left by 3, white is (248, 248, 248)
+    Raw,
+    /// Channels expanded to the full range like the video DAC does
+    Dac,
+    /// Dac, corrected from the CRT gamma to the sRGB one
+    Gamma,
+    /// Gamma, with the slightly washed out colors of a composite signal
+    Composite,
+}
+
+impl ColorProfile {
+    pub fn from_name(name: &str) -> Option<ColorProfile> {
+        match name {
+            "raw" => Some(ColorProfile::Raw),
+            "dac" => Some(ColorProfile::Dac),
+            "gamma" => Some(ColorProfile::Gamma),
+            "composite" => Some(ColorProfile::Composite),
+            _ => None,
+        }
+    }
+}
+
+/// Which GPU to run on, for machines with more than one
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GpuPreference {
+    /// Whatever the driver picks
+    Default,
+    Integrated,
+    Discrete,
+}
+
+impl GpuPreference {
+    pub fn from_name(name: &str) -> Option<GpuPreference> {
+        match name {
+            "default" => Some(GpuPreference::Default),
+            "integrated" => Some(GpuPreference::Integrated),
+            "discrete" => Some(GpuPreference::Discrete),
+            _ => None,
+        }
+    }
+}
+
+/// Settings used when creating the window and the OpenGL context
+#[derive(Clone, Debug)]
+pub struct RendererOptions {
+    /// SDL video driver, e.g. "x11" or "wayland". None for SDL's choice.
+    pub video_driver: Option<String>,
+    pub gpu: GpuPreference,
+    /// None for the default size, centered
+    pub window: Option<WindowGeometry>,
+    pub fullscreen: bool,
+}
+
+impl Default for RendererOptions {
+    fn default() -> RendererOptions {
+        RendererOptions {
+            video_driver: None,
+            gpu: GpuPreference::Default,
+            window: None,
+            fullscreen: false,
+        }
+    }
+}
+
+/// Position and size of the window on the desktop
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WindowGeometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
@@ -0,0 +1,125 @@
+//! Pad states queued for given frames, for scripts and TAS tools. They are
+//! applied at the VBlank that starts their frame, where the window's input
+//! is read too, so the game sees them at the same point as in live play and
+//! a replay follows the same timeline.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PadInput {
+    /// In the pad's bit order, pressed buttons as 0, see `Bus::set_buttons`
+    pub buttons: u16,
+    /// Right X, right Y, left X and left Y, 0x80 at the center
+    pub axes: [u8; 4],
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InputError {
+    /// The frame has started already, its input can't change anymore
+    Past { frame: u64, current: u64 },
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::Past { frame, current } => write!(
+                f,
+                "frame {} has already started, the current one is {}",
+                frame, current
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+#[derive(Default)]
+pub struct InputQueue {
+    inputs: BTreeMap<u64, PadInput>,
+}
+
+impl InputQueue {
+    pub fn new() -> InputQueue {
+        InputQueue::default()
+    }
+
+    /// Queues `input` for `frame`, replacing what was queued for it. Frames
+    /// after `current` only.
+    pub fn schedule(
+        &mut self,
+        frame: u64,
+        current: u64,
+        input: PadInput,
+    ) -> Result<(), InputError> {
+        if frame <= current {
+            return Err(InputError::Past { frame, current });
+        }
+        self.inputs.insert(frame, input);
+        Ok(())
+    }
+
+    /// The input of `frame`, which is starting. Anything queued before it
+    /// is dropped.
+    pub fn take(&mut self, frame: u64) -> Option<PadInput> {
+        let later = self.inputs.split_off(&(frame + 1));
+        let input = self.inputs.remove(&frame);
+        self.inputs = later;
+        input
+    }
+
+    pub fn clear(&mut self) {
+        self.inputs.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pad(buttons: u16) -> PadInput {
+        PadInput {
+            buttons,
+            axes: [0x80; 4],
+        }
+    }
+
+    #[test]
+    fn only_future_frames_can_be_scheduled() {
+        let mut queue = InputQueue::new();
+        assert_eq!(
+            queue.schedule(10, 10, pad(0)),
+            Err(InputError::Past {
+                frame: 10,
+                current: 10
+            })
+        );
+        assert!(queue.schedule(9, 10, pad(0)).is_err());
+        assert!(queue.schedule(11, 10, pad(0)).is_ok());
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn inputs_are_taken_on_their_frame() {
+        let mut queue = InputQueue::new();
+        queue.schedule(3, 0, pad(3)).unwrap();
+        queue.schedule(5, 0, pad(1)).unwrap();
+        queue.schedule(5, 0, pad(5)).unwrap();
+        queue.schedule(8, 0, pad(8)).unwrap();
+
+        assert_eq!(queue.take(1), None);
+        // Frame 3 was skipped, by loading a state for example
+        assert_eq!(queue.take(5), Some(pad(5)));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.take(8), Some(pad(8)));
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+//! Interrupt controller: I_STAT (0x1f80_1070) and I_MASK (0x1f80_1074).
+//!
+//! Every source drives a line, and a rising edge latches its bit in I_STAT
+//! until software acknowledges it by writing 0 there. A line held high
+//! doesn't latch again after an acknowledge. The CPU is interrupted (COP0
+//! CAUSE bit 10) while any latched bit is unmasked.
+
+use crate::hw::bus::BusDevice;
+use crustationcpu::state::{Savestate, StateError, StateReader, StateWriter};
+
+/// The interrupt sources, by I_STAT bit
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Irq {
+    VBlank = 0,
+    Gpu = 1,
+    Cdrom = 2,
+    Dma = 3,
+    Timer0 = 4,
+    Timer1 = 5,
+    Timer2 = 6,
+    Controller = 7,
+    Sio = 8,
+    Spu = 9,
+    Lightpen = 10,
+}
+
+/// I_STAT and I_MASK bits, one per source
+const IRQ_LINES: u32 = 0x7ff;
+
+/// Bits covered by an access of `S` bytes
+fn access_mask<const S: u32>() -> u32 {
+    match S {
+        4 => 0xffff_ffff,
+        _ => (1 << (S * 8)) - 1,
+    }
+}
+
+pub struct InterruptController {
+    stat: u32,
+    mask: u32,
+    /// Level of each line, to latch their rising edges
+    lines: u32,
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController {
+            stat: 0,
+            mask: 0,
+            lines: 0,
+        }
+    }
+
+    /// Sets the level of the line of `irq`, latching it on a rising edge
+    pub fn set_line(&mut self, irq: Irq, high: bool) {
+        let bit = 1 << irq as u32;
+        if high {
+            self.stat |= bit & !self.lines;
+            self.lines |= bit;
+        } else {
+            self.lines &= !bit;
+        }
+    }
+
+    /// A pulse on the line of `irq`, for the sources that don't hold it
+    pub fn raise(&mut self, irq: Irq) {
+        self.set_line(irq, true);
+        self.set_line(irq, false);
+    }
+
+    /// Whether the CPU is interrupted
+    pub fn pending(&self) -> bool {
+        self.stat & self.mask != 0
+    }
+
+    /// I_STAT and I_MASK
+    pub fn registers(&self) -> (u32, u32) {
+        (self.stat, self.mask)
+    }
+}
+
+impl BusDevice for InterruptController {
+    fn read<const S: u32>(&mut self, addr: u32) -> u32 {
+        let reg = match addr & 4 {
+            0 => self.stat,
+            _ => self.mask,
+        };
+        (reg >> ((addr & 3) * 8)) & access_mask::<S>()
+    }
+
+    fn write<const S: u32>(&mut self, addr: u32, value: u32) {
+        // Bytes outside the access are untouched
+        let shift = (addr & 3) * 8;
+        let written = access_mask::<S>() << shift;
+
+        match addr & 4 {
+            // Writing 0 acknowledges, writing 1 leaves the bit alone
+            0 => self.stat &= (value << shift) | !written,
+            _ => self.mask = ((self.mask & !written) | ((value << shift) & written)) & IRQ_LINES,
+        }
+    }
+}
+
+impl Savestate for InterruptController {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"IRQ ");
+        state.write_u32(self.stat);
+        state.write_u32(self.mask);
+        state.write_u32(self.lines);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"IRQ ", "IRQ")?;
+        self.stat = state.read_u32()?;
+        self.mask = state.read_u32()?;
+        self.lines = state.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_and_acknowledge_interleaved() {
+        let mut irq = InterruptController::new();
+
+        // Unmask VBlank and CD-ROM, the upper bits do not exist
+        irq.write::<4>(4, 0xffff_0005);
+        assert_eq!(irq.read::<4>(4), 0x0005);
+        assert_eq!(irq.read::<2>(4), 0x0005);
+
+        irq.raise(Irq::VBlank);
+        irq.raise(Irq::Cdrom);
+        irq.raise(Irq::Timer0);
+        assert_eq!(irq.read::<4>(0), 0x15);
+        assert!(irq.pending());
+
+        // Acknowledge VBlank only, CD-ROM keeps the line up
+        irq.write::<4>(0, !0x1);
+        assert_eq!(irq.read::<4>(0), 0x14);
+        assert!(irq.pending());
+
+        // The next VBlank raises it again
+        irq.raise(Irq::VBlank);
+        assert_eq!(irq.read::<4>(0), 0x15);
+
+        irq.write::<2>(0, !0x5 & 0xffff);
+        assert_eq!(irq.read::<4>(0), 0x10);
+        assert!(!irq.pending());
+
+        // Masked sources are still latched, and fire once unmasked
+        irq.write::<4>(4, 0x10);
+        assert!(irq.pending());
+        irq.write::<4>(4, 0);
+        assert!(!irq.pending());
+    }
+
+    #[test]
+    fn byte_accesses() {
+        let mut irq = InterruptController::new();
+        irq.raise(Irq::VBlank);
+        irq.raise(Irq::Spu);
+        irq.raise(Irq::Lightpen);
+
+        assert_eq!(irq.read::<1>(1), 0x06);
+
+        // Acknowledge SPU through the second byte, VBlank is untouched
+        irq.write::<1>(1, 0xfd);
+        assert_eq!(irq.read::<4>(0), 0x401);
+
+        irq.write::<1>(5, 0x04);
+        assert_eq!(irq.read::<4>(4), 0x400);
+        assert!(irq.pending());
+    }
+
+    #[test]
+    fn lines_latch_on_rising_edges() {
+        let mut irq = InterruptController::new();
+        irq.set_line(Irq::Cdrom, true);
+        assert_eq!(irq.read::<4>(0), 0x4);
+
+        // Held high, an acknowledge sticks until the next edge
+        irq.write::<4>(0, 0);
+        irq.set_line(Irq::Cdrom, true);
+        assert_eq!(irq.read::<4>(0), 0);
+
+        irq.set_line(Irq::Cdrom, false);
+        irq.set_line(Irq::Cdrom, true);
+        assert_eq!(irq.read::<4>(0), 0x4);
+
+        let mut state = StateWriter::new();
+        irq.save_state(&mut state);
+        let data = state.into_inner();
+        let mut restored = InterruptController::new();
+        restored.load_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(restored.registers(), irq.registers());
+        assert_eq!(restored.lines, irq.lines);
+    }
+}
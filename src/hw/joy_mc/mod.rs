@@ -1,29 +1,93 @@
-use crate::hw::bus::{BusDevice};
+use crate::hw::bus::{Bus, BusDevice, PsxEventType};
+use crate::hw::irq::Irq;
+use crustationcpu::state::{Savestate, StateError, StateReader, StateWriter};
+use std::cell::RefCell;
+use std::rc::Weak;
 
-#[derive(Copy, Clone, Debug)]
+/// The pad pulls /ACK low a while after each byte but the last one
+const ACK_CYCLES: u64 = 338;
+
+/// Bytes exchanged with the pad, after the selection with 0x01
+#[derive(Copy, Clone, Debug, PartialEq)]
 enum ControllerState {
     Initial,
-    IdLow,
-    IdHigh,
-    ButtonsLow,
-    ButtonsHigh,
-    Analog0,
-    Analog1,
-    Analog2,
-    Analog3,
+    /// The command is received while the ID is sent
+    Command,
+    /// 0x5a, the multitap address is received
+    Tap,
+    /// The bytes of the command, `payload` of them
+    Payload,
+    /// The pad has nothing more to say until it is deselected
+    Done,
+}
+
+/// Rumble motors of a DualShock, as last set by the game
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Rumble {
+    /// The small motor is either on or off
+    pub small: bool,
+    /// Speed of the large motor
+    pub large: u8,
+}
+
+impl Rumble {
+    pub fn is_off(&self) -> bool {
+        !self.small && self.large == 0
+    }
 }
 
+/// Rumble mapping of a byte that drives no motor
+const NO_MOTOR: u8 = 0xff;
+
 pub struct JoypadMemorycard {
     state: ControllerState,
     joy_ctrl: u16,
     joy_stat: u32,
+    joy_baud: u16,
 
     tx_data: u8,
     rx_data: u8,
+    /// A byte was received and not read yet
+    rx_full: bool,
+    /// A byte is being exchanged
+    transferring: bool,
+    /// The pad will acknowledge the byte being exchanged
+    ack_pending: bool,
 
     txen: bool,
     // rxen: bool,
     current_joy: u16,
+
+    /// Digital pad buttons, active low
+    buttons: u16,
+    /// Right X, right Y, left X and left Y, in the order they are sent.
+    /// 0x80 is the center, 0x00 left or up.
+    axes: [u8; 4],
+
+    /// Command being answered, the ID sent for it and the payload bytes
+    /// exchanged so far
+    command: u8,
+    id: u8,
+    payload: usize,
+    /// First payload byte received, some answers depend on it
+    argument: u8,
+
+    /// Sends the sticks along with the buttons, ID 0x73
+    analog: bool,
+    /// The game locked the mode, the Analog button can't change it
+    analog_locked: bool,
+    /// Configuration mode, entered with 0x43. ID 0xf3.
+    config: bool,
+    /// Motor driven by each payload byte of the read command: 0x00 for the
+    /// small one, 0x01 for the large one
+    rumble_map: [u8; 6],
+    rumble: Rumble,
+    /// Set by the read command being answered
+    next_rumble: Rumble,
+    /// Told about every change of the motors
+    rumble_handler: Option<Box<dyn FnMut(Rumble)>>,
+
+    bus: Weak<RefCell<Bus>>,
 }
 
 impl JoypadMemorycard {
@@ -33,34 +97,135 @@ impl JoypadMemorycard {
             state: ControllerState::Initial,
             joy_ctrl: 0,
             joy_stat: 0,
+            joy_baud: 0,
 
             tx_data: 0,
-            rx_data: 0,
+            rx_data: 0xff,
+            rx_full: false,
+            transferring: false,
+            ack_pending: false,
 
             txen: false,
             // rxen: false,
             current_joy: 0,
+
+            buttons: 0xffff,
+            axes: [0x80; 4],
+
+            command: 0,
+            id: 0x41,
+            payload: 0,
+            argument: 0,
+
+            analog: false,
+            analog_locked: false,
+            config: false,
+            rumble_map: [NO_MOTOR; 6],
+            rumble: Rumble::default(),
+            next_rumble: Rumble::default(),
+            rumble_handler: None,
+
+            bus: Weak::new(),
         }
     }
 
-    // pub fn install_cpu(&mut self, cpu: Weak<RefCell<Cpu>>) {
-    //     self.cpu = cpu;
-    // }
+    pub fn link(&mut self, bus: Weak<RefCell<Bus>>) {
+        self.bus = bus;
+    }
+
+    pub fn set_buttons(&mut self, buttons: u16) {
+        self.buttons = buttons;
+    }
+
+    pub fn set_axes(&mut self, axes: [u8; 4]) {
+        self.axes = axes;
+    }
+
+    pub fn buttons(&self) -> u16 {
+        self.buttons
+    }
+
+    pub fn axes(&self) -> [u8; 4] {
+        self.axes
+    }
+
+    /// Like the Analog button, ignored while the game locks the mode
+    pub fn set_analog(&mut self, analog: bool) {
+        if !self.analog_locked {
+            self.analog = analog;
+        }
+    }
+
+    pub fn rumble(&self) -> Rumble {
+        self.rumble
+    }
+
+    pub fn set_rumble_handler(&mut self, handler: Option<Box<dyn FnMut(Rumble)>>) {
+        self.rumble_handler = handler;
+    }
+
+    fn status(&self) -> u32 {
+        // TX ready, and done when no byte is being exchanged
+        let mut status = self.joy_stat | 1;
+        status |= (self.rx_full as u32) << 1;
+        status |= (!self.transferring as u32) << 2;
+        status
+    }
+
+    /// 8 bits at the baud rate
+    fn transfer_cycles(&self) -> u64 {
+        self.joy_baud.max(1) as u64 * 8
+    }
+
+    /// Called by the bus at the end of a byte, then when the pad
+    /// acknowledges it
+    pub fn transfer_event(&mut self) {
+        let bus = match self.bus.upgrade() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let bus = bus.borrow();
+
+        if self.transferring {
+            self.transferring = false;
+            self.rx_full = true;
+
+            if self.ack_pending {
+                let target = *bus.total_cycles.borrow() + ACK_CYCLES;
+                bus.add_event(PsxEventType::JoypadTransfer, target, 0);
+            }
+            return;
+        }
+
+        if self.ack_pending {
+            self.ack_pending = false;
+            self.joy_stat |= 1 << 7;
+
+            if self.joy_ctrl & (1 << 12) != 0 {
+                self.joy_stat |= 1 << 9;
+                bus.send_irq(Irq::Controller);
+            }
+        }
+    }
 }
 
 impl BusDevice for JoypadMemorycard {
     fn read<const S: u32>(&mut self, addr: u32) -> u32 {
-        println!("[JOY] Read from reg {:04x}", addr);
+        // println!("[JOY] Read from reg {:04x}", addr);
         match addr {
-            0x00 => self.rx_data as u32,
-            0x04 => 7,
+            0x00 => {
+                self.rx_full = false;
+                self.rx_data as u32
+            }
+            0x04 => self.status(),
             0x0a => self.joy_ctrl as u32,
+            0x0e => self.joy_baud as u32,
             _ => 0,
         }
     }
 
     fn write<const S: u32>(&mut self, addr: u32, value: u32) {
-        println!("[JOY] Write to reg {:04x} {:08x}", addr, value);
+        // println!("[JOY] Write to reg {:04x} {:08x}", addr, value);
 
         // Writes to JOY are truncated to 16 bits
         let value = value as u16;
@@ -79,7 +244,9 @@ impl BusDevice for JoypadMemorycard {
             0x0c => {
                 // TODO
             }
-            0x0e => { /* BAUD */ }
+            0x0e => {
+                self.joy_baud = value;
+            }
             _ => {
                 unimplemented!("{}", addr);
             }
@@ -91,7 +258,7 @@ impl JoypadMemorycard {
     fn write_tx_data(&mut self, tx_data: u8) {
         self.tx_data = tx_data;
         if self.txen {
-            self.process_tx_data();
+            self.start_transfer();
         }
     }
 
@@ -100,84 +267,371 @@ impl JoypadMemorycard {
         let value = value & !0xc080;
 
         if value & (1 << 6) != 0 {
-            // panic!("JoyMc reset requested");
+            // Reset
+            self.joy_stat = 0;
+            self.rx_full = false;
+            self.transferring = false;
+            self.ack_pending = false;
         }
 
         self.txen = value & 1 != 0;
 
-        if value & (1 << 1) != 0 {
-            self.current_joy = (value >> 13) & 1;
-
-            self.state = ControllerState::IdLow;
+        if value & (1 << 1) == 0 {
+            // Deselected: the next byte starts over
+            self.state = ControllerState::Initial;
         }
+        self.current_joy = (value >> 13) & 1;
 
         if value & (1 << 4) != 0 {
             self.joy_stat &= !0x208;
             // println!("JoyMc ack");
         }
 
-        // if value & (7 << 10) != 0 {
-        //     panic!("JoyMc requested ack interrupt");
-        // }
-
         self.joy_ctrl = value;
+    }
 
-        if self.txen {
-            self.process_tx_data();
-        }
-    }
-
-    fn process_tx_data(&mut self) {
-        match self.state {
-            ControllerState::Initial => {
-                match self.tx_data {
-                    0 => {}
-                    1 => {
-                        // Started Joypad initialization
-                        self.state = ControllerState::IdLow;
-                    }
-                    _ => {
-                        panic!(
-                            "Unhandled value {:02x} in state {:?}",
-                            self.tx_data, self.state
-                        )
-                    }
-                }
+    /// Exchanges `tx_data` with the selected device. The received byte is
+    /// available at the end of the transfer.
+    fn start_transfer(&mut self) {
+        let bus = match self.bus.upgrade() {
+            Some(bus) => bus,
+            None => return,
+        };
+
+        // /ACK goes back up when the next byte starts
+        self.joy_stat &= !(1 << 7);
+
+        let selected = self.joy_ctrl & (1 << 1) != 0;
+        // Nothing is plugged in the second slot
+        let (rx_data, ack) = if selected && self.current_joy == 0 {
+            self.process_tx_data()
+        } else {
+            (0xff, false)
+        };
+        self.rx_data = rx_data;
+        self.ack_pending = ack;
+        self.transferring = true;
+
+        let bus = bus.borrow();
+        let target = *bus.total_cycles.borrow() + self.transfer_cycles();
+        bus.add_event(PsxEventType::JoypadTransfer, target, 0);
+    }
+
+    /// The byte sent back by the pad for `tx_data`, and whether it
+    /// acknowledges it
+    fn process_tx_data(&mut self) -> (u8, bool) {
+        let (next, response) = match self.state {
+            // Memory cards are selected with 0x81 and not emulated yet
+            ControllerState::Initial if self.tx_data == 0x01 => (ControllerState::Command, 0xff),
+            ControllerState::Command if self.accepts(self.tx_data) => {
+                self.command = self.tx_data;
+                self.id = self.id();
+                self.payload = 0;
+                (ControllerState::Tap, self.id)
             }
-            ControllerState::IdLow => {
-                if self.tx_data == 0x42 {
-                    self.rx_data = 0x41;
-                    self.state = ControllerState::IdHigh;
+            ControllerState::Tap => (ControllerState::Payload, 0x5a),
+            ControllerState::Payload => {
+                let response = self.command_byte(self.payload, self.tx_data);
+                self.payload += 1;
+                match self.payload < self.payload_len() {
+                    true => (ControllerState::Payload, response),
+                    false => (ControllerState::Done, response),
                 }
             }
-            ControllerState::IdHigh => {
-                self.rx_data = 0x5a;
-                self.state = ControllerState::ButtonsLow;
-            }
-            ControllerState::ButtonsLow => {
-                self.rx_data = 0xff;
-                self.state = ControllerState::ButtonsHigh;
-            }
-            ControllerState::ButtonsHigh => {
-                self.rx_data = 0xff;
-                self.state = ControllerState::Analog0;
-            }
-            ControllerState::Analog0 => {
-                self.rx_data = 0x80;
-                self.state = ControllerState::Analog1;
+            _ => (ControllerState::Done, 0xff),
+        };
+
+        self.state = next;
+        // No acknowledge after the last byte
+        let ack = !matches!(next, ControllerState::Done);
+        (response, ack)
+    }
+
+    /// Outside of the configuration mode, only the read command and the
+    /// one entering the mode are known
+    fn accepts(&self, command: u8) -> bool {
+        match self.config {
+            true => (0x40..=0x4f).contains(&command),
+            false => command == 0x42 || command == 0x43,
+        }
+    }
+
+    /// Low byte of the ID, the high nibble is the type of controller and
+    /// the low one the payload length in halfwords
+    fn id(&self) -> u8 {
+        match (self.config, self.analog) {
+            (true, _) => 0xf3,
+            (false, true) => 0x73,
+            (false, false) => 0x41,
+        }
+    }
+
+    /// Of the command being answered
+    fn payload_len(&self) -> usize {
+        (self.id as usize & 0xf) * 2
+    }
+
+    /// Buttons, then the sticks
+    fn pad_byte(&self, index: usize) -> u8 {
+        let [right_x, right_y, left_x, left_y] = self.axes;
+        let buttons = self.buttons.to_le_bytes();
+        [buttons[0], buttons[1], right_x, right_y, left_x, left_y][index]
+    }
+
+    /// Answer to the payload byte `index` of the command, which received
+    /// `tx`
+    fn command_byte(&mut self, index: usize, tx: u8) -> u8 {
+        if index == 0 {
+            self.argument = tx;
+        }
+
+        match self.command {
+            // Buttons and sticks, also sent by 0x43 outside of the
+            // configuration mode
+            0x42 => {
+                self.drive_motor(index, tx);
+                self.pad_byte(index)
             }
-            ControllerState::Analog1 => {
-                self.rx_data = 0x80;
-                self.state = ControllerState::Analog2;
+            // Enter or leave the configuration mode. Outside of it, the
+            // buttons are sent like for a read.
+            0x43 => {
+                if index == 0 {
+                    self.config = tx == 0x01;
+                }
+                match self.id {
+                    0xf3 => 0x00,
+                    _ => self.pad_byte(index),
+                }
             }
-            ControllerState::Analog2 => {
-                self.rx_data = 0x80;
-                self.state = ControllerState::Analog3;
+            // Set the mode and whether the Analog button can change it
+            0x44 => {
+                match index {
+                    0 if tx <= 0x01 => self.analog = tx == 0x01,
+                    1 => self.analog_locked = tx == 0x03,
+                    _ => {}
+                }
+                0x00
             }
-            ControllerState::Analog3 => {
-                self.rx_data = 0x80;
-                // self.state = ControllerState::Analog;
+            // Controller type and current mode
+            0x45 => [0x01, 0x02, self.analog as u8, 0x02, 0x01, 0x00][index],
+            0x46 => match self.argument {
+                0x00 => [0x00, 0x00, 0x01, 0x02, 0x00, 0x0a][index],
+                _ => [0x00, 0x00, 0x01, 0x01, 0x01, 0x14][index],
+            },
+            0x47 => [0x00, 0x00, 0x02, 0x00, 0x01, 0x00][index],
+            0x4c => match self.argument {
+                0x00 => [0x00, 0x00, 0x00, 0x04, 0x00, 0x00][index],
+                _ => [0x00, 0x00, 0x00, 0x07, 0x00, 0x00][index],
+            },
+            // Rumble mapping, the old one is sent back
+            0x4d => std::mem::replace(&mut self.rumble_map[index], tx),
+            _ => 0x00,
+        }
+    }
+
+    /// Sets the motor mapped to the payload byte `index` of a read, and
+    /// tells the handler when the last byte changed the motors
+    fn drive_motor(&mut self, index: usize, tx: u8) {
+        if index == 0 {
+            self.next_rumble = Rumble::default();
+        }
+
+        match self.rumble_map[index] {
+            0x00 => self.next_rumble.small = tx & 1 != 0,
+            0x01 => self.next_rumble.large = tx,
+            _ => {}
+        }
+
+        if index + 1 == self.payload_len() && self.next_rumble != self.rumble {
+            self.rumble = self.next_rumble;
+            if let Some(handler) = &mut self.rumble_handler {
+                handler(self.rumble);
             }
         }
     }
 }
+
+impl ControllerState {
+    const ALL: [ControllerState; 5] = [
+        ControllerState::Initial,
+        ControllerState::Command,
+        ControllerState::Tap,
+        ControllerState::Payload,
+        ControllerState::Done,
+    ];
+}
+
+/// The pressed buttons and the sticks come from the host, they are not
+/// saved
+impl Savestate for JoypadMemorycard {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"JOY ");
+        state.write_u8(self.state as u8);
+        state.write_u16(self.joy_ctrl);
+        state.write_u32(self.joy_stat);
+        state.write_u16(self.joy_baud);
+        state.write_u8(self.tx_data);
+        state.write_u8(self.rx_data);
+        state.write_bool(self.rx_full);
+        state.write_bool(self.transferring);
+        state.write_bool(self.ack_pending);
+        state.write_bool(self.txen);
+        state.write_u16(self.current_joy);
+        state.write_u8(self.command);
+        state.write_u8(self.id);
+        state.write_u8(self.payload as u8);
+        state.write_u8(self.argument);
+        state.write_bool(self.analog);
+        state.write_bool(self.analog_locked);
+        state.write_bool(self.config);
+        state.write_bytes(&self.rumble_map);
+        for rumble in [self.rumble, self.next_rumble] {
+            state.write_bool(rumble.small);
+            state.write_u8(rumble.large);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"JOY ", "joypad")?;
+        self.state = *ControllerState::ALL
+            .get(state.read_u8()? as usize)
+            .ok_or(StateError::Invalid("joypad state"))?;
+        self.joy_ctrl = state.read_u16()?;
+        self.joy_stat = state.read_u32()?;
+        self.joy_baud = state.read_u16()?;
+        self.tx_data = state.read_u8()?;
+        self.rx_data = state.read_u8()?;
+        self.rx_full = state.read_bool()?;
+        self.transferring = state.read_bool()?;
+        self.ack_pending = state.read_bool()?;
+        self.txen = state.read_bool()?;
+        self.current_joy = state.read_u16()?;
+        self.command = state.read_u8()?;
+        self.id = state.read_u8()?;
+        self.payload = state.read_u8()? as usize;
+        self.argument = state.read_u8()?;
+        self.analog = state.read_bool()?;
+        self.analog_locked = state.read_bool()?;
+        self.config = state.read_bool()?;
+        state.read_bytes(&mut self.rumble_map)?;
+        for rumble in [&mut self.rumble, &mut self.next_rumble] {
+            rumble.small = state.read_bool()?;
+            rumble.large = state.read_u8()?;
+        }
+        if !matches!(self.id, 0x41 | 0x73 | 0xf3) || self.payload > 6 {
+            return Err(StateError::Invalid("joypad command"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crustationcpu::PsxBus;
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// Sends `byte` and returns the answer, and whether /ACK came
+    fn exchange(bus: &Bus, byte: u8) -> (u8, bool) {
+        bus.write::<1>(0x1f80_1040, byte as u32);
+        assert_eq!(bus.read::<4>(0x1f80_1044) & 6, 0, "still transferring");
+
+        bus.update_cycles(0x88 * 8 + 1);
+        assert_eq!(bus.read::<4>(0x1f80_1044) & 6, 6);
+        let data = bus.read::<1>(0x1f80_1040) as u8;
+
+        bus.update_cycles(ACK_CYCLES + 1);
+        (data, bus.read::<4>(0x1f80_1044) & (1 << 7) != 0)
+    }
+
+    #[test]
+    fn reads_the_digital_pad() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+        bus.set_speed(None);
+        // Cross and Start pressed
+        bus.set_buttons(!(1 << 14 | 1 << 3));
+
+        bus.write::<2>(0x1f80_104e, 0x88);
+        // Pad 1 selected, TX enabled, ACK interrupt
+        bus.write::<2>(0x1f80_104a, 0x1003);
+
+        assert_eq!(exchange(&bus, 0x01), (0xff, true));
+        assert_eq!(bus.read::<4>(0x1f80_1044) & (1 << 9), 1 << 9);
+        bus.write::<2>(0x1f80_104a, 0x1013);
+        assert_eq!(bus.read::<4>(0x1f80_1044) & (1 << 9), 0);
+
+        assert_eq!(exchange(&bus, 0x42), (0x41, true));
+        assert_eq!(exchange(&bus, 0x00), (0x5a, true));
+        assert_eq!(exchange(&bus, 0x00), (0xf7, true));
+        assert_eq!(exchange(&bus, 0x00), (0xbf, false));
+        assert_eq!(exchange(&bus, 0x00), (0xff, false));
+
+        // Nothing in the second slot
+        bus.write::<2>(0x1f80_104a, 0);
+        bus.write::<2>(0x1f80_104a, 0x3003);
+        assert_eq!(exchange(&bus, 0x01), (0xff, false));
+    }
+
+    /// Selects the pad, exchanges `bytes` and deselects it. Every byte but
+    /// the last one must be acknowledged.
+    fn packet(bus: &Bus, bytes: &[u8]) -> Vec<u8> {
+        bus.write::<2>(0x1f80_104a, 0x0003);
+        let answers = bytes
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| {
+                let (data, ack) = exchange(bus, byte);
+                assert_eq!(ack, i + 1 < bytes.len(), "acknowledge of byte {}", i);
+                data
+            })
+            .collect();
+        bus.write::<2>(0x1f80_104a, 0);
+        answers
+    }
+
+    #[test]
+    fn configures_the_dualshock() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+        bus.set_speed(None);
+        bus.write::<2>(0x1f80_104e, 0x88);
+        bus.set_buttons(!(1 << 12));
+        bus.set_axes([0x10, 0x20, 0x30, 0x40]);
+
+        let rumble = Rc::new(RefCell::new(vec![]));
+        let changes = rumble.clone();
+        bus.set_rumble_handler(Some(Box::new(move |rumble| changes.borrow_mut().push(rumble))));
+
+        // Configuration commands are ignored outside of the mode
+        assert_eq!(packet(&bus, &[0x01, 0x45]), [0xff, 0xff]);
+
+        // Entering it answers like a read
+        assert_eq!(packet(&bus, &[0x01, 0x43, 0x00, 0x01, 0x00]), [0xff, 0x41, 0x5a, 0xff, 0xef]);
+        let config = packet(&bus, &[0x01, 0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(config, [0xff, 0xf3, 0x5a, 0x01, 0x02, 0x00, 0x02, 0x01, 0x00]);
+
+        // Analog mode, locked, and the motors mapped to the first two bytes
+        packet(&bus, &[0x01, 0x44, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00]);
+        let map = packet(&bus, &[0x01, 0x4d, 0x00, 0x00, 0x01, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(&map[3..], [0xff; 6]);
+        let config = packet(&bus, &[0x01, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(config, [0xff, 0xf3, 0x5a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        // The Analog button is locked out
+        bus.set_analog(false);
+        let read = packet(&bus, &[0x01, 0x42, 0x00, 0x01, 0xc0, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(read, [0xff, 0x73, 0x5a, 0xff, 0xef, 0x10, 0x20, 0x30, 0x40]);
+        packet(&bus, &[0x01, 0x42, 0x00, 0x01, 0xc0, 0x00, 0x00, 0x00, 0x00]);
+        packet(&bus, &[0x01, 0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        let on = Rumble {
+            small: true,
+            large: 0xc0,
+        };
+        assert_eq!(*rumble.borrow(), [on, Rumble::default()]);
+    }
+}
@@ -0,0 +1,176 @@
+//! Macroblock decoding: run-length coded coefficients, dequantization,
+//! IDCT and YUV to RGB conversion.
+
+/// Position in the 8x8 block of the n-th coefficient of the stream
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27,
+    20, 13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58,
+    59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Padding between blocks, also the end of block code
+const END_OF_BLOCK: u16 = 0xfe00;
+
+pub type Block = [i16; 64];
+
+/// Tables uploaded by the game with commands 2 and 3
+pub struct Tables {
+    /// Quantization of the luminance and of the color blocks
+    pub luminance: [u8; 64],
+    pub color: [u8; 64],
+    /// IDCT matrix, 64 signed values of about 2^15 * cos
+    pub scale: [i16; 64],
+}
+
+fn sign_extend_10(value: u16) -> i32 {
+    ((value as i32) << 22) >> 22
+}
+
+/// Reads a block of coefficients from `data`, with `quant` for the
+/// dequantization. Returns None when the data ends first.
+///
+/// Each halfword holds a 10-bit coefficient. The first one of a block has
+/// the quantization scale in its top 6 bits, the others the count of zero
+/// coefficients before them.
+pub fn decode_coefficients(
+    data: &mut impl Iterator<Item = u16>,
+    quant: &[u8; 64],
+) -> Option<Block> {
+    let mut block = [0; 64];
+
+    let mut n = data.find(|&n| n != END_OF_BLOCK)?;
+    let scale = (n >> 10) as i32;
+    let mut k = 0;
+    // The DC coefficient is not scaled
+    let mut value = sign_extend_10(n & 0x3ff) * quant[0] as i32;
+
+    loop {
+        if scale == 0 {
+            value = sign_extend_10(n & 0x3ff) * 2;
+        }
+        let clamped = value.clamp(-0x400, 0x3ff) as i16;
+
+        // Scale 0 leaves the coefficients in the order they come in
+        match scale {
+            0 => block[k] = clamped,
+            _ => block[ZIGZAG[k]] = clamped,
+        }
+
+        n = data.next()?;
+        k += (n >> 10) as usize + 1;
+        if k > 63 {
+            break;
+        }
+        value = (sign_extend_10(n & 0x3ff) * quant[k] as i32 * scale + 4) / 8;
+    }
+
+    Some(block)
+}
+
+/// Turns the coefficients of `block` into signed 8-bit samples
+pub fn idct(block: &mut Block, scale: &[i16; 64]) {
+    let mut temp = [0i64; 64];
+
+    for x in 0..8 {
+        for y in 0..8 {
+            temp[x + y * 8] = (0..8)
+                .map(|u| block[u * 8 + x] as i64 * scale[u * 8 + y] as i64)
+                .sum();
+        }
+    }
+
+    for x in 0..8 {
+        for y in 0..8 {
+            let sum: i64 = (0..8).map(|u| temp[u + y * 8] * scale[u * 8 + x] as i64).sum();
+            // Rounded, then wrapped to 9 bits
+            let value = ((sum >> 32) + ((sum >> 31) & 1)) as i32;
+            let value = (value << 23) >> 23;
+            block[x + y * 8] = value.clamp(-128, 127) as i16;
+        }
+    }
+}
+
+/// Converts a macroblock to 16x16 RGB pixels, row by row. `y` holds the
+/// top left, top right, bottom left and bottom right luminance blocks.
+pub fn yuv_to_rgb(cr: &Block, cb: &Block, y: &[Block; 4], signed: bool) -> [[u8; 3]; 256] {
+    let mut pixels = [[0; 3]; 256];
+    let offset = if signed { 0 } else { 128 };
+
+    for (n, pixel) in pixels.iter_mut().enumerate() {
+        let (px, py) = (n % 16, n / 16);
+        let chroma = px / 2 + (py / 2) * 8;
+        let (cr, cb) = (cr[chroma] as i32, cb[chroma] as i32);
+
+        let r = (359 * cr + 0x80) >> 8;
+        let g = (((-88 * cb) & !0x1f) + ((-183 * cr) & !0x07) + 0x80) >> 8;
+        let b = (454 * cb + 0x80) >> 8;
+
+        let luma = y[(py / 8) * 2 + px / 8][(px % 8) + (py % 8) * 8] as i32;
+        *pixel = [r, g, b].map(|c| ((luma + c).clamp(-128, 127) + offset) as u8);
+    }
+
+    pixels
+}
+
+/// Converts a luminance-only block to 8x8 gray samples
+pub fn luminance(y: &Block, signed: bool) -> [u8; 64] {
+    let offset = if signed { 0 } else { 128 };
+    y.map(|luma| (luma as i32 + offset) as u8)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// The IDCT matrix uploaded by the games, 2^15 times twice the DCT-II
+    /// basis
+    pub fn scale_table() -> [i16; 64] {
+        let mut scale = [0; 64];
+        for (i, value) in scale.iter_mut().enumerate() {
+            let (u, x) = ((i / 8) as f64, (i % 8) as f64);
+            let c = if u == 0.0 { (0.125f64).sqrt() } else { 0.5 };
+            let cos = ((2.0 * x + 1.0) * u * std::f64::consts::PI / 16.0).cos();
+            *value = (c * cos * 65536.0).round() as i16;
+        }
+        scale
+    }
+
+    #[test]
+    fn coefficients_are_unzigzagged_and_scaled() {
+        let quant = [2; 64];
+
+        // DC 5 with scale 1, then 3 at the third position of the zigzag
+        let data = [1 << 10 | 5, 1 << 10 | 3, END_OF_BLOCK, 0x1234];
+        let mut data = data.into_iter();
+        let block = decode_coefficients(&mut data, &quant).unwrap();
+        assert_eq!(block[0], 10);
+        assert_eq!(block[8], (3 * 2 + 4) / 8);
+        assert_eq!(block.iter().filter(|&&c| c != 0).count(), 2);
+        assert_eq!(data.next(), Some(0x1234));
+
+        // Negative coefficients, and a block cut short
+        let mut data = [END_OF_BLOCK, 0x3ff, END_OF_BLOCK].into_iter();
+        assert_eq!(decode_coefficients(&mut data, &quant).unwrap()[0], -2);
+        assert!(decode_coefficients(&mut [1 << 10 | 5].into_iter(), &quant).is_none());
+    }
+
+    #[test]
+    fn flat_blocks_stay_flat() {
+        let scale = scale_table();
+        assert_eq!(scale[0], 0x5a82);
+
+        let mut block = [0; 64];
+        block[0] = 80;
+        idct(&mut block, &scale);
+        assert_eq!(block, [10; 64]);
+
+        let gray = yuv_to_rgb(&[0; 64], &[0; 64], &[block; 4], false);
+        assert_eq!(gray, [[138; 3]; 256]);
+
+        // Red comes from Cr
+        let mut cr = [0; 64];
+        cr[0] = 64;
+        let pixels = yuv_to_rgb(&cr, &[0; 64], &[[0; 64]; 4], true);
+        assert_eq!(pixels[0], [90, 210, 0]);
+    }
+}
@@ -0,0 +1,369 @@
+//! MDEC, the macroblock decoder used for FMVs. Games feed it compressed
+//! macroblocks through DMA channel 0 and read the pixels back through
+//! channel 1, then upload them to VRAM.
+
+mod decoder;
+mod worker;
+
+use std::collections::VecDeque;
+
+use crustationcpu::state::{Savestate, StateError, StateReader, StateWriter};
+
+use crate::hw::bus::BusDevice;
+use decoder::Tables;
+use worker::{Job, Worker};
+
+/// Status of a freshly reset MDEC: output FIFO empty, current block 4
+const STATUS_RESET: u32 = 0x8004_0000;
+
+pub struct Mdec {
+    /// The command being received, followed by its parameters
+    command: Vec<u32>,
+    /// Parameter words still expected by the command
+    remaining: u32,
+    /// Decoded pixels, read through MDEC0 or DMA channel 1
+    output: VecDeque<u32>,
+    /// Words still being decoded by the worker, they come after `output`
+    queued: usize,
+    worker: Worker,
+
+    tables: Tables,
+
+    /// Depth, signedness and bit 15 of the last command, status bits 23-26
+    format: u32,
+    /// MDEC1.b30 and b29: request DMA for the input and the output
+    data_in_request: bool,
+    data_out_request: bool,
+}
+
+impl Mdec {
+    pub fn new() -> Mdec {
+        Mdec {
+            command: vec![],
+            remaining: 0,
+            output: VecDeque::new(),
+            queued: 0,
+            worker: Worker::new(),
+
+            tables: Tables {
+                luminance: [0; 64],
+                color: [0; 64],
+                scale: [0; 64],
+            },
+
+            format: 0,
+            data_in_request: false,
+            data_out_request: false,
+        }
+    }
+
+    /// Words that can be read, including those still being decoded
+    pub fn output_len(&self) -> usize {
+        self.output.len() + self.queued
+    }
+
+    fn read_output(&mut self) -> u32 {
+        if self.output.is_empty() {
+            self.receive();
+        }
+        self.output.pop_front().unwrap_or(0)
+    }
+
+    /// Waits for the next macroblock from the worker, if any is pending
+    fn receive(&mut self) -> bool {
+        let len = self.output.len();
+        let received = self.worker.take(&mut self.output);
+        self.queued -= self.output.len() - len;
+        received
+    }
+
+    /// Waits for the worker to be done with all the pending macroblocks
+    fn flush(&mut self) {
+        while self.receive() {}
+    }
+
+    pub fn dma_write(&mut self, words: &[u32]) {
+        for &word in words {
+            self.write_command(word);
+        }
+    }
+
+    pub fn dma_read(&mut self, words: &mut [u32]) {
+        for word in words.iter_mut() {
+            *word = self.read_output();
+        }
+    }
+
+    fn status(&self) -> u32 {
+        let mut status = STATUS_RESET & !(1 << 31);
+
+        let empty = self.output_len() == 0;
+        status |= (empty as u32) << 31;
+        status |= ((self.remaining > 0) as u32) << 29;
+        status |= ((self.data_in_request && self.remaining > 0) as u32) << 28;
+        status |= ((self.data_out_request && !empty) as u32) << 27;
+        status |= self.format << 23;
+        // Parameter words left, minus 1
+        status | (self.remaining.wrapping_sub(1) & 0xffff)
+    }
+
+    fn write_control(&mut self, value: u32) {
+        if value & (1 << 31) != 0 {
+            // println!("[MDEC] Reset");
+            self.command.clear();
+            self.remaining = 0;
+            self.flush();
+            self.output.clear();
+            self.format = 0;
+        }
+
+        self.data_in_request = value & (1 << 30) != 0;
+        self.data_out_request = value & (1 << 29) != 0;
+    }
+
+    fn write_command(&mut self, word: u32) {
+        if self.remaining > 0 {
+            self.command.push(word);
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                self.execute();
+            }
+            return;
+        }
+
+        self.command.clear();
+        self.command.push(word);
+        self.format = (word >> 25) & 0xf;
+        self.remaining = match word >> 29 {
+            1 => word & 0xffff,
+            // Luminance, then optionally color quantization
+            2 => 16 + (word & 1) * 16,
+            3 => 32,
+            // No function
+            _ => 0,
+        };
+
+        if self.remaining == 0 {
+            self.execute();
+        }
+    }
+
+    fn execute(&mut self) {
+        let command = self.command[0];
+        let params = &self.command[1..];
+
+        match command >> 29 {
+            1 => self.decode(),
+            2 => {
+                let bytes: Vec<u8> = params.iter().flat_map(|word| word.to_le_bytes()).collect();
+                self.tables.luminance.copy_from_slice(&bytes[..64]);
+                if command & 1 != 0 {
+                    self.tables.color.copy_from_slice(&bytes[64..]);
+                }
+            }
+            3 => {
+                for (i, word) in params.iter().enumerate() {
+                    self.tables.scale[i * 2] = *word as i16;
+                    self.tables.scale[i * 2 + 1] = (*word >> 16) as i16;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Parses the macroblocks of a command 1 and queues them for the
+    /// worker. Data left after the last complete macroblock is dropped.
+    fn decode(&mut self) {
+        let command = self.command[0];
+        let depth = (command >> 27) & 3;
+        // Monochrome blocks are luminance only, color macroblocks are Cr,
+        // Cb and 4 Y blocks
+        let quant = match depth {
+            0 | 1 => vec![&self.tables.luminance],
+            _ => [[&self.tables.color; 2].as_slice(), &[&self.tables.luminance; 4]].concat(),
+        };
+
+        let mut data = self.command[1..]
+            .iter()
+            .flat_map(|word| [*word as u16, (*word >> 16) as u16]);
+
+        loop {
+            let blocks: Option<Vec<_>> = quant
+                .iter()
+                .map(|quant| decoder::decode_coefficients(&mut data, quant))
+                .collect();
+            let Some(blocks) = blocks else { break };
+
+            let job = Job {
+                blocks,
+                scale: self.tables.scale,
+                depth,
+                signed: command & (1 << 26) != 0,
+                bit15: command & (1 << 25) != 0,
+            };
+            self.queued += job.words();
+            self.worker.push(job);
+        }
+    }
+}
+
+impl BusDevice for Mdec {
+    fn read<const S: u32>(&mut self, addr: u32) -> u32 {
+        match addr {
+            0 => self.read_output(),
+            4 => self.status(),
+            _ => panic!("Invalid read to MDEC"),
+        }
+    }
+
+    fn write<const S: u32>(&mut self, addr: u32, value: u32) {
+        match addr {
+            0 => self.write_command(value),
+            4 => self.write_control(value),
+            _ => panic!("Invalid write to MDEC"),
+        }
+    }
+}
+
+impl Savestate for Mdec {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        self.flush();
+
+        state.tag(b"MDEC");
+        state.write_u32(self.command.len() as u32);
+        for &word in &self.command {
+            state.write_u32(word);
+        }
+        state.write_u32(self.remaining);
+        state.write_u32(self.output.len() as u32);
+        for &word in &self.output {
+            state.write_u32(word);
+        }
+
+        state.write_bytes(&self.tables.luminance);
+        state.write_bytes(&self.tables.color);
+        for &value in &self.tables.scale {
+            state.write_i16(value);
+        }
+
+        state.write_u32(self.format);
+        state.write_bool(self.data_in_request);
+        state.write_bool(self.data_out_request);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"MDEC", "MDEC")?;
+        self.command.clear();
+        for _ in 0..state.read_u32()? {
+            self.command.push(state.read_u32()?);
+        }
+        self.remaining = state.read_u32()?;
+        self.flush();
+        self.output.clear();
+        for _ in 0..state.read_u32()? {
+            self.output.push_back(state.read_u32()?);
+        }
+
+        state.read_bytes(&mut self.tables.luminance)?;
+        state.read_bytes(&mut self.tables.color)?;
+        for value in &mut self.tables.scale {
+            *value = state.read_i16()?;
+        }
+
+        self.format = state.read_u32()?;
+        self.data_in_request = state.read_bool()?;
+        self.data_out_request = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decoder::tests::scale_table;
+
+    /// An MDEC with flat quantization and the usual IDCT matrix
+    fn mdec() -> Mdec {
+        let mut mdec = Mdec::new();
+        mdec.write::<4>(4, 0xe000_0000);
+
+        mdec.write_command(0x4000_0001);
+        mdec.dma_write(&[0x0101_0101; 32]);
+
+        let scale = scale_table();
+        mdec.write_command(0x6000_0000);
+        for pair in scale.chunks_exact(2) {
+            mdec.write_command(pair[0] as u16 as u32 | (pair[1] as u16 as u32) << 16);
+        }
+        mdec
+    }
+
+    #[test]
+    fn decodes_monochrome_blocks() {
+        let mut mdec = mdec();
+        assert_eq!(mdec.status(), 0x8004_ffff);
+
+        // 8-bit: 2 blocks of DC 80, the second one after some padding
+        mdec.write_command(0x2800_0003);
+        assert_eq!(mdec.status() & 0xe000_ffff, 0xa000_0002);
+        mdec.dma_write(&[0xfe00_0450, 0xfe00_fe00, 0xfe00_0450]);
+
+        // Output requested, 8-bit
+        assert_eq!(mdec.status() & 0xfe00_0000, 0x0a00_0000);
+        assert_eq!(mdec.output_len(), 32);
+        let mut words = [0; 32];
+        mdec.dma_read(&mut words);
+        assert_eq!(words, [0x8a8a_8a8a; 32]);
+        assert_eq!(mdec.status() >> 31, 1);
+
+        // 4-bit, signed
+        mdec.write_command(0x2400_0001);
+        mdec.write_command(0xfe00_0450);
+        assert_eq!(mdec.read::<4>(0), 0);
+        assert_eq!(mdec.output_len(), 7);
+    }
+
+    #[test]
+    fn output_stays_in_order_past_the_worker_queue() {
+        let mut mdec = mdec();
+
+        // More blocks than the worker queues, each a different gray
+        mdec.write_command(0x2800_0000 | 100);
+        for i in 0..100 {
+            mdec.write_command(0xfe00_0400 | ((i % 50) * 8));
+        }
+        assert_eq!(mdec.output_len(), 1600);
+
+        for i in 0..100 {
+            let mut words = [0; 16];
+            mdec.dma_read(&mut words);
+            assert_eq!(words, [0x0101_0101 * (128 + i % 50); 16], "block {}", i);
+        }
+        assert_eq!(mdec.output_len(), 0);
+    }
+
+    #[test]
+    fn decodes_color_macroblocks() {
+        let mut mdec = mdec();
+
+        // 15-bit with bit 15 set: Cr and Cb 0, Y 10 everywhere
+        mdec.write_command(0x3a00_0006);
+        mdec.dma_write(&[0xfe00_0400, 0xfe00_0400]);
+        mdec.dma_write(&[0xfe00_0450; 4]);
+        assert_eq!(mdec.output_len(), 128);
+        assert_eq!(mdec.read::<4>(0), 0xc631_c631);
+
+        // 24-bit, a macroblock cut short is dropped
+        mdec.write::<4>(4, 0x8000_0000);
+        mdec.write_command(0x3000_0006);
+        mdec.dma_write(&[0xfe00_0400, 0xfe00_0400, 0xfe00_0450, 0xfe00_0450, 0xfe00_0450]);
+        mdec.write_command(0x0000_0450);
+        assert_eq!(mdec.output_len(), 0);
+
+        mdec.write_command(0x3000_0006);
+        mdec.dma_write(&[0xfe00_0400, 0xfe00_0400]);
+        mdec.dma_write(&[0xfe00_0450; 4]);
+        assert_eq!(mdec.output_len(), 192);
+        assert_eq!(mdec.read::<4>(0), 0x8a8a_8a8a);
+    }
+}
@@ -0,0 +1,148 @@
+//! Background thread for the expensive part of decoding: IDCT, color
+//! conversion and packing of the pixels.
+//!
+//! The run-length coded data is parsed on the emulation thread, so the
+//! size of the output is known as soon as a command completes and DMA
+//! timings don't depend on how fast the worker is. Reading the output
+//! waits for the worker when it's behind.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crate::hw::mdec::decoder::{self, Block};
+
+/// Macroblocks queued before the emulation thread waits for the worker
+const QUEUE_LEN: usize = 64;
+
+/// One macroblock, or one block for monochrome output
+pub struct Job {
+    /// Cr, Cb and the 4 Y blocks, or a single Y block
+    pub blocks: Vec<Block>,
+    pub scale: [i16; 64],
+    /// Bits 25-28 of the command
+    pub depth: u32,
+    pub signed: bool,
+    pub bit15: bool,
+}
+
+impl Job {
+    /// Words of output
+    pub fn words(&self) -> usize {
+        match self.depth {
+            0 => 8,
+            1 => 16,
+            2 => 192,
+            _ => 128,
+        }
+    }
+
+    fn run(mut self) -> Vec<u32> {
+        for block in &mut self.blocks {
+            decoder::idct(block, &self.scale);
+        }
+
+        match self.depth {
+            // First pixel in the low nibble
+            0 => decoder::luminance(&self.blocks[0], self.signed)
+                .chunks_exact(8)
+                .map(|pixels| {
+                    pixels
+                        .iter()
+                        .enumerate()
+                        .fold(0, |word, (i, p)| word | ((*p as u32) >> 4) << (i * 4))
+                })
+                .collect(),
+            1 => decoder::luminance(&self.blocks[0], self.signed)
+                .chunks_exact(4)
+                .map(|p| u32::from_le_bytes(p.try_into().unwrap()))
+                .collect(),
+            depth => {
+                let y = [self.blocks[2], self.blocks[3], self.blocks[4], self.blocks[5]];
+                let pixels = decoder::yuv_to_rgb(&self.blocks[0], &self.blocks[1], &y, self.signed);
+
+                match depth {
+                    2 => {
+                        let bytes: Vec<u8> = pixels.iter().flatten().copied().collect();
+                        bytes
+                            .chunks_exact(4)
+                            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                            .collect()
+                    }
+                    _ => {
+                        let bit15 = (self.bit15 as u32) << 15;
+                        let pixel = |[r, g, b]: [u8; 3]| {
+                            let (r, g, b) = (r as u32 >> 3, g as u32 >> 3, b as u32 >> 3);
+                            r | g << 5 | b << 10 | bit15
+                        };
+                        pixels
+                            .chunks_exact(2)
+                            .map(|pair| pixel(pair[0]) | pixel(pair[1]) << 16)
+                            .collect()
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct Worker {
+    jobs: Option<SyncSender<Job>>,
+    results: Receiver<Vec<u32>>,
+    /// Jobs sent and not received back yet
+    pending: usize,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    pub fn new() -> Worker {
+        let (job_tx, job_rx) = mpsc::sync_channel::<Job>(QUEUE_LEN);
+        let (result_tx, result_rx) = mpsc::channel();
+
+        let thread = thread::Builder::new()
+            .name(String::from("mdec"))
+            .spawn(move || {
+                for job in job_rx {
+                    if result_tx.send(job.run()).is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("Could not start the MDEC thread");
+
+        Worker {
+            jobs: Some(job_tx),
+            results: result_rx,
+            pending: 0,
+            thread: Some(thread),
+        }
+    }
+
+    /// Queues `job`, waiting if the worker is too far behind
+    pub fn push(&mut self, job: Job) {
+        self.jobs.as_ref().unwrap().send(job).unwrap();
+        self.pending += 1;
+    }
+
+    /// Appends the words of the oldest pending job to `output`, waiting
+    /// for it. Returns false when there was none.
+    pub fn take(&mut self, output: &mut VecDeque<u32>) -> bool {
+        if self.pending == 0 {
+            return false;
+        }
+
+        output.extend(self.results.recv().unwrap());
+        self.pending -= 1;
+        true
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // Closing the queue stops the thread
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
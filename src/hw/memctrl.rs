@@ -0,0 +1,167 @@
+//! Memory control: the delay/size registers at 1F801008h-1F80101Ch, with
+//! COM_DELAY at 1F801020h, set how long the CPU waits on each device of
+//! the bus. Timings follow the formula in nocash's psx-spx.
+
+/// The devices with a delay register, in the order of the registers. EXP2
+/// comes last, but the bus uses measured timings for it.
+#[derive(Copy, Clone)]
+pub enum Region {
+    Exp1 = 0,
+    Exp3,
+    Bios,
+    Spu,
+    Cdrom,
+}
+
+/// The values written by the BIOS before anything else, from 1F801008h
+const BIOS_DELAYS: [u32; 6] = [
+    0x0013_243f,
+    0x0000_3022,
+    0x0013_243f,
+    0x2009_31e1,
+    0x0002_0843,
+    0x0007_0777,
+];
+const BIOS_COM_DELAY: u32 = 0x0003_1125;
+
+pub struct MemoryControl {
+    delays: [u32; 6],
+    com_delay: u32,
+    /// Cycles of a byte, halfword and word access for each region
+    access_times: [[u64; 3]; 6],
+}
+
+impl MemoryControl {
+    /// Starts with the BIOS settings, so that side-loaded executables and
+    /// a BIOS that has not got there yet see the usual timings
+    pub fn new() -> MemoryControl {
+        let mut memctrl = MemoryControl {
+            delays: BIOS_DELAYS,
+            com_delay: BIOS_COM_DELAY,
+            access_times: [[0; 3]; 6],
+        };
+        memctrl.update();
+        memctrl
+    }
+
+    /// The values for `Bus::write_io` to start with
+    pub fn registers(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let delays = self.delays.iter().enumerate();
+        delays
+            .map(|(n, &value)| (0x1008 + 4 * n as u32, value))
+            .chain(std::iter::once((0x1020, self.com_delay)))
+    }
+
+    /// A write to the I/O port at `addr` (1008h to 1020h), with the whole
+    /// register after it
+    pub fn write(&mut self, addr: u32, value: u32) {
+        match addr {
+            0x1008..=0x101c => self.delays[(addr as usize - 0x1008) / 4] = value,
+            0x1020 => self.com_delay = value,
+            _ => return,
+        }
+        self.update();
+    }
+
+    /// Cycles an `S`-byte read from `region` takes
+    #[inline(always)]
+    pub fn access_time<const S: u32>(&self, region: Region) -> u64 {
+        let times = &self.access_times[region as usize];
+        match S {
+            1 => times[0],
+            2 => times[1],
+            _ => times[2],
+        }
+    }
+
+    /// Bytes mapped from the start of `region`, from bits 16-20 of its
+    /// delay/size register
+    pub fn size(&self, region: Region) -> u32 {
+        1 << ((self.delays[region as usize] >> 16) & 0x1f)
+    }
+
+    fn update(&mut self) {
+        for (times, &delay) in self.access_times.iter_mut().zip(&self.delays) {
+            *times = access_times(delay, self.com_delay);
+        }
+    }
+}
+
+/// Byte, halfword and word access times with the delay register `delay`.
+/// Bit 12 selects a 16-bit bus, words on an 8-bit one take 4 accesses.
+fn access_times(delay: u32, com_delay: u32) -> [u64; 3] {
+    let access_time = (delay >> 4) & 0xf;
+    let com0 = com_delay & 0xf;
+    let com2 = (com_delay >> 8) & 0xf;
+    let com3 = (com_delay >> 12) & 0xf;
+
+    let mut first = 0;
+    let mut seq = 0;
+    let mut min = 0;
+    if delay & (1 << 8) != 0 {
+        first += com0.saturating_sub(1);
+        seq += com0.saturating_sub(1);
+    }
+    if delay & (1 << 10) != 0 {
+        first += com2;
+        seq += com2;
+    }
+    if delay & (1 << 11) != 0 {
+        min = com3;
+    }
+    if first < 6 {
+        first += 1;
+    }
+
+    let first = (first + access_time + 2).max(min + 6) as u64;
+    let seq = (seq + access_time + 2).max(min + 2) as u64;
+
+    let bus_16bit = delay & (1 << 12) != 0;
+    let half = if bus_16bit { first } else { first + seq };
+    let word = if bus_16bit { first + seq } else { first + 3 * seq };
+    [first - 1, half - 1, word - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bios_settings_give_the_measured_timings() {
+        let memctrl = MemoryControl::new();
+
+        assert_eq!(memctrl.access_time::<1>(Region::Bios), 6);
+        assert_eq!(memctrl.access_time::<2>(Region::Bios), 12);
+        assert_eq!(memctrl.access_time::<4>(Region::Bios), 24);
+        assert_eq!(memctrl.access_time::<2>(Region::Exp3), 5);
+        assert_eq!(memctrl.access_time::<4>(Region::Exp3), 9);
+        assert_eq!(memctrl.access_time::<4>(Region::Cdrom), 24);
+        assert_eq!(memctrl.size(Region::Exp1), 512 * 1024);
+    }
+
+    #[test]
+    fn writes_change_the_timings() {
+        let mut memctrl = MemoryControl::new();
+
+        // A faster ROM on a 16-bit bus
+        memctrl.write(0x1010, 0x0013_1010);
+        assert_eq!(memctrl.access_time::<1>(Region::Bios), 5);
+        assert_eq!(memctrl.access_time::<4>(Region::Bios), 8);
+
+        // COM3 sets a minimum
+        memctrl.write(0x1020, 0x0000_f000);
+        memctrl.write(0x1010, 0x0000_0800);
+        assert_eq!(memctrl.access_time::<1>(Region::Bios), 20);
+        assert_eq!(memctrl.access_time::<4>(Region::Bios), 20 + 3 * 17);
+    }
+
+    #[test]
+    fn registers_round_trip() {
+        let memctrl = MemoryControl::new();
+        let registers: Vec<(u32, u32)> = memctrl.registers().collect();
+
+        assert_eq!(registers.len(), 7);
+        assert_eq!(registers[2], (0x1010, 0x0013_243f));
+        assert_eq!(registers[6], (0x1020, 0x0003_1125));
+    }
+}
@@ -0,0 +1,107 @@
+//! The physical address space, as decoded by the bus. `Bus::read` and
+//! `Bus::write` look devices up in `MEMORY_MAP`, so the debugger's
+//! `memory-map` command lists exactly what the CPU reaches.
+//!
+//! Every region is seen in KUSEG (00000000h), KSEG0 (80000000h) and KSEG1
+//! (A0000000h). The scratchpad (1F800000h) and the cache control register
+//! (FFFE0130h) are inside the CPU and never reach the bus.
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Device {
+    Ram,
+    Exp1,
+    MemControl,
+    JoyMc,
+    Sio,
+    RamSize,
+    Irq,
+    Dma,
+    Timers,
+    Cdrom,
+    Gpu,
+    Mdec,
+    Spu,
+    Exp2,
+    Exp3,
+    Bios,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Mapping {
+    pub start: u32,
+    /// Inclusive
+    pub end: u32,
+    pub device: Device,
+    pub name: &'static str,
+    /// How the device fills its window
+    pub mirrors: &'static str,
+}
+
+const fn map(start: u32, end: u32, device: Device, name: &'static str) -> Mapping {
+    Mapping {
+        start,
+        end,
+        device,
+        name,
+        mirrors: "",
+    }
+}
+
+/// Sorted by address, without overlaps
+pub const MEMORY_MAP: [Mapping; 16] = [
+    Mapping {
+        mirrors: "2 MiB, layout set by RAM_SIZE",
+        ..map(0x0000_0000, 0x007f_ffff, Device::Ram, "Main RAM")
+    },
+    Mapping {
+        mirrors: "Size set at 1F801008h",
+        ..map(0x1f00_0000, 0x1f7f_ffff, Device::Exp1, "Expansion 1")
+    },
+    map(0x1f80_1000, 0x1f80_1020, Device::MemControl, "Memory control"),
+    map(0x1f80_1040, 0x1f80_104f, Device::JoyMc, "Pad/memory card"),
+    map(0x1f80_1050, 0x1f80_105f, Device::Sio, "Serial port"),
+    map(0x1f80_1060, 0x1f80_1060, Device::RamSize, "RAM size"),
+    map(0x1f80_1070, 0x1f80_1077, Device::Irq, "Interrupt control"),
+    map(0x1f80_1080, 0x1f80_10f4, Device::Dma, "DMA"),
+    map(0x1f80_1100, 0x1f80_112f, Device::Timers, "Timers"),
+    map(0x1f80_1800, 0x1f80_1803, Device::Cdrom, "CDROM"),
+    map(0x1f80_1810, 0x1f80_1817, Device::Gpu, "GPU"),
+    map(0x1f80_1820, 0x1f80_1827, Device::Mdec, "MDEC"),
+    map(0x1f80_1c00, 0x1f80_1fff, Device::Spu, "SPU"),
+    map(0x1f80_2000, 0x1f80_207f, Device::Exp2, "Expansion 2"),
+    map(0x1fa0_0000, 0x1fa0_0000, Device::Exp3, "Expansion 3"),
+    map(0x1fc0_0000, 0x1fc7_ffff, Device::Bios, "BIOS ROM"),
+];
+
+/// The mapping holding the physical address `addr`, None in the holes
+#[inline(always)]
+pub fn decode(addr: u32) -> Option<&'static Mapping> {
+    let n = MEMORY_MAP.partition_point(|mapping| mapping.end < addr);
+    MEMORY_MAP.get(n).filter(|mapping| mapping.start <= addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mappings_are_sorted_and_apart() {
+        for pair in MEMORY_MAP.windows(2) {
+            assert!(pair[0].start <= pair[0].end);
+            assert!(pair[0].end < pair[1].start, "{} overlaps {}", pair[0].name, pair[1].name);
+        }
+    }
+
+    #[test]
+    fn addresses_find_their_device() {
+        assert_eq!(decode(0x0000_0000).unwrap().device, Device::Ram);
+        assert_eq!(decode(0x001f_fffc).unwrap().device, Device::Ram);
+        assert_eq!(decode(0x1f80_1814).unwrap().device, Device::Gpu);
+        assert_eq!(decode(0x1f80_1060).unwrap().device, Device::RamSize);
+        assert_eq!(decode(0x1fc7_ffff).unwrap().device, Device::Bios);
+        assert_eq!(decode(0x0080_0000), None);
+        assert_eq!(decode(0x1f80_1064), None);
+        assert_eq!(decode(0x1fc8_0000), None);
+        assert_eq!(decode(0xffff_ffff), None);
+    }
+}
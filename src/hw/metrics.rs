@@ -0,0 +1,384 @@
+//! Emulation statistics, to watch long runs (e.g. soak tests of the
+//! compatibility corpus) from outside the emulator.
+//!
+//! They are sampled once per second of host time and published as JSON or
+//! in the Prometheus text format, either written to a file or served over
+//! HTTP on localhost: `/metrics` for Prometheus, `/metrics.json` for JSON.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use crustationcpu::stats::InstructionCounts;
+
+use crate::hw::dma::ChannelLink;
+
+/// How often the metrics are published
+const SAMPLE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Cycles per second of the real hardware
+const CPU_FREQ: f64 = 33_868_800.0;
+
+/// Where the metrics are published, shared with the readers
+pub type MetricsHandle = Arc<ArcSwap<Metrics>>;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    /// Host seconds since emulation started
+    pub uptime: f64,
+    pub frames: u64,
+    pub fps: f64,
+    pub instructions: u64,
+    pub instructions_per_second: f64,
+    /// Emulated cycles per host second, in percent of the real hardware
+    pub speed: f64,
+    /// Average over the last second, per DMA channel
+    pub dma_words_per_second: [u32; 7],
+    /// Samples waiting to be played
+    pub audio_queued: u64,
+    /// Samples dropped because the output buffer was full
+    pub audio_dropped: u64,
+    /// Samples the audio device asked for while the buffer was empty
+    pub audio_underruns: u64,
+    /// Averages over the last second
+    pub per_frame: FrameMix,
+}
+
+/// Instructions executed per emulated frame, by class
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FrameMix {
+    pub instructions: f64,
+    pub loads: f64,
+    pub stores: f64,
+    pub gte: f64,
+    pub mult_div: f64,
+}
+
+/// Totals read from the machine when a sample is due
+pub struct Counters {
+    pub instructions: u64,
+    pub instruction_counts: InstructionCounts,
+    pub cycles: u64,
+    pub dma_words_per_second: [u32; 7],
+    pub audio_queued: u64,
+    pub audio_dropped: u64,
+    pub audio_underruns: u64,
+}
+
+/// Turns the totals into rates, once per `SAMPLE_PERIOD`
+pub struct MetricsSampler {
+    start: Instant,
+    last: Instant,
+    frames: u64,
+    /// Frames, instructions and cycles at the last sample
+    last_totals: (u64, u64, u64),
+    last_counts: InstructionCounts,
+    handle: MetricsHandle,
+    /// Rewritten at every sample, in Prometheus format if it ends in .prom
+    file: Option<PathBuf>,
+}
+
+impl MetricsSampler {
+    pub fn new(now: Instant) -> MetricsSampler {
+        MetricsSampler {
+            start: now,
+            last: now,
+            frames: 0,
+            last_totals: (0, 0, 0),
+            last_counts: InstructionCounts::default(),
+            handle: Arc::new(ArcSwap::from_pointee(Metrics::default())),
+            file: None,
+        }
+    }
+
+    pub fn handle(&self) -> MetricsHandle {
+        self.handle.clone()
+    }
+
+    pub fn set_file(&mut self, file: Option<PathBuf>) {
+        self.file = file;
+    }
+
+    /// Called at VBlank. Returns true when a sample is due.
+    pub fn end_frame(&mut self, now: Instant) -> bool {
+        self.frames += 1;
+        now.duration_since(self.last) >= SAMPLE_PERIOD
+    }
+
+    /// Publishes the metrics for the time since the last sample
+    pub fn sample(&mut self, now: Instant, counters: Counters) {
+        let elapsed = now.duration_since(self.last).as_secs_f64().max(f64::EPSILON);
+        let (frames, instructions, cycles) = self.last_totals;
+        let rate = |total: u64, last: u64| total.saturating_sub(last) as f64 / elapsed;
+        let frame_count = self.frames.saturating_sub(frames).max(1) as f64;
+        let counts = counters.instruction_counts.since(&self.last_counts);
+        let per_frame = FrameMix {
+            instructions: counters.instructions.saturating_sub(instructions) as f64 / frame_count,
+            loads: counts.loads as f64 / frame_count,
+            stores: counts.stores as f64 / frame_count,
+            gte: counts.gte as f64 / frame_count,
+            mult_div: counts.mult_div as f64 / frame_count,
+        };
+
+        let metrics = Metrics {
+            uptime: now.duration_since(self.start).as_secs_f64(),
+            frames: self.frames,
+            fps: rate(self.frames, frames),
+            instructions: counters.instructions,
+            instructions_per_second: rate(counters.instructions, instructions),
+            speed: rate(counters.cycles, cycles) * 100. / CPU_FREQ,
+            dma_words_per_second: counters.dma_words_per_second,
+            audio_queued: counters.audio_queued,
+            audio_dropped: counters.audio_dropped,
+            audio_underruns: counters.audio_underruns,
+            per_frame,
+        };
+
+        self.last = now;
+        self.last_totals = (self.frames, counters.instructions, counters.cycles);
+        self.last_counts = counters.instruction_counts;
+
+        if let Some(file) = &self.file {
+            if let Err(err) = write_file(file, &metrics) {
+                println!("[METRICS] Could not write {}: {}", file.display(), err);
+            }
+        }
+        self.handle.store(Arc::new(metrics));
+    }
+}
+
+/// Replaced atomically, readers never see a partial file
+fn write_file(path: &Path, metrics: &Metrics) -> io::Result<()> {
+    let text = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("prom") => metrics.to_prometheus(),
+        _ => metrics.to_json(),
+    };
+
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, text)?;
+    fs::rename(&temp, path)
+}
+
+impl Metrics {
+    pub fn to_json(&self) -> String {
+        let dma = self
+            .dma_words_per_second
+            .iter()
+            .enumerate()
+            .map(|(n, words)| format!("\"{:?}\": {}", ChannelLink::get(n as u32), words))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        format!(
+            "{{\"uptime\": {:.3}, \"frames\": {}, \"fps\": {:.2}, \"instructions\": {}, \
+             \"instructions_per_second\": {:.0}, \"speed\": {:.1}, \
+             \"dma_words_per_second\": {{{}}}, \"audio_queued\": {}, \"audio_dropped\": {}, \
+             \"audio_underruns\": {}, \"per_frame\": {{\"instructions\": {:.0}, \"loads\": {:.0}, \
+             \"stores\": {:.0}, \"gte\": {:.0}, \"mult_div\": {:.0}}}}}\n",
+            self.uptime,
+            self.frames,
+            self.fps,
+            self.instructions,
+            self.instructions_per_second,
+            self.speed,
+            dma,
+            self.audio_queued,
+            self.audio_dropped,
+            self.audio_underruns,
+            self.per_frame.instructions,
+            self.per_frame.loads,
+            self.per_frame.stores,
+            self.per_frame.gte,
+            self.per_frame.mult_div
+        )
+    }
+
+    /// The Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let scalars = [
+            (
+                "uptime_seconds",
+                "gauge",
+                "Host time since emulation started",
+                format!("{:.3}", self.uptime),
+            ),
+            ("frames_total", "counter", "Emulated frames", self.frames.to_string()),
+            ("fps", "gauge", "Emulated frames per host second", format!("{:.2}", self.fps)),
+            (
+                "instructions_total",
+                "counter",
+                "Executed instructions",
+                self.instructions.to_string(),
+            ),
+            (
+                "instructions_per_second",
+                "gauge",
+                "Executed instructions per host second",
+                format!("{:.0}", self.instructions_per_second),
+            ),
+            (
+                "speed_percent",
+                "gauge",
+                "Emulation speed, 100 is the real hardware",
+                format!("{:.1}", self.speed),
+            ),
+            (
+                "audio_queued_samples",
+                "gauge",
+                "Samples waiting to be played",
+                self.audio_queued.to_string(),
+            ),
+            (
+                "audio_dropped_samples_total",
+                "counter",
+                "Samples dropped because the output buffer was full",
+                self.audio_dropped.to_string(),
+            ),
+            (
+                "audio_underruns_total",
+                "counter",
+                "Samples played while the output buffer was empty",
+                self.audio_underruns.to_string(),
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in scalars {
+            let _ = write!(
+                out,
+                "# HELP psx_{0} {1}\n# TYPE psx_{0} {2}\npsx_{0} {3}\n",
+                name, help, kind, value
+            );
+        }
+
+        out += "# HELP psx_dma_words_per_second Words moved by each DMA channel\n";
+        out += "# TYPE psx_dma_words_per_second gauge\n";
+        for (n, words) in self.dma_words_per_second.iter().enumerate() {
+            let channel = ChannelLink::get(n as u32);
+            let _ = writeln!(
+                out,
+                "psx_dma_words_per_second{{channel=\"{:?}\"}} {}",
+                channel, words
+            );
+        }
+
+        out += "# HELP psx_instructions_per_frame Executed instructions per frame, by class\n";
+        out += "# TYPE psx_instructions_per_frame gauge\n";
+        let mix = &self.per_frame;
+        for (class, count) in [
+            ("all", mix.instructions),
+            ("load", mix.loads),
+            ("store", mix.stores),
+            ("gte", mix.gte),
+            ("mult_div", mix.mult_div),
+        ] {
+            let _ = writeln!(out, "psx_instructions_per_frame{{class=\"{}\"}} {:.0}", class, count);
+        }
+        out
+    }
+}
+
+/// Serves the metrics on localhost, from a thread of its own
+pub fn serve(handle: MetricsHandle, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(err) = respond(stream, &handle) {
+                println!("[METRICS] Request failed: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, handle: &MetricsHandle) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    // GET /path HTTP/1.1, then headers until an empty line
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let metrics = handle.load();
+    let (status, content_type, body) = match request.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", "text/plain; version=0.0.4", metrics.to_prometheus()),
+        Some("/" | "/metrics.json") => ("200 OK", "application/json", metrics.to_json()),
+        _ => ("404 Not Found", "text/plain", String::from("Not found\n")),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_rates_once_per_second() {
+        let start = Instant::now();
+        let mut sampler = MetricsSampler::new(start);
+        let counters = |instructions, cycles| Counters {
+            instructions,
+            instruction_counts: InstructionCounts {
+                loads: instructions / 5,
+                stores: instructions / 10,
+                gte: 0,
+                mult_div: 600,
+            },
+            cycles,
+            dma_words_per_second: [0, 0, 1024, 0, 0, 0, 0],
+            audio_queued: 100,
+            audio_dropped: 0,
+            audio_underruns: 3,
+        };
+
+        for frame in 1..60 {
+            assert!(!sampler.end_frame(start + SAMPLE_PERIOD * frame / 60));
+        }
+        let now = start + SAMPLE_PERIOD * 2;
+        assert!(sampler.end_frame(now));
+        sampler.sample(now, counters(30_000_000, 33_868_800));
+
+        let metrics = sampler.handle().load_full();
+        assert_eq!(metrics.frames, 60);
+        assert_eq!(metrics.fps, 30.);
+        assert_eq!(metrics.instructions_per_second, 15_000_000.);
+        assert_eq!(metrics.speed, 50.);
+        assert_eq!(metrics.per_frame.instructions, 500_000.);
+        assert_eq!((metrics.per_frame.loads, metrics.per_frame.mult_div), (100_000., 10.));
+
+        // Rates are computed from the previous sample
+        let later = now + SAMPLE_PERIOD;
+        sampler.end_frame(later);
+        sampler.sample(later, counters(45_000_000, 67_737_600));
+        let metrics = sampler.handle().load_full();
+        assert_eq!((metrics.fps, metrics.speed), (1., 100.));
+
+        let json = metrics.to_json();
+        assert!(json.contains("\"frames\": 61,"), "{}", json);
+        assert!(json.contains("\"Gpu\": 1024"), "{}", json);
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE psx_frames_total counter\npsx_frames_total 61\n"));
+        assert!(text.contains("psx_dma_words_per_second{channel=\"Gpu\"} 1024\n"));
+        assert!(text.contains("psx_audio_underruns_total 3\n"));
+        assert!(text.contains("psx_instructions_per_frame{class=\"store\"} 1500000\n"));
+    }
+}
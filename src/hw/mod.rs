@@ -1,20 +1,59 @@
 mod bios;
 pub mod bus;
 mod cdrom;
+mod compat;
 pub mod disasm;
 mod dma;
+pub mod exe;
+mod exp1;
+mod exp2;
+pub mod fill;
 mod gpu;
+pub mod input;
+mod irq;
 mod joy_mc;
+mod mdec;
+mod memctrl;
+mod memmap;
+pub mod metrics;
 mod ram;
+#[cfg(feature = "debug-registers")]
+pub mod registers;
+mod sio;
+mod rom_writes;
 mod spu;
+mod status;
 mod timers;
 mod vec;
 
 use crate::hw::bios::Bios;
-use crate::hw::cdrom::Cdrom;
+use crate::hw::cdrom::{Cdrom, Disc};
 use crate::hw::dma::Dma;
+use crate::hw::exp1::Expansion1;
+use crate::hw::exp2::Expansion2;
 use crate::hw::gpu::Gpu;
 use crate::hw::joy_mc::JoypadMemorycard;
+use crate::hw::mdec::Mdec;
 use crate::hw::ram::Ram;
 use crate::hw::spu::Spu;
 use crate::hw::timers::Timers;
+
+pub use crate::hw::cdrom::DiscError;
+pub use crustationcpu::IcacheMode;
+pub use crate::hw::gpu::{
+    postprocess, ColorProfile, DisplayArea, DisplayCommand, GpuAccuracy, GpuAccuracyConfig,
+    GpuPreference, GpuProfile, GpuState,
+    GpuStateHandle, RendererOptions, VideoFrame, WindowGeometry,
+};
+pub use crate::hw::joy_mc::Rumble;
+pub use crate::hw::spu::SpeedAudio;
+pub use crate::hw::sio::backend::{open as open_serial_backend, SerialBackend};
+
+/// `name` in a temporary directory of this process, so that test runs in
+/// parallel don't write over each other's files
+#[cfg(test)]
+fn test_file(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("crustation-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
@@ -1,24 +1,347 @@
 use crate::hw::bus::{BusDevice};
+use crate::hw::fill::Fill;
 use crate::hw::vec::ByteSerialized;
+use crustationcpu::state::{Savestate, StateError, StateReader, StateWriter};
+
+use std::iter;
+use std::ops::Range;
+
+/// Size of the physical RAM chips installed on retail units
+const PHYSICAL_SIZE: u32 = 2 * 1024 * 1024;
+
+const MB: u32 = 1024 * 1024;
+
+/// What answers to an access in the 8MB RAM window
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Region {
+    /// Physical RAM, at the given (mirrored) offset
+    Memory(u32),
+    /// Nothing is driving the bus
+    HighZ,
+    /// The memory controller refuses the access
+    Locked,
+}
 
 pub struct Ram {
     memory: Vec<u8>,
+
+    /// RAM_SIZE register (0x1f80_1060)
+    ram_size: u32,
+
+    /// Returned by reads of the High-Z and locked areas
+    open_bus: Fill,
 }
 
 impl Ram {
     pub fn new() -> Ram {
         Ram {
-            memory: vec![0; 2 * 1024 * 1024],
+            memory: vec![0; PHYSICAL_SIZE as usize],
+
+            // Value set by the BIOS on boot: 8MB window, 2MB mirrored 4 times
+            ram_size: 0x0000_0b88,
+
+            open_bus: Fill::Ones,
+        }
+    }
+
+    /// Sets the contents of the whole memory, as found at power-on
+    pub fn power_on(&mut self, fill: Fill) {
+        fill.apply(&mut self.memory);
+    }
+
+    pub fn set_open_bus(&mut self, fill: Fill) {
+        self.open_bus = fill;
+    }
+
+    pub fn ram_size(&self) -> u32 {
+        self.ram_size
+    }
+
+    /// Sets RAM_SIZE. Bits 9-11 select how the first 8MB of the address
+    /// space are split between RAM (and its mirrors), High-Z and locked
+    /// areas. This is written by the BIOS SetMemSize (A(9Fh)) function.
+    pub fn set_ram_size(&mut self, value: u32) {
+        self.ram_size = value;
+    }
+
+    /// Word read as done by the DMA controller: the address wraps around
+    /// the physical RAM and drops the low bits
+    pub fn dma_read(&self, addr: u32) -> u32 {
+        self.memory.read::<4>(addr & (PHYSICAL_SIZE - 4))
+    }
+
+    pub fn dma_write(&mut self, addr: u32, value: u32) {
+        self.memory.write::<4>(addr & (PHYSICAL_SIZE - 4), value);
+    }
+
+    /// Reads consecutive words for DMA, `step` bytes apart (4 or -4).
+    /// Returns the address following the last word.
+    pub fn dma_read_words(&self, addr: u32, step: i32, words: &mut [u32]) -> u32 {
+        if step == 4 {
+            for (offset, range) in spans(addr & (PHYSICAL_SIZE - 4), words.len() * 4) {
+                let bytes = &self.memory[offset..offset + range.len()];
+                let words = &mut words[range.start / 4..range.end / 4];
+                for (word, bytes) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+                    *word = u32::from_le_bytes(bytes.try_into().unwrap());
+                }
+            }
+        } else {
+            let mut addr = addr;
+            for word in words.iter_mut() {
+                *word = self.dma_read(addr);
+                addr = addr.wrapping_add(step as u32);
+            }
+        }
+
+        addr.wrapping_add((step as u32).wrapping_mul(words.len() as u32))
+    }
+
+    /// Writes consecutive words for DMA, `step` bytes apart (4 or -4).
+    /// Returns the address following the last word.
+    pub fn dma_write_words(&mut self, addr: u32, step: i32, words: &[u32]) -> u32 {
+        if step == 4 {
+            for (offset, range) in spans(addr & (PHYSICAL_SIZE - 4), words.len() * 4) {
+                let bytes = &mut self.memory[offset..offset + range.len()];
+                let words = &words[range.start / 4..range.end / 4];
+                for (word, bytes) in words.iter().zip(bytes.chunks_exact_mut(4)) {
+                    bytes.copy_from_slice(&word.to_le_bytes());
+                }
+            }
+        } else {
+            let mut addr = addr;
+            for &word in words {
+                self.dma_write(addr, word);
+                addr = addr.wrapping_add(step as u32);
+            }
+        }
+
+        addr.wrapping_add((step as u32).wrapping_mul(words.len() as u32))
+    }
+
+    /// Copies the physical RAM from `addr` to `data`, wrapping around at its
+    /// end. Ignores RAM_SIZE and takes no time.
+    pub fn read_slice(&self, addr: u32, data: &mut [u8]) {
+        for (offset, range) in spans(addr, data.len()) {
+            let len = range.len();
+            data[range].copy_from_slice(&self.memory[offset..offset + len]);
+        }
+    }
+
+    /// Copies `data` to the physical RAM at `addr`, wrapping around at its
+    /// end. For loaders: ignores RAM_SIZE and takes no time.
+    pub fn write_slice(&mut self, addr: u32, data: &[u8]) {
+        for (offset, range) in spans(addr, data.len()) {
+            self.memory[offset..offset + range.len()].copy_from_slice(&data[range]);
         }
     }
+
+    /// Sets `len` bytes of the physical RAM from `addr` to `value`, wrapping
+    /// around at its end
+    pub fn fill(&mut self, addr: u32, len: u32, value: u8) {
+        for (offset, range) in spans(addr, len.min(PHYSICAL_SIZE) as usize) {
+            self.memory[offset..offset + range.len()].fill(value);
+        }
+    }
+
+    fn region(&self, addr: u32) -> Region {
+        // (memory, high-z) sizes of the window, everything above is locked
+        let (memory, high_z) = match (self.ram_size >> 9) & 7 {
+            0 => (MB, 0),
+            1 => (4 * MB, 0),
+            2 => (MB, MB),
+            3 => (4 * MB, 4 * MB),
+            4 => (2 * MB, 0),
+            5 | 7 => (8 * MB, 0),
+            6 => (2 * MB, 2 * MB),
+            _ => unreachable!(),
+        };
+
+        if addr < memory {
+            Region::Memory(addr % memory.min(PHYSICAL_SIZE))
+        } else if addr < memory + high_z {
+            Region::HighZ
+        } else {
+            Region::Locked
+        }
+    }
+}
+
+/// Splits `len` bytes from `addr` into runs that stop at the end of the
+/// physical RAM: their offset in the RAM, and their range in the data
+fn spans(addr: u32, len: usize) -> impl Iterator<Item = (usize, Range<usize>)> {
+    let mut offset = (addr % PHYSICAL_SIZE) as usize;
+    let mut start = 0;
+
+    iter::from_fn(move || {
+        if start == len {
+            return None;
+        }
+
+        let count = (len - start).min(PHYSICAL_SIZE as usize - offset);
+        let span = (offset, start..start + count);
+        offset = 0;
+        start += count;
+        Some(span)
+    })
 }
 
 impl BusDevice for Ram {
     fn read<const S: u32>(&mut self, addr: u32) -> u32 {
-        self.memory.read::<S>(addr)
+        match self.region(addr) {
+            Region::Memory(offset) => self.memory.read::<S>(offset),
+            // TODO: locked accesses should raise a bus error exception
+            Region::HighZ | Region::Locked => self.open_bus.word(addr),
+        }
     }
 
     fn write<const S: u32>(&mut self, addr: u32, value: u32) {
-        self.memory.write::<S>(addr, value);
+        if let Region::Memory(offset) = self.region(addr) {
+            self.memory.write::<S>(offset, value);
+        }
+    }
+}
+
+impl Savestate for Ram {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"RAM ");
+        state.write_u32(self.ram_size);
+        state.write_bytes(&self.memory);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"RAM ", "RAM")?;
+        self.ram_size = state.read_u32()?;
+        state.read_bytes(&mut self.memory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_window_mirrors_2mb_four_times() {
+        let mut ram = Ram::new();
+        ram.write::<4>(0x10, 0x1234_5678);
+
+        assert_eq!(ram.read::<4>(0x20_0010), 0x1234_5678);
+        assert_eq!(ram.read::<4>(0x40_0010), 0x1234_5678);
+        assert_eq!(ram.read::<4>(0x60_0010), 0x1234_5678);
+    }
+
+    #[test]
+    fn writes_through_mirrors_hit_the_same_memory() {
+        let mut ram = Ram::new();
+        ram.write::<2>(0x60_0100, 0xbeef);
+
+        assert_eq!(ram.read::<2>(0x100), 0xbeef);
+    }
+
+    #[test]
+    fn switching_to_2mb_locks_the_mirrors() {
+        let mut ram = Ram::new();
+        ram.write::<4>(0, 0xcafe_babe);
+
+        ram.set_ram_size(4 << 9);
+        assert_eq!(ram.read::<4>(0), 0xcafe_babe);
+        assert_eq!(ram.read::<4>(0x20_0000), 0xffff_ffff);
+
+        // Writes to locked areas are dropped
+        ram.write::<4>(0x20_0000, 0);
+        assert_eq!(ram.read::<4>(0), 0xcafe_babe);
+
+        // And back to 8MB mid-run
+        ram.set_ram_size(0x0000_0b88);
+        assert_eq!(ram.read::<4>(0x20_0000), 0xcafe_babe);
+    }
+
+    #[test]
+    fn one_mb_window_mirrors_the_first_megabyte() {
+        let mut ram = Ram::new();
+        ram.write::<1>(0x42, 0x99);
+
+        ram.set_ram_size(2 << 9);
+        assert_eq!(ram.read::<1>(0x42), 0x99);
+        // 1MB of High-Z follows the memory, then the rest is locked
+        assert_eq!(ram.read::<1>(0x10_0042), 0xffff_ffff);
+        assert_eq!(ram.read::<1>(0x20_0042), 0xffff_ffff);
+
+        ram.set_ram_size(1 << 9);
+        ram.write::<1>(0x20_0000, 0x11);
+        assert_eq!(ram.read::<1>(0), 0x11);
+    }
+
+    #[test]
+    fn power_on_and_open_bus_follow_the_fill() {
+        let mut ram = Ram::new();
+        ram.power_on(Fill::Ones);
+        assert_eq!(ram.read::<4>(0x1f_fffc), 0xffff_ffff);
+
+        ram.power_on(Fill::Random(7));
+        assert_eq!(ram.read::<4>(0x1234), Fill::Random(7).word(0x1234));
+        assert_eq!(ram.read::<4>(0x20_1234), Fill::Random(7).word(0x1234));
+
+        ram.set_ram_size(4 << 9);
+        ram.set_open_bus(Fill::Zeros);
+        assert_eq!(ram.read::<4>(0x20_0000), 0);
+    }
+
+    #[test]
+    fn bulk_accesses_wrap_around() {
+        let mut ram = Ram::new();
+        ram.write_slice(0x801f_fffe, &[1, 2, 3, 4]);
+
+        assert_eq!(ram.read::<2>(0x1f_fffe), 0x0201);
+        assert_eq!(ram.read::<2>(0), 0x0403);
+
+        let mut data = [0; 6];
+        ram.read_slice(0x3f_fffd, &mut data);
+        assert_eq!(data, [0, 1, 2, 3, 4, 0]);
+
+        // Longer than the RAM: the end of the data wins
+        let mut data = vec![1; PHYSICAL_SIZE as usize];
+        data.extend([2, 2]);
+        ram.write_slice(0, &data);
+        assert_eq!(ram.read::<4>(0), 0x0101_0202);
+    }
+
+    #[test]
+    fn dma_addresses_wrap_and_align() {
+        let mut ram = Ram::new();
+        ram.dma_write(0x20_0013, 0xdead_beef);
+
+        assert_eq!(ram.read::<4>(0x10), 0xdead_beef);
+        assert_eq!(ram.dma_read(0xff_fff0 + 0x20), 0xdead_beef);
+    }
+
+    #[test]
+    fn dma_blocks_follow_the_step() {
+        let mut ram = Ram::new();
+
+        let next = ram.dma_write_words(0x100, -4, &[1, 2, 3]);
+        assert_eq!(next, 0xf4);
+        assert_eq!(ram.read::<4>(0xf8), 3);
+
+        let mut words = [0; 3];
+        ram.dma_read_words(0xf8, 4, &mut words);
+        assert_eq!(words, [3, 2, 1]);
+
+        // Forward blocks wrap at the end of the RAM
+        let next = ram.dma_write_words(0x1f_fffa, 4, &[4, 5, 6]);
+        assert_eq!(next, 0x20_0006);
+        assert_eq!(ram.read::<4>(0x1f_fff8), 4);
+        assert_eq!(ram.read::<4>(0), 6);
+
+        let mut words = [0; 3];
+        assert_eq!(ram.dma_read_words(0x7f_fff8, 4, &mut words), 0x80_0004);
+        assert_eq!(words, [4, 5, 6]);
+    }
+
+    #[test]
+    fn ram_size_reads_back() {
+        let mut ram = Ram::new();
+        ram.set_ram_size(0x0000_0888);
+
+        assert_eq!(ram.ram_size(), 0x0000_0888);
     }
 }
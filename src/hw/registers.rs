@@ -0,0 +1,102 @@
+//! Access to the device registers by address, for tests and debugging
+//! tools. Only built with the `debug-registers` feature.
+//!
+//! Accesses go through the same bus decoding as those of the CPU, with
+//! their side effects (reading a FIFO pops it, writing DMA registers may
+//! start a transfer), but they take no emulated time.
+
+use crustationcpu::PsxBus;
+
+use crate::hw::bus::Bus;
+
+/// Registers of a device, in physical addresses
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Device {
+    pub name: &'static str,
+    pub start: u32,
+    /// Inclusive
+    pub end: u32,
+}
+
+impl Device {
+    const fn new(name: &'static str, start: u32, end: u32) -> Device {
+        Device { name, start, end }
+    }
+}
+
+/// The interrupt controller is part of the CPU, and is not listed
+pub const DEVICES: [Device; 10] = [
+    Device::new("Pad/memory card", 0x1f80_1040, 0x1f80_104f),
+    Device::new("RAM size", 0x1f80_1060, 0x1f80_1060),
+    Device::new("DMA", 0x1f80_1080, 0x1f80_10f4),
+    Device::new("Timers", 0x1f80_1100, 0x1f80_112f),
+    Device::new("CDROM", 0x1f80_1800, 0x1f80_1803),
+    Device::new("GPU", 0x1f80_1810, 0x1f80_1817),
+    Device::new("MDEC", 0x1f80_1820, 0x1f80_1827),
+    Device::new("SPU", 0x1f80_1c00, 0x1f80_1fff),
+    Device::new("Expansion 2", 0x1f80_2000, 0x1f80_207f),
+    Device::new("Memory control", 0x1f80_1000, 0x1f80_1020),
+];
+
+impl Bus {
+    pub fn devices(&self) -> &'static [Device] {
+        &DEVICES
+    }
+
+    /// The device whose registers include `addr`, in any region
+    pub fn device_at(&self, addr: u32) -> Option<&'static Device> {
+        let addr = Bus::strip_region(addr);
+        DEVICES.iter().find(|device| (device.start..=device.end).contains(&addr))
+    }
+
+    /// Reads an `S` bytes register. Returns None if no device is there.
+    pub fn read_register<const S: u32>(&self, addr: u32) -> Option<u32> {
+        self.device_at(addr)?;
+
+        let cycles = *self.total_cycles.borrow();
+        let value = match Bus::strip_region(addr) {
+            // Not readable by the CPU, the last written values are kept
+            addr @ 0x1f80_1000..=0x1f80_1020 => self.read_io::<S>(addr & 0xffff),
+            addr => PsxBus::read::<S>(self, addr),
+        };
+        *self.total_cycles.borrow_mut() = cycles;
+
+        Some(value)
+    }
+
+    /// Writes an `S` bytes register. Returns false if no device is there.
+    pub fn write_register<const S: u32>(&self, addr: u32, value: u32) -> bool {
+        if self.device_at(addr).is_none() {
+            return false;
+        }
+
+        let cycles = *self.total_cycles.borrow();
+        PsxBus::write::<S>(self, Bus::strip_region(addr), value);
+        *self.total_cycles.borrow_mut() = cycles;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reaches_registers_by_address() {
+        let bus = Bus::new();
+
+        assert_eq!(bus.device_at(0xbf80_1814).unwrap().name, "GPU");
+        assert_eq!(bus.read_register::<4>(0x1f80_1814), Some(0x1480_2000));
+
+        // Timer 1 target, through KSEG1
+        assert!(bus.write_register::<2>(0xbf80_1118, 0x1234));
+        assert_eq!(bus.read_register::<2>(0x1f80_1118), Some(0x1234));
+
+        assert!(bus.write_register::<4>(0x1f80_1010, 0x0013_243f));
+        assert_eq!(bus.read_register::<4>(0x1f80_1010), Some(0x0013_243f));
+
+        assert_eq!(*bus.total_cycles.borrow(), 0);
+        assert_eq!(bus.read_register::<4>(0x1f80_1070), None);
+        assert!(!bus.write_register::<4>(0x0000_1000, 0));
+    }
+}
@@ -0,0 +1,41 @@
+//! Writes to ROMs, which are dropped. The first ones are reported, as they
+//! usually come from a bug; the others are only counted.
+
+/// Writes reported, per ROM
+const MAX_WRITE_WARNINGS: u64 = 8;
+
+pub struct RomWriteWarnings {
+    /// Log tag of the device, e.g. "BIOS"
+    device: &'static str,
+    /// Hex digits of the addresses reported
+    digits: usize,
+    /// Writes dropped since power on
+    ignored: u64,
+}
+
+impl RomWriteWarnings {
+    pub fn new(device: &'static str, digits: usize) -> RomWriteWarnings {
+        RomWriteWarnings {
+            device,
+            digits,
+            ignored: 0,
+        }
+    }
+
+    /// Counts a write of `S` bytes, and reports it while under the limit
+    pub fn ignore<const S: u32>(&mut self, addr: u32, value: u32) {
+        self.ignored += 1;
+        if self.ignored <= MAX_WRITE_WARNINGS {
+            println!(
+                "[{}] Ignoring {}-byte write of {:08x} to the ROM at {:0digits$x}",
+                self.device,
+                S,
+                value,
+                addr,
+                digits = self.digits
+            );
+        } else if self.ignored == MAX_WRITE_WARNINGS + 1 {
+            println!("[{}] Further writes to the ROM are ignored silently", self.device);
+        }
+    }
+}
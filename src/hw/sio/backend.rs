@@ -0,0 +1,210 @@
+//! Where the bytes of the serial port go: nowhere, the terminal, or another
+//! machine over TCP (another emulator for link cable games, or a PC talking
+//! to homebrew).
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+
+/// The other end of the serial cable
+pub trait SerialBackend {
+    /// A byte sent by the console
+    fn send(&mut self, byte: u8);
+    /// A byte for the console, if one has arrived
+    fn receive(&mut self) -> Option<u8>;
+    /// Whether something is plugged in, seen by the console as DSR and CTS
+    fn connected(&self) -> bool {
+        true
+    }
+}
+
+/// Nothing plugged in
+pub struct NullBackend;
+
+impl SerialBackend for NullBackend {
+    fn send(&mut self, _: u8) {}
+
+    fn receive(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn connected(&self) -> bool {
+        false
+    }
+}
+
+/// Sent bytes go to stdout, stdin is read from another thread
+pub struct StdioBackend {
+    input: mpsc::Receiver<u8>,
+}
+
+impl StdioBackend {
+    pub fn new() -> StdioBackend {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                match byte {
+                    Ok(byte) if tx.send(byte).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+        StdioBackend { input: rx }
+    }
+}
+
+impl SerialBackend for StdioBackend {
+    fn send(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[byte]);
+        let _ = stdout.flush();
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+/// A TCP connection, made to a server or accepted from a client. Sockets
+/// are non-blocking, the port polls them once per character.
+pub struct TcpBackend {
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+}
+
+impl TcpBackend {
+    pub fn connect(addr: &str) -> io::Result<TcpBackend> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        println!("[SIO] Connected to {}", addr);
+        Ok(TcpBackend {
+            listener: None,
+            stream: Some(stream),
+        })
+    }
+
+    /// Waits for a connection on `addr`, and for another one whenever the
+    /// client goes away
+    pub fn listen(addr: &str) -> io::Result<TcpBackend> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        println!("[SIO] Listening on {}", listener.local_addr()?);
+        Ok(TcpBackend {
+            listener: Some(listener),
+            stream: None,
+        })
+    }
+
+    fn accept(&mut self) {
+        let listener = match (&self.listener, &self.stream) {
+            (Some(listener), None) => listener,
+            _ => return,
+        };
+
+        if let Ok((stream, peer)) = listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                let _ = stream.set_nodelay(true);
+                println!("[SIO] Connection from {}", peer);
+                self.stream = Some(stream);
+            }
+        }
+    }
+
+    fn disconnect(&mut self, reason: &str) {
+        if self.stream.take().is_some() {
+            println!("[SIO] Disconnected: {}", reason);
+        }
+    }
+}
+
+impl SerialBackend for TcpBackend {
+    /// Dropped if the peer doesn't keep up and the socket buffer is full
+    fn send(&mut self, byte: u8) {
+        self.accept();
+        if let Some(stream) = &mut self.stream {
+            match stream.write(&[byte]) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => self.disconnect(&err.to_string()),
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.accept();
+        let stream = self.stream.as_mut()?;
+        let mut byte = [0];
+        match stream.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            Ok(_) => {
+                self.disconnect("closed by the peer");
+                None
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => None,
+            Err(err) => {
+                self.disconnect(&err.to_string());
+                None
+            }
+        }
+    }
+
+    fn connected(&self) -> bool {
+        self.stream.is_some()
+    }
+}
+
+/// A backend from its description: `null`, `stdio`, `tcp:HOST:PORT` to
+/// connect, or `listen:[HOST:]PORT` to wait for a connection (on localhost
+/// when HOST is missing)
+pub fn open(spec: &str) -> io::Result<Box<dyn SerialBackend>> {
+    if spec == "null" {
+        Ok(Box::new(NullBackend))
+    } else if spec == "stdio" {
+        Ok(Box::new(StdioBackend::new()))
+    } else if let Some(addr) = spec.strip_prefix("tcp:") {
+        Ok(Box::new(TcpBackend::connect(addr)?))
+    } else if let Some(addr) = spec.strip_prefix("listen:") {
+        let backend = match addr.contains(':') {
+            true => TcpBackend::listen(addr)?,
+            false => TcpBackend::listen(&format!("127.0.0.1:{}", addr))?,
+        };
+        Ok(Box::new(backend))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown serial backend {}", spec),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_backends_talk_to_each_other() {
+        let mut server = TcpBackend::listen("127.0.0.1:0").unwrap();
+        let addr = server.listener.as_ref().unwrap().local_addr().unwrap();
+        let mut client = TcpBackend::connect(&addr.to_string()).unwrap();
+
+        client.send(0x42);
+        let mut received = None;
+        for _ in 0..1000 {
+            received = server.receive();
+            if received.is_some() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(received, Some(0x42));
+        assert!(server.connected());
+    }
+
+    #[test]
+    fn unknown_backends_are_rejected() {
+        assert!(open("null").is_ok());
+        assert!(open("serial:/dev/ttyS0").is_err());
+    }
+}
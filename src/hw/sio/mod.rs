@@ -0,0 +1,382 @@
+//! SIO1, the serial port on the back of early models. A UART with a
+//! single byte TX buffer and an 8-byte RX FIFO, connected to a
+//! `SerialBackend`.
+//!
+//! The port ticks once per character while sending or receiving is
+//! enabled: the byte being sent goes to the backend and a received one,
+//! if any, enters the FIFO.
+
+pub mod backend;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Weak;
+
+use crustationcpu::state::{Savestate, StateError, StateReader, StateWriter};
+
+use crate::hw::bus::{Bus, BusDevice, PsxEventType};
+use crate::hw::irq::Irq;
+use backend::{NullBackend, SerialBackend};
+
+const RX_FIFO_SIZE: usize = 8;
+
+// SIO_STAT bits
+const STAT_TX_READY: u32 = 1 << 0;
+const STAT_RX_NOT_EMPTY: u32 = 1 << 1;
+const STAT_TX_IDLE: u32 = 1 << 2;
+const STAT_RX_OVERRUN: u32 = 1 << 4;
+const STAT_DSR: u32 = 1 << 7;
+const STAT_CTS: u32 = 1 << 8;
+const STAT_IRQ: u32 = 1 << 9;
+
+// SIO_CTRL bits
+const CTRL_TXEN: u16 = 1 << 0;
+const CTRL_RXEN: u16 = 1 << 2;
+const CTRL_ACK: u16 = 1 << 4;
+const CTRL_RESET: u16 = 1 << 6;
+const CTRL_TX_IRQ: u16 = 1 << 10;
+const CTRL_RX_IRQ: u16 = 1 << 11;
+const CTRL_DSR_IRQ: u16 = 1 << 12;
+
+pub struct SerialPort {
+    mode: u16,
+    ctrl: u16,
+    misc: u16,
+    baud: u16,
+    /// The sticky bits of SIO_STAT: overrun and IRQ
+    stat: u32,
+
+    /// Written by the CPU, not being sent yet
+    tx_buffer: Option<u8>,
+    /// Being sent, it reaches the backend at the next tick
+    tx_shift: Option<u8>,
+    rx_fifo: VecDeque<u8>,
+
+    /// Cycles between ticks as scheduled, None when stopped
+    tick_period: Option<u64>,
+    backend: Box<dyn SerialBackend>,
+
+    bus: Weak<RefCell<Bus>>,
+}
+
+impl SerialPort {
+    pub fn new() -> SerialPort {
+        SerialPort {
+            mode: 0,
+            ctrl: 0,
+            misc: 0,
+            baud: 0,
+            stat: 0,
+
+            tx_buffer: None,
+            tx_shift: None,
+            rx_fifo: VecDeque::with_capacity(RX_FIFO_SIZE),
+
+            tick_period: None,
+            backend: Box::new(NullBackend),
+
+            bus: Weak::new(),
+        }
+    }
+
+    pub fn link(&mut self, bus: Weak<RefCell<Bus>>) {
+        self.bus = bus;
+    }
+
+    pub fn set_backend(&mut self, backend: Box<dyn SerialBackend>) {
+        self.backend = backend;
+    }
+
+    fn status(&self) -> u32 {
+        let mut status = self.stat;
+        if self.tx_buffer.is_none() {
+            status |= STAT_TX_READY;
+            if self.tx_shift.is_none() {
+                status |= STAT_TX_IDLE;
+            }
+        }
+        if !self.rx_fifo.is_empty() {
+            status |= STAT_RX_NOT_EMPTY;
+        }
+        if self.backend.connected() {
+            status |= STAT_DSR | STAT_CTS;
+        }
+        status
+    }
+
+    /// Cycles a character takes at the baud rate: a start bit, 5 to 8 data
+    /// bits, the parity bit and the stop bits. None when the mode stops
+    /// the baud rate timer.
+    fn char_cycles(&self) -> Option<u64> {
+        let factor = match self.mode & 3 {
+            0 => return None,
+            1 => 1,
+            2 => 16,
+            _ => 64,
+        };
+        let bit_cycles = ((self.baud as u64 * factor) & !1).max(factor);
+
+        let data_bits = 5 + ((self.mode >> 2) & 3) as u64;
+        let parity_bits = ((self.mode >> 4) & 1) as u64;
+        // 1.5 stop bits are counted as 2
+        let stop_bits = match (self.mode >> 6) & 3 {
+            0 | 1 => 1,
+            _ => 2,
+        };
+        Some(bit_cycles * (1 + data_bits + parity_bits + stop_bits))
+    }
+
+    /// Starts or stops the ticks when the timing or the enabled directions
+    /// change
+    fn reschedule(&mut self) {
+        let enabled = self.ctrl & (CTRL_TXEN | CTRL_RXEN) != 0;
+        let period = self.char_cycles().filter(|_| enabled);
+        if period == self.tick_period {
+            return;
+        }
+        self.tick_period = period;
+
+        let bus = match self.bus.upgrade() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let bus = bus.borrow();
+        match period {
+            Some(cycles) => {
+                let target = *bus.total_cycles.borrow() + cycles;
+                bus.add_event(PsxEventType::SerialTick, target, cycles);
+            }
+            None => bus.remove_event(PsxEventType::SerialTick),
+        }
+    }
+
+    /// Called by the bus once per character
+    pub fn tick(&mut self) {
+        if let Some(byte) = self.tx_shift.take() {
+            self.backend.send(byte);
+        }
+        if self.ctrl & CTRL_TXEN != 0 {
+            self.tx_shift = self.tx_buffer.take();
+        }
+
+        if self.ctrl & CTRL_RXEN != 0 {
+            if let Some(byte) = self.backend.receive() {
+                if self.rx_fifo.len() < RX_FIFO_SIZE {
+                    self.rx_fifo.push_back(byte);
+                } else {
+                    self.stat |= STAT_RX_OVERRUN;
+                }
+            }
+        }
+
+        self.update_irq();
+    }
+
+    /// Raises the interrupt when one of the enabled conditions is met. It
+    /// stays up until acknowledged through SIO_CTRL.
+    fn update_irq(&mut self) {
+        if self.stat & STAT_IRQ != 0 {
+            return;
+        }
+
+        let status = self.status();
+        let rx_threshold = 1 << ((self.ctrl >> 8) & 3);
+        let tx = self.ctrl & CTRL_TX_IRQ != 0 && status & STAT_TX_READY != 0;
+        let rx = self.ctrl & CTRL_RX_IRQ != 0 && self.rx_fifo.len() >= rx_threshold;
+        let dsr = self.ctrl & CTRL_DSR_IRQ != 0 && status & STAT_DSR != 0;
+        if !(tx || rx || dsr) {
+            return;
+        }
+
+        self.stat |= STAT_IRQ;
+        if let Some(bus) = self.bus.upgrade() {
+            bus.borrow().send_irq(Irq::Sio);
+        }
+    }
+
+    fn write_tx_data(&mut self, data: u8) {
+        self.tx_buffer = Some(data);
+        // Goes straight to the shift register when nothing is being sent
+        if self.ctrl & CTRL_TXEN != 0 && self.tx_shift.is_none() {
+            self.tx_shift = self.tx_buffer.take();
+        }
+    }
+
+    fn write_ctrl(&mut self, value: u16) {
+        if value & CTRL_RESET != 0 {
+            self.mode = 0;
+            self.misc = 0;
+            self.baud = 0;
+            self.stat = 0;
+            self.tx_buffer = None;
+            self.tx_shift = None;
+            self.rx_fifo.clear();
+        }
+        if value & CTRL_ACK != 0 {
+            self.stat &= !(STAT_RX_OVERRUN | STAT_IRQ);
+        }
+
+        // Acknowledge and reset are not kept
+        self.ctrl = value & !(CTRL_ACK | CTRL_RESET);
+        if self.ctrl & CTRL_RXEN == 0 {
+            self.rx_fifo.clear();
+        }
+    }
+}
+
+impl BusDevice for SerialPort {
+    fn read<const S: u32>(&mut self, addr: u32) -> u32 {
+        match addr {
+            0x00 => self.rx_fifo.pop_front().unwrap_or(0) as u32,
+            0x04 => self.status(),
+            0x08 => self.mode as u32,
+            0x0a => self.ctrl as u32,
+            0x0c => self.misc as u32,
+            0x0e => self.baud as u32,
+            _ => 0,
+        }
+    }
+
+    fn write<const S: u32>(&mut self, addr: u32, value: u32) {
+        let value = value as u16;
+
+        match addr {
+            0x00 => self.write_tx_data(value as u8),
+            0x08 => self.mode = value,
+            0x0a => self.write_ctrl(value),
+            0x0c => self.misc = value,
+            0x0e => self.baud = value,
+            _ => {}
+        }
+
+        self.reschedule();
+        self.update_irq();
+    }
+}
+
+impl Savestate for SerialPort {
+    /// The backend is not saved, the port keeps the one it has
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"SIO1");
+        state.write_u16(self.mode);
+        state.write_u16(self.ctrl);
+        state.write_u16(self.misc);
+        state.write_u16(self.baud);
+        state.write_u32(self.stat);
+        for byte in [self.tx_buffer, self.tx_shift] {
+            state.write_bool(byte.is_some());
+            state.write_u8(byte.unwrap_or(0));
+        }
+        state.write_u8(self.rx_fifo.len() as u8);
+        for &byte in &self.rx_fifo {
+            state.write_u8(byte);
+        }
+        state.write_u64(self.tick_period.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"SIO1", "serial port")?;
+        self.mode = state.read_u16()?;
+        self.ctrl = state.read_u16()?;
+        self.misc = state.read_u16()?;
+        self.baud = state.read_u16()?;
+        self.stat = state.read_u32()?;
+        for byte in [&mut self.tx_buffer, &mut self.tx_shift] {
+            let full = state.read_bool()?;
+            let value = state.read_u8()?;
+            *byte = full.then_some(value);
+        }
+        let len = state.read_u8()? as usize;
+        if len > RX_FIFO_SIZE {
+            return Err(StateError::Invalid("serial port FIFO"));
+        }
+        self.rx_fifo.clear();
+        for _ in 0..len {
+            self.rx_fifo.push_back(state.read_u8()?);
+        }
+        self.tick_period = Some(state.read_u64()?).filter(|&cycles| cycles != 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crustationcpu::PsxBus;
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// Keeps what is sent, and has the bytes of `input` to receive
+    struct Loopback {
+        sent: Rc<RefCell<Vec<u8>>>,
+        input: VecDeque<u8>,
+    }
+
+    impl SerialBackend for Loopback {
+        fn send(&mut self, byte: u8) {
+            self.sent.borrow_mut().push(byte);
+        }
+
+        fn receive(&mut self) -> Option<u8> {
+            self.input.pop_front()
+        }
+    }
+
+    fn bus_with_loopback(input: &[u8]) -> (Rc<RefCell<Bus>>, Rc<RefCell<Vec<u8>>>) {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let sent = Rc::new(RefCell::new(vec![]));
+        bus.borrow().set_serial_backend(Box::new(Loopback {
+            sent: sent.clone(),
+            input: input.iter().copied().collect(),
+        }));
+        (bus, sent)
+    }
+
+    #[test]
+    fn characters_follow_the_baud_rate() {
+        let mut port = SerialPort::new();
+        assert_eq!(port.char_cycles(), None);
+
+        // 8N1 with a x16 factor, 0x12 reload: 38400 bauds
+        port.mode = 0x4e;
+        port.baud = 0x12;
+        assert_eq!(port.char_cycles(), Some(10 * 0x12 * 16));
+
+        // 7 bits, parity, 2 stop bits, x1: odd reloads are rounded down
+        port.mode = 0xd9;
+        port.baud = 0x25;
+        assert_eq!(port.char_cycles(), Some(11 * 0x24));
+    }
+
+    #[test]
+    fn sends_and_receives_through_the_backend() {
+        let (bus, sent) = bus_with_loopback(b"ok");
+        let bus = bus.borrow();
+
+        bus.write::<2>(0x1f80_1058, 0x4e);
+        bus.write::<2>(0x1f80_105e, 0x12);
+        // TX and RX enabled, IRQ when 2 bytes are received
+        bus.write::<2>(0x1f80_105a, 0x0905);
+        assert_eq!(bus.read::<4>(0x1f80_1054) & 0x185, 0x185);
+
+        bus.write::<1>(0x1f80_1050, b'h' as u32);
+        bus.write::<1>(0x1f80_1050, b'i' as u32);
+        // 'h' is being sent, 'i' waits for it
+        assert_eq!(bus.read::<4>(0x1f80_1054) & 5, 0);
+
+        bus.update_cycles(10 * 0x12 * 16 + 1);
+        assert_eq!(&*sent.borrow(), b"h");
+        assert_eq!(bus.read::<4>(0x1f80_1054) & (STAT_IRQ | STAT_RX_NOT_EMPTY), 0x002);
+
+        bus.update_cycles(10 * 0x12 * 16);
+        assert_eq!(&*sent.borrow(), b"hi");
+        assert_eq!(bus.read::<4>(0x1f80_1054) & 0x207, 0x207);
+        assert_eq!(bus.read::<1>(0x1f80_1050), b'o' as u32);
+        assert_eq!(bus.read::<1>(0x1f80_1050), b'k' as u32);
+
+        // Acknowledged
+        bus.write::<2>(0x1f80_105a, 0x0915);
+        assert_eq!(bus.read::<4>(0x1f80_1054) & STAT_IRQ, 0);
+    }
+}
@@ -0,0 +1,102 @@
+//! SPU ADPCM decoding. CD-XA audio uses the same filters, see cdrom::xa.
+
+/// Filter coefficients, in 1/64ths, applied to the previous and the one
+/// before previous sample
+const POS_TABLE: [i32; 5] = [0, 60, 115, 98, 122];
+const NEG_TABLE: [i32; 5] = [0, 0, -52, -55, -60];
+
+pub const BLOCK_SIZE: usize = 16;
+pub const SAMPLES_PER_BLOCK: usize = 28;
+
+/// Applies `filter` to a sample already scaled to 16 bits. `history` holds
+/// the last two decoded samples (most recent first) and is updated.
+pub fn filter_sample(value: i32, filter: usize, history: &mut [i16; 2]) -> i16 {
+    let filter = filter.min(4);
+    let prediction = history[0] as i32 * POS_TABLE[filter] + history[1] as i32 * NEG_TABLE[filter];
+    let sample = (value + ((prediction + 32) >> 6)).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+    history[1] = history[0];
+    history[0] = sample;
+    sample
+}
+
+/// The shift of a block header. 13 to 15 behave like 9.
+pub fn header_shift(header: u8) -> u32 {
+    match header & 0xf {
+        shift @ 0..=12 => shift as u32,
+        _ => 9,
+    }
+}
+
+/// Decodes a 16 byte block: a shift/filter header, the loop flags, then 28
+/// 4-bit samples, low nibble first.
+pub fn decode_block(block: &[u8; BLOCK_SIZE], history: &mut [i16; 2]) -> [i16; SAMPLES_PER_BLOCK] {
+    let shift = header_shift(block[0]);
+    let filter = ((block[0] >> 4) & 7) as usize;
+
+    let mut samples = [0; SAMPLES_PER_BLOCK];
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let nibble = (block[2 + i / 2] >> ((i & 1) * 4)) & 0xf;
+        let value = (((nibble as i16) << 12) as i32) >> shift;
+        *sample = filter_sample(value, filter, history);
+    }
+
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(header: u8, data: &[u8]) -> [u8; BLOCK_SIZE] {
+        let mut block = [0; BLOCK_SIZE];
+        block[0] = header;
+        block[2..2 + data.len()].copy_from_slice(data);
+        block
+    }
+
+    #[test]
+    fn unfiltered_samples_are_scaled_nibbles() {
+        let mut history = [0; 2];
+
+        let samples = decode_block(&block(0x0c, &[0x21, 0xf8]), &mut history);
+        assert_eq!(samples[..5], [1, 2, -8, -1, 0]);
+
+        let samples = decode_block(&block(0x00, &[0x87]), &mut history);
+        assert_eq!(samples[..3], [0x7000, -0x8000, 0]);
+
+        // Shifts past 12 act as 9
+        let samples = decode_block(&block(0x0d, &[0x01]), &mut history);
+        assert_eq!(samples[0], 8);
+    }
+
+    #[test]
+    fn filters_match_reference_vectors() {
+        // Filter 1 decays the previous sample by 60/64
+        let mut history = [64, 0];
+        let samples = decode_block(&block(0x1c, &[]), &mut history);
+        assert_eq!(samples[..3], [60, 56, 53]);
+        assert_eq!(history, [samples[27], samples[26]]);
+
+        // Filter 2: (115 * old - 52 * older + 32) >> 6
+        let mut history = [1000, 500];
+        let samples = decode_block(&block(0x2c, &[]), &mut history);
+        assert_eq!(samples[..2], [1391, 1687]);
+
+        // Filter 3: (98 * old - 55 * older + 32) >> 6, plus the sample
+        let mut history = [1000, 500];
+        let samples = decode_block(&block(0x3c, &[0x01]), &mut history);
+        assert_eq!(samples[..2], [1103, 830]);
+    }
+
+    #[test]
+    fn output_saturates() {
+        let mut history = [i16::MAX, i16::MIN];
+        let samples = decode_block(&block(0x40, &[0x07]), &mut history);
+        assert_eq!(samples[0], i16::MAX);
+
+        let mut history = [i16::MIN, i16::MAX];
+        let samples = decode_block(&block(0x40, &[0x08]), &mut history);
+        assert_eq!(samples[0], i16::MIN);
+    }
+}
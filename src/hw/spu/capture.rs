@@ -0,0 +1,98 @@
+//! The sound output kept frame by frame, so that tests can check it like
+//! the picture. Samples come from the SPU ticks, which follow the emulated
+//! cycles: a run gives the same samples for each frame however fast it
+//! goes, unlike the audio device, which drops samples when it's behind.
+
+use std::collections::VecDeque;
+
+pub struct AudioCapture {
+    /// Samples of the frame in progress
+    current: Vec<[i16; 2]>,
+    /// The last finished frames by number, oldest first
+    frames: VecDeque<(u64, Vec<[i16; 2]>)>,
+    /// Finished frames kept
+    limit: usize,
+}
+
+impl AudioCapture {
+    pub fn new(limit: usize) -> AudioCapture {
+        AudioCapture {
+            current: vec![],
+            frames: VecDeque::with_capacity(limit),
+            limit,
+        }
+    }
+
+    pub fn push(&mut self, sample: [i16; 2]) {
+        self.current.push(sample);
+    }
+
+    /// Files the samples since the last call under `frame`. Frames from
+    /// `frame` on were run before a state was loaded, they are forgotten.
+    pub fn end_frame(&mut self, frame: u64) {
+        while self.frames.back().is_some_and(|&(last, _)| last >= frame) {
+            self.frames.pop_back();
+        }
+        if self.frames.len() == self.limit {
+            self.frames.pop_front();
+        }
+
+        let samples = std::mem::take(&mut self.current);
+        if self.limit > 0 {
+            self.frames.push_back((frame, samples));
+        }
+    }
+
+    /// Drops the samples of the frame in progress, e.g. when loading a state
+    pub fn restart(&mut self) {
+        self.current.clear();
+    }
+
+    /// The samples of `frame`, None if it isn't finished or was forgotten
+    pub fn samples(&self, frame: u64) -> Option<&[[i16; 2]]> {
+        self.frames
+            .iter()
+            .find(|&&(number, _)| number == frame)
+            .map(|(_, samples)| samples.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_keep_their_samples() {
+        let mut capture = AudioCapture::new(2);
+        for frame in 0..3 {
+            capture.push([frame as i16; 2]);
+            capture.push([-1; 2]);
+            capture.end_frame(frame);
+        }
+        capture.push([5; 2]);
+
+        assert_eq!(capture.samples(0), None);
+        assert_eq!(capture.samples(1), Some(&[[1; 2], [-1; 2]][..]));
+        assert_eq!(capture.samples(2), Some(&[[2; 2], [-1; 2]][..]));
+        assert_eq!(capture.samples(3), None);
+    }
+
+    #[test]
+    fn rewinding_replaces_later_frames() {
+        let mut capture = AudioCapture::new(4);
+        for frame in 0..3 {
+            capture.push([frame as i16; 2]);
+            capture.end_frame(frame);
+        }
+
+        // State from frame 1 loaded
+        capture.push([7; 2]);
+        capture.restart();
+        capture.push([9; 2]);
+        capture.end_frame(1);
+
+        assert_eq!(capture.samples(0), Some(&[[0; 2]][..]));
+        assert_eq!(capture.samples(1), Some(&[[9; 2]][..]));
+        assert_eq!(capture.samples(2), None);
+    }
+}
@@ -0,0 +1,82 @@
+//! The CD audio input: XA-ADPCM sectors decoded by the CD-ROM, at 37.8 or
+//! 18.9 kHz, resampled to the 44.1 kHz of the SPU.
+
+use std::collections::VecDeque;
+
+use crate::hw::spu::SAMPLE_RATE;
+
+/// Samples waiting at most, about four sectors of mono sound at 37.8 kHz.
+/// Older ones are dropped if the CD-ROM gets ahead.
+const CD_BUFFER: usize = 16384;
+
+pub struct CdInput {
+    queue: VecDeque<[i16; 2]>,
+    /// Of the samples in `queue`
+    rate: u64,
+    /// Position between `previous` and `current`, in 1/`SAMPLE_RATE`
+    /// of an input sample
+    phase: u64,
+    previous: [i16; 2],
+    current: [i16; 2],
+}
+
+impl CdInput {
+    pub fn new() -> CdInput {
+        CdInput {
+            queue: VecDeque::with_capacity(CD_BUFFER),
+            rate: SAMPLE_RATE,
+            phase: 0,
+            previous: [0; 2],
+            current: [0; 2],
+        }
+    }
+
+    /// Queues `samples`, played at `rate` Hz
+    pub fn push(&mut self, samples: &[[i16; 2]], rate: u32) {
+        self.rate = rate as u64;
+        self.queue.extend(samples);
+        if self.queue.len() > CD_BUFFER {
+            self.queue.drain(..self.queue.len() - CD_BUFFER);
+        }
+    }
+
+    /// The next sample at 44.1 kHz, interpolated between the input ones.
+    /// Holds the last one once the input runs out.
+    pub fn next(&mut self) -> [i16; 2] {
+        self.phase += self.rate;
+        while self.phase >= SAMPLE_RATE {
+            self.phase -= SAMPLE_RATE;
+            self.previous = self.current;
+            if let Some(sample) = self.queue.pop_front() {
+                self.current = sample;
+            }
+        }
+
+        let weight = self.phase as i32;
+        [0, 1].map(|channel| {
+            let previous = self.previous[channel] as i32;
+            let current = self.current[channel] as i32;
+            (previous + (current - previous) * weight / SAMPLE_RATE as i32) as i16
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resamples_to_the_output_rate() {
+        let mut input = CdInput::new();
+        let samples: Vec<[i16; 2]> = (0..378).map(|n| [n, -n]).collect();
+        input.push(&samples, 37800);
+
+        let output: Vec<[i16; 2]> = (0..441).map(|_| input.next()).collect();
+        // A sample behind, to interpolate towards the next one
+        assert_eq!(output[440], [376, -376]);
+        assert!(output.windows(2).all(|pair| pair[0][0] <= pair[1][0]));
+
+        // Then holds the last sample
+        assert_eq!((0..2).map(|_| input.next()).last(), Some([377, -377]));
+    }
+}
@@ -1,25 +1,567 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+pub mod adpcm;
+mod capture;
+mod cd;
+#[cfg(feature = "audio")]
+mod output;
+mod speed;
+mod stretch;
+pub mod voice;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+
+use byteorder::{ByteOrder, LittleEndian};
+use crustationcpu::state::{Savestate, StateError, StateReader, StateWriter};
+#[cfg(feature = "audio")]
+use sdl2::AudioSubsystem;
+
+use crate::hw::spu::adpcm::BLOCK_SIZE;
+use crate::hw::spu::capture::AudioCapture;
+use crate::hw::spu::cd::CdInput;
+#[cfg(feature = "audio")]
+use crate::hw::spu::output::AudioOutput;
+use crate::hw::spu::speed::SpeedAdapter;
+use crate::hw::spu::voice::Voice;
+
+pub use crate::hw::spu::speed::SpeedAudio;
+
+const CPU_FREQ: u64 = 33_868_800;
+
+pub const SAMPLE_RATE: u64 = 44_100;
+
+/// CPU cycles between two output samples
+pub const SAMPLE_CYCLES: u64 = CPU_FREQ / SAMPLE_RATE;
+
+const RAM_SIZE: usize = 512 * 1024;
+const VOICES: usize = 24;
+
+/// Samples waiting to be played, about 90ms. Samples produced when the
+/// buffer is full (e.g. running faster than real time) are dropped.
+const OUTPUT_BUFFER: usize = 4096;
+
+/// SPUCNT bits
+const CNT_ENABLE: u16 = 1 << 15;
+const CNT_UNMUTE: u16 = 1 << 14;
+const CNT_IRQ_ENABLE: u16 = 1 << 6;
+const CNT_CD_AUDIO: u16 = 1 << 0;
 
 pub struct Spu {
+    /// Registers, read back as written unless they have a live value
     io_space: Vec<u8>,
+    ram: Vec<u8>,
+    voices: Vec<Voice>,
+    main_volume: [i16; 2],
+    /// Byte address of the next manual or DMA transfer
+    transfer_address: u32,
+    /// SPUSTAT.6, cleared by disabling the IRQ in SPUCNT
+    irq_flag: bool,
+    irq_pending: bool,
+    cd_input: CdInput,
+
+    output: Option<mpsc::SyncSender<[i16; 2]>>,
+    /// Follows the emulation speed, see `SpeedAudio`
+    speed: SpeedAdapter,
+    /// Samples to play for the last one produced
+    adapted: Vec<[i16; 2]>,
+    /// Every sample, by frame, see `capture_output`
+    capture: Option<AudioCapture>,
+    stats: Arc<AudioStats>,
+    /// Plays while it's alive
+    #[cfg(feature = "audio")]
+    #[allow(dead_code)]
+    device: Option<AudioOutput>,
+}
+
+/// Health of the output buffer, shared by the SPU and the audio thread
+#[derive(Debug, Default)]
+pub struct AudioStats {
+    /// Samples waiting to be played
+    pub queued: AtomicU64,
+    /// Samples dropped because the buffer was full
+    pub dropped: AtomicU64,
+    /// Samples the device asked for while the buffer was empty
+    pub underruns: AtomicU64,
 }
 
 impl Spu {
     pub fn new() -> Spu {
         Spu {
             io_space: vec![0; 1024],
+            ram: vec![0; RAM_SIZE],
+            voices: (0..VOICES).map(|_| Voice::new()).collect(),
+            main_volume: [0; 2],
+            transfer_address: 0,
+            irq_flag: false,
+            irq_pending: false,
+            cd_input: CdInput::new(),
+            output: None,
+            speed: SpeedAdapter::new(),
+            adapted: vec![],
+            capture: None,
+            stats: Arc::new(AudioStats::default()),
+            #[cfg(feature = "audio")]
+            device: None,
+        }
+    }
+
+    /// Stereo samples at 44.1 kHz are sent to the returned channel, until it
+    /// is dropped
+    pub fn connect_output(&mut self) -> mpsc::Receiver<[i16; 2]> {
+        let (tx, rx) = mpsc::sync_channel(OUTPUT_BUFFER);
+        self.output = Some(tx);
+        rx
+    }
+
+    pub fn set_speed_audio(&mut self, speed_audio: SpeedAudio) {
+        self.speed.set_policy(speed_audio);
+    }
+
+    /// Follows the emulation speed, in percent, None when unlimited
+    pub fn set_output_speed(&mut self, speed: Option<u32>) {
+        self.speed.set_speed(speed);
+    }
+
+    /// Keeps every sample of the last `frames` frames, for
+    /// `captured_samples`
+    pub fn capture_output(&mut self, frames: usize) {
+        self.capture = Some(AudioCapture::new(frames));
+    }
+
+    /// Called at VBlank, with the number of the frame that ends
+    pub fn end_frame(&mut self, frame: u64) {
+        if let Some(capture) = &mut self.capture {
+            capture.end_frame(frame);
+        }
+    }
+
+    /// The samples produced during `frame`, if it's still kept
+    pub fn captured_samples(&self, frame: u64) -> Option<&[[i16; 2]]> {
+        self.capture.as_ref()?.samples(frame)
+    }
+
+    pub fn audio_stats(&self) -> Arc<AudioStats> {
+        self.stats.clone()
+    }
+
+    /// Plays the output on the default audio device
+    #[cfg(feature = "audio")]
+    pub fn open_output(&mut self, audio: &AudioSubsystem) {
+        let samples = self.connect_output();
+        match AudioOutput::open(audio, samples, self.stats.clone()) {
+            Ok(device) => self.device = Some(device),
+            Err(err) => println!("[SPU] Could not open the audio device: {}", err),
         }
     }
 
+    /// Stops the audio thread. Samples produced afterwards are discarded.
+    pub fn close_output(&mut self) {
+        #[cfg(feature = "audio")]
+        {
+            self.device = None;
+        }
+        self.output = None;
+    }
+
     pub fn write<const S: u32>(&mut self, addr: u32, value: u32) {
-        let addr = addr as usize;
-        let mut bytes = &mut self.io_space[addr..addr + 4];
-        bytes.write_u32::<LittleEndian>(value).unwrap();
+        match S {
+            4 => {
+                self.write16(addr, value as u16);
+                self.write16(addr + 2, (value >> 16) as u16);
+            }
+            2 => self.write16(addr, value as u16),
+            _ => {
+                let shift = (addr & 1) * 8;
+                let old = self.read16(addr & !1);
+                let value = (old & !(0xff << shift)) | ((value as u16 & 0xff) << shift);
+                self.write16(addr & !1, value);
+            }
+        }
     }
 
     pub fn read<const S: u32>(&self, addr: u32) -> u32 {
-        let addr = addr as usize;
-        let mut bytes = &self.io_space[addr..addr + 4];
-        bytes.read_u32::<LittleEndian>().unwrap()
+        match S {
+            4 => self.read16(addr) as u32 | (self.read16(addr + 2) as u32) << 16,
+            2 => self.read16(addr) as u32,
+            _ => (self.read16(addr & !1) >> ((addr & 1) * 8)) as u32 & 0xff,
+        }
+    }
+
+    fn reg(&self, addr: u32) -> u16 {
+        LittleEndian::read_u16(&self.io_space[addr as usize..])
+    }
+
+    fn control(&self) -> u16 {
+        self.reg(0x1aa)
+    }
+
+    fn read16(&self, addr: u32) -> u16 {
+        match addr {
+            0x000..=0x17f => {
+                let voice = &self.voices[addr as usize / 16];
+                match addr & 0xf {
+                    0x0 => voice.volume[0],
+                    0x2 => voice.volume[1],
+                    0x4 => voice.pitch,
+                    0x6 => voice.start,
+                    0x8 => voice.adsr as u16,
+                    0xa => (voice.adsr >> 16) as u16,
+                    0xc => voice.envelope.level as u16,
+                    _ => voice.repeat,
+                }
+            }
+            0x19c | 0x19e => {
+                let first = (addr as usize - 0x19c) * 8;
+                let ended = self.voices.iter().skip(first).take(16);
+                ended.enumerate().fold(0, |endx, (n, voice)| endx | (voice.ended as u16) << n)
+            }
+            0x1ae => {
+                // SPUSTAT: the mode bits of SPUCNT, then the IRQ flag and
+                // the DMA requests of the transfer mode
+                let control = self.control();
+                let dma = match (control >> 4) & 3 {
+                    2 => 0x180,
+                    3 => 0x280,
+                    _ => 0,
+                };
+                control & 0x3f | (self.irq_flag as u16) << 6 | dma
+            }
+            0x1b8 => self.main_volume[0] as u16,
+            0x1ba => self.main_volume[1] as u16,
+            0x200..=0x25f => {
+                let voice = &self.voices[(addr as usize - 0x200) / 4];
+                voice.current_volume[(addr as usize / 2) & 1] as u16
+            }
+            _ => self.reg(addr),
+        }
+    }
+
+    fn write16(&mut self, addr: u32, value: u16) {
+        LittleEndian::write_u16(&mut self.io_space[addr as usize..], value);
+
+        match addr {
+            0x000..=0x17f => {
+                let voice = &mut self.voices[addr as usize / 16];
+                match addr & 0xf {
+                    0x0 => voice.set_volume(0, value),
+                    0x2 => voice.set_volume(1, value),
+                    0x4 => voice.pitch = value,
+                    0x6 => voice.start = value,
+                    0x8 => voice.adsr = (voice.adsr & 0xffff_0000) | value as u32,
+                    0xa => voice.adsr = (voice.adsr & 0xffff) | (value as u32) << 16,
+                    0xc => voice.envelope.level = value as i16,
+                    _ => voice.repeat = value,
+                }
+            }
+            0x180 | 0x182 => {
+                // Sweeps keep the current volume
+                if value & 0x8000 == 0 {
+                    self.main_volume[(addr as usize - 0x180) / 2] = (value << 1) as i16;
+                }
+            }
+            0x188 | 0x18a => {
+                let first = (addr as usize - 0x188) * 8;
+                for n in (0..16).filter(|n| value & (1 << n) != 0) {
+                    if let Some(voice) = self.voices.get_mut(first + n) {
+                        voice.key_on(&self.ram);
+                        let address = voice.address();
+                        self.check_irq(address, BLOCK_SIZE as u32);
+                    }
+                }
+            }
+            0x18c | 0x18e => {
+                let first = (addr as usize - 0x18c) * 8;
+                for n in (0..16).filter(|n| value & (1 << n) != 0) {
+                    if let Some(voice) = self.voices.get_mut(first + n) {
+                        voice.key_off();
+                    }
+                }
+            }
+            0x1a6 => self.transfer_address = value as u32 * 8,
+            0x1a8 => self.write_fifo(value),
+            0x1aa if value & CNT_IRQ_ENABLE == 0 => self.irq_flag = false,
+            _ => {}
+        }
+    }
+
+    /// Transfers are written to RAM right away, there's no FIFO to fill
+    fn write_fifo(&mut self, value: u16) {
+        let address = self.transfer_address;
+        LittleEndian::write_u16(&mut self.ram[address as usize..], value);
+        self.check_irq(address, 2);
+        self.transfer_address = (address + 2) % RAM_SIZE as u32;
+    }
+
+    fn read_fifo(&mut self) -> u16 {
+        let address = self.transfer_address;
+        let value = LittleEndian::read_u16(&self.ram[address as usize..]);
+        self.check_irq(address, 2);
+        self.transfer_address = (address + 2) % RAM_SIZE as u32;
+        value
+    }
+
+    pub fn dma_write(&mut self, words: &[u32]) {
+        for word in words {
+            self.write_fifo(*word as u16);
+            self.write_fifo((*word >> 16) as u16);
+        }
+    }
+
+    pub fn dma_read(&mut self, words: &mut [u32]) {
+        for word in words.iter_mut() {
+            *word = self.read_fifo() as u32 | (self.read_fifo() as u32) << 16;
+        }
+    }
+
+    /// Raises the IRQ when the `len` bytes at `address` contain the IRQ
+    /// address
+    fn check_irq(&mut self, address: u32, len: u32) {
+        let irq_address = self.reg(0x1a4) as u32 * 8;
+        let hit = (address..address + len).contains(&irq_address);
+
+        if hit && self.control() & CNT_IRQ_ENABLE != 0 && !self.irq_flag {
+            self.irq_flag = true;
+            self.irq_pending = true;
+        }
+    }
+
+    /// Returns true (once) if the SPU raised an interrupt
+    pub fn take_irq(&mut self) -> bool {
+        std::mem::replace(&mut self.irq_pending, false)
+    }
+
+    /// Queues sound from the CD-ROM, `rate` samples per second
+    pub fn push_cd_audio(&mut self, samples: &[[i16; 2]], rate: u32) {
+        self.cd_input.push(samples, rate);
+    }
+
+    /// Produces the next stereo sample. Called at 44.1 kHz.
+    pub fn tick(&mut self) {
+        let control = self.control();
+        let mut mix = [0i32; 2];
+
+        if control & CNT_ENABLE != 0 {
+            for n in 0..VOICES {
+                let voice = &mut self.voices[n];
+                let fetched = voice.tick(&self.ram);
+                for (channel, mix) in mix.iter_mut().enumerate() {
+                    *mix += (voice.output() as i32 * voice.current_volume[channel] as i32) >> 15;
+                }
+
+                if fetched {
+                    let address = voice.address();
+                    self.check_irq(address, BLOCK_SIZE as u32);
+                }
+            }
+        }
+
+        // Consumed while disabled too, so that it keeps its pace
+        let cd = self.cd_input.next();
+        let cd_volume = [self.reg(0x1b0) as i16, self.reg(0x1b2) as i16];
+
+        let mut frame = [0; 2];
+        if control & CNT_UNMUTE != 0 {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut value = (mix[channel] * self.main_volume[channel] as i32) >> 15;
+                if control & CNT_CD_AUDIO != 0 {
+                    value += (cd[channel] as i32 * cd_volume[channel] as i32) >> 15;
+                }
+                *sample = value.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+            }
+        }
+
+        if let Some(capture) = &mut self.capture {
+            capture.push(frame);
+        }
+        if self.output.is_none() {
+            return;
+        }
+
+        let queued = self.stats.queued.load(Ordering::Relaxed);
+        let mut adapted = std::mem::take(&mut self.adapted);
+        self.speed.push(frame, queued, &mut adapted);
+        for &frame in &adapted {
+            self.send(frame);
+        }
+        adapted.clear();
+        self.adapted = adapted;
+    }
+
+    /// Queues `frame` for the audio device, dropping it if the buffer is full
+    fn send(&mut self, frame: [i16; 2]) {
+        if let Some(output) = &self.output {
+            // Counted before the audio thread can take it
+            self.stats.queued.fetch_add(1, Ordering::Relaxed);
+            if let Err(err) = output.try_send(frame) {
+                self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                match err {
+                    mpsc::TrySendError::Full(_) => {
+                        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    mpsc::TrySendError::Disconnected(_) => self.output = None,
+                }
+            }
+        }
+    }
+}
+
+impl Savestate for Spu {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"SPU ");
+        state.write_bytes(&self.io_space);
+        state.write_bytes(&self.ram);
+        for voice in &self.voices {
+            voice.save_state(state);
+        }
+        state.write_i16(self.main_volume[0]);
+        state.write_i16(self.main_volume[1]);
+        state.write_u32(self.transfer_address);
+        state.write_bool(self.irq_flag);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"SPU ", "SPU")?;
+        state.read_bytes(&mut self.io_space)?;
+        state.read_bytes(&mut self.ram)?;
+        for voice in self.voices.iter_mut() {
+            voice.load_state(state)?;
+        }
+        self.main_volume = [state.read_i16()?, state.read_i16()?];
+        self.transfer_address = (state.read_u32()? % RAM_SIZE as u32) & !1;
+        self.irq_flag = state.read_bool()?;
+        self.cd_input = CdInput::new();
+        if let Some(capture) = &mut self.capture {
+            capture.restart();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(spu: &mut Spu, address: u32, data: &[u8]) {
+        spu.write::<2>(0x1a6, address / 8);
+        for pair in data.chunks(2) {
+            spu.write::<2>(0x1a8, LittleEndian::read_u16(pair) as u32);
+        }
+    }
+
+    #[test]
+    fn manual_transfers_fill_ram() {
+        let mut spu = Spu::new();
+        spu.write::<2>(0x1aa, 0x8010);
+        assert_eq!(spu.read::<2>(0x1ae), 0x10);
+
+        upload(&mut spu, 0x1000, &[1, 2, 3, 4]);
+        assert_eq!(spu.ram[0x1000..0x1004], [1, 2, 3, 4]);
+        assert_eq!(spu.transfer_address, 0x1004);
+
+        // DMA write mode requests transfers
+        spu.write::<2>(0x1aa, 0x8020);
+        assert_eq!(spu.read::<2>(0x1ae), 0x1a0);
+        spu.write::<2>(0x1a6, 0x200);
+        spu.dma_write(&[0x0807_0605]);
+        assert_eq!(spu.ram[0x1000..0x1004], [5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn transfers_hit_the_irq_address() {
+        let mut spu = Spu::new();
+        spu.write::<2>(0x1a4, 0x0201);
+        spu.write::<2>(0x1aa, 0x8050);
+
+        upload(&mut spu, 0x1000, &[0; 8]);
+        assert!(!spu.take_irq());
+        upload(&mut spu, 0x1008, &[0; 2]);
+        assert!(spu.take_irq());
+        assert!(!spu.take_irq());
+        assert_eq!(spu.read::<2>(0x1ae) & 0x40, 0x40);
+
+        // Acknowledged by disabling it
+        spu.write::<2>(0x1aa, 0x8010);
+        assert_eq!(spu.read::<2>(0x1ae) & 0x40, 0);
+    }
+
+    #[test]
+    fn key_on_plays_through_the_output() {
+        let mut spu = Spu::new();
+        let samples = spu.connect_output();
+
+        // A looping block of 0x1000 samples at 0x1000
+        let mut block = [0x11; BLOCK_SIZE];
+        block[0] = 0x00;
+        block[1] = 0x07;
+        upload(&mut spu, 0x1000, &block);
+
+        spu.write::<2>(0x180, 0x3fff);
+        spu.write::<2>(0x182, 0x3fff);
+        spu.write::<4>(0x30, 0x1fff_3fff);
+        spu.write::<2>(0x34, 0x1000);
+        spu.write::<2>(0x36, 0x200);
+        spu.write::<4>(0x38, 0x0000_000f);
+        spu.write::<2>(0x1aa, 0xc000);
+        spu.write::<2>(0x188, 0x8);
+
+        for _ in 0..30 {
+            spu.tick();
+        }
+        let frame = samples.try_iter().last().unwrap();
+        assert!(frame[0] > 0xf00 && frame[0] < 0x1000);
+        assert!(frame[1] > 0x700 && frame[1] < 0x800);
+        assert_eq!(spu.read::<2>(0x19c), 0x8);
+        assert_eq!(spu.read::<2>(0x20e), 0x3ffe);
+
+        // Released down to silence
+        spu.write::<2>(0x18c, 0x8);
+        for _ in 0..10 {
+            spu.tick();
+        }
+        assert_eq!(samples.try_iter().last(), Some([0, 0]));
+    }
+
+    #[test]
+    fn cd_audio_plays_at_its_volume() {
+        let mut spu = Spu::new();
+        let samples = spu.connect_output();
+        spu.write::<2>(0x1b0, 0x4000);
+        spu.write::<2>(0x1b2, 0x4000);
+        spu.push_cd_audio(&[[1000, -1000]; 8], 44100);
+
+        spu.write::<2>(0x1aa, 0xc001);
+        for _ in 0..4 {
+            spu.tick();
+        }
+        assert_eq!(samples.try_iter().last(), Some([500, -500]));
+
+        spu.write::<2>(0x1aa, 0xc000);
+        spu.tick();
+        assert_eq!(samples.try_iter().last(), Some([0, 0]));
+    }
+
+    #[test]
+    fn stretched_output_follows_the_speed() {
+        let mut spu = Spu::new();
+        let samples = spu.connect_output();
+        spu.set_output_speed(Some(200));
+        for _ in 0..2048 {
+            spu.tick();
+        }
+        assert_eq!(samples.try_iter().count(), 2048);
+
+        // Four windows in, each played in half the time
+        spu.set_speed_audio(SpeedAudio::Stretch);
+        for _ in 0..4096 {
+            spu.tick();
+        }
+        assert_eq!(samples.try_iter().count(), 2048);
+
+        // Unlimited, as long as a chunk is waiting at most
+        spu.set_output_speed(None);
+        spu.tick();
+        assert_eq!(samples.try_iter().count(), 0);
+        spu.stats.queued.store(0, Ordering::Relaxed);
+        spu.tick();
+        assert_eq!(samples.try_iter().count(), 1);
     }
 }
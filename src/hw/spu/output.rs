@@ -0,0 +1,64 @@
+//! Plays the SPU output through SDL
+
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+
+use crate::hw::spu::{AudioStats, SAMPLE_RATE};
+
+struct Stream {
+    samples: Receiver<[i16; 2]>,
+    last: [i16; 2],
+    stats: Arc<AudioStats>,
+}
+
+impl AudioCallback for Stream {
+    type Channel = i16;
+
+    /// Runs on the SDL audio thread. When the emulator falls behind, the
+    /// last sample is held rather than dropping to silence, which clicks.
+    fn callback(&mut self, out: &mut [i16]) {
+        for frame in out.chunks_exact_mut(2) {
+            match self.samples.try_recv() {
+                Ok(sample) => {
+                    self.last = sample;
+                    self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                }
+                Err(_) => {
+                    self.stats.underruns.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            frame.copy_from_slice(&self.last);
+        }
+    }
+}
+
+pub struct AudioOutput {
+    _device: AudioDevice<Stream>,
+}
+
+impl AudioOutput {
+    pub fn open(
+        audio: &AudioSubsystem,
+        samples: Receiver<[i16; 2]>,
+        stats: Arc<AudioStats>,
+    ) -> Result<AudioOutput, String> {
+        let spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(2),
+            samples: Some(1024),
+        };
+
+        let device = audio.open_playback(None, &spec, |_| Stream {
+            samples,
+            last: [0; 2],
+            stats,
+        })?;
+        device.resume();
+
+        Ok(AudioOutput { _device: device })
+    }
+}
@@ -0,0 +1,173 @@
+//! The sound output away from the speed of the hardware, e.g. while fast
+//! forwarding: the SPU then produces samples faster (or slower) than the
+//! device plays them, and they must not pile up in the output buffer.
+
+use crate::hw::spu::stretch::TimeStretch;
+
+/// Samples played or skipped at once by `SpeedAudio::Skip`, about 23ms.
+/// Unlimited speed, which has no rate to follow, queues at most that.
+const CHUNK: u64 = 1024;
+
+/// What the sound output does when emulation doesn't run at the speed of
+/// the hardware
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SpeedAudio {
+    /// Played as produced: too fast, samples are dropped once the output
+    /// buffer is full, too slow, the device runs out of them
+    Drop,
+    /// Silence, at the rate of the device
+    Mute,
+    /// Chunks of sound at their pitch, with the ones in between left out.
+    /// Slow motion plays with gaps.
+    Skip,
+    /// Stretched or compressed to the speed, keeping the pitch
+    Stretch,
+}
+
+impl SpeedAudio {
+    pub fn from_name(name: &str) -> Option<SpeedAudio> {
+        match name {
+            "drop" => Some(SpeedAudio::Drop),
+            "mute" => Some(SpeedAudio::Mute),
+            "skip" => Some(SpeedAudio::Skip),
+            "stretch" => Some(SpeedAudio::Stretch),
+            _ => None,
+        }
+    }
+}
+
+/// Turns the samples produced at the emulation speed into the samples to
+/// play
+pub struct SpeedAdapter {
+    policy: SpeedAudio,
+    /// In percent, None when unlimited
+    speed: Option<u32>,
+    /// Set while the sound is stretched to the speed
+    stretch: Option<TimeStretch>,
+    /// Input samples since the last output sample (`Mute`) or since the
+    /// start of the chunk (`Skip`), in hundredths
+    phase: u64,
+}
+
+impl SpeedAdapter {
+    pub fn new() -> SpeedAdapter {
+        SpeedAdapter {
+            policy: SpeedAudio::Drop,
+            speed: Some(100),
+            stretch: None,
+            phase: 0,
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: SpeedAudio) {
+        self.policy = policy;
+        self.restart();
+    }
+
+    pub fn set_speed(&mut self, speed: Option<u32>) {
+        self.speed = speed;
+        self.restart();
+    }
+
+    fn restart(&mut self) {
+        self.phase = 0;
+        let ratio = match (self.policy, self.speed) {
+            (SpeedAudio::Stretch, Some(speed)) if speed != 100 => speed as f64 / 100.0,
+            _ => {
+                self.stretch = None;
+                return;
+            }
+        };
+        match &mut self.stretch {
+            Some(stretch) => stretch.set_ratio(ratio),
+            None => self.stretch = Some(TimeStretch::new(ratio)),
+        }
+    }
+
+    /// Adds the samples to play for `sample` to `out`, with `queued`
+    /// samples waiting for the device
+    pub fn push(&mut self, sample: [i16; 2], queued: u64, out: &mut Vec<[i16; 2]>) {
+        let speed = match (self.policy, self.speed) {
+            (SpeedAudio::Drop, _) | (_, Some(100)) => return out.push(sample),
+            (_, None) => {
+                if queued < CHUNK {
+                    let muted = self.policy == SpeedAudio::Mute;
+                    out.push(if muted { [0; 2] } else { sample });
+                }
+                return;
+            }
+            (_, Some(speed)) => speed as u64,
+        };
+
+        match self.policy {
+            SpeedAudio::Mute => {
+                self.phase += 100;
+                while self.phase >= speed {
+                    self.phase -= speed;
+                    out.push([0; 2]);
+                }
+            }
+            SpeedAudio::Skip => {
+                if self.phase < CHUNK * 100 {
+                    out.push(sample);
+                }
+                self.phase += 100;
+                if self.phase >= CHUNK * speed.max(100) {
+                    self.phase = 0;
+                }
+            }
+            SpeedAudio::Stretch => {
+                if let Some(stretch) = &mut self.stretch {
+                    stretch.push(sample, out);
+                }
+            }
+            SpeedAudio::Drop => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn played(
+        policy: SpeedAudio,
+        speed: Option<u32>,
+        samples: u64,
+        queued: u64,
+    ) -> Vec<[i16; 2]> {
+        let mut adapter = SpeedAdapter::new();
+        adapter.set_policy(policy);
+        adapter.set_speed(speed);
+        let mut out = vec![];
+        for _ in 0..samples {
+            adapter.push([7, 7], queued, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn policies_keep_up_with_the_speed() {
+        assert_eq!(played(SpeedAudio::Drop, Some(300), 3000, 0).len(), 3000);
+        assert_eq!(played(SpeedAudio::Skip, Some(100), 3000, 0).len(), 3000);
+
+        let muted = played(SpeedAudio::Mute, Some(300), 3000, 0);
+        assert_eq!(muted.len(), 1000);
+        assert!(muted.iter().all(|&sample| sample == [0; 2]));
+        assert_eq!(played(SpeedAudio::Mute, Some(50), 3000, 0).len(), 6000);
+
+        // A chunk out of three
+        let skipped = played(SpeedAudio::Skip, Some(300), 3 * CHUNK * 4, 0);
+        assert_eq!(skipped.len() as u64, CHUNK * 4);
+        assert!(skipped.iter().all(|&sample| sample == [7; 2]));
+        assert_eq!(played(SpeedAudio::Skip, Some(50), 3000, 0).len(), 3000);
+    }
+
+    #[test]
+    fn unlimited_speed_queues_a_chunk_at_most() {
+        assert_eq!(played(SpeedAudio::Skip, None, 10, CHUNK - 1).len(), 10);
+        assert!(played(SpeedAudio::Stretch, None, 10, CHUNK).is_empty());
+        assert_eq!(played(SpeedAudio::Mute, None, 1, 0), vec![[0; 2]]);
+        assert_eq!(played(SpeedAudio::Drop, None, 10, CHUNK).len(), 10);
+    }
+}
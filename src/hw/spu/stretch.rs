@@ -0,0 +1,101 @@
+//! Changes the length of the sound without changing its pitch, so that it
+//! keeps up with emulation running at another speed than the hardware.
+//!
+//! Plain overlap-add: windows of the input are taken further apart (or
+//! closer) than they are laid down in the output, and cross-faded. Notes
+//! keep their pitch, sharp attacks get a little smeared.
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+/// Samples in a window, about 23ms
+const WINDOW: usize = 1024;
+/// Output samples between two windows, half of one
+const HOP: usize = WINDOW / 2;
+
+pub struct TimeStretch {
+    /// Input samples consumed per output sample: 2.0 at 200% speed
+    ratio: f64,
+    input: VecDeque<[f32; 2]>,
+    /// Where the next window starts in `input`, in samples
+    position: f64,
+    /// Second half of the last window, added to the first half of the next
+    overlap: Vec<[f32; 2]>,
+    /// Hann window, whose halves sum to one when they overlap
+    weights: Vec<f32>,
+}
+
+impl TimeStretch {
+    pub fn new(ratio: f64) -> TimeStretch {
+        TimeStretch {
+            ratio,
+            input: VecDeque::with_capacity(2 * WINDOW),
+            position: 0.0,
+            overlap: vec![[0.0; 2]; HOP],
+            weights: (0..WINDOW)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / WINDOW as f32).cos())
+                .collect(),
+        }
+    }
+
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.ratio = ratio;
+    }
+
+    /// Adds the next input sample, and the output samples it completes to
+    /// `out`
+    pub fn push(&mut self, sample: [i16; 2], out: &mut Vec<[i16; 2]>) {
+        self.input.push_back(sample.map(f32::from));
+
+        while self.input.len() >= self.position as usize + WINDOW {
+            let start = self.position as usize;
+            for i in 0..WINDOW {
+                let weight = self.weights[i];
+                let value = self.input[start + i].map(|channel| channel * weight);
+                if i < HOP {
+                    let [left, right] = self.overlap[i];
+                    out.push([clamp(left + value[0]), clamp(right + value[1])]);
+                } else {
+                    self.overlap[i - HOP] = value;
+                }
+            }
+
+            self.position += HOP as f64 * self.ratio;
+            let consumed = (self.position as usize).min(self.input.len());
+            self.input.drain(..consumed);
+            self.position -= consumed as f64;
+        }
+    }
+}
+
+fn clamp(value: f32) -> i16 {
+    value.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stretch(ratio: f64, samples: usize) -> Vec<[i16; 2]> {
+        let mut stretch = TimeStretch::new(ratio);
+        let mut out = vec![];
+        for i in 0..samples {
+            stretch.push([1000, -(i as i16 & 0xff)], &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn the_length_follows_the_ratio() {
+        // Less the window still being filled
+        assert_eq!(stretch(2.0, 100 * HOP).len(), 50 * HOP);
+        assert_eq!(stretch(0.5, 100 * HOP).len(), 197 * HOP);
+    }
+
+    #[test]
+    fn overlapping_windows_keep_the_level() {
+        let out = stretch(1.5, 20 * HOP);
+        // The first half window fades in
+        assert!(out[HOP..].iter().all(|&[left, _]| left == 1000));
+    }
+}
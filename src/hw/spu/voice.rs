@@ -0,0 +1,453 @@
+//! The 24 SPU voices: ADPCM playback from sound RAM at a given pitch, shaped
+//! by an ADSR envelope.
+//!
+//! Samples are linearly interpolated (the hardware uses a 4-point gaussian
+//! table). Noise, pitch modulation and volume sweeps are not emulated.
+
+use crustationcpu::state::{StateError, StateReader, StateWriter};
+
+use crate::hw::spu::adpcm::{decode_block, BLOCK_SIZE, SAMPLES_PER_BLOCK};
+
+/// Flags in the second byte of a block
+const LOOP_END: u8 = 1;
+const LOOP_REPEAT: u8 = 2;
+const LOOP_START: u8 = 4;
+
+/// The pitch counter has 12 fractional bits
+const PITCH_ONE: u32 = 0x1000;
+const BLOCK_END: u32 = SAMPLES_PER_BLOCK as u32 * PITCH_ONE;
+
+const MAX_LEVEL: i16 = 0x7fff;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Phase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    /// Released down to zero
+    Off,
+}
+
+impl Phase {
+    const ALL: [Phase; 5] = [
+        Phase::Attack,
+        Phase::Decay,
+        Phase::Sustain,
+        Phase::Release,
+        Phase::Off,
+    ];
+}
+
+/// How the level of a phase changes: every `1 << (shift - 11)` ticks by
+/// `step << (11 - shift)`. Exponential increases are 4 times slower above
+/// 0x6000, exponential decreases are proportional to the level.
+struct Rate {
+    exponential: bool,
+    decrease: bool,
+    shift: u32,
+    step: i32,
+}
+
+pub struct Envelope {
+    pub phase: Phase,
+    pub level: i16,
+    /// Ticks left before the next step
+    wait: u32,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            phase: Phase::Off,
+            level: 0,
+            wait: 0,
+        }
+    }
+
+    fn start(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.wait = 0;
+    }
+
+    /// The rate of the current phase, from the ADSR register
+    fn rate(&self, adsr: u32) -> Rate {
+        match self.phase {
+            Phase::Attack => Rate {
+                exponential: adsr & 0x8000 != 0,
+                decrease: false,
+                shift: (adsr >> 10) & 0x1f,
+                step: 7 - ((adsr >> 8) & 3) as i32,
+            },
+            Phase::Decay => Rate {
+                exponential: true,
+                decrease: true,
+                shift: (adsr >> 4) & 0xf,
+                step: -8,
+            },
+            Phase::Sustain => {
+                let decrease = adsr & (1 << 30) != 0;
+                let step = ((adsr >> 22) & 3) as i32;
+                Rate {
+                    exponential: adsr & (1 << 31) != 0,
+                    decrease,
+                    shift: (adsr >> 24) & 0x1f,
+                    step: if decrease { -8 + step } else { 7 - step },
+                }
+            }
+            Phase::Release | Phase::Off => Rate {
+                exponential: adsr & (1 << 21) != 0,
+                decrease: true,
+                shift: (adsr >> 16) & 0x1f,
+                step: -8,
+            },
+        }
+    }
+
+    pub fn tick(&mut self, adsr: u32) {
+        if self.phase == Phase::Off {
+            return;
+        }
+
+        if self.wait > 0 {
+            self.wait -= 1;
+        } else {
+            let rate = self.rate(adsr);
+            let mut wait = 1 << rate.shift.saturating_sub(11);
+            let mut step = rate.step << 11u32.saturating_sub(rate.shift);
+
+            if rate.exponential && !rate.decrease && self.level > 0x6000 {
+                wait *= 4;
+            }
+            if rate.exponential && rate.decrease {
+                step = (step * self.level as i32) >> 15;
+            }
+
+            self.level = (self.level as i32 + step).clamp(0, MAX_LEVEL as i32) as i16;
+            self.wait = wait - 1;
+        }
+
+        let sustain_level = (((adsr & 0xf) + 1) * 0x800).min(MAX_LEVEL as u32) as i16;
+        match self.phase {
+            Phase::Attack if self.level == MAX_LEVEL => self.start(Phase::Decay),
+            Phase::Decay if self.level <= sustain_level => self.start(Phase::Sustain),
+            Phase::Release if self.level == 0 => self.start(Phase::Off),
+            _ => {}
+        }
+    }
+}
+
+pub struct Voice {
+    /// Volume registers, left and right
+    pub volume: [u16; 2],
+    /// Volumes in use, read back at 0x1f801e00
+    pub current_volume: [i16; 2],
+    /// Sample rate, 0x1000 is 44.1 kHz
+    pub pitch: u16,
+    /// Addresses in units of 8 bytes
+    pub start: u16,
+    pub repeat: u16,
+    pub adsr: u32,
+    pub envelope: Envelope,
+    /// Set when a block with the loop end flag was played, cleared at key on
+    pub ended: bool,
+
+    /// Byte address of the current block
+    address: u32,
+    counter: u32,
+    flags: u8,
+    samples: [i16; SAMPLES_PER_BLOCK],
+    /// Last sample of the previous block, for interpolation
+    previous: i16,
+    history: [i16; 2],
+    output: i16,
+}
+
+impl Voice {
+    pub fn new() -> Voice {
+        Voice {
+            volume: [0; 2],
+            current_volume: [0; 2],
+            pitch: 0,
+            start: 0,
+            repeat: 0,
+            adsr: 0,
+            envelope: Envelope::new(),
+            ended: false,
+            address: 0,
+            counter: 0,
+            flags: 0,
+            samples: [0; SAMPLES_PER_BLOCK],
+            previous: 0,
+            history: [0; 2],
+            output: 0,
+        }
+    }
+
+    /// Byte address of the block being played
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+
+    /// The last sample, after the envelope
+    pub fn output(&self) -> i16 {
+        self.output
+    }
+
+    /// Sets a volume register. Sweeps keep the current volume.
+    pub fn set_volume(&mut self, channel: usize, value: u16) {
+        self.volume[channel] = value;
+        if value & 0x8000 == 0 {
+            self.current_volume[channel] = (value << 1) as i16;
+        }
+    }
+
+    /// Starts playing from the start address, loading its first block
+    pub fn key_on(&mut self, ram: &[u8]) {
+        self.address = self.start as u32 * 8;
+        self.counter = 0;
+        self.previous = 0;
+        self.history = [0; 2];
+        self.ended = false;
+        self.envelope.level = 0;
+        self.envelope.start(Phase::Attack);
+        self.load_block(ram);
+    }
+
+    pub fn key_off(&mut self) {
+        if self.envelope.phase != Phase::Off {
+            self.envelope.start(Phase::Release);
+        }
+    }
+
+    fn load_block(&mut self, ram: &[u8]) {
+        let address = self.address as usize & (ram.len() - 1) & !(BLOCK_SIZE - 1);
+        let block: &[u8; BLOCK_SIZE] = ram[address..address + BLOCK_SIZE].try_into().unwrap();
+
+        self.flags = block[1];
+        if self.flags & LOOP_START != 0 {
+            self.repeat = (address / 8) as u16;
+        }
+        self.samples = decode_block(block, &mut self.history);
+    }
+
+    /// Produces one sample, then moves forward by the pitch. Returns true
+    /// when a new block was loaded from `ram`.
+    pub fn tick(&mut self, ram: &[u8]) -> bool {
+        let index = (self.counter / PITCH_ONE) as usize;
+        let fraction = (self.counter % PITCH_ONE) as i32;
+        let older = match index {
+            0 => self.previous,
+            _ => self.samples[index - 1],
+        } as i32;
+        let sample = older + (((self.samples[index] as i32 - older) * fraction) >> 12);
+
+        self.envelope.tick(self.adsr);
+        self.output = ((sample * self.envelope.level as i32) >> 15) as i16;
+
+        self.counter += self.pitch.min(0x4000) as u32;
+        if self.counter < BLOCK_END {
+            return false;
+        }
+
+        // The pitch is at most 4 times the base rate, there's no skipping
+        // over a whole block
+        self.counter -= BLOCK_END;
+        self.previous = self.samples[SAMPLES_PER_BLOCK - 1];
+
+        if self.flags & LOOP_END != 0 {
+            self.ended = true;
+            self.address = self.repeat as u32 * 8;
+            if self.flags & LOOP_REPEAT == 0 {
+                self.envelope.level = 0;
+                self.envelope.start(Phase::Off);
+            }
+        } else {
+            self.address = self.address.wrapping_add(BLOCK_SIZE as u32);
+        }
+
+        self.load_block(ram);
+        true
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.volume[0]);
+        state.write_u16(self.volume[1]);
+        state.write_i16(self.current_volume[0]);
+        state.write_i16(self.current_volume[1]);
+        state.write_u16(self.pitch);
+        state.write_u16(self.start);
+        state.write_u16(self.repeat);
+        state.write_u32(self.adsr);
+        state.write_u8(self.envelope.phase as u8);
+        state.write_i16(self.envelope.level);
+        state.write_u32(self.envelope.wait);
+        state.write_bool(self.ended);
+        state.write_u32(self.address);
+        state.write_u32(self.counter);
+        state.write_u8(self.flags);
+        for sample in self.samples {
+            state.write_i16(sample);
+        }
+        state.write_i16(self.previous);
+        state.write_i16(self.history[0]);
+        state.write_i16(self.history[1]);
+        state.write_i16(self.output);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.volume = [state.read_u16()?, state.read_u16()?];
+        self.current_volume = [state.read_i16()?, state.read_i16()?];
+        self.pitch = state.read_u16()?;
+        self.start = state.read_u16()?;
+        self.repeat = state.read_u16()?;
+        self.adsr = state.read_u32()?;
+        self.envelope.phase = *Phase::ALL
+            .get(state.read_u8()? as usize)
+            .ok_or(StateError::Invalid("SPU envelope phase"))?;
+        self.envelope.level = state.read_i16()?;
+        self.envelope.wait = state.read_u32()?;
+        self.ended = state.read_bool()?;
+        self.address = state.read_u32()?;
+        self.counter = state.read_u32()?;
+        self.flags = state.read_u8()?;
+        for sample in self.samples.iter_mut() {
+            *sample = state.read_i16()?;
+        }
+        self.previous = state.read_i16()?;
+        self.history = [state.read_i16()?, state.read_i16()?];
+        self.output = state.read_i16()?;
+
+        if self.counter >= BLOCK_END {
+            return Err(StateError::Invalid("SPU voice position"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Blocks of constant samples: nibble 1 with shift 0 is 0x1000
+    fn ram_with_blocks(flags: &[u8]) -> Vec<u8> {
+        let mut ram = vec![0; 0x1000];
+        for (n, flags) in flags.iter().enumerate() {
+            let block = &mut ram[n * BLOCK_SIZE..(n + 1) * BLOCK_SIZE];
+            block[1] = *flags;
+            block[2..].fill(0x11 * (n as u8 + 1));
+        }
+        ram
+    }
+
+    #[test]
+    fn linear_attack_then_decay_to_sustain() {
+        let mut envelope = Envelope::new();
+        envelope.start(Phase::Attack);
+
+        // Fastest linear attack (+7 << 11 per tick), fast decay to 0x4000
+        let adsr = 0x0007;
+        let mut ticks = 0;
+        while envelope.phase == Phase::Attack {
+            envelope.tick(adsr);
+            ticks += 1;
+        }
+        assert_eq!(ticks, 3);
+        assert_eq!(envelope.level, MAX_LEVEL);
+
+        while envelope.phase == Phase::Decay {
+            envelope.tick(adsr);
+        }
+        assert_eq!(envelope.phase, Phase::Sustain);
+        assert!(envelope.level <= 0x4000 && envelope.level > 0x3000);
+    }
+
+    #[test]
+    fn slow_rates_wait_between_steps() {
+        let mut envelope = Envelope::new();
+        envelope.start(Phase::Attack);
+
+        // Shift 13: +7 every 4 ticks
+        let adsr = 13 << 10;
+        for _ in 0..4 {
+            envelope.tick(adsr);
+        }
+        assert_eq!(envelope.level, 7);
+        envelope.tick(adsr);
+        assert_eq!(envelope.level, 14);
+    }
+
+    #[test]
+    fn release_reaches_off() {
+        let mut envelope = Envelope::new();
+        envelope.level = 0x100;
+        envelope.start(Phase::Release);
+
+        // Linear release, shift 0: -8 << 11 per tick
+        envelope.tick(0);
+        assert_eq!(envelope.level, 0);
+        assert_eq!(envelope.phase, Phase::Off);
+    }
+
+    #[test]
+    fn plays_blocks_at_the_pitch() {
+        let ram = ram_with_blocks(&[0, 0]);
+        let mut voice = Voice::new();
+        voice.pitch = 0x2000;
+        voice.adsr = 0x000f;
+        voice.key_on(&ram);
+
+        let mut fetches = 0;
+        for _ in 0..14 {
+            if voice.tick(&ram) {
+                fetches += 1;
+            }
+        }
+        assert_eq!(fetches, 1);
+        assert_eq!(voice.address(), 16);
+        assert_eq!(voice.samples[0], 0x2000);
+    }
+
+    #[test]
+    fn loop_end_without_repeat_mutes() {
+        let ram = ram_with_blocks(&[LOOP_START, LOOP_END]);
+        let mut voice = Voice::new();
+        voice.pitch = 0x4000;
+        voice.adsr = 0x000f;
+        voice.key_on(&ram);
+        assert_eq!(voice.repeat, 0);
+
+        for _ in 0..7 {
+            voice.tick(&ram);
+        }
+        assert!(!voice.ended);
+        assert!(voice.output() > 0);
+
+        for _ in 0..7 {
+            voice.tick(&ram);
+        }
+        assert!(voice.ended);
+        assert_eq!(voice.address(), 0);
+        assert_eq!(voice.envelope.phase, Phase::Off);
+
+        voice.tick(&ram);
+        assert_eq!(voice.output(), 0);
+    }
+
+    #[test]
+    fn loop_repeat_keeps_playing() {
+        let ram = ram_with_blocks(&[0, LOOP_START, LOOP_END | LOOP_REPEAT]);
+        let mut voice = Voice::new();
+        voice.pitch = 0x4000;
+        voice.adsr = 0x000f;
+        voice.key_on(&ram);
+
+        for _ in 0..21 {
+            voice.tick(&ram);
+        }
+        assert!(voice.ended);
+        assert_eq!(voice.repeat, 2);
+        assert_eq!(voice.address(), 16);
+        assert_eq!(voice.envelope.phase, Phase::Sustain);
+    }
+}
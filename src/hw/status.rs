@@ -0,0 +1,84 @@
+//! Decoded interrupt and COP0 registers, for the status panel
+
+const IRQ_NAMES: [&str; 11] = [
+    "VBLANK", "GPU", "CDROM", "DMA", "TMR0", "TMR1", "TMR2", "PAD/MC", "SIO", "SPU", "LIGHTPEN",
+];
+
+/// One line per IRQ: whether it is pending in I_STAT and enabled in
+/// I_MASK. Those with both are keeping COP0 Cause.IP2 set.
+pub fn interrupts(stat: u32, mask: u32) -> String {
+    let mut out = format!("Interrupts: I_STAT {:03x} I_MASK {:03x}\n", stat, mask);
+
+    for (n, name) in IRQ_NAMES.iter().enumerate() {
+        let pending = stat & (1 << n) != 0;
+        let enabled = mask & (1 << n) != 0;
+
+        out += &format!(
+            "  irq{:<2} {:<8} {:<7} {}{}\n",
+            n,
+            name,
+            if pending { "pending" } else { "-" },
+            if enabled { "enabled" } else { "masked" },
+            if pending && enabled { "  <- raising" } else { "" },
+        );
+    }
+
+    out
+}
+
+fn exception_name(code: u32) -> &'static str {
+    match code {
+        0 => "Int",
+        4 => "AdEL",
+        5 => "AdES",
+        6 => "IBE",
+        7 => "DBE",
+        8 => "Syscall",
+        9 => "Bp",
+        10 => "RI",
+        11 => "CpU",
+        12 => "Ov",
+        _ => "?",
+    }
+}
+
+/// The interrupt and mode fields of SR and Cause
+pub fn cop0(sr: u32, cause: u32) -> String {
+    let bit = |value: u32, n: u32| (value >> n) & 1;
+
+    format!(
+        "COP0: SR {:08x} IEc {} KUc {} IM {:02x} IsC {} BEV {} CU0 {} CU2 {}\n      \
+         Cause {:08x} ExcCode {} ({}) IP {:02x} BD {}\n",
+        sr,
+        bit(sr, 0),
+        bit(sr, 1),
+        (sr >> 8) & 0xff,
+        bit(sr, 16),
+        bit(sr, 22),
+        bit(sr, 28),
+        bit(sr, 30),
+        cause,
+        (cause >> 2) & 0x1f,
+        exception_name((cause >> 2) & 0x1f),
+        (cause >> 8) & 0xff,
+        bit(cause, 31),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_interrupts_and_cop0() {
+        let irqs = interrupts(0x009, 0x00c);
+        assert!(irqs.starts_with("Interrupts: I_STAT 009 I_MASK 00c\n"));
+        assert!(irqs.contains("  irq0  VBLANK   pending masked\n"));
+        assert!(irqs.contains("  irq2  CDROM    -       enabled\n"));
+        assert!(irqs.contains("  irq3  DMA      pending enabled  <- raising\n"));
+
+        let cop0 = cop0(0x4000_0401, 0x8000_0424);
+        assert!(cop0.contains("SR 40000401 IEc 1 KUc 0 IM 04 IsC 0 BEV 0 CU0 0 CU2 1\n"));
+        assert!(cop0.contains("Cause 80000424 ExcCode 9 (Bp) IP 04 BD 1\n"));
+    }
+}
@@ -1,4 +1,6 @@
-use crate::hw::bus::{Bus, BusDevice};
+use crate::hw::bus::{Bus, BusDevice, PsxEventType};
+use crate::hw::irq::Irq;
+use crustationcpu::state::{Savestate, StateError, StateReader, StateWriter};
 use std::cell::RefCell;
 use std::rc::Weak;
 
@@ -14,9 +16,11 @@ bitfield! {
     pub irq_at_target, _: 4;
     pub irq_at_wrap, _: 5;
     pub repeat_mode, _: 6;
+    /// Toggle bit 10 at each interrupt instead of pulsing it
     pub pulse_mode, _: 7;
     pub clock_source, _: 9, 8;
-    pub irq_pulse, _: 10;
+    /// Low while an interrupt is requested
+    pub irq_pulse, set_irq_pulse: 10;
     pub reached_target, set_reached_target: 11;
     pub reached_wrap, set_reached_wrap: 12;
 }
@@ -26,7 +30,10 @@ struct Timer {
     current: u16,
     target: u16,
     status: CounterStatus,
-    last_update_cycles: u64,
+    /// Ticks of the clock source at the last update, since power on
+    last_clock: u64,
+    /// Interrupted since the last mode write, for one-shot mode
+    irq_done: bool,
 
     bus: Weak<RefCell<Bus>>,
 }
@@ -38,21 +45,23 @@ impl Timer {
             current: 0,
             target: 0,
             status: CounterStatus(0x400),
-            last_update_cycles: 0,
+            last_clock: 0,
+            irq_done: false,
 
             bus: Weak::new(),
         }
     }
 
     pub fn write_current_value(&mut self, value: u16) {
+        self.update();
         self.current = value;
 
-        self.refresh_cycles();
-
         //println!("Wrote {:08x} value to tmr{}", value, self.n);
     }
 
     pub fn write_status(&mut self, mut value: u32) {
+        self.update();
+
         // Can only set bits 0-9
         value &= 0x3ff;
 
@@ -63,47 +72,145 @@ impl Timer {
 
         // Reset current value on status writes
         self.current = 0;
-        self.refresh_cycles();
+        self.irq_done = false;
+        self.set_irq_line(false);
         //println!("Wrote {:08x} mode to tmr{} ({:?})", self.status.0, self.n, self.status);
     }
 
     pub fn write_target(&mut self, value: u16) {
+        self.update();
         self.target = value;
         //println!("Wrote {:08x} target to tmr{}", value, self.n);
     }
 
-    pub fn get_current_value(&mut self) -> u16 {
-        let previous_cycles = self.refresh_cycles();
+    /// Counts the ticks of the clock source since the last update, and
+    /// interrupts if the counter reached its target or 0xffff meanwhile
+    pub fn update(&mut self) {
+        let previous_clock = self.refresh_clock();
 
-        // Thank you modular arithmetic
-        let delta = (self.last_update_cycles - previous_cycles) as u16;
+        // The dot clock can go back when the resolution changes
+        let ticks = self.last_clock.saturating_sub(previous_clock);
+        if ticks == 0 {
+            return;
+        }
 
-        let divider = match self.n {
-            0 => 1.0,
-            1 => match self.status.clock_source() {
-                0 | 2 => 1.0,
-                1 | 3 => 1.0 / 2200.0, // 15840Hz average of PAL and NTSC
-                _ => unreachable!(),
-            },
-            2 => 1.0,
-            _ => unreachable!(),
-        };
+        let reached_target = self.ticks_to(self.target).is_some_and(|n| n <= ticks);
+        let reached_wrap = self.ticks_to(0xffff).is_some_and(|n| n <= ticks);
+        self.current = self.advance(ticks);
 
-        let delta = ((delta as f32) * divider) as u16;
+        if reached_target {
+            self.status.set_reached_target(true);
+        }
+        if reached_wrap {
+            self.status.set_reached_wrap(true);
+        }
+        if (reached_target && self.status.irq_at_target())
+            || (reached_wrap && self.status.irq_at_wrap())
+        {
+            self.interrupt();
+        }
+    }
 
-        let (new_value, overflown) = self.current.overflowing_add(delta);
-        self.current = new_value;
+    /// Last value before the counter goes back to 0, when counting from
+    /// `value`. A target of 0 doesn't reset it.
+    fn top(&self, value: u16) -> u16 {
+        if self.status.reset_at_target() && self.target != 0 && value <= self.target {
+            self.target
+        } else {
+            0xffff
+        }
+    }
 
-        if overflown && !self.status.reached_wrap() {
-            self.status.set_reached_wrap(true);
+    /// Ticks until the counter next shows `value`, None if it never will
+    fn ticks_to(&self, value: u16) -> Option<u64> {
+        let top = self.top(self.current);
+        if self.current < value && value <= top {
+            return Some((value - self.current) as u64);
+        }
+
+        // After going back to 0
+        let ticks = (top - self.current) as u64 + 1 + value as u64;
+        (value <= self.top(0)).then_some(ticks)
+    }
+
+    /// The counter `ticks` from now
+    fn advance(&self, ticks: u64) -> u16 {
+        let top = self.top(self.current) as u64;
+        let value = self.current as u64 + ticks;
+        if value <= top {
+            return value as u16;
+        }
+
+        let period = self.top(0) as u64 + 1;
+        ((value - top - 1) % period) as u16
+    }
+
+    /// Requests an interrupt, only once after a mode write in one-shot
+    /// mode. Bit 10 goes low for a moment, or toggles in toggle mode.
+    fn interrupt(&mut self) {
+        if self.irq_done && !self.status.repeat_mode() {
+            return;
+        }
+        self.irq_done = true;
+
+        if self.status.pulse_mode() {
+            let requested = self.status.irq_pulse();
+            self.status.set_irq_pulse(!requested);
+            self.set_irq_line(requested);
+        } else {
+            let bus = self.bus.upgrade().unwrap();
+            bus.borrow().send_irq(self.irq());
+        }
+    }
+
+    fn irq(&self) -> Irq {
+        match self.n {
+            0 => Irq::Timer0,
+            1 => Irq::Timer1,
+            _ => Irq::Timer2,
+        }
+    }
+
+    fn set_irq_line(&self, high: bool) {
+        let bus = self.bus.upgrade().unwrap();
+        bus.borrow().set_irq_line(self.irq(), high);
+    }
+
+    /// CPU cycles until the next interrupt, None if none is coming
+    fn irq_cycles(&self, bus: &Bus) -> Option<u64> {
+        if self.irq_done && !self.status.repeat_mode() {
+            return None;
         }
+        let target = self.status.irq_at_target().then(|| self.ticks_to(self.target));
+        let wrap = self.status.irq_at_wrap().then(|| self.ticks_to(0xffff));
+        let ticks = [target, wrap].into_iter().flatten().flatten().min()?;
+
+        let cycles = *bus.total_cycles.borrow();
+        Some(match (self.n, self.status.clock_source()) {
+            (0, 1 | 3) => bus.dot_cycles(cycles, ticks),
+            (1, 1 | 3) => bus.hblank_cycles(ticks),
+            (2, 2 | 3) => (cycles / 8 + ticks) * 8 - cycles,
+            _ => ticks,
+        })
+    }
+
+    /// Ticks of the clock source since power on: the system clock, the
+    /// GPU dot clock, horizontal blanks or the system clock / 8
+    fn clock(&self, bus: &Bus) -> u64 {
+        let cycles = *bus.total_cycles.borrow();
 
-        self.current
+        match (self.n, self.status.clock_source()) {
+            (0, 1 | 3) => bus.dots(cycles),
+            (1, 1 | 3) => bus.hblanks(),
+            (2, 2 | 3) => cycles / 8,
+            _ => cycles,
+        }
     }
 
-    fn refresh_cycles(&mut self) -> u64 {
-        let old = self.last_update_cycles;
-        self.last_update_cycles = *self.bus.upgrade().unwrap().borrow().total_cycles.borrow();
+    fn refresh_clock(&mut self) -> u64 {
+        let old = self.last_clock;
+        let bus = self.bus.upgrade().unwrap();
+        self.last_clock = self.clock(&bus.borrow());
 
         old
     }
@@ -125,6 +232,28 @@ impl Timers {
         self.timers[1].bus = bus.clone();
         self.timers[2].bus = bus;
     }
+
+    /// Brings the counters up to date, for the interrupts due
+    pub fn update(&mut self) {
+        for timer in &mut self.timers {
+            timer.update();
+        }
+        self.schedule();
+    }
+
+    /// Plans the next update for the first interrupt to come
+    fn schedule(&self) {
+        let bus = self.timers[0].bus.upgrade().unwrap();
+        let bus = bus.borrow();
+
+        match self.timers.iter().filter_map(|timer| timer.irq_cycles(&bus)).min() {
+            Some(cycles) => {
+                let target = *bus.total_cycles.borrow() + cycles.max(1);
+                bus.add_event(PsxEventType::Timers, target, 0);
+            }
+            None => bus.remove_event(PsxEventType::Timers),
+        }
+    }
 }
 
 impl BusDevice for Timers {
@@ -138,8 +267,12 @@ impl BusDevice for Timers {
 
         let timer = &mut self.timers[n];
         let val = match addr & 0xf {
-            0x0 => timer.get_current_value() as u32,
+            0x0 => {
+                timer.update();
+                timer.current as u32
+            }
             0x4 => {
+                timer.update();
                 let value = timer.status.0;
 
                 // Bits 11/12 are cleared upon read
@@ -182,5 +315,95 @@ impl BusDevice for Timers {
                 //println!("Invalid access to register {:x} on timer {}", addr & 0xf, n);
             }
         }
+        self.schedule();
+    }
+}
+
+impl Savestate for Timers {
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.tag(b"TMRS");
+        for timer in &self.timers {
+            state.write_u16(timer.current);
+            state.write_u16(timer.target);
+            state.write_u32(timer.status.0);
+            state.write_u64(timer.last_clock);
+            state.write_bool(timer.irq_done);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.expect_tag(b"TMRS", "timers")?;
+        for timer in &mut self.timers {
+            timer.current = state.read_u16()?;
+            timer.target = state.read_u16()?;
+            timer.status.0 = state.read_u32()?;
+            timer.last_clock = state.read_u64()?;
+            timer.irq_done = state.read_bool()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crustationcpu::PsxBus;
+    use std::rc::Rc;
+
+    const I_STAT: u32 = 0x1f80_1070;
+    const TIMER2: u32 = 0x1f80_1120;
+    /// Well over the wait states of the register accesses in between
+    const MARGIN: u64 = 50;
+
+    #[test]
+    fn interrupts_at_target_and_wrap() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow().link(bus.clone());
+        let bus = bus.borrow();
+
+        // System clock, reset and interrupt at target, repeatedly
+        bus.write::<2>(TIMER2 + 8, 999);
+        bus.write::<2>(TIMER2 + 4, 0x58);
+        bus.update_cycles(1000 - MARGIN);
+        assert_eq!(bus.read::<4>(I_STAT), 0);
+        bus.update_cycles(MARGIN);
+        assert_eq!(bus.read::<4>(I_STAT), 0x40);
+        assert!(bus.read::<2>(TIMER2) < MARGIN as u32);
+
+        bus.write::<4>(I_STAT, 0);
+        bus.update_cycles(1000);
+        assert_eq!(bus.read::<4>(I_STAT), 0x40);
+        assert_ne!(bus.read::<2>(TIMER2 + 4) & 0x800, 0);
+
+        // One-shot: the target is still reached, without interrupting
+        bus.write::<4>(I_STAT, 0);
+        bus.write::<2>(TIMER2 + 4, 0x18);
+        bus.update_cycles(1000);
+        assert_eq!(bus.read::<4>(I_STAT), 0x40);
+        bus.write::<4>(I_STAT, 0);
+        bus.update_cycles(1000);
+        assert_eq!(bus.read::<4>(I_STAT), 0);
+        assert_ne!(bus.read::<2>(TIMER2 + 4) & 0x800, 0);
+
+        // Toggle mode: bit 10 goes low, then back high without interrupting
+        bus.write::<2>(TIMER2 + 4, 0xd8);
+        bus.update_cycles(1000);
+        assert_eq!(bus.read::<4>(I_STAT), 0x40);
+        assert_eq!(bus.read::<2>(TIMER2 + 4) & 0x400, 0);
+        bus.write::<4>(I_STAT, 0);
+        bus.update_cycles(1000);
+        assert_eq!(bus.read::<4>(I_STAT), 0);
+        assert_ne!(bus.read::<2>(TIMER2 + 4) & 0x400, 0);
+        bus.update_cycles(1000);
+        assert_eq!(bus.read::<4>(I_STAT), 0x40);
+
+        // Timer 1 on the system clock, interrupt on 0xffff
+        bus.write::<4>(I_STAT, 0);
+        bus.write::<2>(0x1f80_1114, 0x60);
+        bus.update_cycles(0x10000 - MARGIN);
+        assert_eq!(bus.read::<4>(I_STAT) & 0x20, 0);
+        bus.update_cycles(MARGIN);
+        assert_eq!(bus.read::<4>(I_STAT) & 0x20, 0x20);
+        assert_ne!(bus.read::<2>(0x1f80_1114) & 0x1000, 0);
     }
 }
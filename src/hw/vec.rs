@@ -1,31 +1,33 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-
+/// Little-endian access to byte-backed memories (RAM, BIOS, I/O ports).
+///
+/// `S` is the access size in bytes: 1, 2 or 4. Addresses must be aligned to
+/// the access size, as on the real bus: the CPU raises an exception on
+/// misaligned loads and stores before they get here, and DMA works on words.
+/// Values are always returned zero-extended.
 pub trait ByteSerialized {
     fn read<const S: u32>(&self, addr: u32) -> u32;
     fn write<const S: u32>(&mut self, addr: u32, value: u32);
 }
 
-impl ByteSerialized for Vec<u8> {
+impl ByteSerialized for [u8] {
+    #[inline(always)]
     fn read<const S: u32>(&self, addr: u32) -> u32 {
+        debug_assert!(addr.is_multiple_of(S), "Misaligned {}-byte read at {:08x}", S, addr);
         let addr = addr as usize;
 
         match S {
             1 => self[addr] as u32,
-            2 => {
-                let mut bytes = &self[addr..addr + 2];
-                bytes.read_u16::<LittleEndian>().unwrap() as u32
-            }
-            4 => {
-                let mut bytes = &self[addr..addr + 4];
-                bytes.read_u32::<LittleEndian>().unwrap()
-            }
+            2 => u16::from_le_bytes([self[addr], self[addr + 1]]) as u32,
+            4 => u32::from_le_bytes(self[addr..addr + 4].try_into().unwrap()),
             _ => {
                 unreachable!()
             }
         }
     }
 
+    #[inline(always)]
     fn write<const S: u32>(&mut self, addr: u32, value: u32) {
+        debug_assert!(addr.is_multiple_of(S), "Misaligned {}-byte write at {:08x}", S, addr);
         let addr = addr as usize;
 
         match S {
@@ -33,12 +35,10 @@ impl ByteSerialized for Vec<u8> {
                 self[addr] = value as u8;
             }
             2 => {
-                let mut bytes = &mut self[addr..addr + 2];
-                bytes.write_u16::<LittleEndian>(value as u16).unwrap();
+                self[addr..addr + 2].copy_from_slice(&(value as u16).to_le_bytes());
             }
             4 => {
-                let mut bytes = &mut self[addr..addr + 4];
-                bytes.write_u32::<LittleEndian>(value).unwrap();
+                self[addr..addr + 4].copy_from_slice(&value.to_le_bytes());
             }
             _ => {
                 unreachable!()
@@ -46,3 +46,77 @@ impl ByteSerialized for Vec<u8> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random numbers (xorshift32)
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    /// Byte at a time little-endian model to check against
+    fn reference_read(memory: &[u8], addr: usize, size: usize) -> u32 {
+        (0..size).map(|i| (memory[addr + i] as u32) << (8 * i)).sum()
+    }
+
+    fn access<const S: u32>(memory: &mut [u8], model: &mut [u8], rng: &mut Rng) {
+        let addr = (rng.next() as usize % memory.len()) & !(S as usize - 1);
+
+        if rng.next() & 1 != 0 {
+            let value = rng.next();
+            memory.write::<S>(addr as u32, value);
+            for i in 0..S as usize {
+                model[addr + i] = (value >> (8 * i)) as u8;
+            }
+        } else {
+            assert_eq!(memory.read::<S>(addr as u32), reference_read(model, addr, S as usize));
+        }
+    }
+
+    #[test]
+    fn matches_byte_model_for_all_sizes() {
+        let mut memory = vec![0; 256];
+        let mut model = vec![0; 256];
+        let mut rng = Rng(0x1234_5678);
+
+        for _ in 0..100_000 {
+            match rng.next() % 3 {
+                0 => access::<1>(&mut memory, &mut model, &mut rng),
+                1 => access::<2>(&mut memory, &mut model, &mut rng),
+                _ => access::<4>(&mut memory, &mut model, &mut rng),
+            }
+        }
+
+        assert_eq!(memory, model);
+    }
+
+    #[test]
+    fn every_offset_is_little_endian() {
+        let mut memory = [0; 16];
+
+        for offset in (0..16).step_by(4) {
+            memory.write::<4>(offset, 0x0403_0201 + offset * 0x0101_0101);
+        }
+
+        for offset in 0..16 {
+            assert_eq!(memory.read::<1>(offset), offset + 1);
+        }
+        for offset in (0..16).step_by(2) {
+            assert_eq!(memory.read::<2>(offset), ((offset + 2) << 8) | (offset + 1));
+        }
+
+        // Narrow writes leave the rest of the word alone
+        memory.write::<2>(2, 0xbeef);
+        memory.write::<1>(1, 0xaa);
+        assert_eq!(memory.read::<4>(0), 0xbeef_aa01);
+    }
+}
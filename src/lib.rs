@@ -0,0 +1,11 @@
+#![feature(binary_heap_retain)]
+
+pub mod bench;
+#[cfg(feature = "debugger")]
+pub mod debug;
+#[cfg(feature = "debugger")]
+pub mod gdb;
+pub mod hash;
+pub mod hw;
+pub mod limiter;
+pub mod settings;
@@ -0,0 +1,157 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Cycles per second of the real hardware
+const CPU_FREQ: u64 = 33_868_800;
+
+/// If the emulation falls behind by more than this, don't try to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// Keeps emulation in sync with the wall clock, one frame at a time. Frames
+/// last as long as on the hardware: 59.29 Hz in NTSC, 49.76 Hz in PAL.
+pub struct FrameLimiter {
+    /// Emulation speed in percent of the real hardware. None is unlimited.
+    speed: Option<u32>,
+    /// Speed while fast forwarding, None is unlimited
+    fast_forward_speed: Option<u32>,
+    fast_forward: bool,
+    /// When the next frame is due
+    deadline: Instant,
+}
+
+impl Default for FrameLimiter {
+    fn default() -> FrameLimiter {
+        FrameLimiter::new()
+    }
+}
+
+impl FrameLimiter {
+    pub fn new() -> FrameLimiter {
+        FrameLimiter {
+            speed: Some(100),
+            fast_forward_speed: None,
+            fast_forward: false,
+            deadline: Instant::now(),
+        }
+    }
+
+    /// Sets the emulation speed: 50 for slow motion, 200 to fast forward,
+    /// None to run as fast as possible.
+    pub fn set_speed(&mut self, percent: Option<u32>) {
+        self.speed = percent.filter(|&p| p > 0);
+        self.deadline = Instant::now();
+    }
+
+    /// The speed of `set_fast_forward`, unlimited by default
+    pub fn set_fast_forward_speed(&mut self, percent: Option<u32>) {
+        self.fast_forward_speed = percent.filter(|&p| p > 0);
+        self.deadline = Instant::now();
+    }
+
+    pub fn set_fast_forward(&mut self, enabled: bool) {
+        self.fast_forward = enabled;
+        self.deadline = Instant::now();
+    }
+
+    pub fn fast_forward(&self) -> bool {
+        self.fast_forward
+    }
+
+    /// The speed frames run at now, fast forwarding or not
+    pub fn speed(&self) -> Option<u32> {
+        match self.fast_forward {
+            true => self.fast_forward_speed,
+            false => self.speed,
+        }
+    }
+
+    /// How long a frame of `frame_cycles` lasts on the host at the current
+    /// speed
+    pub fn frame_time(&self, frame_cycles: u64) -> Option<Duration> {
+        self.speed().map(|percent| {
+            let nanos = frame_cycles as u128 * 100_000_000_000;
+            Duration::from_nanos((nanos / (CPU_FREQ * percent as u64) as u128) as u64)
+        })
+    }
+
+    /// Called once per emulated frame with its length, sleeps until the
+    /// frame is due
+    pub fn wait(&mut self, frame_cycles: u64) {
+        let frame_time = match self.frame_time(frame_cycles) {
+            Some(frame_time) => frame_time,
+            None => return,
+        };
+
+        self.deadline += frame_time;
+
+        let now = Instant::now();
+        if self.deadline > now {
+            thread::sleep(self.deadline - now);
+        } else if now - self.deadline > MAX_LAG {
+            // Too slow (or we were paused): start over from now, rather than
+            // running unthrottled until we catch up
+            self.deadline = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// From one VBlank to the next, in CPU cycles
+    const NTSC_FRAME: u64 = 571_213;
+    const PAL_FRAME: u64 = 680_581;
+
+    #[test]
+    fn full_speed_follows_the_video_timing() {
+        let limiter = FrameLimiter::new();
+
+        assert_eq!(limiter.frame_time(NTSC_FRAME), Some(Duration::from_nanos(16_865_463)));
+        assert_eq!(limiter.frame_time(PAL_FRAME), Some(Duration::from_nanos(20_094_629)));
+    }
+
+    #[test]
+    fn frame_time_scales_with_speed() {
+        let mut limiter = FrameLimiter::new();
+        let full_speed = limiter.frame_time(NTSC_FRAME).unwrap();
+
+        limiter.set_speed(Some(50));
+        assert_eq!(limiter.frame_time(NTSC_FRAME), Some(full_speed * 2));
+
+        limiter.set_speed(Some(200));
+        assert_eq!(limiter.frame_time(NTSC_FRAME), Some(full_speed / 2));
+    }
+
+    #[test]
+    fn fast_forward_replaces_the_speed() {
+        let mut limiter = FrameLimiter::new();
+        let full_speed = limiter.frame_time(NTSC_FRAME);
+
+        limiter.set_fast_forward(true);
+        assert_eq!(limiter.frame_time(NTSC_FRAME), None);
+        assert_eq!(limiter.speed(), None);
+        limiter.set_fast_forward_speed(Some(400));
+        assert_eq!(limiter.frame_time(NTSC_FRAME), Some(full_speed.unwrap() / 4));
+
+        limiter.set_fast_forward(false);
+        assert_eq!(limiter.frame_time(NTSC_FRAME), full_speed);
+    }
+
+    #[test]
+    fn unlimited_never_waits() {
+        let mut limiter = FrameLimiter::new();
+        limiter.set_speed(None);
+        assert_eq!(limiter.frame_time(NTSC_FRAME), None);
+
+        // 0% makes no sense, treat it as unlimited
+        limiter.set_speed(Some(0));
+        assert_eq!(limiter.frame_time(NTSC_FRAME), None);
+
+        let start = Instant::now();
+        for _ in 0..100 {
+            limiter.wait(NTSC_FRAME);
+        }
+        assert!(start.elapsed() < Duration::from_millis(16));
+    }
+}
@@ -1,38 +1,513 @@
-#![feature(binary_heap_retain)]
-
-mod hw;
-
-use hw::bus::Bus;
-use crustationcpu::CpuCommand;
+use crustationcpu::breakpoints::DebugStop;
+use crustationcpu::trace::{TraceFilter, Tracer};
+use crustationcpu::{CpuCommand, IcacheMode};
+use psx::bench;
+use psx::debug::Debugger;
+use psx::gdb;
+use psx::hash::fnv1a;
+use psx::hw::bus::Bus;
+use psx::hw::disasm::{Disasm, Symbols};
+use psx::hw::exe::{ExeError, ExeHeader};
+use psx::hw::fill::Fill;
+use psx::hw::metrics;
+use psx::hw::postprocess::{FrameBlend, Pass};
+use psx::hw::{
+    open_serial_backend, ColorProfile, GpuAccuracy, GpuPreference, RendererOptions, SpeedAudio,
+};
+use psx::settings::{self, Settings};
 use std::cell::RefCell;
+use std::ffi::{OsStr, OsString};
+use std::io::{IsTerminal, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 fn main() {
+    let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "disasm") {
+        disasm(&args[1..]);
+        return;
+    }
+
     let bus_rc = Rc::new(RefCell::new(Bus::new()));
     let bus = bus_rc.borrow();
     let cpu = bus.cpu.borrow_mut();
 
     let cpu_tx = bus.cpu_tx.clone();
 
+    // Ctrl-C quits, or enters the debugger with --debug. Attached to gdb, it
+    // interrupts like gdb's own Ctrl-C.
+    let debug = args.iter().any(|arg| arg == "--debug");
+    // --bench[=SECONDS] runs headless and as fast as possible for a fixed
+    // emulated time, then prints where the time went
+    let bench: Option<f64> = args.iter().filter_map(|arg| arg.to_str()).find_map(|arg| match arg {
+        "--bench" => Some(10.),
+        _ => arg.strip_prefix("--bench=").map(|s| s.parse().expect("Invalid --bench value")),
+    });
+    // --headless[=FRAMES] runs without a window, as fast as possible, for
+    // FRAMES frames or until Ctrl-C. The GPU still draws into its VRAM copy,
+    // which --screenshot saves.
+    let headless: Option<Option<u64>> =
+        args.iter().filter_map(|arg| arg.to_str()).find_map(|arg| match arg {
+        "--headless" => Some(None),
+        _ => arg
+            .strip_prefix("--headless=")
+            .map(|s| Some(s.parse().expect("Invalid --headless value"))),
+    });
+    let gdb_port: Option<u16> = args
+        .iter()
+        .filter_map(|arg| arg.to_str())
+        .find_map(|arg| arg.strip_prefix("--gdb="))
+        .map(|port| port.parse().expect("Invalid --gdb value"));
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let interrupted_tx = interrupted.clone();
     ctrlc::set_handler(move || {
-        cpu_tx.send(CpuCommand::Break).unwrap();
+        let command = if debug || gdb_port.is_some() {
+            CpuCommand::Debug
+        } else {
+            interrupted_tx.store(true, Ordering::Relaxed);
+            CpuCommand::Break
+        };
+        // The emulator may be shutting down already
+        if cpu_tx.send(command).is_err() {
+            println!("Shutting down");
+        }
     })
     .expect("Error setting Ctrl-C handler");
 
     drop(cpu);
 
-    bus.load_rom("bios/PSXONPSP660.BIN");
+    let mut settings = Settings::load();
+
+    // Needed before the window is created
+    let mut renderer_options = RendererOptions {
+        window: settings.window,
+        fullscreen: settings.fullscreen,
+        ..RendererOptions::default()
+    };
+    let mut bios = settings
+        .bios
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("bios/PSXONPSP660.BIN"));
+    for os_arg in &args {
+        let arg = os_arg.to_string_lossy();
+        if let Some(driver) = arg.strip_prefix("--video-driver=") {
+            renderer_options.video_driver = Some(driver.to_string());
+        } else if let Some(gpu) = arg.strip_prefix("--gpu=") {
+            // default, integrated or discrete
+            renderer_options.gpu = GpuPreference::from_name(gpu).expect("Invalid --gpu value");
+        } else if arg == "--fullscreen" {
+            renderer_options.fullscreen = true;
+        } else if arg == "--windowed" {
+            renderer_options.fullscreen = false;
+        } else if let Some(path) = path_option(os_arg, "--bios=") {
+            bios = path;
+        }
+    }
+
+    if let Err(err) = bus.load_rom(&bios) {
+        fail("the BIOS", &bios, err);
+    }
     bus.link(bus_rc.clone());
+    if bench.is_none() && headless.is_none() {
+        bus.load_renderer(&renderer_options);
+    }
+    if headless.is_some() {
+        bus.set_speed(None);
+    }
 
     drop(bus);
 
     let bus = bus_rc.borrow();
-    let executable = std::env::args().nth(1);
+
+    let mut executable = None;
+    let mut exe_args = vec![];
+    let mut trace_length = None;
+    let mut trace_filter = TraceFilter::ALL;
+    let mut trace_file = PathBuf::from("trace.txt");
+    let mut screenshot = None;
+    let mut screenshot_frames = vec![];
+    let mut resume = None;
+    for os_arg in &args {
+        let arg = os_arg.to_string_lossy();
+        if arg.starts_with("--video-driver=")
+            || arg.starts_with("--gpu=")
+            || arg.starts_with("--bios=")
+            || arg == "--fullscreen"
+            || arg == "--windowed"
+            || arg == "--debug"
+            || arg.starts_with("--gdb=")
+            || arg == "--bench"
+            || arg.starts_with("--bench=")
+            || arg == "--headless"
+            || arg.starts_with("--headless=")
+        {
+            // Already handled
+        } else if arg == "--last-exe" {
+            // The executable of the previous run
+            match &settings.last_exe {
+                Some(exe) => executable = Some(exe.clone()),
+                None => {
+                    println!("No previous executable to run with --last-exe");
+                    std::process::exit(1);
+                }
+            }
+        } else if let Some(speed) = arg.strip_prefix("--speed=") {
+            // Percent of the real hardware speed, or "unlimited"
+            match speed {
+                "unlimited" => bus.set_speed(None),
+                _ => bus.set_speed(Some(speed.parse().expect("Invalid --speed value"))),
+            }
+        } else if let Some(speed) = arg.strip_prefix("--fast-forward=") {
+            // Speed while Tab is toggled on, unlimited by default
+            let speed = speed.parse().expect("Invalid --fast-forward value");
+            bus.set_fast_forward_speed(Some(speed));
+        } else if let Some(mode) = arg.strip_prefix("--speed-audio=") {
+            // drop, mute, skip, or stretch to keep the pitch away from 100% speed
+            let speed_audio = SpeedAudio::from_name(mode).expect("Invalid --speed-audio value");
+            bus.set_speed_audio(speed_audio);
+        } else if let Some(profile) = arg.strip_prefix("--color=") {
+            // raw, dac, gamma or composite
+            bus.set_color_profile(ColorProfile::from_name(profile).expect("Invalid --color value"));
+        } else if let Some(preset) = arg.strip_prefix("--gpu-accuracy=") {
+            // fast, balanced or accurate, F8 cycles through them
+            let preset = GpuAccuracy::from_name(preset).expect("Invalid --gpu-accuracy value");
+            bus.set_gpu_accuracy(preset);
+        } else if let Some(mode) = arg.strip_prefix("--icache=") {
+            // fast or accurate, for code that changes itself without a flush
+            bus.set_icache_mode(IcacheMode::from_name(mode).expect("Invalid --icache value"));
+        } else if let Some(chain) = arg.strip_prefix("--post=") {
+            // Comma separated effects, e.g. composite,scanlines:0.3,curvature
+            bus.set_post_processing(&Pass::parse_chain(chain).expect("Invalid --post value"));
+        } else if arg == "--break-on-gpu-hang" {
+            bus.set_break_on_gpu_hang(true);
+        } else if arg == "--strict-gpu" {
+            bus.set_strict_gpu(true);
+        } else if arg == "--strict-memory" {
+            bus.set_strict_memory(true);
+        } else if let Some(fill) = arg.strip_prefix("--ram-fill=") {
+            // zeros, ones, random or random:SEED
+            bus.set_ram_fill(Fill::from_name(fill).expect("Invalid --ram-fill value"));
+        } else if let Some(fill) = arg.strip_prefix("--open-bus=") {
+            bus.set_open_bus(Fill::from_name(fill).expect("Invalid --open-bus value"));
+        } else if arg == "--log-dma" {
+            bus.set_dma_logging(true);
+        } else if arg == "--log-bios" {
+            // BIOS functions called, e.g. the files opened and events waited on
+            bus.set_bios_logging(true);
+        } else if arg == "--status-panel" {
+            // Also toggled with F9
+            bus.show_status_panel(true);
+        } else if arg == "--immediate-display" {
+            // Show display area changes mid-frame, for debugging
+            bus.set_latch_display(false);
+        } else if arg == "--crosshair" {
+            // Light gun crosshair, F10 toggles its calibration mode
+            bus.set_crosshair(true);
+        } else if let Some(path) = path_option(os_arg, "--metrics-file=") {
+            // Statistics written once per second, .prom for Prometheus
+            bus.set_metrics_file(Some(path));
+        } else if let Some(port) = arg.strip_prefix("--metrics-port=") {
+            // Statistics served on http://127.0.0.1:<port>/metrics
+            let port = port.parse().expect("Invalid --metrics-port value");
+            if let Err(err) = metrics::serve(bus.metrics_handle(), port) {
+                println!("[METRICS] Could not listen on port {}: {}", port, err);
+            }
+        } else if let Some(path) = path_option(os_arg, "--exp1=") {
+            // A cartridge ROM for the parallel port, e.g. Caetla or Unirom
+            if let Err(err) = bus.load_exp1_rom(&path) {
+                println!("[EXP1] Could not load {}: {}", path.display(), err);
+            }
+        } else if let Some(spec) = arg.strip_prefix("--sio=") {
+            // The serial port: null, stdio, tcp:HOST:PORT or listen:[HOST:]PORT
+            match open_serial_backend(spec) {
+                Ok(backend) => bus.set_serial_backend(backend),
+                Err(err) => println!("[SIO] Could not open {}: {}", spec, err),
+            }
+        } else if arg == "--analog" {
+            // Start the pad in analog mode, for games that don't switch it
+            bus.set_analog(true);
+        } else if arg == "--input-display" {
+            // Pad buttons over the picture, F11 toggles them
+            bus.set_input_display(true);
+        } else if let Some(length) = arg.strip_prefix("--trace=") {
+            // Keep the last N instructions, written on a fault, a panic or
+            // with the debugger's trace command
+            trace_length = Some(length.parse().expect("Invalid --trace value"));
+        } else if let Some(filter) = arg.strip_prefix("--trace-filter=") {
+            // all, bios or ram
+            trace_filter = TraceFilter::from_name(filter).expect("Invalid --trace-filter value");
+        } else if let Some(path) = path_option(os_arg, "--trace-file=") {
+            trace_file = path;
+        } else if let Some(mode) = arg.strip_prefix("--blend=") {
+            // off, mix (with the previous frame) or bfi (black frame insertion)
+            bus.set_frame_blend(FrameBlend::from_name(mode).expect("Invalid --blend value"));
+        } else if let Some(path) = path_option(os_arg, "--screenshot=") {
+            // The picture on exit, as a PNG
+            screenshot = Some(path);
+        } else if let Some(frames) = arg.strip_prefix("--screenshot-at=") {
+            // Headless, also after these frames: FILE-FRAME.png
+            screenshot_frames = frames
+                .split(',')
+                .map(|frame| frame.parse().expect("Invalid --screenshot-at value"))
+                .collect();
+        } else if let Some(auto_state) = arg.strip_prefix("--auto-state=") {
+            // on or off, remembered: save the game on exit, resume it on start
+            settings.auto_state = match auto_state {
+                "on" => true,
+                "off" => false,
+                _ => panic!("Invalid --auto-state value"),
+            };
+        } else if arg == "--resume" || arg == "--no-resume" {
+            // Whether to resume the auto state, without asking
+            resume = Some(arg == "--resume");
+        } else if executable.is_none() {
+            executable = Some(PathBuf::from(os_arg));
+        } else {
+            // Everything after the executable is passed to it
+            exe_args.push(arg.into_owned());
+        }
+    }
+
+    let state_name = match &executable {
+        Some(exe) => exe.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+        None => String::from("bios"),
+    };
+    bus.set_state_path(settings::state_path(&state_name));
+
+    if let Some(length) = trace_length {
+        let tracer = Tracer::new(length, trace_filter, &trace_file, Disasm::disasm);
+        bus.cpu.borrow_mut().tracer = Some(tracer);
+    }
+
+    // Disc images boot through the BIOS, executables are side-loaded
+    let is_disc = executable.as_ref().is_some_and(|path| {
+        let ext = path.extension().unwrap_or_default();
+        ext.eq_ignore_ascii_case("cue") || ext.eq_ignore_ascii_case("bin")
+    });
+
+    if is_disc {
+        let disc = executable.as_ref().unwrap();
+        if let Err(err) = bus.load_disc(disc) {
+            fail("the disc", disc, err);
+        }
+    } else if let Some(exe) = &executable {
+        // --debug stops at its first instruction, once the BIOS is done
+        let exe_args: Vec<&str> = exe_args.iter().map(String::as_str).collect();
+        if let Err(err) = bus.sideload_exe(exe, &exe_args, debug) {
+            fail("the executable", exe, err);
+        }
+    }
+
+    // Bench and headless runs are reproducible, they don't use auto states
+    let auto_state = match &executable {
+        Some(exe) if settings.auto_state && bench.is_none() && headless.is_none() => {
+            game_id(&bus, exe, is_disc).and_then(|game| settings::auto_state_path(&game))
+        }
+        _ => None,
+    };
+    if let Some(path) = auto_state.as_ref().filter(|path| path.exists()) {
+        let resume = resume.unwrap_or_else(|| ask("Resume the game from where it was left?"));
+        if resume {
+            println!("[AUTOSTATE] Resuming from {}", path.display());
+            bus.queue_load_state(path);
+        }
+    }
+
+    if let Some(seconds) = bench {
+        print!("{}", bench::run(&bus, seconds).to_json());
+        bus.shutdown();
+        return;
+    }
+
+    if let Some(frames) = headless {
+        let screenshot = screenshot.unwrap_or_else(|| PathBuf::from("screenshot.png"));
+        let mut frame = 0;
+        while frames.is_none_or(|frames| frame < frames)
+            && !interrupted.load(Ordering::Relaxed)
+            && !bus.quit_requested()
+        {
+            bus.run_frame();
+            frame += 1;
+            if screenshot_frames.contains(&frame) {
+                let stem = screenshot.file_stem().unwrap_or_default().to_string_lossy();
+                let path = screenshot.with_file_name(format!("{}-{}.png", stem, frame));
+                save_screenshot(&bus, &path);
+            }
+        }
+        save_screenshot(&bus, &screenshot);
+        println!("[HEADLESS] Ran {} frames", frame);
+        bus.shutdown();
+        return;
+    }
+
+    // --debug starts at the prompt, before the first instruction (of the
+    // executable, when side-loading one)
+    let mut debugger = Debugger::new();
+    let mut stop = (debug && !bus.sideload_pending()).then_some(DebugStop::Requested);
+    // --gdb=PORT hands the emulator to gdb first, and runs on after it detaches
+    let quit = gdb_port.is_some_and(|port| !gdb::serve(&bus, port));
+    if !quit {
+        loop {
+            if let Some(stop) = stop {
+                if !debugger.enter(&bus, stop) {
+                    break;
+                }
+            }
+            // A panic in the core still writes the trace
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| bus.run())) {
+                bus.cpu.borrow_mut().dump_trace();
+                panic::resume_unwind(panic);
+            }
+            stop = bus.take_debug_stop();
+            if stop.is_none() {
+                break;
+            }
+        }
+    }
+
+    bus.print_compatibility_summary();
+    if let Some(path) = &screenshot {
+        save_screenshot(&bus, path);
+    }
+
+    settings.bios = Some(absolute(bios));
     if let Some(exe) = executable {
-        bus.run_until(0x8003_0000);
-        bus.load_exe(&exe);
-        bus.run();
-    } else {
-        bus.run();
+        settings.last_exe = Some(absolute(exe));
+    }
+    if let Some((geometry, fullscreen)) = bus.window_state() {
+        // The fullscreen geometry is the desktop's, keep the windowed one
+        if !fullscreen {
+            settings.window = Some(geometry);
+        }
+        settings.fullscreen = fullscreen;
     }
+    if let Some(path) = &auto_state {
+        println!("[AUTOSTATE] Saving to {}", path.display());
+        bus.queue_save_state(path);
+    }
+    bus.shutdown();
+    settings.save();
+}
+
+/// What auto states are keyed by: the serial number of a disc, or the name
+/// and a hash of an executable, so that a rebuilt one doesn't resume
+fn game_id(bus: &Bus, executable: &Path, is_disc: bool) -> Option<String> {
+    if is_disc {
+        return bus.disc_serial();
+    }
+
+    let hash = fnv1a(std::fs::read(executable).ok()?);
+    let stem = executable.file_stem()?.to_string_lossy();
+    Some(format!("{}-{:016x}", stem, hash))
+}
+
+/// Asks on the terminal, yes by default. Without a terminal, the answer is
+/// no.
+fn ask(question: &str) -> bool {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return false;
+    }
+
+    print!("{} [Y/n] ", question);
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    if stdin.read_line(&mut answer).is_err() {
+        return false;
+    }
+    !matches!(answer.trim().to_ascii_lowercase().as_str(), "n" | "no")
+}
+
+/// Writes the displayed picture as a PNG, and prints its hash for scripts
+/// comparing the output of test programs
+fn save_screenshot(bus: &Bus, path: &Path) {
+    let frame = bus.video_frame();
+    match frame.write_png(path) {
+        Ok(()) => println!(
+            "[SCREENSHOT] {}: {}x{}, hash {:016x}",
+            path.display(),
+            frame.width,
+            frame.height,
+            frame.hash()
+        ),
+        Err(err) => println!("[SCREENSHOT] Could not write {}: {}", path.display(), err),
+    }
+}
+
+/// `disasm <file> [--addr=START] [--count=N] [--symbols=FILE]`: lists a
+/// PS-X EXE, or a BIOS image, without running it
+fn disasm(args: &[OsString]) {
+    let mut path = None;
+    let mut start = None;
+    let mut count = usize::MAX;
+    let mut symbols = Symbols::default();
+
+    for os_arg in args {
+        let arg = os_arg.to_string_lossy();
+        if let Some(addr) = arg.strip_prefix("--addr=") {
+            let addr = addr.trim_start_matches("0x");
+            start = Some(u32::from_str_radix(addr, 16).expect("Invalid --addr value"));
+        } else if let Some(n) = arg.strip_prefix("--count=") {
+            count = n.parse().expect("Invalid --count value");
+        } else if let Some(file) = path_option(os_arg, "--symbols=") {
+            let text = std::fs::read_to_string(&file)
+                .unwrap_or_else(|err| panic!("Could not open {}: {}", file.display(), err));
+            let parsed = Symbols::parse(&text);
+            symbols = parsed.unwrap_or_else(|err| panic!("{}: {}", file.display(), err));
+        } else {
+            path = Some(Path::new(os_arg));
+        }
+    }
+
+    let path = path.expect("Usage: disasm <file> [--addr=START] [--count=N] [--symbols=FILE]");
+    let data = std::fs::read(path)
+        .unwrap_or_else(|err| panic!("Could not open {}: {}", path.display(), err));
+
+    // Anything but an executable is taken for a BIOS image
+    let (base, code) = match ExeHeader::parse(&data) {
+        Ok(header) => {
+            println!("{}\n", header);
+            let code =
+                header.code(&data).unwrap_or_else(|err| panic!("{} is {}", path.display(), err));
+            (header.destination, code)
+        }
+        Err(ExeError::Signature(_)) => (0xbfc0_0000, &data[..]),
+        Err(err) => panic!("{} is {}", path.display(), err),
+    };
+
+    let words: Vec<u32> = code
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect();
+
+    let start = start.unwrap_or(base);
+    let first = (start.wrapping_sub(base) / 4) as usize;
+    if first >= words.len() {
+        panic!("{:08x} is outside of {}", start, path.display());
+    }
+    let last = first.saturating_add(count).min(words.len());
+
+    print!("{}", Disasm::listing(&words[first..last], base + 4 * first as u32, &symbols));
+}
+
+/// Reports a file that can't be loaded and quits
+fn fail(what: &str, path: &Path, err: impl std::fmt::Display) -> ! {
+    println!("Could not load {} {}: {}", what, path.display(), err);
+    std::process::exit(1);
+}
+
+/// `path` from the root, so that the settings still find it when run
+/// from another directory
+fn absolute(path: PathBuf) -> PathBuf {
+    std::fs::canonicalize(&path).unwrap_or(path)
+}
+
+/// The value of `--name=path`, kept as given even if it isn't UTF-8
+fn path_option(arg: &OsStr, name: &str) -> Option<PathBuf> {
+    let value = arg.as_encoded_bytes().strip_prefix(name.as_bytes())?;
+    // SAFETY: split right after an ASCII prefix, as from_encoded_bytes_unchecked allows
+    Some(PathBuf::from(unsafe { OsStr::from_encoded_bytes_unchecked(value) }))
 }
@@ -0,0 +1,164 @@
+//! Preferences kept between runs, in the platform's config directory
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::hw::WindowGeometry;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Settings {
+    /// BIOS used when none is given on the command line
+    pub bios: Option<PathBuf>,
+    pub last_exe: Option<PathBuf>,
+    /// Size and position of the window when it was last closed, out of
+    /// fullscreen
+    pub window: Option<WindowGeometry>,
+    pub fullscreen: bool,
+    /// Save the state of the game on exit, and offer to resume it next time
+    pub auto_state: bool,
+}
+
+impl Settings {
+    /// Defaults if there are no saved settings, or they can't be read
+    pub fn load() -> Settings {
+        match path().and_then(|path| fs::read_to_string(path).ok()) {
+            Some(contents) => Settings::parse(&contents),
+            None => Settings::default(),
+        }
+    }
+
+    pub fn save(&self) {
+        let path = match path() {
+            Some(path) => path,
+            None => return,
+        };
+
+        let result = fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| fs::write(&path, self.serialize()));
+        if let Err(err) = result {
+            println!("Could not save the settings to {}: {}", path.display(), err);
+        }
+    }
+
+    /// One `key = value` per line. Unknown keys and bad values are skipped.
+    fn parse(contents: &str) -> Settings {
+        let mut settings = Settings::default();
+
+        for line in contents.lines() {
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+
+            match key {
+                "bios" => settings.bios = Some(PathBuf::from(value)),
+                "last_exe" => settings.last_exe = Some(PathBuf::from(value)),
+                "fullscreen" => settings.fullscreen = value == "true",
+                "auto_state" => settings.auto_state = value == "true",
+                "window" => settings.window = parse_geometry(value),
+                _ => {}
+            }
+        }
+
+        settings
+    }
+
+    fn serialize(&self) -> String {
+        let mut contents = String::new();
+
+        // The file is text, paths that aren't valid UTF-8 aren't remembered
+        if let Some(bios) = self.bios.as_deref().and_then(Path::to_str) {
+            contents += &format!("bios = {}\n", bios);
+        }
+        if let Some(exe) = self.last_exe.as_deref().and_then(Path::to_str) {
+            contents += &format!("last_exe = {}\n", exe);
+        }
+        if let Some(window) = &self.window {
+            contents += &format!(
+                "window = {} {} {} {}\n",
+                window.x, window.y, window.width, window.height
+            );
+        }
+        contents += &format!("fullscreen = {}\n", self.fullscreen);
+        contents += &format!("auto_state = {}\n", self.auto_state);
+
+        contents
+    }
+}
+
+/// `x y width height`
+fn parse_geometry(value: &str) -> Option<WindowGeometry> {
+    let mut parts = value.split_whitespace();
+
+    Some(WindowGeometry {
+        x: parts.next()?.parse().ok()?,
+        y: parts.next()?.parse().ok()?,
+        width: parts.next()?.parse().ok()?,
+        height: parts.next()?.parse().ok()?,
+    })
+}
+
+fn path() -> Option<PathBuf> {
+    Some(config_dir()?.join("settings.txt"))
+}
+
+/// Savestate file for the quick save/load hotkeys, one per executable (or
+/// "bios" when booting the shell). The directory is created if needed.
+pub fn state_path(name: &str) -> Option<PathBuf> {
+    let dir = config_dir()?.join("states");
+    fs::create_dir_all(&dir).ok()?;
+
+    Some(dir.join(format!("{}.state", name)))
+}
+
+/// Savestate written on exit with `auto_state`, one per game: the serial
+/// number of a disc, or a hash of an executable. Kept apart from the quick
+/// save, so that exiting doesn't overwrite it.
+pub fn auto_state_path(game: &str) -> Option<PathBuf> {
+    state_path(&format!("{}.auto", game))
+}
+
+fn config_dir() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        PathBuf::from(std::env::var_os("APPDATA")?)
+    } else if cfg!(target_os = "macos") {
+        PathBuf::from(std::env::var_os("HOME")?).join("Library/Application Support")
+    } else {
+        match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        }
+    };
+
+    Some(dir.join("crustation"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let settings = Settings {
+            bios: Some(PathBuf::from("bios/SCPH1001.BIN")),
+            last_exe: Some(PathBuf::from("/home/me/psx/hello world.exe")),
+            window: Some(WindowGeometry {
+                x: -10,
+                y: 40,
+                width: 1280,
+                height: 960,
+            }),
+            fullscreen: true,
+            auto_state: true,
+        };
+
+        assert_eq!(Settings::parse(&settings.serialize()), settings);
+    }
+
+    #[test]
+    fn skips_junk() {
+        let settings = Settings::parse("window = 1 2 three 4\ncolor = blue\nnonsense\nfullscreen = yes\n");
+
+        assert_eq!(settings, Settings::default());
+    }
+}